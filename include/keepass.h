@@ -0,0 +1,61 @@
+/*
+ * C interface of the keepass crate, built with the `ffi` feature.
+ * See the documentation of the `ffi` module for ownership rules.
+ */
+
+#ifndef KEEPASS_H
+#define KEEPASS_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef enum KpStatus {
+    KP_OK = 0,
+    KP_INVALID_ARGUMENT = 1,
+    KP_NOT_FOUND = 2,
+    KP_INCORRECT_KEY = 3,
+    KP_CORRUPT = 4,
+    KP_UNSUPPORTED = 5,
+    KP_IO = 6,
+    KP_FAILED = 7,
+} KpStatus;
+
+typedef struct KpDatabase KpDatabase;
+typedef struct KpEntryList KpEntryList;
+
+const char *kp_last_error(void);
+
+KpStatus kp_database_open(const char *path, const char *password, const char *keyfile_path,
+                          KpDatabase **out_db);
+KpStatus kp_database_open_buffer(const uint8_t *data, size_t len, const char *password,
+                                 const char *keyfile_path, KpDatabase **out_db);
+KpStatus kp_database_save(KpDatabase *db, const char *path, const char *password,
+                          const char *keyfile_path);
+KpStatus kp_database_save_buffer(KpDatabase *db, const char *password, const char *keyfile_path,
+                                 uint8_t **out_data, size_t *out_len);
+void kp_database_free(KpDatabase *db);
+
+KpStatus kp_database_entries(KpDatabase *db, KpEntryList **out_list);
+KpStatus kp_database_search(KpDatabase *db, const char *text, KpEntryList **out_list);
+size_t kp_entry_list_len(const KpEntryList *list);
+KpStatus kp_entry_list_uuid(const KpEntryList *list, size_t index, uint8_t out_uuid[16]);
+void kp_entry_list_free(KpEntryList *list);
+
+KpStatus kp_entry_get_field(KpDatabase *db, const uint8_t uuid[16], const char *field,
+                            char **out_value);
+KpStatus kp_entry_set_field(KpDatabase *db, const uint8_t uuid[16], const char *field,
+                            const char *value, bool protected_);
+
+void kp_string_free(char *s);
+void kp_buffer_free(uint8_t *data, size_t len);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
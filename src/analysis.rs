@@ -0,0 +1,431 @@
+//! Health checks of the passwords in a database
+//!
+//! `health_report` finds the entries a "database health check" screen would point out: passwords
+//! that are used for several entries, weak or empty passwords, and entries that have expired or
+//! are about to. The report only holds entry UUIDs and scores, never the passwords themselves:
+//!
+//! ```
+//! use keepass::{analysis::{health_report, HealthOptions}, db::Entry, Database};
+//!
+//! let mut db = Database::new(Default::default());
+//! db.root.add_child(Entry::new().with_title("Mail").with_password("hunter2"));
+//! db.root.add_child(Entry::new().with_title("Forum").with_password("hunter2"));
+//!
+//! let report = health_report(&db, &HealthOptions::default());
+//! assert_eq!(report.reused[0].len(), 2);
+//! assert_eq!(report.weak.len(), 2);
+//! ```
+//!
+//! Like in KeePassXC, entries in the recycle bin are skipped, and so are entries excluded from
+//! reports, either with the KeePass quality check turned off or with the custom data item
+//! `KnownBad` set to `true`. The strength of a password is estimated from the characters it uses,
+//! with repeated characters and sequences counting little, and common passwords or passwords equal
+//! to the title or user name of their entry rated poor right away.
+//!
+//! With the `hibp` feature, `check_pwned` looks up passwords in the breach corpus of Have I Been
+//! Pwned. Only the first five hex digits of the SHA-1 hash of each password are sent, following
+//! the k-anonymity model of the range API, and the request is left to a closure so that any HTTP
+//! client can be used:
+//!
+//! ```
+//! # #[cfg(feature = "hibp")]
+//! # {
+//! use keepass::{analysis::check_pwned, db::Entry};
+//!
+//! let entry = Entry::new().with_password("password");
+//! // a real caller would fetch https://api.pwnedpasswords.com/range/{prefix}
+//! let pwned = check_pwned([&entry], |prefix| {
+//!     assert_eq!(prefix, "5BAA6");
+//!     Ok::<_, std::io::Error>("1E4C9B93F3F0682250B6CF8331B7EE68FD8:10434004\r\n".to_string())
+//! })?;
+//! assert_eq!(pwned[0].count, 10434004);
+//! # }
+//! # Ok::<(), std::io::Error>(())
+//! ```
+
+use std::collections::HashMap;
+
+use chrono::NaiveDateTime;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::{
+    db::{with_audit_context, Database, Entry, Group, Times, Value},
+    key::estimate_password_entropy,
+};
+
+/// Custom data key with which KeePassXC excludes an entry from database reports
+pub const KNOWN_BAD_KEY: &str = "KnownBad";
+
+/// Passwords that are among the first ones tried by any attacker, ignoring case and trailing
+/// digits or punctuation
+const COMMON_PASSWORDS: &[&str] = &[
+    "password", "qwerty", "qwertz", "azerty", "abc", "letmein", "welcome", "admin", "iloveyou", "monkey",
+    "dragon", "football", "baseball", "master", "sunshine", "princess", "shadow", "superman", "trustno1",
+    "passw0rd", "p@ssw0rd", "secret", "login", "hello", "changeme",
+];
+
+/// How hard a password is to guess, with the bounds used by KeePassXC
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PasswordStrength {
+    /// Less than 40 bits of entropy
+    Poor,
+    /// Less than 75 bits of entropy
+    Weak,
+    /// Less than 100 bits of entropy
+    Good,
+    Excellent,
+}
+
+impl PasswordStrength {
+    fn from_bits(bits: f64) -> Self {
+        match bits {
+            b if b < 40.0 => PasswordStrength::Poor,
+            b if b < 75.0 => PasswordStrength::Weak,
+            b if b < 100.0 => PasswordStrength::Good,
+            _ => PasswordStrength::Excellent,
+        }
+    }
+}
+
+/// Settings for checking the health of a database
+#[derive(Debug, Clone)]
+pub struct HealthOptions {
+    /// Passwords rated below this strength are reported as weak
+    pub min_strength: PasswordStrength,
+
+    /// Passwords with fewer characters are reported as weak, whatever their strength
+    pub min_length: usize,
+
+    /// Entries expiring within this time from now are reported as expiring soon
+    pub expiry_warning: chrono::Duration,
+}
+
+impl Default for HealthOptions {
+    fn default() -> Self {
+        HealthOptions {
+            min_strength: PasswordStrength::Good,
+            min_length: 8,
+            expiry_warning: chrono::Duration::days(14),
+        }
+    }
+}
+
+/// An entry with a weak password
+#[derive(Debug, Clone, PartialEq)]
+pub struct WeakPassword {
+    pub entry: Uuid,
+    pub strength: PasswordStrength,
+
+    /// The estimated entropy of the password in bits
+    pub bits: f64,
+
+    /// The number of characters of the password
+    pub length: usize,
+}
+
+/// The entries that need attention, in database order
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HealthReport {
+    /// Entries sharing the same password, one list per password
+    pub reused: Vec<Vec<Uuid>>,
+
+    pub weak: Vec<WeakPassword>,
+
+    /// Entries without a password
+    pub empty: Vec<Uuid>,
+
+    pub expired: Vec<Uuid>,
+
+    /// Entries expiring within `HealthOptions::expiry_warning`, with their expiry time
+    pub expiring: Vec<(Uuid, NaiveDateTime)>,
+}
+
+impl HealthReport {
+    /// Whether no entry needs attention
+    pub fn is_healthy(&self) -> bool {
+        self.reused.is_empty()
+            && self.weak.is_empty()
+            && self.empty.is_empty()
+            && self.expired.is_empty()
+            && self.expiring.is_empty()
+    }
+}
+
+/// Check the health of all entries in the database outside of the recycle bin
+pub fn health_report(db: &Database, options: &HealthOptions) -> HealthReport {
+    let mut report = HealthReport::default();
+    let mut by_password: HashMap<[u8; 32], usize> = HashMap::new();
+    let now = Times::now();
+
+    with_audit_context("health report", || {
+        let mut entries = Vec::new();
+        collect_entries(&db.root, db.meta.recyclebin_uuid, &mut entries);
+
+        for entry in entries {
+            match entry.times.expires_at() {
+                Some(expiry) if expiry <= now => report.expired.push(entry.uuid),
+                Some(expiry) if expiry <= now + options.expiry_warning => {
+                    report.expiring.push((entry.uuid, expiry))
+                }
+                _ => {}
+            }
+
+            let password = entry.get_protected("Password");
+            let password = match password.as_ref().and_then(|p| p.as_str()) {
+                Some(password) if !password.is_empty() => password,
+                _ => {
+                    report.empty.push(entry.uuid);
+                    continue;
+                }
+            };
+
+            // only a hash of each password is kept while looking for reused ones
+            let hash: [u8; 32] = Sha256::digest(password.as_bytes()).into();
+            match by_password.get(&hash) {
+                Some(&index) => report.reused[index].push(entry.uuid),
+                None => {
+                    by_password.insert(hash, report.reused.len());
+                    report.reused.push(vec![entry.uuid]);
+                }
+            }
+
+            let (strength, bits) = rate_password(entry, password);
+            let length = password.chars().count();
+            if strength < options.min_strength || length < options.min_length {
+                report.weak.push(WeakPassword {
+                    entry: entry.uuid,
+                    strength,
+                    bits,
+                    length,
+                });
+            }
+        }
+    });
+
+    report.reused.retain(|entries| entries.len() > 1);
+    report
+}
+
+/// An entry whose password appeared in a known data breach
+#[cfg(feature = "hibp")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PwnedPassword {
+    pub entry: Uuid,
+
+    /// How often the password appeared in the breaches known to Have I Been Pwned
+    pub count: u64,
+}
+
+/// Find the entries whose passwords appeared in data breaches, in the order of `entries`.
+///
+/// `range_lookup` is called once per distinct five-digit hash prefix with the prefix in upper
+/// case, and returns the body of the response of `https://api.pwnedpasswords.com/range/{prefix}`:
+/// lines of the remaining 35 hex digits of a hash and its count, separated by a colon.
+#[cfg(feature = "hibp")]
+pub fn check_pwned<'a, E>(
+    entries: impl IntoIterator<Item = &'a Entry>,
+    mut range_lookup: impl FnMut(&str) -> Result<String, E>,
+) -> Result<Vec<PwnedPassword>, E> {
+    use sha1::Sha1;
+
+    let mut ranges: HashMap<String, HashMap<String, u64>> = HashMap::new();
+    let mut pwned = Vec::new();
+
+    with_audit_context("breach check", || {
+        for entry in entries {
+            let hash = match entry.get_protected("Password") {
+                Some(password) if !password.is_empty() => hex::encode_upper(Sha1::digest(&*password)),
+                _ => continue,
+            };
+            let (prefix, suffix) = hash.split_at(5);
+
+            if !ranges.contains_key(prefix) {
+                let response = range_lookup(prefix)?;
+                ranges.insert(prefix.to_string(), parse_range(&response));
+            }
+            // padded responses list made up hashes with a count of zero
+            match ranges[prefix].get(suffix) {
+                Some(&count) if count > 0 => pwned.push(PwnedPassword {
+                    entry: entry.uuid,
+                    count,
+                }),
+                _ => {}
+            }
+        }
+        Ok(pwned)
+    })
+}
+
+/// The hash suffixes and their counts in a response of the range API
+#[cfg(feature = "hibp")]
+fn parse_range(response: &str) -> HashMap<String, u64> {
+    response
+        .lines()
+        .filter_map(|line| {
+            let (suffix, count) = line.trim().split_once(':')?;
+            Some((suffix.to_ascii_uppercase(), count.trim().parse().ok()?))
+        })
+        .collect()
+}
+
+/// Estimate the strength of the password of an entry
+fn rate_password(entry: &Entry, password: &str) -> (PasswordStrength, f64) {
+    let bits = estimate_password_entropy(password);
+
+    let lowercase = password.to_lowercase();
+    let stem = lowercase.trim_end_matches(|c: char| c.is_ascii_digit() || c.is_ascii_punctuation());
+    let is_common = COMMON_PASSWORDS.contains(&stem);
+    let is_personal = [entry.get_title(), entry.get_username()]
+        .iter()
+        .flatten()
+        .any(|text| text.to_lowercase() == lowercase);
+
+    if is_common || is_personal {
+        (PasswordStrength::Poor, bits)
+    } else {
+        (PasswordStrength::from_bits(bits), bits)
+    }
+}
+
+/// The entries of a group and its subgroups that are checked, skipping the recycle bin and entries
+/// excluded from reports
+fn collect_entries<'a>(group: &'a Group, recycle_bin: Option<Uuid>, out: &mut Vec<&'a Entry>) {
+    if Some(group.uuid) == recycle_bin {
+        return;
+    }
+
+    for entry in group.entries() {
+        let known_bad = entry
+            .custom_data
+            .items
+            .get(KNOWN_BAD_KEY)
+            .and_then(|item| item.value.as_ref())
+            .is_some_and(|value| matches!(value, Value::Unprotected(v) if v.eq_ignore_ascii_case("true")));
+        if !known_bad && entry.quality_check != Some(false) {
+            out.push(entry);
+        }
+    }
+
+    for child in group.groups() {
+        collect_entries(child, recycle_bin, out);
+    }
+}
+
+#[cfg(test)]
+mod analysis_tests {
+    #[cfg(feature = "hibp")]
+    use super::check_pwned;
+    use chrono::Duration;
+
+    use super::{health_report, HealthOptions, PasswordStrength, KNOWN_BAD_KEY};
+    use crate::db::{CustomDataItem, Database, Entry, Group, Times, Value};
+
+    #[test]
+    fn test_health_report() {
+        let mut db = Database::new(Default::default());
+        let strong = "Xk9#mQ2$vL7@pR4!wT6&zB3^";
+
+        let reused_a = Entry::new().with_title("A").with_password(strong);
+        let reused_b = Entry::new().with_title("B").with_password(strong);
+        let common = Entry::new().with_title("C").with_password("Password123!");
+        let personal = Entry::new()
+            .with_title("D")
+            .with_username("jdoe1984x")
+            .with_password("JDoe1984X");
+        let mut short = Entry::new().with_title("E").with_password("x9#Lq");
+        short.times.expires = true;
+        short.times.set_expiry(Times::now() - Duration::days(1));
+        let mut empty = Entry::new().with_title("F");
+        empty.times.expires = true;
+        empty.times.set_expiry(Times::now() + Duration::days(3));
+        let (ids, expiry) = (
+            [
+                reused_a.uuid,
+                reused_b.uuid,
+                common.uuid,
+                personal.uuid,
+                short.uuid,
+                empty.uuid,
+            ],
+            *empty.get_expiry_time().unwrap(),
+        );
+        for entry in [reused_a, reused_b, common, personal, short, empty] {
+            db.root.add_child(entry);
+        }
+
+        // excluded entries are not checked
+        let mut known_bad = Entry::new().with_password("hunter2");
+        known_bad.custom_data.items.insert(
+            KNOWN_BAD_KEY.to_string(),
+            CustomDataItem {
+                value: Some(Value::Unprotected("true".to_string())),
+                last_modification_time: None,
+            },
+        );
+        db.root.add_child(known_bad);
+        let mut unchecked = Entry::new().with_password("hunter2");
+        unchecked.quality_check = Some(false);
+        db.root.add_child(unchecked);
+        let mut trash = Group::new("Recycle Bin");
+        trash.add_child(Entry::new().with_password(strong));
+        db.meta.recyclebin_uuid = Some(trash.uuid);
+        db.root.add_child(trash);
+
+        // the 0001-01-01 placeholder some clients write is not an expiry
+        let mut placeholder = Entry::new().with_password("Qv8&nW3!hZ5#cJ1@yF7$");
+        placeholder.times.expires = true;
+        placeholder.times.set_expiry(crate::xml_db::get_epoch_baseline());
+        db.root.add_child(placeholder);
+
+        let report = health_report(&db, &HealthOptions::default());
+        assert_eq!(report.reused, vec![vec![ids[0], ids[1]]]);
+        let weak: Vec<_> = report.weak.iter().map(|w| (w.entry, w.strength)).collect();
+        assert_eq!(
+            weak,
+            vec![
+                (ids[2], PasswordStrength::Poor),
+                (ids[3], PasswordStrength::Poor),
+                (ids[4], PasswordStrength::Poor),
+            ]
+        );
+        assert_eq!(report.weak[2].length, 5);
+        assert_eq!(report.empty, vec![ids[5]]);
+        assert_eq!(report.expired, vec![ids[4]]);
+        assert_eq!(report.expiring, vec![(ids[5], expiry)]);
+        assert!(!report.is_healthy());
+
+        let empty = Database::new(Default::default());
+        assert!(health_report(&empty, &HealthOptions::default()).is_healthy());
+    }
+
+    #[cfg(feature = "hibp")]
+    #[test]
+    fn test_check_pwned() {
+        let pwned = Entry::new().with_password("password");
+        let other = Entry::new().with_password("password1");
+        let unknown = Entry::new().with_password("Xk9#mQ2$vL7@pR4!wT6&zB3^");
+        let reused = Entry::new().with_password("password");
+        let empty = Entry::new();
+
+        let mut lookups = Vec::new();
+        let found = check_pwned([&pwned, &other, &unknown, &reused, &empty], |prefix| {
+            lookups.push(prefix.to_string());
+            Ok::<_, ()>(match prefix {
+                // the prefixes of "password", with a padding line, and of "password1"
+                "5BAA6" => {
+                    "1e4c9b93f3f0682250b6cf8331b7ee68fd8:3\r\nFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF:0".to_string()
+                }
+                "E38AD" => "214943D8DC7D6C7F5B8C4B6CC3DCB6E13E2:5".to_string(),
+                _ => String::new(),
+            })
+        })
+        .unwrap();
+
+        assert_eq!(lookups.len(), 3);
+        let found: Vec<_> = found.iter().map(|p| (p.entry, p.count)).collect();
+        assert_eq!(found, vec![(pwned.uuid, 3), (reused.uuid, 3)]);
+
+        assert!(check_pwned([&pwned], |_| Err("offline")).is_err());
+    }
+}
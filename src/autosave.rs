@@ -0,0 +1,423 @@
+//! Save a database in the background after it was changed
+//!
+//! Daemons and long-running frontends keep a database open and edit it from several threads. An
+//! `Autosave` owns a background thread that saves the shared database a short delay after it was
+//! marked as changed, so that bursts of edits are saved once. Failed saves are retried with
+//! exponential backoff, and the last error is kept until the application picks it up.
+//!
+//! ```no_run
+//! use std::sync::{Arc, Mutex};
+//!
+//! use keepass::{
+//!     autosave::{save_to_file, Autosave, AutosaveOptions},
+//!     lock::{DatabaseLock, LockOptions},
+//!     Database, DatabaseKey,
+//! };
+//!
+//! let path = "my_database.kdbx";
+//! let key = DatabaseKey::new().with_password("correct horse battery staple");
+//! let lock = DatabaseLock::acquire(path, LockOptions::default())?;
+//! let db = Database::open(&mut std::fs::File::open(path)?, key.clone())?;
+//!
+//! let autosave = Autosave::start(
+//!     Arc::new(Mutex::new(db)),
+//!     AutosaveOptions::default(),
+//!     save_to_file(path, key, Some(lock)),
+//! );
+//!
+//! autosave.edit(|db| db.meta.database_name = Some("Renamed".to_string()));
+//!
+//! // saves pending changes and reports the last error, if any
+//! autosave.stop()?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use thiserror::Error;
+
+use crate::{error::DatabaseSaveError, lock::DatabaseLock, Database, DatabaseKey};
+
+/// Errors while saving in the background
+#[derive(Debug, Error)]
+pub enum AutosaveError {
+    #[error(transparent)]
+    Save(#[from] DatabaseSaveError),
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    /// The lock on the database file was removed or taken over by another process. Autosave
+    /// stops, since saving would overwrite the changes of the new lock holder.
+    #[error("The database lock was lost")]
+    LockLost,
+}
+
+/// A function saving a snapshot of the database, e.g. `save_to_file`
+type SaveFn = Box<dyn FnMut(&Database) -> Result<(), AutosaveError> + Send>;
+
+/// Timing of background saves
+#[derive(Debug, Clone)]
+pub struct AutosaveOptions {
+    /// Time to wait after a change before saving, so that further changes are saved together
+    pub delay: Duration,
+
+    /// Time to wait before retrying after the first failed save. The time doubles with every
+    /// further failure.
+    pub initial_backoff: Duration,
+
+    /// Longest time to wait between retries
+    pub max_backoff: Duration,
+}
+
+impl Default for AutosaveOptions {
+    fn default() -> Self {
+        AutosaveOptions {
+            delay: Duration::from_secs(2),
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(300),
+        }
+    }
+}
+
+impl AutosaveOptions {
+    fn backoff(&self, failures: u32) -> Duration {
+        let factor = 2u32.saturating_pow(failures.saturating_sub(1));
+        self.initial_backoff
+            .checked_mul(factor)
+            .unwrap_or(self.max_backoff)
+            .min(self.max_backoff)
+    }
+}
+
+#[derive(Default)]
+struct State {
+    /// Incremented with every change
+    generation: u64,
+
+    /// The generation of the last saved snapshot
+    saved: u64,
+
+    failures: u32,
+    error: Option<AutosaveError>,
+
+    /// Set after an error that retrying cannot fix
+    halted: bool,
+    stopping: bool,
+}
+
+struct Shared {
+    db: Arc<Mutex<Database>>,
+    state: Mutex<State>,
+    wake: Condvar,
+}
+
+impl Shared {
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// A background thread saving a shared database after changes. Dropping it stops the thread
+/// after saving pending changes, discarding any error.
+pub struct Autosave {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Autosave {
+    /// Start saving `db` with `save` whenever it is marked as changed
+    pub fn start(
+        db: Arc<Mutex<Database>>,
+        options: AutosaveOptions,
+        save: impl FnMut(&Database) -> Result<(), AutosaveError> + Send + 'static,
+    ) -> Self {
+        let shared = Arc::new(Shared {
+            db,
+            state: Mutex::new(State::default()),
+            wake: Condvar::new(),
+        });
+
+        let thread_shared = shared.clone();
+        let save: SaveFn = Box::new(save);
+        let thread = std::thread::spawn(move || run(&thread_shared, &options, save));
+
+        Autosave {
+            shared,
+            thread: Some(thread),
+        }
+    }
+
+    /// The shared database
+    pub fn database(&self) -> &Arc<Mutex<Database>> {
+        &self.shared.db
+    }
+
+    /// Record that the database was changed. Call this after every change made through
+    /// `database()`, after releasing the database mutex.
+    pub fn mark_dirty(&self) {
+        self.shared.state().generation += 1;
+        self.shared.wake.notify_all();
+    }
+
+    /// Change the database and mark it as changed
+    pub fn edit<R>(&self, f: impl FnOnce(&mut Database) -> R) -> R {
+        let result = {
+            let mut db = self.shared.db.lock().unwrap_or_else(|e| e.into_inner());
+            f(&mut db)
+        };
+        self.mark_dirty();
+        result
+    }
+
+    /// Whether there are changes that were not saved yet
+    pub fn is_dirty(&self) -> bool {
+        let state = self.shared.state();
+        state.saved != state.generation
+    }
+
+    /// Whether saving stopped after an error that retrying cannot fix, such as
+    /// `AutosaveError::LockLost`
+    pub fn is_halted(&self) -> bool {
+        self.shared.state().halted
+    }
+
+    /// Take the error of the last failed save, if it has not been taken yet
+    pub fn take_error(&self) -> Option<AutosaveError> {
+        self.shared.state().error.take()
+    }
+
+    /// Stop the background thread after saving pending changes. Returns the error of the last
+    /// failed save, if it has not been taken yet.
+    pub fn stop(mut self) -> Result<(), AutosaveError> {
+        self.shutdown();
+        match self.take_error() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    fn shutdown(&mut self) {
+        self.shared.state().stopping = true;
+        self.shared.wake.notify_all();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for Autosave {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+fn run(shared: &Shared, options: &AutosaveOptions, mut save: SaveFn) {
+    let mut state = shared.state();
+
+    loop {
+        let dirty = state.saved != state.generation && !state.halted;
+        if state.stopping {
+            if dirty {
+                drop(state);
+                save_snapshot(shared, &mut save);
+            }
+            return;
+        }
+        if !dirty {
+            state = shared.wake.wait(state).unwrap_or_else(|e| e.into_inner());
+            continue;
+        }
+
+        // wait for further changes, or for the backoff after a failure
+        let wait = match state.failures {
+            0 => options.delay,
+            n => options.backoff(n),
+        };
+        let deadline = Instant::now() + wait;
+        while !state.stopping {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            state = shared
+                .wake
+                .wait_timeout(state, deadline - now)
+                .unwrap_or_else(|e| e.into_inner())
+                .0;
+        }
+        if state.stopping {
+            continue;
+        }
+
+        drop(state);
+        save_snapshot(shared, &mut save);
+        state = shared.state();
+    }
+}
+
+/// Save a copy of the database, so that it can be edited while the copy is encrypted
+fn save_snapshot(shared: &Shared, save: &mut SaveFn) {
+    // changes marked after this point are saved by the next run
+    let generation = shared.state().generation;
+    let snapshot = shared.db.lock().unwrap_or_else(|e| e.into_inner()).clone();
+
+    let result = save(&snapshot);
+
+    let mut state = shared.state();
+    match result {
+        Ok(()) => {
+            state.saved = state.saved.max(generation);
+            state.failures = 0;
+        }
+        Err(e) => {
+            state.failures = state.failures.saturating_add(1);
+            state.halted = matches!(e, AutosaveError::LockLost);
+            state.error = Some(e);
+        }
+    }
+}
+
+/// Save to a file at `path`, replacing it atomically. If a lock is given, saving fails with
+/// `AutosaveError::LockLost` once the lock is no longer held, and the lock is released when the
+/// autosave stops.
+pub fn save_to_file(
+    path: impl AsRef<Path>,
+    key: DatabaseKey,
+    lock: Option<DatabaseLock>,
+) -> impl FnMut(&Database) -> Result<(), AutosaveError> + Send + 'static {
+    let path = path.as_ref().to_path_buf();
+
+    move |db: &Database| {
+        if lock.as_ref().is_some_and(|lock| !lock.is_held()) {
+            return Err(AutosaveError::LockLost);
+        }
+
+        let mut temp = PathBuf::from(path.as_os_str());
+        temp.set_extension("autosave.tmp");
+
+        let result = (|| -> Result<(), AutosaveError> {
+            let mut file = std::fs::File::create(&temp)?;
+            db.save(&mut file, key.clone())?;
+            file.sync_all()?;
+            std::fs::rename(&temp, &path)?;
+            Ok(())
+        })();
+        if result.is_err() {
+            let _ = std::fs::remove_file(&temp);
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod autosave_tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use crate::{
+        lock::{lock_path, DatabaseLock, LockOptions},
+        Database, DatabaseKey,
+    };
+
+    use super::{save_to_file, Autosave, AutosaveError, AutosaveOptions};
+
+    fn options() -> AutosaveOptions {
+        AutosaveOptions {
+            delay: Duration::from_millis(20),
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(40),
+        }
+    }
+
+    fn wait_for(condition: impl Fn() -> bool) {
+        for _ in 0..500 {
+            if condition() {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        panic!("Timed out");
+    }
+
+    #[test]
+    fn test_autosave_retries() {
+        let saved_names = Arc::new(Mutex::new(Vec::new()));
+        let attempts = Arc::new(Mutex::new(0));
+
+        let names = saved_names.clone();
+        let counter = attempts.clone();
+        let autosave = Autosave::start(
+            Arc::new(Mutex::new(Database::new(Default::default()))),
+            options(),
+            move |db| {
+                *counter.lock().unwrap() += 1;
+                if *counter.lock().unwrap() <= 2 {
+                    return Err(std::io::Error::other("disk full").into());
+                }
+                names.lock().unwrap().push(db.meta.database_name.clone());
+                Ok(())
+            },
+        );
+        assert!(!autosave.is_dirty());
+
+        autosave.edit(|db| db.meta.database_name = Some("first".to_string()));
+        autosave.edit(|db| db.meta.database_name = Some("second".to_string()));
+        assert!(autosave.is_dirty());
+
+        wait_for(|| !autosave.is_dirty());
+        assert_eq!(*attempts.lock().unwrap(), 3);
+        assert_eq!(*saved_names.lock().unwrap(), vec![Some("second".to_string())]);
+        assert!(matches!(autosave.take_error(), Some(AutosaveError::Io(_))));
+        assert!(autosave.take_error().is_none());
+
+        // pending changes are saved when stopping
+        autosave.edit(|db| db.meta.database_name = Some("third".to_string()));
+        autosave.stop().unwrap();
+        assert_eq!(
+            saved_names.lock().unwrap().last().unwrap().as_deref(),
+            Some("third")
+        );
+    }
+
+    #[test]
+    fn test_autosave_lock_lost() {
+        let path = std::env::temp_dir().join(format!("keepass-autosave-{}.kdbx", uuid::Uuid::new_v4()));
+        let key = DatabaseKey::new().with_password("autosave");
+        let lock = DatabaseLock::acquire(&path, LockOptions::default()).unwrap();
+
+        let autosave = Autosave::start(
+            Arc::new(Mutex::new(Database::new(Default::default()))),
+            options(),
+            save_to_file(&path, key.clone(), Some(lock)),
+        );
+        autosave.edit(|db| db.meta.database_name = Some("saved".to_string()));
+        wait_for(|| !autosave.is_dirty());
+
+        let db = Database::open(&mut std::fs::File::open(&path).unwrap(), key).unwrap();
+        assert_eq!(db.meta.database_name.as_deref(), Some("saved"));
+
+        // another process takes over the lock
+        std::fs::write(
+            lock_path(&path),
+            "pid=1\nuser=other\nhost=other\ncreated=2000-01-01T00:00:00\n",
+        )
+        .unwrap();
+        autosave.edit(|db| db.meta.database_name = Some("not saved".to_string()));
+        wait_for(|| autosave.is_halted());
+        assert!(autosave.is_dirty());
+        assert!(matches!(autosave.stop(), Err(AutosaveError::LockLost)));
+
+        // the foreign lock is left in place
+        assert!(lock_path(&path).exists());
+        std::fs::remove_file(lock_path(&path)).unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -4,7 +4,7 @@ use std::fs::File;
 use anyhow::Result;
 use clap::Parser;
 
-use keepass::{Database, DatabaseKey};
+use keepass::{db::reveal_protected_values, Database, DatabaseKey};
 
 #[derive(Parser, Debug)]
 #[command(version, about)]
@@ -19,6 +19,10 @@ struct Args {
     /// Do not use a password to decrypt the database
     #[arg(short = 'n', long)]
     no_password: bool,
+
+    /// Include the values of protected fields like passwords
+    #[arg(short = 'r', long)]
+    reveal_protected: bool,
 }
 
 pub fn main() -> Result<()> {
@@ -42,7 +46,11 @@ pub fn main() -> Result<()> {
     let db = Database::open(&mut source, key)?;
 
     let stdout = std::io::stdout().lock();
-    serde_json::ser::to_writer(stdout, &db)?;
+    if args.reveal_protected {
+        reveal_protected_values(|| serde_json::ser::to_writer(stdout, &db))?;
+    } else {
+        serde_json::ser::to_writer(stdout, &db)?;
+    }
 
     Ok(())
 }
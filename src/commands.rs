@@ -0,0 +1,646 @@
+//! Edits to a database expressed as data
+//!
+//! A `Command` describes a single change to a database. Commands can be validated against a
+//! database, applied to it or dry-run on a copy of it. With the `serialization` feature, commands
+//! can be serialized to build audit logs of changes or to be replayed later.
+//!
+//! ```
+//! use keepass::{
+//!     commands::{apply_all, Command, FieldValue},
+//!     Database,
+//! };
+//! use uuid::Uuid;
+//!
+//! let mut db = Database::new(Default::default());
+//! let entry = Uuid::new_v4();
+//!
+//! let commands = vec![
+//!     Command::AddEntry {
+//!         parent: db.root.uuid,
+//!         uuid: entry,
+//!         fields: [("Title".to_string(), FieldValue::unprotected("Email"))].into(),
+//!     },
+//!     Command::SetField {
+//!         entry,
+//!         field: "Password".to_string(),
+//!         value: FieldValue::protected("hunter2"),
+//!     },
+//! ];
+//!
+//! for command in &commands {
+//!     println!("{}", command);
+//! }
+//!
+//! apply_all(&mut db, &commands).unwrap();
+//! assert_eq!(db.root.entries()[0].get_protected("Password").unwrap().as_str(), Some("hunter2"));
+//! ```
+
+use std::collections::BTreeMap;
+
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::db::{Database, DeletedObject, Entry, Filter, Node, Times, ValidationRule, Value, Violation};
+
+/// Errors while validating or applying a command
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum CommandError {
+    #[error("Could not find group {0}")]
+    GroupNotFound(Uuid),
+
+    #[error("Could not find entry {0}")]
+    EntryNotFound(Uuid),
+
+    #[error("A node with UUID {0} already exists")]
+    DuplicateUuid(Uuid),
+
+    #[error("The root group cannot be moved")]
+    CannotMoveRoot,
+
+    #[error("Group {group} cannot be moved into its own subtree at {parent}")]
+    MoveIntoOwnSubtree { group: Uuid, parent: Uuid },
+
+    #[error("Entry {entry} is managed by {manager} and cannot be edited manually")]
+    ManagedEntry { entry: Uuid, manager: String },
+
+    #[error("The edit violates {} validation rules", .0.len())]
+    Invalid(Vec<Violation>),
+}
+
+/// Who applies a command, for checking edits of entries that are managed by automation (see
+/// `Entry::managed_by`)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Editor {
+    /// A person editing the database. Edits of managed entries are rejected.
+    User,
+
+    /// An automation with the given name, which may edit unmanaged entries and the entries it
+    /// manages itself
+    Automation(String),
+}
+
+/// The value of a field set by a command. Protected values are kept like those of entries: they
+/// are left out of `Debug` and only serialized inside `reveal_protected_values`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serialization", derive(serde::Serialize, serde::Deserialize))]
+pub struct FieldValue(Value);
+
+impl FieldValue {
+    pub fn unprotected(value: &str) -> Self {
+        FieldValue(Value::Unprotected(value.to_string()))
+    }
+
+    pub fn protected(value: &str) -> Self {
+        FieldValue(Value::Protected(value.into()))
+    }
+
+    pub fn is_protected(&self) -> bool {
+        matches!(self.0, Value::Protected(_))
+    }
+
+    fn to_value(&self) -> Value {
+        self.0.clone()
+    }
+}
+
+/// A single edit of a database
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serialization", derive(serde::Serialize, serde::Deserialize))]
+pub enum Command {
+    /// Create a new entry with the given fields in a group
+    AddEntry {
+        parent: Uuid,
+        uuid: Uuid,
+        fields: BTreeMap<String, FieldValue>,
+    },
+
+    /// Set a field of an entry, creating the field if needed
+    SetField {
+        entry: Uuid,
+        field: String,
+        value: FieldValue,
+    },
+
+    /// Move a group (and everything in it) into another group
+    MoveGroup { group: Uuid, new_parent: Uuid },
+
+    /// Delete an entry, recording it as a deleted object
+    DeleteEntry { entry: Uuid },
+}
+
+impl Command {
+    /// Check whether the command could be applied to the database, without changing it
+    pub fn validate(&self, db: &Database) -> Result<(), CommandError> {
+        match self {
+            Command::AddEntry { parent, uuid, .. } => {
+                db.root
+                    .group_by_uuid(parent)
+                    .ok_or(CommandError::GroupNotFound(*parent))?;
+                if db.root.find_by_uuid(uuid).is_some() {
+                    return Err(CommandError::DuplicateUuid(*uuid));
+                }
+            }
+            Command::SetField { entry, .. } | Command::DeleteEntry { entry } => {
+                db.root
+                    .entry_by_uuid(entry)
+                    .ok_or(CommandError::EntryNotFound(*entry))?;
+            }
+            Command::MoveGroup { group, new_parent } => {
+                if *group == db.root.uuid {
+                    return Err(CommandError::CannotMoveRoot);
+                }
+                let moved = db
+                    .root
+                    .group_by_uuid(group)
+                    .ok_or(CommandError::GroupNotFound(*group))?;
+                db.root
+                    .group_by_uuid(new_parent)
+                    .ok_or(CommandError::GroupNotFound(*new_parent))?;
+                if moved.group_by_uuid(new_parent).is_some() {
+                    return Err(CommandError::MoveIntoOwnSubtree {
+                        group: *group,
+                        parent: *new_parent,
+                    });
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check whether the command could be applied to the database by `editor`, rejecting edits of
+    /// entries that are managed by another automation
+    pub fn validate_as(&self, db: &Database, editor: &Editor) -> Result<(), CommandError> {
+        self.validate(db)?;
+
+        if let Command::SetField { entry, .. } | Command::DeleteEntry { entry } = self {
+            let managed_by = db.root.entry_by_uuid(entry).and_then(|e| e.managed_by());
+            if let Some(manager) = managed_by {
+                if *editor != Editor::Automation(manager.to_string()) {
+                    return Err(CommandError::ManagedEntry {
+                        entry: *entry,
+                        manager: manager.to_string(),
+                    });
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Validate the command for `editor` and apply it to the database
+    pub fn apply_as(&self, db: &mut Database, editor: &Editor) -> Result<(), CommandError> {
+        self.validate_as(db, editor)?;
+        self.apply(db)
+    }
+
+    /// Validate and apply the command to the database
+    pub fn apply(&self, db: &mut Database) -> Result<(), CommandError> {
+        self.validate(db)?;
+
+        match self {
+            Command::AddEntry { parent, uuid, fields } => {
+                let mut entry = Entry::new();
+                entry.uuid = *uuid;
+                for (name, value) in fields {
+                    entry.fields.insert(name.clone(), value.to_value());
+                }
+                entry.update_history();
+
+                db.root
+                    .group_by_uuid_mut(parent)
+                    .ok_or(CommandError::GroupNotFound(*parent))?
+                    .add_child(entry);
+            }
+            Command::SetField { entry, field, value } => {
+                let entry = db
+                    .root
+                    .entry_by_uuid_mut(entry)
+                    .ok_or(CommandError::EntryNotFound(*entry))?;
+                entry.fields.insert(field.clone(), value.to_value());
+                entry.update_history();
+            }
+            Command::MoveGroup { group, new_parent } => {
+                let mut node = db
+                    .root
+                    .take_by_uuid(group)
+                    .ok_or(CommandError::GroupNotFound(*group))?;
+                if let Node::Group(ref mut g) = node {
+                    g.times.set_location_changed(Times::now());
+                }
+
+                db.root
+                    .group_by_uuid_mut(new_parent)
+                    .ok_or(CommandError::GroupNotFound(*new_parent))?
+                    .add_child(node);
+            }
+            Command::DeleteEntry { entry } => {
+                db.root
+                    .take_by_uuid(entry)
+                    .ok_or(CommandError::EntryNotFound(*entry))?;
+                db.deleted_objects.objects.push(DeletedObject {
+                    uuid: *entry,
+                    deletion_time: Times::now(),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Apply the command to a copy of the database and return the result, leaving the original
+    /// database untouched
+    pub fn dry_run(&self, db: &Database) -> Result<Database, CommandError> {
+        let mut copy = db.clone();
+        self.apply(&mut copy)?;
+        Ok(copy)
+    }
+}
+
+/// Describes the command for audit logs. The values of protected fields are not included.
+impl std::fmt::Display for Command {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Command::AddEntry { parent, uuid, fields } => {
+                let names: Vec<&str> = fields.keys().map(|k| k.as_str()).collect();
+                write!(
+                    f,
+                    "Add entry {} to group {} with fields [{}]",
+                    uuid,
+                    parent,
+                    names.join(", ")
+                )
+            }
+            Command::SetField {
+                entry,
+                field,
+                value: FieldValue(Value::Unprotected(value)),
+            } => {
+                write!(f, "Set field {} of entry {} to {:?}", field, entry, value)
+            }
+            Command::SetField { entry, field, .. } => {
+                write!(f, "Set protected field {} of entry {}", field, entry)
+            }
+            Command::MoveGroup { group, new_parent } => {
+                write!(f, "Move group {} into group {}", group, new_parent)
+            }
+            Command::DeleteEntry { entry } => write!(f, "Delete entry {}", entry),
+        }
+    }
+}
+
+/// Apply a list of commands in order. If any of them fails, the database is left untouched.
+pub fn apply_all(db: &mut Database, commands: &[Command]) -> Result<(), CommandError> {
+    *db = dry_run_all(db, commands)?;
+    Ok(())
+}
+
+/// Apply a list of commands in order to a copy of the database and return the result
+pub fn dry_run_all(db: &Database, commands: &[Command]) -> Result<Database, CommandError> {
+    let mut copy = db.clone();
+    for command in commands {
+        command.apply(&mut copy)?;
+    }
+    Ok(copy)
+}
+
+/// Apply a list of commands in order on behalf of `editor`. If any of them fails or edits an entry
+/// the editor may not change, the database is left untouched.
+pub fn apply_all_as(db: &mut Database, commands: &[Command], editor: &Editor) -> Result<(), CommandError> {
+    let mut copy = db.clone();
+    for command in commands {
+        command.apply_as(&mut copy, editor)?;
+    }
+    *db = copy;
+    Ok(())
+}
+
+/// Apply a list of commands in order, rejecting them if an entry they add or change violates any
+/// of the rules afterwards. Violations of other entries do not block the edit. If the commands are
+/// rejected, the database is left untouched.
+pub fn apply_all_validated(
+    db: &mut Database,
+    commands: &[Command],
+    rules: &[ValidationRule],
+) -> Result<(), CommandError> {
+    let copy = dry_run_all(db, commands)?;
+
+    let touched: Vec<Uuid> = commands
+        .iter()
+        .filter_map(|command| match command {
+            Command::AddEntry { uuid, .. } => Some(*uuid),
+            Command::SetField { entry, .. } => Some(*entry),
+            Command::MoveGroup { .. } | Command::DeleteEntry { .. } => None,
+        })
+        .collect();
+    let violations: Vec<Violation> = copy
+        .validate_against(rules)
+        .into_iter()
+        .filter(|v| touched.contains(&v.entry))
+        .collect();
+    if !violations.is_empty() {
+        return Err(CommandError::Invalid(violations));
+    }
+
+    *db = copy;
+    Ok(())
+}
+
+/// Build the commands of a bulk edit: `edit` is called with every entry matching the filter and
+/// returns the commands for it. The result can be reviewed with `dry_run_all` and applied with
+/// `apply_all`.
+pub fn bulk(db: &Database, filter: &Filter, edit: impl FnMut(&Entry) -> Vec<Command>) -> Vec<Command> {
+    filter.select(&db.root).into_iter().flat_map(edit).collect()
+}
+
+#[cfg(test)]
+mod commands_tests {
+    use uuid::Uuid;
+
+    use crate::{
+        db::{Filter, Group, NodeRef, ValidationRule, Value},
+        Database,
+    };
+
+    use super::{
+        apply_all, apply_all_as, apply_all_validated, bulk, dry_run_all, Command, CommandError, Editor,
+        FieldValue,
+    };
+
+    fn test_database() -> (Database, Uuid, Uuid) {
+        let mut db = Database::new(Default::default());
+
+        let mut parent = Group::new("parent");
+        let child = Group::new("child");
+        let child_uuid = child.uuid;
+        let parent_uuid = parent.uuid;
+        parent.add_child(child);
+        db.root.add_child(parent);
+
+        (db, parent_uuid, child_uuid)
+    }
+
+    #[test]
+    fn test_apply() {
+        let (mut db, _, child) = test_database();
+        let entry = Uuid::new_v4();
+
+        let commands = vec![
+            Command::AddEntry {
+                parent: child,
+                uuid: entry,
+                fields: [("Title".to_string(), FieldValue::unprotected("Email"))].into(),
+            },
+            Command::SetField {
+                entry,
+                field: "Password".to_string(),
+                value: FieldValue::protected("secret"),
+            },
+            Command::MoveGroup {
+                group: child,
+                new_parent: db.root.uuid,
+            },
+        ];
+        apply_all(&mut db, &commands).unwrap();
+
+        match db.root.get(&["child", "Email"]) {
+            Some(NodeRef::Entry(e)) => {
+                assert_eq!(e.get_password(), Some("secret"));
+                assert_eq!(e.history.as_ref().unwrap().get_entries().len(), 2);
+            }
+            _ => panic!("Expected the entry to be moved with its group"),
+        }
+        assert!(db.root.get(&["parent", "child"]).is_none());
+
+        Command::DeleteEntry { entry }.apply(&mut db).unwrap();
+        assert!(db.root.get(&["child", "Email"]).is_none());
+        assert!(db.deleted_objects.contains(entry));
+    }
+
+    #[test]
+    fn test_validate() {
+        let (db, parent, child) = test_database();
+        let missing = Uuid::new_v4();
+
+        assert_eq!(
+            Command::DeleteEntry { entry: missing }.validate(&db),
+            Err(CommandError::EntryNotFound(missing))
+        );
+        assert_eq!(
+            Command::AddEntry {
+                parent: missing,
+                uuid: Uuid::new_v4(),
+                fields: Default::default(),
+            }
+            .validate(&db),
+            Err(CommandError::GroupNotFound(missing))
+        );
+        assert_eq!(
+            Command::AddEntry {
+                parent,
+                uuid: child,
+                fields: Default::default(),
+            }
+            .validate(&db),
+            Err(CommandError::DuplicateUuid(child))
+        );
+        assert_eq!(
+            Command::MoveGroup {
+                group: parent,
+                new_parent: child,
+            }
+            .validate(&db),
+            Err(CommandError::MoveIntoOwnSubtree {
+                group: parent,
+                parent: child
+            })
+        );
+        assert_eq!(
+            Command::MoveGroup {
+                group: db.root.uuid,
+                new_parent: child,
+            }
+            .validate(&db),
+            Err(CommandError::CannotMoveRoot)
+        );
+    }
+
+    #[test]
+    fn test_dry_run() {
+        let (mut db, _, child) = test_database();
+        let original = db.clone();
+
+        let entry = Uuid::new_v4();
+        let commands = vec![
+            Command::AddEntry {
+                parent: child,
+                uuid: entry,
+                fields: Default::default(),
+            },
+            Command::DeleteEntry {
+                entry: Uuid::new_v4(),
+            },
+        ];
+
+        // the second command fails, so nothing is applied
+        assert!(dry_run_all(&db, &commands).is_err());
+        assert!(apply_all(&mut db, &commands).is_err());
+        assert_eq!(db, original);
+
+        let result = dry_run_all(&db, &commands[..1]).unwrap();
+        assert_eq!(db, original);
+        match result.root.get(&["parent", "child"]) {
+            Some(NodeRef::Group(g)) => assert_eq!(g.entries()[0].uuid, entry),
+            _ => panic!("Expected the child group"),
+        }
+        assert!(commands[0].dry_run(&db).is_ok());
+    }
+
+    #[test]
+    fn test_bulk() {
+        let (mut db, parent, _) = test_database();
+        let mut entries = Vec::new();
+        for (title, tag) in [("vpn", "temporary"), ("mail", "")] {
+            let uuid = Uuid::new_v4();
+            entries.push(uuid);
+            Command::AddEntry {
+                parent,
+                uuid,
+                fields: [("Title".to_string(), FieldValue::unprotected(title))].into(),
+            }
+            .apply(&mut db)
+            .unwrap();
+            db.root
+                .entry_by_uuid_mut(&uuid)
+                .unwrap()
+                .tags
+                .push(tag.to_string());
+        }
+
+        let commands = bulk(&db, &Filter::tag("temporary"), |entry| {
+            vec![Command::DeleteEntry { entry: entry.uuid }]
+        });
+        assert_eq!(commands, vec![Command::DeleteEntry { entry: entries[0] }]);
+
+        apply_all(&mut db, &commands).unwrap();
+        assert_eq!(Filter::All.select_uuids(&db.root), vec![entries[1]]);
+    }
+
+    #[test]
+    fn test_apply_all_validated() {
+        let (mut db, parent, _) = test_database();
+
+        // existing violations do not block unrelated edits
+        let mut legacy = crate::db::Entry::new();
+        let legacy_uuid = legacy.uuid;
+        legacy
+            .fields
+            .insert("URL".to_string(), Value::Unprotected("not a url".to_string()));
+        db.root.add_child(legacy);
+
+        let rules = vec![ValidationRule::url("valid-url", "URL")];
+        let entry = Uuid::new_v4();
+        let add = Command::AddEntry {
+            parent,
+            uuid: entry,
+            fields: [("URL".to_string(), FieldValue::unprotected("https://example.com"))].into(),
+        };
+        apply_all_validated(&mut db, &[add], &rules).unwrap();
+
+        let set_url = Command::SetField {
+            entry,
+            field: "URL".to_string(),
+            value: FieldValue::unprotected("example.com"),
+        };
+        let result = apply_all_validated(&mut db, &[set_url], &rules);
+        match result {
+            Err(CommandError::Invalid(violations)) => {
+                assert_eq!(violations.len(), 1);
+                assert_eq!(violations[0].entry, entry);
+            }
+            other => panic!("Expected a validation error, got {:?}", other),
+        }
+        assert_eq!(
+            db.root.entry_by_uuid(&entry).unwrap().get("URL"),
+            Some("https://example.com")
+        );
+        assert_eq!(db.validate_against(&rules)[0].entry, legacy_uuid);
+    }
+
+    #[test]
+    fn test_managed_entries() {
+        let (mut db, _, _) = test_database();
+
+        let mut managed = crate::db::Entry::new();
+        managed.set_managed_by(Some("terraform"));
+        let managed_uuid = managed.uuid;
+        db.root.add_child(managed);
+
+        let set_password = Command::SetField {
+            entry: managed_uuid,
+            field: "Password".to_string(),
+            value: FieldValue::protected("rotated"),
+        };
+
+        let expected = Err(CommandError::ManagedEntry {
+            entry: managed_uuid,
+            manager: "terraform".to_string(),
+        });
+        assert_eq!(set_password.validate_as(&db, &Editor::User), expected);
+        assert_eq!(
+            set_password.validate_as(&db, &Editor::Automation("ansible".to_string())),
+            expected
+        );
+        assert_eq!(
+            Command::DeleteEntry { entry: managed_uuid }.validate_as(&db, &Editor::User),
+            expected
+        );
+
+        // the automation managing the entry may edit it
+        let terraform = Editor::Automation("terraform".to_string());
+        apply_all_as(&mut db, &[set_password.clone()], &terraform).unwrap();
+        assert_eq!(db.root.entries()[0].get_password(), Some("rotated"));
+
+        // without a policy, the flag is not checked
+        set_password.apply(&mut db).unwrap();
+
+        db.root.entries_mut()[0].set_managed_by(None);
+        assert_eq!(db.root.entries()[0].managed_by(), None);
+        assert!(set_password.validate_as(&db, &Editor::User).is_ok());
+    }
+
+    #[test]
+    fn test_display() {
+        let entry = Uuid::nil();
+        let command = Command::SetField {
+            entry,
+            field: "Password".to_string(),
+            value: FieldValue::protected("secret"),
+        };
+        assert_eq!(
+            command.to_string(),
+            "Set protected field Password of entry 00000000-0000-0000-0000-000000000000"
+        );
+        assert!(!format!("{:?}", command).contains("secret"));
+    }
+
+    #[cfg(feature = "serialization")]
+    #[test]
+    fn test_serialization() {
+        let command = Command::MoveGroup {
+            group: Uuid::nil(),
+            new_parent: Uuid::nil(),
+        };
+        let json = serde_json::to_string(&command).unwrap();
+        assert_eq!(serde_json::from_str::<Command>(&json).unwrap(), command);
+
+        let command = Command::SetField {
+            entry: Uuid::nil(),
+            field: "Password".to_string(),
+            value: FieldValue::protected("secret"),
+        };
+        assert!(!serde_json::to_string(&command).unwrap().contains("secret"));
+        let json = crate::db::reveal_protected_values(|| serde_json::to_string(&command).unwrap());
+        assert_eq!(serde_json::from_str::<Command>(&json).unwrap(), command);
+    }
+}
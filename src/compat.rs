@@ -0,0 +1,130 @@
+//! Functions from 0.x releases that were replaced, kept working while downstream code moves to
+//! the current API
+//!
+//! Enabled with the `compat-0x` feature. Every item here is deprecated and forwards to its
+//! replacement, which the deprecation note names. The module is removed together with the
+//! feature in a future major release.
+//!
+//! This does not make the crate compatible with 0.x as a whole. Changes that cannot be shimmed
+//! still need changes to the calling code:
+//!
+//! - `Value::Protected` holds a `ProtectedValue` instead of a `SecStr`. Protected values are
+//!   built with `Value::Protected(secstr.into())` and read with `ProtectedValue::decrypt`.
+//! - Structs with public fields like `Database`, `Meta` and `SaveOptions` gained fields, so they
+//!   cannot be built as struct literals anymore. Start from `Database::new` or `Default::default()`
+//!   and assign the fields instead.
+//! - The error enums gained variants, like `DatabaseOpenError::InvalidCredentials`, so matches on
+//!   them need a wildcard arm.
+//!
+//! ```
+//! # #![allow(deprecated)]
+//! use keepass::compat;
+//!
+//! let mut file = std::fs::File::open("tests/resources/test_db_with_password.kdbx")?;
+//! let db = compat::open(&mut file, Some("demopass"), None)?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::io::Read;
+
+use crate::{error::DatabaseOpenError, Database, DatabaseKey};
+
+/// The error type of opening a database
+#[deprecated(note = "use `keepass::error::DatabaseOpenError`")]
+pub type Error = DatabaseOpenError;
+
+/// The result type of opening a database
+#[deprecated(note = "use `Result<T, keepass::error::DatabaseOpenError>`")]
+pub type Result<T> = std::result::Result<T, DatabaseOpenError>;
+
+/// Open a database from a password and a key file, the way `Database::open` took them before
+/// `DatabaseKey` existed
+#[deprecated(note = "use `Database::open` with a `DatabaseKey`")]
+pub fn open(
+    source: &mut dyn Read,
+    password: Option<&str>,
+    keyfile: Option<&mut dyn Read>,
+) -> std::result::Result<Database, DatabaseOpenError> {
+    let mut key = DatabaseKey::new();
+    if let Some(password) = password {
+        key = key.with_password(password);
+    }
+    if let Some(keyfile) = keyfile {
+        key = key.with_keyfile(keyfile)?;
+    }
+    Database::open(source, key)
+}
+
+/// A key consisting of a password, as `DatabaseKey::with_password` created it when it was a
+/// constructor
+#[deprecated(note = "use `DatabaseKey::new().with_password(password)`")]
+pub fn key_with_password(password: &str) -> DatabaseKey {
+    DatabaseKey::new().with_password(password)
+}
+
+/// A key consisting of a key file, as `DatabaseKey::with_keyfile` created it when it was a
+/// constructor
+#[deprecated(note = "use `DatabaseKey::new().with_keyfile(keyfile)`")]
+pub fn key_with_keyfile(keyfile: &mut dyn Read) -> std::result::Result<DatabaseKey, std::io::Error> {
+    DatabaseKey::new().with_keyfile(keyfile)
+}
+
+/// A key consisting of a password and a key file
+#[deprecated(note = "use `DatabaseKey::new().with_password(password).with_keyfile(keyfile)`")]
+pub fn key_with_password_and_keyfile(
+    password: &str,
+    keyfile: &mut dyn Read,
+) -> std::result::Result<DatabaseKey, std::io::Error> {
+    DatabaseKey::new().with_password(password).with_keyfile(keyfile)
+}
+
+#[cfg(test)]
+#[allow(deprecated)]
+mod compat_tests {
+    use std::fs::File;
+
+    use crate::db::Database;
+
+    // call sites as they were written against 0.x
+
+    fn open_with_password(path: &str, password: &str) -> super::Result<Database> {
+        let mut file = File::open(path)?;
+        super::open(&mut file, Some(password), None)
+    }
+
+    #[test]
+    fn test_open() {
+        let db = open_with_password("tests/resources/test_db_with_password.kdbx", "demopass").unwrap();
+        assert!(!db.root.children.is_empty());
+
+        let error: super::Error =
+            open_with_password("tests/resources/test_db_with_password.kdbx", "wrong").unwrap_err();
+        assert!(matches!(error, super::Error::InvalidCredentials));
+
+        let mut file = File::open("tests/resources/test_db_with_keyfile.kdbx").unwrap();
+        let mut keyfile = File::open("tests/resources/test_key.key").unwrap();
+        assert!(super::open(&mut file, None, Some(&mut keyfile)).is_ok());
+    }
+
+    #[test]
+    fn test_keys() {
+        let mut file = File::open("tests/resources/test_db_with_password.kdbx").unwrap();
+        let key = super::key_with_password("demopass");
+        assert!(Database::open(&mut file, key).is_ok());
+
+        let mut file = File::open("tests/resources/test_db_with_keyfile.kdbx").unwrap();
+        let mut keyfile = File::open("tests/resources/test_key.key").unwrap();
+        let key = super::key_with_keyfile(&mut keyfile).unwrap();
+        assert!(Database::open(&mut file, key).is_ok());
+
+        let mut keyfile = File::open("tests/resources/test_key.key").unwrap();
+        let key = super::key_with_password_and_keyfile("demopass", &mut keyfile).unwrap();
+        assert_eq!(
+            key,
+            crate::DatabaseKey::new()
+                .with_password("demopass")
+                .with_keyfile(&mut File::open("tests/resources/test_key.key").unwrap())
+                .unwrap()
+        );
+    }
+}
@@ -1,37 +1,85 @@
 use flate2::read::GzDecoder;
-#[cfg(feature = "save_kdbx4")]
 use flate2::write::GzEncoder;
-#[cfg(feature = "save_kdbx4")]
 use flate2::Compression as Flate2Compression;
 use std::io::Read;
-#[cfg(feature = "save_kdbx4")]
 use std::io::Write;
 
+use crate::config::DEFAULT_GZIP_LEVEL;
+
 pub trait Compression {
-    #[cfg(feature = "save_kdbx4")]
     fn compress(&self, in_buffer: &[u8]) -> Result<Vec<u8>, std::io::Error>;
     fn decompress(&self, in_buffer: &[u8]) -> Result<Vec<u8>, std::io::Error>;
+
+    /// Wrap a reader so that everything read from it is decompressed on the fly
+    fn decompress_stream<'a>(&self, reader: &'a mut dyn Read) -> Box<dyn Read + 'a>;
+
+    /// Wrap a writer so that everything written to it is compressed on the fly
+    #[cfg(feature = "save_kdbx4")]
+    fn compress_stream<'a>(&self, writer: &'a mut dyn Write) -> Box<dyn CompressWrite + 'a>;
+}
+
+/// A writer that compresses its input, see `Compression::compress_stream`
+#[cfg(feature = "save_kdbx4")]
+pub trait CompressWrite: Write {
+    /// Write the remaining compressed data
+    fn finish(self: Box<Self>) -> Result<(), std::io::Error>;
+}
+
+#[cfg(feature = "save_kdbx4")]
+impl<'a> CompressWrite for &'a mut dyn Write {
+    fn finish(mut self: Box<Self>) -> Result<(), std::io::Error> {
+        self.flush()
+    }
+}
+
+#[cfg(feature = "save_kdbx4")]
+impl<W: Write> CompressWrite for GzEncoder<W> {
+    fn finish(self: Box<Self>) -> Result<(), std::io::Error> {
+        GzEncoder::finish(*self)?.flush()
+    }
 }
 
 pub struct NoCompression;
 
 impl Compression for NoCompression {
-    #[cfg(feature = "save_kdbx4")]
     fn compress(&self, in_buffer: &[u8]) -> Result<Vec<u8>, std::io::Error> {
         Ok(in_buffer.to_vec())
     }
     fn decompress(&self, in_buffer: &[u8]) -> Result<Vec<u8>, std::io::Error> {
         Ok(in_buffer.to_vec())
     }
+
+    fn decompress_stream<'a>(&self, reader: &'a mut dyn Read) -> Box<dyn Read + 'a> {
+        Box::new(reader)
+    }
+
+    #[cfg(feature = "save_kdbx4")]
+    fn compress_stream<'a>(&self, writer: &'a mut dyn Write) -> Box<dyn CompressWrite + 'a> {
+        Box::new(writer)
+    }
+}
+
+/// GZip compression at a level from 0 (fastest, not compressing at all) to 9 (smallest, slowest)
+pub struct GZipCompression {
+    level: u32,
 }
 
-pub struct GZipCompression;
+impl GZipCompression {
+    pub fn with_level(level: u32) -> Self {
+        GZipCompression { level: level.min(9) }
+    }
+}
+
+impl Default for GZipCompression {
+    fn default() -> Self {
+        GZipCompression::with_level(DEFAULT_GZIP_LEVEL)
+    }
+}
 
 impl Compression for GZipCompression {
-    #[cfg(feature = "save_kdbx4")]
     fn compress(&self, in_buffer: &[u8]) -> Result<Vec<u8>, std::io::Error> {
         let mut res = Vec::new();
-        let mut encoder = GzEncoder::new(&mut res, Flate2Compression::default());
+        let mut encoder = GzEncoder::new(&mut res, Flate2Compression::new(self.level));
         encoder.write_all(in_buffer)?;
         encoder.flush()?;
         encoder.finish()?;
@@ -43,4 +91,13 @@ impl Compression for GZipCompression {
         decoder.read_to_end(&mut res)?;
         Ok(res)
     }
+
+    fn decompress_stream<'a>(&self, reader: &'a mut dyn Read) -> Box<dyn Read + 'a> {
+        Box::new(GzDecoder::new(reader))
+    }
+
+    #[cfg(feature = "save_kdbx4")]
+    fn compress_stream<'a>(&self, writer: &'a mut dyn Write) -> Box<dyn CompressWrite + 'a> {
+        Box::new(GzEncoder::new(writer, Flate2Compression::new(self.level)))
+    }
 }
@@ -1,7 +1,9 @@
 //! Configuration options for how to compress and encrypt databases
 use hex_literal::hex;
 
-use std::convert::TryFrom;
+use std::{convert::TryFrom, time::Duration};
+
+use cipher::generic_array::GenericArray;
 
 pub use crate::format::DatabaseVersion;
 
@@ -10,9 +12,12 @@ use crate::crypt::ciphers::Cipher;
 use crate::{
     compression,
     crypt::{
+        cipher_registry,
         ciphers::{self},
         kdf,
+        kdf_registry::{self, KdfParameters},
     },
+    db::PublicCustomData,
     error::{
         CompressionConfigError, CryptographyError, InnerCipherConfigError, KdfConfigError,
         OuterCipherConfigError,
@@ -28,12 +33,13 @@ const CIPHERSUITE_CHACHA20: [u8; 16] = hex!("d6038a2b8b6f4cb5a524339a31dbb59a");
 
 // Internal IDs for the ciphers
 const PLAIN: u32 = 0;
+const ARC_FOUR_VARIANT: u32 = 1;
 const SALSA_20: u32 = 2;
 const CHA_CHA_20: u32 = 3;
 
 /// Configuration of how a database should be stored
 #[derive(Debug, Clone, PartialEq, Eq)]
-#[cfg_attr(feature = "serialization", derive(serde::Serialize))]
+#[cfg_attr(feature = "serialization", derive(serde::Serialize, serde::Deserialize))]
 pub struct DatabaseConfig {
     /// Version of the outer database file
     pub version: DatabaseVersion,
@@ -49,6 +55,9 @@ pub struct DatabaseConfig {
 
     /// Settings for the Key Derivation Function (KDF)
     pub kdf_config: KdfConfig,
+
+    /// Optional secret key and associated data for Argon2 KDFs
+    pub argon2_secret_parameters: Argon2SecretParameters,
 }
 
 /// Sensible default configuration for new databases
@@ -65,17 +74,71 @@ impl Default for DatabaseConfig {
                 parallelism: 4,
                 version: argon2::Version::Version13,
             },
+            argon2_secret_parameters: Argon2SecretParameters::default(),
         }
     }
 }
 
+/// Revision of the settings returned by `recommended_settings`. It is increased whenever the
+/// recommendations change, so applications can offer to upgrade databases created with older ones.
+pub const RECOMMENDED_SETTINGS_REVISION: u32 = 1;
+
+/// How much time and memory opening a database may cost, see `recommended_settings`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serialization", derive(serde::Serialize, serde::Deserialize))]
+pub enum SecurityLevel {
+    /// Fast enough to unlock on phones and older laptops many times a day
+    Interactive,
+
+    /// Slower to unlock, for databases on desktop computers
+    Moderate,
+
+    /// Several seconds and 1 GiB of memory to unlock, for archives and rarely opened databases
+    Paranoid,
+}
+
+/// Vetted settings for new databases at a security level. The settings follow current guidance
+/// for Argon2id (RFC 9106) and are updated with new releases of this crate, see
+/// `RECOMMENDED_SETTINGS_REVISION`, so applications should not store them but ask again when
+/// creating a database.
+///
+/// ```
+/// use keepass::{config::{recommended_settings, SecurityLevel}, Database};
+///
+/// let db = Database::new(recommended_settings(SecurityLevel::Moderate));
+/// ```
+pub fn recommended_settings(level: SecurityLevel) -> DatabaseConfig {
+    let (iterations, memory, parallelism) = match level {
+        SecurityLevel::Interactive => (3, 64 * 1024 * 1024, 4),
+        SecurityLevel::Moderate => (4, 256 * 1024 * 1024, 4),
+        SecurityLevel::Paranoid => (8, 1024 * 1024 * 1024, 4),
+    };
+
+    DatabaseConfig {
+        version: DatabaseVersion::KDB4(KDBX4_CURRENT_MINOR_VERSION),
+        outer_cipher_config: OuterCipherConfig::AES256,
+        compression_config: CompressionConfig::GZip,
+        inner_cipher_config: InnerCipherConfig::ChaCha20,
+        kdf_config: KdfConfig::Argon2id {
+            iterations,
+            memory,
+            parallelism,
+            version: argon2::Version::Version13,
+        },
+        argon2_secret_parameters: Argon2SecretParameters::default(),
+    }
+}
+
 /// Choices for outer encryption
 #[derive(Debug, Clone, PartialEq, Eq)]
-#[cfg_attr(feature = "serialization", derive(serde::Serialize))]
+#[cfg_attr(feature = "serialization", derive(serde::Serialize, serde::Deserialize))]
 pub enum OuterCipherConfig {
     AES256,
     Twofish,
     ChaCha20,
+    /// A cipher registered with `register_outer_cipher`, identified by its UUID. Only supported
+    /// by KDBX4 databases.
+    Custom(uuid::Uuid),
 }
 
 impl OuterCipherConfig {
@@ -88,24 +151,60 @@ impl OuterCipherConfig {
             OuterCipherConfig::AES256 => Ok(Box::new(ciphers::AES256Cipher::new(key, iv)?)),
             OuterCipherConfig::Twofish => Ok(Box::new(ciphers::TwofishCipher::new(key, iv)?)),
             OuterCipherConfig::ChaCha20 => Ok(Box::new(ciphers::ChaCha20Cipher::new_key_iv(key, iv)?)),
+            OuterCipherConfig::Custom(uuid) => Err(CryptographyError::UnsupportedOuterCipher { uuid: *uuid }),
         }
     }
 
-    #[cfg(feature = "save_kdbx4")]
-    pub(crate) fn get_iv_size(&self) -> usize {
+    /// Get the cipher for en- or decrypting the payload in pieces
+    pub(crate) fn get_stream_cipher(
+        &self,
+        key: &[u8],
+        iv: &[u8],
+    ) -> Result<Box<dyn ciphers::OuterCipherStream>, CryptographyError> {
         match self {
-            OuterCipherConfig::AES256 => ciphers::AES256Cipher::iv_size(),
-            OuterCipherConfig::Twofish => ciphers::TwofishCipher::iv_size(),
-            OuterCipherConfig::ChaCha20 => ciphers::ChaCha20Cipher::iv_size(),
+            OuterCipherConfig::AES256 => Ok(Box::new(ciphers::CbcCipherStream::<aes::Aes256>::new(key, iv)?)),
+            OuterCipherConfig::Twofish => Ok(Box::new(ciphers::CbcCipherStream::<twofish::Twofish>::new(
+                key, iv,
+            )?)),
+            OuterCipherConfig::ChaCha20 => Ok(Box::new(ciphers::ChaCha20Cipher::new_key_iv(key, iv)?)),
+            OuterCipherConfig::Custom(uuid) => cipher_registry::start_outer_cipher(uuid, key, iv),
         }
     }
 
     #[cfg(feature = "save_kdbx4")]
+    pub(crate) fn get_iv_size(&self) -> Result<usize, CryptographyError> {
+        match self {
+            OuterCipherConfig::AES256 => Ok(ciphers::AES256Cipher::iv_size()),
+            OuterCipherConfig::Twofish => Ok(ciphers::TwofishCipher::iv_size()),
+            OuterCipherConfig::ChaCha20 => Ok(ciphers::ChaCha20Cipher::iv_size()),
+            OuterCipherConfig::Custom(uuid) => cipher_registry::registered_outer_cipher(uuid)
+                .map(|cipher| cipher.iv_size())
+                .ok_or(CryptographyError::UnsupportedOuterCipher { uuid: *uuid }),
+        }
+    }
+
+    /// Like `try_from`, but also accepts the UUIDs of ciphers registered with
+    /// `register_outer_cipher`
+    pub(crate) fn try_from_registered(v: &[u8]) -> Result<OuterCipherConfig, OuterCipherConfigError> {
+        OuterCipherConfig::try_from(v).or_else(|e| match uuid::Uuid::from_slice(v) {
+            Ok(uuid) if cipher_registry::registered_outer_cipher(&uuid).is_some() => {
+                Ok(OuterCipherConfig::Custom(uuid))
+            }
+            _ => Err(e),
+        })
+    }
+
+    /// The UUID identifying the cipher in the header of a database
+    pub fn uuid(&self) -> uuid::Uuid {
+        uuid::Uuid::from_bytes(self.dump())
+    }
+
     pub(crate) fn dump(&self) -> [u8; 16] {
         match self {
             OuterCipherConfig::AES256 => CIPHERSUITE_AES256,
             OuterCipherConfig::Twofish => CIPHERSUITE_TWOFISH,
             OuterCipherConfig::ChaCha20 => CIPHERSUITE_CHACHA20,
+            OuterCipherConfig::Custom(uuid) => *uuid.as_bytes(),
         }
     }
 }
@@ -127,28 +226,42 @@ impl TryFrom<&[u8]> for OuterCipherConfig {
 
 /// Choices for encrypting protected values inside of databases
 #[derive(Debug, Clone, PartialEq, Eq)]
-#[cfg_attr(feature = "serialization", derive(serde::Serialize))]
+#[cfg_attr(feature = "serialization", derive(serde::Serialize, serde::Deserialize))]
 pub enum InnerCipherConfig {
     Plain,
     Salsa20,
     ChaCha20,
+    /// The RC4-based cipher of KeePass 2 pre-releases. Databases using it can be opened, but not
+    /// saved until another inner cipher is chosen.
+    ArcFourVariant,
 }
 
 impl InnerCipherConfig {
-    pub(crate) fn get_cipher(&self, key: &[u8]) -> Result<Box<dyn ciphers::Cipher>, CryptographyError> {
+    pub(crate) fn get_cipher(
+        &self,
+        key: &[u8],
+    ) -> Result<Box<dyn ciphers::InnerStreamCipher>, CryptographyError> {
         match self {
             InnerCipherConfig::Plain => Ok(Box::new(ciphers::PlainCipher::new(key)?)),
             InnerCipherConfig::Salsa20 => Ok(Box::new(ciphers::Salsa20Cipher::new(key)?)),
             InnerCipherConfig::ChaCha20 => Ok(Box::new(ciphers::ChaCha20Cipher::new(key)?)),
+            InnerCipherConfig::ArcFourVariant => Ok(Box::new(ciphers::ArcFourVariantCipher::new(key)?)),
         }
     }
 
+    /// Whether databases using this cipher can be saved
+    #[cfg(feature = "save_kdbx4")]
+    pub(crate) fn is_writable(&self) -> bool {
+        !matches!(self, InnerCipherConfig::ArcFourVariant)
+    }
+
     #[cfg(feature = "save_kdbx4")]
     pub(crate) fn dump(&self) -> u32 {
         match self {
             InnerCipherConfig::Plain => PLAIN,
             InnerCipherConfig::Salsa20 => SALSA_20,
             InnerCipherConfig::ChaCha20 => CHA_CHA_20,
+            InnerCipherConfig::ArcFourVariant => ARC_FOUR_VARIANT,
         }
     }
 
@@ -156,7 +269,9 @@ impl InnerCipherConfig {
     pub(crate) fn get_key_size(&self) -> usize {
         match self {
             InnerCipherConfig::Plain => ciphers::PlainCipher::key_size(),
-            InnerCipherConfig::Salsa20 => ciphers::Salsa20Cipher::key_size(),
+            InnerCipherConfig::Salsa20 | InnerCipherConfig::ArcFourVariant => {
+                ciphers::Salsa20Cipher::key_size()
+            }
             InnerCipherConfig::ChaCha20 => ciphers::ChaCha20Cipher::key_size(),
         }
     }
@@ -168,6 +283,7 @@ impl TryFrom<u32> for InnerCipherConfig {
     fn try_from(v: u32) -> Result<InnerCipherConfig, Self::Error> {
         match v {
             PLAIN => Ok(InnerCipherConfig::Plain),
+            ARC_FOUR_VARIANT => Ok(InnerCipherConfig::ArcFourVariant),
             SALSA_20 => Ok(InnerCipherConfig::Salsa20),
             CHA_CHA_20 => Ok(InnerCipherConfig::ChaCha20),
             _ => Err(InnerCipherConfigError::InvalidInnerCipherID { cid: v }.into()),
@@ -183,13 +299,15 @@ const KDF_SALT: &str = "S";
 const KDF_ITERATIONS: &str = "I";
 const KDF_PARALLELISM: &str = "P";
 const KDF_VERSION: &str = "V";
+const KDF_SECRET: &str = "K";
+const KDF_ASSOCIATED_DATA: &str = "A";
 // KDF fields used by AES.
 const KDF_SEED: &str = "S";
 const KDF_ROUNDS: &str = "R";
 
 /// Choices for Key Derivation Functions (KDFs)
 #[derive(Debug, Clone, PartialEq, Eq)]
-#[cfg_attr(feature = "serialization", derive(serde::Serialize))]
+#[cfg_attr(feature = "serialization", derive(serde::Serialize, serde::Deserialize))]
 pub enum KdfConfig {
     /// Derive keys with repeated AES encryption
     Aes { rounds: u64 },
@@ -199,7 +317,13 @@ pub enum KdfConfig {
         memory: u64,
         parallelism: u32,
 
-        #[cfg_attr(feature = "serialization", serde(serialize_with = "serialize_argon2_version"))]
+        #[cfg_attr(
+            feature = "serialization",
+            serde(
+                serialize_with = "serialize_argon2_version",
+                deserialize_with = "deserialize_argon2_version"
+            )
+        )]
         version: argon2::Version,
     },
     /// Derive keys with Argon2id
@@ -208,9 +332,90 @@ pub enum KdfConfig {
         memory: u64,
         parallelism: u32,
 
-        #[cfg_attr(feature = "serialization", serde(serialize_with = "serialize_argon2_version"))]
+        #[cfg_attr(
+            feature = "serialization",
+            serde(
+                serialize_with = "serialize_argon2_version",
+                deserialize_with = "deserialize_argon2_version"
+            )
+        )]
         version: argon2::Version,
     },
+    /// Derive keys with a KDF registered with `register_kdf`, identified by its UUID. Only
+    /// supported by KDBX4 databases.
+    Custom {
+        uuid: uuid::Uuid,
+        /// The parameters of the KDF, without its seed
+        settings: KdfParameters,
+    },
+}
+
+/// The optional secret key (`K`) and associated data (`A`) parameters of Argon2.
+///
+/// The KDBX4 format allows storing both in the KDF parameters, and they are used when deriving the
+/// key if present. KeePass and most other clients never write them and may not be able to open
+/// databases that use them, so they can only be set with the `argon2_secret` feature. The secret
+/// is left out of `Debug` output and serialization.
+#[derive(Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serialization", derive(serde::Serialize, serde::Deserialize))]
+pub struct Argon2SecretParameters {
+    #[cfg_attr(feature = "serialization", serde(skip_serializing, default))]
+    secret: Option<Vec<u8>>,
+    associated_data: Option<Vec<u8>>,
+}
+
+impl Argon2SecretParameters {
+    pub fn secret(&self) -> Option<&[u8]> {
+        self.secret.as_deref()
+    }
+
+    pub fn associated_data(&self) -> Option<&[u8]> {
+        self.associated_data.as_deref()
+    }
+
+    /// Whether neither parameter is set, which is what other KeePass clients expect
+    pub fn is_empty(&self) -> bool {
+        self.secret.is_none() && self.associated_data.is_none()
+    }
+
+    /// Set or clear the secret key. Databases using it may not open in other KeePass clients.
+    #[cfg(feature = "argon2_secret")]
+    pub fn set_secret(&mut self, secret: Option<Vec<u8>>) {
+        self.secret = secret;
+    }
+
+    /// Set or clear the associated data. Databases using it may not open in other KeePass
+    /// clients.
+    #[cfg(feature = "argon2_secret")]
+    pub fn set_associated_data(&mut self, associated_data: Option<Vec<u8>>) {
+        self.associated_data = associated_data;
+    }
+
+    pub(crate) fn from_variant_dictionary(vd: &VariantDictionary) -> Self {
+        Argon2SecretParameters {
+            secret: vd.get::<Vec<u8>>(KDF_SECRET).ok().cloned(),
+            associated_data: vd.get::<Vec<u8>>(KDF_ASSOCIATED_DATA).ok().cloned(),
+        }
+    }
+
+    #[cfg(feature = "save_kdbx4")]
+    fn add_to_variant_dictionary(&self, vd: &mut VariantDictionary) {
+        if let Some(ref secret) = self.secret {
+            vd.set(KDF_SECRET, secret.clone());
+        }
+        if let Some(ref associated_data) = self.associated_data {
+            vd.set(KDF_ASSOCIATED_DATA, associated_data.clone());
+        }
+    }
+}
+
+impl std::fmt::Debug for Argon2SecretParameters {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Argon2SecretParameters")
+            .field("secret", &self.secret.as_ref().map(|_| "<redacted>"))
+            .field("associated_data", &self.associated_data)
+            .finish()
+    }
 }
 
 #[cfg(feature = "serialization")]
@@ -221,30 +426,129 @@ fn serialize_argon2_version<S: serde::Serializer>(
     serializer.serialize_u32(version.as_u32())
 }
 
+#[cfg(feature = "serialization")]
+fn deserialize_argon2_version<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<argon2::Version, D::Error> {
+    match <u32 as serde::Deserialize>::deserialize(deserializer)? {
+        0x10 => Ok(argon2::Version::Version10),
+        0x13 => Ok(argon2::Version::Version13),
+        version => Err(serde::de::Error::custom(KdfConfigError::InvalidKDFVersion {
+            version,
+        })),
+    }
+}
+
+/// How long a trial run of `KdfConfig::benchmark` has to take at most before its result is
+/// scaled to the target duration
+const BENCHMARK_TRIAL_DURATION: Duration = Duration::from_millis(250);
+
 impl KdfConfig {
-    #[cfg(feature = "save_kdbx4")]
-    fn seed_size(&self) -> usize {
+    /// Tune the parameters of the KDF so that deriving a key takes about `target` on the current
+    /// machine, like the "Benchmark 1-second delay" of KeePassXC. The number of AES rounds or
+    /// Argon2 iterations is scaled from timed trial runs, while the memory, parallelism and
+    /// version of Argon2 are kept. Argon2 always does at least one iteration, even if that takes
+    /// longer than `target`.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use keepass::config::KdfConfig;
+    ///
+    /// let kdf = KdfConfig::Argon2id {
+    ///     iterations: 1,
+    ///     memory: 1024 * 1024,
+    ///     parallelism: 2,
+    ///     version: argon2::Version::Version13,
+    /// };
+    /// let tuned = kdf.benchmark(Duration::from_millis(100))?;
+    /// assert!(matches!(tuned, KdfConfig::Argon2id { memory: 1048576, parallelism: 2, .. }));
+    /// # Ok::<(), keepass::error::CryptographyError>(())
+    /// ```
+    pub fn benchmark(&self, target: Duration) -> Result<KdfConfig, CryptographyError> {
+        // the settings of registered KDFs are not known to scale with the work
+        if let KdfConfig::Custom { .. } = self {
+            return Ok(self.clone());
+        }
+
+        let composite_key = GenericArray::default();
+        let seed = vec![0; 32];
+        let secret_parameters = Argon2SecretParameters::default();
+        let trial_duration = (target / 4).min(BENCHMARK_TRIAL_DURATION);
+
+        let mut work = match self {
+            KdfConfig::Aes { .. } => 10_000,
+            KdfConfig::Argon2 { .. } | KdfConfig::Argon2id { .. } | KdfConfig::Custom { .. } => 1,
+        };
+        loop {
+            let kdf = self.with_work(work).get_kdf_seeded(&seed, &secret_parameters);
+
+            let (transformed, elapsed) = measure(|| kdf.transform_key(&composite_key));
+            transformed?;
+
+            if elapsed >= trial_duration {
+                let scaled = work as f64 * target.as_secs_f64() / elapsed.as_secs_f64();
+                return Ok(self.with_work((scaled as u64).max(1)));
+            }
+            work = work.saturating_mul(2);
+        }
+    }
+
+    /// The UUID identifying the KDF in the header of a KDBX4 database
+    pub fn uuid(&self) -> uuid::Uuid {
+        match self {
+            KdfConfig::Aes { .. } => uuid::Uuid::from_bytes(KDF_AES_KDBX4),
+            KdfConfig::Argon2 { .. } => uuid::Uuid::from_bytes(KDF_ARGON2),
+            KdfConfig::Argon2id { .. } => uuid::Uuid::from_bytes(KDF_ARGON2ID),
+            KdfConfig::Custom { uuid, .. } => *uuid,
+        }
+    }
+
+    /// A copy of the config with the given number of AES rounds or Argon2 iterations
+    fn with_work(&self, work: u64) -> KdfConfig {
+        let mut config = self.clone();
+        match &mut config {
+            KdfConfig::Aes { rounds } => *rounds = work,
+            KdfConfig::Argon2 { iterations, .. } | KdfConfig::Argon2id { iterations, .. } => {
+                *iterations = work.min(u32::MAX as u64)
+            }
+            KdfConfig::Custom { .. } => {}
+        }
+        config
+    }
+
+    pub(crate) fn seed_size(&self) -> usize {
         match self {
             KdfConfig::Aes { .. } => 32,
             KdfConfig::Argon2 { .. } => 32,
             KdfConfig::Argon2id { .. } => 32,
+            KdfConfig::Custom { uuid, .. } => kdf_registry::registered_kdf(uuid)
+                .map(|kdf| kdf.seed_size())
+                .unwrap_or(32),
         }
     }
 
     /// For writing out a database, generate a new KDF seed from the config and return the KDF
     /// and the generated seed
     #[cfg(feature = "save_kdbx4")]
-    pub(crate) fn get_kdf_and_seed(&self) -> Result<(Box<dyn kdf::Kdf>, Vec<u8>), getrandom::Error> {
+    pub(crate) fn get_kdf_and_seed(
+        &self,
+        secret_parameters: &Argon2SecretParameters,
+    ) -> Result<(Box<dyn kdf::KeyDerivation>, Vec<u8>), getrandom::Error> {
         let mut kdf_seed = vec![0; self.seed_size()];
         getrandom::fill(&mut kdf_seed)?;
 
-        let kdf = self.get_kdf_seeded(&kdf_seed);
+        let kdf = self.get_kdf_seeded(&kdf_seed, secret_parameters);
 
         Ok((kdf, kdf_seed))
     }
 
-    /// For reading a database, generate a KDF from the KDF config and a provided seed
-    pub(crate) fn get_kdf_seeded(&self, seed: &[u8]) -> Box<dyn kdf::Kdf> {
+    /// For reading a database, generate a KDF from the KDF config and a provided seed. The secret
+    /// parameters are only used by Argon2.
+    pub(crate) fn get_kdf_seeded(
+        &self,
+        seed: &[u8],
+        secret_parameters: &Argon2SecretParameters,
+    ) -> Box<dyn kdf::KeyDerivation> {
         match self {
             KdfConfig::Aes { rounds } => Box::new(kdf::AesKdf {
                 seed: seed.to_vec(),
@@ -262,6 +566,8 @@ impl KdfConfig {
                 parallelism: *parallelism,
                 version: *version,
                 variant: argon2::Variant::Argon2d,
+                secret: secret_parameters.secret.clone().unwrap_or_default(),
+                associated_data: secret_parameters.associated_data.clone().unwrap_or_default(),
             }),
             KdfConfig::Argon2id {
                 memory,
@@ -275,12 +581,23 @@ impl KdfConfig {
                 parallelism: *parallelism,
                 version: *version,
                 variant: argon2::Variant::Argon2id,
+                secret: secret_parameters.secret.clone().unwrap_or_default(),
+                associated_data: secret_parameters.associated_data.clone().unwrap_or_default(),
+            }),
+            KdfConfig::Custom { uuid, settings } => Box::new(kdf_registry::RegisteredKdf {
+                uuid: *uuid,
+                settings: settings.clone(),
+                seed: seed.to_vec(),
             }),
         }
     }
 
     #[cfg(feature = "save_kdbx4")]
-    pub(crate) fn to_variant_dictionary(&self, seed: &[u8]) -> VariantDictionary {
+    pub(crate) fn to_variant_dictionary(
+        &self,
+        seed: &[u8],
+        secret_parameters: &Argon2SecretParameters,
+    ) -> VariantDictionary {
         let mut vd = VariantDictionary::new();
 
         match self {
@@ -301,6 +618,7 @@ impl KdfConfig {
                 vd.set(KDF_ITERATIONS, *iterations);
                 vd.set(KDF_PARALLELISM, *parallelism);
                 vd.set(KDF_VERSION, version.as_u32());
+                secret_parameters.add_to_variant_dictionary(&mut vd);
             }
             KdfConfig::Argon2id {
                 memory,
@@ -314,6 +632,16 @@ impl KdfConfig {
                 vd.set(KDF_ITERATIONS, *iterations);
                 vd.set(KDF_PARALLELISM, *parallelism);
                 vd.set(KDF_VERSION, version.as_u32());
+                secret_parameters.add_to_variant_dictionary(&mut vd);
+            }
+            KdfConfig::Custom { uuid, settings } => {
+                // an unregistered KDF fails to transform the key before the header is written
+                let parameters = match kdf_registry::registered_kdf(uuid) {
+                    Some(kdf) => kdf.join_parameters(settings, seed),
+                    None => settings.clone(),
+                };
+                vd = PublicCustomData { items: parameters }.to_variant_dictionary();
+                vd.set(KDF_ID, uuid.as_bytes().to_vec());
             }
         }
 
@@ -321,6 +649,25 @@ impl KdfConfig {
     }
 }
 
+/// Run `f`, returning how long it took. The standard library has no clock in the browser, where
+/// the time comes from JavaScript instead.
+fn measure<R>(f: impl FnOnce() -> R) -> (R, Duration) {
+    #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+    {
+        let start = std::time::Instant::now();
+        let result = f();
+        (result, start.elapsed())
+    }
+
+    #[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+    {
+        let start = chrono::Utc::now();
+        let result = f();
+        let elapsed = (chrono::Utc::now() - start).to_std().unwrap_or_default();
+        (result, elapsed)
+    }
+}
+
 const KDF_AES_KDBX3: [u8; 16] = hex!("c9d9f39a628a4460bf740d08c18a4fea");
 const KDF_AES_KDBX4: [u8; 16] = hex!("7c02bb8279a74ac0927d114a00648238");
 const KDF_ARGON2: [u8; 16] = hex!("ef636ddf8c29444b91f7a9a403e30a0c");
@@ -381,15 +728,34 @@ impl TryFrom<VariantDictionary> for (KdfConfig, Vec<u8>) {
             let seed: Vec<u8> = vd.get::<Vec<u8>>(KDF_SEED)?.clone();
 
             Ok((KdfConfig::Aes { rounds }, seed))
+        } else if let Some(kdf) = uuid::Uuid::from_slice(uuid)
+            .ok()
+            .and_then(|uuid| kdf_registry::registered_kdf(&uuid))
+        {
+            let mut parameters = PublicCustomData::from_variant_dictionary(vd).items;
+            parameters.remove(KDF_ID);
+            let (settings, seed) = kdf.split_parameters(parameters)?;
+
+            Ok((
+                KdfConfig::Custom {
+                    uuid: kdf.uuid(),
+                    settings,
+                },
+                seed,
+            ))
         } else {
             Err(KdfConfigError::InvalidKDFUUID { uuid: uuid.clone() })
         }
     }
 }
 
+/// The GZip compression level of flate2 and zlib, which databases are saved with unless
+/// `SaveOptions::gzip_level` is given
+pub const DEFAULT_GZIP_LEVEL: u32 = 6;
+
 /// Choices of compression algorithm
 #[derive(Debug, Clone, PartialEq, Eq)]
-#[cfg_attr(feature = "serialization", derive(serde::Serialize))]
+#[cfg_attr(feature = "serialization", derive(serde::Serialize, serde::Deserialize))]
 pub enum CompressionConfig {
     None,
     GZip,
@@ -399,7 +765,16 @@ impl CompressionConfig {
     pub(crate) fn get_compression(&self) -> Box<dyn compression::Compression> {
         match self {
             CompressionConfig::None => Box::new(compression::NoCompression),
-            CompressionConfig::GZip => Box::new(compression::GZipCompression),
+            CompressionConfig::GZip => Box::new(compression::GZipCompression::default()),
+        }
+    }
+
+    /// The compression for writing a database, compressing at `gzip_level` with GZip
+    #[cfg(feature = "save_kdbx4")]
+    pub(crate) fn get_compression_at(&self, gzip_level: u32) -> Box<dyn compression::Compression> {
+        match self {
+            CompressionConfig::None => Box::new(compression::NoCompression),
+            CompressionConfig::GZip => Box::new(compression::GZipCompression::with_level(gzip_level)),
         }
     }
 
@@ -0,0 +1,176 @@
+//! bcrypt_pbkdf, the key derivation of passphrase-protected OpenSSH private keys
+//!
+//! Follows `bcrypt_pbkdf.c` of OpenBSD, along with the parts of Blowfish it needs.
+
+use sha2::{Digest, Sha512};
+use zeroize::{Zeroize, Zeroizing};
+
+mod consts;
+
+/// The Blowfish state, expanded with the key setup of bcrypt
+struct Blowfish {
+    p: [u32; 18],
+    s: [[u32; 256]; 4],
+}
+
+impl Drop for Blowfish {
+    fn drop(&mut self) {
+        self.p.zeroize();
+        self.s.iter_mut().for_each(|s| s.zeroize());
+    }
+}
+
+/// Read the next big-endian word from `data`, starting over at its end
+fn stream_to_word(data: &[u8], pos: &mut usize) -> u32 {
+    let mut word = 0u32;
+    for _ in 0..4 {
+        word = (word << 8) | data[*pos] as u32;
+        *pos = (*pos + 1) % data.len();
+    }
+    word
+}
+
+impl Blowfish {
+    fn new() -> Blowfish {
+        Blowfish {
+            p: consts::P,
+            s: consts::S,
+        }
+    }
+
+    fn f(&self, x: u32) -> u32 {
+        let [a, b, c, d] = x.to_be_bytes();
+        (self.s[0][a as usize].wrapping_add(self.s[1][b as usize]) ^ self.s[2][c as usize])
+            .wrapping_add(self.s[3][d as usize])
+    }
+
+    fn encrypt(&self, left: &mut u32, right: &mut u32) {
+        let (mut l, mut r) = (*left, *right);
+        for i in (0..16).step_by(2) {
+            l ^= self.p[i];
+            r ^= self.f(l);
+            r ^= self.p[i + 1];
+            l ^= self.f(r);
+        }
+        *left = r ^ self.p[17];
+        *right = l ^ self.p[16];
+    }
+
+    /// The expensive key setup of bcrypt, mixing `data` into the encryptions if given
+    fn expand(&mut self, data: Option<&[u8]>, key: &[u8]) {
+        let mut pos = 0;
+        for p in self.p.iter_mut() {
+            *p ^= stream_to_word(key, &mut pos);
+        }
+
+        let mut pos = 0;
+        let (mut l, mut r) = (0u32, 0u32);
+        let mut next = |bf: &Blowfish, l: &mut u32, r: &mut u32| {
+            if let Some(data) = data {
+                *l ^= stream_to_word(data, &mut pos);
+                *r ^= stream_to_word(data, &mut pos);
+            }
+            bf.encrypt(l, r);
+        };
+
+        for i in (0..18).step_by(2) {
+            next(self, &mut l, &mut r);
+            self.p[i] = l;
+            self.p[i + 1] = r;
+        }
+        for i in 0..4 {
+            for k in (0..256).step_by(2) {
+                next(self, &mut l, &mut r);
+                self.s[i][k] = l;
+                self.s[i][k + 1] = r;
+            }
+        }
+    }
+}
+
+/// The hash of a round, with the SHA-512 hashes of the passphrase and of the salt
+fn bcrypt_hash(sha2_pass: &[u8], sha2_salt: &[u8]) -> [u8; 32] {
+    let mut state = Blowfish::new();
+    state.expand(Some(sha2_salt), sha2_pass);
+    for _ in 0..64 {
+        state.expand(None, sha2_salt);
+        state.expand(None, sha2_pass);
+    }
+
+    let mut pos = 0;
+    let mut cdata = [0u32; 8];
+    for word in cdata.iter_mut() {
+        *word = stream_to_word(b"OxychromaticBlowfishSwatDynamite", &mut pos);
+    }
+    for _ in 0..64 {
+        for block in cdata.chunks_mut(2) {
+            let (l, r) = block.split_at_mut(1);
+            state.encrypt(&mut l[0], &mut r[0]);
+        }
+    }
+
+    let mut out = [0u8; 32];
+    for (out, word) in out.chunks_mut(4).zip(cdata.iter()) {
+        out.copy_from_slice(&word.to_le_bytes());
+    }
+    cdata.zeroize();
+    out
+}
+
+/// Derive `len` bytes from a passphrase and a salt in `rounds` rounds
+pub(crate) fn bcrypt_pbkdf(passphrase: &[u8], salt: &[u8], rounds: u32, len: usize) -> Zeroizing<Vec<u8>> {
+    let mut key = Zeroizing::new(vec![0u8; len]);
+    // the rounds of a block are spread over the key, so that all of it takes the time of all rounds
+    let stride = len.div_ceil(32);
+    let amount = len.div_ceil(stride);
+    let sha2_pass = Zeroizing::new(Sha512::digest(passphrase));
+
+    let mut count = 1u32;
+    let mut remaining = len;
+    while remaining > 0 {
+        let mut sha2_salt = Sha512::new()
+            .chain_update(salt)
+            .chain_update(count.to_be_bytes())
+            .finalize();
+        let mut tmp = Zeroizing::new(bcrypt_hash(&sha2_pass, &sha2_salt));
+        let mut out = Zeroizing::new(*tmp);
+        for _ in 1..rounds {
+            sha2_salt = Sha512::digest(&tmp[..]);
+            *tmp = bcrypt_hash(&sha2_pass, &sha2_salt);
+            out.iter_mut().zip(tmp.iter()).for_each(|(o, t)| *o ^= t);
+        }
+
+        let amount = amount.min(remaining);
+        let mut written = 0;
+        for (i, byte) in out.iter().take(amount).enumerate() {
+            let dest = i * stride + (count as usize - 1);
+            if dest >= len {
+                break;
+            }
+            key[dest] = *byte;
+            written += 1;
+        }
+        remaining -= written;
+        count += 1;
+    }
+
+    key
+}
+
+#[cfg(test)]
+mod bcrypt_pbkdf_tests {
+    use super::bcrypt_pbkdf;
+
+    #[test]
+    fn test_bcrypt_pbkdf() {
+        // from the test vectors of the bcrypt-pbkdf crate, which follow OpenBSD's regress tests
+        assert_eq!(
+            hex::encode(&bcrypt_pbkdf(b"password", b"salt", 4, 32)[..]),
+            "5bbf0cc293587f1c3635555c27796598d47e579071bf427e9d8fbe842aba34d9"
+        );
+        assert_eq!(
+            hex::encode(&bcrypt_pbkdf(b"password", b"\x00", 4, 16)[..]),
+            "c12b566235eee04c212598970a579a67"
+        );
+    }
+}
@@ -0,0 +1,177 @@
+//! Process-wide registry of outer ciphers provided by the application
+//!
+//! KeePass plugins add outer ciphers like Serpent or GOST, which are identified by UUIDs that this
+//! crate does not know. KDBX4 databases encrypted with such a cipher can be opened and saved once
+//! an implementation is registered with `register_outer_cipher`. Their outer cipher is then
+//! `OuterCipherConfig::Custom` with the UUID of the cipher.
+
+use std::sync::{Arc, Mutex};
+
+use uuid::Uuid;
+
+use crate::{crypt::ciphers::OuterCipherStream, error::CryptographyError};
+
+/// An outer cipher that is not built into this crate
+///
+/// The key is always the 32 byte master key of the database.
+pub trait CustomCipher: Send + Sync {
+    /// The number of bytes of the initialization vector stored in the database header
+    fn iv_size(&self) -> usize;
+
+    /// The size of the blocks the cipher works on, 1 for stream ciphers. Payloads of block
+    /// ciphers are padded to a multiple of the block size with PKCS#7, so block ciphers are
+    /// usually used in CBC mode.
+    fn block_size(&self) -> usize;
+
+    /// Start en- or decrypting a payload
+    fn start(&self, key: &[u8], iv: &[u8]) -> Result<Box<dyn CustomCipherStream>, CryptographyError>;
+}
+
+/// The state of a `CustomCipher` while en- or decrypting a payload
+pub trait CustomCipherStream {
+    /// Encrypt `data` in place, which is a multiple of the block size long
+    fn encrypt_blocks(&mut self, data: &mut [u8]);
+
+    /// Decrypt `data` in place, which is a multiple of the block size long
+    fn decrypt_blocks(&mut self, data: &mut [u8]);
+}
+
+static REGISTRY: Mutex<Vec<(Uuid, Arc<dyn CustomCipher>)>> = Mutex::new(Vec::new());
+
+fn registry() -> std::sync::MutexGuard<'static, Vec<(Uuid, Arc<dyn CustomCipher>)>> {
+    REGISTRY.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Use `cipher` for databases whose outer cipher has the given UUID, replacing any cipher
+/// registered for it before. Built-in ciphers take precedence over registered ones.
+pub fn register_outer_cipher(uuid: Uuid, cipher: impl CustomCipher + 'static) {
+    let mut registry = registry();
+    registry.retain(|(id, _)| *id != uuid);
+    registry.push((uuid, Arc::new(cipher)));
+}
+
+/// Remove the cipher registered for the given UUID, if any
+pub fn unregister_outer_cipher(uuid: &Uuid) {
+    registry().retain(|(id, _)| id != uuid);
+}
+
+/// The cipher registered for the given UUID
+pub(crate) fn registered_outer_cipher(uuid: &Uuid) -> Option<Arc<dyn CustomCipher>> {
+    registry()
+        .iter()
+        .find(|(id, _)| id == uuid)
+        .map(|(_, cipher)| cipher.clone())
+}
+
+/// Start en- or decrypting a payload with the cipher registered for the given UUID
+pub(crate) fn start_outer_cipher(
+    uuid: &Uuid,
+    key: &[u8],
+    iv: &[u8],
+) -> Result<Box<dyn OuterCipherStream>, CryptographyError> {
+    let cipher =
+        registered_outer_cipher(uuid).ok_or(CryptographyError::UnsupportedOuterCipher { uuid: *uuid })?;
+
+    Ok(Box::new(RegisteredCipherStream {
+        block_size: cipher.block_size(),
+        stream: cipher.start(key, iv)?,
+    }))
+}
+
+struct RegisteredCipherStream {
+    block_size: usize,
+    stream: Box<dyn CustomCipherStream>,
+}
+
+impl OuterCipherStream for RegisteredCipherStream {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    #[cfg(feature = "save_kdbx4")]
+    fn encrypt_blocks(&mut self, data: &mut [u8]) {
+        self.stream.encrypt_blocks(data)
+    }
+
+    fn decrypt_blocks(&mut self, data: &mut [u8]) {
+        self.stream.decrypt_blocks(data)
+    }
+}
+
+#[cfg(feature = "save_kdbx4")]
+#[cfg(test)]
+mod cipher_registry_tests {
+    use uuid::uuid;
+
+    use super::{register_outer_cipher, unregister_outer_cipher, CustomCipher, CustomCipherStream};
+    use crate::{
+        config::{DatabaseConfig, OuterCipherConfig},
+        db::Entry,
+        error::{CryptographyError, DatabaseSaveError},
+        Database, DatabaseKey,
+    };
+
+    /// A toy block cipher that XORs every block with the key and the initialization vector
+    struct XorCipher;
+
+    struct XorCipherStream(Vec<u8>);
+
+    impl CustomCipher for XorCipher {
+        fn iv_size(&self) -> usize {
+            8
+        }
+
+        fn block_size(&self) -> usize {
+            8
+        }
+
+        fn start(&self, key: &[u8], iv: &[u8]) -> Result<Box<dyn CustomCipherStream>, CryptographyError> {
+            let pad = key[..8].iter().zip(iv).map(|(k, i)| k ^ i).collect();
+            Ok(Box::new(XorCipherStream(pad)))
+        }
+    }
+
+    impl CustomCipherStream for XorCipherStream {
+        fn encrypt_blocks(&mut self, data: &mut [u8]) {
+            for (i, b) in data.iter_mut().enumerate() {
+                *b ^= self.0[i % 8];
+            }
+        }
+
+        fn decrypt_blocks(&mut self, data: &mut [u8]) {
+            self.encrypt_blocks(data)
+        }
+    }
+
+    #[test]
+    fn test_registered_outer_cipher() {
+        let cipher_uuid = uuid!("3c4a1e0b-1f52-4b2e-9a57-0d8c6e7f2a91");
+        register_outer_cipher(cipher_uuid, XorCipher);
+
+        let mut db = Database::new(DatabaseConfig {
+            outer_cipher_config: OuterCipherConfig::Custom(cipher_uuid),
+            ..Default::default()
+        });
+        db.root.add_child(Entry::new());
+        let key = DatabaseKey::new().with_password("test");
+
+        let mut data = Vec::new();
+        db.save(&mut data, key.clone()).unwrap();
+
+        let reopened = Database::open(&mut data.as_slice(), key.clone()).unwrap();
+        assert_eq!(
+            reopened.config.outer_cipher_config,
+            OuterCipherConfig::Custom(cipher_uuid)
+        );
+        assert_eq!(reopened.root, db.root);
+
+        unregister_outer_cipher(&cipher_uuid);
+        assert!(Database::open(&mut data.as_slice(), key.clone()).is_err());
+        assert!(matches!(
+            db.save(&mut Vec::new(), key),
+            Err(DatabaseSaveError::Cryptography(
+                CryptographyError::UnsupportedOuterCipher { .. }
+            ))
+        ));
+    }
+}
@@ -1,11 +1,18 @@
-use aes::Aes256;
+use std::io::Read;
 #[cfg(feature = "save_kdbx4")]
-use cipher::BlockEncryptMut;
-use cipher::{block_padding::Pkcs7, generic_array::GenericArray, BlockDecryptMut};
+use std::io::Write;
+
+use aes::Aes256;
+use cipher::{
+    block_padding::{Pkcs7, UnpadError},
+    generic_array::GenericArray,
+    BlockCipher, BlockDecryptMut, BlockEncryptMut, KeyInit,
+};
 use salsa20::{
     cipher::{KeyIvInit, StreamCipher},
     Salsa20,
 };
+use zeroize::Zeroizing;
 
 use crate::crypt::CryptographyError;
 
@@ -27,18 +34,241 @@ pub(crate) trait Cipher {
         Self: Sized;
 }
 
+/// A stream cipher protecting values inside of the XML document, shared by its parser and dumper.
+/// Protected values are en- and decrypted in the order they appear in the document, each one
+/// continuing the keystream where the one before it stopped.
+pub(crate) trait InnerStreamCipher {
+    /// Combine the next `data.len()` bytes of the keystream with `data`
+    fn apply_keystream(&mut self, data: &mut [u8]);
+
+    #[cfg(feature = "save_kdbx4")]
+    fn encrypt(&mut self, plaintext: &[u8]) -> Result<Vec<u8>, CryptographyError> {
+        let mut buffer = Vec::from(plaintext);
+        self.apply_keystream(&mut buffer);
+        Ok(buffer)
+    }
+
+    fn decrypt(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>, CryptographyError> {
+        let mut buffer = Vec::from(ciphertext);
+        self.apply_keystream(&mut buffer);
+        Ok(buffer)
+    }
+}
+
+/// An outer cipher that en- or decrypts the payload in pieces, for `EncryptWriter` and
+/// `DecryptReader`
+pub(crate) trait OuterCipherStream {
+    /// The size of the blocks the cipher works on, 1 for stream ciphers. Payloads of block
+    /// ciphers are padded to a multiple of the block size with PKCS#7.
+    fn block_size(&self) -> usize;
+
+    /// Encrypt `data` in place, which is a multiple of the block size long
+    #[cfg(feature = "save_kdbx4")]
+    fn encrypt_blocks(&mut self, data: &mut [u8]);
+
+    /// Decrypt `data` in place, which is a multiple of the block size long
+    fn decrypt_blocks(&mut self, data: &mut [u8]);
+}
+
+/// A block cipher in CBC mode
+pub(crate) struct CbcCipherStream<C: BlockCipher + BlockEncryptMut + BlockDecryptMut> {
+    #[cfg(feature = "save_kdbx4")]
+    encryptor: cbc::Encryptor<C>,
+    decryptor: cbc::Decryptor<C>,
+}
+
+impl<C: BlockCipher + BlockEncryptMut + BlockDecryptMut + KeyInit> CbcCipherStream<C> {
+    pub(crate) fn new(key: &[u8], iv: &[u8]) -> Result<Self, CryptographyError> {
+        Ok(CbcCipherStream {
+            #[cfg(feature = "save_kdbx4")]
+            encryptor: cbc::Encryptor::new_from_slices(key, iv)?,
+            decryptor: cbc::Decryptor::new_from_slices(key, iv)?,
+        })
+    }
+}
+
+impl<C: BlockCipher + BlockEncryptMut + BlockDecryptMut> OuterCipherStream for CbcCipherStream<C> {
+    fn block_size(&self) -> usize {
+        C::block_size()
+    }
+
+    #[cfg(feature = "save_kdbx4")]
+    fn encrypt_blocks(&mut self, data: &mut [u8]) {
+        for block in data.chunks_exact_mut(C::block_size()) {
+            self.encryptor
+                .encrypt_block_mut(GenericArray::from_mut_slice(block));
+        }
+    }
+
+    fn decrypt_blocks(&mut self, data: &mut [u8]) {
+        for block in data.chunks_exact_mut(C::block_size()) {
+            self.decryptor
+                .decrypt_block_mut(GenericArray::from_mut_slice(block));
+        }
+    }
+}
+
+/// The size of the pieces `DecryptReader` reads from its source
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
+fn stream_error(e: CryptographyError) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+}
+
+/// Encrypts everything written to it with an outer cipher. `finish` needs to be called to write
+/// the padding at the end.
+#[cfg(feature = "save_kdbx4")]
+pub(crate) struct EncryptWriter<'a> {
+    inner: &'a mut dyn Write,
+    cipher: Box<dyn OuterCipherStream>,
+    pending: Zeroizing<Vec<u8>>,
+}
+
+#[cfg(feature = "save_kdbx4")]
+impl<'a> EncryptWriter<'a> {
+    pub(crate) fn new(inner: &'a mut dyn Write, cipher: Box<dyn OuterCipherStream>) -> Self {
+        EncryptWriter {
+            inner,
+            cipher,
+            pending: Zeroizing::new(Vec::new()),
+        }
+    }
+
+    /// Pad, encrypt and write the last block
+    pub(crate) fn finish(mut self) -> Result<(), std::io::Error> {
+        let block_size = self.cipher.block_size();
+        if block_size > 1 {
+            let padding = block_size - self.pending.len();
+            self.pending.resize(block_size, padding as u8);
+            self.cipher.encrypt_blocks(&mut self.pending);
+        }
+        self.inner.write_all(&self.pending)?;
+        self.inner.flush()
+    }
+}
+
+#[cfg(feature = "save_kdbx4")]
+impl<'a> Write for EncryptWriter<'a> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, std::io::Error> {
+        self.pending.extend_from_slice(buf);
+
+        let block_size = self.cipher.block_size();
+        let ready = self.pending.len() / block_size * block_size;
+        if ready > 0 {
+            self.cipher.encrypt_blocks(&mut self.pending[..ready]);
+            self.inner.write_all(&self.pending[..ready])?;
+            self.pending.drain(..ready);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        self.inner.flush()
+    }
+}
+
+/// Decrypts everything read from its source with an outer cipher, removing the padding at the end
+pub(crate) struct DecryptReader<'a> {
+    inner: &'a mut dyn Read,
+    cipher: Box<dyn OuterCipherStream>,
+    /// Encrypted data, of which at least one block is kept back until the end of the source to
+    /// find the padding
+    pending: Vec<u8>,
+    decrypted: Zeroizing<Vec<u8>>,
+    pos: usize,
+    eof: bool,
+}
+
+impl<'a> DecryptReader<'a> {
+    pub(crate) fn new(inner: &'a mut dyn Read, cipher: Box<dyn OuterCipherStream>) -> Self {
+        DecryptReader {
+            inner,
+            cipher,
+            pending: Vec::new(),
+            decrypted: Zeroizing::new(Vec::new()),
+            pos: 0,
+            eof: false,
+        }
+    }
+
+    /// Decrypt the next piece of the source into `decrypted`
+    fn fill(&mut self) -> Result<(), std::io::Error> {
+        let block_size = self.cipher.block_size();
+
+        let start = self.pending.len();
+        self.pending.resize(start + STREAM_CHUNK_SIZE, 0);
+        let read = loop {
+            match self.inner.read(&mut self.pending[start..]) {
+                Ok(read) => break read,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        };
+        self.pending.truncate(start + read);
+
+        let ready = if read == 0 {
+            self.eof = true;
+            if block_size > 1 && (self.pending.is_empty() || self.pending.len() % block_size != 0) {
+                return Err(stream_error(UnpadError.into()));
+            }
+            self.pending.len()
+        } else if block_size > 1 {
+            (self.pending.len() - 1) / block_size * block_size
+        } else {
+            self.pending.len()
+        };
+
+        self.decrypted.clear();
+        self.decrypted.extend(self.pending.drain(..ready));
+        self.cipher.decrypt_blocks(&mut self.decrypted);
+        self.pos = 0;
+
+        if self.eof && block_size > 1 {
+            let padding = *self.decrypted.last().unwrap_or(&0) as usize;
+            let len = self.decrypted.len();
+            if padding == 0
+                || padding > block_size
+                || self.decrypted[len - padding..]
+                    .iter()
+                    .any(|b| *b as usize != padding)
+            {
+                return Err(stream_error(UnpadError.into()));
+            }
+            self.decrypted.truncate(len - padding);
+        }
+
+        Ok(())
+    }
+}
+
+impl<'a> Read for DecryptReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
+        while self.pos == self.decrypted.len() {
+            if self.eof {
+                return Ok(0);
+            }
+            self.fill()?;
+        }
+
+        let len = buf.len().min(self.decrypted.len() - self.pos);
+        buf[..len].copy_from_slice(&self.decrypted[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
 #[cfg(feature = "save_kdbx4")]
 type Aes256CbcEncryptor = cbc::Encryptor<Aes256>;
 type Aes256CbcDecryptor = cbc::Decryptor<Aes256>;
 pub(crate) struct AES256Cipher {
-    key: Vec<u8>,
+    key: Zeroizing<Vec<u8>>,
     iv: Vec<u8>,
 }
 
 impl AES256Cipher {
     pub(crate) fn new(key: &[u8], iv: &[u8]) -> Result<Self, CryptographyError> {
         Ok(AES256Cipher {
-            key: Vec::from(key),
+            key: Zeroizing::new(Vec::from(key)),
             iv: Vec::from(iv),
         })
     }
@@ -82,14 +312,14 @@ impl Cipher for AES256Cipher {
 type TwofishCbcEncryptor = cbc::Encryptor<twofish::Twofish>;
 type TwofishCbcDecryptor = cbc::Decryptor<twofish::Twofish>;
 pub(crate) struct TwofishCipher {
-    key: Vec<u8>,
+    key: Zeroizing<Vec<u8>>,
     iv: Vec<u8>,
 }
 
 impl TwofishCipher {
     pub(crate) fn new(key: &[u8], iv: &[u8]) -> Result<Self, CryptographyError> {
         Ok(TwofishCipher {
-            key: Vec::from(key),
+            key: Zeroizing::new(Vec::from(key)),
             iv: Vec::from(iv),
         })
     }
@@ -137,30 +367,17 @@ impl Salsa20Cipher {
             cipher: Salsa20::new(&key, &iv),
         })
     }
-}
 
-impl Cipher for Salsa20Cipher {
     #[cfg(feature = "save_kdbx4")]
-    fn encrypt(&mut self, plaintext: &[u8]) -> Result<Vec<u8>, CryptographyError> {
-        let mut buffer = Vec::from(plaintext);
-        self.cipher.apply_keystream(&mut buffer);
-        Ok(buffer)
-    }
-    fn decrypt(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>, CryptographyError> {
-        let mut buffer = Vec::from(ciphertext);
-        self.cipher.apply_keystream(&mut buffer);
-        Ok(buffer)
-    }
-
-    #[cfg(feature = "save_kdbx4")]
-    fn iv_size() -> usize {
-        // or 16
+    /// The number of bytes expected by the cipher as a key.
+    pub(crate) fn key_size() -> usize {
         32
     }
+}
 
-    #[cfg(feature = "save_kdbx4")]
-    fn key_size() -> usize {
-        32
+impl InnerStreamCipher for Salsa20Cipher {
+    fn apply_keystream(&mut self, data: &mut [u8]) {
+        self.cipher.apply_keystream(data);
     }
 }
 
@@ -213,28 +430,84 @@ impl Cipher for ChaCha20Cipher {
     }
 }
 
-pub(crate) struct PlainCipher;
-impl PlainCipher {
-    pub(crate) fn new(_: &[u8]) -> Result<Self, CryptographyError> {
-        Ok(PlainCipher)
+impl InnerStreamCipher for ChaCha20Cipher {
+    fn apply_keystream(&mut self, data: &mut [u8]) {
+        self.cipher.apply_keystream(data);
     }
 }
-impl Cipher for PlainCipher {
+
+impl OuterCipherStream for ChaCha20Cipher {
+    fn block_size(&self) -> usize {
+        1
+    }
+
     #[cfg(feature = "save_kdbx4")]
-    fn encrypt(&mut self, plaintext: &[u8]) -> Result<Vec<u8>, CryptographyError> {
-        Ok(Vec::from(plaintext))
+    fn encrypt_blocks(&mut self, data: &mut [u8]) {
+        self.cipher.apply_keystream(data);
     }
-    fn decrypt(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>, CryptographyError> {
-        Ok(Vec::from(ciphertext))
+
+    fn decrypt_blocks(&mut self, data: &mut [u8]) {
+        self.cipher.apply_keystream(data);
     }
+}
 
-    #[cfg(feature = "save_kdbx4")]
-    fn iv_size() -> usize {
-        1
+/// The legacy ArcFourVariant inner cipher of KeePass 2 pre-releases, an RC4 keystream that
+/// swaps with the first element of the state during the key setup and drops its first 512 bytes.
+/// It is only supported for opening databases.
+pub(crate) struct ArcFourVariantCipher {
+    state: Zeroizing<[u8; 256]>,
+    i: u8,
+    j: u8,
+}
+
+impl ArcFourVariantCipher {
+    pub(crate) fn new(key: &[u8]) -> Result<Self, CryptographyError> {
+        if key.is_empty() {
+            return Err(CryptographyError::InvalidLength(cipher::InvalidLength));
+        }
+
+        let mut state = Zeroizing::new([0u8; 256]);
+        for (index, value) in state.iter_mut().enumerate() {
+            *value = index as u8;
+        }
+
+        let mut j = 0u8;
+        for (index, key_byte) in (0..256).zip(key.iter().cycle()) {
+            j = j.wrapping_add(state[index]).wrapping_add(*key_byte);
+            state.swap(0, j as usize);
+        }
+
+        let mut cipher = ArcFourVariantCipher { state, i: 0, j: 0 };
+        cipher.apply_keystream(&mut [0u8; 512]);
+        Ok(cipher)
+    }
+}
+
+impl InnerStreamCipher for ArcFourVariantCipher {
+    fn apply_keystream(&mut self, data: &mut [u8]) {
+        for byte in data.iter_mut() {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.state[self.i as usize]);
+            self.state.swap(self.i as usize, self.j as usize);
+            let index = self.state[self.i as usize].wrapping_add(self.state[self.j as usize]);
+            *byte ^= self.state[index as usize];
+        }
+    }
+}
+
+pub(crate) struct PlainCipher;
+impl PlainCipher {
+    pub(crate) fn new(_: &[u8]) -> Result<Self, CryptographyError> {
+        Ok(PlainCipher)
     }
 
     #[cfg(feature = "save_kdbx4")]
-    fn key_size() -> usize {
+    /// The number of bytes expected by the cipher as a key.
+    pub(crate) fn key_size() -> usize {
         1
     }
 }
+
+impl InnerStreamCipher for PlainCipher {
+    fn apply_keystream(&mut self, _data: &mut [u8]) {}
+}
@@ -1,17 +1,49 @@
+use std::{
+    sync::mpsc::{self, RecvTimeoutError},
+    time::Duration,
+};
+
 use aes::Aes256;
 use cipher::{
     generic_array::{typenum::U32, GenericArray},
     BlockEncrypt, KeyInit,
 };
 use sha2::{Digest, Sha256};
+use zeroize::{Zeroize, Zeroizing};
 
 use super::CryptographyError;
+use crate::progress::{self, CancellationToken, Phase};
 
-pub(crate) trait Kdf {
-    fn transform_key(
+/// How often a running Argon2 hash checks whether it was cancelled
+const CANCELLATION_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// A transformed key, wiped from memory when dropped
+pub(crate) type TransformedKey = Zeroizing<GenericArray<u8, U32>>;
+
+pub(crate) trait KeyDerivation {
+    fn transform_key(&self, composite_key: &GenericArray<u8, U32>)
+        -> Result<TransformedKey, CryptographyError>;
+
+    /// Identifies the KDF and all of its parameters, for caching transformed keys
+    fn cache_id(&self) -> Vec<u8>;
+
+    /// Transform the key, using the process-wide cache of transformed keys
+    fn transform_key_cached(
         &self,
         composite_key: &GenericArray<u8, U32>,
-    ) -> Result<GenericArray<u8, U32>, CryptographyError>;
+    ) -> Result<TransformedKey, CryptographyError> {
+        super::key_cache::cached_transform(&self.cache_id(), composite_key, || {
+            self.transform_key(composite_key)
+        })
+    }
+}
+
+/// Append length-prefixed fields to a cache id
+pub(super) fn push_fields(id: &mut Vec<u8>, fields: &[&[u8]]) {
+    for field in fields {
+        id.extend_from_slice(&(field.len() as u64).to_le_bytes());
+        id.extend_from_slice(field);
+    }
 }
 
 pub struct AesKdf {
@@ -19,28 +51,51 @@ pub struct AesKdf {
     pub rounds: u64,
 }
 
-impl Kdf for AesKdf {
+impl KeyDerivation for AesKdf {
     fn transform_key(
         &self,
         composite_key: &GenericArray<u8, U32>,
-    ) -> Result<GenericArray<u8, U32>, CryptographyError> {
+    ) -> Result<TransformedKey, CryptographyError> {
         let cipher = Aes256::new(&GenericArray::clone_from_slice(&self.seed));
         let mut block1 = GenericArray::clone_from_slice(&composite_key[..16]);
         let mut block2 = GenericArray::clone_from_slice(&composite_key[16..]);
-        for _ in 0..self.rounds {
-            cipher.encrypt_block(&mut block1);
-            cipher.encrypt_block(&mut block2);
+
+        // report the progress after every percent of the rounds
+        progress::start(Phase::KeyDerivation, Some(self.rounds))?;
+        let step = (self.rounds / 100).max(1);
+        let mut done = 0;
+        while done < self.rounds {
+            let rounds = step.min(self.rounds - done);
+            for _ in 0..rounds {
+                cipher.encrypt_block(&mut block1);
+                cipher.encrypt_block(&mut block2);
+            }
+            done += rounds;
+            if let Err(e) = progress::advance(rounds) {
+                block1.zeroize();
+                block2.zeroize();
+                return Err(e);
+            }
         }
 
         let mut digest = Sha256::new();
 
         digest.update(block1);
         digest.update(block2);
+        block1.zeroize();
+        block2.zeroize();
+
+        Ok(Zeroizing::new(digest.finalize()))
+    }
 
-        Ok(digest.finalize())
+    fn cache_id(&self) -> Vec<u8> {
+        let mut id = Vec::new();
+        push_fields(&mut id, &[b"aes", &self.seed, &self.rounds.to_le_bytes()]);
+        id
     }
 }
 
+#[derive(Clone)]
 pub struct Argon2Kdf {
     pub memory: u64,
     pub salt: Vec<u8>,
@@ -48,27 +103,95 @@ pub struct Argon2Kdf {
     pub parallelism: u32,
     pub version: argon2::Version,
     pub variant: argon2::Variant,
+    pub secret: Vec<u8>,
+    pub associated_data: Vec<u8>,
 }
 
-impl Kdf for Argon2Kdf {
+impl KeyDerivation for Argon2Kdf {
     fn transform_key(
         &self,
         composite_key: &GenericArray<u8, U32>,
-    ) -> Result<GenericArray<u8, U32>, CryptographyError> {
+    ) -> Result<TransformedKey, CryptographyError> {
+        // Argon2 cannot report its progress, only its start and its end
+        progress::start(Phase::KeyDerivation, Some(1))?;
+        let key = match progress::token() {
+            Some(token) if cfg!(not(target_arch = "wasm32")) => self.hash_cancellable(composite_key, &token)?,
+            _ => self.hash(composite_key)?,
+        };
+        progress::advance(1)?;
+
+        Ok(Zeroizing::new(*GenericArray::from_slice(&key)))
+    }
+
+    fn cache_id(&self) -> Vec<u8> {
+        let mut id = Vec::new();
+        push_fields(
+            &mut id,
+            &[
+                b"argon2",
+                &self.memory.to_le_bytes(),
+                &self.salt,
+                &self.iterations.to_le_bytes(),
+                &self.parallelism.to_le_bytes(),
+                &self.version.as_u32().to_le_bytes(),
+                &self.variant.as_u32().to_le_bytes(),
+                &self.secret,
+                &self.associated_data,
+            ],
+        );
+        id
+    }
+}
+
+impl Argon2Kdf {
+    fn hash(&self, composite_key: &GenericArray<u8, U32>) -> Result<Zeroizing<Vec<u8>>, CryptographyError> {
         let config = argon2::Config {
-            ad: &[],
+            ad: &self.associated_data,
             hash_length: 32,
             lanes: self.parallelism,
             mem_cost: (self.memory / 1024) as u32,
-            secret: &[],
+            secret: &self.secret,
             time_cost: self.iterations as u32,
             variant: self.variant,
             version: self.version,
         };
 
-        let key = argon2::hash_raw(composite_key, &self.salt, &config)?;
+        Ok(Zeroizing::new(argon2::hash_raw(
+            composite_key,
+            &self.salt,
+            &config,
+        )?))
+    }
+
+    /// Hash on a thread of its own, so that the hash can be given up on once `token` is
+    /// cancelled. The thread then finishes in the background and its result is dropped.
+    fn hash_cancellable(
+        &self,
+        composite_key: &GenericArray<u8, U32>,
+        token: &CancellationToken,
+    ) -> Result<Zeroizing<Vec<u8>>, CryptographyError> {
+        let kdf = self.clone();
+        let key = Zeroizing::new(*composite_key);
+
+        let (sender, receiver) = mpsc::channel();
+        std::thread::spawn(move || {
+            // the receiver is gone if the hash was cancelled
+            let _ = sender.send(kdf.hash(&key));
+        });
 
-        Ok(*GenericArray::from_slice(&key))
+        loop {
+            match receiver.recv_timeout(CANCELLATION_POLL_INTERVAL) {
+                Ok(key) => return key,
+                Err(RecvTimeoutError::Timeout) if token.is_cancelled() => {
+                    return Err(CryptographyError::Cancelled)
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => {
+                    // the thread panicked, hash on this one to surface the panic
+                    return self.hash(composite_key);
+                }
+            }
+        }
     }
 }
 
@@ -0,0 +1,212 @@
+//! Process-wide registry of key derivation functions provided by the application
+//!
+//! KDBX4 databases name their key derivation function (KDF) by a UUID in the KDF parameters of
+//! the header, next to the settings and the seed of the KDF. Databases using a KDF this crate does
+//! not know can be opened and saved once an implementation of `Kdf` is registered with
+//! `register_kdf`. Their KDF is then `KdfConfig::Custom`, which keeps the settings of the KDF
+//! while the seed is generated anew whenever the database is saved.
+
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+use cipher::generic_array::{typenum::U32, GenericArray};
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+use crate::{
+    crypt::kdf::{push_fields, KeyDerivation, TransformedKey},
+    db::PublicValue,
+    error::{CryptographyError, KdfConfigError, VariantDictionaryError},
+};
+
+/// The parameters of a KDF as stored in the header, without the `$UUID` naming the KDF
+pub type KdfParameters = BTreeMap<String, PublicValue>;
+
+/// Name of the parameter holding the seed, as used by the built-in KDFs
+pub const KDF_SEED_PARAMETER: &str = "S";
+
+/// A key derivation function that is not built into this crate
+pub trait Kdf: Send + Sync {
+    /// The UUID naming the KDF in the parameters of the header
+    fn uuid(&self) -> Uuid;
+
+    /// Split the parameters read from a header into the settings of the KDF and its seed. By
+    /// default, the seed is the byte array under `KDF_SEED_PARAMETER`.
+    fn split_parameters(
+        &self,
+        mut parameters: KdfParameters,
+    ) -> Result<(KdfParameters, Vec<u8>), KdfConfigError> {
+        match parameters.remove(KDF_SEED_PARAMETER) {
+            Some(PublicValue::Bytes(seed)) => Ok((parameters, seed)),
+            Some(_) => Err(VariantDictionaryError::Mistyped {
+                key: KDF_SEED_PARAMETER.to_string(),
+            }
+            .into()),
+            None => Err(VariantDictionaryError::MissingKey {
+                key: KDF_SEED_PARAMETER.to_string(),
+            }
+            .into()),
+        }
+    }
+
+    /// Combine the settings and a seed into the parameters written to a header, the inverse of
+    /// `split_parameters`
+    fn join_parameters(&self, settings: &KdfParameters, seed: &[u8]) -> KdfParameters {
+        let mut parameters = settings.clone();
+        parameters.insert(KDF_SEED_PARAMETER.to_string(), PublicValue::Bytes(seed.to_vec()));
+        parameters
+    }
+
+    /// The number of random bytes generated as the seed when saving a database
+    fn seed_size(&self) -> usize {
+        32
+    }
+
+    /// Derive the transformed key from the 32 byte composite key of the database
+    fn transform_key(
+        &self,
+        settings: &KdfParameters,
+        seed: &[u8],
+        composite_key: &[u8; 32],
+    ) -> Result<Zeroizing<[u8; 32]>, CryptographyError>;
+}
+
+static REGISTRY: Mutex<Vec<Arc<dyn Kdf>>> = Mutex::new(Vec::new());
+
+fn registry() -> std::sync::MutexGuard<'static, Vec<Arc<dyn Kdf>>> {
+    REGISTRY.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Use `kdf` for databases whose KDF has its UUID, replacing any KDF registered for it before.
+/// Built-in KDFs take precedence over registered ones.
+pub fn register_kdf(kdf: impl Kdf + 'static) {
+    let uuid = kdf.uuid();
+    let mut registry = registry();
+    registry.retain(|registered| registered.uuid() != uuid);
+    registry.push(Arc::new(kdf));
+}
+
+/// Remove the KDF registered for the given UUID, if any
+pub fn unregister_kdf(uuid: &Uuid) {
+    registry().retain(|registered| registered.uuid() != *uuid);
+}
+
+/// The KDF registered for the given UUID
+pub(crate) fn registered_kdf(uuid: &Uuid) -> Option<Arc<dyn Kdf>> {
+    registry()
+        .iter()
+        .find(|registered| registered.uuid() == *uuid)
+        .cloned()
+}
+
+/// A registered KDF with the settings and seed of a database. The KDF is looked up when the key
+/// is transformed, so that a KDF that is not registered fails like an unsupported one.
+pub(crate) struct RegisteredKdf {
+    pub uuid: Uuid,
+    pub settings: KdfParameters,
+    pub seed: Vec<u8>,
+}
+
+impl KeyDerivation for RegisteredKdf {
+    fn transform_key(
+        &self,
+        composite_key: &GenericArray<u8, U32>,
+    ) -> Result<TransformedKey, CryptographyError> {
+        let kdf = registered_kdf(&self.uuid).ok_or(CryptographyError::UnsupportedKdf { uuid: self.uuid })?;
+        let transformed = kdf.transform_key(&self.settings, &self.seed, &(*composite_key).into())?;
+        Ok(Zeroizing::new((*transformed).into()))
+    }
+
+    fn cache_id(&self) -> Vec<u8> {
+        let mut id = Vec::new();
+        push_fields(&mut id, &[b"custom", self.uuid.as_bytes(), &self.seed]);
+        for (key, value) in &self.settings {
+            push_fields(&mut id, &[key.as_bytes(), format!("{:?}", value).as_bytes()]);
+        }
+        id
+    }
+}
+
+#[cfg(feature = "save_kdbx4")]
+#[cfg(test)]
+mod kdf_registry_tests {
+    use uuid::{uuid, Uuid};
+    use zeroize::Zeroizing;
+
+    use super::{register_kdf, unregister_kdf, Kdf, KdfParameters};
+    use crate::{
+        config::{DatabaseConfig, KdfConfig},
+        db::{Entry, PublicValue},
+        error::{CryptographyError, DatabaseOpenError, DatabaseSaveError},
+        Database, DatabaseKey,
+    };
+
+    const TOY_KDF: Uuid = uuid!("5d1a6c2e-8b3f-4f07-a0d4-6e9b2c1f3a58");
+
+    /// A toy KDF that hashes the composite key with the seed a number of times
+    struct RepeatedHashKdf;
+
+    impl Kdf for RepeatedHashKdf {
+        fn uuid(&self) -> Uuid {
+            TOY_KDF
+        }
+
+        fn transform_key(
+            &self,
+            settings: &KdfParameters,
+            seed: &[u8],
+            composite_key: &[u8; 32],
+        ) -> Result<Zeroizing<[u8; 32]>, CryptographyError> {
+            let rounds = match settings.get("R") {
+                Some(PublicValue::UInt32(rounds)) => *rounds,
+                _ => 1,
+            };
+            let mut key = *composite_key;
+            for _ in 0..rounds {
+                key = crate::crypt::calculate_sha256(&[seed, &key])?.into();
+            }
+            Ok(Zeroizing::new(key))
+        }
+    }
+
+    #[test]
+    fn test_registered_kdf() {
+        register_kdf(RepeatedHashKdf);
+
+        let mut settings = KdfParameters::new();
+        settings.insert("R".to_string(), PublicValue::UInt32(3));
+        let kdf_config = KdfConfig::Custom {
+            uuid: TOY_KDF,
+            settings,
+        };
+        let mut db = Database::new(DatabaseConfig {
+            kdf_config: kdf_config.clone(),
+            ..Default::default()
+        });
+        db.root.add_child(Entry::new());
+        let key = DatabaseKey::new().with_password("test");
+
+        let mut data = Vec::new();
+        db.save(&mut data, key.clone()).unwrap();
+
+        let reopened = Database::open(&mut data.as_slice(), key.clone()).unwrap();
+        assert_eq!(reopened.config.kdf_config, kdf_config);
+        assert_eq!(kdf_config.uuid(), TOY_KDF);
+        assert_eq!(reopened.root, db.root);
+        assert!(matches!(
+            Database::open(&mut data.as_slice(), DatabaseKey::new().with_password("wrong")),
+            Err(DatabaseOpenError::InvalidCredentials)
+        ));
+
+        unregister_kdf(&TOY_KDF);
+        assert!(Database::open(&mut data.as_slice(), key.clone()).is_err());
+        assert!(matches!(
+            db.save(&mut Vec::new(), key),
+            Err(DatabaseSaveError::Cryptography(
+                CryptographyError::UnsupportedKdf { .. }
+            ))
+        ));
+    }
+}
@@ -0,0 +1,130 @@
+//! Process-wide cache of transformed keys
+//!
+//! Transforming the composite key with the KDF of a database deliberately takes a long time.
+//! Reopening the same database with the same key, e.g. after an external change, uses the same
+//! KDF parameters, so the result is cached. Entries are keyed by a hash of the composite key and
+//! all KDF parameters, bounded in number, and wiped from memory when evicted.
+//!
+//! High-security contexts can disable the cache with `set_key_cache_capacity(0)` or flush it with
+//! `clear_key_cache` whenever a database is locked.
+
+use std::{collections::VecDeque, sync::Mutex};
+
+use cipher::generic_array::{typenum::U32, GenericArray};
+use zeroize::Zeroizing;
+
+use crate::{
+    crypt::{calculate_sha256, kdf::TransformedKey},
+    error::CryptographyError,
+};
+
+/// Number of transformed keys cached by default
+pub const DEFAULT_KEY_CACHE_CAPACITY: usize = 4;
+
+/// A hash of a composite key and KDF parameters, or a transformed key
+type CacheBytes = Zeroizing<[u8; 32]>;
+
+struct KeyCache {
+    capacity: usize,
+
+    /// Pairs of (hash of composite key and KDF parameters, transformed key), most recently used
+    /// first
+    entries: VecDeque<(CacheBytes, CacheBytes)>,
+}
+
+static CACHE: Mutex<KeyCache> = Mutex::new(KeyCache {
+    capacity: DEFAULT_KEY_CACHE_CAPACITY,
+    entries: VecDeque::new(),
+});
+
+fn cache() -> std::sync::MutexGuard<'static, KeyCache> {
+    CACHE.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Set how many transformed keys are cached. A capacity of 0 disables the cache.
+pub fn set_key_cache_capacity(capacity: usize) {
+    let mut cache = cache();
+    cache.capacity = capacity;
+    cache.entries.truncate(capacity);
+}
+
+/// Remove all cached transformed keys
+pub fn clear_key_cache() {
+    cache().entries.clear();
+}
+
+/// Transform a key with `transform`, or return the cached result of an earlier transformation
+/// of the same composite key with the same KDF parameters, identified by `kdf_id`
+pub(crate) fn cached_transform(
+    kdf_id: &[u8],
+    composite_key: &GenericArray<u8, U32>,
+    transform: impl FnOnce() -> Result<TransformedKey, CryptographyError>,
+) -> Result<TransformedKey, CryptographyError> {
+    if cache().capacity == 0 {
+        return transform();
+    }
+
+    let id = Zeroizing::new(<[u8; 32]>::from(calculate_sha256(&[kdf_id, composite_key])?));
+
+    {
+        let mut cache = cache();
+        if let Some(position) = cache.entries.iter().position(|(key, _)| *key == id) {
+            let entry = cache.entries.remove(position).unwrap();
+            let transformed = Zeroizing::new(GenericArray::clone_from_slice(&entry.1[..]));
+            cache.entries.push_front(entry);
+            return Ok(transformed);
+        }
+    }
+
+    // the lock is not held while transforming, so that other databases can be opened meanwhile
+    let transformed = transform()?;
+
+    let mut cache = cache();
+    if cache.capacity > 0 && !cache.entries.iter().any(|(key, _)| *key == id) {
+        let mut value = Zeroizing::new([0u8; 32]);
+        value.copy_from_slice(&transformed);
+        cache.entries.push_front((id, value));
+        let capacity = cache.capacity;
+        cache.entries.truncate(capacity);
+    }
+
+    Ok(transformed)
+}
+
+#[cfg(test)]
+mod key_cache_tests {
+    use std::cell::Cell;
+
+    use cipher::generic_array::GenericArray;
+    use zeroize::Zeroizing;
+
+    use super::{cached_transform, clear_key_cache};
+
+    #[test]
+    fn test_cached_transform() {
+        let calls = Cell::new(0);
+        let transform = |value: u8| {
+            calls.set(calls.get() + 1);
+            Ok(Zeroizing::new(GenericArray::clone_from_slice(&[value; 32])))
+        };
+
+        // a unique KDF, so that other tests using the cache do not interfere
+        let id = uuid::Uuid::new_v4();
+        let composite_key = GenericArray::clone_from_slice(&[1u8; 32]);
+
+        let first = cached_transform(id.as_bytes(), &composite_key, || transform(7)).unwrap();
+        let second = cached_transform(id.as_bytes(), &composite_key, || transform(8)).unwrap();
+        assert_eq!(first, second);
+        assert_eq!(calls.get(), 1);
+
+        // other parameters or keys are transformed again
+        let other_key = GenericArray::clone_from_slice(&[2u8; 32]);
+        cached_transform(id.as_bytes(), &other_key, || transform(9)).unwrap();
+        cached_transform(b"other", &composite_key, || transform(9)).unwrap();
+        assert_eq!(calls.get(), 3);
+
+        clear_key_cache();
+        let third = cached_transform(id.as_bytes(), &composite_key, || transform(8)).unwrap();
+        assert_eq!(third[0], 8);
+    }
+}
@@ -12,8 +12,13 @@ use sha2::{Digest, Sha256, Sha512};
 
 use crate::error::CryptographyError;
 
+#[cfg(feature = "ssh")]
+pub(crate) mod bcrypt_pbkdf;
+pub(crate) mod cipher_registry;
 pub(crate) mod ciphers;
 pub(crate) mod kdf;
+pub(crate) mod kdf_registry;
+pub(crate) mod key_cache;
 
 pub(crate) fn calculate_hmac(
     elements: &[&[u8]],
@@ -30,6 +35,18 @@ pub(crate) fn calculate_hmac(
     Ok(result.into_bytes())
 }
 
+/// Check `mac` against the HMAC-SHA256 of the elements in constant time
+#[cfg(any(feature = "search_cache", feature = "quick_unlock", feature = "journal"))]
+pub(crate) fn verify_hmac(elements: &[&[u8]], key: &[u8], mac: &[u8]) -> Result<bool, CryptographyError> {
+    let mut hmac = Hmac::<Sha256>::new_from_slice(key)?;
+
+    for element in elements {
+        hmac.update(element);
+    }
+
+    Ok(hmac.verify_slice(mac).is_ok())
+}
+
 #[cfg(feature = "challenge_response")]
 pub(crate) fn calculate_hmac_sha1(
     elements: &[&[u8]],
@@ -0,0 +1,170 @@
+//! Transient labels on groups and entries
+//!
+//! User interfaces attach state to nodes that has no place in the file: search matches, the
+//! current selection, errors of a failed sync. `Database::annotations` keeps such labels by node
+//! UUID, next to the database instead of in its custom data. Annotations are cloned with the
+//! database, so they carry over to snapshots and filtered views, but they are never saved.
+//!
+//! ```
+//! use keepass::{db::Entry, Database};
+//!
+//! let mut db = Database::new(Default::default());
+//! let entry = Entry::new();
+//! let uuid = entry.uuid;
+//! db.root.add_child(entry);
+//!
+//! db.annotations.add(uuid, "selected");
+//! db.annotations.set(uuid, "error", "Could not fetch icon");
+//! assert!(db.annotations.has(uuid, "selected"));
+//!
+//! // e.g. when the selection is cleared
+//! db.annotations.clear_label("selected");
+//! assert_eq!(db.annotations.get(uuid, "error"), Some("Could not fetch icon"));
+//! ```
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use uuid::Uuid;
+
+use crate::db::{Group, NodeRef};
+
+/// Labels with optional values, attached to groups and entries by UUID. Never saved.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Annotations {
+    labels: HashMap<Uuid, BTreeMap<String, String>>,
+}
+
+impl Annotations {
+    /// Attach a label without a value to a node
+    pub fn add(&mut self, node: Uuid, label: &str) {
+        self.set(node, label, "");
+    }
+
+    /// Attach a label with a value to a node, replacing the previous value
+    pub fn set(&mut self, node: Uuid, label: &str, value: &str) {
+        self.labels
+            .entry(node)
+            .or_default()
+            .insert(label.to_string(), value.to_string());
+    }
+
+    /// The value of a label of a node
+    pub fn get(&self, node: Uuid, label: &str) -> Option<&str> {
+        self.labels.get(&node)?.get(label).map(|v| v.as_str())
+    }
+
+    pub fn has(&self, node: Uuid, label: &str) -> bool {
+        self.get(node, label).is_some()
+    }
+
+    /// All labels of a node and their values
+    pub fn labels(&self, node: Uuid) -> Option<&BTreeMap<String, String>> {
+        self.labels.get(&node)
+    }
+
+    /// Remove a label from a node, returning its value
+    pub fn remove(&mut self, node: Uuid, label: &str) -> Option<String> {
+        let labels = self.labels.get_mut(&node)?;
+        let value = labels.remove(label);
+        if labels.is_empty() {
+            self.labels.remove(&node);
+        }
+        value
+    }
+
+    /// The nodes that have a label
+    pub fn nodes_with(&self, label: &str) -> Vec<Uuid> {
+        self.labels
+            .iter()
+            .filter(|(_, labels)| labels.contains_key(label))
+            .map(|(uuid, _)| *uuid)
+            .collect()
+    }
+
+    /// Remove a label from all nodes
+    pub fn clear_label(&mut self, label: &str) {
+        self.labels.retain(|_, labels| {
+            labels.remove(label);
+            !labels.is_empty()
+        });
+    }
+
+    /// Remove the labels of all nodes
+    pub fn clear(&mut self) {
+        self.labels.clear();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty()
+    }
+
+    /// Remove the labels of nodes that are no longer in the tree below `root`
+    pub fn retain_existing(&mut self, root: &Group) {
+        let existing: HashSet<Uuid> = root
+            .iter()
+            .map(|node| match node {
+                NodeRef::Group(g) => g.uuid,
+                NodeRef::Entry(e) => e.uuid,
+            })
+            .collect();
+        self.labels.retain(|uuid, _| existing.contains(uuid));
+    }
+}
+
+#[cfg(test)]
+mod annotations_tests {
+    use crate::db::{Database, Entry, Group};
+
+    #[test]
+    fn test_annotations() {
+        let mut db = Database::new(Default::default());
+        let group = Group::new("Work");
+        let group_uuid = group.uuid;
+        let entry = Entry::new();
+        let entry_uuid = entry.uuid;
+        db.root.add_child(group);
+        db.root.add_child(entry);
+
+        db.annotations.add(entry_uuid, "matched");
+        db.annotations.add(group_uuid, "matched");
+        db.annotations.set(entry_uuid, "error", "Sync failed");
+        let mut matched = db.annotations.nodes_with("matched");
+        matched.sort();
+        let mut expected = vec![entry_uuid, group_uuid];
+        expected.sort();
+        assert_eq!(matched, expected);
+
+        // annotations are carried over to clones, but not saved
+        let snapshot = db.clone();
+        assert_eq!(snapshot.annotations.get(entry_uuid, "error"), Some("Sync failed"));
+
+        db.annotations.clear_label("matched");
+        assert!(!db.annotations.has(group_uuid, "matched"));
+        assert_eq!(db.annotations.labels(group_uuid), None);
+        assert_eq!(
+            db.annotations.remove(entry_uuid, "error").as_deref(),
+            Some("Sync failed")
+        );
+        assert!(db.annotations.is_empty());
+
+        db.annotations.add(entry_uuid, "selected");
+        db.root.children.clear();
+        db.annotations.retain_existing(&db.root);
+        assert!(db.annotations.is_empty());
+    }
+
+    #[cfg(feature = "save_kdbx4")]
+    #[test]
+    fn test_annotations_are_not_saved() {
+        use crate::{config::DatabaseConfig, DatabaseKey};
+
+        let mut db = Database::new(DatabaseConfig::default());
+        let uuid = db.root.uuid;
+        db.annotations.add(uuid, "selected");
+
+        let key = || DatabaseKey::new().with_password("annotations");
+        let mut data = Vec::new();
+        db.save(&mut data, key()).unwrap();
+        assert!(Database::parse(&data, key()).unwrap().annotations.is_empty());
+    }
+}
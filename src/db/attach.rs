@@ -0,0 +1,475 @@
+//! Bulk import of files from a directory as attachments
+//!
+//! Attachment contents are stored once per database in an `AttachmentPool` and referenced by
+//! identifier from `Entry::attachments`, so importing needs access to both.
+
+use std::path::{Path, PathBuf};
+
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::{
+    db::{AttachmentPool, AttachmentRef, Database, Entry, HeaderAttachment, Value},
+    format::DatabaseVersion,
+};
+
+// flag of a header attachment that should be kept protected in memory
+const PROTECTED_FLAG: u8 = 0x01;
+
+/// Errors while importing attachments from a directory
+#[derive(Debug, Error)]
+pub enum AttachError {
+    #[error("Could not read {}: {}", path.display(), source)]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("No entry with UUID {0}")]
+    EntryNotFound(Uuid),
+
+    #[error("No group with UUID {0}")]
+    GroupNotFound(Uuid),
+}
+
+/// Settings for importing files from a directory
+#[derive(Debug, Clone)]
+pub struct AttachOptions {
+    /// Files larger than this many bytes are skipped
+    pub max_file_size: Option<u64>,
+
+    /// Once this many bytes have been imported, remaining files are skipped
+    pub max_total_size: Option<u64>,
+
+    /// Patterns of files to skip, matched against the file name and the path relative to the
+    /// imported directory (using `/` as separator). `*` matches any sequence of characters and
+    /// `?` matches a single character.
+    pub exclude: Vec<String>,
+
+    /// Whether to descend into subdirectories. Symbolic links to directories are never followed,
+    /// while those to files are imported like files.
+    pub recursive: bool,
+
+    /// Whether to keep the attachments protected in memory
+    pub protect: bool,
+}
+
+impl Default for AttachOptions {
+    fn default() -> Self {
+        AttachOptions {
+            max_file_size: None,
+            max_total_size: None,
+            exclude: Vec::new(),
+            recursive: true,
+            protect: false,
+        }
+    }
+}
+
+/// Why a file was not imported
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SkipReason {
+    Excluded,
+    TooLarge,
+    TotalSizeExceeded,
+}
+
+/// The outcome of importing a directory
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct AttachReport {
+    /// Relative paths of the imported files
+    pub attached: Vec<String>,
+
+    /// Relative paths of files that were not imported
+    pub skipped: Vec<(String, SkipReason)>,
+
+    /// Entries created for the imported files, if importing with one entry per file
+    pub created_entries: Vec<Uuid>,
+
+    /// Total size of the imported files in bytes
+    pub total_size: u64,
+}
+
+impl Entry {
+    /// Attach all files in a directory tree to this entry, naming each attachment after its path
+    /// relative to `path`. The file contents are added to `pool`, which should be the pool of the
+    /// database containing this entry, see `AttachmentPool`.
+    pub fn attach_directory<P: AttachmentPool + ?Sized>(
+        &mut self,
+        pool: &mut P,
+        path: impl AsRef<Path>,
+        options: &AttachOptions,
+    ) -> Result<AttachReport, AttachError> {
+        let mut report = AttachReport::default();
+
+        for (name, content) in read_directory(path.as_ref(), options, &mut report)? {
+            let identifier = pool.add_content(content, options.protect);
+            self.attachments.retain(|a| a.name != name);
+            self.attachments.push(AttachmentRef { name, identifier });
+        }
+
+        if !report.attached.is_empty() {
+            self.update_history();
+        }
+
+        Ok(report)
+    }
+}
+
+impl Database {
+    /// Attach all files in a directory tree to an existing entry, see `Entry::attach_directory`
+    pub fn attach_directory(
+        &mut self,
+        entry: Uuid,
+        path: impl AsRef<Path>,
+        options: &AttachOptions,
+    ) -> Result<AttachReport, AttachError> {
+        let target = self
+            .root
+            .entry_by_uuid_mut(&entry)
+            .ok_or(AttachError::EntryNotFound(entry))?;
+        match self.config.version {
+            DatabaseVersion::KDB3(_) => target.attach_directory(&mut self.meta.binaries, path, options),
+            _ => target.attach_directory(&mut self.header_attachments, path, options),
+        }
+    }
+
+    /// Import all files in a directory tree into a group, creating one entry per file. Each entry
+    /// is titled after the path of its file relative to `path` and holds the file as its only
+    /// attachment.
+    pub fn import_directory(
+        &mut self,
+        group: Uuid,
+        path: impl AsRef<Path>,
+        options: &AttachOptions,
+    ) -> Result<AttachReport, AttachError> {
+        let target = self
+            .root
+            .group_by_uuid_mut(&group)
+            .ok_or(AttachError::GroupNotFound(group))?;
+        let (binaries, header_attachments) = (&mut self.meta.binaries, &mut self.header_attachments);
+        let pool: &mut dyn FnMut(Vec<u8>) -> usize = match self.config.version {
+            DatabaseVersion::KDB3(_) => &mut |content| binaries.add_content(content, options.protect),
+            _ => &mut |content| header_attachments.add_content(content, options.protect),
+        };
+
+        let mut report = AttachReport::default();
+        for (name, content) in read_directory(path.as_ref(), options, &mut report)? {
+            let identifier = pool(content);
+
+            let mut entry = Entry::new();
+            entry
+                .fields
+                .insert("Title".to_string(), Value::Unprotected(name.clone()));
+            entry.attachments.push(AttachmentRef { name, identifier });
+
+            report.created_entries.push(entry.uuid);
+            target.add_child(entry);
+        }
+
+        Ok(report)
+    }
+}
+
+/// Add attachment content to the header, reusing an existing attachment with the same content
+pub(crate) fn add_header_attachment(
+    header_attachments: &mut Vec<HeaderAttachment>,
+    content: Vec<u8>,
+    protect: bool,
+) -> usize {
+    let flags = if protect { PROTECTED_FLAG } else { 0 };
+
+    if let Some(index) = header_attachments
+        .iter()
+        .position(|a| a.flags == flags && a.data().is_ok_and(|data| *data == content[..]))
+    {
+        return index;
+    }
+
+    header_attachments.push(HeaderAttachment {
+        flags,
+        content,
+        packed: false,
+        external: false,
+    });
+    header_attachments.len() - 1
+}
+
+/// Read the files to import from a directory, in the order of their relative paths
+fn read_directory(
+    root: &Path,
+    options: &AttachOptions,
+    report: &mut AttachReport,
+) -> Result<Vec<(String, Vec<u8>)>, AttachError> {
+    let mut files = Vec::new();
+    collect_files(root, root, options, &mut files)?;
+    files.sort();
+
+    let mut out = Vec::new();
+    for (name, path) in files {
+        let file_name = path.file_name().map(|n| n.to_string_lossy()).unwrap_or_default();
+        if options
+            .exclude
+            .iter()
+            .any(|pattern| glob_match(pattern, &name) || glob_match(pattern, &file_name))
+        {
+            report.skipped.push((name, SkipReason::Excluded));
+            continue;
+        }
+
+        let io_error = |source| AttachError::Io {
+            path: path.clone(),
+            source,
+        };
+
+        let size = std::fs::metadata(&path).map_err(io_error)?.len();
+        if options.max_file_size.is_some_and(|max| size > max) {
+            report.skipped.push((name, SkipReason::TooLarge));
+            continue;
+        }
+        if options
+            .max_total_size
+            .is_some_and(|max| report.total_size + size > max)
+        {
+            report.skipped.push((name, SkipReason::TotalSizeExceeded));
+            continue;
+        }
+
+        let content = std::fs::read(&path).map_err(io_error)?;
+        report.total_size += content.len() as u64;
+        report.attached.push(name.clone());
+        out.push((name, content));
+    }
+
+    Ok(out)
+}
+
+fn collect_files(
+    root: &Path,
+    dir: &Path,
+    options: &AttachOptions,
+    files: &mut Vec<(String, PathBuf)>,
+) -> Result<(), AttachError> {
+    let io_error = |source| AttachError::Io {
+        path: dir.to_path_buf(),
+        source,
+    };
+
+    for dir_entry in std::fs::read_dir(dir).map_err(io_error)? {
+        let dir_entry = dir_entry.map_err(io_error)?;
+        let path = dir_entry.path();
+
+        // unlike `Path::is_dir`, the file type of a directory entry does not follow symbolic links,
+        // which could lead back up the tree
+        let file_type = dir_entry.file_type().map_err(io_error)?;
+        if file_type.is_dir() {
+            if options.recursive {
+                collect_files(root, &path, options, files)?;
+            }
+        } else if file_type.is_file() || (file_type.is_symlink() && path.is_file()) {
+            let relative = path.strip_prefix(root).unwrap_or(&path);
+            let name = relative
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            files.push((name, path));
+        }
+    }
+
+    Ok(())
+}
+
+/// Match a text against a pattern where `*` matches any sequence and `?` any single character
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            p = star + 1;
+            t = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod attach_tests {
+    use std::path::PathBuf;
+
+    use crate::{
+        db::{Database, Entry},
+        format::DatabaseVersion,
+    };
+
+    use super::{glob_match, AttachOptions, SkipReason};
+
+    fn test_directory() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("keepass-attach-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(dir.join("certs")).unwrap();
+        std::fs::write(dir.join("id_ed25519"), b"private key").unwrap();
+        std::fs::write(dir.join("id_ed25519.pub"), b"public key").unwrap();
+        std::fs::write(dir.join("notes.tmp"), b"scratch").unwrap();
+        std::fs::write(dir.join("certs/server.pem"), b"certificate").unwrap();
+        std::fs::write(dir.join("certs/copy.pem"), b"certificate").unwrap();
+        std::fs::write(dir.join("certs/huge.bin"), vec![0u8; 4096]).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("*.tmp", "notes.tmp"));
+        assert!(glob_match("certs/*", "certs/server.pem"));
+        assert!(glob_match("id_?????19", "id_ed25519"));
+        assert!(!glob_match("*.tmp", "notes.tmp.bak"));
+        assert!(!glob_match("certs/*", "keys/server.pem"));
+    }
+
+    #[test]
+    fn test_attach_directory() {
+        let dir = test_directory();
+
+        let mut db = Database::new(Default::default());
+        let entry = Entry::new();
+        let entry_uuid = entry.uuid;
+        db.root.add_child(entry);
+
+        let options = AttachOptions {
+            max_file_size: Some(1024),
+            exclude: vec!["*.tmp".to_string()],
+            ..Default::default()
+        };
+        let report = db.attach_directory(entry_uuid, &dir, &options).unwrap();
+
+        assert_eq!(
+            report.attached,
+            vec![
+                "certs/copy.pem",
+                "certs/server.pem",
+                "id_ed25519",
+                "id_ed25519.pub"
+            ]
+        );
+        assert_eq!(
+            report.skipped,
+            vec![
+                ("certs/huge.bin".to_string(), SkipReason::TooLarge),
+                ("notes.tmp".to_string(), SkipReason::Excluded),
+            ]
+        );
+
+        // identical files share the same attachment content
+        assert_eq!(db.header_attachments.len(), 3);
+
+        let entry = db.root.entries()[0];
+        let attachments = entry.attachments();
+        assert_eq!(attachments.len(), 4);
+        assert_eq!(attachments[0].identifier, attachments[1].identifier);
+        assert_eq!(
+            db.header_attachments[attachments[2].identifier].content,
+            b"private key"
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_import_directory() {
+        let dir = test_directory();
+
+        let mut db = Database::new(Default::default());
+        let root = db.root.uuid;
+
+        let options = AttachOptions {
+            max_total_size: Some(25),
+            recursive: false,
+            protect: true,
+            ..Default::default()
+        };
+        let report = db.import_directory(root, &dir, &options).unwrap();
+
+        assert_eq!(report.attached, vec!["id_ed25519", "id_ed25519.pub"]);
+        assert_eq!(
+            report.skipped,
+            vec![("notes.tmp".to_string(), SkipReason::TotalSizeExceeded)]
+        );
+        assert_eq!(report.created_entries.len(), 2);
+        assert_eq!(report.total_size, 21);
+
+        let entries = db.root.entries();
+        assert_eq!(entries[0].get_title(), Some("id_ed25519"));
+        assert_eq!(entries[0].attachments()[0].name, "id_ed25519");
+        assert!(db.header_attachments.iter().all(|a| a.flags == 1));
+
+        assert!(db.import_directory(uuid::Uuid::new_v4(), &dir, &options).is_err());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_import_directory_into_kdbx3() {
+        let dir = test_directory();
+
+        let mut db = Database::new(Default::default());
+        db.config.version = DatabaseVersion::KDB3(1);
+        let root = db.root.uuid;
+
+        let options = AttachOptions {
+            exclude: vec!["*.tmp".to_string(), "huge.bin".to_string()],
+            ..Default::default()
+        };
+        db.import_directory(root, &dir, &options).unwrap();
+
+        assert!(db.header_attachments.is_empty());
+        assert_eq!(db.meta.binaries.binaries.len(), 3);
+        let entry = db.root.entries()[0];
+        assert_eq!(entry.get_title(), Some("certs/copy.pem"));
+        let content = entry.get_attachment(&db.meta.binaries, "certs/copy.pem").unwrap();
+        assert_eq!(content.content, b"certificate");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlinked_directories_are_not_followed() {
+        let dir = test_directory();
+        std::os::unix::fs::symlink(&dir, dir.join("certs/loop")).unwrap();
+        std::os::unix::fs::symlink(dir.join("id_ed25519"), dir.join("certs/key")).unwrap();
+
+        let mut entry = Entry::new();
+        let mut header_attachments = Vec::new();
+        let report = entry
+            .attach_directory(&mut header_attachments, &dir, &Default::default())
+            .unwrap();
+        assert_eq!(
+            report.attached,
+            vec![
+                "certs/copy.pem",
+                "certs/huge.bin",
+                "certs/key",
+                "certs/server.pem",
+                "id_ed25519",
+                "id_ed25519.pub",
+                "notes.tmp"
+            ]
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,335 @@
+//! Attachments of entries and the pools holding their contents
+//!
+//! Databases store the contents of all attachments once, and entries refer to them by an
+//! identifier: KDBX 4 keeps them in the inner header, in `Database::header_attachments`, and KDBX 3
+//! in the binaries of the metadata, `Meta::binaries`. The methods here keep entries and an
+//! `AttachmentPool` in sync, so that the pool does not have to be managed by hand:
+//!
+//! ```
+//! use keepass::db::{Database, Entry};
+//!
+//! let mut db = Database::new(Default::default());
+//! let mut entry = Entry::new();
+//! entry.add_attachment(&mut db.header_attachments, "id_ed25519.pub", b"ssh-ed25519 AAAA".to_vec());
+//! entry.add_attachment(&mut db.header_attachments, "copy.pub", b"ssh-ed25519 AAAA".to_vec());
+//! assert_eq!(db.header_attachments.len(), 1);
+//!
+//! let content = entry.get_attachment(&db.header_attachments, "copy.pub").unwrap().data()?;
+//! assert_eq!(&content[..], b"ssh-ed25519 AAAA");
+//!
+//! entry.remove_attachment("copy.pub");
+//! entry.remove_attachment("id_ed25519.pub");
+//! db.root.add_child(entry);
+//! // the entry's history still refers to the content
+//! assert_eq!(db.remove_orphaned_attachments(), 0);
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
+//! Identical contents are stored only once. Removing an attachment from an entry leaves its
+//! content in the pool, as the history of the entry or other entries may still refer to it.
+//! Contents that are no longer referred to are kept until `Database::remove_orphaned_attachments`
+//! drops them, or a save with `SaveOptions::drop_orphaned_attachments` leaves them out of the
+//! file.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::db::{
+    attach::add_header_attachment, convert::remap_attachments, AttachmentRef, BinaryAttachment,
+    BinaryAttachments, Database, Entry, Group, HeaderAttachment, Node,
+};
+
+/// Where the contents of attachments are kept: `Database::header_attachments` in KDBX 4
+/// databases, and `Meta::binaries` in KDBX 3 databases
+pub trait AttachmentPool {
+    /// How the pool holds a content
+    type Attachment;
+
+    /// Add a content to the pool unless it holds the same content already, and return the
+    /// identifier that entries refer to it by. KDBX 3 cannot keep attachments protected in memory,
+    /// so `protect` only applies to KDBX 4.
+    fn add_content(&mut self, content: Vec<u8>, protect: bool) -> usize;
+
+    /// The content with the given identifier
+    fn content(&self, identifier: usize) -> Option<&Self::Attachment>;
+}
+
+impl AttachmentPool for Vec<HeaderAttachment> {
+    type Attachment = HeaderAttachment;
+
+    fn add_content(&mut self, content: Vec<u8>, protect: bool) -> usize {
+        add_header_attachment(self, content, protect)
+    }
+
+    fn content(&self, identifier: usize) -> Option<&HeaderAttachment> {
+        self.as_slice().get(identifier)
+    }
+}
+
+impl AttachmentPool for BinaryAttachments {
+    type Attachment = BinaryAttachment;
+
+    fn add_content(&mut self, content: Vec<u8>, _protect: bool) -> usize {
+        let existing = self
+            .binaries
+            .iter()
+            .filter(|b| b.data().is_ok_and(|data| *data == content[..]))
+            .find_map(binary_identifier);
+        if let Some(identifier) = existing {
+            return identifier;
+        }
+
+        let identifier = self
+            .binaries
+            .iter()
+            .filter_map(binary_identifier)
+            .max()
+            .map_or(0, |max| max + 1);
+        self.binaries.push(BinaryAttachment {
+            identifier: Some(identifier.to_string()),
+            compressed: true,
+            content,
+            packed: false,
+            external: false,
+        });
+        identifier
+    }
+
+    fn content(&self, identifier: usize) -> Option<&BinaryAttachment> {
+        self.binaries
+            .iter()
+            .find(|b| binary_identifier(b) == Some(identifier))
+    }
+}
+
+/// The identifier that entries refer to a binary of the metadata by
+pub(crate) fn binary_identifier(binary: &BinaryAttachment) -> Option<usize> {
+    binary.identifier.as_deref()?.parse().ok()
+}
+
+impl Entry {
+    /// Attach content to the entry, replacing an attachment with the same name. The content is
+    /// added to `pool`, which should be the pool of the database containing this entry, unless it
+    /// already holds the same content.
+    pub fn add_attachment<P: AttachmentPool + ?Sized>(&mut self, pool: &mut P, name: &str, content: Vec<u8>) {
+        let identifier = pool.add_content(content, false);
+        self.attachments.retain(|a| a.name != name);
+        self.attachments.push(AttachmentRef {
+            name: name.to_string(),
+            identifier,
+        });
+        self.update_history();
+    }
+
+    /// The attachment with the given name, looked up in the pool of the database containing this
+    /// entry
+    pub fn get_attachment<'a, P: AttachmentPool + ?Sized>(
+        &self,
+        pool: &'a P,
+        name: &str,
+    ) -> Option<&'a P::Attachment> {
+        let attachment = self.attachments.iter().find(|a| a.name == name)?;
+        pool.content(attachment.identifier)
+    }
+
+    /// Remove the attachment with the given name from the entry, returning the reference to its
+    /// content if the entry had one
+    pub fn remove_attachment(&mut self, name: &str) -> Option<AttachmentRef> {
+        let index = self.attachments.iter().position(|a| a.name == name)?;
+        let removed = self.attachments.remove(index);
+        self.update_history();
+        Some(removed)
+    }
+}
+
+impl Database {
+    /// Drop the attachment contents that no entry or history entry refers to, from both
+    /// `header_attachments` and `Meta::binaries`, and merge identical ones in `header_attachments`.
+    /// Returns the number of removed contents.
+    pub fn remove_orphaned_attachments(&mut self) -> usize {
+        let count_before = self.header_attachments.len() + self.meta.binaries.binaries.len();
+        let (attachments, indices) = compact(&self.header_attachments, &self.root);
+        let attachments = attachments.into_iter().cloned().collect();
+
+        self.header_attachments = attachments;
+        remap_attachments(&mut self.root, &indices);
+        self.meta
+            .binaries
+            .binaries
+            .retain(|b| binary_identifier(b).is_some_and(|identifier| indices.contains_key(&identifier)));
+        count_before - self.header_attachments.len() - self.meta.binaries.binaries.len()
+    }
+}
+
+/// The attachment contents referred to in a group, each only once, with the new index of every
+/// referred old one. Identifiers that refer to no content are mapped to themselves.
+pub(crate) fn compact<'a>(
+    header_attachments: &'a [HeaderAttachment],
+    root: &Group,
+) -> (Vec<&'a HeaderAttachment>, HashMap<usize, usize>) {
+    let mut used = HashSet::new();
+    collect_identifiers(root, &mut used);
+    let mut used: Vec<_> = used.into_iter().collect();
+    used.sort_unstable();
+
+    let mut attachments: Vec<&HeaderAttachment> = Vec::new();
+    let mut indices = HashMap::new();
+    for old in used {
+        let attachment = match header_attachments.get(old) {
+            Some(attachment) => attachment,
+            None => {
+                indices.insert(old, old);
+                continue;
+            }
+        };
+
+        let new = match attachments.iter().position(|a| *a == attachment) {
+            Some(new) => new,
+            None => {
+                attachments.push(attachment);
+                attachments.len() - 1
+            }
+        };
+        indices.insert(old, new);
+    }
+
+    (attachments, indices)
+}
+
+fn collect_identifiers(group: &Group, used: &mut HashSet<usize>) {
+    for node in &group.children {
+        match node {
+            Node::Group(g) => collect_identifiers(g, used),
+            Node::Entry(e) => {
+                let history = e.history.iter().flat_map(|h| h.entries.iter());
+                for entry in std::iter::once(e).chain(history) {
+                    used.extend(entry.attachments.iter().map(|a| a.identifier));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod attachment_pool_tests {
+    use crate::db::{AttachmentRef, Database, Entry, HeaderAttachment};
+    #[cfg(feature = "save_kdbx4")]
+    use crate::{
+        config::{DatabaseVersion, KdfConfig},
+        db::{BinaryAttachment, SaveOptions},
+    };
+
+    #[test]
+    fn test_add_and_remove_attachments() {
+        let mut db = Database::new(Default::default());
+        let mut entry = Entry::new();
+        entry.add_attachment(&mut db.header_attachments, "a.txt", b"a".to_vec());
+        entry.add_attachment(&mut db.header_attachments, "b.txt", b"b".to_vec());
+        entry.add_attachment(&mut db.header_attachments, "a.txt", b"b".to_vec());
+
+        assert_eq!(db.header_attachments.len(), 2);
+        assert_eq!(entry.attachments().len(), 2);
+        let a = entry.get_attachment(&db.header_attachments, "a.txt").unwrap();
+        assert_eq!(&a.data().unwrap()[..], b"b");
+        assert!(entry.get_attachment(&db.header_attachments, "c.txt").is_none());
+
+        assert_eq!(entry.remove_attachment("b.txt").unwrap().identifier, 1);
+        assert!(entry.remove_attachment("b.txt").is_none());
+
+        // "a" is orphaned once the history is gone, and a copy of "b" is merged into the first one
+        db.header_attachments.push(HeaderAttachment {
+            content: b"b".to_vec(),
+            ..Default::default()
+        });
+        entry.attachments.push(AttachmentRef {
+            name: "c.txt".to_string(),
+            identifier: 2,
+        });
+        entry.history = None;
+        db.root.add_child(entry);
+
+        assert_eq!(db.remove_orphaned_attachments(), 2);
+        assert_eq!(db.header_attachments.len(), 1);
+        let identifiers: Vec<_> = db.root.entries()[0]
+            .attachments()
+            .iter()
+            .map(|a| a.identifier)
+            .collect();
+        assert_eq!(identifiers, vec![0, 0]);
+    }
+
+    #[cfg(feature = "save_kdbx4")]
+    #[test]
+    fn test_save_drops_orphaned_attachments() {
+        let key = || crate::DatabaseKey::new().with_password("demopass");
+        let mut db = Database::new(Default::default());
+        db.header_attachments.push(HeaderAttachment {
+            content: b"orphan".to_vec(),
+            ..Default::default()
+        });
+        let mut entry = Entry::new();
+        entry.add_attachment(&mut db.header_attachments, "kept.txt", b"kept".to_vec());
+        db.root.add_child(entry);
+
+        // the pool is saved as it is by default
+        let mut data = Vec::new();
+        db.save(&mut data, key()).unwrap();
+        let saved = Database::open(&mut data.as_slice(), key()).unwrap();
+        assert_eq!(saved.header_attachments, db.header_attachments);
+
+        let options = SaveOptions {
+            drop_orphaned_attachments: true,
+            ..Default::default()
+        };
+        let mut data = Vec::new();
+        db.save_with_options(&mut data, key(), &options).unwrap();
+        assert_eq!(db.header_attachments.len(), 2);
+
+        let saved = Database::open(&mut data.as_slice(), key()).unwrap();
+        assert_eq!(saved.header_attachments.len(), 1);
+        let entry = &saved.root.entries()[0];
+        let kept = entry
+            .get_attachment(&saved.header_attachments, "kept.txt")
+            .unwrap();
+        assert_eq!(kept.content, b"kept");
+    }
+
+    #[cfg(feature = "save_kdbx4")]
+    #[test]
+    fn test_kdbx3_attachments() {
+        let key = || crate::DatabaseKey::new().with_password("demopass");
+        let mut db = Database::new(Default::default());
+        db.config.kdf_config = KdfConfig::Aes { rounds: 10 };
+        db.convert_to(DatabaseVersion::KDB3(1)).unwrap();
+
+        // identifiers continue after those of the binaries read from the file
+        db.meta.binaries.binaries.push(BinaryAttachment {
+            identifier: Some("4".to_string()),
+            content: b"orphan".to_vec(),
+            ..Default::default()
+        });
+        let mut entry = Entry::new();
+        entry.add_attachment(&mut db.meta.binaries, "a.txt", b"a".to_vec());
+        entry.add_attachment(&mut db.meta.binaries, "copy.txt", b"a".to_vec());
+        entry.add_attachment(&mut db.meta.binaries, "b.txt", b"b".to_vec());
+        let identifiers: Vec<_> = entry.attachments().iter().map(|a| a.identifier).collect();
+        assert_eq!(identifiers, vec![5, 5, 6]);
+        db.root.add_child(entry);
+
+        let options = SaveOptions {
+            drop_orphaned_attachments: true,
+            ..Default::default()
+        };
+        let mut data = Vec::new();
+        db.save_with_options(&mut data, key(), &options).unwrap();
+        let mut saved = Database::parse(&data, key()).unwrap();
+        assert_eq!(saved.meta.binaries.binaries.len(), 2);
+        let entry = &saved.root.entries()[0];
+        let b = entry.get_attachment(&saved.meta.binaries, "b.txt").unwrap();
+        assert_eq!(&b.data().unwrap()[..], b"b");
+        assert!(entry.get_attachment(&saved.header_attachments, "b.txt").is_none());
+
+        assert_eq!(db.remove_orphaned_attachments(), 1);
+        assert_eq!(db.meta.binaries, saved.meta.binaries);
+        assert_eq!(saved.remove_orphaned_attachments(), 0);
+    }
+}
@@ -0,0 +1,362 @@
+//! Editing text attachments in place
+//!
+//! Users keep configuration snippets, SSH keys and similar small text files as attachments.
+//! `as_text_mut` decodes such an attachment into a `String` that can be edited directly; when the
+//! edit is committed or dropped, the text is encoded again with the encoding it was read with and
+//! the attachment is packed and compressed the way it was before.
+//!
+//! ```
+//! use keepass::db::HeaderAttachment;
+//!
+//! let mut attachment = HeaderAttachment {
+//!     content: b"Host example.com\n".to_vec(),
+//!     ..Default::default()
+//! };
+//!
+//! let mut text = attachment.as_text_mut()?;
+//! text.push_str("    User git\n");
+//! text.commit()?;
+//!
+//! assert_eq!(attachment.as_text()?, "Host example.com\n    User git\n");
+//! # Ok::<(), keepass::db::AttachmentTextError>(())
+//! ```
+
+use std::ops::{Deref, DerefMut};
+
+use thiserror::Error;
+use zeroize::Zeroize;
+
+use crate::db::{BinaryAttachment, HeaderAttachment};
+
+/// Attachments larger than this many bytes are not edited as text
+pub const MAX_TEXT_ATTACHMENT_SIZE: usize = 1024 * 1024;
+
+const UTF8_BOM: &[u8] = b"\xef\xbb\xbf";
+const UTF16LE_BOM: &[u8] = b"\xff\xfe";
+const UTF16BE_BOM: &[u8] = b"\xfe\xff";
+
+/// Errors while reading an attachment as text
+#[derive(Debug, Error)]
+pub enum AttachmentTextError {
+    #[error("The attachment does not contain text")]
+    NotText,
+
+    #[error("The attachment is too large to edit as text: {0} bytes")]
+    TooLarge(usize),
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+/// The encoding of a text attachment
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serialization", derive(serde::Serialize))]
+pub enum TextEncoding {
+    Utf8,
+
+    /// UTF-8 starting with a byte order mark, as written by some Windows editors
+    Utf8Bom,
+
+    /// UTF-16 little endian with a byte order mark
+    Utf16Le,
+
+    /// UTF-16 big endian with a byte order mark
+    Utf16Be,
+}
+
+impl TextEncoding {
+    /// Detect the encoding of some content. Content that is neither UTF-16 with a byte order
+    /// mark nor UTF-8 without binary control characters is not text.
+    pub fn detect(content: &[u8]) -> Option<Self> {
+        let encoding = if content.starts_with(UTF8_BOM) {
+            TextEncoding::Utf8Bom
+        } else if content.starts_with(UTF16LE_BOM) {
+            TextEncoding::Utf16Le
+        } else if content.starts_with(UTF16BE_BOM) {
+            TextEncoding::Utf16Be
+        } else {
+            TextEncoding::Utf8
+        };
+
+        encoding.decode(content).map(|_| encoding)
+    }
+
+    fn decode(&self, content: &[u8]) -> Option<String> {
+        let text = match self {
+            TextEncoding::Utf8 => String::from_utf8(content.to_vec()).ok()?,
+            TextEncoding::Utf8Bom => String::from_utf8(content.strip_prefix(UTF8_BOM)?.to_vec()).ok()?,
+            TextEncoding::Utf16Le | TextEncoding::Utf16Be => {
+                let content = content.get(2..)?;
+                if content.len() % 2 != 0 {
+                    return None;
+                }
+                let units = content.chunks_exact(2).map(|pair| match self {
+                    TextEncoding::Utf16Le => u16::from_le_bytes([pair[0], pair[1]]),
+                    _ => u16::from_be_bytes([pair[0], pair[1]]),
+                });
+                char::decode_utf16(units).collect::<Result<String, _>>().ok()?
+            }
+        };
+
+        let binary = text
+            .chars()
+            .any(|c| c.is_control() && !matches!(c, '\t' | '\n' | '\r' | '\x0c'));
+        if binary {
+            return None;
+        }
+        Some(text)
+    }
+
+    fn encode(&self, text: &str) -> Vec<u8> {
+        match self {
+            TextEncoding::Utf8 => text.as_bytes().to_vec(),
+            TextEncoding::Utf8Bom => [UTF8_BOM, text.as_bytes()].concat(),
+            TextEncoding::Utf16Le => UTF16LE_BOM
+                .iter()
+                .copied()
+                .chain(text.encode_utf16().flat_map(|u| u.to_le_bytes()))
+                .collect(),
+            TextEncoding::Utf16Be => UTF16BE_BOM
+                .iter()
+                .copied()
+                .chain(text.encode_utf16().flat_map(|u| u.to_be_bytes()))
+                .collect(),
+        }
+    }
+}
+
+fn decode_text(content: &[u8]) -> Result<(String, TextEncoding), AttachmentTextError> {
+    if content.len() > MAX_TEXT_ATTACHMENT_SIZE {
+        return Err(AttachmentTextError::TooLarge(content.len()));
+    }
+
+    let encoding = TextEncoding::detect(content).ok_or(AttachmentTextError::NotText)?;
+    let text = encoding.decode(content).ok_or(AttachmentTextError::NotText)?;
+    Ok((text, encoding))
+}
+
+enum Target<'a> {
+    Header(&'a mut HeaderAttachment),
+    Binary(&'a mut BinaryAttachment),
+}
+
+/// The text of an attachment being edited, see `HeaderAttachment::as_text_mut`. Changes are
+/// written back to the attachment when the edit is dropped, or explicitly with
+/// `AttachmentText::commit`. The text is wiped from memory afterwards.
+pub struct AttachmentText<'a> {
+    target: Target<'a>,
+    encoding: TextEncoding,
+    text: String,
+    changed: bool,
+}
+
+impl<'a> AttachmentText<'a> {
+    /// The encoding the text is written back with
+    pub fn encoding(&self) -> TextEncoding {
+        self.encoding
+    }
+
+    /// Write the text back with another encoding
+    pub fn set_encoding(&mut self, encoding: TextEncoding) {
+        self.changed |= encoding != self.encoding;
+        self.encoding = encoding;
+    }
+
+    /// Write the changes back to the attachment. Unlike dropping the edit, this reports errors
+    /// while packing the attachment again.
+    pub fn commit(mut self) -> Result<(), std::io::Error> {
+        self.write_back()
+    }
+
+    fn write_back(&mut self) -> Result<(), std::io::Error> {
+        if !self.changed {
+            return Ok(());
+        }
+        self.changed = false;
+
+        let content = self.encoding.encode(&self.text);
+        match &mut self.target {
+            Target::Header(attachment) => {
+                let packed = attachment.packed;
+                attachment.content.zeroize();
+                attachment.content = content;
+                attachment.packed = false;
+                if packed {
+                    attachment.pack()?;
+                }
+            }
+            Target::Binary(attachment) => {
+                let packed = attachment.packed;
+                attachment.content.zeroize();
+                attachment.content = content;
+                attachment.packed = false;
+                if packed {
+                    attachment.pack()?;
+                }
+                // the new text may compress better or worse than the old one
+                #[cfg(feature = "save_kdbx4")]
+                attachment.recompress();
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Deref for AttachmentText<'a> {
+    type Target = String;
+
+    fn deref(&self) -> &String {
+        &self.text
+    }
+}
+
+impl<'a> DerefMut for AttachmentText<'a> {
+    fn deref_mut(&mut self) -> &mut String {
+        self.changed = true;
+        &mut self.text
+    }
+}
+
+impl<'a> Drop for AttachmentText<'a> {
+    fn drop(&mut self) {
+        // the content is left unpacked if packing fails, which is still valid
+        let _ = self.write_back();
+        self.text.zeroize();
+    }
+}
+
+impl HeaderAttachment {
+    /// The content of the attachment as text
+    pub fn as_text(&self) -> Result<String, AttachmentTextError> {
+        Ok(decode_text(&self.data()?)?.0)
+    }
+
+    /// Edit the content of the attachment as text
+    pub fn as_text_mut(&mut self) -> Result<AttachmentText<'_>, AttachmentTextError> {
+        let (text, encoding) = decode_text(&self.data()?)?;
+        Ok(AttachmentText {
+            target: Target::Header(self),
+            encoding,
+            text,
+            changed: false,
+        })
+    }
+}
+
+impl BinaryAttachment {
+    /// The content of the attachment as text
+    pub fn as_text(&self) -> Result<String, AttachmentTextError> {
+        Ok(decode_text(&self.data()?)?.0)
+    }
+
+    /// Edit the content of the attachment as text. Whether the attachment is stored compressed
+    /// is decided again when the edit is written back.
+    pub fn as_text_mut(&mut self) -> Result<AttachmentText<'_>, AttachmentTextError> {
+        let (text, encoding) = decode_text(&self.data()?)?;
+        Ok(AttachmentText {
+            target: Target::Binary(self),
+            encoding,
+            text,
+            changed: false,
+        })
+    }
+}
+
+#[cfg(test)]
+mod attachment_text_tests {
+    use crate::db::{BinaryAttachment, HeaderAttachment};
+
+    use super::{AttachmentTextError, TextEncoding};
+
+    #[test]
+    fn test_detect_encoding() {
+        assert_eq!(
+            TextEncoding::detect(b"ssh-ed25519 AAAA\n"),
+            Some(TextEncoding::Utf8)
+        );
+        assert_eq!(
+            TextEncoding::detect(b"\xef\xbb\xbfkey=1"),
+            Some(TextEncoding::Utf8Bom)
+        );
+        assert_eq!(
+            TextEncoding::detect(b"\xff\xfek\x00"),
+            Some(TextEncoding::Utf16Le)
+        );
+        assert_eq!(
+            TextEncoding::detect(b"\xfe\xff\x00k"),
+            Some(TextEncoding::Utf16Be)
+        );
+        assert_eq!(TextEncoding::detect(b"\x89PNG\r\n\x1a\n"), None);
+        assert_eq!(TextEncoding::detect(b"a\x00b"), None);
+    }
+
+    #[test]
+    fn test_edit_header_attachment() {
+        let mut attachment = HeaderAttachment {
+            content: b"\xff\xfea\x00".to_vec(),
+            ..Default::default()
+        };
+
+        {
+            let mut text = attachment.as_text_mut().unwrap();
+            assert_eq!(text.encoding(), TextEncoding::Utf16Le);
+            text.push('é');
+        }
+        assert_eq!(attachment.content, b"\xff\xfea\x00\xe9\x00");
+
+        // unchanged text is not written back
+        attachment.packed = true;
+        attachment.content = crate::compression::Compression::compress(
+            &crate::compression::GZipCompression::default(),
+            "x".repeat(100).as_bytes(),
+        )
+        .unwrap();
+        let packed = attachment.content.clone();
+        assert_eq!(attachment.as_text_mut().unwrap().len(), 100);
+        assert_eq!(attachment.content, packed);
+
+        let mut text = attachment.as_text_mut().unwrap();
+        text.push('y');
+        text.set_encoding(TextEncoding::Utf8);
+        text.commit().unwrap();
+        assert!(attachment.packed);
+        assert_eq!(attachment.as_text().unwrap(), format!("{}y", "x".repeat(100)));
+
+        let mut binary = HeaderAttachment {
+            content: vec![0, 1, 2],
+            ..Default::default()
+        };
+        assert!(matches!(binary.as_text_mut(), Err(AttachmentTextError::NotText)));
+    }
+
+    #[cfg(feature = "save_kdbx4")]
+    #[test]
+    fn test_edit_binary_attachment_recompresses() {
+        let mut attachment = BinaryAttachment {
+            content: b"short".to_vec(),
+            ..Default::default()
+        };
+
+        attachment
+            .as_text_mut()
+            .unwrap()
+            .push_str(&"compressible ".repeat(50));
+        assert!(attachment.compressed);
+
+        attachment.as_text_mut().unwrap().truncate(3);
+        assert_eq!(attachment.as_text().unwrap(), "sho");
+        assert!(!attachment.compressed);
+    }
+
+    #[test]
+    fn test_too_large() {
+        let attachment = BinaryAttachment {
+            content: vec![b'a'; super::MAX_TEXT_ATTACHMENT_SIZE + 1],
+            ..Default::default()
+        };
+        assert!(matches!(
+            attachment.as_text(),
+            Err(AttachmentTextError::TooLarge(_))
+        ));
+    }
+}
@@ -0,0 +1,193 @@
+//! Auditing of protected value access
+//!
+//! An audit hook can be installed process-wide to get notified whenever the value of a protected
+//! field is read through the `Entry` accessors, e.g. to log secret access centrally.
+//!
+//! ```
+//! use keepass::db::{set_audit_hook, with_audit_context, AccessEvent, Entry, Value};
+//!
+//! set_audit_hook(|event: &AccessEvent| {
+//!     println!(
+//!         "{} read {} of entry {}",
+//!         event.context.unwrap_or("unknown caller"),
+//!         event.field_name,
+//!         event.entry_uuid
+//!     );
+//! });
+//!
+//! let mut entry = Entry::new();
+//! entry.fields.insert("Password".to_string(), Value::Protected("secret".as_bytes().into()));
+//!
+//! with_audit_context("backup job", || {
+//!     assert_eq!(entry.get_protected("Password").unwrap().as_str(), Some("secret"));
+//! });
+//! ```
+//!
+//! Only accesses through `Entry::get` and the convenience getters built on top of it are reported.
+//! Reading `Entry::fields` directly bypasses the hook.
+
+use std::{
+    cell::RefCell,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock,
+    },
+};
+
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+/// Information about a single read of a protected value
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccessEvent<'a> {
+    /// The UUID of the entry holding the value
+    pub entry_uuid: Uuid,
+
+    /// The name of the field that was read
+    pub field_name: &'a str,
+
+    /// When the value was read
+    pub timestamp: DateTime<Utc>,
+
+    /// The innermost context set using `with_audit_context` on the reading thread, if any
+    pub context: Option<&'a str>,
+}
+
+/// A hook that gets notified whenever a protected value is read.
+///
+/// The hook decides for itself which accesses to record, so it can implement arbitrary policies
+/// (e.g. only log passwords, or only accesses outside of a given context).
+pub trait AuditHook: Send + Sync {
+    fn on_access(&self, event: &AccessEvent);
+}
+
+impl<F> AuditHook for F
+where
+    F: Fn(&AccessEvent) + Send + Sync,
+{
+    fn on_access(&self, event: &AccessEvent) {
+        self(event)
+    }
+}
+
+// checked first so that reading protected values stays cheap while no hook is installed
+static HOOK_INSTALLED: AtomicBool = AtomicBool::new(false);
+static HOOK: RwLock<Option<Arc<dyn AuditHook>>> = RwLock::new(None);
+
+thread_local! {
+    static CONTEXT: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Install a process-wide audit hook, replacing any previously installed one
+pub fn set_audit_hook(hook: impl AuditHook + 'static) {
+    let mut guard = HOOK.write().unwrap_or_else(|e| e.into_inner());
+    *guard = Some(Arc::new(hook));
+    HOOK_INSTALLED.store(true, Ordering::Release);
+}
+
+/// Remove the process-wide audit hook
+pub fn clear_audit_hook() {
+    let mut guard = HOOK.write().unwrap_or_else(|e| e.into_inner());
+    *guard = None;
+    HOOK_INSTALLED.store(false, Ordering::Release);
+}
+
+/// Run `f` with a caller-supplied context that is attached to all access events raised on the
+/// current thread while `f` runs. Contexts can be nested; the innermost one is reported.
+pub fn with_audit_context<R>(context: &str, f: impl FnOnce() -> R) -> R {
+    struct PopOnDrop;
+    impl Drop for PopOnDrop {
+        fn drop(&mut self) {
+            CONTEXT.with(|c| c.borrow_mut().pop());
+        }
+    }
+
+    CONTEXT.with(|c| c.borrow_mut().push(context.to_string()));
+    let _pop = PopOnDrop;
+    f()
+}
+
+/// The innermost context of this thread, if any
+#[cfg(feature = "async")]
+pub(crate) fn current_context() -> Option<String> {
+    CONTEXT.with(|c| c.borrow().last().cloned())
+}
+
+/// Report the read of a protected field to the installed audit hook
+pub(crate) fn record_access(entry_uuid: Uuid, field_name: &str) {
+    if !HOOK_INSTALLED.load(Ordering::Acquire) {
+        return;
+    }
+
+    // clone the hook so that it can itself install or clear hooks without deadlocking
+    let hook = match HOOK.read().unwrap_or_else(|e| e.into_inner()).as_ref() {
+        Some(hook) => Arc::clone(hook),
+        None => return,
+    };
+
+    CONTEXT.with(|c| {
+        let context = c.borrow();
+        hook.on_access(&AccessEvent {
+            entry_uuid,
+            field_name,
+            timestamp: crate::db::clock::current_time().and_utc(),
+            context: context.last().map(|s| s.as_str()),
+        });
+    });
+}
+
+#[cfg(test)]
+mod audit_tests {
+    use std::sync::{Arc, Mutex};
+
+    use uuid::Uuid;
+
+    use crate::db::{Entry, Value};
+
+    use super::{clear_audit_hook, set_audit_hook, with_audit_context, AccessEvent};
+
+    #[test]
+    fn test_audit_hook() {
+        let mut entry = Entry::new();
+        entry.fields.insert(
+            "Password".to_string(),
+            Value::Protected("secret".as_bytes().into()),
+        );
+        entry
+            .fields
+            .insert("Title".to_string(), Value::Unprotected("title".to_string()));
+
+        // the hook is process-wide, so only record events for this test's entry
+        let entry_uuid = entry.uuid;
+        let seen: Arc<Mutex<Vec<(Uuid, String, Option<String>)>>> = Default::default();
+        let seen_by_hook = seen.clone();
+        set_audit_hook(move |event: &AccessEvent| {
+            if event.entry_uuid == entry_uuid {
+                seen_by_hook.lock().unwrap().push((
+                    event.entry_uuid,
+                    event.field_name.to_string(),
+                    event.context.map(|c| c.to_string()),
+                ));
+            }
+        });
+
+        assert_eq!(entry.get_title(), Some("title"));
+        assert_eq!(entry.get_password(), Some("secret"));
+        with_audit_context("outer", || {
+            with_audit_context("inner", || entry.get_password());
+            entry.get_password();
+        });
+
+        clear_audit_hook();
+        entry.get_password();
+
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec![
+                (entry_uuid, "Password".to_string(), None),
+                (entry_uuid, "Password".to_string(), Some("inner".to_string())),
+                (entry_uuid, "Password".to_string(), Some("outer".to_string())),
+            ]
+        );
+    }
+}
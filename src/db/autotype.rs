@@ -0,0 +1,232 @@
+//! Resolution of Auto-Type sequences
+//!
+//! Auto-Type types the credentials of an entry into another application as a sequence of
+//! keystrokes. Which sequence is typed depends on the target window: an entry can associate
+//! window titles with their own sequences, and otherwise falls back to its default sequence, the
+//! one of its closest group that sets one, or `{USERNAME}{TAB}{PASSWORD}{ENTER}`:
+//!
+//! ```
+//! use keepass::db::{AutoType, AutoTypeAssociation, Entry};
+//! use keepass::Database;
+//!
+//! let mut entry = Entry::new().with_username("alice").with_password("hunter2");
+//! entry.autotype = Some(AutoType {
+//!     enabled: true,
+//!     associations: vec![AutoTypeAssociation {
+//!         window: Some("*Terminal*".to_string()),
+//!         sequence: Some("{PASSWORD}{ENTER}".to_string()),
+//!     }],
+//!     ..Default::default()
+//! });
+//! let mut db = Database::new(Default::default());
+//! db.root.add_child(entry.clone());
+//!
+//! assert_eq!(
+//!     entry.resolve_autotype_sequence("Terminal - bash", &db).as_deref(),
+//!     Some("hunter2{ENTER}")
+//! );
+//! assert_eq!(entry.resolve_autotype_sequence("Mail", &db), None);
+//! ```
+//!
+//! Window titles are matched ignoring case, with `*` matching any text and placeholders in the
+//! title pattern expanded first. KeePass also accepts regular expressions enclosed in `//`, which
+//! are not supported and never match. Placeholders in the resolved sequence are expanded as
+//! described in `placeholders`, keeping the special keys like `{TAB}` for the typing backend.
+
+use crate::{
+    db::{AutoTypeAssociation, Database, Entry, Group},
+    placeholders::{expand, parent_groups},
+};
+
+/// The sequence typed for entries and groups that do not set one
+pub const DEFAULT_AUTOTYPE_SEQUENCE: &str = "{USERNAME}{TAB}{PASSWORD}{ENTER}";
+
+impl Entry {
+    /// Whether Auto-Type is enabled for the entry and all groups containing it in `db`
+    pub fn autotype_enabled(&self, db: &Database) -> bool {
+        if matches!(self.autotype, Some(ref autotype) if !autotype.enabled) {
+            return false;
+        }
+
+        // groups inherit the setting of their parent unless they set it themselves
+        for group in self.autotype_groups(db).iter().rev() {
+            match group
+                .enable_autotype
+                .as_deref()
+                .map(str::to_ascii_lowercase)
+                .as_deref()
+            {
+                Some("false") => return false,
+                Some("true") => return true,
+                _ => {}
+            }
+        }
+        true
+    }
+
+    /// The sequence typed when no window association applies, before placeholders are
+    /// expanded, or `None` if Auto-Type is disabled for the entry
+    pub fn default_autotype_sequence(&self, db: &Database) -> Option<String> {
+        if !self.autotype_enabled(db) {
+            return None;
+        }
+
+        let own = self.autotype.as_ref().and_then(|a| a.sequence.as_deref());
+        let inherited = self
+            .autotype_groups(db)
+            .into_iter()
+            .rev()
+            .find_map(|g| g.default_autotype_sequence.as_deref().filter(|s| !s.is_empty()));
+        let sequence = own
+            .filter(|s| !s.is_empty())
+            .or(inherited)
+            .unwrap_or(DEFAULT_AUTOTYPE_SEQUENCE);
+        Some(sequence.to_string())
+    }
+
+    /// The expanded keystroke sequence for the window with the given title, from the first
+    /// association matching it, or `None` if Auto-Type is disabled or no association matches
+    pub fn resolve_autotype_sequence(&self, window_title: &str, db: &Database) -> Option<String> {
+        let default = self.default_autotype_sequence(db)?;
+        let association = self
+            .autotype
+            .as_ref()?
+            .associations
+            .iter()
+            .find(|a| self.window_matches(a, window_title, db))?;
+
+        let sequence = association
+            .sequence
+            .as_deref()
+            .filter(|s| !s.is_empty())
+            .unwrap_or(&default);
+        Some(expand(self, sequence, db))
+    }
+
+    fn window_matches(&self, association: &AutoTypeAssociation, window_title: &str, db: &Database) -> bool {
+        match association.window.as_deref() {
+            Some(pattern) if pattern.len() > 4 && pattern.starts_with("//") && pattern.ends_with("//") => false,
+            Some(pattern) if !pattern.is_empty() => wildcard_match(&expand(self, pattern, db), window_title),
+            _ => false,
+        }
+    }
+
+    /// The root group and the groups containing the entry, outermost first
+    fn autotype_groups<'a>(&self, db: &'a Database) -> Vec<&'a Group> {
+        let mut groups = vec![&db.root];
+        groups.extend(parent_groups(&db.root, self).unwrap_or_default());
+        groups
+    }
+}
+
+/// Whether `text` matches `pattern` ignoring case, where `*` in the pattern matches any text
+fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.to_lowercase();
+    let text = text.to_lowercase();
+
+    let mut parts = pattern.split('*');
+    // the text has to start with the part before the first wildcard, and end with the last part
+    let first = parts.next().unwrap_or_default();
+    let mut rest = match text.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+
+    let mut parts: Vec<&str> = parts.collect();
+    let last = match parts.pop() {
+        Some(last) => last,
+        None => return rest.is_empty(),
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod autotype_tests {
+    use super::{wildcard_match, DEFAULT_AUTOTYPE_SEQUENCE};
+    use crate::db::{AutoType, AutoTypeAssociation, Database, Entry, Group};
+
+    fn association(window: &str, sequence: Option<&str>) -> AutoTypeAssociation {
+        AutoTypeAssociation {
+            window: Some(window.to_string()),
+            sequence: sequence.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn test_resolve_autotype_sequence() {
+        let mut entry = Entry::new()
+            .with_title("Mail")
+            .with_username("alice")
+            .with_password("hunter2");
+        entry.autotype = Some(AutoType {
+            enabled: true,
+            associations: vec![
+                association("* - {TITLE} - Firefox", Some("{USERNAME}{ENTER}")),
+                association("Login", None),
+                association("//^Mail$//", Some("{PASSWORD}")),
+            ],
+            ..Default::default()
+        });
+
+        let mut group = Group::new("Work");
+        group.default_autotype_sequence = Some("{PASSWORD}{ENTER}".to_string());
+        group.add_child(entry.clone());
+        let mut db = Database::new(Default::default());
+        db.root.add_child(group);
+
+        assert_eq!(
+            entry
+                .resolve_autotype_sequence("Inbox - MAIL - Firefox", &db)
+                .as_deref(),
+            Some("alice{ENTER}")
+        );
+        assert_eq!(
+            entry.resolve_autotype_sequence("login", &db).as_deref(),
+            Some("hunter2{ENTER}")
+        );
+        assert_eq!(entry.resolve_autotype_sequence("Mail", &db), None);
+
+        db.root.groups_mut()[0].enable_autotype = Some("false".to_string());
+        assert_eq!(entry.resolve_autotype_sequence("Login", &db), None);
+        assert_eq!(entry.default_autotype_sequence(&db), None);
+    }
+
+    #[test]
+    fn test_default_autotype_sequence() {
+        let db = Database::new(Default::default());
+        let mut entry = Entry::new();
+        assert_eq!(
+            entry.default_autotype_sequence(&db).as_deref(),
+            Some(DEFAULT_AUTOTYPE_SEQUENCE)
+        );
+
+        entry.autotype = Some(AutoType {
+            enabled: false,
+            sequence: Some("{PASSWORD}".to_string()),
+            ..Default::default()
+        });
+        assert_eq!(entry.default_autotype_sequence(&db), None);
+        entry.autotype.as_mut().unwrap().enabled = true;
+        assert_eq!(
+            entry.default_autotype_sequence(&db).as_deref(),
+            Some("{PASSWORD}")
+        );
+    }
+
+    #[test]
+    fn test_wildcard_match() {
+        assert!(wildcard_match("Mail", "mail"));
+        assert!(!wildcard_match("Mail", "Mail - Inbox"));
+        assert!(wildcard_match("*", ""));
+        assert!(wildcard_match("Mail*", "Mail - Inbox"));
+        assert!(wildcard_match("*Inbox", "Mail - Inbox"));
+        assert!(wildcard_match("M*l*x", "Mail - Inbox"));
+        assert!(!wildcard_match("*ab*ab", "xab"));
+    }
+}
@@ -0,0 +1,438 @@
+use std::{
+    future::Future,
+    io::{self, Read},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+    thread,
+};
+
+use chrono::NaiveDateTime;
+#[cfg(feature = "save_kdbx4")]
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    sync::mpsc,
+};
+
+#[cfg(feature = "save_kdbx4")]
+use crate::error::DatabaseSaveError;
+use crate::{
+    db::{audit, clock},
+    error::DatabaseOpenError,
+    progress::{self, CancellationToken, Progress},
+    Database, DatabaseKey,
+};
+
+/// The size of the chunks passed between the async I/O and the background thread
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// The number of chunks that can be on their way before the producing side waits
+const CHANNEL_CHUNKS: usize = 4;
+
+/// The result of work done on a background thread, which can be awaited on any executor
+///
+/// Opening and saving a database spends most of its time in the key derivation, which would block
+/// the threads of an async runtime for up to several seconds. A `BackgroundTask` does that work
+/// on a thread of its own and wakes the awaiting task once it is done.
+///
+/// The background thread inherits the thread-local settings of the thread that started the task:
+/// the time of a clock installed with `with_clock`, which stands still on the background thread,
+/// the context of `with_audit_context` and the cancellation token of `with_progress`. Progress is
+/// passed on to the `with_progress` handler of the thread that polls the task.
+#[must_use = "the result of the background work is lost unless the task is awaited"]
+pub struct BackgroundTask<T> {
+    shared: Arc<Mutex<TaskState<T>>>,
+}
+
+struct TaskState<T> {
+    result: Option<thread::Result<T>>,
+    progress: Vec<Progress>,
+    waker: Option<Waker>,
+}
+
+/// The thread-local settings of a thread that are carried over to a background thread
+struct Inherited {
+    time: Option<NaiveDateTime>,
+    audit_context: Option<String>,
+    token: Option<CancellationToken>,
+}
+
+impl Inherited {
+    fn capture() -> Self {
+        Inherited {
+            time: clock::installed_time(),
+            audit_context: audit::current_context(),
+            token: progress::token(),
+        }
+    }
+
+    fn run<T>(self, handler: impl Fn(&Progress) + 'static, work: impl FnOnce() -> T) -> T {
+        let Inherited {
+            time,
+            audit_context,
+            token,
+        } = self;
+
+        let work = move || match audit_context {
+            Some(context) => audit::with_audit_context(&context, work),
+            None => work(),
+        };
+        let work = move || match time {
+            Some(time) => clock::with_clock(clock::ManualClock::new(time), work),
+            None => work(),
+        };
+        progress::with_progress(handler, token.unwrap_or_default(), work)
+    }
+}
+
+impl<T: Send + 'static> BackgroundTask<T> {
+    fn spawn(work: impl FnOnce() -> T + Send + 'static) -> Self {
+        let shared = Arc::new(Mutex::new(TaskState {
+            result: None,
+            progress: Vec::new(),
+            waker: None,
+        }));
+
+        let inherited = Inherited::capture();
+        let thread_shared = shared.clone();
+        thread::spawn(move || {
+            let progress_shared = thread_shared.clone();
+            let handler = move |progress: &Progress| {
+                let mut state = progress_shared.lock().unwrap_or_else(|e| e.into_inner());
+                state.progress.push(*progress);
+                if let Some(waker) = state.waker.take() {
+                    waker.wake();
+                }
+            };
+
+            let result =
+                std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| inherited.run(handler, work)));
+
+            let mut state = thread_shared.lock().unwrap_or_else(|e| e.into_inner());
+            state.result = Some(result);
+            if let Some(waker) = state.waker.take() {
+                waker.wake();
+            }
+        });
+
+        BackgroundTask { shared }
+    }
+}
+
+impl<T> Future for BackgroundTask<T> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        let (progress, result) = {
+            let mut state = self.shared.lock().unwrap_or_else(|e| e.into_inner());
+            let result = state.result.take();
+            if result.is_none() {
+                state.waker = Some(cx.waker().clone());
+            }
+            (std::mem::take(&mut state.progress), result)
+        };
+
+        // the handler runs without the lock, so that it can take its time
+        for progress in progress {
+            progress::forward(progress);
+        }
+
+        match result {
+            Some(Ok(result)) => Poll::Ready(result),
+            // panics of the background thread are passed on to the awaiting task
+            Some(Err(panic)) => std::panic::resume_unwind(panic),
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// Reads the chunks sent by the async side on the background thread
+struct ChannelReader {
+    receiver: mpsc::Receiver<io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    position: usize,
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.chunk.len() {
+            match self.receiver.blocking_recv() {
+                Some(chunk) => {
+                    self.chunk = chunk?;
+                    self.position = 0;
+                }
+                None => return Ok(0),
+            }
+        }
+
+        let len = buf.len().min(self.chunk.len() - self.position);
+        buf[..len].copy_from_slice(&self.chunk[self.position..self.position + len]);
+        self.position += len;
+        Ok(len)
+    }
+}
+
+/// Sends what the background thread writes to the async side in chunks
+#[cfg(feature = "save_kdbx4")]
+struct ChannelWriter {
+    sender: mpsc::Sender<Vec<u8>>,
+    chunk: Vec<u8>,
+}
+
+#[cfg(feature = "save_kdbx4")]
+impl io::Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.chunk.extend_from_slice(buf);
+        if self.chunk.len() >= CHUNK_SIZE {
+            self.flush()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.chunk.is_empty() {
+            return Ok(());
+        }
+        let chunk = std::mem::take(&mut self.chunk);
+        self.sender
+            .blocking_send(chunk)
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "the destination was closed"))
+    }
+}
+
+impl Database {
+    /// Open a database from an async source, for async applications.
+    ///
+    /// The source is read by the awaiting task, while the database is decrypted and parsed on a
+    /// background thread as its data comes in.
+    ///
+    /// ```
+    /// use keepass::{Database, DatabaseKey};
+    ///
+    /// async fn open_database(
+    ///     source: impl tokio::io::AsyncRead + Unpin,
+    /// ) -> Result<Database, keepass::error::DatabaseOpenError> {
+    ///     let key = DatabaseKey::new().with_password("demopass");
+    ///     Database::open_async(source, key).await
+    /// }
+    /// ```
+    pub async fn open_async<R: AsyncRead + Unpin>(
+        mut source: R,
+        key: DatabaseKey,
+    ) -> Result<Database, DatabaseOpenError> {
+        let (sender, receiver) = mpsc::channel(CHANNEL_CHUNKS);
+        let task = BackgroundTask::spawn(move || {
+            let mut reader = ChannelReader {
+                receiver,
+                chunk: Vec::new(),
+                position: 0,
+            };
+            Database::open(&mut reader, key)
+        });
+
+        // stops early when the background thread is done reading, e.g. because the key is wrong
+        loop {
+            let mut chunk = vec![0; CHUNK_SIZE];
+            let chunk = match source.read(&mut chunk).await {
+                Ok(0) => break,
+                Ok(len) => {
+                    chunk.truncate(len);
+                    Ok(chunk)
+                }
+                Err(e) => Err(e),
+            };
+            let failed = chunk.is_err();
+            if sender.send(chunk).await.is_err() || failed {
+                break;
+            }
+        }
+        drop(sender);
+
+        task.await
+    }
+
+    /// Save the database to an async destination, for async applications.
+    ///
+    /// The database is encrypted on a background thread, which shares it with the caller instead
+    /// of copying it, while the awaiting task writes the encrypted data to the destination.
+    ///
+    /// ```
+    /// use std::sync::Arc;
+    ///
+    /// use keepass::{Database, DatabaseKey};
+    ///
+    /// async fn save_database(
+    ///     db: &Arc<Database>,
+    ///     destination: impl tokio::io::AsyncWrite + Unpin,
+    /// ) -> Result<(), keepass::error::DatabaseSaveError> {
+    ///     let key = DatabaseKey::new().with_password("demopass");
+    ///     Arc::clone(db).save_async(destination, key).await
+    /// }
+    /// ```
+    #[cfg(feature = "save_kdbx4")]
+    pub async fn save_async<W: AsyncWrite + Unpin>(
+        self: Arc<Self>,
+        mut destination: W,
+        key: DatabaseKey,
+    ) -> Result<(), DatabaseSaveError> {
+        let (sender, mut receiver) = mpsc::channel(CHANNEL_CHUNKS);
+        let task = BackgroundTask::spawn(move || {
+            let mut writer = ChannelWriter {
+                sender,
+                chunk: Vec::new(),
+            };
+            self.save(&mut writer, key)?;
+            io::Write::flush(&mut writer)?;
+            Ok(())
+        });
+
+        let mut written = Ok(());
+        while let Some(chunk) = receiver.recv().await {
+            if let Err(e) = destination.write_all(&chunk).await {
+                written = Err(e);
+                break;
+            }
+        }
+        // fails the writes of the background thread if the destination failed
+        drop(receiver);
+
+        let saved: Result<(), DatabaseSaveError> = task.await;
+        // the error of the destination explains why the background thread failed to write
+        written?;
+        saved?;
+        destination.flush().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod background_tests {
+    use std::{
+        cell::RefCell,
+        future::Future,
+        rc::Rc,
+        sync::{Arc, Condvar, Mutex},
+        task::{Context, Poll, Wake, Waker},
+    };
+
+    use chrono::NaiveDate;
+
+    use super::BackgroundTask;
+    use crate::{
+        db::{audit, clock, Times},
+        error::DatabaseOpenError,
+        progress::{with_progress, CancellationToken, Phase, Progress},
+        Database, DatabaseKey,
+    };
+
+    /// Wakes a thread blocked in `block_on`
+    #[derive(Default)]
+    struct Signal {
+        woken: Mutex<bool>,
+        condvar: Condvar,
+    }
+
+    impl Wake for Signal {
+        fn wake(self: Arc<Self>) {
+            *self.woken.lock().unwrap() = true;
+            self.condvar.notify_one();
+        }
+    }
+
+    /// A minimal executor running a single future to completion on the current thread
+    pub(crate) fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = Box::pin(future);
+        let signal = Arc::new(Signal::default());
+        let waker = Waker::from(signal.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        loop {
+            if let Poll::Ready(result) = future.as_mut().poll(&mut cx) {
+                return result;
+            }
+            let mut woken = signal.woken.lock().unwrap();
+            while !*woken {
+                woken = signal.condvar.wait(woken).unwrap();
+            }
+            *woken = false;
+        }
+    }
+
+    #[test]
+    fn test_open_async() {
+        let data = std::fs::read("tests/resources/test_db_with_password.kdbx").unwrap();
+
+        let key = DatabaseKey::new().with_password("demopass");
+        let db = block_on(Database::open_async(&data[..], key.clone())).unwrap();
+        assert_eq!(db.root, Database::parse(&data, key).unwrap().root);
+
+        let wrong_key = DatabaseKey::new().with_password("wrong");
+        assert!(block_on(Database::open_async(&data[..], wrong_key)).is_err());
+    }
+
+    #[cfg(feature = "save_kdbx4")]
+    #[test]
+    fn test_save_async() {
+        let mut db = Database::new(Default::default());
+        db.root.add_child(crate::db::Entry::new());
+        let db = Arc::new(db);
+        let key = DatabaseKey::new().with_password("test");
+
+        let mut data = Vec::new();
+        block_on(db.clone().save_async(&mut data, key.clone())).unwrap();
+        let reopened = block_on(Database::open_async(&data[..], key)).unwrap();
+        assert_eq!(reopened.root, db.root);
+    }
+
+    #[test]
+    fn test_inherited_settings() {
+        let now = NaiveDate::from_ymd_opt(2020, 1, 2)
+            .unwrap()
+            .and_hms_opt(3, 4, 5)
+            .unwrap();
+
+        let (time, context) = clock::with_clock(clock::ManualClock::new(now), || {
+            audit::with_audit_context("sync", || {
+                block_on(BackgroundTask::spawn(|| (Times::now(), audit::current_context())))
+            })
+        });
+        assert_eq!(time, now);
+        assert_eq!(context.as_deref(), Some("sync"));
+
+        let (time, context) = block_on(BackgroundTask::spawn(|| {
+            (clock::installed_time(), audit::current_context())
+        }));
+        assert_eq!(time, None);
+        assert_eq!(context, None);
+    }
+
+    #[test]
+    fn test_progress_and_cancellation() {
+        let data = std::fs::read("tests/resources/test_db_kdbx4_with_password_aes.kdbx").unwrap();
+        let key = DatabaseKey::new().with_password("demopass");
+
+        let reports = Rc::new(RefCell::new(Vec::new()));
+        let handler = {
+            let reports = reports.clone();
+            move |progress: &Progress| reports.borrow_mut().push(*progress)
+        };
+        with_progress(handler, CancellationToken::new(), || {
+            block_on(Database::open_async(&data[..], key.clone()))
+        })
+        .unwrap();
+        assert!(reports
+            .borrow()
+            .iter()
+            .any(|progress| progress.phase == Phase::KeyDerivation));
+
+        let token = CancellationToken::new();
+        token.cancel();
+        let result = with_progress(
+            |_: &Progress| {},
+            token,
+            || block_on(Database::open_async(&data[..], key)),
+        );
+        assert!(matches!(result, Err(DatabaseOpenError::Cancelled)));
+    }
+}
@@ -0,0 +1,360 @@
+//! Attachments kept in an external, content-addressed store
+//!
+//! Backup servers holding many databases store the same attachments over and over. With
+//! `Database::parse_with_blob_store`, the content of every attachment is moved into a `BlobStore`
+//! under its SHA-256 hash, so that identical attachments are stored once, and the database only
+//! keeps the hashes. `Database::save_with_blob_store` loads the content back to write a regular,
+//! self-contained KDBX file.
+
+use std::{
+    collections::HashMap,
+    convert::TryInto,
+    path::{Path, PathBuf},
+};
+
+use thiserror::Error;
+
+use crate::{
+    crypt::calculate_sha256,
+    db::Database,
+    error::{CryptographyError, DatabaseOpenError},
+    key::DatabaseKey,
+};
+
+/// SHA-256 hash of the content of an attachment
+pub type BlobHash = [u8; 32];
+
+/// Errors while moving attachments to or from a blob store
+#[derive(Debug, Error)]
+pub enum BlobStoreError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error(transparent)]
+    Cryptography(#[from] CryptographyError),
+
+    #[error(transparent)]
+    Open(#[from] DatabaseOpenError),
+
+    #[cfg(feature = "save_kdbx4")]
+    #[error(transparent)]
+    Save(#[from] crate::error::DatabaseSaveError),
+
+    #[error("Blob {} is missing from the store", hex::encode(_0))]
+    Missing(BlobHash),
+
+    /// The content returned by the store does not match its hash
+    #[error("Blob {} is corrupted", hex::encode(_0))]
+    Corrupted(BlobHash),
+}
+
+/// Storage for attachment content, addressed by its hash
+pub trait BlobStore {
+    fn get(&self, hash: &BlobHash) -> Result<Option<Vec<u8>>, std::io::Error>;
+
+    /// Store content under its hash. Content that is already stored may be written again.
+    fn put(&mut self, hash: &BlobHash, data: &[u8]) -> Result<(), std::io::Error>;
+
+    fn contains(&self, hash: &BlobHash) -> Result<bool, std::io::Error> {
+        Ok(self.get(hash)?.is_some())
+    }
+}
+
+/// A blob store in memory, e.g. for tests or for deduplicating within a process
+#[derive(Debug, Default, Clone)]
+pub struct MemoryBlobStore {
+    blobs: HashMap<BlobHash, Vec<u8>>,
+}
+
+impl MemoryBlobStore {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// The number of stored blobs
+    pub fn len(&self) -> usize {
+        self.blobs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blobs.is_empty()
+    }
+}
+
+impl BlobStore for MemoryBlobStore {
+    fn get(&self, hash: &BlobHash) -> Result<Option<Vec<u8>>, std::io::Error> {
+        Ok(self.blobs.get(hash).cloned())
+    }
+
+    fn put(&mut self, hash: &BlobHash, data: &[u8]) -> Result<(), std::io::Error> {
+        self.blobs.insert(*hash, data.to_vec());
+        Ok(())
+    }
+
+    fn contains(&self, hash: &BlobHash) -> Result<bool, std::io::Error> {
+        Ok(self.blobs.contains_key(hash))
+    }
+}
+
+/// A blob store keeping every blob in a file named after its hash in hex
+#[derive(Debug, Clone)]
+pub struct DirectoryBlobStore {
+    path: PathBuf,
+}
+
+impl DirectoryBlobStore {
+    /// Use the directory at `path`, creating it if needed
+    pub fn new(path: impl AsRef<Path>) -> Result<Self, std::io::Error> {
+        let path = path.as_ref().to_path_buf();
+        std::fs::create_dir_all(&path)?;
+        Ok(DirectoryBlobStore { path })
+    }
+
+    fn blob_path(&self, hash: &BlobHash) -> PathBuf {
+        self.path.join(hex::encode(hash))
+    }
+}
+
+impl BlobStore for DirectoryBlobStore {
+    fn get(&self, hash: &BlobHash) -> Result<Option<Vec<u8>>, std::io::Error> {
+        match std::fs::read(self.blob_path(hash)) {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn put(&mut self, hash: &BlobHash, data: &[u8]) -> Result<(), std::io::Error> {
+        // write to a temporary file first, so that readers never see partial blobs
+        let path = self.blob_path(hash);
+        let temp = path.with_extension(format!("tmp-{}", uuid::Uuid::new_v4()));
+        std::fs::write(&temp, data)?;
+        std::fs::rename(&temp, &path)
+    }
+
+    fn contains(&self, hash: &BlobHash) -> Result<bool, std::io::Error> {
+        Ok(self.blob_path(hash).exists())
+    }
+}
+
+impl Database {
+    /// Parse a database and move the content of its attachments into `store`, see
+    /// `Database::externalize_attachments`
+    pub fn parse_with_blob_store(
+        data: &[u8],
+        key: DatabaseKey,
+        store: &mut dyn BlobStore,
+    ) -> Result<Database, BlobStoreError> {
+        let mut db = Database::parse(data, key)?;
+        db.externalize_attachments(store)?;
+        Ok(db)
+    }
+
+    /// Save a self-contained database, loading the content of external attachments from `store`
+    #[cfg(feature = "save_kdbx4")]
+    pub fn save_with_blob_store(
+        &self,
+        destination: &mut dyn std::io::Write,
+        key: DatabaseKey,
+        store: &dyn BlobStore,
+    ) -> Result<(), BlobStoreError> {
+        let mut db = self.clone();
+        db.internalize_attachments(store)?;
+        db.save(destination, key)?;
+        Ok(())
+    }
+
+    /// Whether the content of any attachment is kept in a blob store
+    pub fn has_external_attachments(&self) -> bool {
+        self.header_attachments.iter().any(|a| a.external)
+            || self.meta.binaries.binaries.iter().any(|a| a.external)
+    }
+
+    /// Move the content of all attachments into `store`, keeping only their hashes. Returns the
+    /// number of blobs that were not in the store before.
+    pub fn externalize_attachments(&mut self, store: &mut dyn BlobStore) -> Result<usize, BlobStoreError> {
+        let mut added = 0;
+
+        for attachment in &mut self.header_attachments {
+            if !attachment.external {
+                let data = attachment.data()?.into_owned();
+                added += externalize(&mut attachment.content, data, store)?;
+                attachment.packed = false;
+                attachment.external = true;
+            }
+        }
+
+        for attachment in &mut self.meta.binaries.binaries {
+            if !attachment.external {
+                let data = attachment.data()?.into_owned();
+                added += externalize(&mut attachment.content, data, store)?;
+                attachment.packed = false;
+                attachment.external = true;
+            }
+        }
+
+        Ok(added)
+    }
+
+    /// Load the content of all external attachments from `store`
+    pub fn internalize_attachments(&mut self, store: &dyn BlobStore) -> Result<(), BlobStoreError> {
+        for attachment in &mut self.header_attachments {
+            if attachment.external {
+                attachment.content = internalize(&attachment.content, store)?;
+                attachment.external = false;
+            }
+        }
+
+        for attachment in &mut self.meta.binaries.binaries {
+            if attachment.external {
+                attachment.content = internalize(&attachment.content, store)?;
+                attachment.external = false;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Store `data` and replace `content` with its hash. Returns 1 if the blob was new.
+fn externalize(
+    content: &mut Vec<u8>,
+    data: Vec<u8>,
+    store: &mut dyn BlobStore,
+) -> Result<usize, BlobStoreError> {
+    let hash: BlobHash = calculate_sha256(&[&data])?.into();
+
+    let added = if store.contains(&hash)? {
+        0
+    } else {
+        store.put(&hash, &data)?;
+        1
+    };
+
+    *content = hash.to_vec();
+    Ok(added)
+}
+
+fn internalize(content: &[u8], store: &dyn BlobStore) -> Result<Vec<u8>, BlobStoreError> {
+    let hash: BlobHash = content
+        .try_into()
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid blob hash"))?;
+
+    let data = store.get(&hash)?.ok_or(BlobStoreError::Missing(hash))?;
+    if calculate_sha256(&[&data])?.as_slice() != hash {
+        return Err(BlobStoreError::Corrupted(hash));
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod blobs_tests {
+    use std::convert::TryInto;
+
+    use crate::db::{AttachmentRef, BinaryAttachment, Database, Entry, HeaderAttachment};
+
+    use super::{BlobStore, BlobStoreError, DirectoryBlobStore, MemoryBlobStore};
+
+    fn database_with_attachments() -> Database {
+        let mut db = Database::new(Default::default());
+        db.header_attachments.push(HeaderAttachment {
+            flags: 1,
+            content: b"shared attachment".to_vec(),
+            packed: false,
+            external: false,
+        });
+        db.meta.binaries.binaries.push(BinaryAttachment {
+            identifier: Some("0".to_string()),
+            compressed: false,
+            content: b"shared attachment".to_vec(),
+            packed: false,
+            external: false,
+        });
+        // attachments that no entry refers to are dropped when saving
+        let mut entry = Entry::new();
+        entry.attachments.push(AttachmentRef {
+            name: "attachment.bin".to_string(),
+            identifier: 0,
+        });
+        db.root.add_child(entry);
+        db
+    }
+
+    #[test]
+    fn test_externalize_attachments() {
+        let mut store = MemoryBlobStore::new();
+
+        let mut first = database_with_attachments();
+        first.header_attachments[0].pack().unwrap();
+        assert_eq!(first.externalize_attachments(&mut store).unwrap(), 1);
+        assert!(first.has_external_attachments());
+        assert_eq!(first.header_attachments[0].content.len(), 32);
+        assert!(first.header_attachments[0].data().is_err());
+        assert_eq!(first.externalize_attachments(&mut store).unwrap(), 0);
+
+        let mut second = database_with_attachments();
+        assert_eq!(second.externalize_attachments(&mut store).unwrap(), 0);
+        assert_eq!(store.len(), 1);
+
+        first.internalize_attachments(&store).unwrap();
+        assert!(!first.has_external_attachments());
+        assert_eq!(
+            first.header_attachments,
+            database_with_attachments().header_attachments
+        );
+        assert_eq!(first.meta.binaries, database_with_attachments().meta.binaries);
+
+        let hash = second.header_attachments[0].content.clone().try_into().unwrap();
+        store.put(&hash, b"tampered").unwrap();
+        assert!(matches!(
+            second.internalize_attachments(&store),
+            Err(BlobStoreError::Corrupted(_))
+        ));
+        assert!(matches!(
+            second.internalize_attachments(&MemoryBlobStore::new()),
+            Err(BlobStoreError::Missing(_))
+        ));
+    }
+
+    #[test]
+    fn test_directory_blob_store() {
+        let path = std::env::temp_dir().join(format!("keepass-blobs-{}", uuid::Uuid::new_v4()));
+        let mut store = DirectoryBlobStore::new(&path).unwrap();
+
+        let mut db = database_with_attachments();
+        assert_eq!(db.externalize_attachments(&mut store).unwrap(), 1);
+        assert_eq!(std::fs::read_dir(&path).unwrap().count(), 1);
+
+        db.internalize_attachments(&store).unwrap();
+        assert_eq!(db.meta.binaries, database_with_attachments().meta.binaries);
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[cfg(feature = "save_kdbx4")]
+    #[test]
+    fn test_save_with_blob_store() {
+        use crate::{error::DatabaseSaveError, key::DatabaseKey};
+
+        let mut store = MemoryBlobStore::new();
+        let key = || DatabaseKey::new().with_password("blobs");
+
+        let mut data = Vec::new();
+        database_with_attachments().save(&mut data, key()).unwrap();
+        let db = Database::parse_with_blob_store(&data, key(), &mut store).unwrap();
+        assert!(db.has_external_attachments());
+
+        assert!(matches!(
+            db.save(&mut Vec::new(), key()),
+            Err(DatabaseSaveError::ExternalAttachments)
+        ));
+
+        let mut saved = Vec::new();
+        db.save_with_blob_store(&mut saved, key(), &store).unwrap();
+        let reopened = Database::parse(&saved, key()).unwrap();
+        assert_eq!(
+            reopened.header_attachments[0].content,
+            b"shared attachment".to_vec()
+        );
+    }
+}
@@ -0,0 +1,235 @@
+//! Browser integration pairings as stored by KeePassXC
+//!
+//! When a browser extension is connected to a database, KeePassXC stores the public key of the
+//! extension in the metadata custom data, under the key `KPXC_BROWSER_` followed by the name the
+//! user gave to the connection. The value is the base64 encoded 32 byte public key used for the
+//! NaCl box encryption of native messages. Modelling these items allows other native messaging
+//! hosts to reuse existing pairings.
+
+use std::convert::TryInto;
+
+use base64::{engine::general_purpose as base64_engine, Engine as _};
+use chrono::NaiveDateTime;
+use thiserror::Error;
+
+use crate::db::{CustomDataItem, Meta, Times, Value};
+
+/// Prefix of the metadata custom data keys holding browser pairings
+pub const BROWSER_KEY_PREFIX: &str = "KPXC_BROWSER_";
+
+/// Size of a browser public key in bytes
+pub const BROWSER_PUBLIC_KEY_SIZE: usize = 32;
+
+/// Errors while working with browser pairings
+#[derive(Debug, Error)]
+pub enum BrowserError {
+    #[error("No browser pairing with id {0:?}")]
+    NotFound(String),
+
+    #[error("A browser pairing with id {0:?} already exists")]
+    AlreadyExists(String),
+
+    #[error("Invalid browser pairing id {0:?}")]
+    InvalidId(String),
+}
+
+/// A browser extension that was paired with the database
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serialization", derive(serde::Serialize))]
+pub struct BrowserAssociation {
+    /// The name of the pairing, as chosen by the user
+    pub id: String,
+
+    /// The public key of the browser extension
+    pub public_key: [u8; BROWSER_PUBLIC_KEY_SIZE],
+
+    /// When the pairing was created or last changed
+    pub last_modified: Option<NaiveDateTime>,
+}
+
+impl Meta {
+    /// All browser pairings of the database, ordered by id. Items with the browser key prefix that
+    /// do not hold a valid public key are skipped.
+    pub fn browser_associations(&self) -> Vec<BrowserAssociation> {
+        let mut associations: Vec<BrowserAssociation> = self
+            .custom_data
+            .items
+            .iter()
+            .filter_map(|(key, item)| {
+                let id = key.strip_prefix(BROWSER_KEY_PREFIX)?;
+                parse_association(id, item)
+            })
+            .collect();
+        associations.sort_by(|a, b| a.id.cmp(&b.id));
+        associations
+    }
+
+    /// Get the browser pairing with the given id
+    pub fn browser_association(&self, id: &str) -> Option<BrowserAssociation> {
+        let item = self.custom_data.items.get(&browser_key(id))?;
+        parse_association(id, item)
+    }
+
+    /// Pair a browser extension with the database, replacing an existing pairing with the same id
+    pub fn set_browser_association(
+        &mut self,
+        id: &str,
+        public_key: &[u8; BROWSER_PUBLIC_KEY_SIZE],
+    ) -> Result<(), BrowserError> {
+        if id.is_empty() || id.chars().any(char::is_control) {
+            return Err(BrowserError::InvalidId(id.to_string()));
+        }
+
+        self.custom_data.items.insert(
+            browser_key(id),
+            CustomDataItem {
+                value: Some(Value::Unprotected(base64_engine::STANDARD.encode(public_key))),
+                last_modification_time: Some(Times::now()),
+            },
+        );
+        Ok(())
+    }
+
+    /// Remove a browser pairing, returning it if it existed
+    pub fn remove_browser_association(&mut self, id: &str) -> Option<BrowserAssociation> {
+        let item = self.custom_data.items.remove(&browser_key(id))?;
+        parse_association(id, &item)
+    }
+
+    /// Replace the public key of an existing pairing, e.g. after the browser extension generated
+    /// a new key pair. Returns the previous pairing.
+    pub fn rotate_browser_key(
+        &mut self,
+        id: &str,
+        new_public_key: &[u8; BROWSER_PUBLIC_KEY_SIZE],
+    ) -> Result<BrowserAssociation, BrowserError> {
+        let previous = self
+            .browser_association(id)
+            .ok_or_else(|| BrowserError::NotFound(id.to_string()))?;
+        self.set_browser_association(id, new_public_key)?;
+        Ok(previous)
+    }
+
+    /// Rename a pairing, keeping its public key
+    pub fn rename_browser_association(&mut self, id: &str, new_id: &str) -> Result<(), BrowserError> {
+        if self.custom_data.items.contains_key(&browser_key(new_id)) {
+            return Err(BrowserError::AlreadyExists(new_id.to_string()));
+        }
+
+        let previous = self
+            .browser_association(id)
+            .ok_or_else(|| BrowserError::NotFound(id.to_string()))?;
+        self.set_browser_association(new_id, &previous.public_key)?;
+        self.custom_data.items.remove(&browser_key(id));
+        Ok(())
+    }
+
+    /// Remove all pairings that were not changed since `cutoff`, returning their ids
+    pub fn remove_browser_associations_before(&mut self, cutoff: NaiveDateTime) -> Vec<String> {
+        let stale: Vec<String> = self
+            .browser_associations()
+            .into_iter()
+            .filter(|a| a.last_modified.is_some_and(|t| t < cutoff))
+            .map(|a| a.id)
+            .collect();
+
+        for id in &stale {
+            self.custom_data.items.remove(&browser_key(id));
+        }
+        stale
+    }
+}
+
+fn browser_key(id: &str) -> String {
+    format!("{}{}", BROWSER_KEY_PREFIX, id)
+}
+
+fn parse_association(id: &str, item: &CustomDataItem) -> Option<BrowserAssociation> {
+    let encoded = match item.value.as_ref()? {
+        Value::Unprotected(v) => v,
+        _ => return None,
+    };
+
+    let public_key = base64_engine::STANDARD.decode(encoded.trim()).ok()?;
+
+    Some(BrowserAssociation {
+        id: id.to_string(),
+        public_key: public_key.try_into().ok()?,
+        last_modified: item.last_modification_time,
+    })
+}
+
+#[cfg(test)]
+mod browser_tests {
+    use crate::db::{CustomDataItem, Meta, Times, Value};
+
+    use super::BrowserError;
+
+    #[test]
+    fn test_browser_associations() {
+        let mut meta = Meta::default();
+
+        // a pairing as written by KeePassXC
+        meta.custom_data.items.insert(
+            "KPXC_BROWSER_Firefox".to_string(),
+            CustomDataItem {
+                value: Some(Value::Unprotected(
+                    "AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE=".to_string(),
+                )),
+                last_modification_time: Some(Times::epoch()),
+            },
+        );
+        // unrelated or malformed items are ignored
+        meta.custom_data.items.insert(
+            "KPXC_BROWSER_broken".to_string(),
+            CustomDataItem {
+                value: Some(Value::Unprotected("not a key".to_string())),
+                last_modification_time: None,
+            },
+        );
+        meta.custom_data.items.insert(
+            "KPXC_DECRYPTION_TIME_PREFERENCE".to_string(),
+            CustomDataItem::default(),
+        );
+
+        let firefox = meta.browser_association("Firefox").unwrap();
+        assert_eq!(firefox.public_key, [1u8; 32]);
+
+        meta.set_browser_association("Chromium", &[2u8; 32]).unwrap();
+        let ids: Vec<String> = meta.browser_associations().into_iter().map(|a| a.id).collect();
+        assert_eq!(ids, vec!["Chromium", "Firefox"]);
+
+        let previous = meta.rotate_browser_key("Chromium", &[3u8; 32]).unwrap();
+        assert_eq!(previous.public_key, [2u8; 32]);
+        assert_eq!(
+            meta.browser_association("Chromium").unwrap().public_key,
+            [3u8; 32]
+        );
+        assert!(matches!(
+            meta.rotate_browser_key("Safari", &[3u8; 32]),
+            Err(BrowserError::NotFound(_))
+        ));
+
+        meta.rename_browser_association("Chromium", "Work laptop")
+            .unwrap();
+        assert!(meta.browser_association("Chromium").is_none());
+        assert_eq!(
+            meta.browser_association("Work laptop").unwrap().public_key,
+            [3u8; 32]
+        );
+        assert!(matches!(
+            meta.rename_browser_association("Work laptop", "Firefox"),
+            Err(BrowserError::AlreadyExists(_))
+        ));
+
+        let removed = meta.remove_browser_associations_before(Times::now() - chrono::Duration::days(1));
+        assert_eq!(removed, vec!["Firefox"]);
+        assert!(meta.remove_browser_association("Work laptop").is_some());
+        assert!(meta.browser_associations().is_empty());
+
+        assert!(matches!(
+            meta.set_browser_association("", &[0u8; 32]),
+            Err(BrowserError::InvalidId(_))
+        ));
+    }
+}
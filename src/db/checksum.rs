@@ -0,0 +1,439 @@
+//! Content checksums of group subtrees
+//!
+//! The checksum of a group covers the group itself and everything below it, so a sync layer or
+//! user interface can tell that nothing under e.g. `/Work` changed by comparing two 32 byte
+//! values instead of comparing the subtrees. Checksums are Merkle-style: the checksum of a group
+//! is computed from the checksums of its children, in order.
+//!
+//! Times and state that change by merely using the database (last access, usage count, location
+//! changes, expanded groups, ...) are left out unless `ChecksumOptions::include_volatile` is set.
+//! Attachments are covered by their name and identifier, not by their content.
+
+use std::collections::HashMap;
+
+use chrono::NaiveDateTime;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::db::{
+    CustomData, Entry, Group, Node, Times, Value, LAST_ACCESS_TIME_TAG_NAME, LOCATION_CHANGED_TAG_NAME,
+};
+
+/// A SHA-256 content hash
+pub type Checksum = [u8; 32];
+
+/// Settings for computing checksums
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ChecksumOptions {
+    /// Also cover times and state that change by merely using the database
+    pub include_volatile: bool,
+}
+
+impl Entry {
+    /// The content checksum of the entry, including its history
+    pub fn checksum(&self, options: &ChecksumOptions) -> Checksum {
+        let mut hasher = Hasher::new();
+        hasher.entry(self, options);
+        if let Some(ref history) = self.history {
+            for old in history.get_entries() {
+                hasher.entry(old, options);
+            }
+        }
+        hasher.finish()
+    }
+}
+
+impl Group {
+    /// The content checksum of the group and everything below it
+    pub fn checksum(&self, options: &ChecksumOptions) -> Checksum {
+        group_checksum(
+            self,
+            options,
+            &mut |entry| entry.checksum(options),
+            &mut |_, _| {},
+        )
+    }
+}
+
+/// Checksums of all groups of a tree, kept up to date with `update`
+///
+/// Entry checksums are cached and only recomputed when the modification time or history of an
+/// entry changed (and, with `include_volatile`, its access time or usage count). Changes that
+/// do not go through `Entry::update_history` or the commands are not noticed automatically, use
+/// `invalidate` for those.
+#[derive(Debug, Clone, Default)]
+pub struct SubtreeChecksums {
+    options: ChecksumOptions,
+    groups: HashMap<Uuid, Checksum>,
+    entries: HashMap<Uuid, (Stamp, Checksum)>,
+}
+
+/// The parts of an entry that change whenever its content is changed through the library
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Stamp {
+    last_modification: Option<NaiveDateTime>,
+    history_len: usize,
+    last_access: Option<NaiveDateTime>,
+    usage_count: usize,
+}
+
+impl SubtreeChecksums {
+    /// Compute the checksums of all groups under (and including) `root`
+    pub fn new(root: &Group, options: ChecksumOptions) -> Self {
+        let mut checksums = SubtreeChecksums {
+            options,
+            ..Default::default()
+        };
+        checksums.update(root);
+        checksums
+    }
+
+    /// The checksum of a group subtree, as of the last update
+    pub fn get(&self, group: Uuid) -> Option<Checksum> {
+        self.groups.get(&group).copied()
+    }
+
+    /// Bring the checksums up to date with the tree under `root`. Returns the UUIDs of the groups
+    /// whose checksum changed or that are new.
+    pub fn update(&mut self, root: &Group) -> Vec<Uuid> {
+        let options = self.options;
+        let cached = std::mem::take(&mut self.entries);
+        let mut entries = HashMap::new();
+        let mut groups = HashMap::new();
+
+        let mut entry_checksum = |entry: &Entry| {
+            let stamp = Stamp::new(entry, &options);
+            let checksum = match cached.get(&entry.uuid) {
+                Some((s, checksum)) if *s == stamp => *checksum,
+                _ => entry.checksum(&options),
+            };
+            entries.insert(entry.uuid, (stamp, checksum));
+            checksum
+        };
+        group_checksum(root, &options, &mut entry_checksum, &mut |uuid, checksum| {
+            groups.insert(uuid, checksum);
+        });
+
+        let mut changed: Vec<Uuid> = groups
+            .iter()
+            .filter(|(uuid, checksum)| self.groups.get(uuid) != Some(checksum))
+            .map(|(uuid, _)| *uuid)
+            .collect();
+        changed.sort();
+
+        self.groups = groups;
+        self.entries = entries;
+        changed
+    }
+
+    /// Forget the cached checksum of an entry that was changed without updating its
+    /// modification time, so that the next `update` recomputes it
+    pub fn invalidate(&mut self, entry: Uuid) {
+        self.entries.remove(&entry);
+    }
+}
+
+impl Stamp {
+    fn new(entry: &Entry, options: &ChecksumOptions) -> Self {
+        let volatile = options.include_volatile;
+        Stamp {
+            last_modification: entry.times.get_last_modification().copied(),
+            history_len: entry.history.as_ref().map_or(0, |h| h.get_entries().len()),
+            last_access: entry.times.get_last_access().copied().filter(|_| volatile),
+            usage_count: if volatile { entry.times.usage_count } else { 0 },
+        }
+    }
+}
+
+fn group_checksum(
+    group: &Group,
+    options: &ChecksumOptions,
+    entry_checksum: &mut dyn FnMut(&Entry) -> Checksum,
+    record: &mut dyn FnMut(Uuid, Checksum),
+) -> Checksum {
+    let mut hasher = Hasher::new();
+    hasher.group(group, options);
+
+    for child in &group.children {
+        match child {
+            Node::Entry(e) => {
+                hasher.tag(b'e');
+                hasher.bytes(&entry_checksum(e));
+            }
+            Node::Group(g) => {
+                hasher.tag(b'g');
+                hasher.bytes(&group_checksum(g, options, entry_checksum, record));
+            }
+        }
+    }
+
+    let checksum = hasher.finish();
+    record(group.uuid, checksum);
+    checksum
+}
+
+/// Feeds the content of nodes into SHA-256 in an unambiguous way
+struct Hasher(Sha256);
+
+impl Hasher {
+    fn new() -> Self {
+        Hasher(Sha256::new())
+    }
+
+    fn finish(self) -> Checksum {
+        self.0.finalize().into()
+    }
+
+    fn tag(&mut self, tag: u8) {
+        self.0.update([tag]);
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.0.update((bytes.len() as u64).to_le_bytes());
+        self.0.update(bytes);
+    }
+
+    fn count(&mut self, count: usize) {
+        self.0.update((count as u64).to_le_bytes());
+    }
+
+    fn str(&mut self, value: &str) {
+        self.bytes(value.as_bytes());
+    }
+
+    fn opt_str(&mut self, value: Option<&str>) {
+        match value {
+            Some(v) => {
+                self.tag(1);
+                self.str(v);
+            }
+            None => self.tag(0),
+        }
+    }
+
+    fn opt_uuid(&mut self, value: Option<&Uuid>) {
+        match value {
+            Some(v) => {
+                self.tag(1);
+                self.bytes(v.as_bytes());
+            }
+            None => self.tag(0),
+        }
+    }
+
+    fn value(&mut self, value: &Value) {
+        match value {
+            Value::Bytes(b) => {
+                self.tag(b'b');
+                self.bytes(b);
+            }
+            Value::Unprotected(s) => {
+                self.tag(b'u');
+                self.str(s);
+            }
+            Value::Protected(s) => {
+                self.tag(b'p');
+                self.bytes(&s.decrypt());
+            }
+        }
+    }
+
+    fn times(&mut self, times: &Times, options: &ChecksumOptions) {
+        self.tag(times.expires as u8);
+        if options.include_volatile {
+            self.count(times.usage_count);
+        }
+
+        let mut keys: Vec<&String> = times
+            .times
+            .keys()
+            .filter(|k| {
+                options.include_volatile
+                    || (k.as_str() != LAST_ACCESS_TIME_TAG_NAME && k.as_str() != LOCATION_CHANGED_TAG_NAME)
+            })
+            .collect();
+        keys.sort();
+        self.count(keys.len());
+        for key in keys {
+            self.str(key);
+            self.bytes(&times.times[key].and_utc().timestamp().to_le_bytes());
+        }
+    }
+
+    fn custom_data(&mut self, custom_data: &CustomData) {
+        let mut keys: Vec<&String> = custom_data.items.keys().collect();
+        keys.sort();
+        self.count(keys.len());
+        for key in keys {
+            let item = &custom_data.items[key];
+            self.str(key);
+            match item.value {
+                Some(ref value) => self.value(value),
+                None => self.tag(0),
+            }
+        }
+    }
+
+    fn group(&mut self, group: &Group, options: &ChecksumOptions) {
+        self.tag(b'G');
+        self.bytes(group.uuid.as_bytes());
+        self.str(&group.name);
+        self.opt_str(group.notes.as_deref());
+        self.count(group.tags.len());
+        for tag in &group.tags {
+            self.str(tag);
+        }
+        self.opt_str(group.icon_id.map(|i| i.to_string()).as_deref());
+        self.opt_uuid(group.custom_icon_uuid.as_ref());
+        self.times(&group.times, options);
+        self.custom_data(&group.custom_data);
+        self.opt_str(group.default_autotype_sequence.as_deref());
+        self.opt_str(group.enable_autotype.as_deref());
+        self.opt_str(group.enable_searching.as_deref());
+        if options.include_volatile {
+            self.tag(group.is_expanded as u8);
+            self.opt_uuid(group.last_top_visible_entry.as_ref());
+            self.opt_uuid(group.previous_parent_group.as_ref());
+        }
+    }
+
+    fn entry(&mut self, entry: &Entry, options: &ChecksumOptions) {
+        self.tag(b'E');
+        self.bytes(entry.uuid.as_bytes());
+
+        let mut names: Vec<&String> = entry.fields.keys().collect();
+        names.sort();
+        self.count(names.len());
+        for name in names {
+            self.str(name);
+            self.value(&entry.fields[name]);
+        }
+
+        match entry.autotype {
+            Some(ref autotype) => {
+                self.tag(autotype.enabled as u8 + 1);
+                self.opt_str(autotype.sequence.as_deref());
+                self.count(autotype.data_transfer_obfuscation);
+                self.count(autotype.associations.len());
+                for association in &autotype.associations {
+                    self.opt_str(association.window.as_deref());
+                    self.opt_str(association.sequence.as_deref());
+                }
+            }
+            None => self.tag(0),
+        }
+
+        self.count(entry.tags.len());
+        for tag in &entry.tags {
+            self.str(tag);
+        }
+        self.times(&entry.times, options);
+        self.custom_data(&entry.custom_data);
+        self.opt_str(entry.icon_id.map(|i| i.to_string()).as_deref());
+        self.opt_uuid(entry.custom_icon_uuid.as_ref());
+        self.opt_str(entry.foreground_color.as_ref().map(|c| c.to_string()).as_deref());
+        self.opt_str(entry.background_color.as_ref().map(|c| c.to_string()).as_deref());
+        self.opt_str(entry.override_url.as_deref());
+        self.opt_str(entry.quality_check.map(|q| q.to_string()).as_deref());
+        self.count(entry.attachments.len());
+        for attachment in &entry.attachments {
+            self.str(&attachment.name);
+            self.count(attachment.identifier);
+        }
+    }
+}
+
+#[cfg(test)]
+mod checksum_tests {
+    use crate::db::{Entry, Group, Times, Value, LAST_ACCESS_TIME_TAG_NAME};
+
+    use super::{ChecksumOptions, SubtreeChecksums};
+
+    fn entry(title: &str) -> Entry {
+        let mut entry = Entry::new();
+        entry
+            .fields
+            .insert("Title".to_string(), Value::Unprotected(title.to_string()));
+        entry.fields.insert(
+            "Password".to_string(),
+            Value::Protected("secret".as_bytes().into()),
+        );
+        entry
+    }
+
+    #[test]
+    fn test_subtree_checksums() {
+        let options = ChecksumOptions::default();
+
+        let mut root = Group::new("Root");
+        let mut work = Group::new("Work");
+        work.add_child(entry("ci"));
+        let work_uuid = work.uuid;
+        root.add_child(work);
+        let mut home = Group::new("Home");
+        home.add_child(entry("mail"));
+        let home_uuid = home.uuid;
+        root.add_child(home);
+
+        // checksums are stable
+        let before = root.checksum(&options);
+        assert_eq!(root.clone().checksum(&options), before);
+
+        let mut checksums = SubtreeChecksums::new(&root, options);
+        assert_eq!(checksums.get(root.uuid), Some(before));
+        assert!(checksums.update(&root).is_empty());
+
+        // volatile times are ignored by default
+        root.groups_mut()[0].entries_mut()[0]
+            .times
+            .times
+            .insert(LAST_ACCESS_TIME_TAG_NAME.to_string(), Times::now());
+        root.groups_mut()[0].entries_mut()[0].times.usage_count += 1;
+        assert_eq!(root.checksum(&options), before);
+        assert_ne!(
+            root.checksum(&ChecksumOptions {
+                include_volatile: true
+            }),
+            before
+        );
+
+        // protected values are covered
+        let home_before = checksums.get(home_uuid);
+        let mut groups = root.groups_mut();
+        let mut entries = groups[0].entries_mut();
+        let entry = &mut entries[0];
+        entry.fields.insert(
+            "Password".to_string(),
+            Value::Protected("changed".as_bytes().into()),
+        );
+        let entry_uuid = entry.uuid;
+        drop(entries);
+        drop(groups);
+
+        // without a new modification time, the cached entry checksum is used until invalidated
+        assert!(checksums.update(&root).is_empty());
+        checksums.invalidate(entry_uuid);
+        assert_eq!(checksums.update(&root), {
+            let mut changed = vec![root.uuid, work_uuid];
+            changed.sort();
+            changed
+        });
+        assert_eq!(checksums.get(home_uuid), home_before);
+        assert_eq!(checksums.get(root.uuid), Some(root.checksum(&options)));
+        assert_ne!(root.checksum(&options), before);
+
+        // changes through the history are picked up by themselves
+        let mut groups = root.groups_mut();
+        let mut entries = groups[1].entries_mut();
+        let entry = &mut entries[0];
+        entry
+            .fields
+            .insert("Title".to_string(), Value::Unprotected("webmail".to_string()));
+        entry.update_history();
+        drop(entries);
+        drop(groups);
+        let changed = checksums.update(&root);
+        assert!(changed.contains(&home_uuid));
+        assert!(!changed.contains(&work_uuid));
+    }
+}
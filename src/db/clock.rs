@@ -0,0 +1,179 @@
+//! Injectable source of the current time
+//!
+//! Every timestamp the library sets (creation and modification times, history, expiry checks,
+//! usage statistics, ...) is taken from `Times::now`, which asks the clock of the current thread.
+//! By default this is the system clock, but tests can run code with a different clock to get
+//! deterministic timestamps:
+//!
+//! ```
+//! use keepass::db::{with_clock, Entry, ManualClock, Times, Value};
+//!
+//! let start = chrono::NaiveDate::from_ymd_opt(2024, 1, 1)
+//!     .unwrap()
+//!     .and_hms_opt(12, 0, 0)
+//!     .unwrap();
+//! let clock = ManualClock::new(start);
+//!
+//! with_clock(clock.clone(), || {
+//!     let mut entry = Entry::new();
+//!     assert_eq!(entry.times.get_creation(), Some(&start));
+//!
+//!     clock.advance(chrono::Duration::minutes(5));
+//!     entry.fields.insert("Title".to_string(), Value::Unprotected("example".to_string()));
+//!     entry.update_history();
+//!     assert_eq!(
+//!         entry.times.get_last_modification(),
+//!         Some(&(start + chrono::Duration::minutes(5)))
+//!     );
+//!     assert_eq!(Times::now(), start + chrono::Duration::minutes(5));
+//! });
+//! ```
+
+use std::{cell::RefCell, rc::Rc};
+
+use chrono::NaiveDateTime;
+
+/// A source of the current time, in UTC
+pub trait Clock {
+    fn now(&self) -> NaiveDateTime;
+}
+
+impl<F> Clock for F
+where
+    F: Fn() -> NaiveDateTime,
+{
+    fn now(&self) -> NaiveDateTime {
+        self()
+    }
+}
+
+/// The clock of the operating system
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> NaiveDateTime {
+        chrono::Utc::now().naive_utc()
+    }
+}
+
+/// A clock that only moves when told to. Clones share the same time, so a test can keep a clone
+/// to advance the clock that was passed to `with_clock`.
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    now: Rc<RefCell<NaiveDateTime>>,
+}
+
+impl ManualClock {
+    pub fn new(now: NaiveDateTime) -> Self {
+        ManualClock {
+            now: Rc::new(RefCell::new(now)),
+        }
+    }
+
+    pub fn set(&self, now: NaiveDateTime) {
+        *self.now.borrow_mut() = now;
+    }
+
+    pub fn advance(&self, duration: chrono::Duration) {
+        let mut now = self.now.borrow_mut();
+        *now += duration;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> NaiveDateTime {
+        *self.now.borrow()
+    }
+}
+
+thread_local! {
+    static CLOCK: RefCell<Vec<Rc<dyn Clock>>> = RefCell::new(Vec::new());
+}
+
+/// Run `f` with `clock` as the source of the current time on this thread. Calls can be nested,
+/// the innermost clock wins. Other threads keep using their own clock.
+pub fn with_clock<C: Clock + 'static, R>(clock: C, f: impl FnOnce() -> R) -> R {
+    struct PopOnDrop;
+
+    impl Drop for PopOnDrop {
+        fn drop(&mut self) {
+            CLOCK.with(|c| c.borrow_mut().pop());
+        }
+    }
+
+    CLOCK.with(|c| c.borrow_mut().push(Rc::new(clock)));
+    let _pop = PopOnDrop;
+    f()
+}
+
+/// The current time according to the clock of this thread
+pub(crate) fn current_time() -> NaiveDateTime {
+    // clone the clock so that it can itself call `with_clock`
+    let clock = CLOCK.with(|c| c.borrow().last().cloned());
+    match clock {
+        Some(clock) => clock.now(),
+        None => SystemClock.now(),
+    }
+}
+
+/// The current time of the clock installed on this thread, if any
+#[cfg(feature = "async")]
+pub(crate) fn installed_time() -> Option<NaiveDateTime> {
+    let clock = CLOCK.with(|c| c.borrow().last().cloned());
+    clock.map(|clock| clock.now())
+}
+
+#[cfg(test)]
+mod clock_tests {
+    use chrono::{NaiveDate, Timelike};
+
+    use crate::db::{Entry, Times, Value};
+
+    use super::{with_clock, ManualClock};
+
+    #[test]
+    fn test_with_clock() {
+        let start = NaiveDate::from_ymd_opt(2020, 2, 29)
+            .unwrap()
+            .and_hms_milli_opt(8, 30, 0, 250)
+            .unwrap();
+        let clock = ManualClock::new(start);
+
+        let mut entry = with_clock(clock.clone(), || {
+            // sub-second precision is dropped, as it is not stored in the database
+            assert_eq!(Times::now(), start.with_nanosecond(0).unwrap());
+
+            let mut entry = Entry::new();
+            clock.advance(chrono::Duration::days(1));
+            entry
+                .fields
+                .insert("Title".to_string(), Value::Unprotected("first".to_string()));
+            entry.update_history();
+
+            assert_eq!(
+                entry.times.get_last_modification(),
+                Some(
+                    &NaiveDate::from_ymd_opt(2020, 3, 1)
+                        .unwrap()
+                        .and_hms_opt(8, 30, 0)
+                        .unwrap()
+                )
+            );
+
+            // the innermost clock wins
+            with_clock(Times::epoch, || assert_eq!(Times::now(), Times::epoch()));
+            assert_eq!(Times::now().date(), NaiveDate::from_ymd_opt(2020, 3, 1).unwrap());
+
+            entry
+        });
+
+        // outside of with_clock, the system clock is used again
+        assert!(Times::now().date() > NaiveDate::from_ymd_opt(2024, 1, 1).unwrap());
+        entry
+            .fields
+            .insert("Title".to_string(), Value::Unprotected("second".to_string()));
+        entry.update_history();
+        assert_eq!(entry.history.unwrap().get_entries().len(), 2);
+    }
+}
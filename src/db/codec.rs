@@ -0,0 +1,248 @@
+//! Detection of already-compressed attachment content
+//!
+//! Compressing data that is already compressed (archives, most image formats) costs time without
+//! making the file any smaller. Attachments are identified by the magic bytes at the start of
+//! their content, so that binaries in the XML metadata are only stored compressed when that
+//! actually saves space.
+
+use crate::db::{BinaryAttachment, BinaryAttachments, HeaderAttachment};
+
+/// The format of attachment content, as far as it matters for compression
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serialization", derive(serde::Serialize))]
+pub enum AttachmentCodec {
+    /// ZIP archives, including formats based on them (e.g. docx, odt, jar)
+    Zip,
+    Gzip,
+    Bzip2,
+    Xz,
+    Zstd,
+    SevenZip,
+    Png,
+    Jpeg,
+    Gif,
+    WebP,
+
+    /// Content that is not known to be compressed
+    Unknown,
+}
+
+impl AttachmentCodec {
+    /// Detect the format of some content from its magic bytes
+    pub fn detect(content: &[u8]) -> Self {
+        const SIGNATURES: &[(&[u8], AttachmentCodec)] = &[
+            (b"PK\x03\x04", AttachmentCodec::Zip),
+            (b"PK\x05\x06", AttachmentCodec::Zip),
+            (b"\x1f\x8b", AttachmentCodec::Gzip),
+            (b"BZh", AttachmentCodec::Bzip2),
+            (b"\xfd7zXZ\x00", AttachmentCodec::Xz),
+            (b"\x28\xb5\x2f\xfd", AttachmentCodec::Zstd),
+            (b"7z\xbc\xaf\x27\x1c", AttachmentCodec::SevenZip),
+            (b"\x89PNG\r\n\x1a\n", AttachmentCodec::Png),
+            (b"\xff\xd8\xff", AttachmentCodec::Jpeg),
+            (b"GIF87a", AttachmentCodec::Gif),
+            (b"GIF89a", AttachmentCodec::Gif),
+        ];
+
+        for (signature, codec) in SIGNATURES {
+            if content.starts_with(signature) {
+                return *codec;
+            }
+        }
+
+        if content.len() >= 12 && &content[0..4] == b"RIFF" && &content[8..12] == b"WEBP" {
+            return AttachmentCodec::WebP;
+        }
+
+        AttachmentCodec::Unknown
+    }
+
+    /// Whether content in this format is already compressed
+    pub fn is_compressed(&self) -> bool {
+        *self != AttachmentCodec::Unknown
+    }
+}
+
+/// How much space storing an attachment compressed saves
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serialization", derive(serde::Serialize))]
+pub struct AttachmentSavings {
+    pub identifier: Option<String>,
+    pub codec: AttachmentCodec,
+
+    /// Size of the attachment content
+    pub original_size: usize,
+
+    /// Size of the attachment as stored in the database, before encoding
+    pub stored_size: usize,
+}
+
+impl AttachmentSavings {
+    pub fn saved_bytes(&self) -> usize {
+        self.original_size.saturating_sub(self.stored_size)
+    }
+}
+
+impl BinaryAttachment {
+    /// Detect the format of the attachment content
+    pub fn codec(&self) -> AttachmentCodec {
+        match self.data() {
+            Ok(data) => AttachmentCodec::detect(&data),
+            Err(_) => AttachmentCodec::Unknown,
+        }
+    }
+
+    /// Whether the attachment should be compressed when writing it. Content that is already
+    /// compressed is never compressed again, even if `compressed` is set.
+    #[cfg(feature = "save_kdbx4")]
+    pub(crate) fn store_compressed(&self) -> bool {
+        self.compressed && !self.codec().is_compressed()
+    }
+
+    /// Decide whether to store the attachment compressed, by trying to compress content that is
+    /// not compressed already and keeping the compression only if it makes the attachment smaller.
+    #[cfg(feature = "save_kdbx4")]
+    pub fn recompress(&mut self) -> AttachmentSavings {
+        use crate::compression::{Compression, GZipCompression};
+
+        let codec = self.codec();
+        let content = self.data().unwrap_or_default();
+        let original_size = content.len();
+
+        let compressed_size = if codec.is_compressed() {
+            None
+        } else {
+            GZipCompression::default()
+                .compress(&content)
+                .ok()
+                .map(|c| c.len())
+                .filter(|size| *size < original_size)
+        };
+
+        self.compressed = compressed_size.is_some();
+
+        AttachmentSavings {
+            identifier: self.identifier.clone(),
+            codec,
+            original_size,
+            stored_size: compressed_size.unwrap_or(original_size),
+        }
+    }
+}
+
+impl BinaryAttachments {
+    /// Decide for every attachment whether to store it compressed, returning the savings per
+    /// attachment
+    #[cfg(feature = "save_kdbx4")]
+    pub fn recompress(&mut self) -> Vec<AttachmentSavings> {
+        self.binaries.iter_mut().map(|b| b.recompress()).collect()
+    }
+}
+
+impl HeaderAttachment {
+    /// Detect the format of the attachment content
+    pub fn codec(&self) -> AttachmentCodec {
+        match self.data() {
+            Ok(data) => AttachmentCodec::detect(&data),
+            Err(_) => AttachmentCodec::Unknown,
+        }
+    }
+}
+
+#[cfg(test)]
+mod codec_tests {
+    use crate::db::BinaryAttachment;
+
+    use super::AttachmentCodec;
+
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\x00\x00\x00\x0dIHDR";
+
+    #[test]
+    fn test_detect() {
+        assert_eq!(AttachmentCodec::detect(PNG), AttachmentCodec::Png);
+        assert_eq!(
+            AttachmentCodec::detect(b"PK\x03\x04\x14\x00"),
+            AttachmentCodec::Zip
+        );
+        assert_eq!(
+            AttachmentCodec::detect(b"\xff\xd8\xff\xe0\x00\x10JFIF"),
+            AttachmentCodec::Jpeg
+        );
+        assert_eq!(
+            AttachmentCodec::detect(b"RIFF\x00\x00\x00\x00WEBPVP8 "),
+            AttachmentCodec::WebP
+        );
+        assert_eq!(AttachmentCodec::detect(b"plain text"), AttachmentCodec::Unknown);
+        assert_eq!(AttachmentCodec::detect(b""), AttachmentCodec::Unknown);
+        assert!(!AttachmentCodec::Unknown.is_compressed());
+        assert!(AttachmentCodec::Png.is_compressed());
+    }
+
+    #[cfg(feature = "save_kdbx4")]
+    #[test]
+    fn test_store_compressed() {
+        let image = BinaryAttachment {
+            identifier: Some("0".to_string()),
+            compressed: true,
+            content: PNG.to_vec(),
+            packed: false,
+            external: false,
+        };
+        assert!(!image.store_compressed());
+
+        let text = BinaryAttachment {
+            identifier: Some("1".to_string()),
+            compressed: true,
+            content: b"some text".to_vec(),
+            packed: false,
+            external: false,
+        };
+        assert!(text.store_compressed());
+    }
+
+    #[cfg(feature = "save_kdbx4")]
+    #[test]
+    fn test_recompress() {
+        use crate::db::BinaryAttachments;
+
+        let mut binaries = BinaryAttachments {
+            binaries: vec![
+                BinaryAttachment {
+                    identifier: Some("0".to_string()),
+                    compressed: true,
+                    content: PNG.to_vec(),
+                    packed: false,
+                    external: false,
+                },
+                BinaryAttachment {
+                    identifier: Some("1".to_string()),
+                    compressed: false,
+                    content: "compressible ".repeat(100).into_bytes(),
+                    packed: false,
+                    external: false,
+                },
+                BinaryAttachment {
+                    identifier: Some("2".to_string()),
+                    compressed: true,
+                    content: b"tiny".to_vec(),
+                    packed: false,
+                    external: false,
+                },
+            ],
+        };
+
+        let savings = binaries.recompress();
+
+        assert!(!binaries.binaries[0].compressed);
+        assert_eq!(savings[0].codec, AttachmentCodec::Png);
+        assert_eq!(savings[0].saved_bytes(), 0);
+
+        assert!(binaries.binaries[1].compressed);
+        assert_eq!(savings[1].original_size, 1300);
+        assert!(savings[1].saved_bytes() > 1000);
+
+        // compressing tiny content would only add overhead
+        assert!(!binaries.binaries[2].compressed);
+        assert_eq!(savings[2].saved_bytes(), 0);
+    }
+}
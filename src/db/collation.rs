@@ -0,0 +1,44 @@
+//! Locale-aware comparison of titles, backed by ICU
+
+use std::cmp::Ordering;
+
+use icu_collator::{options::CollatorOptions, Collator, CollatorBorrowed, CollatorPreferences};
+use icu_locale_core::Locale;
+use thiserror::Error;
+
+/// Errors while setting up locale-aware sorting
+#[derive(Debug, Error)]
+pub enum CollationError {
+    #[error("Invalid locale identifier {0:?}")]
+    InvalidLocale(String),
+
+    #[error("No collation data for locale: {0}")]
+    Data(String),
+}
+
+/// Compares strings following the collation rules of a locale
+pub(crate) struct TitleCollator(CollatorBorrowed<'static>);
+
+impl TitleCollator {
+    /// A collator using the language-independent root collation order
+    pub(crate) fn root() -> Self {
+        Self::for_locale(&Locale::UNKNOWN).expect("root collation data is compiled in")
+    }
+
+    pub(crate) fn new(locale: &str) -> Result<Self, CollationError> {
+        let locale: Locale = locale
+            .parse()
+            .map_err(|_| CollationError::InvalidLocale(locale.to_string()))?;
+        Self::for_locale(&locale)
+    }
+
+    fn for_locale(locale: &Locale) -> Result<Self, CollationError> {
+        let collator = Collator::try_new(CollatorPreferences::from(locale), CollatorOptions::default())
+            .map_err(|e| CollationError::Data(e.to_string()))?;
+        Ok(TitleCollator(collator))
+    }
+
+    pub(crate) fn compare(&self, a: &str, b: &str) -> Ordering {
+        self.0.compare(a, b)
+    }
+}
@@ -0,0 +1,204 @@
+//! Saving without overwriting the changes of other clients
+//!
+//! When a database is shared through a synced folder, another client may save it while it is open
+//! here. `Database::save_if_unchanged` checks that the file still is the one the database was read
+//! from, and otherwise hands back what is in the file now, so that it can be merged instead of
+//! being overwritten:
+//!
+//! ```
+//! # #[cfg(feature = "save_kdbx4")]
+//! # {
+//! use keepass::{db::{Entry, SaveConflictError}, Database, DatabaseKey};
+//!
+//! # let dir = std::env::temp_dir().join(format!("keepass-doc-{}", uuid::Uuid::new_v4()));
+//! # std::fs::create_dir(&dir)?;
+//! # let path = dir.join("vault.kdbx");
+//! # let key = DatabaseKey::new().with_password("demopass");
+//! # Database::new(Default::default()).save_if_unchanged(&path, key.clone())?;
+//! let mut db = Database::open(&mut std::fs::File::open(&path)?, key.clone())?;
+//! db.root.add_child(Entry::new());
+//!
+//! # let mut other = Database::open(&mut std::fs::File::open(&path)?, key.clone())?;
+//! # other.save_if_unchanged(&path, key.clone())?;
+//! match db.save_if_unchanged(&path, key.clone()) {
+//!     Err(SaveConflictError::Conflict(external)) => {
+//!         // merge the changes of the other client, then take its file as the one to replace
+//!         db.file_fingerprint = external.file_fingerprint;
+//!         db.save_if_unchanged(&path, key)?;
+//!     }
+//!     result => result?,
+//! }
+//! # std::fs::remove_dir_all(&dir)?;
+//! # }
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+//!
+//! The check only narrows the window for lost updates: a client saving between the check and the
+//! replacement of the file still goes unnoticed. Lock files, see `crate::lock`, close it among
+//! clients that use them.
+
+use std::io::Read;
+#[cfg(feature = "save_kdbx4")]
+use std::path::Path;
+
+#[cfg(feature = "save_kdbx4")]
+use thiserror::Error;
+
+use crate::{crypt::calculate_sha256, error::DatabaseOpenError, format::read_outer_header_data};
+#[cfg(feature = "save_kdbx4")]
+use crate::{
+    db::{Database, KeyCheck},
+    error::DatabaseSaveError,
+    key::DatabaseKey,
+};
+
+/// Identifies the version of a KDBX file a database was read from by the SHA-256 hash of its
+/// outer header. Every save renews the seeds in the header, so the hash changes whenever any
+/// client saves the file.
+///
+/// Where a database was read from is not part of its contents, so fingerprints are equal as far as
+/// the equality of databases is concerned.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FileFingerprint(Option<[u8; 32]>);
+
+impl FileFingerprint {
+    /// The fingerprint of a KDBX file, which is unknown for other files
+    pub fn of(data: &[u8]) -> FileFingerprint {
+        FileFingerprint::read(&mut &data[..]).unwrap_or_default()
+    }
+
+    /// Read the fingerprint from the start of a KDBX file
+    pub(crate) fn read(source: &mut dyn Read) -> Result<FileFingerprint, DatabaseOpenError> {
+        let header_data = read_outer_header_data(source)?;
+        Ok(FileFingerprint::of_header(&header_data))
+    }
+
+    /// The fingerprint of the outer header of a KDBX file
+    pub(crate) fn of_header(header_data: &[u8]) -> FileFingerprint {
+        FileFingerprint(calculate_sha256(&[header_data]).ok().map(Into::into))
+    }
+
+    /// Whether the database was read from a KDBX file
+    pub fn is_known(&self) -> bool {
+        self.0.is_some()
+    }
+
+    /// Whether both fingerprints are known and identify the same version of a file
+    pub fn matches(&self, other: &FileFingerprint) -> bool {
+        self.0.is_some() && self.0 == other.0
+    }
+}
+
+impl PartialEq for FileFingerprint {
+    fn eq(&self, _: &FileFingerprint) -> bool {
+        true
+    }
+}
+
+impl Eq for FileFingerprint {}
+
+/// Errors while saving a database with `Database::save_if_unchanged`
+#[cfg(feature = "save_kdbx4")]
+#[derive(Debug, Error)]
+pub enum SaveConflictError {
+    /// The file was saved by another client since the database was read from it, or the database
+    /// was not read from it at all. Holds the database as it is in the file now.
+    #[error("The database file was changed by another client")]
+    Conflict(Box<Database>),
+
+    /// The file could not be read to check for changes
+    #[error(transparent)]
+    Open(#[from] DatabaseOpenError),
+
+    #[error(transparent)]
+    Save(#[from] DatabaseSaveError),
+}
+
+#[cfg(feature = "save_kdbx4")]
+impl Database {
+    /// Save the database to the file at `path`, unless another client changed the file since the
+    /// database was read from it, see the module documentation. The file is replaced atomically
+    /// like with `Database::save_to_path`, and the database then remembers the file it saved.
+    pub fn save_if_unchanged(
+        &mut self,
+        path: impl AsRef<Path>,
+        key: DatabaseKey,
+    ) -> Result<(), SaveConflictError> {
+        let path = path.as_ref();
+
+        match std::fs::File::open(path) {
+            Ok(file) => {
+                let mut file = std::io::BufReader::new(file);
+                if !self.file_fingerprint.matches(&FileFingerprint::read(&mut file)?) {
+                    let file = std::fs::File::open(path).map_err(DatabaseOpenError::from)?;
+                    let external = Database::open(&mut std::io::BufReader::new(file), key)?;
+                    return Err(SaveConflictError::Conflict(Box::new(external)));
+                }
+            }
+            // nobody else saved a file that is not there
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(DatabaseOpenError::from(e).into()),
+        }
+
+        self.save_to_path(path, key, &Default::default())?;
+        let saved = std::fs::File::open(path).map_err(DatabaseOpenError::from)?;
+        self.file_fingerprint = FileFingerprint::read(&mut std::io::BufReader::new(saved))?;
+        let saved = std::fs::File::open(path).map_err(DatabaseOpenError::from)?;
+        self.key_check = KeyCheck::read(&mut std::io::BufReader::new(saved))?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "save_kdbx4")]
+#[cfg(test)]
+mod conflict_tests {
+    use super::{FileFingerprint, SaveConflictError};
+    use crate::{db::Entry, Database, DatabaseKey};
+
+    #[test]
+    fn test_save_if_unchanged() {
+        let dir = std::env::temp_dir().join(format!("keepass-conflict-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        let path = dir.join("vault.kdbx");
+        let key = DatabaseKey::new().with_password("test");
+        let open = || Database::open(&mut std::fs::File::open(&path).unwrap(), key.clone()).unwrap();
+
+        let mut db = Database::new(Default::default());
+        assert!(!db.file_fingerprint.is_known());
+        db.save_if_unchanged(&path, key.clone()).unwrap();
+        assert!(db.file_fingerprint.is_known());
+        assert_eq!(db.key_check.verify(&key).unwrap(), Some(true));
+
+        // a new database is not saved over an existing file
+        assert!(matches!(
+            Database::new(Default::default()).save_if_unchanged(&path, key.clone()),
+            Err(SaveConflictError::Conflict(_))
+        ));
+
+        let mut ours = open();
+        let mut theirs = open();
+        assert!(ours.file_fingerprint.matches(&db.file_fingerprint));
+        assert!(ours
+            .file_fingerprint
+            .matches(&FileFingerprint::of(&std::fs::read(&path).unwrap())));
+
+        theirs.root.add_child(Entry::new().with_title("Theirs"));
+        theirs.save_if_unchanged(&path, key.clone()).unwrap();
+        // saving again is fine, as the file is the one saved last
+        theirs.save_if_unchanged(&path, key.clone()).unwrap();
+
+        ours.root.add_child(Entry::new());
+        let external = match ours.save_if_unchanged(&path, key.clone()) {
+            Err(SaveConflictError::Conflict(external)) => external,
+            _ => panic!("Expected a conflict"),
+        };
+        assert_eq!(external.root, theirs.root);
+        assert_eq!(open().root, theirs.root);
+
+        ours.file_fingerprint = external.file_fingerprint;
+        ours.save_if_unchanged(&path, key.clone()).unwrap();
+        assert_eq!(open().root, ours.root);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
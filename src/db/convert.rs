@@ -0,0 +1,298 @@
+//! Conversion between the KDBX 3 and KDBX 4 formats
+//!
+//! The formats differ in more than the version number: KDBX 4 keeps attachments in the inner
+//! header instead of the metadata and prefers the ChaCha20 inner cipher, while KDBX 3 only knows
+//! the AES key derivation. `Database::convert_to` moves the attachments and adjusts the settings,
+//! so that the database can be saved in the other format:
+//!
+//! ```
+//! use keepass::{
+//!     config::{DatabaseConfig, DatabaseVersion, KdfConfig},
+//!     Database,
+//! };
+//!
+//! let mut db = Database::new(DatabaseConfig::default());
+//! assert!(db.convert_to(DatabaseVersion::KDB3(1)).is_err()); // uses Argon2
+//!
+//! db.config.kdf_config = KdfConfig::Aes { rounds: 100_000 };
+//! db.convert_to(DatabaseVersion::KDB3(1))?;
+//! # Ok::<(), keepass::db::ConversionError>(())
+//! ```
+//!
+//! Upgrading to KDBX 4 always succeeds. The AES key derivation is kept, with its rounds written
+//! to the KDF parameters of the KDBX 4 header when saving, and the database gets a UUID in its
+//! public custom data. Downgrading fails with a list of the settings that KDBX 3 cannot hold,
+//! before anything is changed.
+
+use std::collections::HashMap;
+
+use thiserror::Error;
+
+use crate::{
+    config::{InnerCipherConfig, KdfConfig},
+    db::{
+        public_data::DATABASE_UUID_KEY, BinaryAttachment, BinaryAttachments, Database, Entry, Group,
+        HeaderAttachment, Node,
+    },
+    format::DatabaseVersion,
+};
+
+/// Errors upon converting a database to another file format version
+#[derive(Debug, Error)]
+pub enum ConversionError {
+    /// Only KDBX 3 and KDBX 4 databases can be converted
+    #[error("Cannot convert from {} to {}", from.to_string(), to.to_string())]
+    UnsupportedVersion {
+        from: DatabaseVersion,
+        to: DatabaseVersion,
+    },
+
+    /// The database uses settings that the target version does not support
+    #[error("{} does not support: {}", version.to_string(), features.join(", "))]
+    IncompatibleFeatures {
+        version: DatabaseVersion,
+        features: Vec<String>,
+    },
+}
+
+impl Database {
+    /// Convert the database to another version of the KDBX format, to be used when it is saved.
+    /// Converting to the version the database already has only changes the minor version.
+    pub fn convert_to(&mut self, version: DatabaseVersion) -> Result<(), ConversionError> {
+        match (&self.config.version, &version) {
+            (DatabaseVersion::KDB3(_), DatabaseVersion::KDB3(_))
+            | (DatabaseVersion::KDB4(_), DatabaseVersion::KDB4(_)) => {}
+            (DatabaseVersion::KDB3(_), DatabaseVersion::KDB4(_)) => self.upgrade_to_kdbx4(),
+            (DatabaseVersion::KDB4(_), DatabaseVersion::KDB3(_)) => self.downgrade_to_kdbx3()?,
+            (from, to) => {
+                return Err(ConversionError::UnsupportedVersion {
+                    from: from.clone(),
+                    to: to.clone(),
+                })
+            }
+        }
+
+        self.config.version = version;
+        Ok(())
+    }
+
+    /// The settings of the database that KDBX 3 cannot hold
+    pub fn kdbx3_incompatibilities(&self) -> Vec<String> {
+        let mut features = Vec::new();
+
+        match self.config.kdf_config {
+            KdfConfig::Aes { .. } => {}
+            KdfConfig::Argon2 { .. } => features.push("Argon2d key derivation".to_string()),
+            KdfConfig::Argon2id { .. } => features.push("Argon2id key derivation".to_string()),
+            KdfConfig::Custom { uuid, .. } => features.push(format!("Key derivation {}", uuid)),
+        }
+
+        // the database UUID is generated again when upgrading
+        if self
+            .public_custom_data
+            .items
+            .keys()
+            .any(|key| key != DATABASE_UUID_KEY)
+        {
+            features.push("Public custom data".to_string());
+        }
+
+        features
+    }
+
+    fn upgrade_to_kdbx4(&mut self) {
+        if matches!(
+            self.config.inner_cipher_config,
+            InnerCipherConfig::Salsa20 | InnerCipherConfig::ArcFourVariant
+        ) {
+            self.config.inner_cipher_config = InnerCipherConfig::ChaCha20;
+        }
+        self.public_custom_data.ensure_database_uuid();
+
+        // entries of KDBX3 databases refer to the binaries of the metadata by their ID
+        let binaries = std::mem::take(&mut self.meta.binaries.binaries);
+        let mut indices = HashMap::new();
+        for (index, binary) in binaries.into_iter().enumerate() {
+            if let Some(id) = binary
+                .identifier
+                .as_deref()
+                .and_then(|id| id.parse::<usize>().ok())
+            {
+                indices.insert(id, index);
+            }
+            self.header_attachments.push(HeaderAttachment {
+                flags: 0,
+                content: binary.content,
+                packed: binary.packed,
+                external: binary.external,
+            });
+        }
+
+        remap_attachments(&mut self.root, &indices);
+    }
+
+    fn downgrade_to_kdbx3(&mut self) -> Result<(), ConversionError> {
+        let features = self.kdbx3_incompatibilities();
+        if !features.is_empty() {
+            return Err(ConversionError::IncompatibleFeatures {
+                version: DatabaseVersion::KDB3(1),
+                features,
+            });
+        }
+
+        if matches!(
+            self.config.inner_cipher_config,
+            InnerCipherConfig::ChaCha20 | InnerCipherConfig::ArcFourVariant
+        ) {
+            self.config.inner_cipher_config = InnerCipherConfig::Salsa20;
+        }
+        self.meta.binaries = header_attachments_to_binaries(std::mem::take(&mut self.header_attachments));
+        Ok(())
+    }
+}
+
+/// The binaries of the metadata holding the attachments of the inner header, with the index of
+/// each attachment as its ID so that entries keep referring to the same content
+pub(crate) fn header_attachments_to_binaries(attachments: Vec<HeaderAttachment>) -> BinaryAttachments {
+    let binaries = attachments
+        .into_iter()
+        .enumerate()
+        .map(|(index, attachment)| BinaryAttachment {
+            identifier: Some(index.to_string()),
+            compressed: attachment.packed,
+            content: attachment.content,
+            packed: attachment.packed,
+            external: attachment.external,
+        })
+        .collect();
+    BinaryAttachments { binaries }
+}
+
+/// Replace the attachment identifiers of all entries and their history
+pub(crate) fn remap_attachments(group: &mut Group, indices: &HashMap<usize, usize>) {
+    for node in &mut group.children {
+        match node {
+            Node::Group(g) => remap_attachments(g, indices),
+            Node::Entry(e) => {
+                remap_entry_attachments(e, indices);
+                if let Some(history) = &mut e.history {
+                    for old in &mut history.entries {
+                        remap_entry_attachments(old, indices);
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn remap_entry_attachments(entry: &mut Entry, indices: &HashMap<usize, usize>) {
+    for attachment in &mut entry.attachments {
+        if let Some(&index) = indices.get(&attachment.identifier) {
+            attachment.identifier = index;
+        }
+    }
+}
+
+#[cfg(test)]
+mod convert_tests {
+    use crate::{
+        config::{DatabaseConfig, InnerCipherConfig, KdfConfig},
+        db::{AttachmentRef, BinaryAttachment, Entry, HeaderAttachment, PublicValue},
+        format::DatabaseVersion,
+        Database,
+    };
+
+    use super::ConversionError;
+
+    #[test]
+    fn test_upgrade_to_kdbx4() {
+        let mut db = Database::new(DatabaseConfig::default());
+        db.config.version = DatabaseVersion::KDB3(1);
+        db.config.kdf_config = KdfConfig::Aes { rounds: 6000 };
+        db.config.inner_cipher_config = InnerCipherConfig::Salsa20;
+        db.public_custom_data.items.clear();
+        for (id, content) in [("7", b"seven"), ("3", b"three")] {
+            db.meta.binaries.binaries.push(BinaryAttachment {
+                identifier: Some(id.to_string()),
+                content: content.to_vec(),
+                ..Default::default()
+            });
+        }
+        let mut entry = Entry::new();
+        entry.attachments.push(AttachmentRef {
+            name: "three.txt".to_string(),
+            identifier: 3,
+        });
+        db.root.add_child(entry);
+
+        db.convert_to(DatabaseVersion::KDB4(1)).unwrap();
+        assert_eq!(db.config.version, DatabaseVersion::KDB4(1));
+        assert_eq!(db.config.kdf_config, KdfConfig::Aes { rounds: 6000 });
+        assert_eq!(db.config.inner_cipher_config, InnerCipherConfig::ChaCha20);
+        assert!(db.public_custom_data.database_uuid().is_some());
+        assert!(db.meta.binaries.binaries.is_empty());
+        assert_eq!(db.header_attachments.len(), 2);
+        assert_eq!(db.root.entries()[0].attachments[0].identifier, 1);
+        assert_eq!(db.header_attachments[1].content, b"three");
+    }
+
+    #[test]
+    fn test_downgrade_to_kdbx3() {
+        let mut db = Database::new(DatabaseConfig::default());
+        db.public_custom_data
+            .items
+            .insert("plugin".to_string(), PublicValue::Bool(true));
+
+        match db.convert_to(DatabaseVersion::KDB3(1)) {
+            Err(ConversionError::IncompatibleFeatures { features, .. }) => {
+                assert_eq!(features, vec!["Argon2d key derivation", "Public custom data"])
+            }
+            other => panic!("expected incompatible features, got {:?}", other),
+        }
+        assert!(matches!(db.config.version, DatabaseVersion::KDB4(_)));
+
+        db.config.kdf_config = KdfConfig::Aes { rounds: 6000 };
+        db.public_custom_data.items.remove("plugin");
+        db.header_attachments.push(HeaderAttachment {
+            flags: 1,
+            content: b"attached".to_vec(),
+            packed: false,
+            external: false,
+        });
+        db.convert_to(DatabaseVersion::KDB3(1)).unwrap();
+        assert_eq!(db.config.inner_cipher_config, InnerCipherConfig::Salsa20);
+        assert!(db.header_attachments.is_empty());
+        assert_eq!(db.meta.binaries.binaries[0].identifier.as_deref(), Some("0"));
+
+        assert!(matches!(
+            db.convert_to(DatabaseVersion::KDB(0)),
+            Err(ConversionError::UnsupportedVersion { .. })
+        ));
+    }
+
+    #[cfg(feature = "save_kdbx4")]
+    #[test]
+    fn test_save_converted() {
+        let key = || crate::DatabaseKey::new().with_password("demopass");
+        let mut db = Database::open(
+            &mut std::fs::File::open("tests/resources/test_db_with_password.kdbx").unwrap(),
+            key(),
+        )
+        .unwrap();
+        assert!(matches!(db.config.version, DatabaseVersion::KDB3(_)));
+
+        db.convert_to(DatabaseVersion::KDB4(0)).unwrap();
+        let mut data = Vec::new();
+        db.save(&mut data, key()).unwrap();
+        let upgraded = Database::open(&mut data.as_slice(), key()).unwrap();
+        assert!(matches!(upgraded.config.version, DatabaseVersion::KDB4(0)));
+        assert_eq!(upgraded.root, db.root);
+
+        db.convert_to(DatabaseVersion::KDB3(1)).unwrap();
+        let mut data = Vec::new();
+        db.save(&mut data, key()).unwrap();
+        let downgraded = Database::open(&mut data.as_slice(), key()).unwrap();
+        assert!(matches!(downgraded.config.version, DatabaseVersion::KDB3(1)));
+        assert_eq!(downgraded.root, db.root);
+    }
+}
@@ -0,0 +1,319 @@
+//! Adding custom icons and assigning them to entries and groups
+//!
+//! Custom icons are PNG images stored in `Meta::custom_icons` and referred to by their UUID from
+//! the entries and groups showing them. `Database::add_custom_icon` checks that the data can be
+//! decoded as a PNG image before adding it, as KeePass and KeePassXC show broken icons otherwise:
+//!
+//! ```
+//! use keepass::db::{Database, Entry};
+//!
+//! // a single transparent pixel
+//! let png = hex::decode(
+//!     "89504e470d0a1a0a0000000d49484452000000010000000108060000001f15c4890000000d49444154789c63f8\
+//!      cfc0f01f00050001ff89993d1d0000000049454e44ae426082",
+//! )
+//! .unwrap();
+//!
+//! let mut db = Database::new(Default::default());
+//! let icon = db.add_custom_icon(png)?;
+//! let mut entry = Entry::new();
+//! entry.set_custom_icon(Some(icon));
+//! db.root.add_child(entry);
+//!
+//! assert!(db.add_custom_icon(b"GIF89a".to_vec()).is_err());
+//! assert_eq!(db.prune_unused_icons(), 0);
+//! # Ok::<(), keepass::db::IconError>(())
+//! ```
+//!
+//! Adding an image that is already stored returns the UUID of the existing icon.
+//! `Database::prune_unused_icons` removes the icons that no entry, history entry or group refers
+//! to anymore.
+
+use std::{collections::HashSet, io::Read};
+
+use byteorder::{BigEndian, ByteOrder};
+use flate2::{read::ZlibDecoder, Crc};
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::db::{Database, Entry, Group, Icon, Node, Times};
+
+/// The largest size of the decompressed pixel data of an icon, enough for 2048x2048 pixels with
+/// 32 bits each
+const MAX_IMAGE_DATA_SIZE: usize = 16 * 1024 * 1024;
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+
+/// The pixel offsets and steps of the seven passes of an Adam7 interlaced image
+const ADAM7_PASSES: [(usize, usize, usize, usize); 7] = [
+    (0, 0, 8, 8),
+    (4, 0, 8, 8),
+    (0, 4, 4, 8),
+    (2, 0, 4, 4),
+    (0, 2, 2, 4),
+    (1, 0, 2, 2),
+    (0, 1, 1, 2),
+];
+
+/// Errors upon adding a custom icon
+#[derive(Debug, Error)]
+pub enum IconError {
+    #[error("The icon is not a PNG image")]
+    NotPng,
+
+    #[error("The PNG image is corrupt: {0}")]
+    CorruptPng(&'static str),
+
+    #[error("The image is too large for an icon")]
+    TooLarge,
+}
+
+impl Database {
+    /// Add a PNG image as a custom icon, or find the icon that already holds the same image.
+    /// Returns the UUID to refer to the icon with.
+    pub fn add_custom_icon(&mut self, data: Vec<u8>) -> Result<Uuid, IconError> {
+        validate_png(&data)?;
+
+        if let Some(icon) = self.meta.custom_icons.icons.iter().find(|icon| icon.data == data) {
+            return Ok(icon.uuid);
+        }
+
+        let uuid = Uuid::new_v4();
+        self.meta.custom_icons.icons.push(Icon {
+            uuid,
+            data,
+            name: None,
+            last_modification_time: Some(Times::now()),
+        });
+        Ok(uuid)
+    }
+
+    /// Remove the custom icons that no entry, history entry or group refers to. Returns the number
+    /// of removed icons.
+    pub fn prune_unused_icons(&mut self) -> usize {
+        let mut used = HashSet::new();
+        if let Some(uuid) = self.root.custom_icon_uuid {
+            used.insert(uuid);
+        }
+        collect_icons(&self.root, &mut used);
+
+        let count_before = self.meta.custom_icons.icons.len();
+        self.meta
+            .custom_icons
+            .icons
+            .retain(|icon| used.contains(&icon.uuid));
+        count_before - self.meta.custom_icons.icons.len()
+    }
+}
+
+impl Entry {
+    /// Show a custom icon of the database for the entry, or the standard icon with `None`
+    pub fn set_custom_icon(&mut self, icon: Option<Uuid>) {
+        self.custom_icon_uuid = icon;
+        self.times.set_last_modification(Times::now());
+    }
+}
+
+impl Group {
+    /// Show a custom icon of the database for the group, or the standard icon with `None`
+    pub fn set_custom_icon(&mut self, icon: Option<Uuid>) {
+        self.custom_icon_uuid = icon;
+        self.times.set_last_modification(Times::now());
+    }
+}
+
+fn collect_icons(group: &Group, used: &mut HashSet<Uuid>) {
+    for node in &group.children {
+        match node {
+            Node::Group(g) => {
+                used.extend(g.custom_icon_uuid);
+                collect_icons(g, used);
+            }
+            Node::Entry(e) => {
+                let history = e.history.iter().flat_map(|h| h.get_entries());
+                used.extend(
+                    std::iter::once(e)
+                        .chain(history)
+                        .filter_map(|e| e.custom_icon_uuid),
+                );
+            }
+        }
+    }
+}
+
+/// Check that `data` is a PNG image whose chunks are intact and whose pixel data decompresses to
+/// the size given by its dimensions
+fn validate_png(data: &[u8]) -> Result<(), IconError> {
+    let mut rest = data.strip_prefix(&PNG_SIGNATURE[..]).ok_or(IconError::NotPng)?;
+
+    let mut header = None;
+    let mut has_palette = false;
+    let mut image_data = Vec::new();
+    loop {
+        if rest.len() < 12 {
+            return Err(IconError::CorruptPng("truncated chunk"));
+        }
+        let length = BigEndian::read_u32(rest) as usize;
+        if rest.len() - 12 < length {
+            return Err(IconError::CorruptPng("truncated chunk"));
+        }
+        let (chunk_type, chunk_data) = rest[4..8 + length].split_at(4);
+
+        let mut crc = Crc::new();
+        crc.update(&rest[4..8 + length]);
+        if crc.sum() != BigEndian::read_u32(&rest[8 + length..]) {
+            return Err(IconError::CorruptPng("checksum mismatch"));
+        }
+        rest = &rest[12 + length..];
+
+        match (chunk_type, &header) {
+            (b"IHDR", None) if length == 13 => header = Some(chunk_data),
+            (_, None) => return Err(IconError::CorruptPng("missing header")),
+            (b"PLTE", _) => has_palette = true,
+            (b"IDAT", _) => image_data.extend_from_slice(chunk_data),
+            (b"IEND", _) => break,
+            _ => {}
+        }
+    }
+
+    let header = header.ok_or(IconError::CorruptPng("missing header"))?;
+    let width = BigEndian::read_u32(header) as usize;
+    let height = BigEndian::read_u32(&header[4..]) as usize;
+    let (bit_depth, color_type) = (header[8], header[9]);
+    if header[10] != 0 || header[11] != 0 {
+        return Err(IconError::CorruptPng("unknown compression or filter method"));
+    }
+    let interlaced = match header[12] {
+        0 => false,
+        1 => true,
+        _ => return Err(IconError::CorruptPng("unknown interlace method")),
+    };
+
+    let channels = match (color_type, bit_depth) {
+        (0, 1 | 2 | 4 | 8 | 16) => 1,
+        (2, 8 | 16) => 3,
+        (3, 1 | 2 | 4 | 8) if has_palette => 1,
+        (4, 8 | 16) => 2,
+        (6, 8 | 16) => 4,
+        _ => return Err(IconError::CorruptPng("unsupported color type")),
+    };
+    if width == 0 || height == 0 {
+        return Err(IconError::CorruptPng("empty image"));
+    }
+
+    // every row starts with a byte selecting its filter
+    let bits_per_pixel = channels * bit_depth as usize;
+    let image_size = |width: usize, height: usize| match width {
+        0 => 0,
+        _ => height.saturating_mul(1 + (width * bits_per_pixel).div_ceil(8)),
+    };
+    let expected = if interlaced {
+        ADAM7_PASSES
+            .iter()
+            .map(|&(x, y, dx, dy)| image_size((width + dx - 1 - x) / dx, (height + dy - 1 - y) / dy))
+            .fold(0, usize::saturating_add)
+    } else {
+        image_size(width, height)
+    };
+    if expected > MAX_IMAGE_DATA_SIZE {
+        return Err(IconError::TooLarge);
+    }
+
+    let mut decompressed = Vec::new();
+    ZlibDecoder::new(&image_data[..])
+        .take(expected as u64 + 1)
+        .read_to_end(&mut decompressed)
+        .map_err(|_| IconError::CorruptPng("invalid image data"))?;
+    if decompressed.len() != expected {
+        return Err(IconError::CorruptPng("image data does not match the size"));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod custom_icons_tests {
+    use byteorder::{BigEndian, WriteBytesExt};
+    use flate2::{write::ZlibEncoder, Compression, Crc};
+    use std::io::Write;
+
+    use super::{validate_png, IconError, PNG_SIGNATURE};
+    use crate::db::{Database, Entry, Group};
+
+    fn chunk(png: &mut Vec<u8>, chunk_type: &[u8], data: &[u8]) {
+        png.write_u32::<BigEndian>(data.len() as u32).unwrap();
+        png.extend_from_slice(chunk_type);
+        png.extend_from_slice(data);
+        let mut crc = Crc::new();
+        crc.update(chunk_type);
+        crc.update(data);
+        png.write_u32::<BigEndian>(crc.sum()).unwrap();
+    }
+
+    /// A grayscale PNG image with the given dimensions and the given pixel data
+    pub(crate) fn png(width: u32, height: u32, interlace: u8, pixels: &[u8]) -> Vec<u8> {
+        let mut header = Vec::new();
+        header.write_u32::<BigEndian>(width).unwrap();
+        header.write_u32::<BigEndian>(height).unwrap();
+        header.extend_from_slice(&[8, 0, 0, 0, interlace]);
+
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(pixels).unwrap();
+
+        let mut png = PNG_SIGNATURE.to_vec();
+        chunk(&mut png, b"IHDR", &header);
+        chunk(&mut png, b"IDAT", &encoder.finish().unwrap());
+        chunk(&mut png, b"IEND", &[]);
+        png
+    }
+
+    #[test]
+    fn test_validate_png() {
+        // each row of 3 pixels starts with its filter byte
+        assert!(validate_png(&png(3, 2, 0, &[0; 8])).is_ok());
+        assert!(matches!(
+            validate_png(&png(3, 2, 0, &[0; 7])),
+            Err(IconError::CorruptPng(_))
+        ));
+        // an interlaced 3x2 image has passes with rows of 1, 1, 1 and 3 pixels
+        assert!(validate_png(&png(3, 2, 1, &[0; 10])).is_ok());
+        assert!(matches!(
+            validate_png(&png(100_000, 100_000, 0, &[])),
+            Err(IconError::TooLarge)
+        ));
+
+        let mut corrupt = png(3, 2, 0, &[0; 8]);
+        corrupt[20] ^= 1;
+        assert!(matches!(validate_png(&corrupt), Err(IconError::CorruptPng(_))));
+        let truncated = png(3, 2, 0, &[0; 8]);
+        assert!(validate_png(&truncated[..truncated.len() - 12]).is_err());
+        assert!(matches!(validate_png(b"GIF89a"), Err(IconError::NotPng)));
+    }
+
+    #[test]
+    fn test_add_and_prune_icons() {
+        let mut db = Database::new(Default::default());
+        let entry_icon = db.add_custom_icon(png(1, 1, 0, &[0, 0])).unwrap();
+        let group_icon = db.add_custom_icon(png(1, 1, 0, &[0, 255])).unwrap();
+        let history_icon = db.add_custom_icon(png(2, 1, 0, &[0, 0, 0])).unwrap();
+        db.add_custom_icon(png(1, 2, 0, &[0, 0, 0, 0])).unwrap();
+        assert_eq!(db.add_custom_icon(png(1, 1, 0, &[0, 0])).unwrap(), entry_icon);
+        assert_eq!(db.meta.custom_icons.icons.len(), 4);
+
+        let mut entry = Entry::new();
+        entry.set_custom_icon(Some(history_icon));
+        entry.update_history();
+        entry.set_custom_icon(Some(entry_icon));
+        let mut group = Group::new("Icons");
+        group.set_custom_icon(Some(group_icon));
+        group.add_child(entry);
+        db.root.add_child(group);
+
+        assert_eq!(db.prune_unused_icons(), 1);
+        let mut kept: Vec<_> = db.meta.custom_icons.icons.iter().map(|i| i.uuid).collect();
+        kept.sort();
+        let mut expected = vec![entry_icon, group_icon, history_icon];
+        expected.sort();
+        assert_eq!(kept, expected);
+    }
+}
@@ -0,0 +1,359 @@
+//! Deterministic site passwords derived from a master secret
+//!
+//! Instead of storing a literal password, an entry can store the parameters needed to re-derive
+//! it from the password of a master entry: the site name, a random per-entry salt, a counter that
+//! can be incremented to rotate the password, and the `PasswordGenerator` settings describing which
+//! characters the site accepts. The password is generated like `PasswordGenerator::generate` does,
+//! with a stream of HKDF-SHA256 output in place of the random number generator.
+
+use hkdf::Hkdf;
+use secstr::SecStr;
+use sha2::Sha256;
+use thiserror::Error;
+use zeroize::Zeroizing;
+
+use crate::{
+    db::{CustomDataItem, Entry, Times, Value},
+    generator::{uniform_index, GeneratorError, PasswordGenerator},
+};
+
+/// Key of the entry custom data item holding the derivation parameters
+pub const DERIVED_CREDENTIAL_KEY: &str = "KPRS_DERIVED_CREDENTIAL";
+
+const INFO_PREFIX: &[u8] = b"keepass-rs derived credential v1";
+
+/// Errors while deriving a credential
+#[derive(Debug, Error)]
+pub enum DerivationError {
+    #[error(transparent)]
+    Generator(#[from] GeneratorError),
+
+    #[error("Site names must not contain line breaks")]
+    InvalidSite,
+
+    #[error("Master entry has no password")]
+    MissingMasterSecret,
+
+    #[error("Invalid derived credential metadata: {0}")]
+    Metadata(String),
+}
+
+/// Parameters for deriving the password of a single site
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serialization", derive(serde::Serialize))]
+pub struct DerivedCredential {
+    pub site: String,
+    pub salt: Vec<u8>,
+    pub counter: u32,
+    pub profile: PasswordGenerator,
+}
+
+impl DerivedCredential {
+    /// Create parameters for a new site with a random salt
+    pub fn new(site: &str, profile: PasswordGenerator) -> Result<Self, DerivationError> {
+        if site.contains(['\r', '\n']) {
+            return Err(DerivationError::InvalidSite);
+        }
+        profile.validate()?;
+
+        let mut salt = vec![0u8; 16];
+        getrandom::fill(&mut salt).map_err(GeneratorError::from)?;
+
+        Ok(DerivedCredential {
+            site: site.to_string(),
+            salt,
+            counter: 1,
+            profile,
+        })
+    }
+
+    /// Derive the password from the password of a master entry
+    pub fn derive(&self, master: &Entry) -> Result<SecStr, DerivationError> {
+        let secret = master
+            .get_protected("Password")
+            .ok_or(DerivationError::MissingMasterSecret)?;
+        self.derive_from_secret(&secret)
+    }
+
+    /// Derive the password from a raw master secret
+    pub fn derive_from_secret(&self, master_secret: &[u8]) -> Result<SecStr, DerivationError> {
+        let mut info = INFO_PREFIX.to_vec();
+        info.extend_from_slice(&(self.site.len() as u32).to_le_bytes());
+        info.extend_from_slice(self.site.as_bytes());
+        info.extend_from_slice(&self.counter.to_le_bytes());
+
+        let mut stream = HkdfStream {
+            hkdf: Hkdf::<Sha256>::new(Some(&self.salt), master_secret),
+            info,
+            block: 0,
+            buffer: Zeroizing::new(Vec::new()),
+        };
+        self.profile
+            .generate_with(&mut |bound| uniform_index(bound, || Ok::<_, DerivationError>(stream.next_u64())))
+    }
+
+    fn to_metadata(&self) -> String {
+        format!(
+            "counter={}\nlength={}\nclasses={}\nextra={}\nexclude={}\nsalt={}\nsite={}",
+            self.counter,
+            self.profile.length,
+            flags(&self.profile),
+            hex::encode(&self.profile.extra_characters),
+            hex::encode(&self.profile.exclude_characters),
+            hex::encode(&self.salt),
+            self.site
+        )
+    }
+
+    fn from_metadata(s: &str) -> Result<Self, DerivationError> {
+        let mut site = None;
+        let mut salt = None;
+        let mut counter = None;
+        let mut length = None;
+        let mut flags = None;
+        let mut extra = String::new();
+        let mut exclude = String::new();
+
+        for line in s.lines() {
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| DerivationError::Metadata(format!("Malformed line {:?}", line)))?;
+
+            match key {
+                "site" => site = Some(value.to_string()),
+                "salt" => {
+                    salt = Some(hex::decode(value).map_err(|e| DerivationError::Metadata(e.to_string()))?)
+                }
+                "counter" => {
+                    counter = Some(
+                        value
+                            .parse()
+                            .map_err(|_| DerivationError::Metadata(line.to_string()))?,
+                    )
+                }
+                "length" => {
+                    length = Some(
+                        value
+                            .parse()
+                            .map_err(|_| DerivationError::Metadata(line.to_string()))?,
+                    )
+                }
+                "classes" => flags = Some(value.to_string()),
+                "extra" => extra = hex_text(value)?,
+                "exclude" => exclude = hex_text(value)?,
+                _ => {}
+            }
+        }
+
+        let missing = |field: &str| DerivationError::Metadata(format!("Missing field {}", field));
+        let flags = flags.ok_or_else(|| missing("classes"))?;
+
+        Ok(DerivedCredential {
+            site: site.ok_or_else(|| missing("site"))?,
+            salt: salt.ok_or_else(|| missing("salt"))?,
+            counter: counter.ok_or_else(|| missing("counter"))?,
+            profile: PasswordGenerator {
+                length: length.ok_or_else(|| missing("length"))?,
+                lowercase: flags.contains('l'),
+                uppercase: flags.contains('u'),
+                digits: flags.contains('d'),
+                symbols: flags.contains('s'),
+                extra_characters: extra,
+                exclude_lookalikes: flags.contains('x'),
+                exclude_characters: exclude,
+                every_class: flags.contains('e'),
+            },
+        })
+    }
+}
+
+/// HKDF output used as a stream of random numbers, expanded in blocks of 32 bytes
+struct HkdfStream {
+    hkdf: Hkdf<Sha256>,
+    info: Vec<u8>,
+    block: u32,
+    buffer: Zeroizing<Vec<u8>>,
+}
+
+impl HkdfStream {
+    fn next_u64(&mut self) -> u64 {
+        if self.buffer.len() < 8 {
+            let mut info = self.info.clone();
+            info.extend_from_slice(&self.block.to_le_bytes());
+            self.block += 1;
+
+            let mut block = Zeroizing::new(vec![0u8; 32]);
+            self.hkdf
+                .expand(&info, &mut block)
+                .expect("32 bytes are a valid HKDF-SHA256 output length");
+            self.buffer = block;
+        }
+
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&self.buffer[..8]);
+        self.buffer.drain(..8);
+        u64::from_le_bytes(bytes)
+    }
+}
+
+/// The enabled settings of a generator as letters, in the `classes` line of the metadata
+fn flags(profile: &PasswordGenerator) -> String {
+    [
+        (profile.lowercase, 'l'),
+        (profile.uppercase, 'u'),
+        (profile.digits, 'd'),
+        (profile.symbols, 's'),
+        (profile.exclude_lookalikes, 'x'),
+        (profile.every_class, 'e'),
+    ]
+    .iter()
+    .filter(|(enabled, _)| *enabled)
+    .map(|(_, c)| *c)
+    .collect()
+}
+
+fn hex_text(value: &str) -> Result<String, DerivationError> {
+    let bytes = hex::decode(value).map_err(|e| DerivationError::Metadata(e.to_string()))?;
+    String::from_utf8(bytes).map_err(|e| DerivationError::Metadata(e.to_string()))
+}
+
+impl Entry {
+    /// Get the derivation parameters stored on this entry, if any
+    pub fn get_derived_credential(&self) -> Option<Result<DerivedCredential, DerivationError>> {
+        match self
+            .custom_data
+            .items
+            .get(DERIVED_CREDENTIAL_KEY)?
+            .value
+            .as_ref()?
+        {
+            Value::Unprotected(s) => Some(DerivedCredential::from_metadata(s)),
+            _ => Some(Err(DerivationError::Metadata(
+                "Unexpected value type".to_string(),
+            ))),
+        }
+    }
+
+    /// Store derivation parameters on this entry
+    pub fn set_derived_credential(&mut self, credential: &DerivedCredential) {
+        self.custom_data.items.insert(
+            DERIVED_CREDENTIAL_KEY.to_string(),
+            CustomDataItem {
+                value: Some(Value::Unprotected(credential.to_metadata())),
+                last_modification_time: Some(Times::now()),
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod derived_tests {
+    use crate::{
+        db::{Entry, Value},
+        generator::{GeneratorError, PasswordGenerator},
+    };
+
+    use super::{DerivationError, DerivedCredential};
+
+    fn master_entry() -> Entry {
+        let mut master = Entry::new();
+        master.fields.insert(
+            "Password".to_string(),
+            Value::Protected("correct horse battery staple".as_bytes().into()),
+        );
+        master
+    }
+
+    #[test]
+    fn test_derivation_is_deterministic() {
+        let master = master_entry();
+        let credential =
+            DerivedCredential::new("example.com", PasswordGenerator::new().with_symbols(true)).unwrap();
+
+        let password = credential.derive(&master).unwrap();
+        assert_eq!(password.unsecure().len(), 20);
+        let password = std::str::from_utf8(password.unsecure()).unwrap().to_string();
+        assert_eq!(
+            password.as_bytes(),
+            credential.derive(&master).unwrap().unsecure()
+        );
+
+        // every enabled character class is used
+        assert!(password.chars().any(|c| c.is_ascii_lowercase()));
+        assert!(password.chars().any(|c| c.is_ascii_uppercase()));
+        assert!(password.chars().any(|c| c.is_ascii_digit()));
+        assert!(password.chars().any(|c| !c.is_ascii_alphanumeric()));
+
+        // changing any parameter changes the password
+        let mut rotated = credential.clone();
+        rotated.counter += 1;
+        assert_ne!(password.as_bytes(), rotated.derive(&master).unwrap().unsecure());
+
+        let mut other_site = credential.clone();
+        other_site.site = "example.org".to_string();
+        assert_ne!(
+            password.as_bytes(),
+            other_site.derive(&master).unwrap().unsecure()
+        );
+
+        let other_salt =
+            DerivedCredential::new("example.com", PasswordGenerator::new().with_symbols(true)).unwrap();
+        assert_ne!(
+            password.as_bytes(),
+            other_salt.derive(&master).unwrap().unsecure()
+        );
+    }
+
+    #[test]
+    fn test_profile() {
+        let digits_only = PasswordGenerator::new()
+            .with_length(6)
+            .with_lowercase(false)
+            .with_uppercase(false);
+        let credential = DerivedCredential::new("bank", digits_only).unwrap();
+        let pin = credential.derive_from_secret(b"secret").unwrap();
+        assert_eq!(pin.unsecure().len(), 6);
+        assert!(pin.unsecure().iter().all(|c| c.is_ascii_digit()));
+
+        let nothing = PasswordGenerator::new()
+            .with_lowercase(false)
+            .with_uppercase(false)
+            .with_digits(false);
+        assert!(matches!(
+            DerivedCredential::new("site", nothing),
+            Err(DerivationError::Generator(GeneratorError::NoCharacters))
+        ));
+
+        let too_short = PasswordGenerator::new().with_symbols(true).with_length(3);
+        assert!(matches!(
+            DerivedCredential::new("site", too_short),
+            Err(DerivationError::Generator(GeneratorError::InvalidLength(3)))
+        ));
+    }
+
+    #[test]
+    fn test_stored_as_metadata() {
+        let master = master_entry();
+        let profile = PasswordGenerator::new()
+            .with_exclude_lookalikes(true)
+            .with_extra_characters("=\n")
+            .with_exclude_characters("xyz");
+        let credential = DerivedCredential::new("login=example.com", profile).unwrap();
+
+        let mut entry = Entry::new();
+        assert!(entry.get_derived_credential().is_none());
+
+        entry.set_derived_credential(&credential);
+        let restored = entry.get_derived_credential().unwrap().unwrap();
+        assert_eq!(restored, credential);
+        assert_eq!(
+            restored.derive(&master).unwrap(),
+            credential.derive(&master).unwrap()
+        );
+
+        assert!(matches!(
+            DerivedCredential::new("a\nb", PasswordGenerator::new().with_symbols(true)),
+            Err(DerivationError::InvalidSite)
+        ));
+    }
+}
@@ -0,0 +1,706 @@
+//! Differences between two versions of a database, rendered for people
+//!
+//! `Database::diff` lists the groups and entries that were added, removed, moved or edited between
+//! two versions of a database, e.g. before and after a merge, and `Entry::diff` the changes between
+//! two versions of an entry, e.g. the entry and a snapshot from its history.
+//! `DatabaseDiff::render` turns the differences into plain text, text colored for terminals or
+//! JSON, so that command line tools and sync logs can show what changed. Field values are masked
+//! unless `DiffStyle::with_values` is used, since logs are rarely as safe as the database. Shown
+//! user names and URLs can be redacted with `DiffStyle::with_redaction`.
+//!
+//! ```
+//! use keepass::{db::{DiffStyle, Entry, Value}, Database};
+//!
+//! let before = Database::new(Default::default());
+//! let mut after = before.clone();
+//! let mut entry = Entry::new();
+//! entry.fields.insert("Title".to_string(), Value::Unprotected("Mail".to_string()));
+//! after.root.add_child(entry);
+//!
+//! let text = before.diff(&after).render(&DiffStyle::plain());
+//! assert!(text.starts_with("+ entry \"Mail\""));
+//! ```
+
+use std::collections::{BTreeSet, HashMap};
+
+use uuid::Uuid;
+
+use crate::{
+    db::{Database, Entry, Group, Node, Value},
+    redact::RedactionProfile,
+    table::MASKED_VALUE,
+};
+
+/// Whether a node or field was added, removed or changed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+    Added,
+    Removed,
+    Modified,
+}
+
+impl ChangeKind {
+    fn symbol(&self) -> &'static str {
+        match self {
+            ChangeKind::Added => "+",
+            ChangeKind::Removed => "-",
+            ChangeKind::Modified => "~",
+        }
+    }
+
+    fn color(&self) -> &'static str {
+        match self {
+            ChangeKind::Added => "\x1b[32m",
+            ChangeKind::Removed => "\x1b[31m",
+            ChangeKind::Modified => "\x1b[33m",
+        }
+    }
+
+    #[cfg(feature = "serialization")]
+    fn name(&self) -> &'static str {
+        match self {
+            ChangeKind::Added => "added",
+            ChangeKind::Removed => "removed",
+            ChangeKind::Modified => "modified",
+        }
+    }
+}
+
+/// A change of a single field of an entry
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldChange {
+    /// The name of the string field. Changes found by `Entry::diff` outside of the string fields
+    /// are named `Tags`, `Times/<name>`, `Attachment/<name>` and `CustomData/<key>`.
+    pub field: String,
+    pub kind: ChangeKind,
+    pub old: Option<Value>,
+    pub new: Option<Value>,
+}
+
+/// An entry that was added, removed, moved or edited
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EntryChange {
+    pub uuid: Uuid,
+    pub title: Option<String>,
+    pub kind: ChangeKind,
+
+    /// The paths of the old and new parent group, if the entry was moved
+    pub moved: Option<(String, String)>,
+
+    /// The changed fields of an edited entry
+    pub fields: Vec<FieldChange>,
+}
+
+/// A group that was added, removed, moved or renamed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GroupChange {
+    pub uuid: Uuid,
+    pub name: String,
+    pub kind: ChangeKind,
+
+    /// The old name, if the group was renamed
+    pub renamed_from: Option<String>,
+
+    /// The paths of the old and new parent group, if the group was moved
+    pub moved: Option<(String, String)>,
+}
+
+/// The differences between two versions of a database, see `Database::diff`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DatabaseDiff {
+    pub groups: Vec<GroupChange>,
+    pub entries: Vec<EntryChange>,
+}
+
+/// The output format of `DatabaseDiff::render`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiffFormat {
+    Plain,
+
+    /// Plain text with ANSI colors for terminals
+    Colored,
+
+    #[cfg(feature = "serialization")]
+    Json,
+}
+
+/// How `DatabaseDiff::render` shows the differences
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiffStyle {
+    pub format: DiffFormat,
+
+    /// Show the old and new values of changed fields, including protected fields. Values are
+    /// masked by default.
+    pub show_values: bool,
+
+    /// How user names and URLs are redacted when values are shown
+    pub redaction: RedactionProfile,
+}
+
+impl DiffStyle {
+    pub fn plain() -> Self {
+        DiffStyle {
+            format: DiffFormat::Plain,
+            show_values: false,
+            redaction: RedactionProfile::none(),
+        }
+    }
+
+    pub fn colored() -> Self {
+        DiffStyle {
+            format: DiffFormat::Colored,
+            show_values: false,
+            redaction: RedactionProfile::none(),
+        }
+    }
+
+    #[cfg(feature = "serialization")]
+    pub fn json() -> Self {
+        DiffStyle {
+            format: DiffFormat::Json,
+            show_values: false,
+            redaction: RedactionProfile::none(),
+        }
+    }
+
+    /// Show the values of changed fields instead of masking them
+    pub fn with_values(mut self) -> Self {
+        self.show_values = true;
+        self
+    }
+
+    /// Redact user names and URLs in shown values
+    pub fn with_redaction(mut self, redaction: RedactionProfile) -> Self {
+        self.redaction = redaction;
+        self
+    }
+}
+
+impl FieldChange {
+    /// Render the change as a line of text like `Password: ******** -> ********`. Values are
+    /// masked unless `DiffStyle::with_values` is used.
+    pub fn render(&self, style: &DiffStyle) -> String {
+        match self.kind {
+            ChangeKind::Added => format!("{}: {}", self.field, show(&self.field, &self.new, style)),
+            ChangeKind::Removed => format!("{}: {}", self.field, show(&self.field, &self.old, style)),
+            ChangeKind::Modified => format!(
+                "{}: {} -> {}",
+                self.field,
+                show(&self.field, &self.old, style),
+                show(&self.field, &self.new, style)
+            ),
+        }
+    }
+}
+
+impl DatabaseDiff {
+    pub fn is_empty(&self) -> bool {
+        self.groups.is_empty() && self.entries.is_empty()
+    }
+
+    /// Render the differences as text or JSON
+    pub fn render(&self, style: &DiffStyle) -> String {
+        match style.format {
+            DiffFormat::Plain => self.render_text(style, false),
+            DiffFormat::Colored => self.render_text(style, true),
+            #[cfg(feature = "serialization")]
+            DiffFormat::Json => self.render_json(style),
+        }
+    }
+
+    fn render_text(&self, style: &DiffStyle, colored: bool) -> String {
+        let line = |kind: ChangeKind, indent: &str, text: String| {
+            if colored {
+                format!("{}{}{} {}\x1b[0m\n", indent, kind.color(), kind.symbol(), text)
+            } else {
+                format!("{}{} {}\n", indent, kind.symbol(), text)
+            }
+        };
+
+        let mut out = String::new();
+        for group in &self.groups {
+            out += &line(
+                group.kind,
+                "",
+                format!("group \"{}\" ({})", group.name, group.uuid),
+            );
+            if let Some(old) = &group.renamed_from {
+                out += &format!("    renamed from \"{}\"\n", old);
+            }
+            if let Some((from, to)) = &group.moved {
+                out += &format!("    moved from {} to {}\n", from, to);
+            }
+        }
+
+        for entry in &self.entries {
+            let title = entry.title.as_deref().unwrap_or("");
+            out += &line(entry.kind, "", format!("entry \"{}\" ({})", title, entry.uuid));
+            if let Some((from, to)) = &entry.moved {
+                out += &format!("    moved from {} to {}\n", from, to);
+            }
+            for field in &entry.fields {
+                out += &line(field.kind, "    ", field.render(style));
+            }
+        }
+        out
+    }
+
+    #[cfg(feature = "serialization")]
+    fn render_json(&self, style: &DiffStyle) -> String {
+        use serde_json::json;
+
+        let moved = |moved: &Option<(String, String)>| {
+            moved.as_ref().map(|(from, to)| json!({ "from": from, "to": to }))
+        };
+
+        let groups: Vec<_> = self
+            .groups
+            .iter()
+            .map(|g| {
+                json!({
+                    "uuid": g.uuid.to_string(),
+                    "name": g.name,
+                    "change": g.kind.name(),
+                    "renamed_from": g.renamed_from,
+                    "moved": moved(&g.moved),
+                })
+            })
+            .collect();
+
+        let entries: Vec<_> = self
+            .entries
+            .iter()
+            .map(|e| {
+                let fields: Vec<_> = e
+                    .fields
+                    .iter()
+                    .map(|f| {
+                        json!({
+                            "field": f.field,
+                            "change": f.kind.name(),
+                            "old": f.old.as_ref().map(|_| show(&f.field, &f.old, style)),
+                            "new": f.new.as_ref().map(|_| show(&f.field, &f.new, style)),
+                        })
+                    })
+                    .collect();
+                json!({
+                    "uuid": e.uuid.to_string(),
+                    "title": e.title,
+                    "change": e.kind.name(),
+                    "moved": moved(&e.moved),
+                    "fields": fields,
+                })
+            })
+            .collect();
+
+        json!({ "groups": groups, "entries": entries }).to_string()
+    }
+}
+
+fn show(field: &str, value: &Option<Value>, style: &DiffStyle) -> String {
+    match value {
+        None => String::new(),
+        Some(_) if !style.show_values => MASKED_VALUE.to_string(),
+        Some(Value::Unprotected(value)) => style.redaction.redact_field(field, value).into_owned(),
+        Some(Value::Protected(value)) => String::from_utf8_lossy(&value.decrypt()).into_owned(),
+        Some(Value::Bytes(bytes)) => format!("<{} bytes>", bytes.len()),
+    }
+}
+
+/// Every node of a database with the path of its parent group
+struct Index<'a> {
+    groups: HashMap<Uuid, (&'a Group, String)>,
+    entries: HashMap<Uuid, (&'a Entry, String)>,
+}
+
+impl<'a> Index<'a> {
+    fn new(db: &'a Database) -> Self {
+        let mut index = Index {
+            groups: HashMap::new(),
+            entries: HashMap::new(),
+        };
+        index.add(&db.root, String::new());
+        index
+    }
+
+    fn add(&mut self, group: &'a Group, parent_path: String) {
+        let path = if parent_path.is_empty() {
+            group.name.clone()
+        } else {
+            format!("{}/{}", parent_path, group.name)
+        };
+        self.groups.insert(group.uuid, (group, parent_path));
+
+        for node in &group.children {
+            match node {
+                Node::Group(g) => self.add(g, path.clone()),
+                Node::Entry(e) => {
+                    self.entries.insert(e.uuid, (e, path.clone()));
+                }
+            }
+        }
+    }
+}
+
+fn moved(old: &str, new: &str) -> Option<(String, String)> {
+    (old != new).then(|| (old.to_string(), new.to_string()))
+}
+
+fn field_changes(old: &HashMap<String, Value>, new: &HashMap<String, Value>) -> Vec<FieldChange> {
+    let names: BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+    names
+        .into_iter()
+        .filter_map(|name| {
+            let (old, new) = (old.get(name), new.get(name));
+            let kind = match (old, new) {
+                (None, Some(_)) => ChangeKind::Added,
+                (Some(_), None) => ChangeKind::Removed,
+                (Some(a), Some(b)) if a != b => ChangeKind::Modified,
+                _ => return None,
+            };
+            Some(FieldChange {
+                field: name.clone(),
+                kind,
+                old: old.cloned(),
+                new: new.cloned(),
+            })
+        })
+        .collect()
+}
+
+/// Everything `Entry::diff` compares, as named values
+fn comparable_values(entry: &Entry) -> HashMap<String, Value> {
+    let mut values = entry.fields.clone();
+
+    if !entry.tags.is_empty() {
+        values.insert("Tags".to_string(), Value::Unprotected(entry.tags.join(";")));
+    }
+
+    for (name, time) in &entry.times.times {
+        values.insert(
+            format!("Times/{}", name),
+            Value::Unprotected(time.format("%Y-%m-%d %H:%M:%S").to_string()),
+        );
+    }
+    values.insert(
+        "Times/Expires".to_string(),
+        Value::Unprotected(entry.times.expires.to_string()),
+    );
+    values.insert(
+        "Times/UsageCount".to_string(),
+        Value::Unprotected(entry.times.usage_count.to_string()),
+    );
+
+    for attachment in &entry.attachments {
+        values.insert(
+            format!("Attachment/{}", attachment.name),
+            Value::Unprotected(format!("#{}", attachment.identifier)),
+        );
+    }
+
+    for (key, item) in &entry.custom_data.items {
+        values.insert(
+            format!("CustomData/{}", key),
+            item.value
+                .clone()
+                .unwrap_or_else(|| Value::Unprotected(String::new())),
+        );
+    }
+
+    values
+}
+
+/// Times that change whenever an entry is used or saved, which would drown the edits in a
+/// `DatabaseDiff`
+fn is_bookkeeping(field: &str) -> bool {
+    match field.strip_prefix("Times/") {
+        Some(time) => !matches!(time, "Expires" | "ExpiryTime"),
+        None => false,
+    }
+}
+
+impl Entry {
+    /// The changes from this entry to `other`, e.g. between an entry and a snapshot from its
+    /// history, covering the string fields, tags, times, attachments and custom data. Protected
+    /// values stay protected in the changes, `FieldChange::render` masks them unless asked to show
+    /// them. Attachments are compared by their identifier, so both entries should be from the same
+    /// database.
+    pub fn diff(&self, other: &Entry) -> Vec<FieldChange> {
+        field_changes(&comparable_values(self), &comparable_values(other))
+    }
+}
+
+impl Database {
+    /// The groups and entries that were added, removed, moved or edited in `other` compared to
+    /// this database, matched by their UUIDs. Edited entries list their changes as in
+    /// `Entry::diff`, leaving out the access and modification times and usage counts. Changes are
+    /// listed by path and title, so that the order is stable.
+    pub fn diff(&self, other: &Database) -> DatabaseDiff {
+        let (old, new) = (Index::new(self), Index::new(other));
+        let mut diff = DatabaseDiff::default();
+
+        for (uuid, (group, path)) in &old.groups {
+            match new.groups.get(uuid) {
+                None => diff.groups.push(GroupChange {
+                    uuid: *uuid,
+                    name: group.name.clone(),
+                    kind: ChangeKind::Removed,
+                    renamed_from: None,
+                    moved: None,
+                }),
+                Some((new_group, new_path)) => {
+                    let renamed_from = (group.name != new_group.name).then(|| group.name.clone());
+                    let moved = moved(path, new_path);
+                    if renamed_from.is_some() || moved.is_some() {
+                        diff.groups.push(GroupChange {
+                            uuid: *uuid,
+                            name: new_group.name.clone(),
+                            kind: ChangeKind::Modified,
+                            renamed_from,
+                            moved,
+                        });
+                    }
+                }
+            }
+        }
+        for (uuid, (group, _)) in &new.groups {
+            if !old.groups.contains_key(uuid) {
+                diff.groups.push(GroupChange {
+                    uuid: *uuid,
+                    name: group.name.clone(),
+                    kind: ChangeKind::Added,
+                    renamed_from: None,
+                    moved: None,
+                });
+            }
+        }
+
+        let title = |e: &Entry| e.get_title().map(str::to_string);
+        for (uuid, (entry, path)) in &old.entries {
+            match new.entries.get(uuid) {
+                None => diff.entries.push(EntryChange {
+                    uuid: *uuid,
+                    title: title(entry),
+                    kind: ChangeKind::Removed,
+                    moved: None,
+                    fields: Vec::new(),
+                }),
+                Some((new_entry, new_path)) => {
+                    let fields: Vec<_> = entry
+                        .diff(new_entry)
+                        .into_iter()
+                        .filter(|change| !is_bookkeeping(&change.field))
+                        .collect();
+                    let moved = moved(path, new_path);
+                    if !fields.is_empty() || moved.is_some() {
+                        diff.entries.push(EntryChange {
+                            uuid: *uuid,
+                            title: title(new_entry),
+                            kind: ChangeKind::Modified,
+                            moved,
+                            fields,
+                        });
+                    }
+                }
+            }
+        }
+        for (uuid, (entry, _)) in &new.entries {
+            if !old.entries.contains_key(uuid) {
+                diff.entries.push(EntryChange {
+                    uuid: *uuid,
+                    title: title(entry),
+                    kind: ChangeKind::Added,
+                    moved: None,
+                    fields: field_changes(&HashMap::new(), &entry.fields),
+                });
+            }
+        }
+
+        diff.groups
+            .sort_by(|a, b| (&a.name, a.uuid).cmp(&(&b.name, b.uuid)));
+        diff.entries
+            .sort_by(|a, b| (&a.title, a.uuid).cmp(&(&b.title, b.uuid)));
+        diff
+    }
+}
+
+#[cfg(test)]
+mod diff_tests {
+    use crate::{
+        db::{AttachmentRef, CustomDataItem, Database, Entry, Group, Times, Value},
+        redact::RedactionProfile,
+    };
+
+    use super::{ChangeKind, DiffStyle};
+
+    fn entry(title: &str, password: &str) -> Entry {
+        let mut entry = Entry::new();
+        entry
+            .fields
+            .insert("Title".to_string(), Value::Unprotected(title.to_string()));
+        entry.fields.insert(
+            "Password".to_string(),
+            Value::Protected(password.as_bytes().into()),
+        );
+        entry
+    }
+
+    #[test]
+    fn test_diff_and_render() {
+        let mut before = Database::new(Default::default());
+        before.root.name = "Root".to_string();
+        let group = Group::new("Work");
+        let group_uuid = group.uuid;
+        before.root.add_child(group);
+        let mail = entry("Mail", "old secret");
+        let mail_uuid = mail.uuid;
+        before.root.add_child(mail);
+        before.root.add_child(entry("Bank", "1234"));
+
+        let mut after = before.clone();
+        after
+            .root
+            .children
+            .retain(|node| !matches!(node, crate::db::Node::Entry(e) if e.get_title() == Some("Bank")));
+        let mut mail = after.root.entries()[0].clone();
+        after.root.children.retain(|node| node.uuid() != mail_uuid);
+        mail.fields.insert(
+            "Password".to_string(),
+            Value::Protected("new secret".as_bytes().into()),
+        );
+        mail.fields.insert(
+            "URL".to_string(),
+            Value::Unprotected("https://mail.example.com/inbox".to_string()),
+        );
+        let work = after
+            .root
+            .groups_mut()
+            .into_iter()
+            .find(|g| g.uuid == group_uuid)
+            .unwrap();
+        work.name = "Office".to_string();
+        work.add_child(mail);
+
+        let diff = before.diff(&after);
+        assert_eq!(diff.groups.len(), 1);
+        assert_eq!(diff.groups[0].renamed_from.as_deref(), Some("Work"));
+        assert_eq!(diff.entries.len(), 2);
+        assert_eq!(diff.entries[0].kind, ChangeKind::Removed);
+        assert_eq!(diff.entries[1].kind, ChangeKind::Modified);
+        assert!(before.diff(&before).is_empty());
+
+        let plain = diff.render(&DiffStyle::plain());
+        assert!(plain.contains("~ group \"Office\""));
+        assert!(plain.contains("- entry \"Bank\""));
+        assert!(plain.contains("    moved from Root to Root/Office\n"));
+        assert!(plain.contains("    ~ Password: ******** -> ********\n"));
+        assert!(!plain.contains("secret"));
+
+        let revealed = diff.render(&DiffStyle::plain().with_values());
+        assert!(revealed.contains("    ~ Password: old secret -> new secret\n"));
+        assert!(revealed.contains("    + URL: https://mail.example.com/inbox\n"));
+
+        let redacted = diff.render(
+            &DiffStyle::plain()
+                .with_values()
+                .with_redaction(RedactionProfile::screenshot_safe()),
+        );
+        assert!(redacted.contains("    + URL: https://***.***.com\n"));
+        assert!(redacted.contains("    ~ Password: old secret -> new secret\n"));
+
+        let colored = diff.render(&DiffStyle::colored());
+        assert!(colored.contains("\x1b[31m- entry \"Bank\""));
+    }
+
+    #[test]
+    fn test_diff_ignores_bookkeeping() {
+        let mut before = Database::new(Default::default());
+        before.root.add_child(entry("Mail", "secret"));
+
+        let mut after = before.clone();
+        let mail = &mut after.root.entries_mut()[0];
+        mail.times.usage_count += 1;
+        mail.times.set_last_access(Times::now());
+        assert!(before.diff(&after).is_empty());
+
+        let mail = &mut after.root.entries_mut()[0];
+        mail.tags.push("work".to_string());
+        mail.times.expires = true;
+        let diff = before.diff(&after);
+        assert_eq!(diff.entries.len(), 1);
+        let fields: Vec<_> = diff.entries[0].fields.iter().map(|f| f.field.as_str()).collect();
+        assert_eq!(fields, vec!["Tags", "Times/Expires"]);
+    }
+
+    #[cfg(feature = "serialization")]
+    #[test]
+    fn test_render_json() {
+        let before = Database::new(Default::default());
+        let mut after = before.clone();
+        after.root.add_child(entry("Mail", "secret"));
+
+        let json: serde_json::Value =
+            serde_json::from_str(&before.diff(&after).render(&DiffStyle::json())).unwrap();
+        assert_eq!(json["entries"][0]["change"], "added");
+        assert_eq!(json["entries"][0]["title"], "Mail");
+        assert_eq!(json["entries"][0]["fields"][0]["field"], "Password");
+        assert_eq!(json["entries"][0]["fields"][0]["new"], "********");
+        assert_eq!(json["entries"][0]["fields"][0]["old"], serde_json::Value::Null);
+    }
+
+    #[test]
+    fn test_entry_diff() {
+        let mut entry = entry("Mail", "old secret");
+        entry.tags.push("work".to_string());
+        entry.custom_data.items.insert(
+            "plugin".to_string(),
+            CustomDataItem {
+                value: Some(Value::Unprotected("1".to_string())),
+                last_modification_time: None,
+            },
+        );
+        let snapshot = entry.clone();
+
+        entry.fields.insert(
+            "Password".to_string(),
+            Value::Protected("new secret".as_bytes().into()),
+        );
+        entry.tags.push("mail".to_string());
+        entry.custom_data.items.clear();
+        entry.attachments.push(AttachmentRef {
+            name: "key.pem".to_string(),
+            identifier: 0,
+        });
+        entry.times.usage_count += 1;
+
+        assert!(entry.diff(&entry).is_empty());
+
+        let changes = snapshot.diff(&entry);
+        let fields: Vec<_> = changes.iter().map(|c| (c.field.as_str(), c.kind)).collect();
+        assert_eq!(
+            fields,
+            vec![
+                ("Attachment/key.pem", ChangeKind::Added),
+                ("CustomData/plugin", ChangeKind::Removed),
+                ("Password", ChangeKind::Modified),
+                ("Tags", ChangeKind::Modified),
+                ("Times/UsageCount", ChangeKind::Modified),
+            ]
+        );
+
+        let password = &changes[2];
+        assert!(matches!(password.new, Some(Value::Protected(_))));
+        assert_eq!(
+            password.render(&DiffStyle::plain()),
+            "Password: ******** -> ********"
+        );
+        assert_eq!(
+            password.render(&DiffStyle::plain().with_values()),
+            "Password: old secret -> new secret"
+        );
+        assert_eq!(
+            changes[3].render(&DiffStyle::plain().with_values()),
+            "Tags: work -> work;mail"
+        );
+    }
+}
@@ -1,21 +1,26 @@
 use std::collections::HashMap;
 
-use secstr::SecStr;
 use uuid::Uuid;
 
 #[cfg(feature = "_merge")]
-use crate::db::merge::{MergeError, MergeLog};
+use crate::db::merge::{MergeError, MergeLog, MergeStrategy};
 #[cfg(all(test, feature = "_merge"))]
 use std::{thread, time};
 
-use crate::db::{Color, CustomData, Times};
+use crate::db::{
+    Color, CustomData, CustomDataItem, Meta, ProtectedGuard, ProtectedValue, Times, UnknownElement,
+};
 
 #[cfg(feature = "totp")]
 use crate::db::otp::{TOTPError, TOTP};
 
+/// Key of the entry custom data item holding the name of the automation (e.g. a provisioning
+/// tool) that manages the entry
+pub const MANAGED_BY_KEY: &str = "KPRS_MANAGED_BY";
+
 /// A database entry containing several key-value fields.
 #[derive(Debug, Default, Eq, PartialEq, Clone)]
-#[cfg_attr(feature = "serialization", derive(serde::Serialize))]
+#[cfg_attr(feature = "serialization", derive(serde::Serialize, serde::Deserialize))]
 pub struct Entry {
     pub uuid: Uuid,
     pub fields: HashMap<String, Value>,
@@ -35,8 +40,33 @@ pub struct Entry {
     pub override_url: Option<String>,
     pub quality_check: Option<bool>,
 
+    /// The group the entry was in before it was last moved, e.g. into the recycle bin
+    pub previous_parent_group: Option<Uuid>,
+
+    /// References to the binary attachments of this entry
+    pub attachments: Vec<AttachmentRef>,
+
     pub history: Option<History>,
+
+    /// XML elements of the entry unknown to this library, written back when saving the database
+    pub unknown_elements: Vec<UnknownElement>,
+}
+
+/// A named reference from an entry to a binary attachment.
+///
+/// For KDBX4 databases, the identifier is the index into `Database::header_attachments`. For
+/// KDBX3 databases, it is the identifier of an attachment in `Meta::binaries`. History entries
+/// keep their own references, so every version of an entry knows the attachments it had.
+#[derive(Debug, Default, Eq, PartialEq, Clone)]
+#[cfg_attr(feature = "serialization", derive(serde::Serialize, serde::Deserialize))]
+pub struct AttachmentRef {
+    /// The file name of the attachment
+    pub name: String,
+
+    /// The identifier of the binary data of the attachment
+    pub identifier: usize,
 }
+
 impl Entry {
     pub fn new() -> Entry {
         Entry {
@@ -46,6 +76,63 @@ impl Entry {
         }
     }
 
+    /// Set the 'Title' field. Together with the other `with_` methods, this builds entries in a
+    /// single expression:
+    ///
+    /// ```
+    /// use keepass::db::Entry;
+    ///
+    /// let entry = Entry::new()
+    ///     .with_title("Mail")
+    ///     .with_username("jdoe")
+    ///     .with_password("secret")
+    ///     .with_url("https://mail.example.com")
+    ///     .with_tag("personal");
+    /// assert_eq!(entry.get_protected("Password").unwrap().as_str(), Some("secret"));
+    /// ```
+    pub fn with_title(self, title: &str) -> Self {
+        self.with_field("Title", Value::Unprotected(title.to_string()))
+    }
+
+    /// Set the 'UserName' field
+    pub fn with_username(self, username: &str) -> Self {
+        self.with_field("UserName", Value::Unprotected(username.to_string()))
+    }
+
+    /// Set the 'Password' field, which is protected in memory
+    pub fn with_password(self, password: &str) -> Self {
+        self.with_field("Password", Value::Protected(password.into()))
+    }
+
+    /// Set the 'URL' field
+    pub fn with_url(self, url: &str) -> Self {
+        self.with_field("URL", Value::Unprotected(url.to_string()))
+    }
+
+    /// Set the 'Notes' field
+    pub fn with_notes(self, notes: &str) -> Self {
+        self.with_field("Notes", Value::Unprotected(notes.to_string()))
+    }
+
+    /// Set a field, replacing any previous value
+    pub fn with_field(mut self, name: &str, value: Value) -> Self {
+        self.fields.insert(name.to_string(), value);
+        self
+    }
+
+    /// Add a tag, unless the entry has it already, see `Entry::add_tag`
+    pub fn with_tag(mut self, tag: &str) -> Self {
+        self.add_tag(tag);
+        self
+    }
+
+    /// Let the entry expire at `time`
+    pub fn with_expiry(mut self, time: chrono::NaiveDateTime) -> Self {
+        self.times.expires = true;
+        self.times.set_expiry(time);
+        self
+    }
+
     #[cfg(feature = "_merge")]
     pub(crate) fn merge(&self, other: &Entry) -> Result<(Option<Entry>, MergeLog), MergeError> {
         let mut log = MergeLog::default();
@@ -99,6 +186,49 @@ impl Entry {
         return Ok((Some(merged_entry), entry_merge_log));
     }
 
+    /// Merge `other` into this entry according to one of the strategies that do not synchronize.
+    /// Returns `None` if this entry is kept as-is.
+    #[cfg(feature = "_merge")]
+    pub(crate) fn overwrite_with(
+        &self,
+        other: &Entry,
+        strategy: &MergeStrategy,
+    ) -> Result<(Option<Entry>, MergeLog), MergeError> {
+        if !self.has_diverged_from(other) {
+            return Ok((None, MergeLog::default()));
+        }
+
+        let overwrite = match strategy {
+            MergeStrategy::Synchronize | MergeStrategy::KeepExisting => false,
+            MergeStrategy::OverwriteIfNewer => {
+                other.times.get_last_modification() > self.times.get_last_modification()
+            }
+            MergeStrategy::OverwriteExisting => true,
+        };
+        if !overwrite {
+            return Ok((None, MergeLog::default()));
+        }
+
+        // The replaced version is kept in the history, even if it was never committed to it.
+        let mut destination = self.clone();
+        if self.has_uncommitted_changes() {
+            destination
+                .history
+                .get_or_insert_with(History::default)
+                .add_entry(self.clone());
+        }
+        let (mut merged_entry, log) = other.merge_history(&destination)?;
+
+        // The location changed timestamp is handled separately when merging two databases.
+        if let Some(location_changed_timestamp) = self.times.get_location_changed() {
+            merged_entry
+                .times
+                .set_location_changed(*location_changed_timestamp);
+        }
+
+        Ok((Some(merged_entry), log))
+    }
+
     #[cfg(feature = "_merge")]
     pub(crate) fn merge_history(&self, other: &Entry) -> Result<(Entry, MergeLog), MergeError> {
         let mut log = MergeLog::default();
@@ -173,13 +303,42 @@ impl Entry {
 }
 
 impl<'a> Entry {
-    /// Get a field by name, taking care of unprotecting Protected values automatically
+    /// Get a field by name, taking care of unprotecting Protected values automatically.
+    ///
+    /// A Protected value stays decrypted in memory until the entry is dropped, use
+    /// `get_protected` to have it wiped as soon as it is no longer needed. Reading a Protected
+    /// value is reported to the audit hook, if one is installed.
     pub fn get(&'a self, key: &str) -> Option<&'a str> {
         match self.fields.get(key) {
-            Some(&Value::Bytes(_)) => None,
-            Some(&Value::Protected(ref pv)) => std::str::from_utf8(pv.unsecure()).ok(),
+            Some(&Value::Protected(ref pv)) => {
+                crate::db::audit::record_access(self.uuid, key);
+                std::str::from_utf8(pv.unsecure()).ok()
+            }
+            _ => self.get_unprotected(key),
+        }
+    }
+
+    /// Get a field by name like `get`, but without decrypting Protected values, which are
+    /// returned as `None`
+    pub(crate) fn get_unprotected(&'a self, key: &str) -> Option<&'a str> {
+        match self.fields.get(key) {
             Some(&Value::Unprotected(ref uv)) => Some(&uv),
-            None => None,
+            _ => None,
+        }
+    }
+
+    /// Get a field by name, decrypting Protected values only for as long as the returned guard
+    /// lives. Unprotected values are copied into a guard as well.
+    ///
+    /// Reading a Protected value is reported to the audit hook, if one is installed.
+    pub fn get_protected(&self, key: &str) -> Option<ProtectedGuard> {
+        match self.fields.get(key)? {
+            Value::Bytes(_) => None,
+            Value::Protected(pv) => {
+                crate::db::audit::record_access(self.uuid, key);
+                Some(pv.decrypt())
+            }
+            Value::Unprotected(uv) => Some(ProtectedGuard(uv.as_str().into())),
         }
     }
 
@@ -191,10 +350,32 @@ impl<'a> Entry {
         }
     }
 
+    /// Get the attachments of this entry, or of this version of the entry if it is part of the
+    /// history of another entry
+    pub fn attachments(&'a self) -> &'a [AttachmentRef] {
+        &self.attachments
+    }
+
     pub fn get_uuid(&'a self) -> &'a Uuid {
         &self.uuid
     }
 
+    /// Get the text of an item of the custom data of this entry, see `CustomData::get`
+    pub fn get_custom_data(&self, key: &str) -> Option<&str> {
+        self.custom_data.get(key)
+    }
+
+    /// Set an item of the custom data of this entry, stamping its last modification time, see
+    /// `CustomData::set`
+    pub fn set_custom_data(&mut self, key: &str, value: &str, protected: bool) -> Option<CustomDataItem> {
+        self.custom_data.set(key, value, protected)
+    }
+
+    /// Remove an item of the custom data of this entry, returning it
+    pub fn remove_custom_data(&mut self, key: &str) -> Option<CustomDataItem> {
+        self.custom_data.remove(key)
+    }
+
     /// Get a timestamp field by name
     ///
     /// Returning the chrono::NaiveDateTime which does not include timezone
@@ -211,10 +392,41 @@ impl<'a> Entry {
         self.times.get_expiry()
     }
 
-    /// Convenience method for getting a TOTP from this entry
+    /// Convenience method for getting a TOTP from this entry. Besides `otpauth://` URIs in the
+    /// 'otp' field, the formats of the KeeOtp and TrayTOTP plugins are read, see
+    /// `TOTP::from_keeotp` and `TOTP::from_traytotp`. Their TOTPs are issued by the title, and
+    /// labeled with the title and the username.
     #[cfg(feature = "totp")]
     pub fn get_otp(&'a self) -> Result<TOTP, TOTPError> {
-        self.get_raw_otp_value().ok_or(TOTPError::NoRecord)?.parse()
+        let otp = self.get_protected("otp");
+        let seed = self.get_protected("TOTP Seed");
+        fn text(guard: &Option<ProtectedGuard>) -> Option<&str> {
+            guard.as_ref().and_then(|g| g.as_str())
+        }
+
+        let mut totp = match (text(&otp), text(&seed)) {
+            (Some(uri), _) if uri.contains("://") => return uri.parse(),
+            (Some(value), _) => TOTP::from_keeotp(value)?,
+            (None, Some(seed)) => {
+                let settings = self.get_protected("TOTP Settings");
+                TOTP::from_traytotp(seed, text(&settings))?
+            }
+            (None, None) => return Err(TOTPError::NoRecord),
+        };
+        let title = self.get_title().unwrap_or_default();
+        totp.label = match self.get_username() {
+            Some(username) if !username.is_empty() => format!("{}:{}", title, username),
+            _ => title.to_string(),
+        };
+        totp.issuer = Some(title.to_string()).filter(|t| !t.is_empty());
+        Ok(totp)
+    }
+
+    /// The TOTP of this entry as an `otpauth://totp/` URI, whichever format it is stored in, e.g.
+    /// to show it as a QR code for an authenticator app. See `Entry::get_otp`.
+    #[cfg(feature = "totp")]
+    pub fn otp_uri(&'a self) -> Result<String, TOTPError> {
+        Ok(self.get_otp()?.to_uri())
     }
 
     /// Convenience method for getting the raw value of the 'otp' field
@@ -222,14 +434,16 @@ impl<'a> Entry {
         self.get("otp")
     }
 
-    /// Convenience method for getting the value of the 'Title' field
+    /// Convenience method for getting the value of the 'Title' field. A Protected value is not
+    /// decrypted and returns `None`, read it with `get_protected` or `get` instead.
     pub fn get_title(&'a self) -> Option<&'a str> {
-        self.get("Title")
+        self.get_unprotected("Title")
     }
 
-    /// Convenience method for getting the value of the 'UserName' field
+    /// Convenience method for getting the value of the 'UserName' field. A Protected value is not
+    /// decrypted and returns `None`, read it with `get_protected` or `get` instead.
     pub fn get_username(&'a self) -> Option<&'a str> {
-        self.get("UserName")
+        self.get_unprotected("UserName")
     }
 
     /// Convenience method for getting the value of the 'Password' field
@@ -237,9 +451,37 @@ impl<'a> Entry {
         self.get("Password")
     }
 
-    /// Convenience method for getting the value of the 'URL' field
+    /// Convenience method for getting the value of the 'URL' field. A Protected value is not
+    /// decrypted and returns `None`, read it with `get_protected` or `get` instead.
     pub fn get_url(&'a self) -> Option<&'a str> {
-        self.get("URL")
+        self.get_unprotected("URL")
+    }
+
+    /// The name of the automation that manages this entry, if it is marked as machine-managed
+    pub fn managed_by(&'a self) -> Option<&'a str> {
+        match self.custom_data.items.get(MANAGED_BY_KEY)?.value.as_ref()? {
+            Value::Unprotected(manager) if !manager.is_empty() => Some(manager),
+            _ => None,
+        }
+    }
+
+    /// Mark the entry as managed by an automation, or clear the mark. Managed entries can still be
+    /// edited directly, but commands applied with `commands::Editor::User` reject edits to them.
+    pub fn set_managed_by(&mut self, manager: Option<&str>) {
+        match manager {
+            Some(manager) => {
+                self.custom_data.items.insert(
+                    MANAGED_BY_KEY.to_string(),
+                    CustomDataItem {
+                        value: Some(Value::Unprotected(manager.to_string())),
+                        last_modification_time: Some(Times::now()),
+                    },
+                );
+            }
+            None => {
+                self.custom_data.items.remove(MANAGED_BY_KEY);
+            }
+        }
     }
 
     /// Adds the current version of the entry to the entry's history
@@ -269,6 +511,83 @@ impl<'a> Entry {
         true
     }
 
+    /// Adds a copy of the current version of the entry to its history, the way KeePass backs up an
+    /// entry before editing it. Unlike `update_history`, the last modification timestamp is left
+    /// as-is.
+    ///
+    /// Returns whether or not a new history entry was added, which is not the case if the newest
+    /// history entry already is the current version.
+    pub fn push_history(&mut self) -> bool {
+        if !self.has_uncommitted_changes() {
+            return false;
+        }
+
+        let snapshot = self.clone();
+        self.history
+            .get_or_insert_with(History::default)
+            .add_entry(snapshot);
+        true
+    }
+
+    /// Removes the oldest versions from the history until it fits into
+    /// `Meta::history_max_items` and `Meta::history_max_size`.
+    ///
+    /// Returns the number of removed history entries.
+    pub fn trim_history(&mut self, meta: &Meta) -> usize {
+        match self.history.as_mut() {
+            Some(history) => history.trim(meta.history_max_items, meta.history_max_size),
+            None => 0,
+        }
+    }
+
+    /// Approximate size of the entry in bytes, counting the texts of its fields, tags, custom data
+    /// and attachment names. The data of attachments is stored in the database, not the entry.
+    pub(crate) fn approximate_size(&self) -> usize {
+        let value_size = |value: &Value| match value {
+            Value::Bytes(b) => b.len(),
+            Value::Unprotected(u) => u.len(),
+            Value::Protected(p) => p.len(),
+        };
+
+        let fields: usize = self.fields.iter().map(|(k, v)| k.len() + value_size(v)).sum();
+        let tags: usize = self.tags.iter().map(|t| t.len()).sum();
+        let custom_data: usize = self
+            .custom_data
+            .items
+            .iter()
+            .map(|(k, item)| k.len() + item.value.as_ref().map(value_size).unwrap_or(0))
+            .sum();
+        let attachments: usize = self.attachments.iter().map(|a| a.name.len()).sum();
+
+        fields + tags + custom_data + attachments
+    }
+
+    /// Restores the version of the entry at the given index of its history (0 being the most
+    /// recent one), including the attachments that the entry had back then. The current
+    /// version is kept in the history.
+    ///
+    /// Returns whether or not the history had an entry at the given index.
+    pub fn restore_from_history(&mut self, index: usize) -> bool {
+        let snapshot = match self.history.as_ref().and_then(|h| h.entries.get(index)) {
+            Some(snapshot) => snapshot.clone(),
+            None => return false,
+        };
+
+        self.update_history();
+
+        let history = self.history.take();
+        let times = self.times.clone();
+
+        *self = snapshot;
+        self.history = history;
+        self.times = times;
+
+        // record the restored version as the newest one
+        self.update_history();
+
+        true
+    }
+
     /// Determines if the entry was modified since the last
     /// history update.
     fn has_uncommitted_changes(&self) -> bool {
@@ -295,12 +614,12 @@ impl<'a> Entry {
     }
 }
 
-/// A value that can be a raw string, byte array, or protected memory region
+/// A value that can be a raw string, byte array, or a string kept encrypted in memory
 #[derive(Debug, Eq, PartialEq, Clone)]
 pub enum Value {
     Bytes(Vec<u8>),
     Unprotected(String),
-    Protected(SecStr),
+    Protected(ProtectedValue),
 }
 
 impl Value {
@@ -308,49 +627,50 @@ impl Value {
         match self {
             Value::Bytes(b) => b.is_empty(),
             Value::Unprotected(u) => u.is_empty(),
-            Value::Protected(p) => p.unsecure().is_empty(),
-        }
-    }
-}
-
-#[cfg(feature = "serialization")]
-impl serde::Serialize for Value {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: serde::Serializer,
-    {
-        match self {
-            Value::Bytes(b) => serializer.serialize_bytes(b),
-            Value::Unprotected(u) => serializer.serialize_str(u),
-            Value::Protected(p) => serializer.serialize_str(String::from_utf8_lossy(p.unsecure()).as_ref()),
+            Value::Protected(p) => p.is_empty(),
         }
     }
 }
 
 /// An AutoType setting associated with an Entry
 #[derive(Debug, Default, Eq, PartialEq, Clone)]
-#[cfg_attr(feature = "serialization", derive(serde::Serialize))]
+#[cfg_attr(feature = "serialization", derive(serde::Serialize, serde::Deserialize))]
 pub struct AutoType {
     pub enabled: bool,
+
+    /// The keystroke sequence of the entry, or `None` to use the one of its group
     pub sequence: Option<String>,
+
+    /// How Auto-Type hides the typed data from keyloggers, `0` for not at all and `1` for
+    /// two-channel obfuscation via the clipboard
+    pub data_transfer_obfuscation: usize,
+
     pub associations: Vec<AutoTypeAssociation>,
 }
 
 /// A window association associated with an AutoType setting
 #[derive(Debug, Default, Eq, PartialEq, Clone)]
-#[cfg_attr(feature = "serialization", derive(serde::Serialize))]
+#[cfg_attr(feature = "serialization", derive(serde::Serialize, serde::Deserialize))]
 pub struct AutoTypeAssociation {
+    /// The title of the target window, where `*` matches any text
     pub window: Option<String>,
+
+    /// The keystroke sequence for the window, or `None` to use the default sequence
     pub sequence: Option<String>,
 }
 
 /// An entry's history
 #[derive(Debug, Default, Eq, PartialEq, Clone)]
-#[cfg_attr(feature = "serialization", derive(serde::Serialize))]
+#[cfg_attr(feature = "serialization", derive(serde::Serialize, serde::Deserialize))]
 pub struct History {
     pub(crate) entries: Vec<Entry>,
 }
 impl History {
+    /// Get the attachments of the entry at the given index of the history, if any
+    pub fn attachments(&self, index: usize) -> Option<&[AttachmentRef]> {
+        self.entries.get(index).map(|e| e.attachments())
+    }
+
     pub fn add_entry(&mut self, mut entry: Entry) {
         // DISCUSS: should we make sure that the last modification time is not the same
         // or older than the entry at the top of the history?
@@ -362,6 +682,32 @@ impl History {
         self.entries.insert(0, entry);
     }
 
+    /// Removes the oldest entries until at most `max_items` are left and their approximate size is
+    /// at most `max_size` bytes.
+    ///
+    /// Returns the number of removed entries.
+    pub fn trim(&mut self, max_items: Option<usize>, max_size: Option<usize>) -> usize {
+        let count_before = self.entries.len();
+
+        if let Some(max_items) = max_items {
+            self.entries.truncate(max_items);
+        }
+        if let Some(max_size) = max_size {
+            let mut size = 0;
+            let keep = self
+                .entries
+                .iter()
+                .take_while(|e| {
+                    size += e.approximate_size();
+                    size <= max_size
+                })
+                .count();
+            self.entries.truncate(keep);
+        }
+
+        count_before - self.entries.len()
+    }
+
     pub fn get_entries(&self) -> &Vec<Entry> {
         &self.entries
     }
@@ -435,9 +781,62 @@ impl History {
 mod entry_tests {
     use std::{thread, time};
 
-    use secstr::SecStr;
+    use super::{AttachmentRef, Entry, Value};
+    use crate::db::{ProtectedValue, Times};
 
-    use super::{Entry, Value};
+    #[test]
+    fn custom_data_accessors() {
+        let start = Times::epoch() + chrono::Duration::days(10_000);
+        let mut entry = Entry::new();
+        let mut group = crate::db::Group::new("Shared");
+
+        crate::db::with_clock(
+            move || start,
+            || {
+                assert!(entry.set_custom_data("KPXC_PLUGIN", "on", false).is_none());
+                entry.set_custom_data("Secret", "hunter2", true);
+                group.set_custom_data("KeeShare/Reference", "reference", false);
+            },
+        );
+
+        assert_eq!(entry.get_custom_data("KPXC_PLUGIN"), Some("on"));
+        assert_eq!(entry.get_custom_data("Secret"), Some("hunter2"));
+        let secret = &entry.custom_data.items["Secret"];
+        assert!(matches!(secret.value, Some(Value::Protected(_))));
+        assert_eq!(secret.last_modification_time, Some(start));
+        assert_eq!(group.get_custom_data("KeeShare/Reference"), Some("reference"));
+
+        let previous = entry.set_custom_data("KPXC_PLUGIN", "off", false).unwrap();
+        assert_eq!(previous.value, Some(Value::Unprotected("on".to_string())));
+        assert!(entry.remove_custom_data("KPXC_PLUGIN").is_some());
+        assert_eq!(entry.get_custom_data("KPXC_PLUGIN"), None);
+    }
+
+    #[test]
+    fn with_fields() {
+        let expiry = Times::now() + chrono::Duration::days(30);
+        let entry = Entry::new()
+            .with_title("Mail")
+            .with_username("jdoe")
+            .with_password("secret")
+            .with_url("https://mail.example.com")
+            .with_notes("Work account")
+            .with_field("PIN", Value::Protected("1234".into()))
+            .with_tag("work")
+            .with_tag("work")
+            .with_expiry(expiry);
+
+        assert_eq!(entry.get_title(), Some("Mail"));
+        assert_eq!(entry.get_username(), Some("jdoe"));
+        assert_eq!(entry.get_url(), Some("https://mail.example.com"));
+        assert_eq!(entry.get("Notes"), Some("Work account"));
+        assert!(matches!(entry.fields["Password"], Value::Protected(_)));
+        assert!(matches!(entry.fields["PIN"], Value::Protected(_)));
+        assert_eq!(entry.tags, vec!["work"]);
+        assert!(entry.times.expires);
+        assert_eq!(entry.get_expiry_time(), Some(&expiry));
+        assert!(entry.times.get_creation().is_some());
+    }
 
     #[test]
     fn byte_values() {
@@ -453,7 +852,7 @@ mod entry_tests {
 
         entry.fields.insert(
             "a-protected".to_string(),
-            Value::Protected(SecStr::new("asdf".as_bytes().to_vec())),
+            Value::Protected(ProtectedValue::new("asdf".as_bytes().to_vec())),
         );
 
         assert_eq!(entry.get_bytes("a-bytes"), Some(&[1, 2, 3][..]));
@@ -462,9 +861,57 @@ mod entry_tests {
 
         assert_eq!(entry.get("a-bytes"), None);
 
+        assert_eq!(entry.get_protected("a-protected").unwrap().as_str(), Some("asdf"));
+        assert_eq!(&*entry.get_protected("a-unprotected").unwrap(), b"asdf");
+        assert!(entry.get_protected("a-bytes").is_none());
+
         assert_eq!(entry.fields["a-bytes"].is_empty(), false);
     }
 
+    #[test]
+    fn push_and_trim_history() {
+        use crate::db::Meta;
+
+        let mut entry = Entry::new();
+        for title in ["A", "B", "C", "D"].iter() {
+            entry
+                .fields
+                .insert("Title".to_string(), Value::Unprotected(title.to_string()));
+            assert!(entry.push_history());
+        }
+        // the current version is already the newest history entry
+        assert!(!entry.push_history());
+        assert_eq!(entry.history.as_ref().unwrap().get_entries().len(), 4);
+
+        let history_titles = |entry: &Entry| -> Vec<String> {
+            entry
+                .history
+                .as_ref()
+                .unwrap()
+                .get_entries()
+                .iter()
+                .map(|e| e.get_title().unwrap().to_string())
+                .collect()
+        };
+
+        let mut meta = Meta::default();
+        assert_eq!(entry.trim_history(&meta), 0);
+
+        meta.history_max_items = Some(3);
+        assert_eq!(entry.trim_history(&meta), 1);
+        assert_eq!(history_titles(&entry), vec!["D", "C", "B"]);
+
+        // every version takes the length of "Title" and its value
+        meta.history_max_size = Some(13);
+        assert_eq!(entry.trim_history(&meta), 1);
+        assert_eq!(history_titles(&entry), vec!["D", "C"]);
+
+        let mut db = crate::Database::new(Default::default());
+        db.meta.history_max_items = Some(1);
+        db.root.add_child(entry);
+        assert_eq!(db.trim_histories(), 1);
+    }
+
     #[test]
     fn update_history() {
         let mut entry = Entry::new();
@@ -550,6 +997,46 @@ mod entry_tests {
         }
     }
 
+    #[test]
+    fn restore_from_history() {
+        let attachment = |name: &str, identifier| AttachmentRef {
+            name: name.to_string(),
+            identifier,
+        };
+
+        let mut entry = Entry::new();
+        entry
+            .fields
+            .insert("Title".to_string(), Value::Unprotected("old".to_string()));
+        entry.attachments = vec![attachment("a.txt", 0), attachment("b.txt", 1)];
+        assert!(entry.update_history());
+
+        entry
+            .fields
+            .insert("Title".to_string(), Value::Unprotected("new".to_string()));
+        entry.attachments = vec![attachment("c.txt", 2)];
+        assert!(entry.update_history());
+
+        let history = entry.history.as_ref().unwrap();
+        assert_eq!(history.attachments(0), Some(&[attachment("c.txt", 2)][..]));
+        assert_eq!(history.get_entries()[1].attachments().len(), 2);
+
+        assert!(!entry.restore_from_history(5));
+        assert!(entry.restore_from_history(1));
+
+        assert_eq!(entry.get_title(), Some("old"));
+        assert_eq!(
+            entry.attachments(),
+            &[attachment("a.txt", 0), attachment("b.txt", 1)]
+        );
+
+        // the restored version became the newest history entry, the replaced one is kept
+        let history = entry.history.as_ref().unwrap();
+        assert_eq!(history.get_entries().len(), 3);
+        assert_eq!(history.get_entries()[0].get_title(), Some("old"));
+        assert_eq!(history.get_entries()[1].get_title(), Some("new"));
+    }
+
     #[cfg(feature = "totp")]
     #[test]
     fn totp() {
@@ -557,24 +1044,37 @@ mod entry_tests {
         entry.fields.insert("otp".to_string(), Value::Unprotected("otpauth://totp/ACME%20Co:john.doe@email.com?secret=HXDMVJECJJWSRB3HWIZR4IFUGFTMXBOZ&issuer=ACME%20Co&algorithm=SHA1&digits=6&period=30".to_string()));
 
         assert!(entry.get_otp().is_ok());
-    }
 
-    #[cfg(feature = "serialization")]
-    #[test]
-    fn serialization() {
-        assert_eq!(
-            serde_json::to_string(&Value::Bytes(vec![65, 66, 67])).unwrap(),
-            "[65,66,67]".to_string()
+        // the formats of the KeeOtp and TrayTOTP plugins
+        let mut entry = Entry::new().with_title("Legacy");
+        assert!(matches!(
+            entry.get_otp(),
+            Err(crate::db::otp::TOTPError::NoRecord)
+        ));
+        entry.fields.insert(
+            "TOTP Seed".to_string(),
+            Value::Protected("JBSWY3DPEHPK3PXP".into()),
         );
+        entry.fields.insert(
+            "TOTP Settings".to_string(),
+            Value::Unprotected("30;8".to_string()),
+        );
+        let totp = entry.get_otp().unwrap();
+        assert_eq!((totp.label.as_str(), totp.digits), ("Legacy", 8));
 
+        entry
+            .fields
+            .insert("UserName".to_string(), Value::Unprotected("jdoe".to_string()));
         assert_eq!(
-            serde_json::to_string(&Value::Unprotected("ABC".to_string())).unwrap(),
-            "\"ABC\"".to_string()
+            entry.otp_uri().unwrap(),
+            "otpauth://totp/Legacy:jdoe?secret=JBSWY3DPEHPK3PXP&issuer=Legacy&algorithm=SHA1&digits=8&period=30"
         );
 
-        assert_eq!(
-            serde_json::to_string(&Value::Protected(SecStr::new("ABC".as_bytes().to_vec()))).unwrap(),
-            "\"ABC\"".to_string()
+        entry.fields.insert(
+            "otp".to_string(),
+            Value::Protected("key=JBSWY3DPEHPK3PXP&size=7".into()),
         );
+        let totp = entry.get_otp().unwrap();
+        assert_eq!((totp.get_secret().as_str(), totp.digits), ("JBSWY3DPEHPK3PXP", 7));
     }
 }
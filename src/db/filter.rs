@@ -0,0 +1,576 @@
+//! Selecting entries for maintenance operations
+//!
+//! A `Filter` describes a set of entries by group, tags, age, expiry and field contents. The same
+//! filter can be passed to exports (`SecretsExportOptions::filter`), reports
+//! (`CompliancePolicy::filter`), table views (`TableView::for_filter`) and bulk edits
+//! (`commands::bulk`), so that e.g. a cleanup tool can preview, report on and then change exactly
+//! the same entries.
+//!
+//! ```
+//! use keepass::db::{Database, Filter};
+//!
+//! let db = Database::new(Default::default());
+//! let stale = Filter::tag("temporary")
+//!     .and(Filter::unchanged_for(chrono::Duration::days(90)))
+//!     .and(Filter::expired().not());
+//! assert!(stale.select(&db.root).is_empty());
+//! ```
+//!
+//! The crate has no regular expression engine of its own, `Filter::field_matches` takes any
+//! predicate instead, e.g. one calling `regex::Regex::is_match`.
+//!
+//! Search boxes can combine `Filter::any_field_contains` with other filters and pass the result to
+//! `Database::search`, or to `Database::search_mut` to change the entries found:
+//!
+//! ```
+//! use keepass::db::{Database, Filter};
+//!
+//! let db = Database::new(Default::default());
+//! let found = db.search(&Filter::any_field_contains("mail").and(Filter::tag("work").not()));
+//! assert!(found.is_empty());
+//! ```
+//!
+//! `Filter::url_matches` finds the entries for a web page, by their URL and their additional
+//! `KP2A_URL*` URLs, as described in `url_match`.
+
+use std::{fmt, sync::Arc};
+
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+use crate::{
+    db::{Clock, Database, Entry, Group, Node, Times},
+    url_match::{entry_match_level, MatchMode},
+};
+
+/// A predicate on the value of a field
+pub type FieldPredicate = Arc<dyn Fn(&str) -> bool + Send + Sync>;
+
+/// A condition on entries, see the module documentation
+#[derive(Clone, Default)]
+pub enum Filter {
+    /// Every entry
+    #[default]
+    All,
+
+    /// Entries matching all filters
+    And(Vec<Filter>),
+
+    /// Entries matching any of the filters
+    Or(Vec<Filter>),
+
+    Not(Box<Filter>),
+
+    /// Entries in the group with this UUID or any of its subgroups
+    InGroup(Uuid),
+
+    /// Entries with this tag, ignoring case
+    Tag(String),
+
+    /// Entries that were not modified for at least this long
+    UnchangedFor(chrono::Duration),
+
+    /// Entries that expired already
+    Expired,
+
+    /// Entries that expire within this time from now, or expired already
+    ExpiresWithin(chrono::Duration),
+
+    /// Entries with a non-empty field of this name
+    HasField(String),
+
+    /// Entries with a field containing the text, ignoring case
+    FieldContains {
+        field: String,
+        text: String,
+    },
+
+    /// Entries with any field except the password containing the text, ignoring case
+    AnyFieldContains(String),
+
+    /// Entries with a field for which the predicate returns true
+    FieldMatches {
+        field: String,
+        predicate: FieldPredicate,
+    },
+
+    /// Entries with a URL matching the page at this URL at least as closely as the mode requires,
+    /// see `url_match`
+    UrlMatches {
+        url: String,
+        mode: MatchMode,
+    },
+}
+
+impl fmt::Debug for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Filter::All => write!(f, "All"),
+            Filter::And(filters) => f.debug_tuple("And").field(filters).finish(),
+            Filter::Or(filters) => f.debug_tuple("Or").field(filters).finish(),
+            Filter::Not(filter) => f.debug_tuple("Not").field(filter).finish(),
+            Filter::InGroup(uuid) => f.debug_tuple("InGroup").field(uuid).finish(),
+            Filter::Tag(tag) => f.debug_tuple("Tag").field(tag).finish(),
+            Filter::UnchangedFor(age) => f.debug_tuple("UnchangedFor").field(age).finish(),
+            Filter::Expired => write!(f, "Expired"),
+            Filter::ExpiresWithin(time) => f.debug_tuple("ExpiresWithin").field(time).finish(),
+            Filter::HasField(field) => f.debug_tuple("HasField").field(field).finish(),
+            Filter::FieldContains { field, text } => f
+                .debug_struct("FieldContains")
+                .field("field", field)
+                .field("text", text)
+                .finish(),
+            Filter::AnyFieldContains(text) => f.debug_tuple("AnyFieldContains").field(text).finish(),
+            Filter::FieldMatches { field, .. } => f
+                .debug_struct("FieldMatches")
+                .field("field", field)
+                .finish_non_exhaustive(),
+            Filter::UrlMatches { url, mode } => f
+                .debug_struct("UrlMatches")
+                .field("url", url)
+                .field("mode", mode)
+                .finish(),
+        }
+    }
+}
+
+impl Filter {
+    pub fn in_group(group: Uuid) -> Self {
+        Filter::InGroup(group)
+    }
+
+    pub fn tag(tag: &str) -> Self {
+        Filter::Tag(tag.to_string())
+    }
+
+    pub fn unchanged_for(age: chrono::Duration) -> Self {
+        Filter::UnchangedFor(age)
+    }
+
+    pub fn expired() -> Self {
+        Filter::Expired
+    }
+
+    pub fn expires_within(time: chrono::Duration) -> Self {
+        Filter::ExpiresWithin(time)
+    }
+
+    pub fn has_field(field: &str) -> Self {
+        Filter::HasField(field.to_string())
+    }
+
+    pub fn field_contains(field: &str, text: &str) -> Self {
+        Filter::FieldContains {
+            field: field.to_string(),
+            text: text.to_string(),
+        }
+    }
+
+    pub fn any_field_contains(text: &str) -> Self {
+        Filter::AnyFieldContains(text.to_string())
+    }
+
+    pub fn field_matches(field: &str, predicate: impl Fn(&str) -> bool + Send + Sync + 'static) -> Self {
+        Filter::FieldMatches {
+            field: field.to_string(),
+            predicate: Arc::new(predicate),
+        }
+    }
+
+    pub fn url_matches(url: &str, mode: MatchMode) -> Self {
+        Filter::UrlMatches {
+            url: url.to_string(),
+            mode,
+        }
+    }
+
+    /// Entries matching both filters
+    pub fn and(self, other: Filter) -> Self {
+        match self {
+            Filter::All => other,
+            Filter::And(mut filters) => {
+                filters.push(other);
+                Filter::And(filters)
+            }
+            filter => Filter::And(vec![filter, other]),
+        }
+    }
+
+    /// Entries matching either filter
+    pub fn or(self, other: Filter) -> Self {
+        match self {
+            Filter::Or(mut filters) => {
+                filters.push(other);
+                Filter::Or(filters)
+            }
+            filter => Filter::Or(vec![filter, other]),
+        }
+    }
+
+    #[allow(clippy::should_implement_trait)]
+    pub fn not(self) -> Self {
+        Filter::Not(Box::new(self))
+    }
+
+    /// Whether an entry matches. `groups` are the UUIDs of the groups containing the entry, from
+    /// the root down to its parent.
+    pub fn matches(&self, entry: &Entry, groups: &[Uuid]) -> bool {
+        self.matches_at(entry, groups, Times::now())
+    }
+
+    /// The matching entries under `root`, in depth-first order
+    pub fn select<'a>(&self, root: &'a Group) -> Vec<&'a Entry> {
+        let mut out = Vec::new();
+        self.select_in(root, &mut Vec::new(), Times::now(), &mut out);
+        out
+    }
+
+    /// The matching entries under `root` for changing them, in depth-first order
+    pub fn select_mut<'a>(&self, root: &'a mut Group) -> Vec<&'a mut Entry> {
+        let mut out = Vec::new();
+        self.select_in_mut(root, &mut Vec::new(), Times::now(), &mut out);
+        out
+    }
+
+    /// The UUIDs of the matching entries under `root`, e.g. to change them afterwards
+    pub fn select_uuids(&self, root: &Group) -> Vec<Uuid> {
+        self.select(root).into_iter().map(|e| e.uuid).collect()
+    }
+
+    fn select_in<'a>(
+        &self,
+        group: &'a Group,
+        groups: &mut Vec<Uuid>,
+        now: chrono::NaiveDateTime,
+        out: &mut Vec<&'a Entry>,
+    ) {
+        groups.push(group.uuid);
+        for entry in group.entries() {
+            if self.matches_at(entry, groups, now) {
+                out.push(entry);
+            }
+        }
+        for child in group.groups() {
+            self.select_in(child, groups, now, out);
+        }
+        groups.pop();
+    }
+
+    fn select_in_mut<'a>(
+        &self,
+        group: &'a mut Group,
+        groups: &mut Vec<Uuid>,
+        now: chrono::NaiveDateTime,
+        out: &mut Vec<&'a mut Entry>,
+    ) {
+        groups.push(group.uuid);
+        // entries come before the subgroups, like in `select_in`
+        let mut children = Vec::new();
+        for node in group.children.iter_mut() {
+            match node {
+                Node::Entry(entry) => {
+                    if self.matches_at(entry, groups, now) {
+                        out.push(entry);
+                    }
+                }
+                Node::Group(child) => children.push(child),
+            }
+        }
+        for child in children {
+            self.select_in_mut(child, groups, now, out);
+        }
+        groups.pop();
+    }
+
+    fn matches_at(&self, entry: &Entry, groups: &[Uuid], now: chrono::NaiveDateTime) -> bool {
+        let expiry = || entry.times.expires_at();
+
+        match self {
+            Filter::All => true,
+            Filter::And(filters) => filters.iter().all(|f| f.matches_at(entry, groups, now)),
+            Filter::Or(filters) => filters.iter().any(|f| f.matches_at(entry, groups, now)),
+            Filter::Not(filter) => !filter.matches_at(entry, groups, now),
+            Filter::InGroup(uuid) => groups.contains(uuid),
+            Filter::Tag(tag) => entry.has_tag(tag),
+            Filter::UnchangedFor(age) => entry
+                .times
+                .get_last_modification()
+                .is_some_and(|modified| now - *modified >= *age),
+            Filter::Expired => expiry().is_some_and(|expiry| expiry <= now),
+            Filter::ExpiresWithin(time) => expiry().is_some_and(|expiry| expiry <= now + *time),
+            Filter::HasField(field) => field_matches(entry, field, |v| !v.is_empty()),
+            Filter::FieldContains { field, text } => field_matches(entry, field, |v| {
+                Zeroizing::new(v.to_lowercase()).contains(&text.to_lowercase())
+            }),
+            Filter::AnyFieldContains(text) => {
+                let text = text.to_lowercase();
+                entry
+                    .fields
+                    .keys()
+                    .filter(|field| *field != "Password")
+                    .any(|field| {
+                        field_matches(entry, field, |v| Zeroizing::new(v.to_lowercase()).contains(&text))
+                    })
+            }
+            Filter::FieldMatches { field, predicate } => field_matches(entry, field, |v| predicate(v)),
+            Filter::UrlMatches { url, mode } => {
+                entry_match_level(entry, url).is_some_and(|level| level >= *mode)
+            }
+        }
+    }
+}
+
+/// Whether a text field of an entry exists and matches, decrypting it only for the check
+fn field_matches(entry: &Entry, field: &str, matches: impl FnOnce(&str) -> bool) -> bool {
+    entry
+        .get_protected(field)
+        .is_some_and(|value| value.as_str().is_some_and(matches))
+}
+
+impl Database {
+    /// The entries matching `filter`, in depth-first order
+    pub fn search(&self, filter: &Filter) -> Vec<&Entry> {
+        filter.select(&self.root)
+    }
+
+    /// The entries matching `filter` for changing them, in depth-first order
+    pub fn search_mut(&mut self, filter: &Filter) -> Vec<&mut Entry> {
+        filter.select_mut(&mut self.root)
+    }
+
+    /// The entries that expired by the time of `clock`, in depth-first order, see
+    /// `Times::expires_at`. Entries in the recycle bin are left out.
+    pub fn expired_entries(&self, clock: &dyn Clock) -> impl Iterator<Item = &Entry> {
+        let now = clock.now();
+        self.entries_outside_recycle_bin()
+            .filter(move |entry| entry.times.expires_at().is_some_and(|expiry| expiry <= now))
+    }
+
+    /// The entries that have not expired by the time of `clock`, but will within `time`, in
+    /// depth-first order. Entries in the recycle bin are left out.
+    pub fn expiring_within(&self, time: chrono::Duration, clock: &dyn Clock) -> impl Iterator<Item = &Entry> {
+        let now = clock.now();
+        self.entries_outside_recycle_bin().filter(move |entry| {
+            entry
+                .times
+                .expires_at()
+                .is_some_and(|expiry| now < expiry && expiry <= now + time)
+        })
+    }
+
+    fn entries_outside_recycle_bin(&self) -> impl Iterator<Item = &Entry> {
+        fn collect<'a>(group: &'a Group, recycle_bin: Option<Uuid>, out: &mut Vec<&'a Entry>) {
+            let mut children = Vec::new();
+            for node in &group.children {
+                match node {
+                    Node::Entry(entry) => out.push(entry),
+                    Node::Group(child) if Some(child.uuid) != recycle_bin => children.push(child),
+                    Node::Group(_) => {}
+                }
+            }
+            for child in children {
+                collect(child, recycle_bin, out);
+            }
+        }
+
+        let mut entries = Vec::new();
+        collect(&self.root, self.meta.recyclebin_uuid, &mut entries);
+        entries.into_iter()
+    }
+}
+
+#[cfg(test)]
+mod filter_tests {
+    use chrono::Duration;
+
+    use crate::{
+        db::{with_clock, Database, Entry, Group, Times, Value},
+        url_match::MatchMode,
+    };
+
+    use super::Filter;
+
+    fn entry(title: &str, tags: &[&str]) -> Entry {
+        let mut entry = Entry::new();
+        entry
+            .fields
+            .insert("Title".to_string(), Value::Unprotected(title.to_string()));
+        entry.tags = tags.iter().map(|t| t.to_string()).collect();
+        entry
+    }
+
+    #[test]
+    fn test_filters() {
+        let start = Times::epoch() + Duration::days(10_000);
+        let (root, work_uuid) = with_clock(
+            move || start,
+            || {
+                let mut root = Group::new("Root");
+                root.add_child(entry("bank", &["Finance"]));
+
+                let mut work = Group::new("Work");
+                let mut vpn = entry("vpn", &["temporary"]);
+                vpn.times.expires = true;
+                vpn.times.set_expiry(start + Duration::days(5));
+                work.add_child(vpn);
+                let mut sub = Group::new("Servers");
+                let mut ci = entry("ci", &[]);
+                ci.fields.insert(
+                    "URL".to_string(),
+                    Value::Unprotected("https://ci.example.com".to_string()),
+                );
+                sub.add_child(ci);
+                work.add_child(sub);
+                let work_uuid = work.uuid;
+                root.add_child(work);
+
+                (root, work_uuid)
+            },
+        );
+
+        let titles = |filter: &Filter| -> Vec<String> {
+            with_clock(
+                move || start + Duration::days(30),
+                || {
+                    filter
+                        .select(&root)
+                        .iter()
+                        .map(|e| e.get_title().unwrap().to_string())
+                        .collect()
+                },
+            )
+        };
+
+        assert_eq!(titles(&Filter::All), vec!["bank", "vpn", "ci"]);
+        assert_eq!(titles(&Filter::in_group(work_uuid)), vec!["vpn", "ci"]);
+        assert_eq!(titles(&Filter::tag("finance")), vec!["bank"]);
+        assert_eq!(titles(&Filter::expired()), vec!["vpn"]);
+        assert_eq!(
+            titles(&Filter::unchanged_for(Duration::days(31))),
+            Vec::<String>::new()
+        );
+        assert_eq!(titles(&Filter::unchanged_for(Duration::days(30))).len(), 3);
+        assert_eq!(titles(&Filter::has_field("URL")), vec!["ci"]);
+        assert_eq!(titles(&Filter::field_contains("URL", "EXAMPLE")), vec!["ci"]);
+        assert_eq!(
+            titles(&Filter::url_matches(
+                "https://ci.example.com/job/1",
+                MatchMode::StartsWith
+            )),
+            vec!["ci"]
+        );
+        assert!(titles(&Filter::url_matches("https://example.com", MatchMode::Domain)).is_empty());
+        assert_eq!(
+            titles(&Filter::field_matches("Title", |t| t.len() == 4)),
+            vec!["bank"]
+        );
+
+        let combined = Filter::in_group(work_uuid).and(Filter::expired().not());
+        assert_eq!(titles(&combined), vec!["ci"]);
+        assert_eq!(
+            titles(&Filter::tag("finance").or(Filter::has_field("URL"))),
+            vec!["bank", "ci"]
+        );
+        assert_eq!(titles(&Filter::All.and(Filter::tag("temporary"))), vec!["vpn"]);
+
+        // the expiry window is relative to the current time
+        with_clock(
+            move || start,
+            || {
+                assert!(Filter::expired().select(&root).is_empty());
+                assert_eq!(Filter::expires_within(Duration::days(7)).select(&root).len(), 1);
+            },
+        );
+    }
+
+    #[test]
+    fn test_expiry_queries() {
+        let now = Times::epoch() + Duration::days(10_000);
+        let clock = move || now;
+
+        let mut db = Database::new(Default::default());
+        let expiring = |title: &str, expiry| {
+            let mut entry = entry(title, &[]);
+            entry.times.expires = true;
+            entry.times.set_expiry(expiry);
+            entry
+        };
+        db.root.add_child(expiring("expired", now - Duration::days(1)));
+        db.root.add_child(expiring("soon", now + Duration::days(3)));
+        db.root.add_child(expiring("later", now + Duration::days(30)));
+        // the placeholder of clients that write a zero timestamp instead of none
+        db.root
+            .add_child(expiring("placeholder", crate::xml_db::get_epoch_baseline()));
+        let mut never = expiring("never", now - Duration::days(1));
+        never.times.expires = false;
+        db.root.add_child(never);
+
+        let mut bin = Group::new("Recycle Bin");
+        db.meta.recyclebin_uuid = Some(bin.uuid);
+        bin.add_child(expiring("deleted", now - Duration::days(1)));
+        db.root.add_child(bin);
+
+        let titles = |entries: Vec<&Entry>| -> Vec<String> {
+            entries
+                .iter()
+                .map(|e| e.get_title().unwrap().to_string())
+                .collect()
+        };
+        assert_eq!(titles(db.expired_entries(&clock).collect()), vec!["expired"]);
+        assert_eq!(
+            titles(db.expiring_within(Duration::days(7), &clock).collect()),
+            vec!["soon"]
+        );
+        assert_eq!(
+            titles(db.expiring_within(Duration::days(60), &clock).collect()),
+            vec!["soon", "later"]
+        );
+    }
+
+    #[test]
+    fn test_search() {
+        let mut db = Database::new(Default::default());
+        let mut mail = entry("Mail", &[]);
+        mail.fields.insert(
+            "UserName".to_string(),
+            Value::Unprotected("jdoe@example.com".to_string()),
+        );
+        mail.fields
+            .insert("Password".to_string(), Value::Protected("example".into()));
+        db.root.add_child(mail);
+
+        let mut work = Group::new("Work");
+        let mut server = entry("Server", &["work"]);
+        server
+            .fields
+            .insert("Host".to_string(), Value::Protected("db.example.com".into()));
+        work.add_child(server);
+        work.add_child(entry("Wiki", &["work"]));
+        db.root.add_child(work);
+
+        let titles = |entries: Vec<&Entry>| -> Vec<String> {
+            entries
+                .iter()
+                .map(|e| e.get_title().unwrap().to_string())
+                .collect()
+        };
+
+        // custom fields are searched, passwords are not
+        assert_eq!(
+            titles(db.search(&Filter::any_field_contains("EXAMPLE"))),
+            vec!["Mail", "Server"]
+        );
+        assert_eq!(
+            titles(db.search(&Filter::any_field_contains("example").and(Filter::tag("work")))),
+            vec!["Server"]
+        );
+
+        for entry in db.search_mut(&Filter::tag("work")) {
+            entry.tags.push("reviewed".to_string());
+        }
+        assert_eq!(
+            titles(db.search(&Filter::tag("reviewed"))),
+            vec!["Server", "Wiki"]
+        );
+    }
+}
@@ -0,0 +1,270 @@
+//! Reports on databases that cannot be opened
+//!
+//! When a user's database fails to open, the error alone rarely tells whether the file was
+//! truncated by a sync tool, damaged on disk or opened with the wrong key, and the file itself
+//! cannot be shared. `Database::parse_with_forensics` returns a `ForensicReport` along with the
+//! error: the header fields that were read, the results of the hash and HMAC checks, the index of
+//! the last verified block and whether decryption, decompression and the XML parser succeeded.
+//! Keys, seeds, initialization vectors and decrypted content are never part of the report, so it
+//! can be attached to bug reports as is.
+//!
+//! The report covers every step for KDBX 4 files. For older formats it only records the version.
+//!
+//! ```
+//! use keepass::{Database, DatabaseKey};
+//!
+//! let mut data = std::fs::read("tests/resources/test_db_kdbx4_with_password_aes.kdbx")?;
+//! let last = data.len() - 100;
+//! data[last] ^= 1;
+//!
+//! let failed = Database::parse_with_forensics(&data, DatabaseKey::new().with_password("demopass"))
+//!     .unwrap_err();
+//! eprintln!("{}", failed.report);
+//! assert!(failed.report.header_hmac.passed());
+//! assert!(!failed.report.block_stream.passed());
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::fmt;
+
+use crate::{
+    db::Database,
+    error::DatabaseOpenError,
+    format::{kdbx4::analyze_kdbx4, DatabaseVersion},
+    key::DatabaseKey,
+};
+
+/// The outcome of one step of opening a database
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serialization", derive(serde::Serialize))]
+pub enum CheckResult {
+    /// An earlier step failed
+    #[default]
+    NotReached,
+    Passed,
+    Failed(String),
+}
+
+impl CheckResult {
+    pub fn passed(&self) -> bool {
+        *self == CheckResult::Passed
+    }
+}
+
+impl fmt::Display for CheckResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CheckResult::NotReached => write!(f, "not reached"),
+            CheckResult::Passed => write!(f, "passed"),
+            CheckResult::Failed(reason) => write!(f, "failed: {}", reason),
+        }
+    }
+}
+
+/// A field of the outer or inner header. The values of secret fields like seeds and keys are
+/// left out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serialization", derive(serde::Serialize))]
+pub struct HeaderField {
+    pub id: u8,
+    pub name: String,
+
+    /// Offset of the field from the start of the file, or of the decrypted payload for fields of
+    /// the inner header
+    pub offset: usize,
+    pub length: usize,
+    pub value: Option<String>,
+}
+
+/// How far opening a database got, see the module documentation
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serialization", derive(serde::Serialize))]
+pub struct ForensicReport {
+    /// The error returned when parsing the database
+    pub error: Option<String>,
+    pub file_size: usize,
+    pub version: Option<String>,
+
+    pub header_fields: Vec<HeaderField>,
+    pub header: CheckResult,
+
+    /// The SHA-256 hash of the header, which detects corruption
+    pub header_hash: CheckResult,
+
+    /// The HMAC of the header, which fails for wrong keys
+    pub header_hmac: CheckResult,
+
+    /// Number of blocks of the HMAC block stream that were read and verified
+    pub blocks_verified: usize,
+    pub block_stream: CheckResult,
+
+    /// Bytes after the final block
+    pub trailing_bytes: usize,
+
+    pub decryption: CheckResult,
+    pub decompression: CheckResult,
+    pub decompressed_size: Option<usize>,
+
+    pub inner_header_fields: Vec<HeaderField>,
+    pub inner_header: CheckResult,
+    pub xml: CheckResult,
+}
+
+impl ForensicReport {
+    /// Check a database step by step, without parsing it into a `Database`
+    pub fn analyze(data: &[u8], key: &DatabaseKey) -> Self {
+        let mut report = ForensicReport {
+            file_size: data.len(),
+            ..Default::default()
+        };
+
+        let version = match DatabaseVersion::parse(data) {
+            Ok(version) => version,
+            Err(e) => {
+                report.header = CheckResult::Failed(e.to_string());
+                return report;
+            }
+        };
+        report.version = Some(format!("{:?}", version));
+
+        if let DatabaseVersion::KDB4(_) = version {
+            analyze_kdbx4(data, key, &mut report);
+        }
+        report
+    }
+}
+
+impl fmt::Display for ForensicReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let fields = |f: &mut fmt::Formatter<'_>, fields: &[HeaderField]| -> fmt::Result {
+            for field in fields {
+                write!(
+                    f,
+                    "    {:>6}: {} (id {}, {} bytes)",
+                    field.offset, field.name, field.id, field.length
+                )?;
+                match &field.value {
+                    Some(value) => writeln!(f, " {}", value)?,
+                    None => writeln!(f)?,
+                }
+            }
+            Ok(())
+        };
+
+        if let Some(error) = &self.error {
+            writeln!(f, "Error: {}", error)?;
+        }
+        writeln!(f, "File size: {} bytes", self.file_size)?;
+        writeln!(f, "Version: {}", self.version.as_deref().unwrap_or("unknown"))?;
+        writeln!(f, "Outer header: {}", self.header)?;
+        fields(f, &self.header_fields)?;
+        writeln!(f, "Header hash: {}", self.header_hash)?;
+        writeln!(f, "Header HMAC: {}", self.header_hmac)?;
+        writeln!(
+            f,
+            "Block stream: {} ({} blocks verified, {} trailing bytes)",
+            self.block_stream, self.blocks_verified, self.trailing_bytes
+        )?;
+        writeln!(f, "Decryption: {}", self.decryption)?;
+        write!(f, "Decompression: {}", self.decompression)?;
+        match self.decompressed_size {
+            Some(size) => writeln!(f, " ({} bytes)", size)?,
+            None => writeln!(f)?,
+        }
+        writeln!(f, "Inner header: {}", self.inner_header)?;
+        fields(f, &self.inner_header_fields)?;
+        writeln!(f, "XML: {}", self.xml)
+    }
+}
+
+/// An error opening a database together with a report on how far opening it got
+#[derive(Debug)]
+pub struct FailedParse {
+    pub error: DatabaseOpenError,
+    pub report: Box<ForensicReport>,
+}
+
+impl fmt::Display for FailedParse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.error.fmt(f)
+    }
+}
+
+impl std::error::Error for FailedParse {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+impl Database {
+    /// Parse a database, producing a `ForensicReport` if it cannot be opened
+    pub fn parse_with_forensics(data: &[u8], key: DatabaseKey) -> Result<Database, FailedParse> {
+        Database::parse(data, key.clone()).map_err(|error| {
+            let mut report = ForensicReport::analyze(data, &key);
+            report.error = Some(error.to_string());
+            FailedParse {
+                error,
+                report: Box::new(report),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod forensics_tests {
+    use crate::{db::Database, DatabaseKey};
+
+    use super::{CheckResult, ForensicReport};
+
+    const PATH: &str = "tests/resources/test_db_kdbx4_with_password_aes.kdbx";
+
+    fn key() -> DatabaseKey {
+        DatabaseKey::new().with_password("demopass")
+    }
+
+    #[test]
+    fn test_report_of_valid_file() {
+        let data = std::fs::read(PATH).unwrap();
+        let report = ForensicReport::analyze(&data, &key());
+
+        assert_eq!(report.version.as_deref(), Some("KDB4(1)"));
+        assert!(report
+            .header_fields
+            .iter()
+            .any(|f| f.name == "OuterCipher" && f.value.as_deref() == Some("AES256")));
+        let seed = report
+            .header_fields
+            .iter()
+            .find(|f| f.name == "MasterSeed")
+            .unwrap();
+        assert_eq!(seed.value, None);
+        assert!(report.xml.passed());
+        assert!(report.blocks_verified >= 2);
+        assert_eq!(report.inner_header_fields.last().unwrap().name, "End");
+    }
+
+    #[test]
+    fn test_report_of_broken_files() {
+        let data = std::fs::read(PATH).unwrap();
+
+        let failed =
+            Database::parse_with_forensics(&data, DatabaseKey::new().with_password("wrong")).unwrap_err();
+        assert!(failed.report.header_hash.passed());
+        assert!(matches!(failed.report.header_hmac, CheckResult::Failed(_)));
+        assert_eq!(failed.report.block_stream, CheckResult::NotReached);
+        assert!(failed.report.error.is_some());
+
+        // a flipped bit in the first block
+        let mut corrupted = data.clone();
+        let first_block = failed.report.header_fields.last().unwrap();
+        let block_start = first_block.offset + 5 + first_block.length + 64;
+        corrupted[block_start + 40] ^= 1;
+        let report = ForensicReport::analyze(&corrupted, &key());
+        assert_eq!(report.blocks_verified, 0);
+        assert!(matches!(&report.block_stream, CheckResult::Failed(r) if r.contains("block 0")));
+
+        let report = ForensicReport::analyze(&data[..100], &key());
+        assert!(matches!(report.header, CheckResult::Failed(_)));
+        assert!(report.to_string().contains("Outer header: failed"));
+    }
+}
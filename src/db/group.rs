@@ -4,33 +4,30 @@ use uuid::Uuid;
 
 use crate::db::{
     entry::Entry,
-    node::{Node, NodeIter, NodeRef, NodeRefMut},
-    CustomData, Times,
+    node::{EntryIterMut, EntryPathIterMut, Node, NodeIter, NodePathIter, NodeRef, NodeRefMut},
+    CustomData, CustomDataItem, Meta, ProtectedValue, Times,
 };
 
+#[cfg(feature = "collation")]
+use crate::db::collation::{CollationError, TitleCollator};
+
 #[cfg(feature = "_merge")]
 use crate::db::merge::{MergeError, MergeEvent, MergeEventType, MergeLog};
 
 #[cfg(feature = "_merge")]
 pub(crate) type NodeLocation = Vec<Uuid>;
 
+/// Key of the group custom data item holding the number of days after which entries created in
+/// the group expire
+pub const DEFAULT_EXPIRY_DAYS_KEY: &str = "KPRS_DEFAULT_EXPIRY_DAYS";
+
 pub enum SearchField {
-    #[cfg(any(test, feature = "_merge"))]
-    UUID,
     Title,
 }
 
 impl SearchField {
     pub(crate) fn matches(&self, node: &Node, field_value: &str) -> bool {
         match self {
-            #[cfg(any(test, feature = "_merge"))]
-            SearchField::UUID => {
-                let uuid = match node {
-                    Node::Entry(e) => e.uuid,
-                    Node::Group(g) => g.uuid,
-                };
-                uuid.to_string() == field_value
-            }
             SearchField::Title => {
                 let title = match node {
                     Node::Entry(e) => e.get_title(),
@@ -47,7 +44,7 @@ impl SearchField {
 
 /// A database group with child groups and entries
 #[derive(Debug, Default, Eq, PartialEq, Clone)]
-#[cfg_attr(feature = "serialization", derive(serde::Serialize))]
+#[cfg_attr(feature = "serialization", derive(serde::Serialize, serde::Deserialize))]
 pub struct Group {
     /// The unique identifier of the group
     pub uuid: Uuid,
@@ -58,6 +55,9 @@ pub struct Group {
     /// Notes for the group
     pub notes: Option<String>,
 
+    /// Tags of the group
+    pub tags: Vec<String>,
+
     /// ID of the group's icon
     pub icon_id: Option<usize>,
 
@@ -91,6 +91,66 @@ pub struct Group {
     // TODO figure out what that is supposed to mean. According to the KeePass sourcecode, it has
     // something to do with restoring selected items when re-opening a database.
     pub last_top_visible_entry: Option<Uuid>,
+
+    /// The group the group was in before it was last moved, e.g. into the recycle bin
+    pub previous_parent_group: Option<Uuid>,
+
+    /// Child entries that could not be parsed and were set aside instead of failing the whole
+    /// database. Quarantined entries are written back unchanged when saving the database, after
+    /// the children of the group. A malformed entry in the history of an entry is not quarantined
+    /// on its own, it quarantines the entry it belongs to.
+    pub quarantined: Vec<QuarantinedNode>,
+
+    /// XML elements of the group unknown to this library, written back when saving the database
+    pub unknown_elements: Vec<UnknownElement>,
+}
+
+/// An entry that failed to parse, kept together with its raw XML fragment
+#[derive(Debug, Default, Eq, PartialEq, Clone)]
+#[cfg_attr(feature = "serialization", derive(serde::Serialize, serde::Deserialize))]
+pub struct QuarantinedNode {
+    /// The UUID of the entry, if it could be recovered from the fragment
+    pub uuid: Option<Uuid>,
+
+    /// The raw XML fragment of the entry. Elements marked as `Protected="True"` are left empty,
+    /// their content is kept in `protected_values`.
+    pub raw_xml: String,
+
+    /// The decrypted content of the protected elements in the fragment, in document order, which
+    /// is encrypted again when saving
+    #[cfg_attr(
+        feature = "serialization",
+        serde(with = "crate::db::serialization::protected_values")
+    )]
+    pub protected_values: Vec<ProtectedValue>,
+
+    /// A description of the error that caused the entry to be quarantined
+    pub error: String,
+}
+
+/// An XML element unknown to this library, such as data of a KeePass plugin or a field of a newer
+/// KDBX version, kept verbatim so that it is not lost when the database is saved
+///
+/// Only unknown children of entries, groups and the metadata are kept. Unknown attributes of known
+/// elements are dropped, unknown children nested deeper, e.g. in `AutoType`, are skipped and
+/// reported as `ParseWarning::IgnoredElement`, and unknown children of `Root` fail the parsing.
+#[derive(Debug, Default, Eq, PartialEq, Clone)]
+#[cfg_attr(feature = "serialization", derive(serde::Serialize, serde::Deserialize))]
+pub struct UnknownElement {
+    /// The name of the element
+    pub name: String,
+
+    /// The XML fragment of the element. Elements marked as `Protected="True"` are left empty,
+    /// their content is kept in `protected_values`.
+    pub xml: String,
+
+    /// The decrypted content of the protected elements in the fragment, in document order, which
+    /// is encrypted again when saving
+    #[cfg_attr(
+        feature = "serialization",
+        serde(with = "crate::db::serialization::protected_values")
+    )]
+    pub protected_values: Vec<ProtectedValue>,
 }
 
 impl Group {
@@ -108,6 +168,123 @@ impl Group {
         self.children.push(node.into());
     }
 
+    /// Add a child node, for building groups in a single expression:
+    ///
+    /// ```
+    /// use keepass::db::{Entry, Group};
+    ///
+    /// let group = Group::new("Internet")
+    ///     .with_notes("Web accounts")
+    ///     .with_child(Group::new("Email").with_child(Entry::new().with_title("GMail")));
+    /// assert!(group.get(&["Email", "GMail"]).is_some());
+    /// ```
+    pub fn with_child(mut self, node: impl Into<Node>) -> Self {
+        self.add_child(node);
+        self
+    }
+
+    /// Set the notes of the group
+    pub fn with_notes(mut self, notes: &str) -> Self {
+        self.notes = Some(notes.to_string());
+        self
+    }
+
+    /// Set the ID of the group's icon
+    pub fn with_icon_id(mut self, icon_id: usize) -> Self {
+        self.icon_id = Some(icon_id);
+        self
+    }
+
+    /// Let the group expire at `time`
+    pub fn with_expiry(mut self, time: chrono::NaiveDateTime) -> Self {
+        self.times.expires = true;
+        self.times.set_expiry(time);
+        self
+    }
+
+    /// Create a new entry in this group, applying the group's defaults for new entries such as
+    /// `default_expiry_days`.
+    pub fn create_entry(&mut self) -> &mut Entry {
+        let mut entry = Entry::new();
+
+        if let Some(days) = self.default_expiry_days() {
+            entry.times.expires = true;
+            entry
+                .times
+                .set_expiry(Times::now() + chrono::Duration::days(i64::from(days)));
+        }
+
+        self.children.push(Node::Entry(entry));
+        match self.children.last_mut() {
+            Some(Node::Entry(e)) => e,
+            _ => unreachable!("an entry was just added"),
+        }
+    }
+
+    /// The number of days after which entries created with `create_entry` expire, if set
+    pub fn default_expiry_days(&self) -> Option<u32> {
+        self.custom_data
+            .get_protected(DEFAULT_EXPIRY_DAYS_KEY)?
+            .as_str()?
+            .trim()
+            .parse()
+            .ok()
+    }
+
+    /// Set or clear the number of days after which entries created with `create_entry` expire.
+    /// The setting is stored in the group's custom data, so that it is kept in the database file.
+    pub fn set_default_expiry_days(&mut self, days: Option<u32>) {
+        match days {
+            Some(days) => self.set_custom_data(DEFAULT_EXPIRY_DAYS_KEY, &days.to_string(), false),
+            None => self.remove_custom_data(DEFAULT_EXPIRY_DAYS_KEY),
+        };
+    }
+
+    /// Get the text of an item of the custom data of this group, see `CustomData::get`
+    pub fn get_custom_data(&self, key: &str) -> Option<&str> {
+        self.custom_data.get(key)
+    }
+
+    /// Set an item of the custom data of this group, stamping its last modification time, see
+    /// `CustomData::set`
+    pub fn set_custom_data(&mut self, key: &str, value: &str, protected: bool) -> Option<CustomDataItem> {
+        self.custom_data.set(key, value, protected)
+    }
+
+    /// Remove an item of the custom data of this group, returning it
+    pub fn remove_custom_data(&mut self, key: &str) -> Option<CustomDataItem> {
+        self.custom_data.remove(key)
+    }
+
+    /// Sort the direct children of this group by the title of entries and the name of groups.
+    ///
+    /// With the `collation` feature enabled, titles are compared using the language-independent
+    /// Unicode collation order, so that e.g. "Äpfel" sorts next to "Apfel" instead of after "Zebra".
+    /// Without it, titles are compared case-insensitively by code point. Children with the same
+    /// title keep their relative order.
+    pub fn sort_children_by_title(&mut self) {
+        #[cfg(feature = "collation")]
+        {
+            let collator = TitleCollator::root();
+            self.children
+                .sort_by(|a, b| collator.compare(node_title(a), node_title(b)));
+        }
+
+        #[cfg(not(feature = "collation"))]
+        self.children
+            .sort_by_cached_key(|node| node_title(node).to_lowercase());
+    }
+
+    /// Sort the direct children of this group by title, following the collation rules of the
+    /// given locale (e.g. "de", "sv" or "es-u-co-trad").
+    #[cfg(feature = "collation")]
+    pub fn sort_children_by_title_for_locale(&mut self, locale: &str) -> Result<(), CollationError> {
+        let collator = TitleCollator::new(locale)?;
+        self.children
+            .sort_by(|a, b| collator.compare(node_title(a), node_title(b)));
+        Ok(())
+    }
+
     /// Recursively get a Group or Entry reference by specifying a path relative to the current Group
     /// ```
     /// use keepass::{Database, DatabaseKey, db::NodeRef};
@@ -131,9 +308,24 @@ impl Group {
         self.get_internal(&path, SearchField::Title)
     }
 
+    /// Get a Group or Entry by the UUIDs of the nodes on the path to it, relative to the current
+    /// Group
     #[cfg(any(test, feature = "_merge"))]
-    pub(crate) fn get_by_uuid<'a, T: AsRef<str>>(&'a self, path: &[T]) -> Option<NodeRef<'a>> {
-        self.get_internal(&path, SearchField::UUID)
+    pub(crate) fn get_by_uuid<'a>(&'a self, path: &[Uuid]) -> Option<NodeRef<'a>> {
+        let (last, groups) = match path.split_last() {
+            Some(split) => split,
+            None => return Some(NodeRef::Group(self)),
+        };
+
+        let mut group = self;
+        for uuid in groups {
+            group = group.groups().into_iter().find(|g| g.uuid == *uuid)?;
+        }
+        group
+            .children
+            .iter()
+            .find(|n| n.uuid() == *last)
+            .map(Node::as_ref)
     }
 
     fn get_internal<'a, T: AsRef<str>>(&'a self, path: &[T], search_field: SearchField) -> Option<NodeRef<'a>> {
@@ -168,9 +360,27 @@ impl Group {
         self.get_mut_internal(path, SearchField::Title)
     }
 
+    /// Get a mutable reference to a Group or Entry by the UUIDs of the nodes on the path to it,
+    /// see `Group::get_by_uuid`
     #[cfg(any(test, feature = "_merge"))]
-    pub(crate) fn get_by_uuid_mut<'a, T: AsRef<str>>(&'a mut self, path: &[T]) -> Option<NodeRefMut<'a>> {
-        self.get_mut_internal(path, SearchField::UUID)
+    pub(crate) fn get_by_uuid_mut<'a>(&'a mut self, path: &[Uuid]) -> Option<NodeRefMut<'a>> {
+        let (last, groups) = match path.split_last() {
+            Some(split) => split,
+            None => return Some(NodeRefMut::Group(self)),
+        };
+
+        let mut group = self;
+        for uuid in groups {
+            group = group.children.iter_mut().find_map(|n| match n {
+                Node::Group(g) if g.uuid == *uuid => Some(g),
+                _ => None,
+            })?;
+        }
+        group
+            .children
+            .iter_mut()
+            .find(|n| n.uuid() == *last)
+            .map(Node::as_mut)
     }
 
     fn get_mut_internal<'a, T: AsRef<str>>(
@@ -205,10 +415,82 @@ impl Group {
         }
     }
 
+    /// Find a Group or Entry with the given UUID anywhere below this Group, including the Group
+    /// itself
+    pub fn find_by_uuid(&self, uuid: &Uuid) -> Option<NodeRef<'_>> {
+        if self.uuid == *uuid {
+            return Some(NodeRef::Group(self));
+        }
+        self.children.iter().find_map(|child| match child {
+            Node::Group(g) => g.find_by_uuid(uuid),
+            Node::Entry(e) if e.uuid == *uuid => Some(NodeRef::Entry(e)),
+            Node::Entry(_) => None,
+        })
+    }
+
+    /// Find a Group or Entry with the given UUID anywhere below this Group for changing it, see
+    /// `Group::find_by_uuid`
+    pub fn find_by_uuid_mut(&mut self, uuid: &Uuid) -> Option<NodeRefMut<'_>> {
+        if self.uuid == *uuid {
+            return Some(NodeRefMut::Group(self));
+        }
+        self.children.iter_mut().find_map(|child| match child {
+            Node::Group(g) => g.find_by_uuid_mut(uuid),
+            Node::Entry(e) if e.uuid == *uuid => Some(NodeRefMut::Entry(e)),
+            Node::Entry(_) => None,
+        })
+    }
+
+    /// Find a Group with the given UUID anywhere below this Group, see `Group::find_by_uuid`
+    pub(crate) fn group_by_uuid(&self, uuid: &Uuid) -> Option<&Group> {
+        match self.find_by_uuid(uuid)? {
+            NodeRef::Group(g) => Some(g),
+            NodeRef::Entry(_) => None,
+        }
+    }
+
+    pub(crate) fn group_by_uuid_mut(&mut self, uuid: &Uuid) -> Option<&mut Group> {
+        match self.find_by_uuid_mut(uuid)? {
+            NodeRefMut::Group(g) => Some(g),
+            NodeRefMut::Entry(_) => None,
+        }
+    }
+
+    /// Find an Entry with the given UUID anywhere below this Group, see `Group::find_by_uuid`
+    pub(crate) fn entry_by_uuid(&self, uuid: &Uuid) -> Option<&Entry> {
+        match self.find_by_uuid(uuid)? {
+            NodeRef::Entry(e) => Some(e),
+            NodeRef::Group(_) => None,
+        }
+    }
+
+    pub(crate) fn entry_by_uuid_mut(&mut self, uuid: &Uuid) -> Option<&mut Entry> {
+        match self.find_by_uuid_mut(uuid)? {
+            NodeRefMut::Entry(e) => Some(e),
+            NodeRefMut::Group(_) => None,
+        }
+    }
+
+    /// The UUID of the Group directly containing the node with the given UUID, anywhere below
+    /// this Group
+    pub(crate) fn parent_of(&self, uuid: &Uuid) -> Option<Uuid> {
+        if self.children.iter().any(|c| c.uuid() == *uuid) {
+            return Some(self.uuid);
+        }
+        self.groups().into_iter().find_map(|g| g.parent_of(uuid))
+    }
+
+    /// Remove the node with the given UUID from anywhere below this Group and return it
+    pub(crate) fn take_by_uuid(&mut self, uuid: &Uuid) -> Option<Node> {
+        if let Some(index) = self.children.iter().position(|c| c.uuid() == *uuid) {
+            return Some(self.children.remove(index));
+        }
+        self.groups_mut().into_iter().find_map(|g| g.take_by_uuid(uuid))
+    }
+
     #[cfg(feature = "_merge")]
     pub(crate) fn find_group(&self, path: &Vec<Uuid>) -> Option<&Group> {
-        let path: Vec<String> = path.iter().map(|p| p.to_string()).collect();
-        let node_ref = match self.get_by_uuid(&path) {
+        let node_ref = match self.get_by_uuid(path) {
             Some(n) => n,
             None => return None,
         };
@@ -220,8 +502,7 @@ impl Group {
 
     #[cfg(feature = "_merge")]
     pub(crate) fn find_entry(&self, path: &Vec<Uuid>) -> Option<&Entry> {
-        let path: Vec<String> = path.iter().map(|p| p.to_string()).collect();
-        let node_ref = match self.get_by_uuid(&path) {
+        let node_ref = match self.get_by_uuid(path) {
             Some(n) => n,
             None => return None,
         };
@@ -233,8 +514,7 @@ impl Group {
 
     #[cfg(feature = "_merge")]
     pub(crate) fn find_entry_mut(&mut self, path: &Vec<Uuid>) -> Option<&mut Entry> {
-        let path: Vec<String> = path.iter().map(|p| p.to_string()).collect();
-        let node_ref = match self.get_by_uuid_mut(&path) {
+        let node_ref = match self.get_by_uuid_mut(path) {
             Some(n) => n,
             None => return None,
         };
@@ -246,8 +526,7 @@ impl Group {
 
     #[cfg(feature = "_merge")]
     pub(crate) fn find_group_mut(&mut self, path: &Vec<Uuid>) -> Option<&mut Group> {
-        let path: Vec<String> = path.iter().map(|p| p.to_string()).collect();
-        let node_ref = match self.get_by_uuid_mut(&path) {
+        let node_ref = match self.get_by_uuid_mut(path) {
             Some(n) => n,
             None => return None,
         };
@@ -277,6 +556,17 @@ impl Group {
         self.times.get_expiry()
     }
 
+    /// Trim the histories of all entries in this group and its subgroups, see `Entry::trim_history`
+    pub(crate) fn trim_histories(&mut self, meta: &Meta) -> usize {
+        self.children
+            .iter_mut()
+            .map(|node| match node {
+                Node::Entry(e) => e.trim_history(meta),
+                Node::Group(g) => g.trim_histories(meta),
+            })
+            .sum()
+    }
+
     pub fn entries(&self) -> Vec<&Entry> {
         let mut response: Vec<&Entry> = vec![];
         for node in &self.children {
@@ -351,6 +641,20 @@ impl Group {
         )));
     }
 
+    /// Reorder the children of this group to follow `order`. Children that are not listed keep
+    /// their relative order and are moved behind the listed ones.
+    #[cfg(feature = "_merge")]
+    pub(crate) fn reorder_children(&mut self, order: &[Uuid]) {
+        let position = |node: &Node| {
+            order
+                .iter()
+                .position(|uuid| *uuid == node.uuid())
+                .unwrap_or(order.len())
+        };
+        // sort_by_key is stable, so unlisted children stay in their current order
+        self.children.sort_by_key(position);
+    }
+
     #[cfg(feature = "_merge")]
     pub(crate) fn find_node_location(&self, id: Uuid) -> Option<NodeLocation> {
         let mut current_location = vec![self.uuid];
@@ -418,6 +722,7 @@ impl Group {
 
         self.name = other.name.clone();
         self.notes = other.notes.clone();
+        self.tags = other.tags.clone();
         self.icon_id = other.icon_id.clone();
         self.custom_icon_uuid = other.custom_icon_uuid.clone();
         self.custom_data = other.custom_data.clone();
@@ -434,6 +739,7 @@ impl Group {
         self.enable_autotype = other.enable_autotype.clone();
         self.enable_searching = other.enable_searching.clone();
         self.last_top_visible_entry = other.last_top_visible_entry.clone();
+        self.previous_parent_group = other.previous_parent_group;
 
         log.events.push(MergeEvent {
             event_type: MergeEventType::GroupUpdated,
@@ -457,10 +763,53 @@ impl Group {
     }
 }
 
+fn node_title(node: &Node) -> &str {
+    match node {
+        Node::Group(g) => &g.name,
+        Node::Entry(e) => e.get_title().unwrap_or_default(),
+    }
+}
+
 impl<'a> Group {
     pub fn iter(&'a self) -> NodeIter<'a> {
         (&self).into_iter()
     }
+
+    /// Iterate over all entries below this group, breadth-first, for changing them. Groups are
+    /// not part of the iteration, as a group would also give access to the entries after it; use
+    /// `groups_mut` to change them.
+    pub fn iter_mut(&'a mut self) -> EntryIterMut<'a> {
+        self.into_iter()
+    }
+
+    /// Iterate over this group and all nodes below it like `iter`, together with the names of the
+    /// groups leading from this group to the group each node is in. The path is empty for this
+    /// group and its direct children.
+    ///
+    /// ```
+    /// use keepass::db::{Entry, Group, NodeRef};
+    ///
+    /// let mut root = Group::new("Root");
+    /// let mut work = Group::new("Work");
+    /// work.add_child(Entry::new());
+    /// root.add_child(work);
+    ///
+    /// let paths: Vec<_> = root
+    ///     .iter_with_paths()
+    ///     .filter(|(_, node)| matches!(node, NodeRef::Entry(_)))
+    ///     .map(|(path, _)| path)
+    ///     .collect();
+    /// assert_eq!(paths, vec![vec!["Work"]]);
+    /// ```
+    pub fn iter_with_paths(&'a self) -> NodePathIter<'a> {
+        NodePathIter::new(self)
+    }
+
+    /// Iterate over all entries below this group like `iter_mut`, together with the names of the
+    /// groups leading from this group to the group each entry is in
+    pub fn iter_mut_with_paths(&'a mut self) -> EntryPathIterMut<'a> {
+        EntryPathIterMut::new(self)
+    }
 }
 
 impl<'a> IntoIterator for &'a Group {
@@ -475,10 +824,21 @@ impl<'a> IntoIterator for &'a Group {
     }
 }
 
+impl<'a> IntoIterator for &'a mut Group {
+    type Item = &'a mut Entry;
+    type IntoIter = EntryIterMut<'a>;
+
+    fn into_iter(self) -> EntryIterMut<'a> {
+        EntryIterMut::new(self)
+    }
+}
+
 #[cfg(test)]
 mod group_tests {
+    use uuid::Uuid;
+
     use super::Group;
-    use crate::db::Entry;
+    use crate::db::{Entry, Node, NodeRef, NodeRefMut, Times};
     use crate::Database;
 
     #[test]
@@ -519,6 +879,42 @@ mod group_tests {
         assert!(db.root.get_mut(&[]).is_some());
     }
 
+    #[test]
+    fn find_by_uuid() {
+        let mut db = Database::new(Default::default());
+
+        let mut internet = Group::new("Internet");
+        let mut email = Group::new("Email");
+        let gmail = Entry::new();
+        let gmail_uuid = gmail.uuid;
+        email.add_child(gmail);
+        let email_uuid = email.uuid;
+        internet.add_child(email);
+        db.root.add_child(internet);
+
+        assert!(matches!(db.find_by_uuid(&gmail_uuid), Some(NodeRef::Entry(e)) if e.uuid == gmail_uuid));
+        assert!(matches!(db.find_by_uuid(&email_uuid), Some(NodeRef::Group(g)) if g.name == "Email"));
+        assert!(matches!(db.find_by_uuid(&db.root.uuid), Some(NodeRef::Group(_))));
+        assert!(db.find_by_uuid(&Uuid::new_v4()).is_none());
+
+        if let Some(NodeRefMut::Entry(e)) = db.find_by_uuid_mut(&gmail_uuid) {
+            e.tags.push("mail".to_string());
+        }
+        if let Some(NodeRefMut::Group(g)) = db.root.get_mut(&["Internet", "Email"]) {
+            g.name = "Mail".to_string();
+        }
+        match db.root.get(&["Internet", "Mail"]) {
+            Some(NodeRef::Group(g)) => assert_eq!(g.entries()[0].tags, vec!["mail"]),
+            _ => panic!("the renamed group was not found"),
+        }
+
+        assert_eq!(db.root.parent_of(&gmail_uuid), Some(email_uuid));
+        assert!(db.root.entry_by_uuid(&email_uuid).is_none());
+        assert_eq!(db.root.group_by_uuid(&email_uuid).unwrap().name, "Mail");
+        assert!(matches!(db.root.take_by_uuid(&gmail_uuid), Some(Node::Entry(_))));
+        assert!(db.root.entry_by_uuid(&gmail_uuid).is_none());
+    }
+
     #[test]
     fn get_by_uuid() {
         let mut db = Database::new(Default::default());
@@ -532,31 +928,16 @@ mod group_tests {
         general_group.add_child(sample_entry.clone());
         db.root.add_child(general_group.clone());
 
-        let general_group_uuid = general_group.uuid.to_string();
-        let sample_entry_uuid = sample_entry.uuid.to_string();
-        let invalid_uuid = uuid::Uuid::new_v4().to_string();
-
-        // Testing with references to the UUIDs
-        let group_path: [&str; 1] = [general_group_uuid.as_ref()];
-        let entry_path: [&str; 2] = [general_group_uuid.as_ref(), sample_entry_uuid.as_ref()];
-        let invalid_path: [&str; 1] = [invalid_uuid.as_ref()];
-        let empty_path: [&str; 0] = [];
-
-        assert!(db.root.get_by_uuid(&group_path).is_some());
-        assert!(db.root.get_by_uuid(&entry_path).is_some());
-        assert!(db.root.get_by_uuid(&invalid_path).is_none());
-        assert!(db.root.get_by_uuid(&empty_path).is_some());
-
-        // Testing with owned versions of the UUIDs.
-        let group_path = vec![general_group_uuid.clone()];
-        let entry_path = vec![general_group_uuid.clone(), sample_entry_uuid.clone()];
-        let invalid_path = vec![invalid_uuid.clone()];
-        let empty_path: Vec<String> = vec![];
+        let invalid_uuid = Uuid::new_v4();
 
-        assert!(db.root.get_by_uuid(&group_path).is_some());
-        assert!(db.root.get_by_uuid(&entry_path).is_some());
-        assert!(db.root.get_by_uuid(&invalid_path).is_none());
-        assert!(db.root.get_by_uuid(&empty_path).is_some());
+        assert!(db.root.get_by_uuid(&[general_group.uuid]).is_some());
+        assert!(db
+            .root
+            .get_by_uuid(&[general_group.uuid, sample_entry.uuid])
+            .is_some());
+        assert!(db.root.get_by_uuid(&[invalid_uuid]).is_none());
+        assert!(db.root.get_by_uuid(&[sample_entry.uuid]).is_none());
+        assert!(db.root.get_by_uuid(&[]).is_some());
     }
 
     #[test]
@@ -572,30 +953,136 @@ mod group_tests {
         general_group.add_child(sample_entry.clone());
         db.root.add_child(general_group.clone());
 
-        let general_group_uuid = general_group.uuid.to_string();
-        let sample_entry_uuid = sample_entry.uuid.to_string();
-        let invalid_uuid = uuid::Uuid::new_v4().to_string();
-
-        // Testing with references to the UUIDs
-        let group_path: [&str; 1] = [general_group_uuid.as_ref()];
-        let entry_path: [&str; 2] = [general_group_uuid.as_ref(), sample_entry_uuid.as_ref()];
-        let invalid_path: [&str; 1] = [invalid_uuid.as_ref()];
-        let empty_path: [&str; 0] = [];
-
-        assert!(db.root.get_by_uuid_mut(&group_path).is_some());
-        assert!(db.root.get_by_uuid_mut(&entry_path).is_some());
-        assert!(db.root.get_by_uuid_mut(&invalid_path).is_none());
-        assert!(db.root.get_by_uuid_mut(&empty_path).is_some());
-
-        // Testing with owned versions of the UUIDs.
-        let group_path = vec![general_group_uuid.clone()];
-        let entry_path = vec![general_group_uuid.clone(), sample_entry_uuid.clone()];
-        let invalid_path = vec![invalid_uuid.clone()];
-        let empty_path: Vec<String> = vec![];
-
-        assert!(db.root.get_by_uuid_mut(&group_path).is_some());
-        assert!(db.root.get_by_uuid_mut(&entry_path).is_some());
-        assert!(db.root.get_by_uuid_mut(&invalid_path).is_none());
-        assert!(db.root.get_by_uuid_mut(&empty_path).is_some());
+        let invalid_uuid = Uuid::new_v4();
+
+        assert!(db.root.get_by_uuid_mut(&[general_group.uuid]).is_some());
+        assert!(db
+            .root
+            .get_by_uuid_mut(&[general_group.uuid, sample_entry.uuid])
+            .is_some());
+        assert!(db.root.get_by_uuid_mut(&[invalid_uuid]).is_none());
+        assert!(db.root.get_by_uuid_mut(&[sample_entry.uuid]).is_none());
+        assert!(db.root.get_by_uuid_mut(&[]).is_some());
+    }
+
+    fn child_names(group: &Group) -> Vec<&str> {
+        group.children.iter().map(super::node_title).collect()
+    }
+
+    #[test]
+    fn sort_children_by_title() {
+        let mut group = Group::new("root");
+        for title in ["banana", "Zebra", "Äpfel", "apple"] {
+            let mut entry = Entry::new();
+            entry.fields.insert(
+                "Title".to_string(),
+                crate::db::Value::Unprotected(title.to_string()),
+            );
+            group.add_child(entry);
+        }
+        group.add_child(Group::new("Cherry"));
+
+        group.sort_children_by_title();
+
+        #[cfg(feature = "collation")]
+        assert_eq!(
+            child_names(&group),
+            vec!["Äpfel", "apple", "banana", "Cherry", "Zebra"]
+        );
+
+        #[cfg(not(feature = "collation"))]
+        assert_eq!(
+            child_names(&group),
+            vec!["apple", "banana", "Cherry", "Zebra", "Äpfel"]
+        );
+    }
+
+    #[cfg(feature = "collation")]
+    #[test]
+    fn sort_children_by_title_for_locale() {
+        let mut group = Group::new("root");
+        for name in ["ö", "z", "o"] {
+            group.add_child(Group::new(name));
+        }
+
+        // Swedish sorts "ö" after "z", German treats it like "o"
+        group.sort_children_by_title_for_locale("sv").unwrap();
+        assert_eq!(child_names(&group), vec!["o", "z", "ö"]);
+
+        group.sort_children_by_title_for_locale("de").unwrap();
+        assert_eq!(child_names(&group), vec!["o", "ö", "z"]);
+
+        assert!(group.sort_children_by_title_for_locale("not a locale!").is_err());
+    }
+
+    #[test]
+    fn create_entry_with_default_expiry() {
+        let mut group = Group::new("Contractors");
+
+        let entry = group.create_entry();
+        assert!(!entry.times.expires);
+
+        group.set_default_expiry_days(Some(90));
+        assert_eq!(group.default_expiry_days(), Some(90));
+
+        let entry = group.create_entry();
+        assert!(entry.times.expires);
+        let expires_in = *entry.get_expiry_time().unwrap() - Times::now();
+        assert!(expires_in > chrono::Duration::days(89));
+        assert!(expires_in <= chrono::Duration::days(90));
+
+        assert_eq!(group.entries().len(), 2);
+
+        group.set_default_expiry_days(None);
+        assert_eq!(group.default_expiry_days(), None);
+        assert!(group.custom_data.items.is_empty());
+    }
+
+    #[test]
+    fn test_iterators_with_paths() {
+        let mut root = Group::new("Root");
+        let mut work = Group::new("Work");
+        let mut servers = Group::new("Servers");
+        servers.add_child(Entry::new());
+        work.add_child(servers);
+        work.add_child(Entry::new());
+        root.add_child(work);
+        root.add_child(Entry::new());
+
+        let paths: Vec<(Vec<&str>, bool)> = root
+            .iter_with_paths()
+            .map(|(path, node)| (path, matches!(node, NodeRef::Entry(_))))
+            .collect();
+        assert_eq!(
+            paths,
+            vec![
+                (vec![], false),
+                (vec![], false),
+                (vec![], true),
+                (vec!["Work"], false),
+                (vec!["Work"], true),
+                (vec!["Work", "Servers"], true),
+            ]
+        );
+
+        for (path, entry) in root.iter_mut_with_paths() {
+            if path.first() == Some(&"Work") {
+                entry.tags.push("work".to_string());
+            }
+        }
+        let tagged: Vec<usize> = root
+            .iter()
+            .filter_map(|node| match node {
+                NodeRef::Entry(e) => Some(e.tags.len()),
+                NodeRef::Group(_) => None,
+            })
+            .collect();
+        assert_eq!(tagged, vec![0, 1, 1]);
+
+        for entry in &mut root {
+            entry.tags.clear();
+        }
+        assert_eq!(root.iter_mut().filter(|e| !e.tags.is_empty()).count(), 0);
+        assert_eq!(root.iter_mut().count(), 3);
     }
 }
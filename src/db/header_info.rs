@@ -0,0 +1,101 @@
+//! Settings of a database that can be read without the key
+//!
+//! The outer header of a KDBX file is not encrypted, since it holds what is needed to derive the
+//! key. Applications can show how a database is protected before asking for the password:
+//!
+//! ```
+//! use keepass::{config::KdfConfig, Database};
+//!
+//! let mut file = std::fs::File::open("tests/resources/test_db_kdbx4_with_password_argon2.kdbx")?;
+//! let info = Database::get_header_info(&mut file)?;
+//!
+//! if let KdfConfig::Argon2 { iterations, memory, .. } | KdfConfig::Argon2id { iterations, memory, .. } =
+//!     info.kdf_config
+//! {
+//!     println!("This database uses Argon2, {} MiB, {} iterations", memory / 1024 / 1024, iterations);
+//! }
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::io::Read;
+
+use crate::{
+    config::{CompressionConfig, KdfConfig, OuterCipherConfig},
+    db::Database,
+    error::DatabaseOpenError,
+    format::{kdbx3, kdbx4, read_outer_header_data, DatabaseVersion, SUPPORTED_KDBX_VERSIONS},
+};
+
+/// The settings stored in the outer header of a database, see `Database::get_header_info`
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serialization", derive(serde::Serialize))]
+pub struct HeaderInfo {
+    pub version: DatabaseVersion,
+
+    /// The cipher encrypting the payload. `OuterCipherConfig::uuid` gives the UUID it is stored
+    /// as in the header.
+    pub outer_cipher_config: OuterCipherConfig,
+
+    pub compression_config: CompressionConfig,
+
+    /// The key derivation function and its parameters. Seeds and Argon2 secrets are not included.
+    pub kdf_config: KdfConfig,
+}
+
+impl Database {
+    /// Read the settings in the outer header of a KDBX 3 or KDBX 4 database without the key. Only
+    /// the header is read from `source`.
+    pub fn get_header_info(source: &mut dyn Read) -> Result<HeaderInfo, DatabaseOpenError> {
+        let header_data = read_outer_header_data(source)?;
+
+        match DatabaseVersion::parse(&header_data)? {
+            DatabaseVersion::KDB3(_) => kdbx3::parse_header_info(&header_data),
+            DatabaseVersion::KDB4(_) => kdbx4::parse_header_info(&header_data),
+            version @ (DatabaseVersion::KDB(_) | DatabaseVersion::KDB2(_)) => {
+                Err(DatabaseOpenError::IncompatibleVersion {
+                    found: version.to_string(),
+                    supported: SUPPORTED_KDBX_VERSIONS,
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod header_info_tests {
+    use crate::{
+        config::{CompressionConfig, KdfConfig, OuterCipherConfig},
+        error::DatabaseOpenError,
+        format::DatabaseVersion,
+        Database,
+    };
+
+    #[test]
+    fn test_get_header_info() -> Result<(), DatabaseOpenError> {
+        let mut file = std::fs::File::open("tests/resources/test_db_kdbx4_with_password_argon2.kdbx")?;
+        let info = Database::get_header_info(&mut file)?;
+        assert!(matches!(info.version, DatabaseVersion::KDB4(_)));
+        assert!(matches!(info.kdf_config, KdfConfig::Argon2 { .. }));
+
+        let mut file = std::fs::File::open("tests/resources/test_db_with_password.kdbx")?;
+        let info = Database::get_header_info(&mut file)?;
+        assert_eq!(info.version, DatabaseVersion::KDB3(1));
+        assert_eq!(info.outer_cipher_config, OuterCipherConfig::AES256);
+        assert_eq!(
+            info.outer_cipher_config.uuid().to_string(),
+            "31c1f2e6-bf71-4350-be58-05216afc5aff"
+        );
+        assert_eq!(info.compression_config, CompressionConfig::GZip);
+        assert!(matches!(info.kdf_config, KdfConfig::Aes { .. }));
+
+        // the payload is not read
+        let data = std::fs::read("tests/resources/test_db_with_password.kdbx")?;
+        let mut source = &data[..];
+        Database::get_header_info(&mut source)?;
+        assert!(!source.is_empty());
+
+        assert!(Database::get_header_info(&mut &data[..100]).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,262 @@
+//! Assigning downloaded favicons to entries
+//!
+//! The crate does not do any networking itself. Callers implement `IconFetcher` using the HTTP
+//! client of their choice and pass it to `Database::assign_icons`, which decides which sites to
+//! fetch and stores the results as custom icons, similar to the "Download favicons" feature of
+//! KeePassXC.
+
+use std::collections::HashMap;
+
+use uuid::Uuid;
+
+use crate::{
+    db::{Database, Entry, NodeRef, Times},
+    url_match::UrlParts,
+};
+
+/// Downloads the favicon of a website
+pub trait IconFetcher {
+    type Error: std::fmt::Display;
+
+    /// Fetch the icon for a site, given as its origin (e.g. `https://example.com`). Returns the
+    /// raw image data, or `None` if the site has no icon.
+    fn fetch(&mut self, origin: &str) -> Result<Option<Vec<u8>>, Self::Error>;
+}
+
+/// A site for which fetching the icon failed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IconFetchFailure {
+    pub origin: String,
+    pub error: String,
+}
+
+/// The outcome of `Database::assign_icons`
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct IconAssignment {
+    /// Entries that got a custom icon assigned
+    pub assigned: Vec<Uuid>,
+
+    /// Custom icons that were added to the database
+    pub added_icons: Vec<Uuid>,
+
+    /// Sites for which the fetcher returned an error or an image that is not a valid PNG, see
+    /// `Database::add_custom_icon`
+    pub failed: Vec<IconFetchFailure>,
+}
+
+impl Database {
+    /// Fetch favicons for all entries that do not have a custom icon yet, based on their URL or, if
+    /// they have no URL, a title that looks like a domain name.
+    ///
+    /// Every site is only fetched once, and the images are added with `Database::add_custom_icon`,
+    /// which stores identical images as a single custom icon.
+    pub fn assign_icons<F: IconFetcher>(&mut self, fetcher: &mut F) -> IconAssignment {
+        let mut assignment = IconAssignment::default();
+        let mut icons_by_origin: HashMap<String, Option<Uuid>> = HashMap::new();
+
+        let missing: Vec<(Uuid, String)> = self
+            .root
+            .iter()
+            .filter_map(|node| match node {
+                NodeRef::Entry(e) if e.custom_icon_uuid.is_none() => Some((e.uuid, entry_origin(e)?)),
+                _ => None,
+            })
+            .collect();
+
+        for (entry, origin) in missing {
+            let icon = match icons_by_origin.get(&origin) {
+                Some(icon) => *icon,
+                None => {
+                    let icon = match fetcher.fetch(&origin) {
+                        Ok(Some(data)) if !data.is_empty() => {
+                            self.add_fetched_icon(data, &origin, &mut assignment)
+                        }
+                        Ok(_) => None,
+                        Err(e) => {
+                            assignment.failed.push(IconFetchFailure {
+                                origin: origin.clone(),
+                                error: e.to_string(),
+                            });
+                            None
+                        }
+                    };
+                    icons_by_origin.insert(origin, icon);
+                    icon
+                }
+            };
+
+            if let (Some(uuid), Some(entry)) = (icon, self.root.entry_by_uuid_mut(&entry)) {
+                entry.custom_icon_uuid = Some(uuid);
+                entry.times.set_last_modification(Times::now());
+                assignment.assigned.push(entry.uuid);
+            }
+        }
+
+        assignment
+    }
+
+    fn add_fetched_icon(
+        &mut self,
+        data: Vec<u8>,
+        origin: &str,
+        assignment: &mut IconAssignment,
+    ) -> Option<Uuid> {
+        let icons_before = self.meta.custom_icons.icons.len();
+        match self.add_custom_icon(data) {
+            Ok(uuid) => {
+                if self.meta.custom_icons.icons.len() > icons_before {
+                    assignment.added_icons.push(uuid);
+                }
+                Some(uuid)
+            }
+            Err(e) => {
+                assignment.failed.push(IconFetchFailure {
+                    origin: origin.to_string(),
+                    error: e.to_string(),
+                });
+                None
+            }
+        }
+    }
+}
+
+/// The origin of the site an entry belongs to, e.g. `https://example.com:8443`
+fn entry_origin(entry: &Entry) -> Option<String> {
+    let (candidate, from_title) = match entry.get_url().map(str::trim).filter(|u| !u.is_empty()) {
+        Some(url) => (url, false),
+        None => (entry.get_title()?.trim(), true),
+    };
+
+    let mut url = UrlParts::parse(candidate)?;
+    let scheme = url.scheme.get_or_insert_with(|| "https".to_string());
+    if scheme != "http" && scheme != "https" {
+        return None;
+    }
+
+    // titles are only used if they look like a domain name
+    let looks_like_host = url.host.contains('.')
+        && url
+            .host
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-');
+    if !looks_like_host || (from_title && url.port.is_some()) {
+        return None;
+    }
+
+    url.origin()
+}
+
+#[cfg(test)]
+mod icons_tests {
+    use std::collections::HashMap;
+
+    use crate::db::{custom_icons::custom_icons_tests::png, Database, Entry, Group, Value};
+
+    use super::{entry_origin, IconFetcher};
+
+    struct FakeFetcher {
+        icons: HashMap<&'static str, Vec<u8>>,
+        requests: Vec<String>,
+    }
+
+    impl IconFetcher for FakeFetcher {
+        type Error = String;
+
+        fn fetch(&mut self, origin: &str) -> Result<Option<Vec<u8>>, String> {
+            self.requests.push(origin.to_string());
+            if origin == "https://broken.example.com" {
+                return Err("connection refused".to_string());
+            }
+            Ok(self.icons.get(origin).cloned())
+        }
+    }
+
+    fn entry(title: &str, url: &str) -> Entry {
+        let mut entry = Entry::new();
+        entry
+            .fields
+            .insert("Title".to_string(), Value::Unprotected(title.to_string()));
+        if !url.is_empty() {
+            entry
+                .fields
+                .insert("URL".to_string(), Value::Unprotected(url.to_string()));
+        }
+        entry
+    }
+
+    #[test]
+    fn test_entry_origin() {
+        assert_eq!(
+            entry_origin(&entry("Mail", "https://Mail.Example.com/inbox?x=1")).as_deref(),
+            Some("https://mail.example.com")
+        );
+        assert_eq!(
+            entry_origin(&entry("Router", "http://admin@192.168.0.1:8080/")).as_deref(),
+            Some("http://192.168.0.1:8080")
+        );
+        assert_eq!(
+            entry_origin(&entry("example.org", "")).as_deref(),
+            Some("https://example.org")
+        );
+        assert_eq!(entry_origin(&entry("My bank", "")), None);
+        assert_eq!(entry_origin(&entry("Files", "ftp://files.example.com")), None);
+    }
+
+    #[test]
+    fn test_assign_icons() {
+        let mut db = Database::new(Default::default());
+        let existing_icon = db.add_custom_icon(png(1, 1, 0, &[0, 0])).unwrap();
+
+        db.root.add_child(entry("Mail", "https://mail.example.com/inbox"));
+        db.root
+            .add_child(entry("Calendar", "https://mail.example.com/calendar"));
+        db.root.add_child(entry("Broken", "https://broken.example.com"));
+        db.root.add_child(entry("Not a PNG", "https://gif.example.com"));
+
+        let mut group = Group::new("Shopping");
+        group.add_child(entry("shop.example.com", ""));
+        group.add_child(entry("Nothing", ""));
+        let mut has_icon = entry("Has icon", "https://other.example.com");
+        has_icon.custom_icon_uuid = Some(existing_icon);
+        group.add_child(has_icon);
+        db.root.add_child(group);
+
+        let mut fetcher = FakeFetcher {
+            icons: HashMap::from([
+                ("https://mail.example.com", png(1, 1, 0, &[0, 255])),
+                ("https://gif.example.com", b"GIF89a".to_vec()),
+                // the same image as an icon already in the database
+                ("https://shop.example.com", png(1, 1, 0, &[0, 0])),
+            ]),
+            requests: Vec::new(),
+        };
+
+        let assignment = db.assign_icons(&mut fetcher);
+
+        assert_eq!(
+            fetcher.requests,
+            vec![
+                "https://mail.example.com",
+                "https://broken.example.com",
+                "https://gif.example.com",
+                "https://shop.example.com"
+            ]
+        );
+        assert_eq!(assignment.assigned.len(), 3);
+        assert_eq!(assignment.added_icons.len(), 1);
+        assert_eq!(assignment.failed.len(), 2);
+        assert_eq!(assignment.failed[0].error, "connection refused");
+        assert_eq!(assignment.failed[1].origin, "https://gif.example.com");
+        assert_eq!(db.meta.custom_icons.icons.len(), 2);
+
+        let entries: Vec<&Entry> = db.root.entries();
+        assert_eq!(entries[0].custom_icon_uuid, Some(assignment.added_icons[0]));
+        assert_eq!(entries[1].custom_icon_uuid, Some(assignment.added_icons[0]));
+        assert_eq!(entries[2].custom_icon_uuid, None);
+        assert_eq!(entries[3].custom_icon_uuid, None);
+
+        let shopping = db.root.groups()[0].entries();
+        assert_eq!(shopping[0].custom_icon_uuid, Some(existing_icon));
+        assert_eq!(shopping[1].custom_icon_uuid, None);
+    }
+}
@@ -0,0 +1,266 @@
+//! Recovering what is left of damaged databases
+//!
+//! When a sync conflict or a failing disk damages a database, `Database::parse` refuses all of it.
+//! `Database::parse_lenient` instead opens as much of a KDBX 4 database as survived: blocks of the
+//! payload whose HMAC does not match are left out, a file that breaks off is read up to where it
+//! does, and the groups and entries of the XML document are kept up to the point where it is
+//! damaged. As the payload is usually compressed, anything after the first damaged block is
+//! usually lost, but everything before it is kept. The key still has to be correct, as nothing can
+//! be decrypted otherwise.
+//!
+//! The `RecoveryReport` tells what had to be left out, so that the recovered database can be
+//! checked before it is saved over anything:
+//!
+//! ```
+//! use keepass::{Database, DatabaseKey};
+//!
+//! let mut data = std::fs::read("tests/resources/test_db_kdbx4_with_password_aes.kdbx")?;
+//! data.truncate(data.len() - 100);
+//!
+//! let (db, report) = Database::parse_lenient(&data, DatabaseKey::new().with_password("demopass"))?;
+//! assert!(!report.is_complete());
+//! eprintln!("{}", report);
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::fmt;
+
+use crate::{
+    db::{Database, NodeRef},
+    error::DatabaseOpenError,
+    format::{kdbx4::parse_lenient, DatabaseVersion},
+    key::DatabaseKey,
+};
+
+/// What `Database::parse_lenient` had to leave out of a damaged database
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serialization", derive(serde::Serialize))]
+pub struct RecoveryReport {
+    /// The SHA-256 hash of the header did not match while its HMAC did, so only the hash itself
+    /// was damaged
+    pub header_hash_mismatch: bool,
+
+    /// Indices of the blocks of the payload whose HMAC did not match. Their data is used anyway,
+    /// as leaving it out would garble everything after it, but may be damaged.
+    pub skipped_blocks: Vec<u64>,
+
+    /// The file ended before the final block of the payload. What there was of the last block is
+    /// used, even though it could not be verified.
+    pub truncated: bool,
+
+    /// Why the payload could not be decompressed to its end
+    pub payload_error: Option<String>,
+
+    /// Where and why the XML document broke off. The elements open at that point were closed
+    /// there, so the groups and the last entry of that path may be missing some of their fields.
+    pub xml_error: Option<String>,
+
+    /// Number of groups recovered, including the root group
+    pub recovered_groups: usize,
+    pub recovered_entries: usize,
+
+    /// Entries that were recovered but could not be parsed, see `Group::quarantined`
+    pub quarantined_entries: usize,
+}
+
+impl RecoveryReport {
+    /// Whether nothing had to be left out
+    pub fn is_complete(&self) -> bool {
+        self.skipped_blocks.is_empty()
+            && !self.truncated
+            && self.payload_error.is_none()
+            && self.xml_error.is_none()
+            && self.quarantined_entries == 0
+    }
+}
+
+impl fmt::Display for RecoveryReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Recovered {} groups and {} entries ({} quarantined)",
+            self.recovered_groups, self.recovered_entries, self.quarantined_entries
+        )?;
+        if self.header_hash_mismatch {
+            writeln!(f, "Header hash: damaged, the header itself is intact")?;
+        }
+        if !self.skipped_blocks.is_empty() {
+            let blocks: Vec<String> = self.skipped_blocks.iter().map(|b| b.to_string()).collect();
+            writeln!(f, "Skipped blocks: {}", blocks.join(", "))?;
+        }
+        if self.truncated {
+            writeln!(f, "The file is truncated")?;
+        }
+        if let Some(e) = &self.payload_error {
+            writeln!(f, "Payload: {}", e)?;
+        }
+        if let Some(e) = &self.xml_error {
+            writeln!(f, "XML: {}", e)?;
+        }
+        Ok(())
+    }
+}
+
+impl Database {
+    /// Open as much of a damaged KDBX 4 database as possible, see the module documentation.
+    /// Other versions fail with `DatabaseOpenError::IncompatibleVersion`.
+    pub fn parse_lenient(
+        data: &[u8],
+        key: DatabaseKey,
+    ) -> Result<(Database, RecoveryReport), DatabaseOpenError> {
+        let (db, mut report) = match DatabaseVersion::parse(data)? {
+            DatabaseVersion::KDB4(_) => parse_lenient(data, &key)?,
+            version => {
+                return Err(DatabaseOpenError::IncompatibleVersion {
+                    found: version.to_string(),
+                    supported: "KDBX4",
+                })
+            }
+        };
+
+        for node in &db.root {
+            match node {
+                NodeRef::Group(g) => {
+                    report.recovered_groups += 1;
+                    report.quarantined_entries += g.quarantined.len();
+                }
+                NodeRef::Entry(_) => report.recovered_entries += 1,
+            }
+        }
+
+        Ok((db, report))
+    }
+}
+
+#[cfg(test)]
+mod lenient_tests {
+    use crate::{
+        db::{Database, NodeRef},
+        error::DatabaseOpenError,
+        DatabaseKey,
+    };
+
+    const PATH: &str = "tests/resources/test_db_kdbx4_with_password_aes.kdbx";
+
+    fn key() -> DatabaseKey {
+        DatabaseKey::new().with_password("demopass")
+    }
+
+    fn titles(db: &Database) -> Vec<String> {
+        db.root
+            .iter()
+            .filter_map(|node| match node {
+                NodeRef::Entry(e) => e.get_title().map(str::to_string),
+                NodeRef::Group(_) => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_intact_database_is_complete() {
+        let data = std::fs::read(PATH).unwrap();
+        let (db, report) = Database::parse_lenient(&data, key()).unwrap();
+
+        assert!(report.is_complete(), "{}", report);
+        let strict = Database::parse(&data, key()).unwrap();
+        assert_eq!((&db.root, &db.meta), (&strict.root, &strict.meta));
+        assert_eq!(report.recovered_entries, titles(&db).len());
+    }
+
+    #[test]
+    fn test_damaged_final_block_is_skipped() {
+        let mut data = std::fs::read(PATH).unwrap();
+        let last = data.len() - 20;
+        data[last] ^= 1;
+        assert!(Database::parse(&data, key()).is_err());
+
+        let (db, report) = Database::parse_lenient(&data, key()).unwrap();
+        assert_eq!(report.skipped_blocks, vec![1]);
+        assert!(report.truncated);
+        assert_eq!(report.xml_error, None);
+        assert_eq!(
+            titles(&db),
+            titles(&Database::parse(&std::fs::read(PATH).unwrap(), key()).unwrap())
+        );
+    }
+
+    #[cfg(feature = "save_kdbx4")]
+    #[test]
+    fn test_damaged_middle_block_keeps_what_follows() {
+        use crate::{
+            config::{CompressionConfig, DatabaseConfig, OuterCipherConfig},
+            db::{Entry, Value},
+            format::read_outer_header_data,
+        };
+
+        // a note spanning the first three blocks of the payload, followed by more entries
+        let mut db = Database::new(DatabaseConfig {
+            outer_cipher_config: OuterCipherConfig::ChaCha20,
+            compression_config: CompressionConfig::None,
+            ..Default::default()
+        });
+        let mut big = Entry::new().with_title("Big");
+        big.fields.insert(
+            "Notes".to_string(),
+            Value::Unprotected("a".repeat(3 * 1024 * 1024)),
+        );
+        db.root.add_child(big);
+        for i in 0..10 {
+            db.root
+                .add_child(Entry::new().with_title(&format!("After {}", i)));
+        }
+        let mut data = Vec::new();
+        db.save(&mut data, key()).unwrap();
+
+        // damage the middle of the second block, after the first block and its HMAC and size
+        let header_len = read_outer_header_data(&mut &data[..]).unwrap().len();
+        let block_len = 36 + 1024 * 1024;
+        data[header_len + 64 + block_len + 36 + 1000] ^= 1;
+        assert!(Database::parse(&data, key()).is_err());
+
+        let (recovered, report) = Database::parse_lenient(&data, key()).unwrap();
+        assert_eq!(report.skipped_blocks, vec![1]);
+        assert!(!report.truncated);
+        assert_eq!(report.payload_error, None);
+        assert_eq!(report.xml_error, None);
+        assert_eq!(titles(&recovered), titles(&db));
+
+        // only the damaged byte differs
+        let notes = |db: &Database| db.root.entries()[0].get_protected("Notes").unwrap().to_vec();
+        let differing = notes(&recovered)
+            .iter()
+            .zip(notes(&db).iter())
+            .filter(|(a, b)| a != b)
+            .count();
+        assert_eq!(differing, 1);
+    }
+
+    #[test]
+    fn test_truncated_database_keeps_the_start() {
+        let intact = std::fs::read(PATH).unwrap();
+        let expected = titles(&Database::parse(&intact, key()).unwrap());
+
+        let (db, report) = Database::parse_lenient(&intact[..intact.len() - 100], key()).unwrap();
+        assert!(report.truncated);
+        assert!(report.payload_error.is_some() || report.xml_error.is_some());
+        let recovered = titles(&db);
+        assert!(!recovered.is_empty() && recovered.len() <= expected.len());
+        assert!(recovered.iter().all(|title| expected.contains(title)));
+    }
+
+    #[test]
+    fn test_wrong_key_is_not_recovered() {
+        let data = std::fs::read(PATH).unwrap();
+        assert!(matches!(
+            Database::parse_lenient(&data, DatabaseKey::new().with_password("wrong")),
+            Err(DatabaseOpenError::InvalidCredentials)
+        ));
+        assert!(matches!(
+            Database::parse_lenient(
+                &std::fs::read("tests/resources/test_db_with_password.kdbx").unwrap(),
+                key()
+            ),
+            Err(DatabaseOpenError::IncompatibleVersion { .. })
+        ));
+    }
+}
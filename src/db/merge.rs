@@ -1,4 +1,6 @@
-use crate::db::NodeLocation;
+use std::collections::HashMap;
+
+use crate::db::{references::rewrite_entry_references, CustomDataItem, Entry, Node, NodeLocation, Value};
 use thiserror::Error;
 use uuid::Uuid;
 
@@ -13,6 +15,9 @@ pub enum MergeEventType {
     GroupDeleted,
     GroupLocationUpdated,
     GroupUpdated,
+
+    /// A conflicting version of an entry was added next to it, see `ConflictPolicy::ConflictCopy`
+    EntryConflictCopyCreated,
 }
 
 #[derive(Debug, Clone)]
@@ -51,6 +56,121 @@ pub enum MergeError {
 
     #[error("Found history entries with the same timestamp ({0}) for entry {1}.")]
     DuplicateHistoryEntries(String, String),
+
+    #[error("Entries with UUID {0} are in conflict.")]
+    EntryConflict(String),
+}
+
+/// Custom data key of a conflicted copy, holding the UUID of the entry it is a copy of
+pub const CONFLICT_SOURCE_KEY: &str = "KPRS_CONFLICT_SOURCE";
+
+/// What to do with an entry that was changed in both databases in a way that cannot be reconciled,
+/// e.g. both versions have the same modification time but different contents.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Keep the version of the destination database and drop the other one
+    #[default]
+    KeepDestination,
+
+    /// Abort the merge with `MergeError::EntryConflict`
+    Fail,
+
+    /// Keep the version of the destination database and add the other version as a new sibling
+    /// entry, so that the user can resolve the conflict manually
+    ConflictCopy,
+}
+
+/// How the nodes that exist in both databases are reconciled, following the merge modes of
+/// KeePass 2. Nodes that only exist in the other database are added with every strategy.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum MergeStrategy {
+    /// Keep the most recently modified version of every node, combine the histories of entries,
+    /// follow nodes that were moved and apply the deleted objects of the other database
+    #[default]
+    Synchronize,
+
+    /// Leave the nodes of the destination database untouched
+    KeepExisting,
+
+    /// Replace entries with the version of the other database when it was modified more recently,
+    /// keeping the replaced version in the history. Nodes are not moved or deleted.
+    OverwriteIfNewer,
+
+    /// Replace entries with the version of the other database, keeping the replaced version in
+    /// the history. Nodes are not moved or deleted.
+    OverwriteExisting,
+}
+
+/// Settings for merging two databases
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MergeOptions {
+    pub strategy: MergeStrategy,
+
+    pub conflict_policy: ConflictPolicy,
+
+    /// Appended to the title of conflicted copies
+    pub conflict_suffix: String,
+
+    /// Added to the tags of conflicted copies
+    pub conflict_tag: String,
+}
+
+impl Default for MergeOptions {
+    fn default() -> Self {
+        MergeOptions {
+            strategy: MergeStrategy::default(),
+            conflict_policy: ConflictPolicy::default(),
+            conflict_suffix: " (conflicted copy)".to_string(),
+            conflict_tag: "conflict".to_string(),
+        }
+    }
+}
+
+impl MergeOptions {
+    /// Create the conflicted copy of the `other` version of an entry. The copy gets a new UUID
+    /// and no history, and remembers the UUID of the original entry in its custom data. References
+    /// of the entry to itself are pointed at the copy.
+    pub(crate) fn conflict_copy(&self, other: &Entry) -> Entry {
+        let mut copy = other.clone();
+        copy.uuid = Uuid::new_v4();
+        copy.history = None;
+
+        let mapping = HashMap::from([(other.uuid, copy.uuid)]);
+        rewrite_entry_references(&mut copy, &mapping);
+
+        let title = format!(
+            "{}{}",
+            other.get_title().unwrap_or_default(),
+            self.conflict_suffix
+        );
+        copy.fields.insert("Title".to_string(), Value::Unprotected(title));
+        if !copy.tags.contains(&self.conflict_tag) {
+            copy.tags.push(self.conflict_tag.clone());
+        }
+
+        copy.custom_data.items.insert(
+            CONFLICT_SOURCE_KEY.to_string(),
+            CustomDataItem {
+                value: Some(Value::Unprotected(other.uuid.to_string())),
+                last_modification_time: other.times.get_last_modification().copied(),
+            },
+        );
+        copy
+    }
+}
+
+/// Whether `entry` is a conflicted copy of this exact version of `original`
+pub(crate) fn is_conflict_copy_of(entry: &Entry, original: &Entry) -> bool {
+    match entry.custom_data.items.get(CONFLICT_SOURCE_KEY) {
+        Some(CustomDataItem {
+            value: Some(Value::Unprotected(uuid)),
+            ..
+        }) => {
+            *uuid == original.uuid.to_string()
+                && entry.times.get_last_modification() == original.times.get_last_modification()
+        }
+        _ => false,
+    }
 }
 
 impl MergeLog {
@@ -69,6 +189,47 @@ impl MergeLog {
     }
 }
 
+/// The most recent `LocationChanged` time of the given children. Moving or reordering a node
+/// bumps its own location change time rather than the modification time of its group, so this
+/// tells which version of a group was rearranged last.
+pub(crate) fn latest_location_change(children: &[Node]) -> Option<chrono::NaiveDateTime> {
+    children
+        .iter()
+        .filter_map(|child| match child {
+            Node::Entry(e) => e.times.get_location_changed(),
+            Node::Group(g) => g.times.get_location_changed(),
+        })
+        .max()
+        .copied()
+}
+
+/// Combine the child ordering of two versions of the same group.
+///
+/// The `base` ordering is kept as-is. Nodes that only appear in `other` are inserted right after
+/// the closest node that precedes them in `other` and is already placed, or at the front if there
+/// is no such node. The result only depends on the two orderings, so repeated merges and re-saves
+/// do not reshuffle the children.
+pub(crate) fn merge_ordering(base: &[Uuid], other: &[Uuid]) -> Vec<Uuid> {
+    let mut merged: Vec<Uuid> = base.to_vec();
+
+    for (index, uuid) in other.iter().enumerate() {
+        if merged.contains(uuid) {
+            continue;
+        }
+
+        let insert_at = other[..index]
+            .iter()
+            .rev()
+            .find_map(|predecessor| merged.iter().position(|m| m == predecessor))
+            .map(|p| p + 1)
+            .unwrap_or(0);
+
+        merged.insert(insert_at, *uuid);
+    }
+
+    merged
+}
+
 #[cfg(test)]
 mod merge_tests {
     use std::{thread, time};
@@ -1098,6 +1259,176 @@ mod merge_tests {
         assert_eq!(merge_result.events.len(), 0);
     }
 
+    #[test]
+    fn test_conflict_policies() {
+        use crate::db::{ConflictPolicy, MergeOptions, Value, CONFLICT_SOURCE_KEY};
+
+        let destination_db = create_test_database();
+        let mut source_db = destination_db.clone();
+
+        // Both versions have the same modification time, so neither can win.
+        let entry = &mut source_db.root.entries_mut()[0];
+        entry.fields.insert(
+            "Password".to_string(),
+            Value::Unprotected("changed without updating the timestamp".to_string()),
+        );
+        let self_reference = format!("{{REF:U@I:{}}}", entry.uuid.simple());
+        entry
+            .fields
+            .insert("Notes".to_string(), Value::Unprotected(self_reference));
+
+        let mut keep_db = destination_db.clone();
+        let merge_result = keep_db.merge(&source_db).unwrap();
+        assert_eq!(merge_result.events.len(), 0);
+        assert_eq!(keep_db, destination_db);
+
+        let mut fail_db = destination_db.clone();
+        let options = MergeOptions {
+            conflict_policy: ConflictPolicy::Fail,
+            ..Default::default()
+        };
+        assert!(matches!(
+            fail_db.merge_with_options(&source_db, &options),
+            Err(super::MergeError::EntryConflict(_))
+        ));
+
+        let mut copy_db = destination_db.clone();
+        let options = MergeOptions {
+            conflict_policy: ConflictPolicy::ConflictCopy,
+            ..Default::default()
+        };
+        let merge_result = copy_db.merge_with_options(&source_db, &options).unwrap();
+        assert_eq!(merge_result.events.len(), 1);
+        assert!(matches!(
+            merge_result.events[0].event_type,
+            super::MergeEventType::EntryConflictCopyCreated
+        ));
+
+        let entries = copy_db.root.entries();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0], destination_db.root.entries()[0]);
+        let copy = entries[1];
+        assert_ne!(copy.uuid, entries[0].uuid);
+        assert_eq!(copy.get_title(), Some("entry1 (conflicted copy)"));
+        assert_eq!(
+            copy.get_password(),
+            Some("changed without updating the timestamp")
+        );
+        assert_eq!(copy.tags, vec!["conflict"]);
+        assert!(copy.custom_data.items.contains_key(CONFLICT_SOURCE_KEY));
+        // references of the entry to itself point at the copy
+        assert_eq!(
+            copy.get("Notes"),
+            Some(format!("{{REF:U@I:{}}}", copy.uuid.simple().to_string().to_uppercase()).as_str())
+        );
+
+        // Merging the same version again does not create another copy.
+        let merge_result = copy_db.merge_with_options(&source_db, &options).unwrap();
+        assert_eq!(merge_result.events.len(), 0);
+        assert_eq!(copy_db.root.entries().len(), 2);
+    }
+
+    #[test]
+    fn test_merge_strategies() {
+        use crate::db::{DeletedObject, MergeOptions, MergeStrategy};
+
+        let mut destination_db = create_test_database();
+        let mut source_db = destination_db.clone();
+
+        // entry1 was modified last in the source, entry2 in the destination.
+        destination_db.root.entries_mut()[0].set_field_and_commit("Title", "entry1_from_destination");
+        source_db.root.entries_mut()[0].set_field_and_commit("Title", "entry1_from_source");
+        get_group_mut(&mut source_db, &["group1", "subgroup1"]).entries_mut()[0]
+            .set_field_and_commit("Title", "entry2_from_source");
+        get_group_mut(&mut destination_db, &["group1", "subgroup1"]).entries_mut()[0]
+            .set_field_and_commit("Title", "entry2_from_destination");
+
+        let mut new_entry = Entry::new();
+        new_entry.set_field_and_commit("Title", "new_entry");
+        source_db.root.add_child(new_entry);
+
+        let mut local_entry = Entry::new();
+        local_entry.set_field_and_commit("Title", "local_entry");
+        source_db.deleted_objects.objects.push(DeletedObject {
+            uuid: local_entry.uuid,
+            deletion_time: Times::now() + chrono::Duration::seconds(1),
+        });
+        destination_db.root.add_child(local_entry);
+
+        let merge_with_strategy = |strategy: MergeStrategy| {
+            let mut db = destination_db.clone();
+            let options = MergeOptions {
+                strategy,
+                ..Default::default()
+            };
+            db.merge_with_options(&source_db, &options).unwrap();
+            db
+        };
+        let titles = |db: &Database| {
+            let mut titles: Vec<String> = get_all_entries(&db.root)
+                .iter()
+                .map(|e| e.get_title().unwrap().to_string())
+                .collect();
+            titles.sort();
+            titles
+        };
+        let history_titles = |entry: &Entry| -> Vec<String> {
+            entry
+                .history
+                .as_ref()
+                .unwrap()
+                .get_entries()
+                .iter()
+                .map(|e| e.get_title().unwrap().to_string())
+                .collect()
+        };
+
+        let db = merge_with_strategy(MergeStrategy::KeepExisting);
+        assert_eq!(
+            titles(&db),
+            vec![
+                "entry1_from_destination",
+                "entry2_from_destination",
+                "local_entry",
+                "new_entry"
+            ]
+        );
+
+        let db = merge_with_strategy(MergeStrategy::OverwriteIfNewer);
+        assert_eq!(
+            titles(&db),
+            vec![
+                "entry1_from_source",
+                "entry2_from_destination",
+                "local_entry",
+                "new_entry"
+            ]
+        );
+        let entry1 = get_entry(&db, &["entry1_from_source"]);
+        assert!(entry1.history.as_ref().unwrap().is_ordered());
+        assert!(history_titles(entry1).contains(&"entry1_from_destination".to_string()));
+
+        let db = merge_with_strategy(MergeStrategy::OverwriteExisting);
+        assert_eq!(
+            titles(&db),
+            vec![
+                "entry1_from_source",
+                "entry2_from_source",
+                "local_entry",
+                "new_entry"
+            ]
+        );
+        let entry2 = get_entry(&db, &["group1", "subgroup1", "entry2_from_source"]);
+        assert!(history_titles(entry2).contains(&"entry2_from_destination".to_string()));
+
+        // Only synchronizing applies the deletions of the other database.
+        let db = merge_with_strategy(MergeStrategy::Synchronize);
+        assert_eq!(
+            titles(&db),
+            vec!["entry1_from_source", "entry2_from_destination", "new_entry"]
+        );
+    }
+
     #[test]
     fn test_group_update_in_source() {
         let mut destination_db = create_test_database();
@@ -1253,4 +1584,86 @@ mod merge_tests {
             Some(new_location_changed_timestamp).as_ref(),
         );
     }
+
+    fn child_uuids(group: &Group) -> Vec<Uuid> {
+        group.children.iter().map(|c| c.uuid()).collect()
+    }
+
+    #[test]
+    fn test_merge_ordering() {
+        let [a, b, c, d, e] = [1u128, 2, 3, 4, 5].map(Uuid::from_u128);
+
+        assert_eq!(super::merge_ordering(&[a, b, c], &[a, b, c]), vec![a, b, c]);
+        assert_eq!(super::merge_ordering(&[c, b, a], &[a, b, c]), vec![c, b, a]);
+        assert_eq!(super::merge_ordering(&[a, c], &[a, b, c]), vec![a, b, c]);
+        assert_eq!(
+            super::merge_ordering(&[b, c], &[d, e, b, a, c]),
+            vec![d, e, b, a, c]
+        );
+    }
+
+    #[test]
+    fn test_reordering_in_source() {
+        let mut destination_db = create_test_database();
+        let mut source_db = destination_db.clone();
+
+        source_db.root.children.reverse();
+        let source_location_changed = Times::now() + chrono::Duration::seconds(1);
+        for child in source_db.root.children.iter_mut().take(1) {
+            match child {
+                Node::Entry(e) => e.times.set_location_changed(source_location_changed),
+                Node::Group(g) => g.times.set_location_changed(source_location_changed),
+            }
+        }
+
+        destination_db.merge(&source_db).unwrap();
+        assert_eq!(child_uuids(&destination_db.root), child_uuids(&source_db.root));
+
+        // merging again does not reshuffle anything
+        destination_db.merge(&source_db).unwrap();
+        assert_eq!(child_uuids(&destination_db.root), child_uuids(&source_db.root));
+    }
+
+    #[test]
+    fn test_reordering_in_destination_is_kept() {
+        let mut destination_db = create_test_database();
+        let source_db = destination_db.clone();
+
+        destination_db.root.children.reverse();
+        let destination_location_changed = Times::now() + chrono::Duration::seconds(1);
+        for child in destination_db.root.children.iter_mut().take(1) {
+            match child {
+                Node::Entry(e) => e.times.set_location_changed(destination_location_changed),
+                Node::Group(g) => g.times.set_location_changed(destination_location_changed),
+            }
+        }
+        let expected_order = child_uuids(&destination_db.root);
+
+        destination_db.merge(&source_db).unwrap();
+        assert_eq!(child_uuids(&destination_db.root), expected_order);
+    }
+
+    #[test]
+    fn test_new_entry_keeps_position() {
+        let mut destination_db = create_test_database();
+        let mut source_db = destination_db.clone();
+
+        let mut entry3 = Entry::new();
+        entry3.set_field_and_commit("Title", "entry3");
+        let entry3_uuid = entry3.uuid;
+        source_db.root.children.insert(1, entry3.into());
+
+        let merge_result = destination_db.merge(&source_db).unwrap();
+        assert_eq!(merge_result.events.len(), 1);
+
+        assert_eq!(
+            child_uuids(&destination_db.root),
+            vec![
+                Uuid::parse_str(ENTRY1_ID).unwrap(),
+                entry3_uuid,
+                Uuid::parse_str(GROUP1_ID).unwrap(),
+                Uuid::parse_str(GROUP2_ID).unwrap(),
+            ]
+        );
+    }
 }
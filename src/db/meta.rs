@@ -1,11 +1,11 @@
 use chrono::NaiveDateTime;
 use uuid::Uuid;
 
-use crate::db::{Color, CustomData};
+use crate::db::{Color, CustomData, UnknownElement, Value};
 
 /// Database metadata
 #[derive(Debug, Default, Eq, PartialEq, Clone)]
-#[cfg_attr(feature = "serialization", derive(serde::Serialize))]
+#[cfg_attr(feature = "serialization", derive(serde::Serialize, serde::Deserialize))]
 pub struct Meta {
     /// the program that generated the database file.
     pub generator: Option<String>,
@@ -82,11 +82,32 @@ pub struct Meta {
 
     /// Additional custom data fields
     pub custom_data: CustomData,
+
+    /// XML elements of the metadata unknown to this library, written back when saving the
+    /// database
+    pub unknown_elements: Vec<UnknownElement>,
+}
+
+impl Meta {
+    /// The memory protection settings of the database, or the defaults of KeePass if it has none
+    pub fn effective_memory_protection(&self) -> MemoryProtection {
+        self.memory_protection.unwrap_or_default()
+    }
+
+    /// A value for a field of an entry, protected if the memory protection settings of the
+    /// database protect the field. Saving protects the values of such fields in any case.
+    pub fn field_value(&self, field_name: &str, value: &str) -> Value {
+        if self.effective_memory_protection().protects(field_name) {
+            Value::Protected(value.into())
+        } else {
+            Value::Unprotected(value.to_string())
+        }
+    }
 }
 
 /// Database memory protection settings
-#[derive(Debug, PartialEq, Eq, Clone)]
-#[cfg_attr(feature = "serialization", derive(serde::Serialize))]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "serialization", derive(serde::Serialize, serde::Deserialize))]
 pub struct MemoryProtection {
     /// Whether titles should be protected
     pub protect_title: bool,
@@ -104,6 +125,21 @@ pub struct MemoryProtection {
     pub protect_notes: bool,
 }
 
+impl MemoryProtection {
+    /// Whether the settings protect a field. Fields other than the standard ones are protected
+    /// one by one, by making their values `Value::Protected`.
+    pub fn protects(&self, field_name: &str) -> bool {
+        match field_name {
+            "Title" => self.protect_title,
+            "UserName" => self.protect_username,
+            "Password" => self.protect_password,
+            "URL" => self.protect_url,
+            "Notes" => self.protect_notes,
+            _ => false,
+        }
+    }
+}
+
 impl Default for MemoryProtection {
     fn default() -> Self {
         Self {
@@ -118,34 +154,51 @@ impl Default for MemoryProtection {
 
 /// Collection of custom icons
 #[derive(Debug, Default, PartialEq, Eq, Clone)]
-#[cfg_attr(feature = "serialization", derive(serde::Serialize))]
+#[cfg_attr(feature = "serialization", derive(serde::Serialize, serde::Deserialize))]
 pub struct CustomIcons {
     pub icons: Vec<Icon>,
 }
 
 /// A custom icon
 #[derive(Debug, Default, PartialEq, Eq, Clone)]
-#[cfg_attr(feature = "serialization", derive(serde::Serialize))]
+#[cfg_attr(feature = "serialization", derive(serde::Serialize, serde::Deserialize))]
 pub struct Icon {
     /// UUID, to reference the icon
     pub uuid: Uuid,
 
     /// Image data
     pub data: Vec<u8>,
+
+    /// Name of the icon
+    pub name: Option<String>,
+
+    /// Time of the last modification of the icon
+    pub last_modification_time: Option<NaiveDateTime>,
 }
 
 /// Collection of binary attachments in the metadata of an XML database
 #[derive(Debug, Default, PartialEq, Eq, Clone)]
-#[cfg_attr(feature = "serialization", derive(serde::Serialize))]
+#[cfg_attr(feature = "serialization", derive(serde::Serialize, serde::Deserialize))]
 pub struct BinaryAttachments {
     pub binaries: Vec<BinaryAttachment>,
 }
 
 /// Binary attachment in the metadata of a XML database
 #[derive(Debug, Default, PartialEq, Eq, Clone)]
-#[cfg_attr(feature = "serialization", derive(serde::Serialize))]
+#[cfg_attr(feature = "serialization", derive(serde::Serialize, serde::Deserialize))]
 pub struct BinaryAttachment {
     pub identifier: Option<String>,
+
+    /// Whether the content is stored GZip-compressed in the file, at `SaveOptions::gzip_level`.
+    /// Content that is compressed already, like images, is stored as it is. See
+    /// `BinaryAttachment::recompress` to choose by whether compressing saves space.
     pub compressed: bool,
+
     pub content: Vec<u8>,
+
+    /// Whether `content` is held gzip-compressed in memory, see `BinaryAttachment::data`
+    pub packed: bool,
+
+    /// Whether the content is kept in a `BlobStore`, with `content` holding its hash
+    pub external: bool,
 }
@@ -1,9 +1,58 @@
 //! Types for representing data contained in a KeePass database
 
+pub(crate) mod annotations;
+pub(crate) mod attach;
+pub(crate) mod attachment_pool;
+pub(crate) mod attachment_text;
+pub(crate) mod audit;
+pub(crate) mod autotype;
+pub(crate) mod blobs;
+pub(crate) mod browser;
+pub(crate) mod checksum;
+pub(crate) mod clock;
+pub(crate) mod codec;
+pub(crate) mod conflict;
+pub(crate) mod convert;
+pub(crate) mod custom_icons;
+pub(crate) mod diff;
 pub(crate) mod entry;
+pub(crate) mod filter;
+pub(crate) mod forensics;
 pub(crate) mod group;
+pub(crate) mod header_info;
+pub(crate) mod icons;
+pub(crate) mod lenient;
 pub(crate) mod meta;
+pub(crate) mod mutation;
 pub(crate) mod node;
+pub(crate) mod notes;
+pub(crate) mod packed;
+pub(crate) mod plain_xml;
+pub(crate) mod probe;
+pub(crate) mod protected;
+pub(crate) mod public_data;
+pub(crate) mod recovery;
+pub(crate) mod references;
+pub(crate) mod rekey;
+pub(crate) mod schema;
+pub(crate) mod source;
+pub(crate) mod tags;
+pub(crate) mod templates;
+pub(crate) mod trash;
+pub(crate) mod usage;
+pub(crate) mod validation;
+pub(crate) mod view;
+pub(crate) mod walk;
+pub(crate) mod warnings;
+
+#[cfg(feature = "async")]
+pub(crate) mod background;
+
+#[cfg(feature = "collation")]
+pub(crate) mod collation;
+
+#[cfg(feature = "derived_credentials")]
+pub(crate) mod derived;
 
 #[cfg(feature = "_merge")]
 pub(crate) mod merge;
@@ -11,25 +60,93 @@ pub(crate) mod merge;
 #[cfg(feature = "totp")]
 pub(crate) mod otp;
 
+#[cfg(feature = "save_kdbx4")]
+pub(crate) mod persist;
+
+#[cfg(feature = "serialization")]
+pub(crate) mod serialization;
+
 #[cfg(feature = "_merge")]
 use std::collections::VecDeque;
+use std::io::Read;
 use std::{collections::HashMap, str::FromStr};
 
 use chrono::NaiveDateTime;
 use uuid::Uuid;
 
 pub use crate::db::{
-    entry::{AutoType, AutoTypeAssociation, Entry, History, Value},
-    group::Group,
+    annotations::Annotations,
+    attach::{AttachError, AttachOptions, AttachReport, SkipReason},
+    attachment_pool::AttachmentPool,
+    attachment_text::{AttachmentText, AttachmentTextError, TextEncoding, MAX_TEXT_ATTACHMENT_SIZE},
+    audit::{clear_audit_hook, set_audit_hook, with_audit_context, AccessEvent, AuditHook},
+    autotype::DEFAULT_AUTOTYPE_SEQUENCE,
+    blobs::{BlobHash, BlobStore, BlobStoreError, DirectoryBlobStore, MemoryBlobStore},
+    browser::{BrowserAssociation, BrowserError, BROWSER_KEY_PREFIX, BROWSER_PUBLIC_KEY_SIZE},
+    checksum::{Checksum, ChecksumOptions, SubtreeChecksums},
+    clock::{with_clock, Clock, ManualClock, SystemClock},
+    codec::{AttachmentCodec, AttachmentSavings},
+    conflict::FileFingerprint,
+    convert::ConversionError,
+    custom_icons::IconError,
+    diff::{ChangeKind, DatabaseDiff, DiffFormat, DiffStyle, EntryChange, FieldChange, GroupChange},
+    entry::{AttachmentRef, AutoType, AutoTypeAssociation, Entry, History, Value, MANAGED_BY_KEY},
+    filter::{FieldPredicate, Filter},
+    forensics::{CheckResult, FailedParse, ForensicReport, HeaderField},
+    group::{Group, QuarantinedNode, UnknownElement, DEFAULT_EXPIRY_DAYS_KEY},
+    header_info::HeaderInfo,
+    icons::{IconAssignment, IconFetchFailure, IconFetcher},
+    lenient::RecoveryReport,
     meta::{BinaryAttachment, BinaryAttachments, CustomIcons, Icon, MemoryProtection, Meta},
-    node::{Node, NodeIter, NodeRef, NodeRefMut},
+    mutation::{EntryEdit, HistoryPolicy, MoveError, MutationOptions},
+    node::{EntryIterMut, EntryPathIterMut, Node, NodeIter, NodePathIter, NodeRef, NodeRefMut},
+    notes::{FencedBlock, NotesSection, StructuredNotes},
+    probe::EntryQuery,
+    protected::{ProtectedGuard, ProtectedValue},
+    public_data::{PublicCustomData, PublicValue, DATABASE_NAME_KEY, DATABASE_UUID_KEY},
+    recovery::{RecoveryCode, RecoveryCodeError, RecoveryCodes, RECOVERY_CODES_FIELD},
+    references::ReferenceError,
+    rekey::{ChangeKeyError, KeyCheck},
+    schema::{FieldKind, FieldSchema, SchemaError, SchemaField, ENTRY_SCHEMA_KEY, SCHEMA_KEY_PREFIX},
+    source::SourceFormat,
+    tags::{same_tag, TAG_SEPARATORS},
+    trash::{SoftDeleteError, DELETED_AT_KEY, RECYCLED_FROM_KEY},
+    usage::{AccessStats, UsageStats, USAGE_STATS_NAMESPACE},
+    validation::{Check, ValidationRule, Violation, ViolationKind},
+    view::FilteredView,
+    walk::{Visitor, WalkControl, WalkEvent},
+    warnings::{ParseOutcome, ParseWarning},
+};
+
+#[cfg(feature = "_merge")]
+use crate::db::merge::{
+    is_conflict_copy_of, latest_location_change, merge_ordering, MergeError, MergeEvent, MergeEventType,
+    MergeLog,
 };
 
 #[cfg(feature = "_merge")]
-use crate::db::merge::{MergeError, MergeEvent, MergeEventType, MergeLog};
+pub use crate::db::merge::{ConflictPolicy, MergeOptions, MergeStrategy, CONFLICT_SOURCE_KEY};
+
+#[cfg(feature = "async")]
+pub use crate::db::background::BackgroundTask;
+
+#[cfg(feature = "collation")]
+pub use crate::db::collation::CollationError;
+
+#[cfg(feature = "derived_credentials")]
+pub use crate::db::derived::{DerivationError, DerivedCredential, DERIVED_CREDENTIAL_KEY};
 
 #[cfg(feature = "totp")]
-pub use crate::db::otp::{TOTPAlgorithm, TOTP};
+pub use crate::db::otp::{TOTPAlgorithm, TOTPEncoder, TOTP};
+
+#[cfg(feature = "save_kdbx4")]
+pub use crate::db::conflict::SaveConflictError;
+
+#[cfg(feature = "save_kdbx4")]
+pub use crate::db::persist::backup_paths;
+
+#[cfg(feature = "serialization")]
+pub use crate::db::serialization::reveal_protected_values;
 
 #[cfg(feature = "_merge")]
 use crate::db::group::NodeLocation;
@@ -38,16 +155,20 @@ use crate::{
     error::{DatabaseIntegrityError, DatabaseOpenError, ParseColorError},
     format::{
         kdb::parse_kdb,
-        kdbx3::{decrypt_kdbx3, parse_kdbx3},
-        kdbx4::{decrypt_kdbx4, parse_kdbx4},
-        DatabaseVersion,
+        kdbx3::{decrypt_kdbx3, parse_kdbx3, verify_kdbx3_key},
+        kdbx4::{
+            decrypt_kdbx4, decrypt_kdbx4_stream, parse_kdbx4, parse_kdbx4_transformed, read_kdbx4,
+            verify_kdbx4_key,
+        },
+        read_outer_header_data, DatabaseVersion, SUPPORTED_KDBX_VERSIONS, SUPPORTED_VERSIONS,
     },
-    key::DatabaseKey,
+    key::{DatabaseKey, TransformedKey},
+    xml_db::get_epoch_baseline,
 };
 
 /// A decrypted KeePass database
 #[derive(Debug, Clone, PartialEq, Eq)]
-#[cfg_attr(feature = "serialization", derive(serde::Serialize))]
+#[cfg_attr(feature = "serialization", derive(serde::Serialize, serde::Deserialize))]
 pub struct Database {
     /// Configuration settings of the database such as encryption and compression algorithms
     pub config: DatabaseConfig,
@@ -63,26 +184,192 @@ pub struct Database {
 
     /// Metadata of the KeePass database
     pub meta: Meta,
+
+    /// Data found after the end of the encrypted payload of a KDBX4 file, such as signatures or
+    /// sync metadata appended by other tools
+    pub trailing_data: Vec<u8>,
+
+    /// Custom data in the outer header, which can be read without the key. Holds the UUID of the
+    /// database.
+    pub public_custom_data: PublicCustomData,
+
+    /// Transient labels of groups and entries for user interfaces, which are never saved
+    #[cfg_attr(feature = "serialization", serde(skip))]
+    pub annotations: Annotations,
+
+    /// Identifies the file the database was read from, see `Database::save_if_unchanged`
+    #[cfg_attr(feature = "serialization", serde(skip))]
+    pub file_fingerprint: FileFingerprint,
+
+    /// Checks keys against the file the database was read from or the key it was changed to, see
+    /// `Database::change_key`
+    #[cfg_attr(feature = "serialization", serde(skip))]
+    pub key_check: KeyCheck,
+}
+
+/// Read the version header at the start of a database, or less if the source ends before it
+fn read_version_header(source: &mut dyn Read) -> Result<Vec<u8>, std::io::Error> {
+    let mut data = Vec::new();
+    source
+        .take(DatabaseVersion::get_version_header_size() as u64)
+        .read_to_end(&mut data)?;
+    Ok(data)
 }
 
 impl Database {
     /// Parse a database from a std::io::Read
+    ///
+    /// KDBX 4 databases are decrypted while they are read, so that the encrypted file is never
+    /// held in memory as a whole. Older formats are read completely before they are parsed.
     pub fn open(source: &mut dyn std::io::Read, key: DatabaseKey) -> Result<Database, DatabaseOpenError> {
-        let mut data = Vec::new();
-        source.read_to_end(&mut data)?;
+        let mut data = read_version_header(source)?;
+        if let Ok(DatabaseVersion::KDB4(_)) = DatabaseVersion::parse(&data) {
+            let header_data = read_outer_header_data(&mut data.as_slice().chain(&mut *source))?;
+            // keep the hash and HMAC following the header, to check keys against later
+            let mut header = header_data.clone();
+            header.resize(header_data.len() + rekey::HEADER_HASHES_SIZE, 0);
+            source.read_exact(&mut header[header_data.len()..])?;
+
+            let mut db = read_kdbx4(&mut header.as_slice().chain(source), None, &key)?;
+            db.file_fingerprint = FileFingerprint::of_header(&header_data);
+            db.key_check = KeyCheck::of(&header);
+            return Ok(db);
+        }
 
+        source.read_to_end(&mut data)?;
         Database::parse(data.as_ref(), key)
     }
 
+    /// Open the database file at `path` with a password, a key file or both. The version of the
+    /// file is detected from its header, and a wrong password or key file fails with
+    /// `DatabaseOpenError::InvalidCredentials`.
+    ///
+    /// ```
+    /// use keepass::Database;
+    ///
+    /// let db = Database::open_path("tests/resources/test_db_with_password.kdbx", Some("demopass"), None)?;
+    /// # Ok::<(), keepass::error::DatabaseOpenError>(())
+    /// ```
+    pub fn open_path(
+        path: impl AsRef<std::path::Path>,
+        password: Option<&str>,
+        keyfile: Option<&std::path::Path>,
+    ) -> Result<Database, DatabaseOpenError> {
+        let mut key = DatabaseKey::new();
+        if let Some(password) = password {
+            key = key.with_password(password);
+        }
+        if let Some(keyfile) = keyfile {
+            key = key.with_keyfile(&mut std::fs::File::open(keyfile)?)?;
+        }
+
+        Database::open(&mut std::io::BufReader::new(std::fs::File::open(path)?), key)
+    }
+
+    /// Check whether a key opens a database, without decrypting, decompressing or parsing its
+    /// contents. This still runs the key derivation function, but is otherwise cheap, so that an
+    /// unlock screen can reject a wrong password before opening a large database.
+    ///
+    /// For KDBX 4, this reads the header and checks its HMAC. For KDBX 3, which has no HMAC, the
+    /// first block of the payload is decrypted and compared to the stream start bytes in the
+    /// header. KDB databases have no way to check a key but decrypting the whole file.
+    ///
+    /// ```
+    /// use keepass::{Database, DatabaseKey};
+    ///
+    /// let mut file = std::fs::File::open("tests/resources/test_db_with_password.kdbx")?;
+    /// assert!(!Database::verify_key(&mut file, DatabaseKey::new().with_password("wrong"))?);
+    /// # Ok::<(), keepass::error::DatabaseOpenError>(())
+    /// ```
+    pub fn verify_key(source: &mut dyn std::io::Read, key: DatabaseKey) -> Result<bool, DatabaseOpenError> {
+        let version_header = read_version_header(source)?;
+        let database_version = DatabaseVersion::parse(&version_header)?;
+        let source = &mut version_header.as_slice().chain(source);
+
+        match database_version {
+            DatabaseVersion::KDB(_) => {
+                let mut data = Vec::new();
+                source.read_to_end(&mut data)?;
+                match parse_kdb(&data, &key) {
+                    Ok(_) => Ok(true),
+                    Err(DatabaseOpenError::InvalidCredentials) => Ok(false),
+                    Err(e) => Err(e),
+                }
+            }
+            DatabaseVersion::KDB2(_) => Err(DatabaseOpenError::IncompatibleVersion {
+                found: database_version.to_string(),
+                supported: SUPPORTED_VERSIONS,
+            }),
+            DatabaseVersion::KDB3(_) => verify_kdbx3_key(source, &key),
+            DatabaseVersion::KDB4(_) => verify_kdbx4_key(source, &key),
+        }
+    }
+
     pub fn parse(data: &[u8], key: DatabaseKey) -> Result<Database, DatabaseOpenError> {
         let database_version = DatabaseVersion::parse(data)?;
 
-        match database_version {
-            DatabaseVersion::KDB(_) => parse_kdb(data, &key),
-            DatabaseVersion::KDB2(_) => Err(DatabaseOpenError::UnsupportedVersion.into()),
-            DatabaseVersion::KDB3(_) => parse_kdbx3(data, &key),
-            DatabaseVersion::KDB4(_) => parse_kdbx4(data, &key),
+        let mut db = match database_version {
+            DatabaseVersion::KDB(_) => parse_kdb(data, &key)?,
+            DatabaseVersion::KDB2(_) => {
+                return Err(DatabaseOpenError::IncompatibleVersion {
+                    found: database_version.to_string(),
+                    supported: SUPPORTED_VERSIONS,
+                })
+            }
+            DatabaseVersion::KDB3(_) => parse_kdbx3(data, &key)?,
+            DatabaseVersion::KDB4(_) => parse_kdbx4(data, &key)?,
+        };
+        db.file_fingerprint = FileFingerprint::of(data);
+        db.key_check = KeyCheck::of(data);
+        Ok(db)
+    }
+
+    /// Parse a KDBX 4 database and return the key transformed by its KDF along with it, so that
+    /// later parses and saves of the database can skip the KDF, see `TransformedKey`. Other
+    /// versions fail with `DatabaseOpenError::IncompatibleVersion`.
+    ///
+    /// ```
+    /// use keepass::{Database, DatabaseKey};
+    ///
+    /// let data = std::fs::read("tests/resources/test_db_kdbx4_with_password_argon2.kdbx")?;
+    /// let (db, transformed_key) = Database::parse_transformed(&data, DatabaseKey::new().with_password("demopass"))?;
+    ///
+    /// // no password and no KDF needed to open the database again
+    /// let reopened = Database::parse(&data, DatabaseKey::new().with_transformed_key(transformed_key))?;
+    /// assert_eq!(reopened.root, db.root);
+    /// # Ok::<(), keepass::error::DatabaseOpenError>(())
+    /// ```
+    pub fn parse_transformed(
+        data: &[u8],
+        key: DatabaseKey,
+    ) -> Result<(Database, TransformedKey), DatabaseOpenError> {
+        match DatabaseVersion::parse(data)? {
+            DatabaseVersion::KDB4(_) => {
+                let (mut db, transformed_key) = parse_kdbx4_transformed(data, &key)?;
+                db.file_fingerprint = FileFingerprint::of(data);
+                db.key_check = KeyCheck::of(data);
+                Ok((db, transformed_key))
+            }
+            version => Err(DatabaseOpenError::IncompatibleVersion {
+                found: version.to_string(),
+                supported: "KDBX4",
+            }),
+        }
+    }
+
+    /// Parse a database, using custom options
+    pub fn parse_with_options(
+        data: &[u8],
+        key: DatabaseKey,
+        options: &ParseOptions,
+    ) -> Result<Database, DatabaseOpenError> {
+        let mut db = Database::parse(data, key)?;
+
+        if options.pack_attachments {
+            db.pack_attachments()?;
         }
+
+        Ok(db)
     }
 
     /// Save a database to a std::io::Write
@@ -91,33 +378,71 @@ impl Database {
         &self,
         destination: &mut dyn std::io::Write,
         key: DatabaseKey,
+    ) -> Result<(), crate::error::DatabaseSaveError> {
+        self.save_with_options(destination, key, &SaveOptions::default())
+    }
+
+    /// Save a database to a std::io::Write, using custom options
+    #[cfg(feature = "save_kdbx4")]
+    pub fn save_with_options(
+        &self,
+        destination: &mut dyn std::io::Write,
+        key: DatabaseKey,
+        options: &SaveOptions,
     ) -> Result<(), crate::error::DatabaseSaveError> {
         use crate::error::DatabaseSaveError;
-        use crate::format::kdbx4::dump_kdbx4;
+        use crate::format::{kdbx3::dump_kdbx3, kdbx4::dump_kdbx4};
 
         match self.config.version {
-            DatabaseVersion::KDB(_) => Err(DatabaseSaveError::UnsupportedVersion.into()),
-            DatabaseVersion::KDB2(_) => Err(DatabaseSaveError::UnsupportedVersion.into()),
-            DatabaseVersion::KDB3(_) => Err(DatabaseSaveError::UnsupportedVersion.into()),
-            DatabaseVersion::KDB4(_) => dump_kdbx4(self, &key, destination),
+            DatabaseVersion::KDB(_) => return Err(DatabaseSaveError::UnsupportedVersion.into()),
+            DatabaseVersion::KDB2(_) => return Err(DatabaseSaveError::UnsupportedVersion.into()),
+            DatabaseVersion::KDB3(_) | DatabaseVersion::KDB4(_) => {}
+        }
+
+        if self.has_external_attachments() {
+            return Err(DatabaseSaveError::ExternalAttachments);
         }
+        let dumped = if let DatabaseVersion::KDB3(_) = self.config.version {
+            dump_kdbx3(self, &key, destination, options)
+        } else {
+            dump_kdbx4(self, &key, destination, options)
+        };
+        // a cancellation may surface as any error of the writers, see `crate::progress`
+        dumped.map_err(|e| match crate::progress::is_cancelled() {
+            true => DatabaseSaveError::Cancelled,
+            false => e,
+        })?;
+        if options.preserve_trailing_data {
+            destination.write_all(&self.trailing_data)?;
+        }
+        Ok(())
     }
 
     /// Helper function to load a database into its internal XML chunks
     pub fn get_xml(source: &mut dyn std::io::Read, key: DatabaseKey) -> Result<Vec<u8>, DatabaseOpenError> {
-        let mut data = Vec::new();
+        let mut data = read_version_header(source)?;
+        if let Ok(DatabaseVersion::KDB4(_)) = DatabaseVersion::parse(&data) {
+            return Ok(std::mem::take(
+                &mut decrypt_kdbx4_stream(&mut data.as_slice().chain(source), None, &key)?.3,
+            ));
+        }
         source.read_to_end(&mut data)?;
 
         let database_version = DatabaseVersion::parse(data.as_ref())?;
 
-        let data = match database_version {
-            DatabaseVersion::KDB(_) => return Err(DatabaseOpenError::UnsupportedVersion),
-            DatabaseVersion::KDB2(_) => return Err(DatabaseOpenError::UnsupportedVersion),
+        let mut xml = match database_version {
+            DatabaseVersion::KDB(_) | DatabaseVersion::KDB2(_) => {
+                return Err(DatabaseOpenError::IncompatibleVersion {
+                    found: database_version.to_string(),
+                    supported: SUPPORTED_KDBX_VERSIONS,
+                })
+            }
             DatabaseVersion::KDB3(_) => decrypt_kdbx3(data.as_ref(), &key)?.2,
             DatabaseVersion::KDB4(_) => decrypt_kdbx4(data.as_ref(), &key)?.3,
         };
 
-        Ok(data)
+        // the caller asked for the plaintext, so it is handed over instead of being wiped
+        Ok(std::mem::take(&mut *xml))
     }
 
     /// Get the version of a database without decrypting it
@@ -136,17 +461,64 @@ impl Database {
             root: Group::new("Root"),
             deleted_objects: Default::default(),
             meta: Default::default(),
+            trailing_data: Vec::new(),
+            public_custom_data: PublicCustomData::new(),
+            annotations: Default::default(),
+            file_fingerprint: Default::default(),
+            key_check: Default::default(),
         }
     }
 
+    /// Trim the histories of all entries to `Meta::history_max_items` and `Meta::history_max_size`,
+    /// returning the number of removed history entries
+    pub fn trim_histories(&mut self) -> usize {
+        self.root.trim_histories(&self.meta)
+    }
+
+    /// Find a group or entry by its UUID, see `Group::find_by_uuid`
+    pub fn find_by_uuid(&self, uuid: &Uuid) -> Option<NodeRef<'_>> {
+        self.root.find_by_uuid(uuid)
+    }
+
+    /// Find a group or entry by its UUID for changing it, see `Group::find_by_uuid_mut`
+    pub fn find_by_uuid_mut(&mut self, uuid: &Uuid) -> Option<NodeRefMut<'_>> {
+        self.root.find_by_uuid_mut(uuid)
+    }
+
     /// Merge this database with another version of this same database.
     /// This function will use the UUIDs to detect that entries and groups are
     /// the same.
     #[cfg(feature = "_merge")]
     pub fn merge(&mut self, other: &Database) -> Result<MergeLog, MergeError> {
+        self.merge_with_options(other, &MergeOptions::default())
+    }
+
+    /// Merge this database with another version of this same database, using the given options to
+    /// choose the merge strategy and to handle conflicting entries.
+    ///
+    /// ```
+    /// use keepass::{db::{MergeOptions, MergeStrategy}, Database};
+    ///
+    /// # let mut local = Database::new(Default::default());
+    /// # let synced = local.clone();
+    /// let options = MergeOptions {
+    ///     strategy: MergeStrategy::OverwriteIfNewer,
+    ///     ..Default::default()
+    /// };
+    /// let log = local.merge_with_options(&synced, &options)?;
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    #[cfg(feature = "_merge")]
+    pub fn merge_with_options(
+        &mut self,
+        other: &Database,
+        options: &MergeOptions,
+    ) -> Result<MergeLog, MergeError> {
         let mut log = MergeLog::default();
-        log.append(&self.merge_group(vec![], &other.root, false)?);
-        log.append(&self.merge_deletions(&other)?);
+        log.append(&self.merge_group(vec![], &other.root, false, options)?);
+        if options.strategy == MergeStrategy::Synchronize {
+            log.append(&self.merge_deletions(&other)?);
+        }
         Ok(log)
     }
 
@@ -314,16 +686,93 @@ impl Database {
         None
     }
 
+    /// Add a conflicted copy of `other_entry` next to the entry at `existing_entry_location`,
+    /// unless a copy of that version was already added by an earlier merge.
+    #[cfg(feature = "_merge")]
+    fn add_conflict_copy(
+        &mut self,
+        existing_entry_location: &NodeLocation,
+        other_entry: &Entry,
+        options: &MergeOptions,
+    ) -> Result<MergeLog, MergeError> {
+        let mut log = MergeLog::default();
+
+        let parent_location = existing_entry_location[..existing_entry_location.len() - 1].to_vec();
+        let parent_group = match self.root.find_group_mut(&parent_location) {
+            Some(g) => g,
+            None => return Err(MergeError::FindGroupError(parent_location)),
+        };
+
+        if parent_group
+            .entries()
+            .iter()
+            .any(|e| is_conflict_copy_of(e, other_entry))
+        {
+            return Ok(log);
+        }
+
+        let copy = options.conflict_copy(other_entry);
+        log.events.push(MergeEvent {
+            event_type: MergeEventType::EntryConflictCopyCreated,
+            node_uuid: copy.uuid,
+        });
+        parent_group.add_child(copy);
+
+        Ok(log)
+    }
+
+    /// Replace the entry at `existing_entry_location` with `other_entry` if the strategy says so
+    #[cfg(feature = "_merge")]
+    fn overwrite_entry(
+        &mut self,
+        existing_entry_location: &NodeLocation,
+        existing_entry: &Entry,
+        other_entry: &Entry,
+        strategy: &MergeStrategy,
+    ) -> Result<MergeLog, MergeError> {
+        let (merged_entry, mut log) = match existing_entry.overwrite_with(other_entry, strategy)? {
+            (Some(m), log) => (m, log),
+            (None, log) => return Ok(log),
+        };
+
+        let existing_entry = match self.root.find_entry_mut(existing_entry_location) {
+            Some(e) => e,
+            None => return Err(MergeError::FindEntryError(existing_entry_location.to_vec())),
+        };
+        *existing_entry = merged_entry;
+
+        log.events.push(MergeEvent {
+            event_type: MergeEventType::EntryUpdated,
+            node_uuid: other_entry.uuid,
+        });
+        Ok(log)
+    }
+
     #[cfg(feature = "_merge")]
     fn merge_group(
         &mut self,
         current_group_path: NodeLocation,
         current_group: &Group,
         is_in_deleted_group: bool,
+        options: &MergeOptions,
     ) -> Result<MergeLog, MergeError> {
         let mut log = MergeLog::default();
 
-        if let Some(destination_group_location) = self.find_node_location(current_group.uuid) {
+        // Remember the ordering of the destination group before any children get added, moved or
+        // updated, so that the manual ordering of both sides can be combined afterwards. The side
+        // whose children changed their location last is taken to have the newer ordering.
+        let destination_ordering = self.root.find_group(&current_group_path).map(|g| {
+            let order: Vec<Uuid> = g.children.iter().map(|c| c.uuid()).collect();
+            let source_is_newer =
+                latest_location_change(&current_group.children) > latest_location_change(&g.children);
+            (order, source_is_newer)
+        });
+
+        let existing_group_location = match options.strategy {
+            MergeStrategy::KeepExisting => None,
+            _ => self.find_node_location(current_group.uuid),
+        };
+        if let Some(destination_group_location) = existing_group_location {
             let mut destination_group_path = destination_group_location.clone();
             destination_group_path.push(current_group.uuid);
             let destination_group = match self.root.find_group_mut(&destination_group_path) {
@@ -347,6 +796,16 @@ impl Database {
                 // relocate it.
                 let mut existing_entry = self.root.find_entry(&existing_entry_location).unwrap().clone();
 
+                if options.strategy != MergeStrategy::Synchronize {
+                    log.append(&self.overwrite_entry(
+                        &existing_entry_location,
+                        &existing_entry,
+                        other_entry,
+                        &options.strategy,
+                    )?);
+                    continue;
+                }
+
                 // The entry already exists but is not at the right location. We might have to
                 // relocate it.
                 if current_group_path.last() != destination_entry_location.last() && !is_in_deleted_group {
@@ -397,10 +856,29 @@ impl Database {
 
                 // The entry already exists and is at the right location, so we can proceed and merge
                 // the two entries.
-                let (merged_entry, entry_merge_log) = existing_entry.merge(other_entry)?;
-                let merged_entry = match merged_entry {
-                    Some(m) => m,
-                    None => continue,
+                let merge_result = existing_entry.merge(other_entry);
+                let (merged_entry, entry_merge_log) = match merge_result {
+                    Ok((Some(m), entry_merge_log)) => (m, entry_merge_log),
+                    Ok((None, _)) | Err(MergeError::DuplicateHistoryEntries(_, _)) => {
+                        // Both versions changed in a way that cannot be reconciled.
+                        match options.conflict_policy {
+                            ConflictPolicy::KeepDestination => {
+                                merge_result?;
+                            }
+                            ConflictPolicy::Fail => {
+                                return Err(MergeError::EntryConflict(other_entry.uuid.to_string()))
+                            }
+                            ConflictPolicy::ConflictCopy => {
+                                log.append(&self.add_conflict_copy(
+                                    &existing_entry_location,
+                                    other_entry,
+                                    options,
+                                )?);
+                            }
+                        }
+                        continue;
+                    }
+                    Err(e) => return Err(e),
                 };
 
                 if existing_entry.eq(&merged_entry) {
@@ -452,7 +930,7 @@ impl Database {
             new_group_location.push(other_group_uuid);
 
             if self.deleted_objects.contains(other_group.uuid) || is_in_deleted_group {
-                let new_merge_log = self.merge_group(new_group_location, other_group, true)?;
+                let new_merge_log = self.merge_group(new_group_location, other_group, true, options)?;
                 log.append(&new_merge_log);
                 continue;
             }
@@ -461,7 +939,9 @@ impl Database {
 
             // The group already exists in the destination database.
             if let Some(destination_group_location) = destination_group_location {
-                if current_group_path != destination_group_location {
+                if current_group_path != destination_group_location
+                    && options.strategy == MergeStrategy::Synchronize
+                {
                     let mut existing_group_location = destination_group_location.clone();
                     existing_group_location.push(other_group_uuid);
 
@@ -503,7 +983,7 @@ impl Database {
                         });
 
                         let new_merge_log =
-                            self.merge_group(new_group_location, other_group, is_in_deleted_group)?;
+                            self.merge_group(new_group_location, other_group, is_in_deleted_group, options)?;
                         log.append(&new_merge_log);
                         continue;
                     }
@@ -511,7 +991,8 @@ impl Database {
 
                 // The group already exists and is at the right location, so we can proceed and merge
                 // the two groups.
-                let new_merge_log = self.merge_group(new_group_location, other_group, is_in_deleted_group)?;
+                let new_merge_log =
+                    self.merge_group(new_group_location, other_group, is_in_deleted_group, options)?;
                 log.append(&new_merge_log);
                 continue;
             }
@@ -529,10 +1010,24 @@ impl Database {
             };
             new_group_parent_group.add_child(new_group.clone());
 
-            let new_merge_log = self.merge_group(new_group_location, other_group, is_in_deleted_group)?;
+            let new_merge_log =
+                self.merge_group(new_group_location, other_group, is_in_deleted_group, options)?;
             log.append(&new_merge_log);
         }
 
+        if let Some((destination_order, source_is_newer)) = destination_ordering {
+            let source_order: Vec<Uuid> = current_group.children.iter().map(|c| c.uuid()).collect();
+            let ordering = if source_is_newer {
+                merge_ordering(&source_order, &destination_order)
+            } else {
+                merge_ordering(&destination_order, &source_order)
+            };
+
+            if let Some(destination_group) = self.root.find_group_mut(&current_group_path) {
+                destination_group.reorder_children(&ordering);
+            }
+        }
+
         Ok(log)
     }
 
@@ -564,9 +1059,102 @@ impl Database {
     }
 }
 
+/// Iterates over the root group and all nodes below it
+impl<'a> IntoIterator for &'a Database {
+    type Item = NodeRef<'a>;
+    type IntoIter = NodeIter<'a>;
+
+    fn into_iter(self) -> NodeIter<'a> {
+        self.root.iter()
+    }
+}
+
+/// Iterates over all entries of the database for changing them
+impl<'a> IntoIterator for &'a mut Database {
+    type Item = &'a mut Entry;
+    type IntoIter = EntryIterMut<'a>;
+
+    fn into_iter(self) -> EntryIterMut<'a> {
+        self.root.iter_mut()
+    }
+}
+
+/// Options for parsing a database
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ParseOptions {
+    /// Keep attachments compressed in memory and decompress them on access, see
+    /// `HeaderAttachment::data`
+    pub pack_attachments: bool,
+}
+
+impl ParseOptions {
+    /// Options that keep the memory used by the parsed database low, at the cost of CPU time when
+    /// accessing attachments. Meant for mobile or embedded devices opening large databases.
+    pub fn low_memory() -> Self {
+        ParseOptions {
+            pack_attachments: true,
+        }
+    }
+}
+
+/// Options for saving a database
+#[cfg(feature = "save_kdbx4")]
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SaveOptions {
+    /// Write `Database::trailing_data` after the encrypted payload. Trailing data usually refers
+    /// to the previous contents of the file (e.g. a signature), so it is stripped by default.
+    pub preserve_trailing_data: bool,
+
+    /// Pad the file to a multiple of this many bytes, so that its size changes only in steps and
+    /// reveals less about edits to anyone watching synced files. The padding is an empty comment
+    /// in the outer header, which readers skip. Preserved trailing data is written after the
+    /// padded file.
+    pub bucket_size: Option<usize>,
+
+    /// How many backups of the previous file `Database::save_to_path` keeps, none by default
+    pub backups: usize,
+
+    /// Leave attachment contents that no entry or history entry refers to out of the file, see
+    /// `Database::remove_orphaned_attachments`. The contents of `Database::header_attachments` are
+    /// renumbered in the file, with identical ones merged. The database itself is not changed.
+    pub drop_orphaned_attachments: bool,
+
+    /// The GZip level from 0 (fastest) to 9 (smallest) to compress with, `DEFAULT_GZIP_LEVEL` if
+    /// not given. It applies to the payload of databases using `CompressionConfig::GZip` and to
+    /// the `BinaryAttachment`s of KDBX 3 databases that are stored compressed. The level is not
+    /// stored in the file.
+    pub gzip_level: Option<u32>,
+}
+
+#[cfg(feature = "save_kdbx4")]
+impl SaveOptions {
+    /// Pad the file to a multiple of `size` bytes, see `SaveOptions::bucket_size`
+    pub fn pad_to_bucket(mut self, size: usize) -> Self {
+        self.bucket_size = Some(size);
+        self
+    }
+
+    /// Keep `count` backups when saving to a path, see `SaveOptions::backups`
+    pub fn keep_backups(mut self, count: usize) -> Self {
+        self.backups = count;
+        self
+    }
+
+    /// Compress at the given GZip level, see `SaveOptions::gzip_level`
+    pub fn with_gzip_level(mut self, level: u32) -> Self {
+        self.gzip_level = Some(level);
+        self
+    }
+
+    /// The GZip level to compress with
+    pub(crate) fn effective_gzip_level(&self) -> u32 {
+        self.gzip_level.unwrap_or(crate::config::DEFAULT_GZIP_LEVEL)
+    }
+}
+
 /// Timestamps for a Group or Entry
 #[derive(Debug, Default, PartialEq, Eq, Clone)]
-#[cfg_attr(feature = "serialization", derive(serde::Serialize))]
+#[cfg_attr(feature = "serialization", derive(serde::Serialize, serde::Deserialize))]
 pub struct Times {
     /// Does this node expire
     pub expires: bool,
@@ -633,10 +1221,29 @@ impl Times {
         self.times.insert(LOCATION_CHANGED_TAG_NAME.to_string(), time);
     }
 
-    // Returns the current time, without the nanoseconds since
-    // the last leap second.
+    /// When the node expires. `None` if it does not expire, or if its expiry time is missing or the
+    /// `0001-01-01` placeholder of KDBX, which some clients write for no expiry time.
+    pub fn expires_at(&self) -> Option<NaiveDateTime> {
+        self.get_expiry()
+            .filter(|expiry| self.expires && **expiry > get_epoch_baseline())
+            .copied()
+    }
+
+    /// Record a use of the node like KeePass does: set the last access time, count the usage and,
+    /// if the node was `modified`, also set the last modification time
+    pub fn touch(&mut self, modified: bool) {
+        let now = Times::now();
+        self.set_last_access(now);
+        self.usage_count += 1;
+        if modified {
+            self.set_last_modification(now);
+        }
+    }
+
+    // Returns the current time according to the clock of the current thread (see `with_clock`),
+    // without the nanoseconds since the last leap second.
     pub fn now() -> NaiveDateTime {
-        let now = chrono::Utc::now().timestamp();
+        let now = clock::current_time().and_utc().timestamp();
         chrono::DateTime::from_timestamp(now, 0).unwrap().naive_utc()
     }
 
@@ -659,14 +1266,59 @@ impl Times {
 
 /// Collection of custom data fields for an entry or metadata
 #[derive(Debug, Default, PartialEq, Eq, Clone)]
-#[cfg_attr(feature = "serialization", derive(serde::Serialize))]
+#[cfg_attr(feature = "serialization", derive(serde::Serialize, serde::Deserialize))]
 pub struct CustomData {
     pub items: HashMap<String, CustomDataItem>,
 }
 
+impl CustomData {
+    /// Get the text of an item. Protected values are returned decrypted, and stay decrypted in
+    /// memory until the item is dropped. Items without a value or with a value that is not text
+    /// are returned as `None`.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        match self.items.get(key)?.value.as_ref()? {
+            Value::Unprotected(value) => Some(value),
+            Value::Protected(value) => std::str::from_utf8(value.unsecure()).ok(),
+            Value::Bytes(_) => None,
+        }
+    }
+
+    /// Get the value of an item, decrypting Protected values only for as long as the returned
+    /// guard lives. Items without a value or with bytes as their value are returned as `None`.
+    pub fn get_protected(&self, key: &str) -> Option<ProtectedGuard> {
+        match self.items.get(key)?.value.as_ref()? {
+            Value::Unprotected(value) => Some(ProtectedGuard(value.as_str().into())),
+            Value::Protected(value) => Some(value.decrypt()),
+            Value::Bytes(_) => None,
+        }
+    }
+
+    /// Set an item to a text value, stamping its last modification time as KeePassXC does.
+    /// Returns the previous item, if any.
+    pub fn set(&mut self, key: &str, value: &str, protected: bool) -> Option<CustomDataItem> {
+        let value = if protected {
+            Value::Protected(value.into())
+        } else {
+            Value::Unprotected(value.to_string())
+        };
+        self.items.insert(
+            key.to_string(),
+            CustomDataItem {
+                value: Some(value),
+                last_modification_time: Some(Times::now()),
+            },
+        )
+    }
+
+    /// Remove an item, returning it
+    pub fn remove(&mut self, key: &str) -> Option<CustomDataItem> {
+        self.items.remove(key)
+    }
+}
+
 /// Custom data field for an entry or metadata for internal use
 #[derive(Debug, Default, PartialEq, Eq, Clone)]
-#[cfg_attr(feature = "serialization", derive(serde::Serialize))]
+#[cfg_attr(feature = "serialization", derive(serde::Serialize, serde::Deserialize))]
 pub struct CustomDataItem {
     pub value: Option<Value>,
     pub last_modification_time: Option<NaiveDateTime>,
@@ -674,23 +1326,33 @@ pub struct CustomDataItem {
 
 /// Custom data field for an entry or metadata from XML data
 #[derive(Debug, Default, PartialEq, Eq, Clone)]
-#[cfg_attr(feature = "serialization", derive(serde::Serialize))]
+#[cfg_attr(feature = "serialization", derive(serde::Serialize, serde::Deserialize))]
 pub struct CustomDataItemDenormalized {
     pub key: String,
     pub custom_data_item: CustomDataItem,
 }
 
 /// Binary attachments stored in a database inner header
+///
+/// KDBX 4 compresses the payload as a whole, so these are compressed along with everything else
+/// at `SaveOptions::gzip_level`, and cannot be stored compressed one by one like the
+/// `BinaryAttachment`s of KDBX 3.
 #[derive(Debug, Default, PartialEq, Eq, Clone)]
-#[cfg_attr(feature = "serialization", derive(serde::Serialize))]
+#[cfg_attr(feature = "serialization", derive(serde::Serialize, serde::Deserialize))]
 pub struct HeaderAttachment {
     pub flags: u8,
     pub content: Vec<u8>,
+
+    /// Whether `content` is held gzip-compressed in memory, see `HeaderAttachment::data`
+    pub packed: bool,
+
+    /// Whether the content is kept in a `BlobStore`, with `content` holding its hash
+    pub external: bool,
 }
 
 /// Elements that have been previously deleted
 #[derive(Debug, Default, PartialEq, Eq, Clone)]
-#[cfg_attr(feature = "serialization", derive(serde::Serialize))]
+#[cfg_attr(feature = "serialization", derive(serde::Serialize, serde::Deserialize))]
 pub struct DeletedObjects {
     pub objects: Vec<DeletedObject>,
 }
@@ -708,7 +1370,7 @@ impl DeletedObjects {
 
 /// A reference to a deleted element
 #[derive(Debug, Default, PartialEq, Eq, Clone)]
-#[cfg_attr(feature = "serialization", derive(serde::Serialize))]
+#[cfg_attr(feature = "serialization", derive(serde::Serialize, serde::Deserialize))]
 pub struct DeletedObject {
     pub uuid: Uuid,
     pub deletion_time: NaiveDateTime,
@@ -732,6 +1394,17 @@ impl serde::Serialize for Color {
     }
 }
 
+#[cfg(feature = "serialization")]
+impl<'de> serde::Deserialize<'de> for Color {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s = <String as serde::Deserialize>::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
 impl FromStr for Color {
     type Err = ParseColorError;
 
@@ -753,13 +1426,13 @@ impl FromStr for Color {
 
 impl Color {
     pub fn to_string(&self) -> String {
-        format!("#{:0x}{:0x}{:0x}", self.r, self.g, self.b)
+        format!("#{:02x}{:02x}{:02x}", self.r, self.g, self.b)
     }
 }
 
 #[cfg(test)]
 mod database_tests {
-    use std::fs::File;
+    use std::{fs::File, path::Path};
 
     use crate::{error::DatabaseOpenError, Database, DatabaseKey};
 
@@ -775,6 +1448,60 @@ mod database_tests {
         Ok(())
     }
 
+    #[test]
+    fn test_verify_key() -> Result<(), DatabaseOpenError> {
+        for (path, password) in [
+            ("tests/resources/test_db_with_password.kdbx", "demopass"),
+            ("tests/resources/test_db_kdbx4_with_password_aes.kdbx", "demopass"),
+            (
+                "tests/resources/test_db_kdbx4_with_password_argon2_chacha20.kdbx",
+                "demopass",
+            ),
+            ("tests/resources/test_db_kdb_with_password.kdb", "foobar"),
+        ] {
+            let key = DatabaseKey::new().with_password(password);
+            assert!(Database::verify_key(&mut File::open(path)?, key)?, "{}", path);
+
+            let key = DatabaseKey::new().with_password("wrong");
+            assert!(!Database::verify_key(&mut File::open(path)?, key)?, "{}", path);
+        }
+
+        // only the header and the hashes after it are needed for KDBX 4
+        let data = std::fs::read("tests/resources/test_db_kdbx4_with_password_aes.kdbx")?;
+        let header_length = crate::format::read_outer_header_data(&mut &data[..])?.len();
+        let key = DatabaseKey::new().with_password("demopass");
+        assert!(Database::verify_key(&mut &data[..header_length + 64], key)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_path() {
+        let db = Database::open_path(
+            "tests/resources/test_db_kdbx4_with_keyfile_v2.kdbx",
+            Some("demopass"),
+            Some(Path::new("tests/resources/test_db_kdbx4_with_keyfile_v2.keyx")),
+        )
+        .unwrap();
+        assert_eq!(db.root.name, "Root");
+
+        let kdbx3 = Database::open_path(
+            "tests/resources/test_db_with_password.kdbx",
+            Some("demopass"),
+            None,
+        );
+        assert!(kdbx3.is_ok());
+
+        assert!(matches!(
+            Database::open_path("tests/resources/test_db_with_password.kdbx", Some("wrong"), None),
+            Err(DatabaseOpenError::InvalidCredentials)
+        ));
+        assert!(matches!(
+            Database::open_path("tests/resources/does_not_exist.kdbx", Some("demopass"), None),
+            Err(DatabaseOpenError::Io(_))
+        ));
+    }
+
     #[test]
     fn test_open_invalid_version_header_size() {
         assert!(Database::parse(&[], DatabaseKey::new().with_password("testing")).is_err());
@@ -0,0 +1,503 @@
+//! Editing entries with consistent history
+//!
+//! KeePass keeps the versions of an entry from before each edit in its history. Editing
+//! `Entry::fields` directly leaves it to the caller to take such a snapshot, so histories end up
+//! depending on which code path made the change. `Entry::edit` groups edits and captures the
+//! state from before the edit according to a `HistoryPolicy`:
+//!
+//! ```
+//! use keepass::db::{Entry, MutationOptions, Value};
+//!
+//! let mut entry = Entry::new();
+//! entry.set_field_with("Title", Value::Unprotected("Mail".to_string()), &MutationOptions::default());
+//!
+//! // like confirming the edit dialog of KeePass: one snapshot for all changes
+//! let mut edit = entry.edit(&MutationOptions::default());
+//! edit.set_field("UserName", Value::Unprotected("jdoe".to_string()));
+//! edit.set_field("URL", Value::Unprotected("https://mail.example.com".to_string()));
+//! edit.commit();
+//!
+//! let history = entry.history.as_ref().unwrap().get_entries();
+//! assert_eq!(history.len(), 2);
+//! assert_eq!(history[0].get_title(), Some("Mail"));
+//! assert_eq!(history[0].get_username(), None);
+//! ```
+//!
+//! Edits also keep the times of the entry as KeePass does, which merges rely on: committing an
+//! edit sets the last modification and access times and counts a usage. `Group::rename` does the
+//! same for groups, `Database::move_node` records when and from where a node was moved, and
+//! `Times::touch` records any other use.
+
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::db::{Database, Entry, Group, History, Node, NodeRef, Times, Value};
+
+/// Errors when moving a node with `Database::move_node`
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum MoveError {
+    #[error("Could not find node {0}")]
+    NodeNotFound(Uuid),
+
+    #[error("Could not find group {0}")]
+    GroupNotFound(Uuid),
+
+    #[error("The root group cannot be moved")]
+    CannotMoveRoot,
+
+    #[error("Cannot move group {group} into its own subgroup {parent}")]
+    MoveIntoOwnSubtree { group: Uuid, parent: Uuid },
+}
+
+/// When the state of an entry from before an edit is added to its history
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HistoryPolicy {
+    /// Before every single change
+    Always,
+
+    /// Once when an edit with changes is committed, no matter how many changes it made
+    OnSave,
+
+    /// Never, e.g. for bulk imports or automated changes that should not clutter the history
+    Never,
+}
+
+/// How entries are edited, see `Entry::edit`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MutationOptions {
+    pub history: HistoryPolicy,
+
+    /// Remove the oldest versions from the history when it grows beyond this many items, like
+    /// `Meta::history_max_items`
+    pub max_history_items: Option<usize>,
+
+    /// Touch the times of the entry when it was changed, see `Times::touch`
+    pub update_modification_time: bool,
+}
+
+impl Default for MutationOptions {
+    fn default() -> Self {
+        MutationOptions {
+            history: HistoryPolicy::OnSave,
+            max_history_items: None,
+            update_modification_time: true,
+        }
+    }
+}
+
+impl MutationOptions {
+    pub fn with_history(mut self, history: HistoryPolicy) -> Self {
+        self.history = history;
+        self
+    }
+
+    pub fn with_max_history_items(mut self, max_history_items: usize) -> Self {
+        self.max_history_items = Some(max_history_items);
+        self
+    }
+}
+
+/// A group of changes to an entry, see `Entry::edit`. Changes are committed when the edit is
+/// dropped, or explicitly with `EntryEdit::commit`.
+pub struct EntryEdit<'a> {
+    entry: &'a mut Entry,
+    options: MutationOptions,
+
+    /// The state before the edit, or before the last change with `HistoryPolicy::Always`
+    before: Entry,
+    changed: bool,
+    committed: bool,
+}
+
+impl<'a> EntryEdit<'a> {
+    /// Set a field, returning its previous value
+    pub fn set_field(&mut self, name: &str, value: Value) -> Option<Value> {
+        self.change(|entry| entry.fields.insert(name.to_string(), value))
+    }
+
+    /// Remove a field, returning its value
+    pub fn remove_field(&mut self, name: &str) -> Option<Value> {
+        self.change(|entry| entry.fields.remove(name))
+    }
+
+    /// Replace the tags of the entry
+    pub fn set_tags(&mut self, tags: Vec<String>) {
+        self.change(|entry| entry.tags = tags)
+    }
+
+    /// Make any other change to the entry
+    pub fn change<T>(&mut self, change: impl FnOnce(&mut Entry) -> T) -> T {
+        let result = change(self.entry);
+
+        if !same_version(self.entry, &self.before) {
+            self.changed = true;
+            if self.options.history == HistoryPolicy::Always {
+                self.record();
+            }
+        }
+        result
+    }
+
+    /// Commit the changes, returning whether the entry was changed
+    pub fn commit(mut self) -> bool {
+        self.finish()
+    }
+
+    fn finish(&mut self) -> bool {
+        if self.committed {
+            return self.changed;
+        }
+        self.committed = true;
+
+        // changes that were reverted within the edit do not count
+        if self.options.history != HistoryPolicy::Always {
+            self.changed = !same_version(self.entry, &self.before);
+        }
+
+        if self.changed {
+            if self.options.history == HistoryPolicy::OnSave {
+                self.record();
+            }
+            if self.options.update_modification_time {
+                self.entry.times.touch(true);
+            }
+        }
+        self.changed
+    }
+
+    /// Add the state before the last change to the history and start from the current state
+    fn record(&mut self) {
+        let before = std::mem::replace(&mut self.before, snapshot(self.entry));
+
+        let history = self.entry.history.get_or_insert_with(History::default);
+        // histories kept with `Entry::update_history` already hold the previous version
+        let known = history
+            .entries
+            .first()
+            .is_some_and(|newest| same_version(newest, &before));
+        if !known {
+            history.add_entry(before);
+        }
+        if let Some(max) = self.options.max_history_items {
+            history.entries.truncate(max);
+        }
+    }
+}
+
+impl<'a> Drop for EntryEdit<'a> {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+fn snapshot(entry: &Entry) -> Entry {
+    let mut snapshot = entry.clone();
+    snapshot.history = None;
+    snapshot
+}
+
+/// Whether two entries are the same version, ignoring their times and history
+fn same_version(a: &Entry, b: &Entry) -> bool {
+    a.fields == b.fields
+        && a.tags == b.tags
+        && a.autotype == b.autotype
+        && a.custom_data == b.custom_data
+        && a.icon_id == b.icon_id
+        && a.custom_icon_uuid == b.custom_icon_uuid
+        && a.foreground_color == b.foreground_color
+        && a.background_color == b.background_color
+        && a.override_url == b.override_url
+        && a.quality_check == b.quality_check
+        && a.previous_parent_group == b.previous_parent_group
+        && a.attachments == b.attachments
+}
+
+impl Entry {
+    /// Start a group of changes to the entry, which adds the state from before the changes to
+    /// the history according to `options`
+    pub fn edit(&mut self, options: &MutationOptions) -> EntryEdit<'_> {
+        EntryEdit {
+            before: snapshot(self),
+            entry: self,
+            options: options.clone(),
+            changed: false,
+            committed: false,
+        }
+    }
+
+    /// Set a single field as one edit, returning its previous value
+    pub fn set_field_with(&mut self, name: &str, value: Value, options: &MutationOptions) -> Option<Value> {
+        self.edit(options).set_field(name, value)
+    }
+
+    /// Remove a single field as one edit, returning its value
+    pub fn remove_field_with(&mut self, name: &str, options: &MutationOptions) -> Option<Value> {
+        self.edit(options).remove_field(name)
+    }
+
+    /// Set a single field as one edit with the default `MutationOptions`, returning its previous
+    /// value
+    pub fn set_field(&mut self, name: &str, value: Value) -> Option<Value> {
+        self.set_field_with(name, value, &MutationOptions::default())
+    }
+}
+
+impl Group {
+    /// Rename the group, touching its times if the name changed
+    pub fn rename(&mut self, name: &str) {
+        if self.name != name {
+            self.name = name.to_string();
+            self.times.touch(true);
+        }
+    }
+}
+
+impl Database {
+    /// Move an entry or a group into `new_parent` at `position` among its children, setting the
+    /// time its location changed and remembering the group it was in as its
+    /// `previous_parent_group`. `position` counts the children of `new_parent` without the moved
+    /// node, `None` or a position past the end appends it. Moving a node within its group only
+    /// reorders it.
+    ///
+    /// The move is checked before anything is changed, so on failure the database is left as it
+    /// was, including when a group would be moved into its own subtree.
+    pub fn move_node(
+        &mut self,
+        node: Uuid,
+        new_parent: Uuid,
+        position: Option<usize>,
+    ) -> Result<(), MoveError> {
+        if node == self.root.uuid {
+            return Err(MoveError::CannotMoveRoot);
+        }
+        let parent = self.root.parent_of(&node).ok_or(MoveError::NodeNotFound(node))?;
+        match self.root.find_by_uuid(&new_parent) {
+            Some(NodeRef::Group(_)) => {}
+            _ => return Err(MoveError::GroupNotFound(new_parent)),
+        }
+        if let Some(NodeRef::Group(group)) = self.root.find_by_uuid(&node) {
+            if group.find_by_uuid(&new_parent).is_some() {
+                return Err(MoveError::MoveIntoOwnSubtree {
+                    group: node,
+                    parent: new_parent,
+                });
+            }
+        }
+
+        let mut removed = self
+            .root
+            .take_by_uuid(&node)
+            .ok_or(MoveError::NodeNotFound(node))?;
+        if parent != new_parent {
+            let (times, previous_parent_group) = match removed {
+                Node::Entry(ref mut e) => (&mut e.times, &mut e.previous_parent_group),
+                Node::Group(ref mut g) => (&mut g.times, &mut g.previous_parent_group),
+            };
+            times.set_location_changed(Times::now());
+            *previous_parent_group = Some(parent);
+        }
+
+        // the new parent was found above and cannot have been inside the removed node
+        let children = &mut self
+            .root
+            .group_by_uuid_mut(&new_parent)
+            .expect("new parent outside of the moved node")
+            .children;
+        let position = position.unwrap_or(children.len()).min(children.len());
+        children.insert(position, removed);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod mutation_tests {
+    use chrono::NaiveDateTime;
+
+    use crate::db::{with_clock, Database, Entry, Group, ManualClock, Value};
+
+    use super::{HistoryPolicy, MoveError, MutationOptions};
+
+    fn value(v: &str) -> Value {
+        Value::Unprotected(v.to_string())
+    }
+
+    fn history_titles(entry: &Entry) -> Vec<Option<&str>> {
+        entry
+            .history
+            .as_ref()
+            .map(|h| h.get_entries().iter().map(|e| e.get_title()).collect())
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn test_history_policies() {
+        let always = MutationOptions::default().with_history(HistoryPolicy::Always);
+        let mut entry = Entry::new();
+        entry.set_field_with("Title", value("A"), &always);
+        {
+            let mut edit = entry.edit(&always);
+            edit.set_field("Title", value("B"));
+            edit.set_field("Title", value("C"));
+            // unchanged values do not add versions
+            edit.set_field("Title", value("C"));
+        }
+        assert_eq!(history_titles(&entry), vec![Some("B"), Some("A"), None]);
+
+        let mut entry = Entry::new();
+        let mut edit = entry.edit(&MutationOptions::default());
+        edit.set_field("Title", value("A"));
+        edit.set_field("UserName", value("jdoe"));
+        assert!(edit.commit());
+        assert_eq!(history_titles(&entry), vec![None]);
+
+        let mut edit = entry.edit(&MutationOptions::default());
+        edit.set_field("Title", value("reverted"));
+        edit.set_field("Title", value("A"));
+        assert!(!edit.commit());
+        assert_eq!(history_titles(&entry), vec![None]);
+
+        let never = MutationOptions::default().with_history(HistoryPolicy::Never);
+        assert_eq!(
+            entry.set_field_with("Title", value("B"), &never),
+            Some(value("A"))
+        );
+        assert_eq!(history_titles(&entry), vec![None]);
+        assert!(entry.times.get_last_modification().is_some());
+    }
+
+    #[test]
+    fn test_max_history_items() {
+        let options = MutationOptions::default().with_max_history_items(2);
+        let mut entry = Entry::new();
+        for title in ["A", "B", "C", "D"] {
+            entry.set_field_with("Title", value(title), &options);
+        }
+        assert_eq!(history_titles(&entry), vec![Some("C"), Some("B")]);
+
+        entry.remove_field_with("Title", &options);
+        assert_eq!(entry.get_title(), None);
+        assert_eq!(history_titles(&entry), vec![Some("D"), Some("C")]);
+    }
+
+    #[test]
+    fn test_update_history_is_not_duplicated() {
+        let mut entry = Entry::new();
+        entry.fields.insert("Title".to_string(), value("A"));
+        entry.update_history();
+
+        entry.set_field_with("Title", value("B"), &MutationOptions::default());
+        assert_eq!(history_titles(&entry), vec![Some("A")]);
+    }
+
+    #[test]
+    fn test_times_are_maintained() {
+        let start: NaiveDateTime = "2024-01-01T00:00:00".parse().unwrap();
+        let later: NaiveDateTime = "2024-02-01T00:00:00".parse().unwrap();
+        let clock = ManualClock::new(start);
+
+        let mut db = Database::new(Default::default());
+        let (mut entry, mut group) = with_clock(clock.clone(), || (Entry::new(), Group::new("Work")));
+        let (entry_uuid, group_uuid) = (entry.uuid, group.uuid);
+        clock.set(later);
+
+        with_clock(clock.clone(), || {
+            entry.set_field("Title", value("Mail"));
+            assert_eq!(entry.times.get_last_modification(), Some(&later));
+            assert_eq!(entry.times.get_last_access(), Some(&later));
+            assert_eq!(entry.times.usage_count, 1);
+
+            // setting the same value again is no edit
+            entry.set_field("Title", value("Mail"));
+            assert_eq!(entry.times.usage_count, 1);
+
+            group.rename("Work");
+            assert_eq!(group.times.get_last_modification(), Some(&start));
+            group.rename("Office");
+            assert_eq!(group.times.get_last_modification(), Some(&later));
+
+            entry.times.touch(false);
+            assert_eq!(entry.times.usage_count, 2);
+        });
+
+        let root = db.root.uuid;
+        db.root.add_child(group);
+        db.root.add_child(entry);
+        with_clock(clock.clone(), || db.move_node(entry_uuid, group_uuid, None)).unwrap();
+
+        let moved = &db.root.groups()[0].entries()[0];
+        assert_eq!(moved.uuid, entry_uuid);
+        assert_eq!(moved.previous_parent_group, Some(root));
+        assert_eq!(moved.times.get_location_changed(), Some(&later));
+
+        assert_eq!(
+            db.move_node(root, group_uuid, None),
+            Err(MoveError::CannotMoveRoot)
+        );
+        assert_eq!(
+            db.move_node(group_uuid, group_uuid, None),
+            Err(MoveError::MoveIntoOwnSubtree {
+                group: group_uuid,
+                parent: group_uuid
+            })
+        );
+        assert_eq!(
+            db.move_node(entry_uuid, entry_uuid, None),
+            Err(MoveError::GroupNotFound(entry_uuid))
+        );
+    }
+
+    #[test]
+    fn test_move_node_positions() {
+        let mut db = Database::new(Default::default());
+        let mut work = Group::new("Work");
+        let mut servers = Group::new("Servers");
+        servers.add_child(Group::new("Old"));
+        let servers_uuid = servers.uuid;
+        work.add_child(servers);
+        let work_uuid = work.uuid;
+        db.root.add_child(work);
+        for title in ["A", "B", "C"] {
+            let mut entry = Entry::new();
+            entry.fields.insert("Title".to_string(), value(title));
+            db.root.add_child(entry);
+        }
+        let titles = |group: &Group| -> Vec<String> {
+            group
+                .entries()
+                .iter()
+                .map(|e| e.get_title().unwrap().to_string())
+                .collect()
+        };
+        let c = db.root.entries()[2].uuid;
+        let location_changed = *db.root.entries()[2].times.get_location_changed().unwrap();
+
+        // reordering within the group is no move
+        db.move_node(c, db.root.uuid, Some(0)).unwrap();
+        assert_eq!(titles(&db.root), vec!["C", "A", "B"]);
+        assert_eq!(db.root.children[0].uuid(), c);
+        assert_eq!(db.root.entries()[0].previous_parent_group, None);
+        assert_eq!(
+            db.root.entries()[0].times.get_location_changed(),
+            Some(&location_changed)
+        );
+
+        db.move_node(c, servers_uuid, Some(0)).unwrap();
+        let servers = db.root.groups()[0].groups()[0];
+        assert_eq!(servers.children[0].uuid(), c);
+        assert_eq!(servers.entries()[0].previous_parent_group, Some(db.root.uuid));
+
+        // a group cannot move into its own subtree, and nothing changes when it tries
+        let before = db.clone();
+        assert_eq!(
+            db.move_node(work_uuid, servers_uuid, None),
+            Err(MoveError::MoveIntoOwnSubtree {
+                group: work_uuid,
+                parent: servers_uuid
+            })
+        );
+        assert_eq!(db, before);
+
+        db.move_node(servers_uuid, db.root.uuid, Some(99)).unwrap();
+        assert_eq!(db.root.children.last().unwrap().uuid(), servers_uuid);
+    }
+}
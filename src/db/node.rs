@@ -1,10 +1,12 @@
 use std::collections::VecDeque;
 
+use uuid::Uuid;
+
 use crate::db::{entry::Entry, group::Group};
 
 /// An owned node in the database tree structure which can either be an Entry or Group
 #[derive(Debug, Eq, PartialEq, Clone)]
-#[cfg_attr(feature = "serialization", derive(serde::Serialize))]
+#[cfg_attr(feature = "serialization", derive(serde::Serialize, serde::Deserialize))]
 pub enum Node {
     Group(Group),
     Entry(Entry),
@@ -18,6 +20,14 @@ impl Node {
     pub fn as_mut<'a>(&'a mut self) -> NodeRefMut<'a> {
         self.into()
     }
+
+    /// The unique identifier of the group or entry
+    pub fn uuid(&self) -> Uuid {
+        match self {
+            Node::Group(g) => g.uuid,
+            Node::Entry(e) => e.uuid,
+        }
+    }
 }
 
 impl From<Entry> for Node {
@@ -88,3 +98,93 @@ impl<'a> Iterator for NodeIter<'a> {
         Some(head)
     }
 }
+
+/// An iterator over Group and Entry references together with the path of the group they are in,
+/// see `Group::iter_with_paths`
+pub struct NodePathIter<'a> {
+    start: &'a Group,
+    queue: VecDeque<(Vec<&'a str>, NodeRef<'a>)>,
+}
+
+impl<'a> NodePathIter<'a> {
+    pub(crate) fn new(start: &'a Group) -> Self {
+        Self {
+            start,
+            queue: VecDeque::from([(Vec::new(), NodeRef::Group(start))]),
+        }
+    }
+}
+
+impl<'a> Iterator for NodePathIter<'a> {
+    type Item = (Vec<&'a str>, NodeRef<'a>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (path, head) = self.queue.pop_front()?;
+
+        if let NodeRef::Group(g) = head {
+            // paths are relative to the group the iteration started at
+            let mut child_path = path.clone();
+            if !std::ptr::eq(g, self.start) {
+                child_path.push(&g.name);
+            }
+            self.queue
+                .extend(g.children.iter().map(|n| (child_path.clone(), n.into())));
+        }
+
+        Some((path, head))
+    }
+}
+
+/// An iterator over mutable references to all entries below a group together with the path of
+/// the group they are in, see `Group::iter_mut_with_paths`
+pub struct EntryPathIterMut<'a> {
+    queue: VecDeque<(Vec<&'a str>, &'a mut Node)>,
+}
+
+impl<'a> EntryPathIterMut<'a> {
+    pub(crate) fn new(start: &'a mut Group) -> Self {
+        Self {
+            queue: start.children.iter_mut().map(|n| (Vec::new(), n)).collect(),
+        }
+    }
+}
+
+impl<'a> Iterator for EntryPathIterMut<'a> {
+    type Item = (Vec<&'a str>, &'a mut Entry);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.queue.pop_front()? {
+                (path, Node::Entry(e)) => return Some((path, e)),
+                (path, Node::Group(Group { name, children, .. })) => {
+                    let name: &'a String = name;
+                    let mut child_path = path;
+                    child_path.push(name);
+                    self.queue
+                        .extend(children.iter_mut().map(|n| (child_path.clone(), n)));
+                }
+            }
+        }
+    }
+}
+
+/// An iterator over mutable references to all entries below a group, see `Group::iter_mut`
+pub struct EntryIterMut<'a> {
+    inner: EntryPathIterMut<'a>,
+}
+
+impl<'a> EntryIterMut<'a> {
+    pub(crate) fn new(start: &'a mut Group) -> Self {
+        Self {
+            inner: EntryPathIterMut::new(start),
+        }
+    }
+}
+
+impl<'a> Iterator for EntryIterMut<'a> {
+    type Item = &'a mut Entry;
+
+    fn next(&mut self) -> Option<&'a mut Entry> {
+        self.inner.next().map(|(_, entry)| entry)
+    }
+}
@@ -0,0 +1,391 @@
+//! Structured data in the notes of an entry
+//!
+//! Many users keep recovery codes, account numbers and similar data in the notes, using a loose
+//! Markdown-like layout:
+//!
+//! ````text
+//! Account: 1234-5678
+//! Support PIN: 4711
+//!
+//! ## Recovery codes
+//! ```
+//! abcd-efgh
+//! ijkl-mnop
+//! ```
+//! ````
+//!
+//! `StructuredNotes` splits the notes into sections at headings, and every section into
+//! `key: value` fields, fenced blocks and the remaining free text. Writing the notes back with
+//! `to_notes` produces a canonical layout: the fields of a section come first, followed by its
+//! fenced blocks and its free text.
+
+use crate::db::{Entry, Value};
+
+/// A fenced block, e.g. a list of recovery codes
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serialization", derive(serde::Serialize))]
+pub struct FencedBlock {
+    /// The text after the opening fence, e.g. a language
+    pub info: String,
+
+    /// The lines between the fences
+    pub content: String,
+}
+
+impl FencedBlock {
+    /// The non-empty lines of the block, trimmed
+    pub fn lines(&self) -> impl Iterator<Item = &str> {
+        self.content.lines().map(str::trim).filter(|l| !l.is_empty())
+    }
+}
+
+/// A part of the notes, started by a heading
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serialization", derive(serde::Serialize))]
+pub struct NotesSection {
+    /// The heading and its level (the number of `#`), `None` for the text before the first
+    /// heading
+    pub heading: Option<(usize, String)>,
+
+    /// `key: value` lines, in order
+    pub fields: Vec<(String, String)>,
+
+    pub blocks: Vec<FencedBlock>,
+
+    /// Everything else, with surrounding empty lines removed
+    pub text: String,
+}
+
+impl NotesSection {
+    /// The value of the first field with the given key, ignoring case
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Set the value of a field, replacing the first field with the same key (ignoring case) or
+    /// adding a new one
+    pub fn set(&mut self, key: &str, value: &str) {
+        match self.fields.iter_mut().find(|(k, _)| k.eq_ignore_ascii_case(key)) {
+            Some(field) => field.1 = value.to_string(),
+            None => self.fields.push((key.to_string(), value.to_string())),
+        }
+    }
+
+    /// Remove all fields with the given key, ignoring case. Returns the first removed value.
+    pub fn remove(&mut self, key: &str) -> Option<String> {
+        let first = self.get(key).map(str::to_string);
+        self.fields.retain(|(k, _)| !k.eq_ignore_ascii_case(key));
+        first
+    }
+
+    fn is_empty(&self) -> bool {
+        self.heading.is_none() && self.fields.is_empty() && self.blocks.is_empty() && self.text.is_empty()
+    }
+}
+
+/// The notes of an entry, split into sections
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serialization", derive(serde::Serialize))]
+pub struct StructuredNotes {
+    pub sections: Vec<NotesSection>,
+}
+
+impl StructuredNotes {
+    pub fn parse(notes: &str) -> Self {
+        let mut sections = vec![NotesSection::default()];
+        let mut text: Vec<&str> = Vec::new();
+        let mut lines = notes.lines();
+
+        while let Some(line) = lines.next() {
+            let section = sections.last_mut().unwrap();
+
+            if let Some((fence, info)) = parse_fence(line) {
+                let mut content = Vec::new();
+                for line in lines.by_ref() {
+                    if line.trim_start().starts_with(fence)
+                        && line
+                            .trim()
+                            .trim_start_matches(fence.chars().next().unwrap())
+                            .is_empty()
+                    {
+                        break;
+                    }
+                    content.push(line);
+                }
+                section.blocks.push(FencedBlock {
+                    info: info.to_string(),
+                    content: content.join("\n"),
+                });
+            } else if let Some(heading) = parse_heading(line) {
+                section.text = join_text(&text);
+                text.clear();
+                sections.push(NotesSection {
+                    heading: Some(heading),
+                    ..Default::default()
+                });
+            } else if let Some((key, value)) = parse_field(line) {
+                section.fields.push((key.to_string(), value.to_string()));
+            } else {
+                text.push(line);
+            }
+        }
+        sections.last_mut().unwrap().text = join_text(&text);
+
+        if sections[0].is_empty() {
+            sections.remove(0);
+        }
+        StructuredNotes { sections }
+    }
+
+    /// The section before the first heading, created if needed
+    pub fn preamble_mut(&mut self) -> &mut NotesSection {
+        if self.sections.first().is_none_or(|s| s.heading.is_some()) {
+            self.sections.insert(0, NotesSection::default());
+        }
+        &mut self.sections[0]
+    }
+
+    /// The first section with the given heading, ignoring case
+    pub fn section(&self, heading: &str) -> Option<&NotesSection> {
+        self.sections.iter().find(|s| {
+            s.heading
+                .as_ref()
+                .is_some_and(|(_, h)| h.eq_ignore_ascii_case(heading))
+        })
+    }
+
+    pub fn section_mut(&mut self, heading: &str) -> Option<&mut NotesSection> {
+        self.sections.iter_mut().find(|s| {
+            s.heading
+                .as_ref()
+                .is_some_and(|(_, h)| h.eq_ignore_ascii_case(heading))
+        })
+    }
+
+    /// The value of the first field with the given key in any section
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.sections.iter().find_map(|s| s.get(key))
+    }
+
+    /// Write the notes in the canonical layout
+    pub fn to_notes(&self) -> String {
+        let mut parts = Vec::new();
+
+        for section in &self.sections {
+            let mut lines = Vec::new();
+            if let Some((level, ref heading)) = section.heading {
+                lines.push(format!("{} {}", "#".repeat(level.max(1)), heading));
+            }
+            for (key, value) in &section.fields {
+                lines.push(format!("{}: {}", key, value));
+            }
+            let mut part = lines.join("\n");
+
+            for block in &section.blocks {
+                // a block containing a fence needs a longer one
+                let mut fence = "```".to_string();
+                while block.content.contains(&fence) {
+                    fence.push('`');
+                }
+                if !part.is_empty() {
+                    part.push_str("\n\n");
+                }
+                part.push_str(&format!("{}{}\n", fence, block.info));
+                if !block.content.is_empty() {
+                    part.push_str(&block.content);
+                    part.push('\n');
+                }
+                part.push_str(&fence);
+            }
+
+            if !section.text.is_empty() {
+                if !part.is_empty() {
+                    part.push_str("\n\n");
+                }
+                part.push_str(&section.text);
+            }
+            parts.push(part);
+        }
+
+        parts.join("\n\n")
+    }
+}
+
+impl Entry {
+    /// The notes of the entry, parsed into sections
+    pub fn structured_notes(&self) -> StructuredNotes {
+        let notes = self.get_protected("Notes");
+        StructuredNotes::parse(notes.as_ref().and_then(|n| n.as_str()).unwrap_or_default())
+    }
+
+    /// Replace the notes of the entry with the canonical form of `notes`, keeping their
+    /// protection
+    pub fn set_structured_notes(&mut self, notes: &StructuredNotes) {
+        let text = notes.to_notes();
+        let value = match self.fields.get("Notes") {
+            Some(Value::Protected(_)) => Value::Protected(text.as_bytes().into()),
+            _ => Value::Unprotected(text),
+        };
+        self.fields.insert("Notes".to_string(), value);
+    }
+}
+
+fn parse_fence(line: &str) -> Option<(&str, &str)> {
+    let trimmed = line.trim_start();
+    for c in ['`', '~'] {
+        let length = trimmed.chars().take_while(|&x| x == c).count();
+        if length >= 3 {
+            let (fence, info) = trimmed.split_at(length);
+            return Some((fence, info.trim()));
+        }
+    }
+    None
+}
+
+fn parse_heading(line: &str) -> Option<(usize, String)> {
+    let level = line.chars().take_while(|&c| c == '#').count();
+    let rest = &line[level..];
+    if level == 0 || level > 6 || !rest.starts_with(' ') || rest.trim().is_empty() {
+        return None;
+    }
+    Some((level, rest.trim().to_string()))
+}
+
+/// A `key: value` line. Keys are short and made of words, so that URLs and sentences are not
+/// mistaken for fields.
+fn parse_field(line: &str) -> Option<(&str, &str)> {
+    let (key, value) = line.split_once(':')?;
+    if !(value.is_empty() || value.starts_with(' ') || value.starts_with('\t')) {
+        return None;
+    }
+
+    let valid_key = !key.is_empty()
+        && key.len() <= 64
+        && !key.starts_with(char::is_whitespace)
+        && !key.ends_with(char::is_whitespace)
+        && key
+            .chars()
+            .all(|c| c.is_alphanumeric() || matches!(c, ' ' | '_' | '-' | '.' | '(' | ')' | '/'));
+    if !valid_key {
+        return None;
+    }
+
+    Some((key, value.trim()))
+}
+
+fn join_text(lines: &[&str]) -> String {
+    lines.join("\n").trim_matches('\n').to_string()
+}
+
+#[cfg(test)]
+mod notes_tests {
+    use crate::db::{Entry, Value};
+
+    use super::{FencedBlock, StructuredNotes};
+
+    const NOTES: &str = "Account: 1234-5678
+Support PIN: 4711
+Website: https://example.com/login
+
+Remember to rotate the PIN.
+
+## Recovery codes
+```text
+abcd-efgh
+
+ijkl-mnop
+```
+Created: 2024-01-01
+
+# Security questions
+First pet: Rex
+~~~
+unclosed fence";
+
+    #[test]
+    fn test_parse_notes() {
+        let notes = StructuredNotes::parse(NOTES);
+        assert_eq!(notes.sections.len(), 3);
+
+        let preamble = &notes.sections[0];
+        assert_eq!(preamble.heading, None);
+        assert_eq!(preamble.get("support pin"), Some("4711"));
+        assert_eq!(preamble.get("Website"), Some("https://example.com/login"));
+        assert_eq!(preamble.text, "Remember to rotate the PIN.");
+
+        let codes = notes.section("recovery codes").unwrap();
+        assert_eq!(codes.heading, Some((2, "Recovery codes".to_string())));
+        assert_eq!(codes.blocks[0].info, "text");
+        assert_eq!(
+            codes.blocks[0].lines().collect::<Vec<_>>(),
+            vec!["abcd-efgh", "ijkl-mnop"]
+        );
+        assert_eq!(codes.get("Created"), Some("2024-01-01"));
+
+        assert_eq!(notes.get("First pet"), Some("Rex"));
+        assert_eq!(notes.sections[2].blocks[0].content, "unclosed fence");
+
+        // lines that only look similar are kept as text
+        let notes = StructuredNotes::parse(
+            "see https://example.com\nA sentence: with a colon, is still a field\n#hashtag",
+        );
+        assert_eq!(notes.sections[0].fields.len(), 1);
+        assert_eq!(notes.sections[0].text, "see https://example.com\n#hashtag");
+    }
+
+    #[test]
+    fn test_write_notes() {
+        let mut entry = Entry::new();
+        entry
+            .fields
+            .insert("Notes".to_string(), Value::Protected(NOTES.as_bytes().into()));
+
+        let mut notes = entry.structured_notes();
+        notes.preamble_mut().set("support pin", "0815");
+        notes.preamble_mut().remove("Website");
+        notes.section_mut("Security questions").unwrap().blocks.clear();
+        notes
+            .section_mut("Recovery codes")
+            .unwrap()
+            .blocks
+            .push(FencedBlock {
+                info: String::new(),
+                content: "```\nnested".to_string(),
+            });
+        entry.set_structured_notes(&notes);
+
+        assert!(matches!(entry.fields["Notes"], Value::Protected(_)));
+        assert_eq!(
+            entry.get("Notes").unwrap(),
+            "Account: 1234-5678
+Support PIN: 0815
+
+Remember to rotate the PIN.
+
+## Recovery codes
+Created: 2024-01-01
+
+```text
+abcd-efgh
+
+ijkl-mnop
+```
+
+````
+```
+nested
+````
+
+# Security questions
+First pet: Rex"
+        );
+
+        // the canonical form is stable
+        let reparsed = entry.structured_notes();
+        assert_eq!(reparsed, notes);
+        assert_eq!(reparsed.to_notes(), entry.get("Notes").unwrap());
+    }
+}
@@ -1,4 +1,5 @@
 use base32;
+use base64::Engine;
 use std::time::{Duration, SystemTime, SystemTimeError, UNIX_EPOCH};
 use thiserror::Error;
 use totp_lite::{totp_custom, Sha1, Sha256, Sha512};
@@ -8,6 +9,12 @@ use zeroize::{Zeroize, ZeroizeOnDrop};
 const DEFAULT_PERIOD: u64 = 30;
 const DEFAULT_DIGITS: u32 = 8;
 
+/// Number of characters of Steam Guard codes
+const STEAM_DIGITS: u32 = 5;
+
+/// The characters of Steam Guard codes
+const STEAM_ALPHABET: &[u8] = b"23456789BCDFGHJKMNPQRTVWXY";
+
 /// Choices of hash algorithm for TOTP
 #[derive(Debug, PartialEq, Eq, Zeroize, ZeroizeOnDrop)]
 pub enum TOTPAlgorithm {
@@ -29,6 +36,18 @@ impl std::str::FromStr for TOTPAlgorithm {
     }
 }
 
+/// How a one time password is presented to the user
+#[derive(Debug, Default, PartialEq, Eq, Zeroize, ZeroizeOnDrop)]
+pub enum TOTPEncoder {
+    /// Decimal digits, as specified by RFC 6238
+    #[default]
+    Numeric,
+
+    /// Five characters of an alphabet without lookalikes, as used by Steam Guard. Written as
+    /// `encoder=steam` in `otpauth://` URIs by KeePassXC.
+    Steam,
+}
+
 /// Time-based one time password settings
 #[derive(Debug, PartialEq, Eq, Zeroize, ZeroizeOnDrop)]
 pub struct TOTP {
@@ -37,6 +56,7 @@ pub struct TOTP {
     pub period: u64,
     pub digits: u32,
     pub algorithm: TOTPAlgorithm,
+    pub encoder: TOTPEncoder,
 
     secret: Vec<u8>,
 }
@@ -86,6 +106,16 @@ pub enum TOTPError {
 
     #[error("Bad hash algorithm: '{}'", _0)]
     BadAlgorithm(String),
+
+    #[error("Bad encoder: '{}'", _0)]
+    BadEncoder(String),
+
+    /// The OTP is not time-based, e.g. a counter-based HOTP of the KeeOtp plugin
+    #[error("Unsupported OTP type: '{}'", _0)]
+    UnsupportedType(String),
+
+    #[error("Bad TOTP settings: '{}'", _0)]
+    BadSettings(String),
 }
 
 impl std::str::FromStr for TOTP {
@@ -105,6 +135,7 @@ impl std::str::FromStr for TOTP {
         let mut period: u64 = DEFAULT_PERIOD;
         let mut digits: u32 = DEFAULT_DIGITS;
         let mut algorithm: TOTPAlgorithm = TOTPAlgorithm::Sha1;
+        let mut encoder = TOTPEncoder::Numeric;
 
         for pair in query_pairs {
             let (k, v) = pair;
@@ -114,6 +145,8 @@ impl std::str::FromStr for TOTP {
                 "period" => period = v.parse()?,
                 "digits" => digits = v.parse()?,
                 "algorithm" => algorithm = v.parse()?,
+                "encoder" if v.eq_ignore_ascii_case("steam") => encoder = TOTPEncoder::Steam,
+                "encoder" => return Err(TOTPError::BadEncoder(v.to_string())),
                 _ => {}
             }
         }
@@ -123,6 +156,10 @@ impl std::str::FromStr for TOTP {
         let secret =
             base32::decode(base32::Alphabet::Rfc4648 { padding: true }, &secret).ok_or(TOTPError::Base32)?;
 
+        if encoder == TOTPEncoder::Steam {
+            digits = STEAM_DIGITS;
+        }
+
         Ok(TOTP {
             label,
             secret,
@@ -130,17 +167,108 @@ impl std::str::FromStr for TOTP {
             period,
             digits,
             algorithm,
+            encoder,
         })
     }
 }
 
 impl TOTP {
+    /// Parse the `otp` field as written by the KeeOtp plugin, a query string such as
+    /// `key=JBSWY3DPEHPK3PXP&step=30&size=6&otpHashMode=Sha256`. The key is Base32 unless an
+    /// `encoding` of `Base64`, `Hex` or `UTF8` is given.
+    pub fn from_keeotp(s: &str) -> Result<TOTP, TOTPError> {
+        let mut key: Option<String> = None;
+        let mut encoding = "base32".to_string();
+        let mut period: u64 = DEFAULT_PERIOD;
+        let mut digits: u32 = 6;
+        let mut algorithm: TOTPAlgorithm = TOTPAlgorithm::Sha1;
+
+        for (k, v) in url::form_urlencoded::parse(s.trim().as_bytes()) {
+            match k.as_ref() {
+                "key" => key = Some(v.to_string()),
+                "encoding" => encoding = v.to_lowercase(),
+                "step" => period = v.parse()?,
+                "size" => digits = v.parse()?,
+                "otpHashMode" => algorithm = v.to_uppercase().parse()?,
+                "type" if v.eq_ignore_ascii_case("totp") => {}
+                "type" => return Err(TOTPError::UnsupportedType(v.to_string())),
+                _ => {}
+            }
+        }
+
+        let key = key.ok_or(TOTPError::MissingField("key"))?;
+        let secret = match encoding.as_str() {
+            "base32" => decode_base32(&key)?,
+            "base64" => base64::engine::general_purpose::STANDARD
+                .decode(key.trim())
+                .map_err(|_| TOTPError::BadSettings(format!("encoding={}", encoding)))?,
+            "hex" => {
+                hex::decode(key.trim()).map_err(|_| TOTPError::BadSettings(format!("encoding={}", encoding)))?
+            }
+            "utf8" => key.into_bytes(),
+            _ => return Err(TOTPError::BadSettings(format!("encoding={}", encoding))),
+        };
+
+        Ok(TOTP {
+            label: String::new(),
+            issuer: None,
+            period,
+            digits,
+            algorithm,
+            encoder: TOTPEncoder::Numeric,
+            secret,
+        })
+    }
+
+    /// Build the TOTP of the `TOTP Seed` and `TOTP Settings` fields of the TrayTOTP plugin, which
+    /// KeePassXC wrote as well. The seed is Base32, the settings are the period and the number of
+    /// digits, e.g. `30;6`, where `S` for the digits stands for Steam Guard codes. Missing settings
+    /// default to `30;6`.
+    pub fn from_traytotp(seed: &str, settings: Option<&str>) -> Result<TOTP, TOTPError> {
+        let mut period: u64 = DEFAULT_PERIOD;
+        let mut digits: u32 = 6;
+        let mut encoder = TOTPEncoder::Numeric;
+
+        if let Some(settings) = settings.filter(|s| !s.trim().is_empty()) {
+            // a third part is the URL of a time server, which is not used
+            let mut parts = settings.trim().split(';');
+            period = parts.next().unwrap_or_default().trim().parse()?;
+            match parts.next().map(str::trim) {
+                Some("S") => {
+                    encoder = TOTPEncoder::Steam;
+                    digits = STEAM_DIGITS;
+                }
+                Some(d) => digits = d.parse()?,
+                None => return Err(TOTPError::BadSettings(settings.to_string())),
+            }
+        }
+
+        Ok(TOTP {
+            label: String::new(),
+            issuer: None,
+            period,
+            digits,
+            algorithm: TOTPAlgorithm::Sha1,
+            encoder,
+            secret: decode_base32(seed)?,
+        })
+    }
+
     /// Get the one-time code for a specific unix timestamp
     pub fn value_at(&self, time: u64) -> OTPCode {
-        let code = match self.algorithm {
-            TOTPAlgorithm::Sha1 => totp_custom::<Sha1>(self.period, self.digits, &self.secret, time),
-            TOTPAlgorithm::Sha256 => totp_custom::<Sha256>(self.period, self.digits, &self.secret, time),
-            TOTPAlgorithm::Sha512 => totp_custom::<Sha512>(self.period, self.digits, &self.secret, time),
+        let code = match self.encoder {
+            TOTPEncoder::Numeric => self.numeric_code(self.digits, time),
+            TOTPEncoder::Steam => {
+                // with 10 digits, the numeric code is the complete truncated HMAC value
+                let mut value: u32 = self.numeric_code(10, time).parse().unwrap_or_default();
+                (0..STEAM_DIGITS)
+                    .map(|_| {
+                        let c = STEAM_ALPHABET[value as usize % STEAM_ALPHABET.len()];
+                        value /= STEAM_ALPHABET.len() as u32;
+                        c as char
+                    })
+                    .collect()
+            }
         };
 
         let valid_for = Duration::from_secs(self.period - (time % self.period));
@@ -152,6 +280,14 @@ impl TOTP {
         }
     }
 
+    fn numeric_code(&self, digits: u32, time: u64) -> String {
+        match self.algorithm {
+            TOTPAlgorithm::Sha1 => totp_custom::<Sha1>(self.period, digits, &self.secret, time),
+            TOTPAlgorithm::Sha256 => totp_custom::<Sha256>(self.period, digits, &self.secret, time),
+            TOTPAlgorithm::Sha512 => totp_custom::<Sha512>(self.period, digits, &self.secret, time),
+        }
+    }
+
     /// Get the current one-time code
     pub fn value_now(&self) -> Result<OTPCode, SystemTimeError> {
         let time: u64 = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
@@ -161,11 +297,51 @@ impl TOTP {
     pub fn get_secret(&self) -> String {
         base32::encode(base32::Alphabet::Rfc4648 { padding: true }, &self.secret)
     }
+
+    /// Write the TOTP as an `otpauth://totp/` URI with all of its settings, as authenticator apps
+    /// read them from QR codes
+    pub fn to_uri(&self) -> String {
+        let mut uri = Url::parse("otpauth://totp/").expect("valid base URI");
+        // labels read from URIs are still percent-encoded, which is kept
+        uri.set_path(&self.label);
+
+        let secret = base32::encode(base32::Alphabet::Rfc4648 { padding: false }, &self.secret);
+        let algorithm = match self.algorithm {
+            TOTPAlgorithm::Sha1 => "SHA1",
+            TOTPAlgorithm::Sha256 => "SHA256",
+            TOTPAlgorithm::Sha512 => "SHA512",
+        };
+        {
+            let mut query = uri.query_pairs_mut();
+            query.append_pair("secret", &secret);
+            if let Some(issuer) = &self.issuer {
+                query.append_pair("issuer", issuer);
+            }
+            query
+                .append_pair("algorithm", algorithm)
+                .append_pair("digits", &self.digits.to_string())
+                .append_pair("period", &self.period.to_string());
+            if self.encoder == TOTPEncoder::Steam {
+                query.append_pair("encoder", "steam");
+            }
+        }
+        uri.to_string()
+    }
+}
+
+/// Decode a Base32 secret as typed by users, ignoring spaces, case and padding
+fn decode_base32(secret: &str) -> Result<Vec<u8>, TOTPError> {
+    let secret: String = secret
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != '=')
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+    base32::decode(base32::Alphabet::Rfc4648 { padding: false }, &secret).ok_or(TOTPError::Base32)
 }
 
 #[cfg(test)]
 mod kdbx4_otp_tests {
-    use super::{TOTPAlgorithm, TOTPError, TOTP};
+    use super::{TOTPAlgorithm, TOTPEncoder, TOTPError, TOTP};
     use crate::{
         db::{Database, NodeRef},
         key::DatabaseKey,
@@ -204,6 +380,7 @@ mod kdbx4_otp_tests {
             period: 30,
             digits: 6,
             algorithm: TOTPAlgorithm::Sha1,
+            encoder: TOTPEncoder::Numeric,
         };
 
         assert_eq!(otp_str.parse::<TOTP>()?, expected);
@@ -234,6 +411,7 @@ mod kdbx4_otp_tests {
             period: 30,
             digits: 6,
             algorithm: TOTPAlgorithm::Sha512,
+            encoder: TOTPEncoder::Numeric,
         };
 
         assert_eq!(otp_str.parse::<TOTP>()?, expected);
@@ -250,6 +428,7 @@ mod kdbx4_otp_tests {
             period: 30,
             digits: 6,
             algorithm: TOTPAlgorithm::Sha1,
+            encoder: TOTPEncoder::Numeric,
         };
 
         assert_eq!(totp.value_at(1234).code, "806863")
@@ -289,10 +468,116 @@ mod kdbx4_otp_tests {
             period: 30,
             digits: 6,
             algorithm: TOTPAlgorithm::Sha1,
+            encoder: TOTPEncoder::Numeric,
         };
 
         assert_eq!(otp_str.parse::<TOTP>()?, expected);
 
         Ok(())
     }
+
+    #[test]
+    fn totp_steam() -> Result<(), TOTPError> {
+        let otp_str = "otpauth://totp/Steam:jdoe?secret=JBSWY3DPEHPK3PXP&period=30&issuer=Steam&encoder=steam";
+        let totp = otp_str.parse::<TOTP>()?;
+        assert_eq!(totp.encoder, TOTPEncoder::Steam);
+        assert_eq!(totp.digits, 5);
+
+        let code = totp.value_at(1234);
+        assert_eq!(code.code.len(), 5);
+        assert!(code.code.bytes().all(|c| super::STEAM_ALPHABET.contains(&c)));
+        assert_eq!(code.valid_for.as_secs(), 26);
+
+        // the Steam characters encode the same HMAC value as the numeric code
+        let numeric: u32 = totp.numeric_code(10, 1234).parse().unwrap();
+        let first = super::STEAM_ALPHABET[numeric as usize % 26] as char;
+        assert!(code.code.starts_with(first));
+
+        assert!(matches!(
+            "otpauth://totp/x?secret=JBSWY3DPEHPK3PXP&encoder=other".parse::<TOTP>(),
+            Err(TOTPError::BadEncoder(_))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn totp_uri() -> Result<(), TOTPError> {
+        let otp_str = "otpauth://totp/sha512%20totp:none?secret=GEZDGNBVGY%3D%3D%3D%3D%3D%3D&period=30&digits=6&issuer=sha512%20totp&algorithm=SHA512";
+        let totp = otp_str.parse::<TOTP>()?;
+        assert_eq!(
+            totp.to_uri(),
+            "otpauth://totp/sha512%20totp:none?secret=GEZDGNBVGY&issuer=sha512+totp&algorithm=SHA512&digits=6&period=30"
+        );
+        assert_eq!(totp.to_uri().parse::<TOTP>()?, totp);
+
+        let steam =
+            "otpauth://totp/Steam:jdoe?secret=JBSWY3DPEHPK3PXP&issuer=Steam&encoder=steam".parse::<TOTP>()?;
+        assert!(steam.to_uri().ends_with("&digits=5&period=30&encoder=steam"));
+        assert_eq!(steam.to_uri().parse::<TOTP>()?, steam);
+
+        let mut totp = TOTP::from_traytotp("JBSWY3DPEHPK3PXP", Some("60;8"))?;
+        totp.label = "ACME Co:jane@example.com".to_string();
+        assert_eq!(
+            totp.to_uri(),
+            "otpauth://totp/ACME%20Co:jane@example.com?secret=JBSWY3DPEHPK3PXP&algorithm=SHA1&digits=8&period=60"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn totp_keeotp() -> Result<(), TOTPError> {
+        let expected = "otpauth://totp/x?secret=JBSWY3DPEHPK3PXP&period=30&digits=6".parse::<TOTP>()?;
+
+        let totp = TOTP::from_keeotp("key=JBSWY3DPEHPK3PXP")?;
+        assert_eq!(totp.get_secret(), expected.get_secret());
+        assert_eq!((totp.period, totp.digits), (30, 6));
+        assert_eq!(totp.value_at(1234).code, expected.value_at(1234).code);
+
+        // keys are typed by users
+        let totp = TOTP::from_keeotp("key=jbsw+y3dp+ehpk+3pxp&size=8&step=60&otpHashMode=Sha256")?;
+        assert_eq!(totp.get_secret(), expected.get_secret());
+        assert_eq!((totp.period, totp.digits), (60, 8));
+        assert_eq!(totp.algorithm, TOTPAlgorithm::Sha256);
+
+        let totp = TOTP::from_keeotp("key=48656c6c6f21deadbeef&encoding=Hex&type=Totp")?;
+        assert_eq!(totp.get_secret(), expected.get_secret());
+
+        assert!(matches!(
+            TOTP::from_keeotp("key=JBSWY3DPEHPK3PXP&type=Hotp&counter=1"),
+            Err(TOTPError::UnsupportedType(_))
+        ));
+        assert!(matches!(
+            TOTP::from_keeotp("size=6"),
+            Err(TOTPError::MissingField("key"))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn totp_traytotp() -> Result<(), TOTPError> {
+        let totp = TOTP::from_traytotp("JBSW Y3DP EHPK 3PXP", None)?;
+        assert_eq!(totp.get_secret(), "JBSWY3DPEHPK3PXP");
+        assert_eq!((totp.period, totp.digits), (30, 6));
+
+        let totp = TOTP::from_traytotp("JBSWY3DPEHPK3PXP", Some("60;8"))?;
+        assert_eq!((totp.period, totp.digits), (60, 8));
+
+        let totp = TOTP::from_traytotp("JBSWY3DPEHPK3PXP", Some("30;S;https://time.example.com"))?;
+        assert_eq!(totp.encoder, TOTPEncoder::Steam);
+        assert_eq!(totp.digits, 5);
+
+        assert!(matches!(
+            TOTP::from_traytotp("JBSWY3DPEHPK3PXP", Some("30")),
+            Err(TOTPError::BadSettings(_))
+        ));
+        assert!(matches!(
+            TOTP::from_traytotp("not base32!", None),
+            Err(TOTPError::Base32)
+        ));
+
+        Ok(())
+    }
 }
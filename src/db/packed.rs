@@ -0,0 +1,214 @@
+//! Attachments kept compressed in memory
+//!
+//! Attachments are usually the largest part of a database. When parsing with
+//! `ParseOptions::low_memory`, attachment content is gzip-compressed in memory ("packed") and only
+//! decompressed when it is accessed through `data`, trading CPU time for memory on devices with
+//! little RAM. Content that is already compressed (see `AttachmentCodec`) is never packed.
+
+use std::borrow::Cow;
+
+use crate::{
+    compression::{Compression, GZipCompression},
+    db::{AttachmentCodec, BinaryAttachment, Database, HeaderAttachment},
+};
+
+impl HeaderAttachment {
+    /// The content of the attachment, decompressed if it is packed
+    pub fn data(&self) -> Result<Cow<'_, [u8]>, std::io::Error> {
+        data(&self.content, self.packed, self.external)
+    }
+
+    /// Compress the content in memory if that makes it smaller. Returns whether the attachment
+    /// is packed afterwards.
+    pub fn pack(&mut self) -> Result<bool, std::io::Error> {
+        pack(&mut self.content, &mut self.packed, self.external)
+    }
+
+    /// Decompress packed content
+    pub fn unpack(&mut self) -> Result<(), std::io::Error> {
+        unpack(&mut self.content, &mut self.packed)
+    }
+}
+
+impl BinaryAttachment {
+    /// The content of the attachment, decompressed if it is packed
+    pub fn data(&self) -> Result<Cow<'_, [u8]>, std::io::Error> {
+        data(&self.content, self.packed, self.external)
+    }
+
+    /// Compress the content in memory if that makes it smaller. Returns whether the attachment
+    /// is packed afterwards.
+    pub fn pack(&mut self) -> Result<bool, std::io::Error> {
+        pack(&mut self.content, &mut self.packed, self.external)
+    }
+
+    /// Decompress packed content
+    pub fn unpack(&mut self) -> Result<(), std::io::Error> {
+        unpack(&mut self.content, &mut self.packed)
+    }
+}
+
+impl Database {
+    /// Pack all attachments of the database, see `HeaderAttachment::pack`. Returns the number of
+    /// bytes saved.
+    pub fn pack_attachments(&mut self) -> Result<usize, std::io::Error> {
+        let mut saved = 0;
+
+        for attachment in &mut self.header_attachments {
+            let size = attachment.content.len();
+            attachment.pack()?;
+            saved += size - attachment.content.len();
+        }
+
+        for attachment in &mut self.meta.binaries.binaries {
+            let size = attachment.content.len();
+            attachment.pack()?;
+            saved += size - attachment.content.len();
+        }
+
+        Ok(saved)
+    }
+
+    /// Unpack all attachments of the database
+    pub fn unpack_attachments(&mut self) -> Result<(), std::io::Error> {
+        for attachment in &mut self.header_attachments {
+            attachment.unpack()?;
+        }
+        for attachment in &mut self.meta.binaries.binaries {
+            attachment.unpack()?;
+        }
+        Ok(())
+    }
+}
+
+fn data(content: &[u8], packed: bool, external: bool) -> Result<Cow<'_, [u8]>, std::io::Error> {
+    if external {
+        Err(std::io::Error::other(
+            "The attachment content is kept in a blob store, see `Database::internalize_attachments`",
+        ))
+    } else if packed {
+        Ok(Cow::Owned(GZipCompression::default().decompress(content)?))
+    } else {
+        Ok(Cow::Borrowed(content))
+    }
+}
+
+fn pack(content: &mut Vec<u8>, packed: &mut bool, external: bool) -> Result<bool, std::io::Error> {
+    if *packed || external || AttachmentCodec::detect(content).is_compressed() {
+        return Ok(*packed);
+    }
+
+    let compressed = GZipCompression::default().compress(content)?;
+    if compressed.len() < content.len() {
+        *content = compressed;
+        *packed = true;
+    }
+
+    Ok(*packed)
+}
+
+fn unpack(content: &mut Vec<u8>, packed: &mut bool) -> Result<(), std::io::Error> {
+    if *packed {
+        *content = GZipCompression::default().decompress(content)?;
+        *packed = false;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod packed_tests {
+    use crate::db::{AttachmentRef, BinaryAttachment, Database, Entry, HeaderAttachment};
+
+    #[test]
+    fn test_pack_attachments() {
+        let text = "compressible ".repeat(100).into_bytes();
+        let image = b"\x89PNG\r\n\x1a\n\x00\x00\x00\x0dIHDR".to_vec();
+
+        let mut db = Database::new(Default::default());
+        db.header_attachments.push(HeaderAttachment {
+            flags: 1,
+            content: text.clone(),
+            packed: false,
+            external: false,
+        });
+        db.header_attachments.push(HeaderAttachment {
+            flags: 0,
+            content: image.clone(),
+            packed: false,
+            external: false,
+        });
+        db.meta.binaries.binaries.push(BinaryAttachment {
+            identifier: Some("0".to_string()),
+            compressed: true,
+            content: text.clone(),
+            packed: false,
+            external: false,
+        });
+
+        let saved = db.pack_attachments().unwrap();
+        assert!(saved > 2000);
+
+        // already compressed content is left alone
+        assert!(db.header_attachments[0].packed);
+        assert!(!db.header_attachments[1].packed);
+        assert!(db.meta.binaries.binaries[0].packed);
+
+        assert_eq!(db.header_attachments[0].data().unwrap(), text.as_slice());
+        assert_eq!(db.header_attachments[1].data().unwrap(), image.as_slice());
+        assert_eq!(db.meta.binaries.binaries[0].data().unwrap(), text.as_slice());
+
+        // packing twice does not change anything
+        assert_eq!(db.pack_attachments().unwrap(), 0);
+
+        db.unpack_attachments().unwrap();
+        assert!(!db.header_attachments[0].packed);
+        assert_eq!(db.header_attachments[0].content, text);
+        assert_eq!(db.meta.binaries.binaries[0].content, text);
+    }
+
+    #[cfg(feature = "save_kdbx4")]
+    #[test]
+    fn test_low_memory_roundtrip() {
+        use crate::{db::ParseOptions, DatabaseKey};
+
+        let text = "compressible ".repeat(100).into_bytes();
+
+        let mut db = Database::new(Default::default());
+        db.header_attachments.push(HeaderAttachment {
+            flags: 0,
+            content: text.clone(),
+            packed: false,
+            external: false,
+        });
+        // attachments that no entry refers to are dropped when saving
+        let mut entry = Entry::new();
+        entry.attachments.push(AttachmentRef {
+            name: "attachment.bin".to_string(),
+            identifier: 0,
+        });
+        db.root.add_child(entry);
+
+        let mut file = Vec::new();
+        db.save(&mut file, DatabaseKey::new().with_password("test"))
+            .unwrap();
+
+        let low_memory = Database::parse_with_options(
+            &file,
+            DatabaseKey::new().with_password("test"),
+            &ParseOptions::low_memory(),
+        )
+        .unwrap();
+        assert!(low_memory.header_attachments[0].packed);
+        assert!(low_memory.header_attachments[0].content.len() < text.len());
+        assert_eq!(low_memory.header_attachments[0].data().unwrap(), text.as_slice());
+
+        // packed attachments are written decompressed
+        let mut file = Vec::new();
+        low_memory
+            .save(&mut file, DatabaseKey::new().with_password("test"))
+            .unwrap();
+        let reopened = Database::parse(&file, DatabaseKey::new().with_password("test")).unwrap();
+        assert!(!reopened.header_attachments[0].packed);
+        assert_eq!(reopened.header_attachments[0].content, text);
+    }
+}
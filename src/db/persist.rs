@@ -0,0 +1,179 @@
+use std::{
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    db::{clock, Database, SaveOptions},
+    error::DatabaseSaveError,
+    key::DatabaseKey,
+};
+
+impl Database {
+    /// Save the database to a file at `path`, replacing it atomically.
+    ///
+    /// The database is written to a temporary file next to `path` and synced to disk before it
+    /// takes the place of the old file, so that a crash or a failing disk never leaves a partially
+    /// written database behind. If `SaveOptions::backups` is set, the old file is kept as a backup
+    /// named after the file and the time of saving, e.g. `vault.kdbx.20240203-040506.789.bak`, and
+    /// the oldest backups beyond that number are removed.
+    ///
+    /// ```
+    /// use keepass::{db::SaveOptions, Database, DatabaseKey};
+    ///
+    /// # let dir = std::env::temp_dir().join(format!("keepass-doc-{}", uuid::Uuid::new_v4()));
+    /// # std::fs::create_dir(&dir)?;
+    /// # let path = dir.join("vault.kdbx");
+    /// let db = Database::new(Default::default());
+    /// let key = DatabaseKey::new().with_password("demopass");
+    /// db.save_to_path(&path, key, &SaveOptions::default().keep_backups(3))?;
+    /// # std::fs::remove_dir_all(&dir)?;
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn save_to_path(
+        &self,
+        path: impl AsRef<Path>,
+        key: DatabaseKey,
+        options: &SaveOptions,
+    ) -> Result<(), DatabaseSaveError> {
+        let path = path.as_ref();
+
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(format!(".tmp-{}", uuid::Uuid::new_v4().simple()));
+        let temp = PathBuf::from(temp);
+
+        let result = (|| -> Result<(), DatabaseSaveError> {
+            let mut file = File::create(&temp)?;
+            self.save_with_options(&mut file, key, options)?;
+            file.flush()?;
+            file.sync_all()?;
+            Ok(())
+        })();
+        if let Err(e) = result {
+            let _ = std::fs::remove_file(&temp);
+            return Err(e);
+        }
+
+        // the old file is copied rather than moved, so that `path` always holds a database
+        if options.backups > 0 && path.exists() {
+            let mut backup = path.as_os_str().to_owned();
+            backup.push(clock::current_time().format(".%Y%m%d-%H%M%S%.3f.bak").to_string());
+            if let Err(e) = std::fs::copy(path, &backup) {
+                let _ = std::fs::remove_file(&temp);
+                return Err(e.into());
+            }
+        }
+
+        if let Err(e) = std::fs::rename(&temp, path) {
+            let _ = std::fs::remove_file(&temp);
+            return Err(e.into());
+        }
+        sync_parent_dir(path);
+
+        if options.backups > 0 {
+            let backups = backup_paths(path)?;
+            for old in &backups[..backups.len().saturating_sub(options.backups)] {
+                std::fs::remove_file(old)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The backups of the database file at `path` made by `Database::save_to_path`, oldest first
+pub fn backup_paths(path: impl AsRef<Path>) -> Result<Vec<PathBuf>, std::io::Error> {
+    let path = path.as_ref();
+    let prefix = match path.file_name() {
+        Some(name) => format!("{}.", name.to_string_lossy()),
+        None => return Ok(Vec::new()),
+    };
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+
+    let mut backups = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        let name = name.to_string_lossy();
+        let is_backup = name
+            .strip_prefix(&prefix)
+            .and_then(|rest| rest.strip_suffix(".bak"))
+            .is_some_and(|stamp| {
+                stamp.len() == 19
+                    && stamp
+                        .bytes()
+                        .all(|b| b.is_ascii_digit() || b == b'-' || b == b'.')
+            });
+        if is_backup {
+            backups.push(dir.join(&*name));
+        }
+    }
+    // the timestamps sort in the order the backups were made
+    backups.sort();
+    Ok(backups)
+}
+
+/// Make the rename of a file durable by syncing its directory, where the platform supports it
+fn sync_parent_dir(path: &Path) {
+    #[cfg(unix)]
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        if let Ok(dir) = File::open(dir) {
+            let _ = dir.sync_all();
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+}
+
+#[cfg(test)]
+mod persist_tests {
+    use chrono::NaiveDate;
+
+    use super::backup_paths;
+    use crate::{
+        db::{with_clock, Entry, SaveOptions},
+        Database, DatabaseKey,
+    };
+
+    #[test]
+    fn test_save_to_path() {
+        let dir = std::env::temp_dir().join(format!("keepass-persist-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        let path = dir.join("vault.kdbx");
+        let key = DatabaseKey::new().with_password("test");
+        let options = SaveOptions::default().keep_backups(2);
+
+        let mut db = Database::new(Default::default());
+        for second in 0..4 {
+            db.root.add_child(Entry::new());
+            let time = NaiveDate::from_ymd_opt(2024, 2, 3)
+                .unwrap()
+                .and_hms_opt(4, 5, second)
+                .unwrap();
+            with_clock(move || time, || db.save_to_path(&path, key.clone(), &options)).unwrap();
+        }
+
+        let reopened = Database::open(&mut std::fs::File::open(&path).unwrap(), key.clone()).unwrap();
+        assert_eq!(reopened.root.children.len(), 4);
+
+        // the first save had nothing to back up, and only the two newest backups are kept
+        let backups = backup_paths(&path).unwrap();
+        assert_eq!(
+            backups,
+            vec![
+                dir.join("vault.kdbx.20240203-040502.000.bak"),
+                dir.join("vault.kdbx.20240203-040503.000.bak"),
+            ]
+        );
+        let backup = Database::open(&mut std::fs::File::open(&backups[0]).unwrap(), key).unwrap();
+        assert_eq!(backup.root.children.len(), 2);
+
+        // no temporary files are left behind
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 3);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,167 @@
+//! Unencrypted KeePass 2 XML documents
+//!
+//! KeePass can export a database as the XML document that is otherwise encrypted in a KDBX file,
+//! with protected values like passwords in plain text. `Database::export_xml` writes such a
+//! document and `Database::import_xml` reads it, e.g. to inspect or diff the contents of a
+//! database, or to exchange databases with tools that work on the XML export of KeePass:
+//!
+//! ```
+//! # #[cfg(feature = "save_kdbx4")]
+//! # {
+//! use keepass::{db::Entry, Database};
+//!
+//! let mut db = Database::new(Default::default());
+//! db.root.add_child(Entry::new().with_title("Mail").with_password("hunter2"));
+//!
+//! let mut xml = Vec::new();
+//! db.export_xml(&mut xml)?;
+//! assert!(String::from_utf8_lossy(&xml).contains("<Value ProtectInMemory=\"True\">hunter2</Value>"));
+//!
+//! let imported = Database::import_xml(&mut xml.as_slice())?;
+//! assert_eq!(imported.root, db.root);
+//! # }
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+//!
+//! The document holds the metadata, groups, entries and deleted objects of the database, but not
+//! the settings of the KDBX file like the KDF or ciphers. Imported databases get the default
+//! settings.
+
+use std::io::Read;
+#[cfg(feature = "save_kdbx4")]
+use std::io::Write;
+
+use crate::{
+    config::DatabaseConfig,
+    crypt::ciphers::PlainCipher,
+    db::Database,
+    error::{DatabaseIntegrityError, DatabaseOpenError},
+};
+#[cfg(feature = "save_kdbx4")]
+use crate::{db::convert::header_attachments_to_binaries, error::DatabaseSaveError};
+
+impl Database {
+    /// Write the database as an unencrypted KeePass 2 XML document, with protected values in
+    /// plain text. Attachments in the inner header of a KDBX4 file are written to the binaries
+    /// of the metadata, as KeePass does.
+    #[cfg(feature = "save_kdbx4")]
+    pub fn export_xml(&self, destination: &mut dyn Write) -> Result<(), DatabaseSaveError> {
+        if self.has_external_attachments() {
+            return Err(DatabaseSaveError::ExternalAttachments);
+        }
+
+        if self.header_attachments.is_empty() {
+            crate::xml_db::dump::dump_export(self, destination)?;
+            return Ok(());
+        }
+
+        // entries of KDBX4 databases refer to header attachments by their index
+        let mut db = self.clone();
+        db.meta.binaries = header_attachments_to_binaries(std::mem::take(&mut db.header_attachments));
+
+        crate::xml_db::dump::dump_export(&db, destination)?;
+        Ok(())
+    }
+
+    /// Read an unencrypted KeePass 2 XML document, like the ones written by
+    /// `Database::export_xml` or the XML export of KeePass and KeePassXC
+    pub fn import_xml(source: &mut dyn Read) -> Result<Database, DatabaseOpenError> {
+        let mut data = zeroize::Zeroizing::new(Vec::new());
+        source.read_to_end(&mut data)?;
+        parse_xml_export(&data)
+    }
+}
+
+/// Parse an unencrypted XML export into a database with the default settings
+pub(crate) fn parse_xml_export(data: &[u8]) -> Result<Database, DatabaseOpenError> {
+    // values in exports are not encrypted with an inner stream cipher
+    let content = crate::xml_db::parse::parse(data, &mut PlainCipher).map_err(DatabaseIntegrityError::from)?;
+
+    Ok(Database {
+        root: content.root.group,
+        deleted_objects: content.root.deleted_objects,
+        meta: content.meta,
+        ..Database::new(DatabaseConfig::default())
+    })
+}
+
+#[cfg(feature = "save_kdbx4")]
+#[cfg(test)]
+mod plain_xml_tests {
+    use chrono::NaiveDate;
+
+    use crate::{
+        db::{AttachmentRef, DeletedObject, Entry, HeaderAttachment, Value},
+        Database, DatabaseKey,
+    };
+
+    #[test]
+    fn test_export_import_xml() {
+        let time = NaiveDate::from_ymd_opt(2024, 2, 3)
+            .unwrap()
+            .and_hms_opt(4, 5, 6)
+            .unwrap();
+
+        let mut db = Database::new(Default::default());
+        db.meta.database_name = Some("Exported".to_string());
+        let mut entry = Entry::new()
+            .with_title("Mail")
+            .with_password("hunter2 & <friends>");
+        entry.times.set_creation(time);
+        db.root.add_child(entry);
+        db.deleted_objects.objects.push(DeletedObject {
+            uuid: uuid::Uuid::new_v4(),
+            deletion_time: time,
+        });
+
+        let mut xml = Vec::new();
+        db.export_xml(&mut xml).unwrap();
+        let text = String::from_utf8(xml.clone()).unwrap();
+        assert!(text.starts_with("<?xml"));
+        assert!(text.contains("<DatabaseName>Exported</DatabaseName>"));
+
+        let imported = Database::import_xml(&mut xml.as_slice()).unwrap();
+        assert_eq!(imported.meta, db.meta);
+        assert_eq!(imported.root, db.root);
+        assert_eq!(imported.deleted_objects, db.deleted_objects);
+    }
+
+    #[test]
+    fn test_export_header_attachments() {
+        let mut db = Database::open(
+            &mut std::fs::File::open("tests/resources/test_db_kdbx4_with_password_aes.kdbx").unwrap(),
+            DatabaseKey::new().with_password("demopass"),
+        )
+        .unwrap();
+        db.header_attachments = vec![HeaderAttachment {
+            flags: 1,
+            content: b"attached".to_vec(),
+            packed: false,
+            external: false,
+        }];
+        let mut entry = Entry::new().with_title("with attachment");
+        entry.fields.insert(
+            "Password".to_string(),
+            Value::Protected("secret".as_bytes().into()),
+        );
+        entry.attachments.push(AttachmentRef {
+            name: "file.txt".to_string(),
+            identifier: 0,
+        });
+        db.root.add_child(entry);
+
+        let mut xml = Vec::new();
+        db.export_xml(&mut xml).unwrap();
+        let imported = Database::import_xml(&mut xml.as_slice()).unwrap();
+        assert_eq!(imported.root, db.root);
+        assert_eq!(imported.meta.binaries.binaries.len(), 1);
+        assert_eq!(
+            imported.meta.binaries.binaries[0].identifier.as_deref(),
+            Some("0")
+        );
+        assert_eq!(
+            imported.meta.binaries.binaries[0].data().unwrap().as_ref(),
+            b"attached"
+        );
+    }
+}
@@ -0,0 +1,184 @@
+//! Reading a single entry without parsing the whole database
+//!
+//! Tools like an SSH askpass program or a git credential helper need one secret, and need it
+//! quickly. `Database::probe_entry` still decrypts the whole payload, but then only scans the XML
+//! for the wanted entry instead of building the full tree, and stops at the first match.
+
+use uuid::Uuid;
+
+use crate::{
+    db::{Database, Entry, NodeRef},
+    error::DatabaseOpenError,
+    format::{kdbx3::decrypt_kdbx3, kdbx4::decrypt_kdbx4, DatabaseVersion, SUPPORTED_VERSIONS},
+    key::DatabaseKey,
+};
+
+/// Which entry `Database::probe_entry` looks for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EntryQuery {
+    /// The entry with this UUID
+    Uuid(Uuid),
+
+    /// The first entry, in document order, with exactly this title
+    Title(String),
+}
+
+impl EntryQuery {
+    fn matches(&self, entry: &Entry) -> bool {
+        match self {
+            EntryQuery::Uuid(uuid) => entry.uuid == *uuid,
+            EntryQuery::Title(title) => entry.get_title() == Some(title.as_str()),
+        }
+    }
+}
+
+impl Database {
+    /// Find a single entry in an encrypted database without building the whole database. Returns
+    /// `None` if no entry matches. Entries in the recycle bin are found like any other entry.
+    pub fn probe_entry(
+        data: &[u8],
+        key: DatabaseKey,
+        query: &EntryQuery,
+    ) -> Result<Option<Entry>, DatabaseOpenError> {
+        let uuid = match query {
+            EntryQuery::Uuid(uuid) => Some(*uuid),
+            EntryQuery::Title(_) => None,
+        };
+        let matches = |entry: &Entry| query.matches(entry);
+
+        let (mut inner_decryptor, xml) = match DatabaseVersion::parse(data)? {
+            // KDB files are small and not XML-based, so they are parsed in full
+            DatabaseVersion::KDB(_) => {
+                let db = Database::parse(data, key)?;
+                return Ok(db.root.iter().find_map(|node| match node {
+                    NodeRef::Entry(e) if matches(e) => Some(e.clone()),
+                    _ => None,
+                }));
+            }
+            version @ DatabaseVersion::KDB2(_) => {
+                return Err(DatabaseOpenError::IncompatibleVersion {
+                    found: version.to_string(),
+                    supported: SUPPORTED_VERSIONS,
+                })
+            }
+            DatabaseVersion::KDB3(_) => {
+                let (_, inner_decryptor, xml) = decrypt_kdbx3(data, &key)?;
+                (inner_decryptor, xml)
+            }
+            DatabaseVersion::KDB4(_) => {
+                let (_, _, inner_decryptor, xml, _) = decrypt_kdbx4(data, &key)?;
+                (inner_decryptor, xml)
+            }
+        };
+
+        Ok(crate::xml_db::parse::probe_entry(
+            &xml,
+            &mut *inner_decryptor,
+            uuid,
+            &matches,
+        )?)
+    }
+}
+
+#[cfg(test)]
+mod probe_tests {
+    use crate::{
+        db::{Database, NodeRef},
+        key::DatabaseKey,
+    };
+
+    use super::EntryQuery;
+
+    #[test]
+    fn test_probe_entry() {
+        let data = std::fs::read("tests/resources/test_db_with_password.kdbx").unwrap();
+        let db = Database::parse(&data, DatabaseKey::new().with_password("demopass")).unwrap();
+        let entries: Vec<_> = db
+            .root
+            .iter()
+            .filter_map(|n| match n {
+                NodeRef::Entry(e) => Some(e),
+                NodeRef::Group(_) => None,
+            })
+            .collect();
+
+        // protected values of earlier entries are skipped correctly
+        let last = entries.last().unwrap();
+        let probed = Database::probe_entry(
+            &data,
+            DatabaseKey::new().with_password("demopass"),
+            &EntryQuery::Uuid(last.uuid),
+        )
+        .unwrap();
+        assert_eq!(probed.as_ref(), Some(*last));
+
+        let title = entries[0].get_title().unwrap().to_string();
+        let probed = Database::probe_entry(
+            &data,
+            DatabaseKey::new().with_password("demopass"),
+            &EntryQuery::Title(title),
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(probed.get_password(), entries[0].get_password());
+
+        let missing = Database::probe_entry(
+            &data,
+            DatabaseKey::new().with_password("demopass"),
+            &EntryQuery::Title("no such entry".to_string()),
+        )
+        .unwrap();
+        assert_eq!(missing, None);
+
+        assert!(Database::probe_entry(
+            &data,
+            DatabaseKey::new().with_password("wrong"),
+            &EntryQuery::Uuid(last.uuid),
+        )
+        .is_err());
+    }
+
+    #[cfg(feature = "save_kdbx4")]
+    #[test]
+    fn test_probe_saved_entry() {
+        use crate::db::{Entry, Value};
+
+        let mut db = Database::new(Default::default());
+        let mut uuids = Vec::new();
+        for i in 0..5 {
+            let mut entry = Entry::new();
+            entry
+                .fields
+                .insert("Title".to_string(), Value::Unprotected(format!("entry {}", i)));
+            entry.fields.insert(
+                "Password".to_string(),
+                Value::Protected(format!("password {}", i).as_bytes().into()),
+            );
+            uuids.push(entry.uuid);
+            db.root.add_child(entry);
+        }
+
+        let mut data = Vec::new();
+        db.save(&mut data, DatabaseKey::new().with_password("test"))
+            .unwrap();
+
+        let probed = Database::probe_entry(
+            &data,
+            DatabaseKey::new().with_password("test"),
+            &EntryQuery::Uuid(uuids[3]),
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(probed.get_password(), Some("password 3"));
+
+        let probed = Database::probe_entry(
+            &data,
+            DatabaseKey::new().with_password("test"),
+            &EntryQuery::Title("entry 4".to_string()),
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(probed.uuid, uuids[4]);
+        assert_eq!(probed.get_password(), Some("password 4"));
+    }
+}
@@ -0,0 +1,244 @@
+//! Protected values encrypted in memory
+//!
+//! Like KeePassXC, the library keeps protected values like passwords encrypted while they are in
+//! memory, so that they do not end up in core dumps or swap in plain text. Each `ProtectedValue`
+//! is encrypted with ChaCha20 under a key that is generated once per process, and only decrypted
+//! when it is accessed:
+//!
+//! ```
+//! use keepass::db::{Entry, Value};
+//!
+//! let mut entry = Entry::new();
+//! entry.fields.insert("Password".to_string(), Value::Protected("hunter2".into()));
+//!
+//! let password = entry.get_protected("Password").unwrap();
+//! assert_eq!(password.as_str(), Some("hunter2"));
+//! drop(password); // wipes the decrypted password
+//! ```
+//!
+//! The guards returned by `Entry::get_protected` and `ProtectedValue::decrypt` keep the plain
+//! text in memory that is locked against swapping and wiped when they are dropped. `Entry::get`,
+//! `Entry::get_password` and `ProtectedValue::unsecure` return plain references instead, so the
+//! decrypted value has to stay around until the protected value itself is dropped.
+//! `Entry::get_title`, `Entry::get_username` and `Entry::get_url` never decrypt, and return
+//! `None` for protected fields.
+
+use std::{
+    fmt,
+    ops::Deref,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        OnceLock,
+    },
+};
+
+use chacha20::ChaCha20;
+use cipher::{KeyIvInit, StreamCipher};
+use secstr::SecStr;
+use zeroize::Zeroizing;
+
+/// The key that all protected values of the process are encrypted with
+static SESSION_KEY: OnceLock<Zeroizing<[u8; 32]>> = OnceLock::new();
+
+/// The nonce for the next encrypted value, so that no two values share a key stream
+static NEXT_NONCE: AtomicU64 = AtomicU64::new(0);
+
+/// En- or decrypt `data` in place with the key stream for `nonce`
+fn apply_keystream(nonce: u64, data: &mut [u8]) {
+    let key = SESSION_KEY.get_or_init(|| {
+        let mut key = Zeroizing::new([0u8; 32]);
+        getrandom::fill(&mut key[..]).expect("Generate session key for protected values");
+        key
+    });
+
+    let mut iv = [0u8; 12];
+    iv[4..].copy_from_slice(&nonce.to_le_bytes());
+    ChaCha20::new(key[..].into(), &iv.into()).apply_keystream(data);
+}
+
+/// A value that is kept encrypted in memory, e.g. a password
+pub struct ProtectedValue {
+    nonce: u64,
+    ciphertext: Vec<u8>,
+
+    /// The decrypted value, once it has been borrowed with `unsecure`
+    plaintext: OnceLock<SecStr>,
+}
+
+impl ProtectedValue {
+    /// Protect a value, encrypting the given buffer in place
+    pub fn new(mut value: Vec<u8>) -> ProtectedValue {
+        let nonce = NEXT_NONCE.fetch_add(1, Ordering::Relaxed);
+        apply_keystream(nonce, &mut value);
+
+        ProtectedValue {
+            nonce,
+            ciphertext: value,
+            plaintext: OnceLock::new(),
+        }
+    }
+
+    /// Decrypt the value into a guard that wipes it when it is dropped
+    pub fn decrypt(&self) -> ProtectedGuard {
+        let mut plaintext = self.ciphertext.clone();
+        apply_keystream(self.nonce, &mut plaintext);
+        ProtectedGuard(SecStr::new(plaintext))
+    }
+
+    /// Borrow the decrypted value. It is decrypted on the first call and kept in memory until
+    /// the protected value is dropped.
+    pub fn unsecure(&self) -> &[u8] {
+        self.plaintext.get_or_init(|| self.decrypt().0).unsecure()
+    }
+
+    /// The length of the value in bytes, which is known without decrypting it
+    pub fn len(&self) -> usize {
+        self.ciphertext.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ciphertext.is_empty()
+    }
+}
+
+impl Clone for ProtectedValue {
+    fn clone(&self) -> Self {
+        ProtectedValue {
+            nonce: self.nonce,
+            ciphertext: self.ciphertext.clone(),
+            plaintext: OnceLock::new(),
+        }
+    }
+}
+
+impl PartialEq for ProtectedValue {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len() && *self.decrypt() == *other.decrypt()
+    }
+}
+
+impl Eq for ProtectedValue {}
+
+impl fmt::Debug for ProtectedValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("***SECRET***")
+    }
+}
+
+impl From<Vec<u8>> for ProtectedValue {
+    fn from(value: Vec<u8>) -> Self {
+        ProtectedValue::new(value)
+    }
+}
+
+impl From<&[u8]> for ProtectedValue {
+    fn from(value: &[u8]) -> Self {
+        ProtectedValue::new(value.to_vec())
+    }
+}
+
+impl From<String> for ProtectedValue {
+    fn from(value: String) -> Self {
+        ProtectedValue::new(value.into_bytes())
+    }
+}
+
+impl From<&str> for ProtectedValue {
+    fn from(value: &str) -> Self {
+        ProtectedValue::new(value.as_bytes().to_vec())
+    }
+}
+
+impl From<SecStr> for ProtectedValue {
+    fn from(value: SecStr) -> Self {
+        ProtectedValue::new(value.unsecure().to_vec())
+    }
+}
+
+/// A decrypted protected value, wiped from memory when it is dropped
+pub struct ProtectedGuard(pub(crate) SecStr);
+
+impl ProtectedGuard {
+    /// The value as text, if it is valid UTF-8
+    pub fn as_str(&self) -> Option<&str> {
+        std::str::from_utf8(self.0.unsecure()).ok()
+    }
+}
+
+impl Deref for ProtectedGuard {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.0.unsecure()
+    }
+}
+
+impl fmt::Debug for ProtectedGuard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("***SECRET***")
+    }
+}
+
+#[cfg(test)]
+mod protected_tests {
+    use super::ProtectedValue;
+
+    #[test]
+    fn test_protected_value() {
+        let value = ProtectedValue::from("hunter2");
+        assert_ne!(value.ciphertext, b"hunter2");
+        assert_eq!(value.len(), 7);
+        assert_eq!(&*value.decrypt(), b"hunter2");
+        assert!(value.plaintext.get().is_none());
+
+        let unsecure = value.unsecure();
+        assert_eq!(unsecure, b"hunter2");
+        assert!(value.plaintext.get().is_some());
+
+        // the same text encrypts differently, but compares equal
+        let other = ProtectedValue::from("hunter2".to_string());
+        assert_ne!(value.ciphertext, other.ciphertext);
+        assert_eq!(value, other);
+        assert_eq!(value.clone(), value);
+        assert_ne!(value, ProtectedValue::from("hunter3"));
+
+        assert_eq!(format!("{:?}", value), "***SECRET***");
+    }
+
+    #[test]
+    fn test_library_does_not_cache_plaintext() {
+        use crate::{
+            db::{Entry, Filter, Value},
+            Database,
+        };
+
+        let mut db = Database::new(Default::default());
+        let mut entry = Entry::new().with_title("Mail");
+        entry
+            .fields
+            .insert("Password".to_string(), Value::Protected("hunter2".into()));
+        entry
+            .fields
+            .insert("Notes".to_string(), Value::Protected("{PASSWORD}".into()));
+        entry
+            .fields
+            .insert("UserName".to_string(), Value::Protected("jdoe".into()));
+        db.root.add_child(entry);
+
+        let entry = &db.root.entries()[0];
+        assert_eq!(db.search(&Filter::AnyFieldContains("hunt".to_string())).len(), 0);
+        assert_eq!(crate::placeholders::expand(entry, "{PASSWORD}", &db), "hunter2");
+        entry.structured_notes();
+        crate::report::compliance(&db, &Default::default());
+        crate::export::csv::export_csv(&db, &mut Vec::new()).unwrap();
+        assert_eq!(entry.get_username(), None);
+        assert_eq!(entry.get_protected("UserName").unwrap().as_str(), Some("jdoe"));
+
+        for name in ["Password", "Notes", "UserName"].iter() {
+            match entry.fields.get(*name) {
+                Some(Value::Protected(value)) => assert!(value.plaintext.get().is_none()),
+                _ => panic!("Expected a protected {}", name),
+            }
+        }
+    }
+}
@@ -0,0 +1,227 @@
+//! Public custom data of the outer KDBX4 header
+//!
+//! KDBX4 files can carry custom data in their outer header, which is authenticated but not
+//! encrypted, so it can be read without the key. Tools managing many databases use it to tell
+//! files apart: every database gets a random UUID under `DATABASE_UUID_KEY` that stays the same
+//! when the file is renamed, moved or synced. A name can be published as well, but since anyone
+//! with the file can read it, that is left to the application.
+//!
+//! ```no_run
+//! use keepass::Database;
+//!
+//! let public = Database::read_public_custom_data(&mut std::fs::File::open("vault.kdbx")?)?;
+//! println!("{:?} {:?}", public.database_uuid(), public.name());
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::collections::BTreeMap;
+
+use uuid::Uuid;
+
+use crate::{
+    db::Database,
+    error::DatabaseOpenError,
+    format::{kdbx4::parse_public_custom_data, DatabaseVersion},
+    variant_dictionary::{VariantDictionary, VariantDictionaryValue},
+};
+
+/// Key of the public custom data item holding the UUID of the database
+pub const DATABASE_UUID_KEY: &str = "KPRS_DATABASE_UUID";
+
+/// Key of the public custom data item holding the published name of the database
+pub const DATABASE_NAME_KEY: &str = "KPRS_DATABASE_NAME";
+
+/// A value of the public custom data
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serialization", derive(serde::Serialize, serde::Deserialize))]
+pub enum PublicValue {
+    UInt32(u32),
+    UInt64(u64),
+    Bool(bool),
+    Int32(i32),
+    Int64(i64),
+    String(String),
+    Bytes(Vec<u8>),
+}
+
+/// Custom data stored unencrypted in the outer header of KDBX4 databases
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serialization", derive(serde::Serialize, serde::Deserialize))]
+pub struct PublicCustomData {
+    pub items: BTreeMap<String, PublicValue>,
+}
+
+impl PublicCustomData {
+    /// Public custom data with a new database UUID
+    pub fn new() -> Self {
+        let mut data = PublicCustomData::default();
+        data.ensure_database_uuid();
+        data
+    }
+
+    /// The UUID identifying the database
+    pub fn database_uuid(&self) -> Option<Uuid> {
+        match self.items.get(DATABASE_UUID_KEY)? {
+            PublicValue::Bytes(bytes) => Uuid::from_slice(bytes).ok(),
+            _ => None,
+        }
+    }
+
+    /// The published name of the database
+    pub fn name(&self) -> Option<&str> {
+        match self.items.get(DATABASE_NAME_KEY)? {
+            PublicValue::String(name) => Some(name),
+            _ => None,
+        }
+    }
+
+    /// Generate a database UUID if there is none yet, returning the UUID
+    pub fn ensure_database_uuid(&mut self) -> Uuid {
+        if let Some(uuid) = self.database_uuid() {
+            return uuid;
+        }
+
+        let uuid = Uuid::new_v4();
+        self.items.insert(
+            DATABASE_UUID_KEY.to_string(),
+            PublicValue::Bytes(uuid.as_bytes().to_vec()),
+        );
+        uuid
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub(crate) fn from_variant_dictionary(vd: VariantDictionary) -> Self {
+        let items = vd
+            .data
+            .into_iter()
+            .map(|(key, value)| {
+                let value = match value {
+                    VariantDictionaryValue::UInt32(v) => PublicValue::UInt32(v),
+                    VariantDictionaryValue::UInt64(v) => PublicValue::UInt64(v),
+                    VariantDictionaryValue::Bool(v) => PublicValue::Bool(v),
+                    VariantDictionaryValue::Int32(v) => PublicValue::Int32(v),
+                    VariantDictionaryValue::Int64(v) => PublicValue::Int64(v),
+                    VariantDictionaryValue::String(v) => PublicValue::String(v),
+                    VariantDictionaryValue::ByteArray(v) => PublicValue::Bytes(v),
+                };
+                (key, value)
+            })
+            .collect();
+        PublicCustomData { items }
+    }
+
+    #[cfg(feature = "save_kdbx4")]
+    pub(crate) fn to_variant_dictionary(&self) -> VariantDictionary {
+        let mut vd = VariantDictionary::new();
+        for (key, value) in &self.items {
+            match value {
+                PublicValue::UInt32(v) => vd.set(key, *v),
+                PublicValue::UInt64(v) => vd.set(key, *v),
+                PublicValue::Bool(v) => vd.set(key, *v),
+                PublicValue::Int32(v) => vd.set(key, *v),
+                PublicValue::Int64(v) => vd.set(key, *v),
+                PublicValue::String(v) => vd.set(key, v.clone()),
+                PublicValue::Bytes(v) => vd.set(key, v.clone()),
+            }
+        }
+        vd
+    }
+}
+
+impl Database {
+    /// The UUID identifying the database, which stays the same when the file is renamed
+    pub fn database_uuid(&self) -> Option<Uuid> {
+        self.public_custom_data.database_uuid()
+    }
+
+    /// Publish a name for the database in the public custom data, or remove it. The name can be
+    /// read by anyone with the file.
+    pub fn set_public_name(&mut self, name: Option<&str>) {
+        match name {
+            Some(name) => {
+                self.public_custom_data.items.insert(
+                    DATABASE_NAME_KEY.to_string(),
+                    PublicValue::String(name.to_string()),
+                );
+            }
+            None => {
+                self.public_custom_data.items.remove(DATABASE_NAME_KEY);
+            }
+        }
+    }
+
+    /// Read the public custom data of a database without the key. Databases older than KDBX4
+    /// have no public custom data.
+    pub fn read_public_custom_data(
+        source: &mut dyn std::io::Read,
+    ) -> Result<PublicCustomData, DatabaseOpenError> {
+        let mut data = Vec::new();
+        source.read_to_end(&mut data)?;
+
+        match DatabaseVersion::parse(&data)? {
+            DatabaseVersion::KDB4(_) => parse_public_custom_data(&data),
+            _ => Ok(PublicCustomData::default()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod public_data_tests {
+    use crate::db::Database;
+
+    use super::{PublicCustomData, PublicValue};
+
+    #[test]
+    fn test_database_uuid() {
+        let db = Database::new(Default::default());
+        assert!(db.database_uuid().is_some());
+        assert_ne!(
+            db.database_uuid(),
+            Database::new(Default::default()).database_uuid()
+        );
+
+        let mut data = PublicCustomData::default();
+        assert_eq!(data.database_uuid(), None);
+        let uuid = data.ensure_database_uuid();
+        assert_eq!(data.ensure_database_uuid(), uuid);
+
+        data.items.insert(
+            "KPRS_DATABASE_UUID".to_string(),
+            PublicValue::String("x".to_string()),
+        );
+        assert_eq!(data.database_uuid(), None);
+    }
+
+    #[cfg(feature = "save_kdbx4")]
+    #[test]
+    fn test_read_public_custom_data() {
+        use crate::{
+            config::{DatabaseConfig, KdfConfig},
+            DatabaseKey,
+        };
+
+        let mut db = Database::new(DatabaseConfig {
+            kdf_config: KdfConfig::Aes { rounds: 10 },
+            ..Default::default()
+        });
+        db.set_public_name(Some("Work"));
+        db.public_custom_data
+            .items
+            .insert("OtherTool".to_string(), PublicValue::UInt64(42));
+
+        let key = || DatabaseKey::new().with_password("public");
+        let mut data = Vec::new();
+        db.save(&mut data, key()).unwrap();
+
+        let public = Database::read_public_custom_data(&mut data.as_slice()).unwrap();
+        assert_eq!(public.database_uuid(), db.database_uuid());
+        assert_eq!(public.name(), Some("Work"));
+        assert_eq!(public.items.get("OtherTool"), Some(&PublicValue::UInt64(42)));
+
+        let reopened = Database::parse(&data, key()).unwrap();
+        assert_eq!(reopened.public_custom_data, db.public_custom_data);
+    }
+}
@@ -0,0 +1,223 @@
+//! One-time recovery codes
+//!
+//! Services hand out a list of recovery codes for when the second factor is lost, and each code
+//! can only be used once. The codes of an entry are kept in the protected field
+//! `RECOVERY_CODES_FIELD`, one code per line, with used codes checked off:
+//!
+//! ```text
+//! [x] abcd-efgh
+//! [ ] ijkl-mnop
+//! ```
+//!
+//! The format stays readable in other KeePass clients, which show the field like any other.
+
+use thiserror::Error;
+use zeroize::Zeroize;
+
+use crate::db::{Entry, Value};
+
+/// Name of the protected field holding the recovery codes of an entry
+pub const RECOVERY_CODES_FIELD: &str = "Recovery Codes";
+
+/// Errors when using a recovery code
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum RecoveryCodeError {
+    #[error("The entry has no recovery codes")]
+    NoRecoveryCodes,
+
+    #[error("The recovery code is not known")]
+    UnknownCode,
+
+    #[error("The recovery code was already used")]
+    AlreadyUsed,
+}
+
+/// A single recovery code. The code is wiped from memory when dropped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecoveryCode {
+    pub code: String,
+    pub used: bool,
+}
+
+impl Drop for RecoveryCode {
+    fn drop(&mut self) {
+        self.code.zeroize();
+    }
+}
+
+impl RecoveryCode {
+    /// Whether `code` is this code, ignoring case, whitespace and dashes as services differ in
+    /// how they display codes
+    pub fn matches(&self, code: &str) -> bool {
+        let normalize = |c: &str| -> String {
+            c.chars()
+                .filter(|c| !c.is_whitespace() && *c != '-')
+                .flat_map(char::to_lowercase)
+                .collect()
+        };
+        let wanted = normalize(code);
+        !wanted.is_empty() && normalize(&self.code) == wanted
+    }
+}
+
+/// The recovery codes of an entry
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RecoveryCodes {
+    pub codes: Vec<RecoveryCode>,
+}
+
+impl RecoveryCodes {
+    /// New, unused codes
+    pub fn new<S: AsRef<str>>(codes: &[S]) -> Self {
+        RecoveryCodes {
+            codes: codes
+                .iter()
+                .map(|c| c.as_ref().trim())
+                .filter(|c| !c.is_empty())
+                .map(|c| RecoveryCode {
+                    code: c.to_string(),
+                    used: false,
+                })
+                .collect(),
+        }
+    }
+
+    /// Parse the content of the recovery codes field. Lines without a check box are unused codes.
+    pub fn parse(value: &str) -> Self {
+        let codes = value
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty())
+            .map(|line| {
+                let (used, code) =
+                    if let Some(code) = line.strip_prefix("[x]").or_else(|| line.strip_prefix("[X]")) {
+                        (true, code)
+                    } else if let Some(code) = line.strip_prefix("[ ]") {
+                        (false, code)
+                    } else {
+                        (false, line)
+                    };
+                RecoveryCode {
+                    code: code.trim().to_string(),
+                    used,
+                }
+            })
+            .filter(|c| !c.code.is_empty())
+            .collect();
+
+        RecoveryCodes { codes }
+    }
+
+    /// The codes in the format of the recovery codes field
+    pub fn to_field_value(&self) -> String {
+        self.codes
+            .iter()
+            .map(|c| format!("[{}] {}", if c.used { 'x' } else { ' ' }, c.code))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// The codes that can still be used
+    pub fn unused(&self) -> impl Iterator<Item = &str> {
+        self.codes.iter().filter(|c| !c.used).map(|c| c.code.as_str())
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.unused().count()
+    }
+
+    /// Check off a code
+    pub fn mark_used(&mut self, code: &str) -> Result<(), RecoveryCodeError> {
+        let found = self
+            .codes
+            .iter_mut()
+            .find(|c| c.matches(code))
+            .ok_or(RecoveryCodeError::UnknownCode)?;
+        if found.used {
+            return Err(RecoveryCodeError::AlreadyUsed);
+        }
+        found.used = true;
+        Ok(())
+    }
+}
+
+impl Entry {
+    /// The recovery codes stored in the entry, if any
+    pub fn recovery_codes(&self) -> Option<RecoveryCodes> {
+        let value = self.get_protected(RECOVERY_CODES_FIELD)?;
+        Some(RecoveryCodes::parse(value.as_str()?))
+    }
+
+    /// Store recovery codes in the entry, replacing the previous ones. An empty list removes the
+    /// field.
+    pub fn set_recovery_codes(&mut self, codes: &RecoveryCodes) {
+        if codes.codes.is_empty() {
+            self.fields.remove(RECOVERY_CODES_FIELD);
+            return;
+        }
+
+        let mut value = codes.to_field_value();
+        self.fields.insert(
+            RECOVERY_CODES_FIELD.to_string(),
+            Value::Protected(value.as_bytes().into()),
+        );
+        value.zeroize();
+    }
+
+    /// Check off a recovery code after it was used. Returns the number of unused codes left, so
+    /// callers can remind the user to generate new ones.
+    pub fn mark_code_used(&mut self, code: &str) -> Result<usize, RecoveryCodeError> {
+        let mut codes = self.recovery_codes().ok_or(RecoveryCodeError::NoRecoveryCodes)?;
+        codes.mark_used(code)?;
+        self.set_recovery_codes(&codes);
+        Ok(codes.remaining())
+    }
+}
+
+#[cfg(test)]
+mod recovery_tests {
+    use crate::db::{Entry, Value};
+
+    use super::{RecoveryCodeError, RecoveryCodes, RECOVERY_CODES_FIELD};
+
+    #[test]
+    fn test_recovery_codes() {
+        let mut entry = Entry::new();
+        assert_eq!(entry.recovery_codes(), None);
+        assert_eq!(
+            entry.mark_code_used("abcd-efgh"),
+            Err(RecoveryCodeError::NoRecoveryCodes)
+        );
+
+        entry.set_recovery_codes(&RecoveryCodes::new(&["ABCD-EFGH", "ijkl-mnop", " "]));
+        assert!(matches!(entry.fields[RECOVERY_CODES_FIELD], Value::Protected(_)));
+        assert_eq!(entry.recovery_codes().unwrap().remaining(), 2);
+
+        assert_eq!(entry.mark_code_used("abcd efgh"), Ok(1));
+        assert_eq!(
+            entry.mark_code_used("ABCDEFGH"),
+            Err(RecoveryCodeError::AlreadyUsed)
+        );
+        assert_eq!(
+            entry.mark_code_used("0000-0000"),
+            Err(RecoveryCodeError::UnknownCode)
+        );
+        assert_eq!(
+            entry.get(RECOVERY_CODES_FIELD),
+            Some("[x] ABCD-EFGH\n[ ] ijkl-mnop")
+        );
+        assert_eq!(
+            entry.recovery_codes().unwrap().unused().collect::<Vec<_>>(),
+            vec!["ijkl-mnop"]
+        );
+
+        // codes entered by hand in other clients
+        let codes = RecoveryCodes::parse("1111\n\n[X] 2222\n[ ]3333\n[x]");
+        assert_eq!(codes.codes.len(), 3);
+        assert!(codes.codes[1].used);
+        assert_eq!(codes.unused().collect::<Vec<_>>(), vec!["1111", "3333"]);
+
+        entry.set_recovery_codes(&RecoveryCodes::default());
+        assert_eq!(entry.recovery_codes(), None);
+    }
+}
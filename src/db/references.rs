@@ -0,0 +1,521 @@
+//! Keeping field references intact
+//!
+//! KeePass entries can take field values from other entries with placeholders like
+//! `{REF:P@I:46C9B1FFBD4ABC4BBB260C6190BAD20C}` ("the password of the entry with this UUID") or
+//! `{REF:U@T:Mail}` ("the user name of the entry titled Mail"). Such references break silently
+//! when the referenced entry gets a new UUID, e.g. after cloning an entry and deleting the
+//! original, or when the field that is searched in is changed.
+//!
+//! `Database::rewrite_references` updates references after UUIDs were remapped, and
+//! `Database::set_referenced_field` changes a standard field of an entry together with the
+//! references that find the entry by that field. `Entry::get_resolved` replaces references with
+//! the values they refer to:
+//!
+//! ```
+//! use keepass::db::{Database, Entry, Value};
+//!
+//! let mut db = Database::new(Default::default());
+//! let mut mail = Entry::new();
+//! mail.fields.insert("Title".to_string(), Value::Unprotected("Mail".to_string()));
+//! mail.fields.insert("Password".to_string(), Value::Protected("secret".as_bytes().into()));
+//! db.root.add_child(mail);
+//!
+//! let mut alias = Entry::new();
+//! alias.fields.insert("Password".to_string(), Value::Unprotected("{REF:P@T:Mail}".to_string()));
+//!
+//! assert_eq!(alias.get_protected("Password").unwrap().as_str(), Some("{REF:P@T:Mail}"));
+//! assert_eq!(alias.get_resolved("Password", &db)?, Some("secret".to_string()));
+//! # Ok::<(), keepass::db::ReferenceError>(())
+//! ```
+
+use std::collections::HashMap;
+
+use thiserror::Error;
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+use crate::db::{Database, Entry, Group, Node, NodeRef, Times, Value};
+
+/// The standard fields and the codes references use for them
+const FIELD_CODES: [(&str, char); 5] = [
+    ("Title", 'T'),
+    ("UserName", 'U'),
+    ("Password", 'P'),
+    ("URL", 'A'),
+    ("Notes", 'N'),
+];
+
+/// The reference code of a standard field, e.g. `P` for `Password`
+fn field_code(field: &str) -> Option<char> {
+    FIELD_CODES
+        .iter()
+        .find(|(name, _)| *name == field)
+        .map(|(_, code)| *code)
+}
+
+/// The standard field of a reference code, e.g. `Password` for `P`
+fn field_name(code: char) -> Option<&'static str> {
+    FIELD_CODES
+        .iter()
+        .find(|(_, c)| *c == code)
+        .map(|(name, _)| *name)
+}
+
+/// Errors when resolving field references
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum ReferenceError {
+    #[error("Field {1} of entry {0} refers to itself")]
+    Cycle(Uuid, String),
+}
+
+impl Entry {
+    /// Get a field by name with its `{REF:...}` placeholders replaced by the values they refer to
+    /// in `db`, following references in the referenced fields as well. References find the first
+    /// entry whose field equals the search text, ignoring case, or whose UUID is the search text.
+    /// Placeholders that match no entry are kept as they are, like KeePass does.
+    pub fn get_resolved(&self, field: &str, db: &Database) -> Result<Option<String>, ReferenceError> {
+        resolve_field(self, field, db, &mut Vec::new())
+    }
+}
+
+impl Database {
+    /// Update all `{REF:X@I:<uuid>}` references to entries that were given a new UUID. `mapping`
+    /// maps old UUIDs to new ones. Returns the UUIDs of the entries that were changed.
+    pub fn rewrite_references(&mut self, mapping: &HashMap<Uuid, Uuid>) -> Vec<Uuid> {
+        if mapping.is_empty() {
+            return Vec::new();
+        }
+
+        rewrite_in_group(&mut self.root, &mut |search_in, text| {
+            remap_uuid(mapping, search_in, text)
+        })
+    }
+
+    /// Set a standard field (`Title`, `UserName`, `Password`, `URL` or `Notes`) of an entry and
+    /// update the references that find the entry by the old value of that field, e.g.
+    /// `{REF:P@T:<old title>}`. The field keeps its protection. Returns the UUIDs of the
+    /// referencing entries that were changed, or `None` if there is no such entry.
+    pub fn set_referenced_field(&mut self, entry: Uuid, field: &str, value: &str) -> Option<Vec<Uuid>> {
+        let target = self.root.entry_by_uuid_mut(&entry)?;
+        let old = target
+            .get_protected(field)
+            .and_then(|old| old.as_str().map(|old| Zeroizing::new(old.to_string())));
+
+        let new_value = match target.fields.get(field) {
+            Some(Value::Protected(_)) => Value::Protected(value.as_bytes().into()),
+            _ => Value::Unprotected(value.to_string()),
+        };
+        target.fields.insert(field.to_string(), new_value);
+        target.update_history();
+
+        let (code, old) = match (field_code(field), old) {
+            (Some(code), Some(old)) if !old.is_empty() && *old != value => (code, old),
+            _ => return Some(Vec::new()),
+        };
+
+        Some(rewrite_in_group(&mut self.root, &mut |search_in, text| {
+            if search_in.to_ascii_uppercase() == code && text.eq_ignore_ascii_case(&old) {
+                Some(value.to_string())
+            } else {
+                None
+            }
+        }))
+    }
+}
+
+/// Resolve a field of an entry, keeping track of the fields that are being resolved in `stack`
+fn resolve_field(
+    entry: &Entry,
+    field: &str,
+    db: &Database,
+    stack: &mut Vec<(Uuid, String)>,
+) -> Result<Option<String>, ReferenceError> {
+    let value = match entry.get_protected(field) {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    let value = match value.as_str() {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+
+    let key = (entry.uuid, field.to_string());
+    if stack.contains(&key) {
+        return Err(ReferenceError::Cycle(entry.uuid, field.to_string()));
+    }
+    stack.push(key);
+    let resolved = resolve_text(value, db, stack);
+    stack.pop();
+
+    resolved.map(Some)
+}
+
+pub(crate) fn resolve_text(
+    text: &str,
+    db: &Database,
+    stack: &mut Vec<(Uuid, String)>,
+) -> Result<String, ReferenceError> {
+    const PREFIX: &str = "{REF:";
+
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = find_ignore_case(rest, PREFIX) {
+        let (before, placeholder) = rest.split_at(start);
+        out.push_str(before);
+
+        let end = match placeholder.find('}') {
+            Some(end) => end,
+            None => {
+                rest = placeholder;
+                break;
+            }
+        };
+
+        let target =
+            parse_reference(&placeholder[PREFIX.len()..end]).and_then(|(wanted, search_in, search)| {
+                Some((wanted, find_referenced_entry(db, search_in, search)?))
+            });
+        match target {
+            Some(('I', target)) => out.push_str(&target.uuid.simple().to_string().to_uppercase()),
+            Some((wanted, target)) => {
+                let value = match field_name(wanted) {
+                    Some(field) => resolve_field(target, field, db, stack)?,
+                    None => None,
+                };
+                out.push_str(&value.unwrap_or_default());
+            }
+            None => out.push_str(&placeholder[..=end]),
+        }
+
+        rest = &placeholder[end + 1..];
+    }
+
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Split the inside of a `{REF:<wanted>@<search in>:<text>}` placeholder into the upper case codes
+/// and the search text
+fn parse_reference(inner: &str) -> Option<(char, char, &str)> {
+    let ((wanted, search_in), search) = inner
+        .split_once(':')
+        .and_then(|(codes, search)| Some((codes.split_once('@')?, search)))?;
+
+    let single = |code: &str| {
+        let mut chars = code.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) => Some(c.to_ascii_uppercase()),
+            _ => None,
+        }
+    };
+    Some((single(wanted)?, single(search_in)?, search))
+}
+
+fn find_referenced_entry<'a>(db: &'a Database, search_in: char, search: &str) -> Option<&'a Entry> {
+    let field_is = |entry: &Entry, field: &str| {
+        entry
+            .get_protected(field)
+            .is_some_and(|v| v.as_str().is_some_and(|v| v.eq_ignore_ascii_case(search)))
+    };
+    let matches = |entry: &Entry| match search_in {
+        'I' => Uuid::parse_str(search).is_ok_and(|uuid| uuid == entry.uuid),
+        // other fields are the ones that are not standard fields
+        'O' => entry
+            .fields
+            .keys()
+            .any(|field| field_code(field).is_none() && field_is(entry, field)),
+        code => field_name(code).is_some_and(|field| field_is(entry, field)),
+    };
+
+    db.root.iter().find_map(|node| match node {
+        NodeRef::Entry(e) if matches(e) => Some(e),
+        _ => None,
+    })
+}
+
+/// The new search text of a reference by UUID, written like KeePass does: 32 upper case hex
+/// digits
+fn remap_uuid(mapping: &HashMap<Uuid, Uuid>, search_in: char, text: &str) -> Option<String> {
+    if search_in != 'I' {
+        return None;
+    }
+    let uuid = mapping.get(&Uuid::parse_str(text).ok()?)?;
+    Some(uuid.simple().to_string().to_uppercase())
+}
+
+/// Rewrite the references in a single entry, see `rewrite_references`. Returns whether the entry
+/// was changed. The modification time is left alone.
+#[cfg(feature = "_merge")]
+pub(crate) fn rewrite_entry_references(entry: &mut Entry, mapping: &HashMap<Uuid, Uuid>) -> bool {
+    let mut changed = false;
+    for value in entry.fields.values_mut() {
+        changed |= rewrite_value(value, &mut |search_in, text| remap_uuid(mapping, search_in, text));
+    }
+    changed
+}
+
+type Rewrite<'a> = dyn FnMut(char, &str) -> Option<String> + 'a;
+
+fn rewrite_in_group(group: &mut Group, rewrite: &mut Rewrite) -> Vec<Uuid> {
+    let mut changed = Vec::new();
+
+    for node in &mut group.children {
+        match node {
+            Node::Entry(entry) => {
+                let mut entry_changed = false;
+                for value in entry.fields.values_mut() {
+                    entry_changed |= rewrite_value(value, rewrite);
+                }
+                if entry_changed {
+                    entry.times.set_last_modification(Times::now());
+                    changed.push(entry.uuid);
+                }
+            }
+            Node::Group(g) => changed.extend(rewrite_in_group(g, rewrite)),
+        }
+    }
+
+    changed
+}
+
+fn rewrite_value(value: &mut Value, rewrite: &mut Rewrite) -> bool {
+    let rewritten = match value {
+        Value::Unprotected(text) => rewrite_text(text, rewrite).map(Value::Unprotected),
+        Value::Protected(secret) => secret
+            .decrypt()
+            .as_str()
+            .and_then(|text| rewrite_text(text, rewrite))
+            .map(|text| Value::Protected(text.as_bytes().into())),
+        Value::Bytes(_) => None,
+    };
+
+    match rewritten {
+        Some(new) => {
+            *value = new;
+            true
+        }
+        None => false,
+    }
+}
+
+/// Rewrite the search text of all `{REF:<wanted>@<search in>:<text>}` placeholders in `text`.
+/// `rewrite` gets the upper case search-in code and the search text, and returns the new search
+/// text, if any. Returns `None` if nothing was rewritten.
+fn rewrite_text(text: &str, rewrite: &mut Rewrite) -> Option<String> {
+    const PREFIX: &str = "{REF:";
+
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    let mut changed = false;
+
+    while let Some(start) = find_ignore_case(rest, PREFIX) {
+        let (before, placeholder) = rest.split_at(start);
+        out.push_str(before);
+
+        let end = match placeholder.find('}') {
+            Some(end) => end,
+            None => {
+                rest = placeholder;
+                break;
+            }
+        };
+        let inner = &placeholder[PREFIX.len()..end];
+        let parts = inner
+            .split_once(':')
+            .and_then(|(codes, search)| Some((codes.split_once('@')?, search)));
+
+        match parts {
+            Some(((wanted, search_in), search)) if search_in.chars().count() == 1 => {
+                let code = search_in.chars().next().unwrap_or_default().to_ascii_uppercase();
+                match rewrite(code, search) {
+                    Some(new) if new != search => {
+                        out.push_str(&format!(
+                            "{}{}@{}:{}}}",
+                            &placeholder[..PREFIX.len()],
+                            wanted,
+                            search_in,
+                            new
+                        ));
+                        changed = true;
+                    }
+                    _ => out.push_str(&placeholder[..=end]),
+                }
+            }
+            _ => out.push_str(&placeholder[..=end]),
+        }
+
+        rest = &placeholder[end + 1..];
+    }
+
+    if !changed {
+        return None;
+    }
+    out.push_str(rest);
+    Some(out)
+}
+
+fn find_ignore_case(haystack: &str, needle: &str) -> Option<usize> {
+    haystack.char_indices().map(|(i, _)| i).find(|&i| {
+        haystack
+            .get(i..i + needle.len())
+            .is_some_and(|candidate| candidate.eq_ignore_ascii_case(needle))
+    })
+}
+
+#[cfg(test)]
+mod references_tests {
+    use std::collections::HashMap;
+
+    use uuid::Uuid;
+
+    use crate::db::{Database, Entry, ReferenceError, Value};
+
+    fn entry(title: &str, password: &str) -> Entry {
+        let mut entry = Entry::new();
+        entry
+            .fields
+            .insert("Title".to_string(), Value::Unprotected(title.to_string()));
+        entry.fields.insert(
+            "Password".to_string(),
+            Value::Protected(password.as_bytes().into()),
+        );
+        entry
+    }
+
+    #[test]
+    fn test_rewrite_references() {
+        let mut db = Database::new(Default::default());
+        let original = entry("Mail", "secret");
+        let original_uuid = original.uuid;
+        db.root.add_child(original);
+
+        let mut referencing = entry("Mail alias", "");
+        let referencing_uuid = referencing.uuid;
+        let reference = format!(
+            "{{REF:P@I:{}}}",
+            original_uuid.simple().to_string().to_uppercase()
+        );
+        referencing.fields.insert(
+            "Password".to_string(),
+            Value::Protected(reference.as_bytes().into()),
+        );
+        referencing.fields.insert(
+            "Notes".to_string(),
+            Value::Unprotected(format!(
+                "user {{ref:u@i:{}}}, other {{REF:U@T:Mail}}",
+                original_uuid
+            )),
+        );
+        db.root.add_child(referencing);
+
+        // nothing changes for unrelated mappings
+        let mut mapping = HashMap::new();
+        mapping.insert(Uuid::new_v4(), Uuid::new_v4());
+        assert!(db.rewrite_references(&mapping).is_empty());
+
+        let clone_uuid = Uuid::new_v4();
+        let mapping: HashMap<Uuid, Uuid> = vec![(original_uuid, clone_uuid)].into_iter().collect();
+        assert_eq!(db.rewrite_references(&mapping), vec![referencing_uuid]);
+
+        let referencing = db.root.entries()[1];
+        let new_reference = clone_uuid.simple().to_string().to_uppercase();
+        assert_eq!(
+            referencing.get_password(),
+            Some(format!("{{REF:P@I:{}}}", new_reference).as_str())
+        );
+        assert!(matches!(referencing.fields["Password"], Value::Protected(_)));
+        assert_eq!(
+            referencing.get("Notes"),
+            Some(format!("user {{ref:u@i:{}}}, other {{REF:U@T:Mail}}", new_reference).as_str())
+        );
+    }
+
+    #[test]
+    fn test_set_referenced_field() {
+        let mut db = Database::new(Default::default());
+        let target = entry("Mail", "secret");
+        let target_uuid = target.uuid;
+        db.root.add_child(target);
+
+        let mut referencing = entry("Alias", "{REF:P@T:mail}");
+        referencing.fields.insert(
+            "UserName".to_string(),
+            Value::Unprotected("{REF:U@T:Other}".to_string()),
+        );
+        let referencing_uuid = referencing.uuid;
+        db.root.add_child(referencing);
+
+        assert_eq!(db.set_referenced_field(Uuid::new_v4(), "Title", "x"), None);
+        assert_eq!(
+            db.set_referenced_field(target_uuid, "Title", "Webmail"),
+            Some(vec![referencing_uuid])
+        );
+
+        assert_eq!(db.root.entries()[0].get_title(), Some("Webmail"));
+        assert_eq!(db.root.entries()[1].get_password(), Some("{REF:P@T:Webmail}"));
+        assert_eq!(db.root.entries()[1].get_username(), Some("{REF:U@T:Other}"));
+
+        // the protection of the field is kept, and other fields have no references to update
+        assert_eq!(
+            db.set_referenced_field(target_uuid, "Password", "changed"),
+            Some(vec![])
+        );
+        assert!(matches!(
+            db.root.entries()[0].fields["Password"],
+            Value::Protected(_)
+        ));
+    }
+
+    #[test]
+    fn test_get_resolved() {
+        let mut db = Database::new(Default::default());
+        let mut target = entry("Mail", "secret");
+        target
+            .fields
+            .insert("UserName".to_string(), Value::Unprotected("jdoe".to_string()));
+        target
+            .fields
+            .insert("Server".to_string(), Value::Unprotected("imap".to_string()));
+        let target_uuid = target.uuid;
+        db.root.add_child(target);
+
+        let hex = target_uuid.simple().to_string().to_uppercase();
+        let mut chained = entry("Chained", "{REF:P@T:mail}");
+        chained.fields.insert(
+            "Notes".to_string(),
+            Value::Unprotected(format!(
+                "{{REF:U@I:{}}} on {{ref:t@o:IMAP}}, {{REF:P@T:Missing}}",
+                hex
+            )),
+        );
+        let chained_uuid = chained.uuid;
+        db.root.add_child(chained);
+
+        let alias = entry("Alias", &format!("{{REF:P@I:{}}}!", chained_uuid));
+        assert_eq!(
+            alias.get_resolved("Password", &db),
+            Ok(Some("secret!".to_string()))
+        );
+        assert_eq!(alias.get_resolved("Missing", &db), Ok(None));
+
+        let chained = db.root.entries()[1];
+        assert_eq!(
+            chained.get_resolved("Notes", &db),
+            Ok(Some("jdoe on Mail, {REF:P@T:Missing}".to_string()))
+        );
+
+        let mut looping = entry("Loop", "{REF:P@T:Loop2}");
+        let looping_uuid = looping.uuid;
+        looping
+            .fields
+            .insert("Title".to_string(), Value::Unprotected("Loop1".to_string()));
+        db.root.add_child(looping);
+        db.root.add_child(entry("Loop2", "{REF:P@T:Loop1}"));
+        let looping = db.root.entries()[2];
+        assert_eq!(
+            looping.get_resolved("Password", &db),
+            Err(ReferenceError::Cycle(looping_uuid, "Password".to_string()))
+        );
+    }
+}
@@ -0,0 +1,437 @@
+//! Changing the master key and the key derivation of a database
+//!
+//! The database does not keep its key: every save takes one and encrypts the database with fresh
+//! seeds. `Database::change_key` checks the old key against the file the database was read from,
+//! or against the key it was last changed to, records when the key changed, and hands back the
+//! new key to save with:
+//!
+//! ```
+//! # #[cfg(feature = "save_kdbx4")]
+//! # {
+//! use keepass::{Database, DatabaseKey};
+//!
+//! let data = std::fs::read("tests/resources/test_db_kdbx4_with_password_aes.kdbx")?;
+//! let old_key = DatabaseKey::new().with_password("demopass");
+//! let mut db = Database::parse(&data, old_key.clone())?;
+//!
+//! let new_key = db.change_key(&old_key, DatabaseKey::new().with_password("n3w p4ssw0rd"))?;
+//! let mut saved = Vec::new();
+//! db.save(&mut saved, new_key.clone())?;
+//! assert!(Database::verify_key(&mut saved.as_slice(), new_key)?);
+//! # }
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::io::Read;
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use thiserror::Error;
+
+use crate::{
+    config::{Argon2SecretParameters, KdfConfig},
+    db::{Database, Times},
+    error::{DatabaseKeyError, DatabaseOpenError},
+    format::{kdbx3::verify_kdbx3_key, kdbx4::verify_kdbx4_key, read_outer_header_data, DatabaseVersion},
+    key::{DatabaseKey, TransformedKey},
+};
+
+/// Checks keys against the file a database was read from or the key it was changed to. Holds
+/// nothing secret: either the outer header of a KDBX file along with what follows it, which is in
+/// the file anyway, or a MAC made with the transformed key, which takes the KDF to check a guess
+/// against just like the file does.
+///
+/// Like `FileFingerprint`, it is not part of the contents of a database, so all key checks are
+/// equal as far as the equality of databases is concerned.
+#[derive(Debug, Clone, Default)]
+pub struct KeyCheck(Check);
+
+#[derive(Debug, Clone, Default)]
+enum Check {
+    #[default]
+    Unknown,
+
+    /// The outer header of a KDBX 3 file and the start of its payload
+    Kdbx3(Vec<u8>),
+
+    /// The outer header of a KDBX 4 file, its hash and its HMAC
+    Kdbx4(Vec<u8>),
+
+    /// A MAC of the KDF seed made with a key transformed with it
+    Derived {
+        kdf_config: KdfConfig,
+        kdf_seed: Vec<u8>,
+        argon2_secret_parameters: Argon2SecretParameters,
+        mac: Vec<u8>,
+    },
+}
+
+/// How much of a file after its outer header is kept: the hash and the HMAC of the header of a
+/// KDBX 4 file, or at least the encrypted stream start bytes of a KDBX 3 file
+pub(crate) const HEADER_HASHES_SIZE: usize = 64;
+
+impl KeyCheck {
+    /// The key check of a KDBX file, which is unknown for other files
+    pub(crate) fn of(data: &[u8]) -> KeyCheck {
+        KeyCheck::read(&mut &data[..]).unwrap_or_default()
+    }
+
+    /// Read the key check from the start of a file
+    pub(crate) fn read(source: &mut dyn Read) -> Result<KeyCheck, DatabaseOpenError> {
+        let mut header = read_outer_header_data(source)?;
+        let version = DatabaseVersion::parse(&header)?;
+
+        let header_len = header.len();
+        header.resize(header_len + HEADER_HASHES_SIZE, 0);
+        source.read_exact(&mut header[header_len..])?;
+        Ok(KeyCheck(match version {
+            DatabaseVersion::KDB3(_) => Check::Kdbx3(header),
+            DatabaseVersion::KDB4(_) => Check::Kdbx4(header),
+            _ => Check::Unknown,
+        }))
+    }
+
+    /// A check for a key, which is transformed with the given KDF settings and a new seed. The
+    /// transformed key is returned along with it.
+    fn derive(
+        key: &DatabaseKey,
+        kdf_config: &KdfConfig,
+        argon2_secret_parameters: &Argon2SecretParameters,
+    ) -> Result<(KeyCheck, TransformedKey), DatabaseKeyError> {
+        let mut kdf_seed = vec![0; kdf_config.seed_size()];
+        getrandom::fill(&mut kdf_seed).map_err(|e| std::io::Error::other(e.to_string()))?;
+
+        let transformed_key = transform(key, kdf_config, &kdf_seed, argon2_secret_parameters)?;
+        let check = KeyCheck(Check::Derived {
+            kdf_config: kdf_config.clone(),
+            kdf_seed: kdf_seed.clone(),
+            argon2_secret_parameters: argon2_secret_parameters.clone(),
+            mac: key_mac(&transformed_key, &kdf_seed)
+                .finalize()
+                .into_bytes()
+                .to_vec(),
+        });
+        Ok((check, transformed_key))
+    }
+
+    /// Whether keys can be checked, which they can for databases read from a KDBX file and for
+    /// databases whose key was changed
+    pub fn is_known(&self) -> bool {
+        !matches!(self.0, Check::Unknown)
+    }
+
+    /// Check whether a key is the key of the database, or `None` if that is unknown. This runs
+    /// the KDF, unless the key was transformed for the same settings before, e.g. while opening
+    /// the database.
+    pub fn verify(&self, key: &DatabaseKey) -> Result<Option<bool>, DatabaseOpenError> {
+        match &self.0 {
+            Check::Unknown => Ok(None),
+            Check::Kdbx3(header) => verify_kdbx3_key(&mut header.as_slice(), key).map(Some),
+            Check::Kdbx4(header) => verify_kdbx4_key(&mut header.as_slice(), key).map(Some),
+            Check::Derived {
+                kdf_config,
+                kdf_seed,
+                argon2_secret_parameters,
+                mac,
+            } => {
+                let transformed_key =
+                    match key.transformed_key_for(kdf_config, kdf_seed, argon2_secret_parameters) {
+                        Some(transformed_key) => transformed_key.clone(),
+                        None => transform(key, kdf_config, kdf_seed, argon2_secret_parameters)?,
+                    };
+                Ok(Some(
+                    key_mac(&transformed_key, kdf_seed).verify_slice(mac).is_ok(),
+                ))
+            }
+        }
+    }
+}
+
+/// Transform a key like saving a KDBX 4 database does, using the process-wide cache
+fn transform(
+    key: &DatabaseKey,
+    kdf_config: &KdfConfig,
+    kdf_seed: &[u8],
+    argon2_secret_parameters: &Argon2SecretParameters,
+) -> Result<TransformedKey, DatabaseKeyError> {
+    #[cfg(feature = "challenge_response")]
+    let key = &key.clone().perform_challenge(kdf_seed)?;
+
+    let transformed_key = kdf_config
+        .get_kdf_seeded(kdf_seed, argon2_secret_parameters)
+        .transform_key_cached(&*key.composite_key()?)?;
+    Ok(TransformedKey::new(
+        kdf_config.clone(),
+        kdf_seed.to_vec(),
+        argon2_secret_parameters.clone(),
+        &transformed_key,
+    ))
+}
+
+/// The MAC that `KeyCheck` keeps for a key derived with a seed
+fn key_mac(transformed_key: &TransformedKey, kdf_seed: &[u8]) -> Hmac<Sha256> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(transformed_key.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(b"keepass-rs key check");
+    mac.update(kdf_seed);
+    mac
+}
+
+impl PartialEq for KeyCheck {
+    fn eq(&self, _: &KeyCheck) -> bool {
+        true
+    }
+}
+
+impl Eq for KeyCheck {}
+
+/// Errors while changing the master key of a database with `Database::change_key`
+#[derive(Debug, Error)]
+pub enum ChangeKeyError {
+    /// The old key is not the key of the database
+    #[error("The old key is incorrect")]
+    IncorrectKey,
+
+    /// The database has no key to check the old key against, e.g. because it was created rather
+    /// than read from a KDBX file. See `Database::change_key_unchecked`.
+    #[error("The old key cannot be checked")]
+    Unverifiable,
+
+    /// The new key has no password, keyfile or other component
+    #[error("The new key is empty")]
+    EmptyKey,
+
+    /// One of the keys could not be used, e.g. because of a keyfile that cannot be read
+    #[error(transparent)]
+    Key(#[from] DatabaseKeyError),
+
+    /// The old key could not be checked
+    #[error(transparent)]
+    Check(DatabaseOpenError),
+}
+
+impl Database {
+    /// Change the master key of the database from `old_key` to `new_key`, returning the key to save
+    /// the database with from now on.
+    ///
+    /// `old_key` has to be the key of the database: the key of the KDBX file it was read from, or
+    /// the key it was last changed to. Databases that have neither, like new databases or those
+    /// read from KDB files, fail with `ChangeKeyError::Unverifiable`, see
+    /// `Database::change_key_unchecked`. Checking the key is cheap if it was used to open the
+    /// database, since transformed keys are cached.
+    ///
+    /// `Meta::master_key_changed` is set to now. The new key is transformed with the KDF settings
+    /// of the database and a fresh seed, so that later calls can check it. The returned key carries
+    /// this transformed key, so that the next save does not run the KDF again, while any
+    /// transformed key `new_key` was given is dropped.
+    pub fn change_key(
+        &mut self,
+        old_key: &DatabaseKey,
+        new_key: DatabaseKey,
+    ) -> Result<DatabaseKey, ChangeKeyError> {
+        match self.key_check.verify(old_key) {
+            Ok(Some(true)) => {}
+            Ok(None) => return Err(ChangeKeyError::Unverifiable),
+            Ok(Some(false)) | Err(DatabaseOpenError::InvalidCredentials) => {
+                return Err(ChangeKeyError::IncorrectKey)
+            }
+            Err(DatabaseOpenError::Key(e)) => return Err(e.into()),
+            Err(e) => return Err(ChangeKeyError::Check(e)),
+        }
+
+        self.change_key_unchecked(new_key)
+    }
+
+    /// Set the master key of the database to `new_key` without checking the old key, e.g. for a
+    /// new database. Otherwise like `Database::change_key`.
+    pub fn change_key_unchecked(&mut self, new_key: DatabaseKey) -> Result<DatabaseKey, ChangeKeyError> {
+        let new_key = new_key.without_transformed_key();
+        if new_key.is_empty() {
+            return Err(ChangeKeyError::EmptyKey);
+        }
+
+        let (key_check, transformed_key) = KeyCheck::derive(
+            &new_key,
+            &self.config.kdf_config,
+            &self.config.argon2_secret_parameters,
+        )?;
+        self.meta.master_key_changed = Some(Times::now());
+        self.key_check = key_check;
+        Ok(new_key.with_transformed_key(transformed_key))
+    }
+
+    /// Change the key derivation function of the database and its parameters, setting
+    /// `Meta::settings_changed` to now.
+    ///
+    /// The next save derives the key with the new settings and a fresh seed: a transformed key
+    /// given to the save is only used while its KDF settings match those of the database, see
+    /// `TransformedKey`.
+    pub fn set_kdf(&mut self, kdf_config: KdfConfig) {
+        self.config.kdf_config = kdf_config;
+        self.meta.settings_changed = Some(Times::now());
+    }
+}
+
+#[cfg(test)]
+mod rekey_tests {
+    use super::{ChangeKeyError, KeyCheck};
+    use crate::{
+        config::KdfConfig,
+        db::{with_clock, ManualClock},
+        Database, DatabaseKey,
+    };
+
+    const AES_DB: &str = "tests/resources/test_db_kdbx4_with_password_aes.kdbx";
+
+    #[test]
+    fn test_key_check() {
+        let data = std::fs::read(AES_DB).unwrap();
+        let check = KeyCheck::of(&data);
+        assert!(check.is_known());
+        assert_eq!(
+            check
+                .verify(&DatabaseKey::new().with_password("demopass"))
+                .unwrap(),
+            Some(true)
+        );
+        assert_eq!(
+            check.verify(&DatabaseKey::new().with_password("wrong")).unwrap(),
+            Some(false)
+        );
+
+        // KDBX 3 files are checked by decrypting the start of their payload
+        let kdbx3 = std::fs::read("tests/resources/test_db_with_password.kdbx").unwrap();
+        let check = KeyCheck::of(&kdbx3);
+        assert_eq!(
+            check
+                .verify(&DatabaseKey::new().with_password("demopass"))
+                .unwrap(),
+            Some(true)
+        );
+        assert_eq!(
+            check.verify(&DatabaseKey::new().with_password("wrong")).unwrap(),
+            Some(false)
+        );
+
+        let kdb = std::fs::read("tests/resources/test_db_kdb_with_password.kdb").unwrap();
+        assert!(!KeyCheck::of(&kdb).is_known());
+        assert!(!Database::new(Default::default()).key_check.is_known());
+
+        // streamed and parsed databases both remember their file
+        let key = DatabaseKey::new().with_password("demopass");
+        let opened = Database::open(&mut data.as_slice(), key.clone()).unwrap();
+        assert_eq!(opened.key_check.verify(&key).unwrap(), Some(true));
+        let (parsed, _) = Database::parse_transformed(&data, key.clone()).unwrap();
+        assert_eq!(parsed.key_check.verify(&key).unwrap(), Some(true));
+    }
+
+    #[test]
+    fn test_change_key() {
+        let data = std::fs::read(AES_DB).unwrap();
+        let old_key = DatabaseKey::new().with_password("demopass");
+        let mut db = Database::parse(&data, old_key.clone()).unwrap();
+        let new_key = DatabaseKey::new().with_password("new");
+
+        assert!(matches!(
+            db.change_key(&DatabaseKey::new().with_password("wrong"), new_key.clone()),
+            Err(ChangeKeyError::IncorrectKey)
+        ));
+        assert!(matches!(
+            db.change_key(&old_key, DatabaseKey::new()),
+            Err(ChangeKeyError::EmptyKey)
+        ));
+        let unchanged = db.meta.master_key_changed;
+
+        let now = "2024-02-03T04:05:06".parse().unwrap();
+        let clock = ManualClock::new(now);
+        let returned = with_clock(clock, || db.change_key(&old_key, new_key.clone())).unwrap();
+        assert_eq!(returned.without_transformed_key(), new_key);
+        assert_ne!(db.meta.master_key_changed, unchanged);
+        assert_eq!(db.meta.master_key_changed, Some(now));
+
+        // from now on, the new key is checked instead of the old one
+        assert_eq!(db.key_check.verify(&new_key).unwrap(), Some(true));
+        assert!(matches!(
+            db.change_key(&old_key, DatabaseKey::new().with_password("newer")),
+            Err(ChangeKeyError::IncorrectKey)
+        ));
+        let newer_key = DatabaseKey::new().with_password("newer");
+        db.change_key(&new_key, newer_key.clone()).unwrap();
+        assert_eq!(db.key_check.verify(&new_key).unwrap(), Some(false));
+        assert_eq!(db.key_check.verify(&newer_key).unwrap(), Some(true));
+
+        // a key only holding a transformed key is empty once that is dropped
+        let (_, transformed_key) = Database::parse_transformed(&data, old_key.clone()).unwrap();
+        assert!(matches!(
+            db.change_key(
+                &newer_key,
+                DatabaseKey::new().with_transformed_key(transformed_key)
+            ),
+            Err(ChangeKeyError::EmptyKey)
+        ));
+    }
+
+    #[test]
+    fn test_change_key_of_new_database() {
+        let mut db = Database::new(Default::default());
+        db.config.kdf_config = KdfConfig::Aes { rounds: 10 };
+        let key = DatabaseKey::new().with_password("first");
+
+        assert!(matches!(
+            db.change_key(&DatabaseKey::new(), key.clone()),
+            Err(ChangeKeyError::Unverifiable)
+        ));
+        assert!(db.meta.master_key_changed.is_none());
+
+        db.change_key_unchecked(key.clone()).unwrap();
+        assert!(db.meta.master_key_changed.is_some());
+        assert!(matches!(
+            db.change_key(&DatabaseKey::new().with_password("wrong"), key.clone()),
+            Err(ChangeKeyError::IncorrectKey)
+        ));
+        db.change_key(&key, DatabaseKey::new().with_password("second"))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_set_kdf() {
+        let mut db = Database::new(Default::default());
+        let now = "2024-02-03T04:05:06".parse().unwrap();
+        let clock = ManualClock::new(now);
+        with_clock(clock, || db.set_kdf(KdfConfig::Aes { rounds: 1000 }));
+        assert_eq!(db.config.kdf_config, KdfConfig::Aes { rounds: 1000 });
+        assert_eq!(db.meta.settings_changed, Some(now));
+    }
+
+    #[cfg(feature = "save_kdbx4")]
+    #[test]
+    fn test_save_after_rekey() {
+        let data = std::fs::read(AES_DB).unwrap();
+        let old_key = DatabaseKey::new().with_password("demopass");
+        let (mut db, transformed_key) = Database::parse_transformed(&data, old_key.clone()).unwrap();
+        let new_key = db
+            .change_key(
+                &old_key,
+                DatabaseKey::new()
+                    .with_password("new")
+                    .with_transformed_key(transformed_key.clone()),
+            )
+            .unwrap();
+
+        // the transformed key of the old key is not saved with, even though the KDF is the same
+        let mut saved = Vec::new();
+        db.save(&mut saved, new_key.clone()).unwrap();
+        assert!(!Database::verify_key(&mut saved.as_slice(), old_key.clone()).unwrap());
+        let reopened = Database::parse(&saved, new_key.clone()).unwrap();
+        assert_eq!(reopened.meta.master_key_changed, db.meta.master_key_changed);
+        assert_eq!(reopened.root, db.root);
+        assert!(reopened.key_check.verify(&new_key).unwrap().unwrap());
+
+        db.set_kdf(KdfConfig::Aes { rounds: 1234 });
+        let mut saved = Vec::new();
+        db.save(&mut saved, new_key.clone()).unwrap();
+        let reopened = Database::parse(&saved, new_key).unwrap();
+        assert_eq!(reopened.config.kdf_config, KdfConfig::Aes { rounds: 1234 });
+        assert_eq!(reopened.meta.settings_changed, db.meta.settings_changed);
+    }
+}
@@ -0,0 +1,457 @@
+//! Named field schemas for structured entries
+//!
+//! A `FieldSchema` describes a type of entry, e.g. a credit card with a number, an expiry date and
+//! a protected CVV. Schemas are stored in the custom data of the metadata, so every application
+//! opening the database sees the same types. `Entry::apply_schema` adds the fields of a schema to
+//! an entry and remembers the schema, and `Database::validate_schemas` checks entries against the
+//! schemas applied to them.
+//!
+//! ```
+//! use keepass::{db::{Entry, FieldKind, FieldSchema}, Database};
+//!
+//! let mut db = Database::new(Default::default());
+//! let card = FieldSchema::new("Credit Card")
+//!     .field("Number", FieldKind::Number, true)
+//!     .field("Expiry", FieldKind::Date, true)
+//!     .protected_field("CVV", FieldKind::Number, false);
+//! db.meta.set_schema(&card);
+//!
+//! let mut entry = Entry::new();
+//! entry.apply_schema(&db.meta, "Credit Card")?;
+//! assert_eq!(entry.schema_name(), Some("Credit Card"));
+//! db.root.add_child(entry);
+//!
+//! // the number and expiry date are still missing
+//! assert_eq!(db.validate_schemas().len(), 2);
+//! # Ok::<(), keepass::db::SchemaError>(())
+//! ```
+
+use thiserror::Error;
+
+use crate::db::{
+    with_audit_context, CustomDataItem, Database, Entry, Group, Meta, Times, ValidationRule, Value, Violation,
+};
+
+/// Prefix of the keys of the metadata custom data holding schemas, followed by the schema name
+pub const SCHEMA_KEY_PREFIX: &str = "KPRS_SCHEMA/";
+
+/// Key of the entry custom data holding the name of the schema applied to the entry
+pub const ENTRY_SCHEMA_KEY: &str = "KPRS_SCHEMA";
+
+/// Errors while loading schemas
+#[derive(Debug, Error)]
+pub enum SchemaError {
+    #[error("No schema named {0}")]
+    NotFound(String),
+
+    #[error("Malformed schema {schema}: {line:?}")]
+    Malformed { schema: String, line: String },
+}
+
+/// What values a schema field takes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serialization", derive(serde::Serialize))]
+pub enum FieldKind {
+    /// Any text
+    Text,
+
+    /// Digits, optionally grouped with spaces or dashes, e.g. card or account numbers
+    Number,
+
+    /// A date like `2030-12-31`, or a month like `2030-12` or `12/30`
+    Date,
+
+    /// An absolute URL
+    Url,
+
+    /// An email address
+    Email,
+}
+
+impl FieldKind {
+    fn name(&self) -> &'static str {
+        match self {
+            FieldKind::Text => "text",
+            FieldKind::Number => "number",
+            FieldKind::Date => "date",
+            FieldKind::Url => "url",
+            FieldKind::Email => "email",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        [
+            FieldKind::Text,
+            FieldKind::Number,
+            FieldKind::Date,
+            FieldKind::Url,
+            FieldKind::Email,
+        ]
+        .iter()
+        .copied()
+        .find(|kind| kind.name() == name)
+    }
+
+    fn rule(&self, name: &str, field: &str) -> Option<ValidationRule> {
+        match self {
+            FieldKind::Text => None,
+            FieldKind::Number => Some(ValidationRule::matches(name, field, "a number", is_number)),
+            FieldKind::Date => Some(ValidationRule::matches(name, field, "a date", is_date)),
+            FieldKind::Url => Some(ValidationRule::url(name, field)),
+            FieldKind::Email => Some(ValidationRule::matches(name, field, "an email address", is_email)),
+        }
+    }
+}
+
+/// A field of a schema
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serialization", derive(serde::Serialize))]
+pub struct SchemaField {
+    pub name: String,
+    pub kind: FieldKind,
+
+    /// Whether the value is protected in memory and in the file
+    pub protected: bool,
+
+    /// Whether entries of the schema must have a value for the field
+    pub required: bool,
+}
+
+/// A named type of entry, see the module documentation
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serialization", derive(serde::Serialize))]
+pub struct FieldSchema {
+    pub name: String,
+    pub fields: Vec<SchemaField>,
+}
+
+impl FieldSchema {
+    pub fn new(name: &str) -> Self {
+        FieldSchema {
+            name: name.to_string(),
+            fields: Vec::new(),
+        }
+    }
+
+    /// Add an unprotected field
+    pub fn field(self, name: &str, kind: FieldKind, required: bool) -> Self {
+        self.with_field(name, kind, required, false)
+    }
+
+    /// Add a protected field
+    pub fn protected_field(self, name: &str, kind: FieldKind, required: bool) -> Self {
+        self.with_field(name, kind, required, true)
+    }
+
+    fn with_field(mut self, name: &str, kind: FieldKind, required: bool, protected: bool) -> Self {
+        self.fields.push(SchemaField {
+            name: name.to_string(),
+            kind,
+            protected,
+            required,
+        });
+        self
+    }
+
+    /// Validation rules for the fields of the schema, named `<schema>:<field>`
+    pub fn rules(&self) -> Vec<ValidationRule> {
+        let mut rules = Vec::new();
+        for field in &self.fields {
+            let name = format!("{}:{}", self.name, field.name);
+            if field.required {
+                rules.push(ValidationRule::required(&name, &field.name));
+            }
+            rules.extend(field.kind.rule(&name, &field.name));
+        }
+        rules
+    }
+
+    /// Add the missing fields of the schema to an entry and protect the fields that should be
+    /// protected. Existing values are kept, and protected values are never unprotected.
+    pub fn apply(&self, entry: &mut Entry) {
+        for field in &self.fields {
+            let value = entry
+                .fields
+                .entry(field.name.clone())
+                .or_insert_with(|| Value::Unprotected(String::new()));
+
+            if field.protected {
+                if let Value::Unprotected(v) = value {
+                    *value = Value::Protected(v.as_bytes().into());
+                }
+            }
+        }
+
+        entry.custom_data.items.insert(
+            ENTRY_SCHEMA_KEY.to_string(),
+            CustomDataItem {
+                value: Some(Value::Unprotected(self.name.clone())),
+                last_modification_time: Some(Times::now()),
+            },
+        );
+    }
+
+    fn to_metadata(&self) -> String {
+        self.fields
+            .iter()
+            .map(|field| {
+                let mut line = format!("{}={}", field.name, field.kind.name());
+                if field.required {
+                    line.push_str(";required");
+                }
+                if field.protected {
+                    line.push_str(";protected");
+                }
+                line
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    fn from_metadata(name: &str, s: &str) -> Result<Self, SchemaError> {
+        let malformed = |line: &str| SchemaError::Malformed {
+            schema: name.to_string(),
+            line: line.to_string(),
+        };
+
+        let mut schema = FieldSchema::new(name);
+        for line in s.lines() {
+            // field names may contain `=`, the kind and flags never do
+            let (field, spec) = line.rsplit_once('=').ok_or_else(|| malformed(line))?;
+            let mut parts = spec.split(';');
+            let kind = parts
+                .next()
+                .and_then(FieldKind::from_name)
+                .ok_or_else(|| malformed(line))?;
+
+            let mut required = false;
+            let mut protected = false;
+            for flag in parts {
+                match flag {
+                    "required" => required = true,
+                    "protected" => protected = true,
+                    _ => return Err(malformed(line)),
+                }
+            }
+            schema = schema.with_field(field, kind, required, protected);
+        }
+        Ok(schema)
+    }
+}
+
+impl Meta {
+    /// The names of all schemas stored in the database, sorted
+    pub fn schema_names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self
+            .custom_data
+            .items
+            .keys()
+            .filter_map(|key| key.strip_prefix(SCHEMA_KEY_PREFIX))
+            .collect();
+        names.sort_unstable();
+        names
+    }
+
+    /// Load a schema by name
+    pub fn schema(&self, name: &str) -> Result<FieldSchema, SchemaError> {
+        let key = format!("{}{}", SCHEMA_KEY_PREFIX, name);
+        match self
+            .custom_data
+            .items
+            .get(&key)
+            .and_then(|item| item.value.as_ref())
+        {
+            Some(Value::Unprotected(s)) => FieldSchema::from_metadata(name, s),
+            _ => Err(SchemaError::NotFound(name.to_string())),
+        }
+    }
+
+    /// Store a schema, replacing a schema with the same name
+    pub fn set_schema(&mut self, schema: &FieldSchema) {
+        self.custom_data.items.insert(
+            format!("{}{}", SCHEMA_KEY_PREFIX, schema.name),
+            CustomDataItem {
+                value: Some(Value::Unprotected(schema.to_metadata())),
+                last_modification_time: Some(Times::now()),
+            },
+        );
+    }
+
+    /// Remove a schema, returning whether it existed. Entries keep their fields.
+    pub fn remove_schema(&mut self, name: &str) -> bool {
+        self.custom_data
+            .items
+            .remove(&format!("{}{}", SCHEMA_KEY_PREFIX, name))
+            .is_some()
+    }
+}
+
+impl Entry {
+    /// Apply a schema stored in the metadata to the entry, see `FieldSchema::apply`
+    pub fn apply_schema(&mut self, meta: &Meta, name: &str) -> Result<(), SchemaError> {
+        meta.schema(name)?.apply(self);
+        Ok(())
+    }
+
+    /// The name of the schema applied to the entry
+    pub fn schema_name(&self) -> Option<&str> {
+        match self.custom_data.items.get(ENTRY_SCHEMA_KEY)?.value.as_ref()? {
+            Value::Unprotected(name) => Some(name),
+            _ => None,
+        }
+    }
+}
+
+impl Database {
+    /// Check every entry with a schema against the rules of its schema, see `FieldSchema::rules`.
+    /// Entries whose schema was removed or is malformed are not checked.
+    pub fn validate_schemas(&self) -> Vec<Violation> {
+        let mut out = Vec::new();
+        with_audit_context("schema validation", || {
+            validate_group(&self.root, &self.meta, &mut out)
+        });
+        out
+    }
+}
+
+fn validate_group(group: &Group, meta: &Meta, out: &mut Vec<Violation>) {
+    for entry in group.entries() {
+        if let Some(Ok(schema)) = entry.schema_name().map(|name| meta.schema(name)) {
+            out.extend(schema.rules().iter().filter_map(|rule| rule.check(entry)));
+        }
+    }
+    for child in group.groups() {
+        validate_group(child, meta, out);
+    }
+}
+
+fn is_number(value: &str) -> bool {
+    value.chars().any(|c| c.is_ascii_digit())
+        && value.chars().all(|c| c.is_ascii_digit() || c == ' ' || c == '-')
+}
+
+fn is_date(value: &str) -> bool {
+    use chrono::NaiveDate;
+
+    let month = |year: &str, month: &str| {
+        let year: i32 = match year.len() {
+            2 => year.parse::<i32>().map(|y| 2000 + y),
+            4 => year.parse(),
+            _ => return false,
+        }
+        .unwrap_or(0);
+        month
+            .parse()
+            .is_ok_and(|month| year > 0 && NaiveDate::from_ymd_opt(year, month, 1).is_some())
+    };
+
+    if NaiveDate::parse_from_str(value, "%Y-%m-%d").is_ok() {
+        return true;
+    }
+    match (value.split_once('-'), value.split_once('/')) {
+        (Some((y, m)), None) => y.len() == 4 && month(y, m),
+        (None, Some((m, y))) => m.len() == 2 && month(y, m),
+        _ => false,
+    }
+}
+
+fn is_email(value: &str) -> bool {
+    match value.rsplit_once('@') {
+        Some((local, domain)) => {
+            !local.is_empty()
+                && domain.contains('.')
+                && !domain.starts_with('.')
+                && !domain.ends_with('.')
+                && !value.contains(char::is_whitespace)
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod schema_tests {
+    use crate::db::{Database, Entry, Value, ViolationKind};
+
+    use super::{FieldKind, FieldSchema, SchemaError};
+
+    fn card() -> FieldSchema {
+        FieldSchema::new("Credit Card")
+            .field("Number", FieldKind::Number, true)
+            .field("Expiry", FieldKind::Date, false)
+            .protected_field("CVV", FieldKind::Number, true)
+            .field("Key=Value", FieldKind::Text, false)
+    }
+
+    #[test]
+    fn test_schema_roundtrip() {
+        let mut db = Database::new(Default::default());
+        db.meta.set_schema(&card());
+        db.meta
+            .set_schema(&FieldSchema::new("Login").field("Mail", FieldKind::Email, true));
+
+        assert_eq!(db.meta.schema_names(), vec!["Credit Card", "Login"]);
+        assert_eq!(db.meta.schema("Credit Card").unwrap(), card());
+        assert!(matches!(
+            db.meta.schema("Passport"),
+            Err(SchemaError::NotFound(_))
+        ));
+
+        assert!(db.meta.remove_schema("Login"));
+        assert!(!db.meta.remove_schema("Login"));
+        assert_eq!(db.meta.schema_names(), vec!["Credit Card"]);
+    }
+
+    #[test]
+    fn test_apply_and_validate() {
+        let mut db = Database::new(Default::default());
+        db.meta.set_schema(&card());
+
+        let mut entry = Entry::new();
+        entry
+            .fields
+            .insert("CVV".to_string(), Value::Unprotected("123".to_string()));
+        entry
+            .fields
+            .insert("Expiry".to_string(), Value::Unprotected("13/30".to_string()));
+        entry.apply_schema(&db.meta, "Credit Card").unwrap();
+        assert!(matches!(entry.fields["CVV"], Value::Protected(_)));
+        assert_eq!(entry.get("CVV"), Some("123"));
+        assert_eq!(entry.get("Number"), Some(""));
+        let uuid = entry.uuid;
+        db.root.add_child(entry);
+
+        let violations = db.validate_schemas();
+        assert_eq!(violations.len(), 2);
+        assert_eq!(violations[0].entry, uuid);
+        assert_eq!(violations[0].rule, "Credit Card:Number");
+        assert_eq!(violations[0].kind, ViolationKind::Missing);
+        assert_eq!(violations[1].field, "Expiry");
+
+        let entry = &mut db.root.entries_mut()[0];
+        entry.fields.insert(
+            "Number".to_string(),
+            Value::Unprotected("4111 1111 1111 1111".to_string()),
+        );
+        entry
+            .fields
+            .insert("Expiry".to_string(), Value::Unprotected("12/30".to_string()));
+        assert!(db.validate_schemas().is_empty());
+
+        // entries of removed schemas are not checked
+        db.root.entries_mut()[0].fields.remove("Number");
+        db.meta.remove_schema("Credit Card");
+        assert!(db.validate_schemas().is_empty());
+    }
+
+    #[test]
+    fn test_field_kinds() {
+        assert!(super::is_date("2030-02-28"));
+        assert!(super::is_date("2030-12"));
+        assert!(super::is_date("01/2031"));
+        assert!(!super::is_date("2030-02-30"));
+        assert!(!super::is_date("1/30"));
+        assert!(super::is_email("jdoe@example.com"));
+        assert!(!super::is_email("jdoe@localhost"));
+        assert!(!super::is_number("--"));
+    }
+}
@@ -0,0 +1,256 @@
+//! Serialization of the data model with serde
+//!
+//! With the `serialization` feature, `Database` and everything it contains can be serialized and
+//! deserialized with any serde format. Protected values like passwords are left out unless the
+//! serialization happens inside `reveal_protected_values`:
+//!
+//! ```
+//! use keepass::db::{reveal_protected_values, Database, Entry, Value};
+//!
+//! let mut db = Database::new(Default::default());
+//! let mut entry = Entry::new();
+//! entry.fields.insert("Password".to_string(), Value::Protected("hunter2".as_bytes().into()));
+//! db.root.add_child(entry);
+//!
+//! let hidden = serde_json::to_string(&db)?;
+//! assert!(!hidden.contains("hunter2"));
+//!
+//! let revealed = reveal_protected_values(|| serde_json::to_string(&db))?;
+//! let copy: Database = serde_json::from_str(&revealed)?;
+//! assert_eq!(copy.root, db.root);
+//! # Ok::<(), serde_json::Error>(())
+//! ```
+//!
+//! Bytes are serialized as bytes, unprotected values as strings and protected values as a map
+//! with the single key `protected`, whose value is `null` when the value is not revealed. Such
+//! hidden values are deserialized as empty protected values.
+
+use std::{cell::Cell, fmt};
+
+use serde::{
+    de::{self, MapAccess, SeqAccess, Visitor},
+    ser::SerializeMap,
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+
+use crate::db::Value;
+
+/// The key of the map that protected values are serialized as
+const PROTECTED_KEY: &str = "protected";
+
+thread_local! {
+    static REVEAL_DEPTH: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Run `f` with protected values revealed in everything serialized on this thread. Calls can
+/// be nested. Other threads keep leaving protected values out.
+pub fn reveal_protected_values<R>(f: impl FnOnce() -> R) -> R {
+    struct HideOnDrop;
+
+    impl Drop for HideOnDrop {
+        fn drop(&mut self) {
+            REVEAL_DEPTH.with(|d| d.set(d.get() - 1));
+        }
+    }
+
+    REVEAL_DEPTH.with(|d| d.set(d.get() + 1));
+    let _hide = HideOnDrop;
+    f()
+}
+
+fn revealing() -> bool {
+    REVEAL_DEPTH.with(|d| d.get() > 0)
+}
+
+impl Serialize for Value {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match self {
+            Value::Bytes(b) => serializer.serialize_bytes(b),
+            Value::Unprotected(u) => serializer.serialize_str(u),
+            Value::Protected(p) => {
+                let revealed = if revealing() {
+                    Some(String::from_utf8_lossy(&p.decrypt()).into_owned())
+                } else {
+                    None
+                };
+                let mut map = serializer.serialize_map(Some(1))?;
+                map.serialize_entry(PROTECTED_KEY, &revealed)?;
+                map.end()
+            }
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Value {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_any(ValueVisitor)
+    }
+}
+
+struct ValueVisitor;
+
+impl<'de> Visitor<'de> for ValueVisitor {
+    type Value = Value;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a string, bytes or a map with a protected value")
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Value, E> {
+        Ok(Value::Unprotected(v.to_string()))
+    }
+
+    fn visit_string<E: de::Error>(self, v: String) -> Result<Value, E> {
+        Ok(Value::Unprotected(v))
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Value, E> {
+        Ok(Value::Bytes(v.to_vec()))
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<Value, E> {
+        Ok(Value::Bytes(v))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Value, A::Error> {
+        let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(b) = seq.next_element()? {
+            bytes.push(b);
+        }
+        Ok(Value::Bytes(bytes))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Value, A::Error> {
+        let mut protected: Option<Option<String>> = None;
+        while let Some(key) = map.next_key::<String>()? {
+            if key != PROTECTED_KEY {
+                return Err(de::Error::unknown_field(&key, &[PROTECTED_KEY]));
+            }
+            if protected.is_some() {
+                return Err(de::Error::duplicate_field(PROTECTED_KEY));
+            }
+            protected = Some(map.next_value()?);
+        }
+        let protected = protected.ok_or_else(|| de::Error::missing_field(PROTECTED_KEY))?;
+        Ok(Value::Protected(protected.unwrap_or_default().into()))
+    }
+}
+
+/// Serializes protected values like `Value::Protected`, for use with `#[serde(with)]`
+pub(crate) mod protected_values {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use crate::db::{ProtectedValue, Value};
+
+    pub(crate) fn serialize<S: Serializer>(
+        values: &[ProtectedValue],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let values: Vec<Value> = values.iter().cloned().map(Value::Protected).collect();
+        values.serialize(serializer)
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<ProtectedValue>, D::Error> {
+        let values = Vec::<Value>::deserialize(deserializer)?;
+        Ok(values
+            .into_iter()
+            .map(|value| match value {
+                Value::Protected(value) => value,
+                Value::Unprotected(value) => value.into(),
+                Value::Bytes(value) => value.into(),
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod serialization_tests {
+    use super::reveal_protected_values;
+    use crate::{
+        config::{DatabaseConfig, KdfConfig},
+        db::{Color, Entry, ProtectedValue, UnknownElement, Value},
+        Database,
+    };
+
+    #[test]
+    fn test_value_serialization() {
+        assert_eq!(
+            serde_json::to_string(&Value::Bytes(vec![65, 66, 67])).unwrap(),
+            "[65,66,67]"
+        );
+        assert_eq!(
+            serde_json::to_string(&Value::Unprotected("ABC".to_string())).unwrap(),
+            "\"ABC\""
+        );
+
+        let protected = Value::Protected(ProtectedValue::new("ABC".as_bytes().to_vec()));
+        assert_eq!(serde_json::to_string(&protected).unwrap(), "{\"protected\":null}");
+        let revealed = reveal_protected_values(|| serde_json::to_string(&protected).unwrap());
+        assert_eq!(revealed, "{\"protected\":\"ABC\"}");
+        // the scope ends with the closure
+        assert_eq!(serde_json::to_string(&protected).unwrap(), "{\"protected\":null}");
+
+        for json in ["[65,66,67]", "\"ABC\"", "{\"protected\":\"ABC\"}"] {
+            let value: Value = serde_json::from_str(json).unwrap();
+            let again = reveal_protected_values(|| serde_json::to_string(&value).unwrap());
+            assert_eq!(again, json);
+        }
+        assert_eq!(
+            serde_json::from_str::<Value>("{\"protected\":null}").unwrap(),
+            Value::Protected(ProtectedValue::from(""))
+        );
+        assert!(serde_json::from_str::<Value>("{\"secret\":\"ABC\"}").is_err());
+    }
+
+    #[test]
+    fn test_database_roundtrip() {
+        let mut db = Database::new(DatabaseConfig {
+            kdf_config: KdfConfig::Argon2id {
+                iterations: 2,
+                memory: 65536,
+                parallelism: 2,
+                version: argon2::Version::Version13,
+            },
+            ..Default::default()
+        });
+        db.meta.color = Some(Color { r: 1, g: 2, b: 255 });
+
+        let mut entry = Entry::new();
+        entry
+            .fields
+            .insert("Title".to_string(), Value::Unprotected("Demo".to_string()));
+        entry.fields.insert(
+            "Password".to_string(),
+            Value::Protected("hunter2".as_bytes().into()),
+        );
+        entry.unknown_elements.push(UnknownElement {
+            name: "PluginSecret".to_string(),
+            xml: "<PluginSecret Protected=\"True\"></PluginSecret>".to_string(),
+            protected_values: vec!["swordfish".into()],
+        });
+        entry.update_history();
+        db.root.add_child(entry);
+
+        let json = reveal_protected_values(|| serde_json::to_string(&db).unwrap());
+        let copy: Database = serde_json::from_str(&json).unwrap();
+        assert_eq!(copy.config, db.config);
+        assert_eq!(copy.meta, db.meta);
+        assert_eq!(copy.root, db.root);
+
+        let hidden = serde_json::to_string(&db).unwrap();
+        assert!(!hidden.contains("hunter2"));
+        assert!(!hidden.contains("swordfish"));
+        let copy: Database = serde_json::from_str(&hidden).unwrap();
+        let entry = copy.root.entries()[0];
+        assert_eq!(entry.get_title(), Some("Demo"));
+        assert_eq!(entry.get_password(), Some(""));
+    }
+}
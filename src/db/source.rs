@@ -0,0 +1,133 @@
+//! Opening whatever KeePass file a user has
+//!
+//! `Database::open_any` accepts encrypted databases of every supported version as well as the
+//! unencrypted XML exports written by KeePass and KeePassXC, and reports which of them it found.
+//! Frontends can use the `SourceFormat` to e.g. warn before overwriting a KDB file with KDBX4, or
+//! to suggest encrypting an imported XML export.
+
+use crate::{
+    db::{plain_xml::parse_xml_export, Database},
+    error::{DatabaseIntegrityError, DatabaseOpenError},
+    format::DatabaseVersion,
+    key::DatabaseKey,
+};
+
+/// The kind of file a database was read from
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serialization", derive(serde::Serialize))]
+pub enum SourceFormat {
+    /// An encrypted KDB or KDBX file
+    Encrypted(DatabaseVersion),
+
+    /// An unencrypted XML export
+    Xml,
+}
+
+impl SourceFormat {
+    /// Detect the format of a file from its first bytes, without decrypting it
+    pub fn sniff(data: &[u8]) -> Result<SourceFormat, DatabaseIntegrityError> {
+        if looks_like_xml(data) {
+            return Ok(SourceFormat::Xml);
+        }
+        DatabaseVersion::parse(data).map(SourceFormat::Encrypted)
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        matches!(self, SourceFormat::Encrypted(_))
+    }
+}
+
+impl Database {
+    /// Read a database from any supported file format. The key is not used for XML exports.
+    pub fn open_any(
+        source: &mut dyn std::io::Read,
+        key: DatabaseKey,
+    ) -> Result<(Database, SourceFormat), DatabaseOpenError> {
+        let mut data = Vec::new();
+        source.read_to_end(&mut data)?;
+
+        Database::parse_any(data.as_ref(), key)
+    }
+
+    /// Parse a database from any supported file format, see `Database::open_any`
+    pub fn parse_any(data: &[u8], key: DatabaseKey) -> Result<(Database, SourceFormat), DatabaseOpenError> {
+        let format = SourceFormat::sniff(data)?;
+
+        let db = match format {
+            SourceFormat::Encrypted(_) => Database::parse(data, key)?,
+            SourceFormat::Xml => parse_xml_export(data)?,
+        };
+
+        Ok((db, format))
+    }
+}
+
+fn looks_like_xml(data: &[u8]) -> bool {
+    let data = data.strip_prefix(b"\xef\xbb\xbf".as_ref()).unwrap_or(data);
+    let start = data
+        .iter()
+        .position(|b| !b.is_ascii_whitespace())
+        .unwrap_or(data.len());
+    let data = &data[start..];
+    data.starts_with(b"<?xml") || data.starts_with(b"<KeePassFile")
+}
+
+#[cfg(test)]
+mod source_tests {
+    use crate::{
+        db::{Database, Value},
+        format::DatabaseVersion,
+        key::DatabaseKey,
+    };
+
+    use super::SourceFormat;
+
+    const EXPORT: &str = "\u{feff}<?xml version=\"1.0\" encoding=\"utf-8\" standalone=\"yes\"?>
+<KeePassFile>
+\t<Meta><DatabaseName>Exported</DatabaseName></Meta>
+\t<Root>
+\t\t<Group>
+\t\t\t<UUID>AAAAAAAAAAAAAAAAAAAAAQ==</UUID>
+\t\t\t<Name>Root</Name>
+\t\t\t<Entry>
+\t\t\t\t<UUID>AAAAAAAAAAAAAAAAAAAAAg==</UUID>
+\t\t\t\t<String><Key>Title</Key><Value>Mail</Value></String>
+\t\t\t\t<String><Key>Password</Key><Value ProtectInMemory=\"True\">hunter2</Value></String>
+\t\t\t</Entry>
+\t\t</Group>
+\t</Root>
+</KeePassFile>";
+
+    #[test]
+    fn test_open_any() {
+        let data = std::fs::read("tests/resources/test_db_with_password.kdbx").unwrap();
+        let (db, format) = Database::parse_any(&data, DatabaseKey::new().with_password("demopass")).unwrap();
+        assert_eq!(format, SourceFormat::Encrypted(DatabaseVersion::KDB3(1)));
+        assert_eq!(
+            db.root.name,
+            Database::parse(&data, DatabaseKey::new().with_password("demopass"))
+                .unwrap()
+                .root
+                .name
+        );
+
+        let data = std::fs::read("tests/resources/test_db_kdb_with_password.kdb").unwrap();
+        let (_, format) = Database::parse_any(&data, DatabaseKey::new().with_password("foobar")).unwrap();
+        assert!(matches!(format, SourceFormat::Encrypted(DatabaseVersion::KDB(_))));
+
+        let (db, format) = Database::open_any(&mut EXPORT.as_bytes(), DatabaseKey::new()).unwrap();
+        assert_eq!(format, SourceFormat::Xml);
+        assert!(!format.is_encrypted());
+        assert_eq!(db.meta.database_name.as_deref(), Some("Exported"));
+        let entry = db.root.entries()[0];
+        assert_eq!(entry.get_title(), Some("Mail"));
+        assert!(matches!(entry.fields["Password"], Value::Protected(_)));
+        assert_eq!(entry.get_password(), Some("hunter2"));
+
+        assert_eq!(
+            SourceFormat::sniff(b"\n  <KeePassFile/>").unwrap(),
+            SourceFormat::Xml
+        );
+        assert!(SourceFormat::sniff(b"not a database").is_err());
+    }
+}
@@ -0,0 +1,166 @@
+//! Managing and querying tags
+//!
+//! KeePass stores the tags of an entry, and since KDBX 4.1 those of a group, as one string
+//! separated by `;` or `,`. Tags compare without regard to case, so that `Work` and `work` are the
+//! same tag. `add_tag` trims the tags it is given and splits them at the separators, so a tag
+//! never contains one and always survives saving and opening the database:
+//!
+//! ```
+//! use keepass::db::{Database, Entry};
+//!
+//! let mut db = Database::new(Default::default());
+//! let mut entry = Entry::new();
+//! entry.add_tag(" Work; mail ");
+//! entry.add_tag("work");
+//! assert_eq!(entry.tags, vec!["Work", "mail"]);
+//! db.root.add_child(entry);
+//!
+//! assert_eq!(db.all_tags(), vec!["mail", "Work"]);
+//! assert_eq!(db.entries_with_tag("WORK").len(), 1);
+//! ```
+
+use crate::db::{Database, Entry, Group, NodeRef};
+
+/// The characters separating tags when they are stored
+pub const TAG_SEPARATORS: [char; 2] = [';', ','];
+
+/// Whether two tags are the same, ignoring case
+pub fn same_tag(a: &str, b: &str) -> bool {
+    a.to_lowercase() == b.to_lowercase()
+}
+
+/// Split `tags` at the separators, trimming the tags and leaving out empty ones
+fn split_tags(tags: &str) -> impl Iterator<Item = &str> {
+    tags.split(TAG_SEPARATORS)
+        .map(str::trim)
+        .filter(|t| !t.is_empty())
+}
+
+fn add_tag(tags: &mut Vec<String>, tag: &str) -> bool {
+    let mut added = false;
+    for tag in split_tags(tag) {
+        if !tags.iter().any(|t| same_tag(t, tag)) {
+            tags.push(tag.to_string());
+            added = true;
+        }
+    }
+    added
+}
+
+fn remove_tag(tags: &mut Vec<String>, tag: &str) -> bool {
+    let before = tags.len();
+    tags.retain(|t| !same_tag(t, tag.trim()));
+    tags.len() != before
+}
+
+impl Entry {
+    /// Whether the entry has `tag`, ignoring case
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags.iter().any(|t| same_tag(t, tag.trim()))
+    }
+
+    /// Add one or more tags separated by `;` or `,`, unless the entry has them already. Returns
+    /// whether any tag was added.
+    pub fn add_tag(&mut self, tag: &str) -> bool {
+        add_tag(&mut self.tags, tag)
+    }
+
+    /// Remove a tag, ignoring case. Returns whether the entry had it.
+    pub fn remove_tag(&mut self, tag: &str) -> bool {
+        remove_tag(&mut self.tags, tag)
+    }
+}
+
+impl Group {
+    /// Whether the group has `tag`, ignoring case. Group tags are only stored by KDBX 4.1.
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags.iter().any(|t| same_tag(t, tag.trim()))
+    }
+
+    /// Add one or more tags separated by `;` or `,`, unless the group has them already. Returns
+    /// whether any tag was added.
+    pub fn add_tag(&mut self, tag: &str) -> bool {
+        add_tag(&mut self.tags, tag)
+    }
+
+    /// Remove a tag, ignoring case. Returns whether the group had it.
+    pub fn remove_tag(&mut self, tag: &str) -> bool {
+        remove_tag(&mut self.tags, tag)
+    }
+}
+
+impl Database {
+    /// All tags of the groups and entries of the database, sorted without regard to case. Of tags
+    /// that only differ in case, the first one found is listed.
+    pub fn all_tags(&self) -> Vec<String> {
+        let mut all: Vec<String> = Vec::new();
+        for node in &self.root {
+            let tags = match node {
+                NodeRef::Group(g) => &g.tags,
+                NodeRef::Entry(e) => &e.tags,
+            };
+            for tag in tags {
+                if !all.iter().any(|t| same_tag(t, tag)) {
+                    all.push(tag.clone());
+                }
+            }
+        }
+        all.sort_by_key(|t| t.to_lowercase());
+        all
+    }
+
+    /// The entries that have `tag`, ignoring case, in the order of `Group::iter`
+    pub fn entries_with_tag(&self, tag: &str) -> Vec<&Entry> {
+        self.root
+            .iter()
+            .filter_map(|node| match node {
+                NodeRef::Entry(e) if e.has_tag(tag) => Some(e),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The groups that have `tag`, ignoring case, in the order of `Group::iter`
+    pub fn groups_with_tag(&self, tag: &str) -> Vec<&Group> {
+        self.root
+            .iter()
+            .filter_map(|node| match node {
+                NodeRef::Group(g) if g.has_tag(tag) => Some(g),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tags_tests {
+    use crate::db::{Database, Entry, Group};
+
+    #[test]
+    fn test_tags() {
+        let mut entry = Entry::new();
+        assert!(entry.add_tag("Work, ;mail"));
+        assert!(!entry.add_tag("MAIL"));
+        assert!(!entry.add_tag(" ; "));
+        assert_eq!(entry.tags, vec!["Work", "mail"]);
+        assert!(entry.has_tag(" work"));
+
+        assert!(entry.remove_tag("WORK"));
+        assert!(!entry.remove_tag("work"));
+        assert_eq!(entry.tags, vec!["mail"]);
+
+        let mut db = Database::new(Default::default());
+        let mut group = Group::new("Servers");
+        group.add_tag("infra");
+        let mut server = Entry::new();
+        server.add_tag("Infra;ssh");
+        group.add_child(server);
+        db.root.add_child(group);
+        db.root.add_child(entry);
+
+        assert_eq!(db.all_tags(), vec!["infra", "mail", "ssh"]);
+        assert_eq!(db.entries_with_tag("INFRA").len(), 1);
+        assert_eq!(db.entries_with_tag("work").len(), 0);
+        assert_eq!(db.groups_with_tag("infra")[0].name, "Servers");
+    }
+}
@@ -0,0 +1,122 @@
+//! Entry templates
+//!
+//! KeePass and its ports create new entries from templates: entries in the group named by
+//! `Meta::entry_templates_group`, which prefill e.g. the fields of a credit card or the Auto-Type
+//! sequence of a web shop. `Database::templates` lists them and `Entry::from_template` creates an
+//! entry from one of them:
+//!
+//! ```
+//! use keepass::{
+//!     db::{Entry, Group, Value},
+//!     Database,
+//! };
+//!
+//! let mut db = Database::new(Default::default());
+//! let templates = Group::new("Templates")
+//!     .with_child(Entry::new().with_title("Credit card").with_field("PIN", Value::Protected("".into())));
+//! db.meta.entry_templates_group = Some(templates.uuid);
+//! db.root.add_child(templates);
+//!
+//! let entry = Entry::from_template(db.templates()[0]);
+//! assert!(matches!(entry.fields["PIN"], Value::Protected(_)));
+//! db.root.add_child(entry);
+//! ```
+
+use crate::db::{Database, Entry, NodeRef};
+
+impl Database {
+    /// The entries in the templates group and its subgroups. Empty if the database has no
+    /// templates group, or the group no longer exists.
+    pub fn templates(&self) -> Vec<&Entry> {
+        let group = match self.meta.entry_templates_group {
+            Some(uuid) => self.root.find_by_uuid(&uuid),
+            None => None,
+        };
+
+        match group {
+            Some(NodeRef::Group(group)) => group
+                .iter()
+                .filter_map(|node| match node {
+                    NodeRef::Entry(entry) => Some(entry),
+                    NodeRef::Group(_) => None,
+                })
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl Entry {
+    /// Create an entry from a template, see `Database::templates`. The entry gets the fields
+    /// along with their protection, the Auto-Type settings, the icon, the tags, the colors and the
+    /// attachments of the template, but a UUID and times of its own, and no history.
+    pub fn from_template(template: &Entry) -> Entry {
+        Entry {
+            fields: template.fields.clone(),
+            autotype: template.autotype.clone(),
+            tags: template.tags.clone(),
+            icon_id: template.icon_id,
+            custom_icon_uuid: template.custom_icon_uuid,
+            foreground_color: template.foreground_color.clone(),
+            background_color: template.background_color.clone(),
+            override_url: template.override_url.clone(),
+            quality_check: template.quality_check,
+            attachments: template.attachments.clone(),
+            ..Entry::new()
+        }
+    }
+}
+
+#[cfg(test)]
+mod templates_tests {
+    use crate::{
+        db::{AutoType, Entry, Group, Value},
+        Database,
+    };
+
+    #[test]
+    fn test_templates() {
+        let mut db = Database::new(Default::default());
+        assert!(db.templates().is_empty());
+
+        let mut template = Entry::new()
+            .with_title("Web shop")
+            .with_username("")
+            .with_password("")
+            .with_tag("shopping");
+        template.icon_id = Some(42);
+        template.autotype = Some(AutoType {
+            enabled: true,
+            sequence: Some("{USERNAME}{TAB}{PASSWORD}{ENTER}".to_string()),
+            ..Default::default()
+        });
+        template.history = Some(Default::default());
+        template.times.usage_count = 3;
+
+        let templates = Group::new("Templates")
+            .with_child(template.clone())
+            .with_child(Group::new("Cards").with_child(Entry::new().with_title("Credit card")));
+        let templates_uuid = templates.uuid;
+        db.root.add_child(templates);
+        db.root.add_child(Entry::new().with_title("Not a template"));
+
+        // the group has to be named by the metadata
+        assert!(db.templates().is_empty());
+        db.meta.entry_templates_group = Some(templates_uuid);
+        let titles: Vec<_> = db.templates().iter().map(|t| t.get_title().unwrap()).collect();
+        assert_eq!(titles, vec!["Web shop", "Credit card"]);
+
+        let entry = Entry::from_template(db.templates()[0]);
+        assert_ne!(entry.uuid, template.uuid);
+        assert_eq!(entry.fields, template.fields);
+        assert!(matches!(entry.fields["Password"], Value::Protected(_)));
+        assert_eq!(entry.autotype, template.autotype);
+        assert_eq!(entry.icon_id, Some(42));
+        assert_eq!(entry.tags, vec!["shopping".to_string()]);
+        assert_eq!(entry.history, None);
+        assert_eq!(entry.times.usage_count, 0);
+
+        db.meta.entry_templates_group = Some(uuid::Uuid::new_v4());
+        assert!(db.templates().is_empty());
+    }
+}
@@ -0,0 +1,553 @@
+//! Soft deletion of entries with a retention period
+//!
+//! `Database::soft_delete` moves an entry into the recycle bin and records when it was deleted in
+//! the custom data item `DELETED_AT_KEY`. `Database::purge_older_than` later removes the entries
+//! that have been in the recycle bin for longer than a retention period, so that retention
+//! policies can be enforced without keeping track of deletions elsewhere.
+//!
+//! Entries moved to the recycle bin by other clients have no deletion time, their
+//! `LocationChanged` time is used instead.
+//!
+//! `Database::recycle`, `Database::restore` and `Database::empty_recycle_bin` follow what KeePass
+//! does when deleting entries and groups, restoring them and emptying the recycle bin.
+//! Recycled nodes remember the group they were recycled from in the custom data item
+//! `RECYCLED_FROM_KEY` and, like in KeePassXC, as their previous parent group.
+//!
+//! Whatever is removed permanently, by `Database::remove` or one of the above, is recorded in
+//! `Database::deleted_objects`, so that merging with an older copy of the database does not bring
+//! it back.
+
+use chrono::NaiveDateTime;
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::db::{
+    CustomData, CustomDataItem, Database, DeletedObject, Entry, Group, Node, NodeRef, Times, Value,
+};
+
+/// Key of the entry custom data item holding the time the entry was moved to the recycle bin
+pub const DELETED_AT_KEY: &str = "KPRS_DELETED_AT";
+
+/// Key of the custom data item holding the UUID of the group a node was recycled from
+pub const RECYCLED_FROM_KEY: &str = "KPRS_RECYCLED_FROM";
+
+/// Icon of the recycle bin group in KeePass
+const RECYCLE_BIN_ICON: usize = 43;
+
+const TIMESTAMP_FORMAT: &str = "%Y-%m-%dT%H:%M:%S";
+
+/// Errors when soft-deleting or restoring entries
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum SoftDeleteError {
+    #[error("Could not find entry {0}")]
+    EntryNotFound(Uuid),
+
+    #[error("Could not find group {0}")]
+    GroupNotFound(Uuid),
+
+    #[error("The recycle bin is disabled for this database")]
+    RecycleBinDisabled,
+
+    #[error("Entry {0} is not in the recycle bin")]
+    NotDeleted(Uuid),
+
+    #[error("Could not find node {0}")]
+    NodeNotFound(Uuid),
+
+    #[error("Node {0} is the root group or the recycle bin, which cannot be recycled")]
+    CannotRecycle(Uuid),
+
+    #[error("The root group cannot be removed")]
+    CannotRemoveRoot,
+}
+
+impl Entry {
+    /// When the entry was moved to the recycle bin with `Database::soft_delete`
+    pub fn deleted_at(&self) -> Option<NaiveDateTime> {
+        match self.custom_data.items.get(DELETED_AT_KEY)?.value.as_ref()? {
+            Value::Unprotected(time) => NaiveDateTime::parse_from_str(time, TIMESTAMP_FORMAT).ok(),
+            _ => None,
+        }
+    }
+}
+
+impl Database {
+    /// Recycle an entry like `Database::recycle` and record the time of deletion. Unlike
+    /// `recycle`, this fails instead of removing the entry permanently if the recycle bin is
+    /// disabled. Entries that are already in the recycle bin are left untouched.
+    pub fn soft_delete(&mut self, entry: Uuid) -> Result<(), SoftDeleteError> {
+        if self.meta.recyclebin_enabled == Some(false) {
+            return Err(SoftDeleteError::RecycleBinDisabled);
+        }
+        if self.is_in_recycle_bin(entry) {
+            return Ok(());
+        }
+        self.root
+            .entry_by_uuid(&entry)
+            .ok_or(SoftDeleteError::EntryNotFound(entry))?;
+
+        self.recycle(entry)?;
+
+        let now = Times::now();
+        let entry = self
+            .root
+            .entry_by_uuid_mut(&entry)
+            .ok_or(SoftDeleteError::EntryNotFound(entry))?;
+        entry.custom_data.items.insert(
+            DELETED_AT_KEY.to_string(),
+            CustomDataItem {
+                value: Some(Value::Unprotected(now.format(TIMESTAMP_FORMAT).to_string())),
+                last_modification_time: Some(now),
+            },
+        );
+        Ok(())
+    }
+
+    /// Delete an entry or a group the way KeePass does: move it into the recycle bin, creating the
+    /// recycle bin if needed. If the recycle bin is disabled or the node already is in the recycle
+    /// bin, it is removed permanently and recorded as deleted object instead.
+    ///
+    /// Returns whether the node was moved into the recycle bin.
+    pub fn recycle(&mut self, node: Uuid) -> Result<bool, SoftDeleteError> {
+        if node == self.root.uuid || Some(node) == self.meta.recyclebin_uuid {
+            return Err(SoftDeleteError::CannotRecycle(node));
+        }
+        self.root
+            .parent_of(&node)
+            .ok_or(SoftDeleteError::NodeNotFound(node))?;
+
+        if self.meta.recyclebin_enabled == Some(false) || self.is_in_recycle_bin(node) {
+            let removed = self
+                .root
+                .take_by_uuid(&node)
+                .ok_or(SoftDeleteError::NodeNotFound(node))?;
+            self.record_deleted(&removed);
+            return Ok(false);
+        }
+
+        self.move_to_recycle_bin(node)?;
+        Ok(true)
+    }
+
+    /// Permanently remove an entry or a group, recording it and everything in it as deleted
+    /// objects
+    pub fn remove(&mut self, node: Uuid) -> Result<Node, SoftDeleteError> {
+        if node == self.root.uuid {
+            return Err(SoftDeleteError::CannotRemoveRoot);
+        }
+        let removed = self
+            .root
+            .take_by_uuid(&node)
+            .ok_or(SoftDeleteError::NodeNotFound(node))?;
+        self.record_deleted(&removed);
+        Ok(removed)
+    }
+
+    /// Move an entry or a group out of the recycle bin, back into the group it was recycled from.
+    /// Nodes go into the root group if that group is unknown, no longer exists or is in the
+    /// recycle bin itself.
+    ///
+    /// Returns the UUID of the group the node was restored into.
+    pub fn restore(&mut self, node: Uuid) -> Result<Uuid, SoftDeleteError> {
+        if !self.is_in_recycle_bin(node) {
+            return match self.root.parent_of(&node) {
+                Some(_) => Err(SoftDeleteError::NotDeleted(node)),
+                None => Err(SoftDeleteError::NodeNotFound(node)),
+            };
+        }
+
+        // nodes recycled by KeePassXC only know their previous parent group
+        let recycled_from = self
+            .root
+            .iter()
+            .find_map(|n| match n {
+                NodeRef::Entry(e) if e.uuid == node => Some((&e.custom_data, e.previous_parent_group)),
+                NodeRef::Group(g) if g.uuid == node => Some((&g.custom_data, g.previous_parent_group)),
+                _ => None,
+            })
+            .and_then(|(custom_data, previous_parent)| {
+                let recycled_from = match custom_data
+                    .items
+                    .get(RECYCLED_FROM_KEY)
+                    .and_then(|item| item.value.as_ref())
+                {
+                    Some(Value::Unprotected(uuid)) => Uuid::parse_str(uuid).ok(),
+                    _ => None,
+                };
+                recycled_from.or(previous_parent)
+            });
+
+        let parent = match recycled_from {
+            Some(parent)
+                if self.root.group_by_uuid(&parent).is_some()
+                    && Some(parent) != self.meta.recyclebin_uuid
+                    && !self.is_in_recycle_bin(parent) =>
+            {
+                parent
+            }
+            _ => self.root.uuid,
+        };
+
+        self.move_out_of_recycle_bin(node, parent)?;
+        Ok(parent)
+    }
+
+    /// Permanently remove everything in the recycle bin, recording it as deleted objects. Returns
+    /// the UUIDs of the removed entries and groups.
+    pub fn empty_recycle_bin(&mut self) -> Vec<Uuid> {
+        let bin = match self
+            .meta
+            .recyclebin_uuid
+            .and_then(|uuid| self.root.group_by_uuid_mut(&uuid))
+        {
+            Some(bin) => bin,
+            None => return Vec::new(),
+        };
+
+        let removed = std::mem::take(&mut bin.children);
+        let deleted_before = self.deleted_objects.objects.len();
+        for node in &removed {
+            self.record_deleted(node);
+        }
+        self.deleted_objects.objects[deleted_before..]
+            .iter()
+            .map(|deleted| deleted.uuid)
+            .collect()
+    }
+
+    /// Permanently remove the entries that were moved to the recycle bin more than `age` ago,
+    /// recording them as deleted objects. Returns the UUIDs of the removed entries.
+    pub fn purge_older_than(&mut self, age: chrono::Duration) -> Vec<Uuid> {
+        let cutoff = Times::now() - age;
+        let bin = match self.meta.recyclebin_uuid {
+            Some(uuid) => uuid,
+            None => return Vec::new(),
+        };
+        let bin = match self.root.group_by_uuid_mut(&bin) {
+            Some(bin) => bin,
+            None => return Vec::new(),
+        };
+
+        let mut purged = Vec::new();
+        purge_group(bin, cutoff, &mut purged);
+
+        let now = Times::now();
+        self.deleted_objects
+            .objects
+            .extend(purged.iter().map(|uuid| DeletedObject {
+                uuid: *uuid,
+                deletion_time: now,
+            }));
+        purged
+    }
+
+    /// Whether an entry or a group is in the recycle bin, not counting the recycle bin itself
+    fn is_in_recycle_bin(&self, node: Uuid) -> bool {
+        self.meta
+            .recyclebin_uuid
+            .and_then(|uuid| self.root.group_by_uuid(&uuid))
+            .is_some_and(|bin| {
+                bin.iter().skip(1).any(|n| match n {
+                    NodeRef::Entry(e) => e.uuid == node,
+                    NodeRef::Group(g) => g.uuid == node,
+                })
+            })
+    }
+
+    /// Move a node into the recycle bin, recording where it was
+    fn move_to_recycle_bin(&mut self, node: Uuid) -> Result<(), SoftDeleteError> {
+        let parent = self
+            .root
+            .parent_of(&node)
+            .ok_or(SoftDeleteError::NodeNotFound(node))?;
+        let mut removed = self
+            .root
+            .take_by_uuid(&node)
+            .ok_or(SoftDeleteError::NodeNotFound(node))?;
+
+        let now = Times::now();
+        let (custom_data, times, previous_parent) = node_data_mut(&mut removed);
+        *previous_parent = Some(parent);
+        custom_data.items.insert(
+            RECYCLED_FROM_KEY.to_string(),
+            CustomDataItem {
+                value: Some(Value::Unprotected(parent.to_string())),
+                last_modification_time: Some(now),
+            },
+        );
+        times.set_location_changed(now);
+
+        self.recycle_bin_mut().add_child(removed);
+        Ok(())
+    }
+
+    /// Move a node from the recycle bin into `parent`, forgetting about its deletion and where it
+    /// was
+    fn move_out_of_recycle_bin(&mut self, node: Uuid, parent: Uuid) -> Result<(), SoftDeleteError> {
+        self.root
+            .group_by_uuid_mut(&parent)
+            .ok_or(SoftDeleteError::GroupNotFound(parent))?;
+
+        let mut removed = self
+            .root
+            .take_by_uuid(&node)
+            .ok_or(SoftDeleteError::NodeNotFound(node))?;
+        let (custom_data, times, _) = node_data_mut(&mut removed);
+        custom_data.items.remove(DELETED_AT_KEY);
+        custom_data.items.remove(RECYCLED_FROM_KEY);
+        times.set_location_changed(Times::now());
+
+        self.root
+            .group_by_uuid_mut(&parent)
+            .ok_or(SoftDeleteError::GroupNotFound(parent))?
+            .add_child(removed);
+        Ok(())
+    }
+
+    /// Record a removed node and everything in it as deleted objects, unless they already are
+    fn record_deleted(&mut self, node: &Node) {
+        let now = Times::now();
+        let uuids: Vec<Uuid> = match node {
+            Node::Entry(e) => vec![e.uuid],
+            Node::Group(g) => g
+                .iter()
+                .map(|n| match n {
+                    NodeRef::Entry(e) => e.uuid,
+                    NodeRef::Group(g) => g.uuid,
+                })
+                .collect(),
+        };
+        for uuid in uuids {
+            if !self.deleted_objects.contains(uuid) {
+                self.deleted_objects.objects.push(DeletedObject {
+                    uuid,
+                    deletion_time: now,
+                });
+            }
+        }
+    }
+
+    fn recycle_bin_mut(&mut self) -> &mut Group {
+        let exists = self
+            .meta
+            .recyclebin_uuid
+            .is_some_and(|uuid| self.root.group_by_uuid(&uuid).is_some());
+
+        if !exists {
+            let mut bin = Group::new("Recycle Bin");
+            bin.icon_id = Some(RECYCLE_BIN_ICON);
+            bin.enable_searching = Some("false".to_string());
+            self.meta.recyclebin_uuid = Some(bin.uuid);
+            self.meta.recyclebin_changed = Some(Times::now());
+            self.root.add_child(bin);
+        }
+
+        let uuid = self.meta.recyclebin_uuid.unwrap();
+        self.root.group_by_uuid_mut(&uuid).unwrap()
+    }
+}
+
+fn node_data_mut(node: &mut Node) -> (&mut CustomData, &mut Times, &mut Option<Uuid>) {
+    match node {
+        Node::Entry(e) => (&mut e.custom_data, &mut e.times, &mut e.previous_parent_group),
+        Node::Group(g) => (&mut g.custom_data, &mut g.times, &mut g.previous_parent_group),
+    }
+}
+
+fn purge_group(group: &mut Group, cutoff: NaiveDateTime, purged: &mut Vec<Uuid>) {
+    group.children.retain_mut(|node| match node {
+        Node::Entry(e) => {
+            let deleted_at = e.deleted_at().or_else(|| e.times.get_location_changed().copied());
+            let expired = deleted_at.is_some_and(|time| time <= cutoff);
+            if expired {
+                purged.push(e.uuid);
+            }
+            !expired
+        }
+        Node::Group(g) => {
+            purge_group(g, cutoff, purged);
+            true
+        }
+    });
+}
+
+#[cfg(test)]
+mod trash_tests {
+    use chrono::Duration;
+
+    use crate::db::{with_clock, Database, Entry, Group, Node, Times};
+
+    use super::{SoftDeleteError, RECYCLED_FROM_KEY};
+
+    #[test]
+    fn test_soft_delete_and_purge() {
+        let start = Times::epoch() + Duration::days(10_000);
+        let mut db = Database::new(Default::default());
+        let group = Group::new("Work");
+        let group_uuid = group.uuid;
+        db.root.add_child(group);
+
+        let old = Entry::new();
+        let old_uuid = old.uuid;
+        let recent = Entry::new();
+        let recent_uuid = recent.uuid;
+        db.root.add_child(old);
+        db.root.add_child(recent);
+
+        with_clock(move || start, || db.soft_delete(old_uuid)).unwrap();
+        with_clock(move || start + Duration::days(20), || db.soft_delete(recent_uuid)).unwrap();
+        assert_eq!(db.soft_delete(recent_uuid), Ok(()));
+
+        let bin = db.meta.recyclebin_uuid.unwrap();
+        let bin_group = db.root.groups().into_iter().find(|g| g.uuid == bin).unwrap();
+        assert_eq!(bin_group.entries().len(), 2);
+        assert_eq!(bin_group.entries()[0].deleted_at(), Some(start));
+
+        let purged = with_clock(
+            move || start + Duration::days(31),
+            || db.purge_older_than(Duration::days(30)),
+        );
+        assert_eq!(purged, vec![old_uuid]);
+        assert!(db.deleted_objects.contains(old_uuid));
+
+        assert_eq!(db.restore(old_uuid), Err(SoftDeleteError::NodeNotFound(old_uuid)));
+        assert_eq!(db.restore(recent_uuid), Ok(db.root.uuid));
+        let recent = db
+            .root
+            .entries()
+            .into_iter()
+            .find(|e| e.uuid == recent_uuid)
+            .unwrap();
+        assert_eq!(recent.deleted_at(), None);
+        assert_eq!(
+            db.restore(recent_uuid),
+            Err(SoftDeleteError::NotDeleted(recent_uuid))
+        );
+
+        // groups are not soft-deleted
+        assert_eq!(
+            db.soft_delete(group_uuid),
+            Err(SoftDeleteError::EntryNotFound(group_uuid))
+        );
+
+        db.meta.recyclebin_enabled = Some(false);
+        assert_eq!(
+            db.soft_delete(recent_uuid),
+            Err(SoftDeleteError::RecycleBinDisabled)
+        );
+    }
+
+    #[test]
+    fn test_recycle_restore_and_empty() {
+        let mut db = Database::new(Default::default());
+        let mut work = Group::new("Work");
+        let work_uuid = work.uuid;
+        let mut projects = Group::new("Projects");
+        let projects_uuid = projects.uuid;
+        let nested = Entry::new();
+        let nested_uuid = nested.uuid;
+        projects.add_child(nested);
+        work.add_child(projects);
+        let entry = Entry::new();
+        let entry_uuid = entry.uuid;
+        work.add_child(entry);
+        db.root.add_child(work);
+
+        assert_eq!(
+            db.recycle(db.root.uuid),
+            Err(SoftDeleteError::CannotRecycle(db.root.uuid))
+        );
+        assert_eq!(db.recycle(entry_uuid), Ok(true));
+        assert_eq!(db.recycle(projects_uuid), Ok(true));
+
+        let bin_uuid = db.meta.recyclebin_uuid.unwrap();
+        let bin = db.root.groups().into_iter().find(|g| g.uuid == bin_uuid).unwrap();
+        assert_eq!(bin.children.len(), 2);
+        assert_eq!(bin.groups()[0].entries()[0].uuid, nested_uuid);
+        assert!(bin.entries()[0].custom_data.items.contains_key(RECYCLED_FROM_KEY));
+        assert_eq!(bin.entries()[0].previous_parent_group, Some(work_uuid));
+        assert_eq!(bin.entries()[0].deleted_at(), None);
+        assert!(bin.entries()[0].times.get_location_changed().is_some());
+        assert_eq!(
+            db.recycle(bin_uuid),
+            Err(SoftDeleteError::CannotRecycle(bin_uuid))
+        );
+
+        assert_eq!(db.restore(entry_uuid), Ok(work_uuid));
+        let work = db
+            .root
+            .groups()
+            .into_iter()
+            .find(|g| g.uuid == work_uuid)
+            .unwrap();
+        assert_eq!(work.entries()[0].uuid, entry_uuid);
+        assert!(work.entries()[0].custom_data.items.is_empty());
+        assert_eq!(
+            db.restore(entry_uuid),
+            Err(SoftDeleteError::NotDeleted(entry_uuid))
+        );
+
+        // the group an entry was recycled from is gone, so it is restored into the root group
+        assert_eq!(db.recycle(entry_uuid), Ok(true));
+        assert_eq!(db.recycle(work_uuid), Ok(true));
+        assert_eq!(db.restore(entry_uuid), Ok(db.root.uuid));
+
+        // recycling what already is in the recycle bin deletes it permanently
+        assert_eq!(db.recycle(work_uuid), Ok(false));
+        assert!(db.deleted_objects.contains(work_uuid));
+
+        let mut removed = db.empty_recycle_bin();
+        removed.sort();
+        let mut expected = vec![projects_uuid, nested_uuid];
+        expected.sort();
+        assert_eq!(removed, expected);
+        assert!(db.deleted_objects.contains(nested_uuid));
+        let bin = db.root.groups().into_iter().find(|g| g.uuid == bin_uuid).unwrap();
+        assert!(bin.children.is_empty());
+
+        // without a recycle bin, nodes are deleted right away
+        db.meta.recyclebin_enabled = Some(false);
+        assert_eq!(db.recycle(entry_uuid), Ok(false));
+        assert!(db.deleted_objects.contains(entry_uuid));
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut db = Database::new(Default::default());
+        let mut group = Group::new("Work");
+        let group_uuid = group.uuid;
+        let entry = Entry::new();
+        let entry_uuid = entry.uuid;
+        group.add_child(entry);
+        db.root.add_child(group);
+
+        assert_eq!(db.remove(db.root.uuid), Err(SoftDeleteError::CannotRemoveRoot));
+        assert!(matches!(db.remove(group_uuid), Ok(Node::Group(g)) if g.uuid == group_uuid));
+        assert!(db.root.children.is_empty());
+        assert!(db.deleted_objects.contains(group_uuid));
+        assert!(db.deleted_objects.contains(entry_uuid));
+        assert_eq!(
+            db.remove(group_uuid),
+            Err(SoftDeleteError::NodeNotFound(group_uuid))
+        );
+    }
+
+    #[test]
+    fn test_restore_by_keepassxc() {
+        let mut db = Database::new(Default::default());
+        let work = Group::new("Work");
+        let work_uuid = work.uuid;
+        db.root.add_child(work);
+        let entry = Entry::new();
+        let entry_uuid = entry.uuid;
+        db.root.add_child(entry);
+        db.recycle(entry_uuid).unwrap();
+
+        // KeePassXC only records the previous parent group
+        let entry = db.root.entry_by_uuid_mut(&entry_uuid).unwrap();
+        entry.custom_data.items.clear();
+        entry.previous_parent_group = Some(work_uuid);
+
+        assert_eq!(db.restore(entry_uuid), Ok(work_uuid));
+    }
+}
@@ -0,0 +1,279 @@
+//! Local usage statistics stored in the database metadata
+//!
+//! Applications can opt in to counting how often a database is opened and saved, and by which
+//! client. The statistics are stored as custom data items of the database metadata, so they
+//! travel with the file and let users with several devices see which one modified the database
+//! last. Nothing is sent anywhere.
+//!
+//! All items are stored under keys starting with `USAGE_STATS_NAMESPACE`:
+//!
+//! | Key                       | Value                                            |
+//! |---------------------------|--------------------------------------------------|
+//! | `KPRS_USAGE/Opens`        | number of recorded opens                         |
+//! | `KPRS_USAGE/Saves`        | number of recorded saves                         |
+//! | `KPRS_USAGE/LastClient`   | name of the client that recorded the last event  |
+//! | `KPRS_USAGE/LastOpened`   | time of the last recorded open                   |
+//! | `KPRS_USAGE/LastSaved`    | time of the last recorded save                   |
+//! | `KPRS_USAGE/Access/<uuid>`| accesses and time of last access of an entry     |
+//!
+//! Entry accesses are only counted when the application calls `Database::record_access`, e.g.
+//! when the user copies a password. `Database::recently_used` and `Database::frequently_used`
+//! then list the entries for the "recent entries" section of a picker.
+
+use std::collections::HashMap;
+
+use chrono::NaiveDateTime;
+use uuid::Uuid;
+
+use crate::db::{CustomDataItem, Database, Entry, Meta, NodeRef, Times, Value};
+
+/// Prefix of all custom data keys holding usage statistics
+pub const USAGE_STATS_NAMESPACE: &str = "KPRS_USAGE/";
+
+const OPENS_KEY: &str = "KPRS_USAGE/Opens";
+const SAVES_KEY: &str = "KPRS_USAGE/Saves";
+const LAST_CLIENT_KEY: &str = "KPRS_USAGE/LastClient";
+const LAST_OPENED_KEY: &str = "KPRS_USAGE/LastOpened";
+const LAST_SAVED_KEY: &str = "KPRS_USAGE/LastSaved";
+const ACCESS_KEY_PREFIX: &str = "KPRS_USAGE/Access/";
+
+const TIME_FORMAT: &str = "%Y-%m-%dT%H:%M:%S";
+
+/// Usage statistics recorded in a database
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serialization", derive(serde::Serialize))]
+pub struct UsageStats {
+    pub opens: u64,
+    pub saves: u64,
+    pub last_client: Option<String>,
+    pub last_opened: Option<NaiveDateTime>,
+    pub last_saved: Option<NaiveDateTime>,
+}
+
+/// How often and when an entry was accessed, see `Database::record_access`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serialization", derive(serde::Serialize))]
+pub struct AccessStats {
+    pub count: u64,
+    pub last_access: NaiveDateTime,
+}
+
+impl AccessStats {
+    fn parse(value: &str) -> Option<Self> {
+        let (count, last_access) = value.split_once(';')?;
+        Some(AccessStats {
+            count: count.parse().ok()?,
+            last_access: NaiveDateTime::parse_from_str(last_access, TIME_FORMAT).ok()?,
+        })
+    }
+}
+
+impl Meta {
+    /// Read the usage statistics recorded in this database
+    pub fn usage_stats(&self) -> UsageStats {
+        UsageStats {
+            opens: self
+                .usage_value(OPENS_KEY)
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(0),
+            saves: self
+                .usage_value(SAVES_KEY)
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(0),
+            last_client: self.usage_value(LAST_CLIENT_KEY).map(|v| v.to_string()),
+            last_opened: self
+                .usage_value(LAST_OPENED_KEY)
+                .and_then(|v| NaiveDateTime::parse_from_str(v, TIME_FORMAT).ok()),
+            last_saved: self
+                .usage_value(LAST_SAVED_KEY)
+                .and_then(|v| NaiveDateTime::parse_from_str(v, TIME_FORMAT).ok()),
+        }
+    }
+
+    /// Record that the database was opened by a client
+    pub fn record_open(&mut self, client: &str) {
+        let stats = self.usage_stats();
+        let now = Times::now();
+        self.set_usage_value(OPENS_KEY, (stats.opens + 1).to_string(), now);
+        self.set_usage_value(LAST_OPENED_KEY, now.format(TIME_FORMAT).to_string(), now);
+        self.set_usage_value(LAST_CLIENT_KEY, client.to_string(), now);
+    }
+
+    /// Record that the database is being saved by a client. Call this before saving so that the
+    /// statistics are included in the saved file.
+    pub fn record_save(&mut self, client: &str) {
+        let stats = self.usage_stats();
+        let now = Times::now();
+        self.set_usage_value(SAVES_KEY, (stats.saves + 1).to_string(), now);
+        self.set_usage_value(LAST_SAVED_KEY, now.format(TIME_FORMAT).to_string(), now);
+        self.set_usage_value(LAST_CLIENT_KEY, client.to_string(), now);
+    }
+
+    /// Remove all usage statistics from the database
+    pub fn clear_usage_stats(&mut self) {
+        self.custom_data
+            .items
+            .retain(|key, _| !key.starts_with(USAGE_STATS_NAMESPACE));
+    }
+
+    /// The access statistics of all entries that were accessed, by entry UUID
+    pub fn access_stats(&self) -> HashMap<Uuid, AccessStats> {
+        self.custom_data
+            .items
+            .iter()
+            .filter_map(|(key, item)| {
+                let uuid = Uuid::parse_str(key.strip_prefix(ACCESS_KEY_PREFIX)?).ok()?;
+                match item.value.as_ref()? {
+                    Value::Unprotected(v) => Some((uuid, AccessStats::parse(v)?)),
+                    _ => None,
+                }
+            })
+            .collect()
+    }
+
+    fn usage_value(&self, key: &str) -> Option<&str> {
+        match self.custom_data.items.get(key)?.value.as_ref()? {
+            Value::Unprotected(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    fn set_usage_value(&mut self, key: &str, value: String, now: NaiveDateTime) {
+        self.custom_data.items.insert(
+            key.to_string(),
+            CustomDataItem {
+                value: Some(Value::Unprotected(value)),
+                last_modification_time: Some(now),
+            },
+        );
+    }
+}
+
+impl Database {
+    /// Record that an entry was accessed, e.g. because its password was copied. The statistics are
+    /// kept in the metadata and saved with the database.
+    pub fn record_access(&mut self, entry: Uuid) {
+        let key = format!("{}{}", ACCESS_KEY_PREFIX, entry);
+        let count = self
+            .meta
+            .usage_value(&key)
+            .and_then(AccessStats::parse)
+            .map_or(0, |stats| stats.count);
+
+        let now = Times::now();
+        self.meta
+            .set_usage_value(&key, format!("{};{}", count + 1, now.format(TIME_FORMAT)), now);
+    }
+
+    /// The access statistics of an entry, if it was accessed
+    pub fn entry_access_stats(&self, entry: Uuid) -> Option<AccessStats> {
+        let key = format!("{}{}", ACCESS_KEY_PREFIX, entry);
+        self.meta.usage_value(&key).and_then(AccessStats::parse)
+    }
+
+    /// Up to `n` entries, most recently accessed first
+    pub fn recently_used(&self, n: usize) -> Vec<&Entry> {
+        self.used_entries(n, |stats| (stats.last_access, stats.count))
+    }
+
+    /// Up to `n` entries, most often accessed first. Entries accessed equally often are ordered by
+    /// their last access.
+    pub fn frequently_used(&self, n: usize) -> Vec<&Entry> {
+        self.used_entries(n, |stats| (stats.count, stats.last_access))
+    }
+
+    fn used_entries<K: Ord>(&self, n: usize, rank: impl Fn(&AccessStats) -> K) -> Vec<&Entry> {
+        let stats = self.meta.access_stats();
+
+        // statistics of entries that were deleted meanwhile are skipped
+        let mut entries: Vec<(&Entry, &AccessStats)> = self
+            .root
+            .iter()
+            .filter_map(|node| match node {
+                NodeRef::Entry(e) => Some((e, stats.get(&e.uuid)?)),
+                NodeRef::Group(_) => None,
+            })
+            .collect();
+
+        entries
+            .sort_by(|(a, a_stats), (b, b_stats)| rank(b_stats).cmp(&rank(a_stats)).then(a.uuid.cmp(&b.uuid)));
+        entries.into_iter().take(n).map(|(e, _)| e).collect()
+    }
+}
+
+#[cfg(test)]
+mod usage_tests {
+    use crate::db::{CustomDataItem, Meta, Value};
+
+    #[test]
+    fn test_usage_stats() {
+        let mut meta = Meta::default();
+        meta.custom_data.items.insert(
+            "KPXC_DECRYPTION_TIME_PREFERENCE".to_string(),
+            CustomDataItem {
+                value: Some(Value::Unprotected("1000".to_string())),
+                last_modification_time: None,
+            },
+        );
+
+        assert_eq!(meta.usage_stats().opens, 0);
+        assert_eq!(meta.usage_stats().last_client, None);
+
+        meta.record_open("laptop");
+        meta.record_open("laptop");
+        meta.record_save("phone");
+
+        let stats = meta.usage_stats();
+        assert_eq!(stats.opens, 2);
+        assert_eq!(stats.saves, 1);
+        assert_eq!(stats.last_client.as_deref(), Some("phone"));
+        assert!(stats.last_opened.is_some());
+        assert!(stats.last_saved.is_some());
+
+        meta.clear_usage_stats();
+        assert_eq!(meta.usage_stats(), Default::default());
+
+        // other custom data is kept
+        assert_eq!(meta.custom_data.items.len(), 1);
+    }
+
+    #[test]
+    fn test_recently_and_frequently_used() {
+        use chrono::Duration;
+
+        use crate::db::{with_clock, Database, Entry, Times};
+
+        let start = Times::epoch() + Duration::days(10_000);
+        let mut db = Database::new(Default::default());
+        let entries: Vec<Entry> = (0..3).map(|_| Entry::new()).collect();
+        let uuids: Vec<_> = entries.iter().map(|e| e.uuid).collect();
+        for entry in entries {
+            db.root.add_child(entry);
+        }
+
+        assert!(db.recently_used(5).is_empty());
+
+        for (minutes, entry) in [(0, 0), (1, 0), (2, 1), (3, 0), (4, 2)] {
+            let uuid = uuids[entry];
+            with_clock(
+                move || start + Duration::minutes(minutes),
+                || db.record_access(uuid),
+            );
+        }
+        // accesses of deleted entries are skipped
+        db.record_access(uuid::Uuid::new_v4());
+
+        let recent: Vec<_> = db.recently_used(2).iter().map(|e| e.uuid).collect();
+        assert_eq!(recent, vec![uuids[2], uuids[0]]);
+
+        let frequent: Vec<_> = db.frequently_used(5).iter().map(|e| e.uuid).collect();
+        assert_eq!(frequent, vec![uuids[0], uuids[2], uuids[1]]);
+
+        let stats = db.entry_access_stats(uuids[0]).unwrap();
+        assert_eq!(stats.count, 3);
+        assert_eq!(stats.last_access, start + Duration::minutes(3));
+
+        db.meta.clear_usage_stats();
+        assert!(db.frequently_used(5).is_empty());
+    }
+}
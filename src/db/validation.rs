@@ -0,0 +1,282 @@
+//! Data hygiene rules for entries
+//!
+//! Organizations sharing a database often want its entries to follow some conventions: URLs that
+//! actually parse, a user name on every entry of a team group, passwords following a policy. A
+//! `ValidationRule` checks one field of the entries in its scope, and
+//! `Database::validate_against` lists every violation. To keep edits from introducing new
+//! violations, apply them with `commands::apply_all_validated`.
+//!
+//! ```
+//! use keepass::db::{Database, Filter, ValidationRule};
+//!
+//! let db = Database::new(Default::default());
+//! let team = db.root.uuid;
+//! let rules = vec![
+//!     ValidationRule::url("valid-url", "URL"),
+//!     ValidationRule::required("has-username", "UserName").within(Filter::in_group(team)),
+//!     ValidationRule::matches("long-password", "Password", "at least 16 characters", |p| {
+//!         p.chars().count() >= 16
+//!     }),
+//! ];
+//! assert!(db.validate_against(&rules).is_empty());
+//! ```
+
+use std::fmt;
+
+use uuid::Uuid;
+
+use crate::db::{with_audit_context, Database, Entry, FieldPredicate, Filter, Group};
+
+/// What a rule checks about its field
+#[derive(Clone)]
+pub enum Check {
+    /// The field is present and not empty
+    Required,
+
+    /// The field is empty or an absolute URL
+    Url,
+
+    /// The field is empty or the predicate returns true for it
+    Matches {
+        /// Describes the expected value in violations, e.g. "at least 16 characters"
+        description: String,
+        predicate: FieldPredicate,
+    },
+}
+
+impl fmt::Debug for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Check::Required => write!(f, "Required"),
+            Check::Url => write!(f, "Url"),
+            Check::Matches { description, .. } => f
+                .debug_struct("Matches")
+                .field("description", description)
+                .finish_non_exhaustive(),
+        }
+    }
+}
+
+/// A check of one field of the entries matching `scope`
+#[derive(Debug, Clone)]
+pub struct ValidationRule {
+    /// Identifies the rule in violations
+    pub name: String,
+    pub field: String,
+    pub check: Check,
+    pub scope: Filter,
+}
+
+impl ValidationRule {
+    pub fn required(name: &str, field: &str) -> Self {
+        ValidationRule::new(name, field, Check::Required)
+    }
+
+    pub fn url(name: &str, field: &str) -> Self {
+        ValidationRule::new(name, field, Check::Url)
+    }
+
+    pub fn matches(
+        name: &str,
+        field: &str,
+        description: &str,
+        predicate: impl Fn(&str) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        ValidationRule::new(
+            name,
+            field,
+            Check::Matches {
+                description: description.to_string(),
+                predicate: std::sync::Arc::new(predicate),
+            },
+        )
+    }
+
+    fn new(name: &str, field: &str, check: Check) -> Self {
+        ValidationRule {
+            name: name.to_string(),
+            field: field.to_string(),
+            check,
+            scope: Filter::All,
+        }
+    }
+
+    /// Only check the entries matching `scope`
+    pub fn within(self, scope: Filter) -> Self {
+        ValidationRule { scope, ..self }
+    }
+
+    /// Check an entry, regardless of the scope of the rule
+    pub fn check(&self, entry: &Entry) -> Option<Violation> {
+        let value = entry.get_protected(&self.field);
+        let value = value.as_ref().and_then(|v| v.as_str()).unwrap_or_default();
+
+        let kind = match &self.check {
+            Check::Required if value.trim().is_empty() => ViolationKind::Missing,
+            Check::Url if !value.is_empty() && !is_absolute_url(value) => ViolationKind::InvalidUrl,
+            Check::Matches {
+                description,
+                predicate,
+            } if !value.is_empty() && !predicate(value) => ViolationKind::NoMatch(description.clone()),
+            _ => return None,
+        };
+
+        Some(Violation {
+            entry: entry.uuid,
+            rule: self.name.clone(),
+            field: self.field.clone(),
+            kind,
+        })
+    }
+}
+
+/// How a field violates a rule
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serialization", derive(serde::Serialize))]
+pub enum ViolationKind {
+    Missing,
+    InvalidUrl,
+
+    /// The value does not match the description of a `Check::Matches` rule
+    NoMatch(String),
+}
+
+/// A field of an entry violating a rule. Field values are never included, as they may be secret.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serialization", derive(serde::Serialize))]
+pub struct Violation {
+    pub entry: Uuid,
+    pub rule: String,
+    pub field: String,
+    pub kind: ViolationKind,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Entry {} violates rule {}: ", self.entry, self.rule)?;
+        match &self.kind {
+            ViolationKind::Missing => write!(f, "{} is missing", self.field),
+            ViolationKind::InvalidUrl => write!(f, "{} is not a valid URL", self.field),
+            ViolationKind::NoMatch(description) => write!(f, "{} must be {}", self.field, description),
+        }
+    }
+}
+
+impl Database {
+    /// Check every entry against the rules. Violations are ordered by entry, depth-first, and then
+    /// by rule.
+    pub fn validate_against(&self, rules: &[ValidationRule]) -> Vec<Violation> {
+        let mut out = Vec::new();
+        with_audit_context("validation", || {
+            validate_group(&self.root, rules, &mut Vec::new(), &mut out)
+        });
+        out
+    }
+}
+
+fn validate_group(group: &Group, rules: &[ValidationRule], groups: &mut Vec<Uuid>, out: &mut Vec<Violation>) {
+    groups.push(group.uuid);
+    for entry in group.entries() {
+        out.extend(
+            rules
+                .iter()
+                .filter(|rule| rule.scope.matches(entry, groups))
+                .filter_map(|rule| rule.check(entry)),
+        );
+    }
+    for child in group.groups() {
+        validate_group(child, rules, groups, out);
+    }
+    groups.pop();
+}
+
+/// `scheme:rest` with a valid scheme, and a host if the URL has an authority part
+fn is_absolute_url(value: &str) -> bool {
+    let (scheme, rest) = match value.split_once(':') {
+        Some(parts) => parts,
+        None => return false,
+    };
+
+    let valid_scheme = scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+        && scheme
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'));
+    if !valid_scheme || rest.is_empty() || rest.contains(char::is_whitespace) {
+        return false;
+    }
+
+    match rest.strip_prefix("//") {
+        Some(authority) => !authority.starts_with(['/', '?', '#']) && !authority.is_empty(),
+        None => true,
+    }
+}
+
+#[cfg(test)]
+mod validation_tests {
+    use crate::db::{Database, Entry, Filter, Group, Value};
+
+    use super::{ValidationRule, ViolationKind};
+
+    fn entry(fields: &[(&str, &str)]) -> Entry {
+        let mut entry = Entry::new();
+        for (name, value) in fields {
+            entry
+                .fields
+                .insert(name.to_string(), Value::Unprotected(value.to_string()));
+        }
+        entry
+    }
+
+    #[test]
+    fn test_validate_against() {
+        let mut db = Database::new(Default::default());
+        let web = entry(&[("URL", "https://example.com/login"), ("Password", "short")]);
+        let web_uuid = web.uuid;
+        db.root.add_child(web);
+        db.root
+            .add_child(entry(&[("URL", "example.com"), ("UserName", "")]));
+
+        let mut team = Group::new("Team");
+        let team_uuid = team.uuid;
+        let shared = entry(&[("URL", "ssh://"), ("UserName", " ")]);
+        let shared_uuid = shared.uuid;
+        team.add_child(shared);
+        team.add_child(entry(&[("URL", "mailto:team@example.com"), ("UserName", "team")]));
+        db.root.add_child(team);
+
+        let rules = vec![
+            ValidationRule::url("valid-url", "URL"),
+            ValidationRule::required("has-username", "UserName").within(Filter::in_group(team_uuid)),
+            ValidationRule::matches("long-password", "Password", "at least 8 characters", |p| {
+                p.len() >= 8
+            }),
+        ];
+
+        let violations = db.validate_against(&rules);
+        let summary: Vec<(String, ViolationKind)> = violations
+            .iter()
+            .map(|v| (v.rule.clone(), v.kind.clone()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (
+                    "long-password".to_string(),
+                    ViolationKind::NoMatch("at least 8 characters".to_string())
+                ),
+                ("valid-url".to_string(), ViolationKind::InvalidUrl),
+                ("valid-url".to_string(), ViolationKind::InvalidUrl),
+                ("has-username".to_string(), ViolationKind::Missing),
+            ]
+        );
+        assert_eq!(violations[0].entry, web_uuid);
+        assert_eq!(violations[3].entry, shared_uuid);
+        assert_eq!(
+            violations[0].to_string(),
+            format!(
+                "Entry {} violates rule long-password: Password must be at least 8 characters",
+                web_uuid
+            )
+        );
+    }
+}
@@ -0,0 +1,142 @@
+//! Read-only views showing part of a database
+//!
+//! One database file is often used in several contexts, e.g. on a work laptop that should not
+//! show the "Personal" group. `Database::filtered_view` returns a `FilteredView` containing only
+//! the entries matching a `Filter`. The view dereferences to a `Database`, so it can be passed to
+//! searches, exports and reports like the full database, but it cannot be changed or saved back.
+//!
+//! ```
+//! use keepass::db::{Database, Entry, Filter, Group};
+//!
+//! let mut db = Database::new(Default::default());
+//! let mut personal = Group::new("Personal");
+//! let personal_uuid = personal.uuid;
+//! personal.add_child(Entry::new());
+//! db.root.add_child(personal);
+//!
+//! let work = db.filtered_view(&Filter::in_group(personal_uuid).not());
+//! assert!(work.root.groups().is_empty());
+//! ```
+
+use std::{collections::HashSet, ops::Deref};
+
+use uuid::Uuid;
+
+use crate::db::{Database, DeletedObjects, Filter, Group, Node};
+
+/// A copy of a database containing only the entries matching a filter, see the module
+/// documentation
+#[derive(Debug, Clone)]
+pub struct FilteredView {
+    db: Database,
+}
+
+impl Deref for FilteredView {
+    type Target = Database;
+
+    fn deref(&self) -> &Database {
+        &self.db
+    }
+}
+
+impl Database {
+    /// A read-only view of the entries matching `filter`.
+    ///
+    /// Groups are hidden if they contain entries, directly or in subgroups, but none of them
+    /// match. Empty groups and the root group are always shown. Deleted objects are not part of
+    /// the view.
+    pub fn filtered_view(&self, filter: &Filter) -> FilteredView {
+        let visible: HashSet<Uuid> = filter.select_uuids(&self.root).into_iter().collect();
+
+        let mut db = self.clone();
+        prune(&mut db.root, &visible);
+        db.deleted_objects = DeletedObjects::default();
+        db.annotations.retain_existing(&db.root);
+
+        FilteredView { db }
+    }
+}
+
+/// Remove the entries that are not visible, and groups that only contained such entries.
+/// Returns whether the group had any entries, and whether any of them are visible.
+fn prune(group: &mut Group, visible: &HashSet<Uuid>) -> (bool, bool) {
+    let mut had_entries = false;
+    let mut any_visible = false;
+
+    group.children.retain_mut(|node| match node {
+        Node::Entry(e) => {
+            had_entries = true;
+            let keep = visible.contains(&e.uuid);
+            any_visible |= keep;
+            keep
+        }
+        Node::Group(g) => {
+            let (had, shown) = prune(g, visible);
+            had_entries |= had;
+            any_visible |= shown;
+            shown || !had
+        }
+    });
+
+    (had_entries, any_visible)
+}
+
+#[cfg(test)]
+mod view_tests {
+    use crate::db::{Database, DeletedObject, Entry, Filter, Group, NodeRef, Times, Value};
+
+    fn entry(title: &str) -> Entry {
+        let mut entry = Entry::new();
+        entry
+            .fields
+            .insert("Title".to_string(), Value::Unprotected(title.to_string()));
+        entry
+    }
+
+    #[test]
+    fn test_filtered_view() {
+        let mut db = Database::new(Default::default());
+        db.root.add_child(entry("shared"));
+
+        let mut work = Group::new("Work");
+        work.add_child(entry("vpn"));
+        work.add_child(Group::new("Empty"));
+        db.root.add_child(work);
+
+        let mut personal = Group::new("Personal");
+        let personal_uuid = personal.uuid;
+        let mut banking = Group::new("Banking");
+        banking.add_child(entry("bank"));
+        personal.add_child(banking);
+        personal.add_child(entry("mail"));
+        db.root.add_child(personal);
+
+        db.deleted_objects.objects.push(DeletedObject {
+            uuid: uuid::Uuid::new_v4(),
+            deletion_time: Times::now(),
+        });
+
+        let view = db.filtered_view(&Filter::in_group(personal_uuid).not());
+        let names: Vec<String> = view
+            .root
+            .iter()
+            .map(|node| match node {
+                NodeRef::Group(g) => g.name.clone(),
+                NodeRef::Entry(e) => e.get_title().unwrap().to_string(),
+            })
+            .collect();
+        assert_eq!(names.len(), 5);
+        for name in ["shared", "Work", "vpn", "Empty"] {
+            assert!(names.contains(&name.to_string()));
+        }
+        assert!(view.deleted_objects.objects.is_empty());
+
+        // views work wherever a database is expected
+        assert_eq!(Filter::All.select(&view.root).len(), 2);
+        assert_eq!(db.filtered_view(&Filter::All).root, db.root);
+
+        // groups are hidden together with all their entries, including empty subgroups
+        let view = db.filtered_view(&Filter::tag("none"));
+        assert!(view.root.children.is_empty());
+    }
+}
@@ -0,0 +1,190 @@
+//! Walking the tree of groups and entries with a visitor
+//!
+//! `Database::walk` visits the groups and entries depth-first, in the order of their group's
+//! children, and tells the visitor when it enters and leaves a group. The visitor decides after
+//! each event whether to go on, to leave out the rest of a subtree or to stop, so that exporters
+//! and auditors do not need to nest iterators or track depths themselves:
+//!
+//! ```
+//! use keepass::db::{Database, Entry, Group, WalkControl, WalkEvent};
+//!
+//! let mut db = Database::new(Default::default());
+//! let mut bin = Group::new("Recycle Bin");
+//! bin.add_child(Entry::new());
+//! db.meta.recyclebin_uuid = Some(bin.uuid);
+//! db.root.add_child(bin);
+//! db.root.add_child(Entry::new());
+//!
+//! let recycle_bin = db.meta.recyclebin_uuid;
+//! let mut entries = 0;
+//! db.walk(&mut |event: WalkEvent| match event {
+//!     WalkEvent::EnterGroup(group) if Some(group.uuid) == recycle_bin => WalkControl::SkipSubtree,
+//!     WalkEvent::Entry(_) => {
+//!         entries += 1;
+//!         WalkControl::Continue
+//!     }
+//!     _ => WalkControl::Continue,
+//! });
+//! assert_eq!(entries, 1);
+//! ```
+
+use crate::db::{Database, Entry, Group, Node};
+
+/// What `Database::walk` reports to the visitor
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WalkEvent<'a> {
+    /// A group is entered, before any of its children are visited
+    EnterGroup(&'a Group),
+
+    /// A group is left after its children were visited. Only reported for groups whose
+    /// `EnterGroup` was answered with `WalkControl::Continue`.
+    ExitGroup(&'a Group),
+
+    Entry(&'a Entry),
+}
+
+/// How `Database::walk` goes on after an event
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WalkControl {
+    Continue,
+
+    /// After `EnterGroup`, leave out the children of the group. After an entry, leave out the
+    /// remaining children of its group. Makes no difference after `ExitGroup`.
+    SkipSubtree,
+
+    /// End the walk without any further events
+    Stop,
+}
+
+/// Receives the events of `Database::walk`. Implemented for closures taking a `WalkEvent`.
+pub trait Visitor {
+    fn visit(&mut self, event: WalkEvent<'_>) -> WalkControl;
+}
+
+impl<F> Visitor for F
+where
+    F: FnMut(WalkEvent<'_>) -> WalkControl,
+{
+    fn visit(&mut self, event: WalkEvent<'_>) -> WalkControl {
+        self(event)
+    }
+}
+
+impl Group {
+    /// Walk this group and everything below it, see the module documentation. Returns `false` if
+    /// the visitor stopped the walk.
+    pub fn walk(&self, visitor: &mut dyn Visitor) -> bool {
+        match visitor.visit(WalkEvent::EnterGroup(self)) {
+            WalkControl::Continue => {}
+            WalkControl::SkipSubtree => return true,
+            WalkControl::Stop => return false,
+        }
+
+        for child in &self.children {
+            let control = match child {
+                Node::Group(g) if g.walk(visitor) => WalkControl::Continue,
+                Node::Group(_) => WalkControl::Stop,
+                Node::Entry(e) => visitor.visit(WalkEvent::Entry(e)),
+            };
+            match control {
+                WalkControl::Continue => {}
+                WalkControl::SkipSubtree => break,
+                WalkControl::Stop => return false,
+            }
+        }
+
+        visitor.visit(WalkEvent::ExitGroup(self)) != WalkControl::Stop
+    }
+}
+
+impl Database {
+    /// Walk all groups and entries of the database, starting at the root group, see the module
+    /// documentation. Returns `false` if the visitor stopped the walk.
+    pub fn walk(&self, visitor: &mut dyn Visitor) -> bool {
+        self.root.walk(visitor)
+    }
+}
+
+#[cfg(test)]
+mod walk_tests {
+    use crate::db::{Entry, Group, Value};
+
+    use super::{WalkControl, WalkEvent};
+
+    fn entry(title: &str) -> Entry {
+        let mut entry = Entry::new();
+        entry
+            .fields
+            .insert("Title".to_string(), Value::Unprotected(title.to_string()));
+        entry
+    }
+
+    fn walk(root: &Group, control: impl Fn(&WalkEvent) -> WalkControl) -> (Vec<String>, bool) {
+        let mut events = Vec::new();
+        let completed = root.walk(&mut |event: WalkEvent| {
+            events.push(match event {
+                WalkEvent::EnterGroup(g) => format!("> {}", g.name),
+                WalkEvent::ExitGroup(g) => format!("< {}", g.name),
+                WalkEvent::Entry(e) => e.get_title().unwrap().to_string(),
+            });
+            control(&event)
+        });
+        (events, completed)
+    }
+
+    #[test]
+    fn test_walk() {
+        let mut root = Group::new("Root");
+        let mut work = Group::new("Work");
+        work.add_child(entry("vpn"));
+        work.add_child(entry("ci"));
+        root.add_child(work);
+        let mut bin = Group::new("Recycle Bin");
+        bin.add_child(entry("old"));
+        root.add_child(bin);
+        root.add_child(entry("mail"));
+
+        let (events, completed) = walk(&root, |_| WalkControl::Continue);
+        assert!(completed);
+        assert_eq!(
+            events,
+            vec![
+                "> Root",
+                "> Work",
+                "vpn",
+                "ci",
+                "< Work",
+                "> Recycle Bin",
+                "old",
+                "< Recycle Bin",
+                "mail",
+                "< Root"
+            ]
+        );
+
+        let (events, _) = walk(&root, |event| match event {
+            WalkEvent::EnterGroup(g) if g.name == "Recycle Bin" => WalkControl::SkipSubtree,
+            WalkEvent::Entry(e) if e.get_title() == Some("vpn") => WalkControl::SkipSubtree,
+            _ => WalkControl::Continue,
+        });
+        assert_eq!(
+            events,
+            vec![
+                "> Root",
+                "> Work",
+                "vpn",
+                "< Work",
+                "> Recycle Bin",
+                "mail",
+                "< Root"
+            ]
+        );
+
+        let (events, completed) = walk(&root, |event| match event {
+            WalkEvent::Entry(e) if e.get_title() == Some("ci") => WalkControl::Stop,
+            _ => WalkControl::Continue,
+        });
+        assert!(!completed);
+        assert_eq!(events, vec!["> Root", "> Work", "vpn", "ci"]);
+    }
+}
@@ -0,0 +1,247 @@
+//! Non-fatal issues found while opening a database
+//!
+//! Problems that do not prevent a database from being opened would otherwise be invisible to the
+//! user. `Database::parse_with_warnings` returns them next to the database, so that applications
+//! can decide whether to show them.
+
+use uuid::Uuid;
+
+use crate::{
+    config::{InnerCipherConfig, KdfConfig},
+    db::{Database, Group, Node, Times},
+    error::DatabaseOpenError,
+    format::DatabaseVersion,
+    key::DatabaseKey,
+    xml_db::{get_epoch_baseline, parse::collect_ignored_elements},
+};
+
+/// A non-fatal issue found while opening a database
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serialization", derive(serde::Serialize))]
+pub enum ParseWarning {
+    /// XML elements unknown to this library were skipped. Unknown elements of entries, groups and
+    /// the metadata are kept in their `unknown_elements`, only those nested deeper are skipped and
+    /// will be missing when the database is saved.
+    IgnoredElement { name: String, count: usize },
+
+    /// An entry could not be parsed and was quarantined, see `Group::quarantined`
+    QuarantinedEntry { uuid: Option<Uuid>, error: String },
+
+    /// A timestamp of a group or entry is zero (0001-01-01), which some clients write instead of
+    /// leaving it out
+    ZeroTimestamp { uuid: Uuid, field: String },
+
+    /// The database uses a setting that is considered outdated
+    Deprecated { setting: String },
+
+    /// The database uses a setting that other KeePass clients may not support
+    Interoperability { setting: String },
+
+    /// The file contains data after the end of the database, see `Database::trailing_data`
+    TrailingData { len: usize },
+}
+
+impl std::fmt::Display for ParseWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseWarning::IgnoredElement { name, count } => {
+                write!(f, "Ignored {} unknown <{}> element(s)", count, name)
+            }
+            ParseWarning::QuarantinedEntry {
+                uuid: Some(uuid),
+                error,
+            } => {
+                write!(f, "Quarantined entry {}: {}", uuid, error)
+            }
+            ParseWarning::QuarantinedEntry { uuid: None, error } => {
+                write!(f, "Quarantined entry without UUID: {}", error)
+            }
+            ParseWarning::ZeroTimestamp { uuid, field } => {
+                write!(f, "{} of {} is zero", field, uuid)
+            }
+            ParseWarning::Deprecated { setting } => write!(f, "Deprecated setting: {}", setting),
+            ParseWarning::Interoperability { setting } => {
+                write!(f, "Setting not supported by all clients: {}", setting)
+            }
+            ParseWarning::TrailingData { len } => {
+                write!(f, "{} bytes of data after the end of the database", len)
+            }
+        }
+    }
+}
+
+/// A parsed database together with the non-fatal issues found while parsing it
+#[derive(Debug)]
+pub struct ParseOutcome {
+    pub db: Database,
+    pub warnings: Vec<ParseWarning>,
+}
+
+impl Database {
+    /// Parse a database from a std::io::Read, also returning non-fatal issues
+    pub fn open_with_warnings(
+        source: &mut dyn std::io::Read,
+        key: DatabaseKey,
+    ) -> Result<ParseOutcome, DatabaseOpenError> {
+        let mut data = Vec::new();
+        source.read_to_end(&mut data)?;
+
+        Database::parse_with_warnings(data.as_ref(), key)
+    }
+
+    /// Parse a database, also returning non-fatal issues
+    pub fn parse_with_warnings(data: &[u8], key: DatabaseKey) -> Result<ParseOutcome, DatabaseOpenError> {
+        let (db, ignored_elements) = collect_ignored_elements(|| Database::parse(data, key));
+        let db = db?;
+
+        let mut warnings = Vec::new();
+
+        let mut ignored_counts: Vec<(String, usize)> = Vec::new();
+        for name in ignored_elements {
+            match ignored_counts.iter_mut().find(|(n, _)| *n == name) {
+                Some((_, count)) => *count += 1,
+                None => ignored_counts.push((name, 1)),
+            }
+        }
+        warnings.extend(
+            ignored_counts
+                .into_iter()
+                .map(|(name, count)| ParseWarning::IgnoredElement { name, count }),
+        );
+
+        deprecated_settings(&db, &mut warnings);
+        group_warnings(&db.root, &mut warnings);
+
+        if !db.trailing_data.is_empty() {
+            warnings.push(ParseWarning::TrailingData {
+                len: db.trailing_data.len(),
+            });
+        }
+
+        Ok(ParseOutcome { db, warnings })
+    }
+}
+
+fn deprecated_settings(db: &Database, warnings: &mut Vec<ParseWarning>) {
+    let mut deprecated = |setting: &str| {
+        warnings.push(ParseWarning::Deprecated {
+            setting: setting.to_string(),
+        })
+    };
+
+    match db.config.version {
+        DatabaseVersion::KDB(_) | DatabaseVersion::KDB2(_) => deprecated("KDB file format"),
+        DatabaseVersion::KDB3(_) => deprecated("KDBX 3 file format"),
+        DatabaseVersion::KDB4(_) => {}
+    }
+
+    match db.config.inner_cipher_config {
+        InnerCipherConfig::Plain => deprecated("Unencrypted protected values"),
+        InnerCipherConfig::Salsa20 => deprecated("Salsa20 inner stream cipher"),
+        InnerCipherConfig::ChaCha20 => {}
+        InnerCipherConfig::ArcFourVariant => deprecated("ArcFourVariant inner stream cipher"),
+    }
+
+    if let KdfConfig::Aes { .. } = db.config.kdf_config {
+        deprecated("AES-KDF key derivation");
+    }
+
+    let secret_parameters = &db.config.argon2_secret_parameters;
+    if secret_parameters.secret().is_some() {
+        warnings.push(ParseWarning::Interoperability {
+            setting: "Argon2 secret key".to_string(),
+        });
+    }
+    if secret_parameters.associated_data().is_some() {
+        warnings.push(ParseWarning::Interoperability {
+            setting: "Argon2 associated data".to_string(),
+        });
+    }
+}
+
+fn group_warnings(group: &Group, warnings: &mut Vec<ParseWarning>) {
+    zero_timestamps(group.uuid, &group.times, warnings);
+
+    for quarantined in &group.quarantined {
+        warnings.push(ParseWarning::QuarantinedEntry {
+            uuid: quarantined.uuid,
+            error: quarantined.error.clone(),
+        });
+    }
+
+    for node in &group.children {
+        match node {
+            Node::Group(g) => group_warnings(g, warnings),
+            Node::Entry(e) => zero_timestamps(e.uuid, &e.times, warnings),
+        }
+    }
+}
+
+fn zero_timestamps(uuid: Uuid, times: &Times, warnings: &mut Vec<ParseWarning>) {
+    let zero = get_epoch_baseline();
+
+    let mut fields: Vec<&String> = times
+        .times
+        .iter()
+        .filter(|(_, time)| **time == zero)
+        .map(|(field, _)| field)
+        .collect();
+    fields.sort();
+
+    for field in fields {
+        warnings.push(ParseWarning::ZeroTimestamp {
+            uuid,
+            field: field.clone(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod warnings_tests {
+    use std::fs::File;
+
+    use crate::{db::Database, key::DatabaseKey};
+
+    use super::ParseWarning;
+
+    #[test]
+    fn test_parse_with_warnings() {
+        let path = std::path::Path::new("tests/resources/test_db_with_password.kdbx");
+        let outcome = Database::open_with_warnings(
+            &mut File::open(path).unwrap(),
+            DatabaseKey::new().with_password("demopass"),
+        )
+        .unwrap();
+
+        assert_eq!(outcome.db.root.name, "sample");
+        assert!(outcome.warnings.contains(&ParseWarning::Deprecated {
+            setting: "KDBX 3 file format".to_string()
+        }));
+        assert!(outcome
+            .warnings
+            .iter()
+            .all(|w| !matches!(w, ParseWarning::TrailingData { .. })));
+    }
+
+    #[test]
+    fn test_ignored_elements() {
+        use crate::{
+            crypt::ciphers::PlainCipher,
+            db::Group,
+            xml_db::parse::{collect_ignored_elements, parse_from_bytes},
+        };
+
+        let xml = "<Group><Name>Root</Name><Entry><AutoType><FutureFlag>True</FutureFlag>\
+                   <FutureField><Nested/></FutureField><FutureField/></AutoType></Entry></Group>";
+        let mut cipher = PlainCipher::new(&[]).unwrap();
+        let (group, ignored) =
+            collect_ignored_elements(|| parse_from_bytes::<Group>(xml.as_bytes(), &mut cipher));
+
+        assert_eq!(group.unwrap().name, "Root");
+        assert_eq!(ignored, vec!["FutureFlag", "FutureField", "FutureField"]);
+
+        // nothing is recorded outside of collect_ignored_elements
+        let (_, ignored) = collect_ignored_elements(|| ());
+        assert!(ignored.is_empty());
+    }
+}
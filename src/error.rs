@@ -6,21 +6,63 @@ use thiserror::Error;
 pub use crate::db::otp::TOTPError;
 
 /// Errors upon reading a Database
+///
+/// A wrong password or key file, a damaged file and a file that needs a newer version of this
+/// library are told apart by the variant:
+///
+/// ```
+/// use keepass::{error::DatabaseOpenError, Database};
+///
+/// match Database::open_path("tests/resources/test_db_with_password.kdbx", Some("wrong"), None) {
+///     Err(DatabaseOpenError::InvalidCredentials) => println!("Wrong password or key file"),
+///     Err(DatabaseOpenError::CorruptHeader { .. } | DatabaseOpenError::DatabaseIntegrity(_)) => {
+///         println!("The file is damaged")
+///     }
+///     Err(DatabaseOpenError::UnsupportedFeature(_) | DatabaseOpenError::IncompatibleVersion { .. }) => {
+///         println!("The file needs a newer version of this app")
+///     }
+///     Err(e) => println!("{}", e),
+///     Ok(_) => unreachable!(),
+/// }
+/// ```
 #[derive(Debug, Error)]
 pub enum DatabaseOpenError {
     /// An I/O error has occurred while reading the database
     #[error(transparent)]
     Io(#[from] std::io::Error),
 
-    /// An error with the database's key has occurred
+    /// An error with the database's key has occurred, e.g. a key file that cannot be read
     #[error(transparent)]
-    Key(#[from] DatabaseKeyError),
+    Key(DatabaseKeyError),
+
+    /// The key does not open the database, because of a wrong password or key file. As the header
+    /// is authenticated with the key, a header that was tampered with is reported the same way.
+    #[error("Invalid credentials")]
+    InvalidCredentials,
+
+    /// A field of the outer header is missing or has an invalid value
+    #[error("Corrupt header: missing or invalid {}", field)]
+    CorruptHeader { field: String },
+
+    /// The database uses a cipher, key derivation or compression that this library does not know
+    #[error("Unsupported feature: {}", _0)]
+    UnsupportedFeature(String),
+
+    /// The database has a file format version that this library cannot read
+    #[error("Incompatible database version {} (supported: {})", found, supported)]
+    IncompatibleVersion { found: String, supported: &'static str },
 
     /// The database is corrupted
     #[error(transparent)]
-    DatabaseIntegrity(#[from] DatabaseIntegrityError),
+    DatabaseIntegrity(DatabaseIntegrityError),
+
+    /// Opening the database was cancelled, see `crate::progress`
+    #[error("Cancelled")]
+    Cancelled,
 
-    /// The database version cannot be read by this library
+    /// The database version cannot be read by this library. This is no longer returned, see
+    /// `DatabaseOpenError::IncompatibleVersion` and `DatabaseOpenError::UnsupportedFeature`.
+    #[deprecated(note = "never returned, use `DatabaseOpenError::IncompatibleVersion`")]
     #[error("Opening this database version is not supported")]
     UnsupportedVersion,
 }
@@ -138,6 +180,11 @@ pub enum DatabaseSaveError {
     #[error("Saving this database version is not supported")]
     UnsupportedVersion,
 
+    /// A setting of the database cannot be stored in its version of the file format, e.g. an
+    /// Argon2 key derivation in a KDBX 3 database
+    #[error("{0} cannot be saved in this database version")]
+    UnsupportedSetting(String),
+
     /// Error while writing out the inner XML database
     #[error("Error while generating XML")]
     Xml(#[from] xml::writer::Error),
@@ -152,11 +199,20 @@ pub enum DatabaseSaveError {
 
     /// A cryptography error occurred while writing the database
     #[error(transparent)]
-    Cryptography(#[from] CryptographyError),
+    Cryptography(CryptographyError),
 
     /// An error getting randomness for keys occurred
     #[error(transparent)]
     Random(#[from] getrandom::Error),
+
+    /// Attachments are kept in a blob store and need to be loaded back before saving, see
+    /// `Database::save_with_blob_store`
+    #[error("The database has attachments that are kept in a blob store")]
+    ExternalAttachments,
+
+    /// Saving the database was cancelled, see `crate::progress`
+    #[error("Cancelled")]
+    Cancelled,
 }
 
 /// Errors related to the database key
@@ -182,6 +238,15 @@ pub enum DatabaseKeyError {
     #[error("Could not obtain a key from the keyfile")]
     InvalidKeyFile,
 
+    /// The hash in an XML keyfile of version 2.0 does not match its key, e.g. because of a typo
+    /// in a keyfile that was typed in from a printout
+    #[error("The hash in the keyfile does not match its key")]
+    KeyFileHashMismatch,
+
+    /// Could not get the key of the Windows user account
+    #[error("Error with the Windows user account key: {0}")]
+    UserAccountKeyError(String),
+
     /// Could not get challenge response key.
     #[error("Error with the challenge-response key: {0}")]
     ChallengeResponseKeyError(String),
@@ -242,6 +307,19 @@ pub enum CryptographyError {
 
     #[error(transparent)]
     Argon2(#[from] argon2::Error),
+
+    /// The outer cipher is not built in, and no cipher is registered for it with
+    /// `register_outer_cipher` or it is not supported in this format version
+    #[error("Unsupported outer cipher: {}", uuid)]
+    UnsupportedOuterCipher { uuid: uuid::Uuid },
+
+    /// The KDF of the database is neither built in nor registered with `register_kdf`
+    #[error("Unsupported key derivation function: {}", uuid)]
+    UnsupportedKdf { uuid: uuid::Uuid },
+
+    /// The key derivation was cancelled, see `crate::progress`
+    #[error("Cancelled")]
+    Cancelled,
 }
 
 /// Errors reading from the HMAC block stream
@@ -325,6 +403,45 @@ pub struct ParseColorError(pub String);
 #[cfg(not(tarpaulin_include))]
 mod conversions {
     use super::*;
+    use crate::format::{KEEPASS_LATEST_ID, SUPPORTED_VERSIONS};
+
+    impl From<DatabaseKeyError> for DatabaseOpenError {
+        fn from(e: DatabaseKeyError) -> Self {
+            match e {
+                DatabaseKeyError::IncorrectKey => DatabaseOpenError::InvalidCredentials,
+                e => DatabaseOpenError::Key(e),
+            }
+        }
+    }
+
+    impl From<DatabaseIntegrityError> for DatabaseOpenError {
+        fn from(e: DatabaseIntegrityError) -> Self {
+            match e {
+                // a KDBX file of a major version that did not exist yet when this was written
+                DatabaseIntegrityError::InvalidKDBXVersion {
+                    version: KEEPASS_LATEST_ID,
+                    file_major_version,
+                    file_minor_version,
+                } => DatabaseOpenError::IncompatibleVersion {
+                    found: format!("KDBX{}.{}", file_major_version, file_minor_version),
+                    supported: SUPPORTED_VERSIONS,
+                },
+                DatabaseIntegrityError::Cryptography(CryptographyError::Cancelled) => {
+                    DatabaseOpenError::Cancelled
+                }
+                e => DatabaseOpenError::DatabaseIntegrity(e),
+            }
+        }
+    }
+
+    impl From<CryptographyError> for DatabaseSaveError {
+        fn from(e: CryptographyError) -> Self {
+            match e {
+                CryptographyError::Cancelled => DatabaseSaveError::Cancelled,
+                e => DatabaseSaveError::Cryptography(e),
+            }
+        }
+    }
 
     impl From<CryptographyError> for DatabaseOpenError {
         fn from(e: CryptographyError) -> Self {
@@ -0,0 +1,356 @@
+//! Export of entries to secrets managers and other password managers
+//!
+//! Exports for other password managers live in submodules, e.g. `csv` for the CSV layout of
+//! KeePassXC. The rest of this module maps entries to secrets managers.
+//!
+//! Every entry becomes one secret, addressed by the path of its group and its title, e.g.
+//! `infra/databases/postgres`. The secret holds the fields of the entry as key-value pairs, with
+//! the standard fields renamed to `username`, `password`, `url` and `notes`. The title is only
+//! used for the path.
+//!
+//! Two layouts are supported:
+//! * HashiCorp Vault KV: `export_to_vault` hands every secret to a callback that writes it to
+//!   Vault, so that the transport and authentication stay with the caller.
+//! * SOPS: `export_to_sops_yaml` builds a YAML document with one mapping per secret, where every
+//!   value is encrypted individually by a callback. The `sops` metadata block is left to the
+//!   caller.
+//!
+//! User names and URLs can be redacted with `SecretsExportOptions::redaction`, e.g. to share an
+//! export for debugging.
+
+pub mod csv;
+
+use std::collections::{BTreeMap, HashSet};
+
+use uuid::Uuid;
+use zeroize::Zeroize;
+
+use crate::{
+    db::{with_audit_context, Database, Entry, Filter, Group, Value},
+    redact::RedactionProfile,
+};
+
+/// Settings for exporting entries as secrets
+#[derive(Debug, Clone, Default)]
+pub struct SecretsExportOptions {
+    /// Prepended to the path of every secret, e.g. `secret/keepass`
+    pub prefix: String,
+
+    /// Only export fields that are protected in memory, e.g. the password
+    pub protected_only: bool,
+
+    /// Names of fields that are not exported
+    pub exclude_fields: Vec<String>,
+
+    /// Only export the entries matching this filter
+    pub filter: Filter,
+
+    /// How user names and URLs are redacted. Nothing is redacted by default.
+    pub redaction: RedactionProfile,
+}
+
+/// An entry mapped to a secret
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExportedSecret {
+    /// The entry the secret was created from
+    pub uuid: Uuid,
+
+    /// Path of the secret, made unique by appending the entry UUID if needed
+    pub path: String,
+
+    /// Fields of the entry. The values are wiped from memory when the secret is dropped.
+    pub data: BTreeMap<String, String>,
+}
+
+impl Drop for ExportedSecret {
+    fn drop(&mut self) {
+        for value in self.data.values_mut() {
+            value.zeroize();
+        }
+    }
+}
+
+/// Map every entry of the database to a secret, in depth-first order. Entries without any
+/// exported field are skipped.
+pub fn secrets(db: &Database, options: &SecretsExportOptions) -> Vec<ExportedSecret> {
+    let mut out = Vec::new();
+    let mut used_paths = HashSet::new();
+    let selected: HashSet<Uuid> = options.filter.select_uuids(&db.root).into_iter().collect();
+
+    with_audit_context("secrets export", || {
+        let prefix = options.prefix.trim_matches('/').to_string();
+        collect_group(&db.root, &prefix, options, &selected, &mut used_paths, &mut out)
+    });
+
+    out
+}
+
+/// Export every entry as a HashiCorp Vault KV secret. `write` is called with every secret and
+/// should store `data` at `path`. Returns the number of secrets written, or the first error
+/// returned by `write`.
+pub fn export_to_vault<E>(
+    db: &Database,
+    options: &SecretsExportOptions,
+    mut write: impl FnMut(&ExportedSecret) -> Result<(), E>,
+) -> Result<usize, E> {
+    let secrets = secrets(db, options);
+    for secret in &secrets {
+        write(secret)?;
+    }
+    Ok(secrets.len())
+}
+
+/// Export every entry as a SOPS YAML document. `encrypt` is called with every secret, field name
+/// and field value, and returns the encrypted value, usually of the form `ENC[AES256_GCM,...]`.
+/// Keys are kept in plain text, as SOPS does.
+pub fn export_to_sops_yaml<E>(
+    db: &Database,
+    options: &SecretsExportOptions,
+    mut encrypt: impl FnMut(&ExportedSecret, &str, &str) -> Result<String, E>,
+) -> Result<String, E> {
+    let mut yaml = String::new();
+
+    for secret in secrets(db, options) {
+        yaml.push_str(&format!("{}:\n", yaml_string(&secret.path)));
+        for (key, value) in &secret.data {
+            let encrypted = encrypt(&secret, key, value)?;
+            yaml.push_str(&format!(
+                "    {}: {}\n",
+                yaml_string(key),
+                yaml_string(&encrypted)
+            ));
+        }
+    }
+
+    Ok(yaml)
+}
+
+fn collect_group(
+    group: &Group,
+    path: &str,
+    options: &SecretsExportOptions,
+    selected: &HashSet<Uuid>,
+    used_paths: &mut HashSet<String>,
+    out: &mut Vec<ExportedSecret>,
+) {
+    for entry in group.entries() {
+        if !selected.contains(&entry.uuid) {
+            continue;
+        }
+
+        let data = secret_data(entry, options);
+        if data.is_empty() {
+            continue;
+        }
+
+        let name =
+            path_segment(entry.get_title().unwrap_or_default()).unwrap_or_else(|| entry.uuid.to_string());
+        let mut secret_path = join_path(path, &name);
+        if !used_paths.insert(secret_path.clone()) {
+            secret_path = format!("{}-{}", secret_path, entry.uuid);
+            used_paths.insert(secret_path.clone());
+        }
+
+        out.push(ExportedSecret {
+            uuid: entry.uuid,
+            path: secret_path,
+            data,
+        });
+    }
+
+    for child in group.groups() {
+        let name = path_segment(&child.name).unwrap_or_else(|| child.uuid.to_string());
+        collect_group(child, &join_path(path, &name), options, selected, used_paths, out);
+    }
+}
+
+fn secret_data(entry: &Entry, options: &SecretsExportOptions) -> BTreeMap<String, String> {
+    let mut data = BTreeMap::new();
+
+    for (name, value) in &entry.fields {
+        if name == "Title" || options.exclude_fields.contains(name) {
+            continue;
+        }
+
+        match value {
+            Value::Protected(_) => {}
+            Value::Unprotected(_) if !options.protected_only => {}
+            _ => continue,
+        }
+
+        let value = entry.get_protected(name);
+        let value = match value.as_ref().and_then(|v| v.as_str()) {
+            Some(v) if !v.is_empty() => v,
+            _ => continue,
+        };
+
+        let key = match name.as_str() {
+            "UserName" => "username",
+            "Password" => "password",
+            "URL" => "url",
+            "Notes" => "notes",
+            other => other,
+        };
+        let value = options.redaction.redact_field(name, value);
+        data.insert(key.to_string(), value.into_owned());
+    }
+
+    data
+}
+
+/// Turn a group name or title into a path segment, replacing separators and whitespace
+fn path_segment(name: &str) -> Option<String> {
+    let segment: String = name
+        .trim()
+        .chars()
+        .map(|c| {
+            if c == '/' || c == '\\' || c.is_whitespace() {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect();
+
+    if segment.is_empty() || segment == "." || segment == ".." {
+        None
+    } else {
+        Some(segment)
+    }
+}
+
+fn join_path(parent: &str, name: &str) -> String {
+    if parent.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}/{}", parent, name)
+    }
+}
+
+/// Format a string as a double-quoted YAML scalar
+fn yaml_string(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod export_tests {
+    use crate::{
+        db::{Database, Entry, Filter, Group, Value},
+        redact::RedactionProfile,
+    };
+
+    use super::{export_to_sops_yaml, export_to_vault, secrets, SecretsExportOptions};
+
+    fn test_database() -> Database {
+        let mut db = Database::new(Default::default());
+
+        let mut group = Group::new("Data bases");
+        for (user, password) in [("admin", "s3cret"), ("backup", "hunter2")] {
+            let mut entry = Entry::new();
+            entry
+                .fields
+                .insert("Title".to_string(), Value::Unprotected("postgres".to_string()));
+            entry
+                .fields
+                .insert("UserName".to_string(), Value::Unprotected(user.to_string()));
+            entry.fields.insert(
+                "Password".to_string(),
+                Value::Protected(password.as_bytes().into()),
+            );
+            group.add_child(entry);
+        }
+        db.root.add_child(group);
+
+        // entries without any field to export are skipped
+        db.root.add_child(Entry::new());
+
+        db
+    }
+
+    #[test]
+    fn test_vault_export() {
+        let db = test_database();
+        let options = SecretsExportOptions {
+            prefix: "secret/keepass/".to_string(),
+            ..Default::default()
+        };
+
+        let mut written = Vec::new();
+        let count = export_to_vault(&db, &options, |secret| -> Result<(), ()> {
+            written.push((secret.path.clone(), secret.data.clone()));
+            Ok(())
+        })
+        .unwrap();
+
+        assert_eq!(count, 2);
+        assert_eq!(written[0].0, "secret/keepass/Data_bases/postgres");
+        assert_eq!(written[0].1["username"], "admin");
+        assert_eq!(written[0].1["password"], "s3cret");
+        assert!(!written[0].1.contains_key("Title"));
+
+        // the second entry with the same title gets a unique path
+        let uuid = db.root.groups()[0].entries()[1].uuid;
+        assert_eq!(
+            written[1].0,
+            format!("secret/keepass/Data_bases/postgres-{}", uuid)
+        );
+
+        let failed = export_to_vault(&db, &options, |_| Err("unreachable"));
+        assert_eq!(failed, Err("unreachable"));
+
+        let options = SecretsExportOptions {
+            filter: Filter::field_contains("UserName", "backup"),
+            ..Default::default()
+        };
+        let selected = secrets(&db, &options);
+        assert_eq!(selected.len(), 1);
+        assert_eq!(selected[0].uuid, uuid);
+
+        let options = SecretsExportOptions {
+            redaction: RedactionProfile::screenshot_safe(),
+            ..Default::default()
+        };
+        let redacted = secrets(&db, &options);
+        assert_eq!(redacted[0].data["username"], "ad…");
+        assert_eq!(redacted[0].data["password"], "s3cret");
+    }
+
+    #[test]
+    fn test_sops_export() {
+        let db = test_database();
+        let options = SecretsExportOptions {
+            protected_only: true,
+            ..Default::default()
+        };
+        assert_eq!(secrets(&db, &options)[0].data.len(), 1);
+
+        let yaml = export_to_sops_yaml(&db, &options, |_, key, value| -> Result<String, ()> {
+            Ok(format!("ENC[{}:{}]", key, value.len()))
+        })
+        .unwrap();
+
+        let uuid = db.root.groups()[0].entries()[1].uuid;
+        assert_eq!(
+            yaml,
+            format!(
+                "\"Data_bases/postgres\":\n    \"password\": \"ENC[password:6]\"\n\
+                 \"Data_bases/postgres-{}\":\n    \"password\": \"ENC[password:7]\"\n",
+                uuid
+            )
+        );
+    }
+}
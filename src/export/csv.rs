@@ -0,0 +1,191 @@
+//! Export of entries as CSV in the layout of KeePassXC
+//!
+//! Every entry becomes one line with the columns of `KEEPASSXC_COLUMNS`. The group column holds
+//! the path of the group of the entry including the root group, separated by `/`, e.g.
+//! `Root/Internet/Email`. Timestamps are written in UTC as `2024-02-03T04:05:06Z`. Entries in
+//! the recycle bin are left out, as KeePassXC does.
+//!
+//! The export holds all passwords in plain text, so it should be handled with care:
+//!
+//! ```
+//! use keepass::{db::Entry, export::csv::export_csv, Database};
+//!
+//! let mut db = Database::new(Default::default());
+//! db.root.add_child(Entry::new().with_title("GMail").with_username("alice"));
+//!
+//! let mut out = Vec::new();
+//! export_csv(&db, &mut out)?;
+//! let csv = String::from_utf8(out).unwrap();
+//! assert!(csv.starts_with("\"Group\",\"Title\",\"Username\","));
+//! # Ok::<(), std::io::Error>(())
+//! ```
+
+use std::io::Write;
+
+use chrono::NaiveDateTime;
+use zeroize::Zeroizing;
+
+use crate::db::{Database, Entry, Group, ProtectedGuard};
+
+/// The columns of a CSV export of KeePassXC, in order
+pub const KEEPASSXC_COLUMNS: [&str; 10] = [
+    "Group",
+    "Title",
+    "Username",
+    "Password",
+    "URL",
+    "Notes",
+    "TOTP",
+    "Icon",
+    "Last Modified",
+    "Created",
+];
+
+/// The format of timestamps in CSV exports
+pub(crate) const CSV_TIME_FORMAT: &str = "%Y-%m-%dT%H:%M:%SZ";
+
+/// Write all entries of the database as CSV with a header line, in depth-first order
+pub fn export_csv(db: &Database, writer: &mut dyn Write) -> Result<(), std::io::Error> {
+    write_line(writer, KEEPASSXC_COLUMNS.iter().copied())?;
+
+    let recycle_bin = match db.meta.recyclebin_enabled {
+        Some(false) => None,
+        _ => db.meta.recyclebin_uuid,
+    };
+    export_group(&db.root, &db.root.name, recycle_bin, writer)
+}
+
+fn export_group(
+    group: &Group,
+    path: &str,
+    recycle_bin: Option<uuid::Uuid>,
+    writer: &mut dyn Write,
+) -> Result<(), std::io::Error> {
+    for entry in group.entries() {
+        export_entry(entry, path, writer)?;
+    }
+
+    for child in group.groups() {
+        if Some(child.uuid) == recycle_bin {
+            continue;
+        }
+        export_group(child, &format!("{}/{}", path, child.name), recycle_bin, writer)?;
+    }
+
+    Ok(())
+}
+
+fn export_entry(entry: &Entry, path: &str, writer: &mut dyn Write) -> Result<(), std::io::Error> {
+    let icon = entry.icon_id.unwrap_or(0).to_string();
+    let modified = format_time(entry.times.get_last_modification());
+    let created = format_time(entry.times.get_creation());
+
+    let field = |name: &str| entry.get_protected(name);
+    let (title, username, password, url, notes, otp) = (
+        field("Title"),
+        field("UserName"),
+        field("Password"),
+        field("URL"),
+        field("Notes"),
+        field("otp"),
+    );
+    fn text(guard: &Option<ProtectedGuard>) -> &str {
+        guard.as_ref().and_then(|g| g.as_str()).unwrap_or_default()
+    }
+
+    write_line(
+        writer,
+        [
+            path,
+            text(&title),
+            text(&username),
+            text(&password),
+            text(&url),
+            text(&notes),
+            text(&otp),
+            &icon,
+            &modified,
+            &created,
+        ],
+    )
+}
+
+fn format_time(time: Option<&NaiveDateTime>) -> String {
+    time.map(|t| t.format(CSV_TIME_FORMAT).to_string())
+        .unwrap_or_default()
+}
+
+/// Write one line with every field quoted, as KeePassXC does
+fn write_line<'a>(
+    writer: &mut dyn Write,
+    fields: impl IntoIterator<Item = &'a str>,
+) -> Result<(), std::io::Error> {
+    let mut line = Zeroizing::new(String::new());
+    for (i, field) in fields.into_iter().enumerate() {
+        if i > 0 {
+            line.push(',');
+        }
+        line.push('"');
+        line.push_str(&Zeroizing::new(field.replace('"', "\"\"")));
+        line.push('"');
+    }
+    line.push('\n');
+    writer.write_all(line.as_bytes())
+}
+
+#[cfg(test)]
+mod csv_export_tests {
+    use chrono::NaiveDate;
+    use uuid::Uuid;
+
+    use super::export_csv;
+    use crate::db::{Database, Entry, Group, Value};
+
+    #[test]
+    fn test_export_csv() {
+        let time = NaiveDate::from_ymd_opt(2024, 2, 3)
+            .unwrap()
+            .and_hms_opt(4, 5, 6)
+            .unwrap();
+
+        let mut db = Database::new(Default::default());
+        let mut entry = Entry::new()
+            .with_title("GMail")
+            .with_username("alice")
+            .with_password("say \"hi\"")
+            .with_url("https://mail.google.com")
+            .with_notes("first line\nsecond line")
+            .with_field(
+                "otp",
+                Value::Protected("otpauth://totp/GMail?secret=JBSWY3DPEHPK3PXP".as_bytes().into()),
+            );
+        entry.icon_id = Some(19);
+        entry.times.set_creation(time);
+        entry.times.set_last_modification(time);
+        db.root.add_child(Group::new("Internet").with_child(entry));
+
+        let mut trash = Group::new("Recycle Bin");
+        trash.add_child(Entry::new().with_title("deleted"));
+        db.meta.recyclebin_uuid = Some(trash.uuid);
+        db.meta.recyclebin_enabled = Some(true);
+        db.root.add_child(trash);
+
+        let mut out = Vec::new();
+        export_csv(&db, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "\"Group\",\"Title\",\"Username\",\"Password\",\"URL\",\"Notes\",\"TOTP\",\"Icon\",\"Last Modified\",\"Created\"\n\
+             \"Root/Internet\",\"GMail\",\"alice\",\"say \"\"hi\"\"\",\"https://mail.google.com\",\
+             \"first line\nsecond line\",\"otpauth://totp/GMail?secret=JBSWY3DPEHPK3PXP\",\"19\",\
+             \"2024-02-03T04:05:06Z\",\"2024-02-03T04:05:06Z\"\n"
+        );
+
+        // once it is no longer the recycle bin, the group is exported like any other
+        db.meta.recyclebin_uuid = Some(Uuid::nil());
+        let mut out = Vec::new();
+        export_csv(&db, &mut out).unwrap();
+        assert!(String::from_utf8(out)
+            .unwrap()
+            .contains("\"Root/Recycle Bin\",\"deleted\""));
+    }
+}
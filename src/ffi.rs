@@ -0,0 +1,607 @@
+//! C interface for using the crate from other languages
+//!
+//! With the `ffi` feature, the library built as `cdylib` exports functions with a stable C ABI,
+//! declared in `include/keepass.h`, so that e.g. Swift or Kotlin apps can open, search, edit and
+//! save databases:
+//!
+//! ```c
+//! KpDatabase *db = NULL;
+//! if (kp_database_open("vault.kdbx", "demopass", NULL, &db) != KP_OK) {
+//!     fprintf(stderr, "%s\n", kp_last_error());
+//!     return 1;
+//! }
+//!
+//! KpEntryList *found = NULL;
+//! kp_database_search(db, "mail", &found);
+//! for (size_t i = 0; i < kp_entry_list_len(found); i++) {
+//!     uint8_t uuid[16];
+//!     char *title = NULL;
+//!     kp_entry_list_uuid(found, i, uuid);
+//!     if (kp_entry_get_field(db, uuid, "Title", &title) == KP_OK) {
+//!         printf("%s\n", title);
+//!         kp_string_free(title);
+//!     }
+//! }
+//! kp_entry_list_free(found);
+//! kp_database_free(db);
+//! ```
+//!
+//! Crates depending on this one only build an `rlib`; the `cdylib` is built with
+//! `cargo rustc --lib --release --features ffi --crate-type cdylib`.
+//!
+//! Databases and entry lists are opaque handles that have to be released with their `_free`
+//! function, as do the strings and buffers returned by the library. Entries are referred to by
+//! their UUID as 16 bytes, which stays valid while the database is edited. Every function returns
+//! a `KpStatus`, and `kp_last_error` describes the last failure on the calling thread. Strings are
+//! UTF-8 and terminated by a zero byte, and optional arguments may be `NULL`.
+
+use std::{
+    cell::RefCell,
+    ffi::{CStr, CString},
+    os::raw::c_char,
+    panic::{catch_unwind, AssertUnwindSafe},
+    ptr,
+};
+
+use uuid::Uuid;
+use zeroize::Zeroize;
+
+use crate::{
+    db::{Database, Filter, MutationOptions, NodeRef, NodeRefMut, SaveOptions, Value},
+    error::{DatabaseOpenError, DatabaseSaveError},
+    key::DatabaseKey,
+};
+
+/// The result of a call through the C interface
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KpStatus {
+    Ok = 0,
+
+    /// An argument was `NULL` or not valid UTF-8
+    InvalidArgument = 1,
+
+    /// No entry has the given UUID, or the entry does not have the field
+    NotFound = 2,
+
+    /// The password or key file is wrong
+    IncorrectKey = 3,
+
+    /// The database is damaged
+    Corrupt = 4,
+
+    /// The database uses a version or setting that is not supported
+    Unsupported = 5,
+
+    /// Reading or writing a file failed
+    Io = 6,
+
+    /// Any other error, including panics inside the library
+    Failed = 7,
+}
+
+/// An opened database
+pub struct KpDatabase(Database);
+
+/// The UUIDs of the entries found in a database
+pub struct KpEntryList(Vec<Uuid>);
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// A failed call, with its status and a description for `kp_last_error`
+struct FfiError(KpStatus, String);
+
+impl FfiError {
+    fn invalid(what: &str) -> Self {
+        FfiError(KpStatus::InvalidArgument, format!("Invalid argument: {}", what))
+    }
+}
+
+impl From<DatabaseOpenError> for FfiError {
+    fn from(e: DatabaseOpenError) -> Self {
+        let status = match &e {
+            DatabaseOpenError::Io(_) => KpStatus::Io,
+            DatabaseOpenError::InvalidCredentials => KpStatus::IncorrectKey,
+            DatabaseOpenError::Key(_) | DatabaseOpenError::Cancelled => KpStatus::Failed,
+            DatabaseOpenError::CorruptHeader { .. } | DatabaseOpenError::DatabaseIntegrity(_) => {
+                KpStatus::Corrupt
+            }
+            DatabaseOpenError::UnsupportedFeature(_) | DatabaseOpenError::IncompatibleVersion { .. } => {
+                KpStatus::Unsupported
+            }
+            #[allow(deprecated)]
+            DatabaseOpenError::UnsupportedVersion => KpStatus::Unsupported,
+        };
+        FfiError(status, e.to_string())
+    }
+}
+
+impl From<DatabaseSaveError> for FfiError {
+    fn from(e: DatabaseSaveError) -> Self {
+        let status = match &e {
+            DatabaseSaveError::Io(_) => KpStatus::Io,
+            DatabaseSaveError::UnsupportedVersion | DatabaseSaveError::UnsupportedSetting(_) => {
+                KpStatus::Unsupported
+            }
+            _ => KpStatus::Failed,
+        };
+        FfiError(status, e.to_string())
+    }
+}
+
+impl From<std::io::Error> for FfiError {
+    fn from(e: std::io::Error) -> Self {
+        FfiError(KpStatus::Io, e.to_string())
+    }
+}
+
+/// Run the body of an exported function, recording its error and turning panics into errors, as
+/// they must not unwind into the caller
+fn call(f: impl FnOnce() -> Result<(), FfiError>) -> KpStatus {
+    let result = catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|_| {
+        Err(FfiError(
+            KpStatus::Failed,
+            "Internal error in the keepass library".to_string(),
+        ))
+    });
+
+    let (status, message) = match result {
+        Ok(()) => (KpStatus::Ok, None),
+        Err(FfiError(status, message)) => (status, CString::new(message.replace('\0', "")).ok()),
+    };
+    LAST_ERROR.with(|e| *e.borrow_mut() = message);
+    status
+}
+
+/// The string behind a pointer that may be `NULL`
+unsafe fn optional_str<'a>(s: *const c_char, what: &str) -> Result<Option<&'a str>, FfiError> {
+    if s.is_null() {
+        return Ok(None);
+    }
+    CStr::from_ptr(s)
+        .to_str()
+        .map(Some)
+        .map_err(|_| FfiError::invalid(what))
+}
+
+/// The string behind a pointer that must not be `NULL`
+unsafe fn required_str<'a>(s: *const c_char, what: &str) -> Result<&'a str, FfiError> {
+    optional_str(s, what)?.ok_or_else(|| FfiError::invalid(what))
+}
+
+unsafe fn database<'a>(db: *mut KpDatabase) -> Result<&'a mut Database, FfiError> {
+    db.as_mut()
+        .map(|db| &mut db.0)
+        .ok_or_else(|| FfiError::invalid("database"))
+}
+
+unsafe fn uuid(uuid: *const u8) -> Result<Uuid, FfiError> {
+    if uuid.is_null() {
+        return Err(FfiError::invalid("uuid"));
+    }
+    let mut bytes = [0u8; 16];
+    ptr::copy_nonoverlapping(uuid, bytes.as_mut_ptr(), bytes.len());
+    Ok(Uuid::from_bytes(bytes))
+}
+
+/// Store a value in an out parameter that must not be `NULL`
+unsafe fn write_out<T>(out: *mut T, value: T, what: &str) -> Result<(), FfiError> {
+    match out.as_mut() {
+        Some(out) => {
+            *out = value;
+            Ok(())
+        }
+        None => Err(FfiError::invalid(what)),
+    }
+}
+
+/// The key made of a password and the key file at a path, both optional
+unsafe fn key(password: *const c_char, keyfile_path: *const c_char) -> Result<DatabaseKey, FfiError> {
+    let mut key = DatabaseKey::new();
+    if let Some(password) = optional_str(password, "password")? {
+        key = key.with_password(password);
+    }
+    if let Some(keyfile_path) = optional_str(keyfile_path, "keyfile_path")? {
+        key = key.with_keyfile(&mut std::fs::File::open(keyfile_path)?)?;
+    }
+    Ok(key)
+}
+
+fn into_c_string(s: &[u8]) -> Result<*mut c_char, FfiError> {
+    CString::new(s)
+        .map(CString::into_raw)
+        .map_err(|_| FfiError(KpStatus::Failed, "The value contains a zero byte".to_string()))
+}
+
+/// Describe the last failed call on this thread. The string belongs to the library and stays
+/// valid until the next call on this thread; it is `NULL` if the last call succeeded.
+#[no_mangle]
+pub extern "C" fn kp_last_error() -> *const c_char {
+    LAST_ERROR.with(|e| e.borrow().as_ref().map_or(ptr::null(), |e| e.as_ptr()))
+}
+
+/// Open the database file at `path`, storing the handle in `out_db`
+///
+/// # Safety
+///
+/// The strings have to be valid and zero-terminated, and `out_db` has to point to writable memory.
+#[no_mangle]
+pub unsafe extern "C" fn kp_database_open(
+    path: *const c_char,
+    password: *const c_char,
+    keyfile_path: *const c_char,
+    out_db: *mut *mut KpDatabase,
+) -> KpStatus {
+    call(|| {
+        let path = required_str(path, "path")?;
+        let key = key(password, keyfile_path)?;
+        let mut file = std::io::BufReader::new(std::fs::File::open(path)?);
+        let db = Database::open(&mut file, key)?;
+        write_out(out_db, Box::into_raw(Box::new(KpDatabase(db))), "out_db")
+    })
+}
+
+/// Open a database from the `len` bytes at `data`, storing the handle in `out_db`
+///
+/// # Safety
+///
+/// `data` has to point to `len` readable bytes, the strings have to be valid and zero-terminated,
+/// and `out_db` has to point to writable memory.
+#[no_mangle]
+pub unsafe extern "C" fn kp_database_open_buffer(
+    data: *const u8,
+    len: usize,
+    password: *const c_char,
+    keyfile_path: *const c_char,
+    out_db: *mut *mut KpDatabase,
+) -> KpStatus {
+    call(|| {
+        if data.is_null() {
+            return Err(FfiError::invalid("data"));
+        }
+        let data = std::slice::from_raw_parts(data, len);
+        let key = key(password, keyfile_path)?;
+        let db = Database::parse(data, key)?;
+        write_out(out_db, Box::into_raw(Box::new(KpDatabase(db))), "out_db")
+    })
+}
+
+/// Save the database to the file at `path`, replacing it atomically
+///
+/// # Safety
+///
+/// `db` has to be a handle from `kp_database_open`, and the strings have to be valid and
+/// zero-terminated.
+#[no_mangle]
+pub unsafe extern "C" fn kp_database_save(
+    db: *mut KpDatabase,
+    path: *const c_char,
+    password: *const c_char,
+    keyfile_path: *const c_char,
+) -> KpStatus {
+    call(|| {
+        let db = database(db)?;
+        let path = required_str(path, "path")?;
+        let key = key(password, keyfile_path)?;
+        db.save_to_path(path, key, &SaveOptions::default())?;
+        Ok(())
+    })
+}
+
+/// Save the database into a new buffer, storing it in `out_data` and its length in `out_len`. The
+/// buffer has to be released with `kp_buffer_free`.
+///
+/// # Safety
+///
+/// `db` has to be a handle from `kp_database_open`, the strings have to be valid and
+/// zero-terminated, and the out parameters have to point to writable memory.
+#[no_mangle]
+pub unsafe extern "C" fn kp_database_save_buffer(
+    db: *mut KpDatabase,
+    password: *const c_char,
+    keyfile_path: *const c_char,
+    out_data: *mut *mut u8,
+    out_len: *mut usize,
+) -> KpStatus {
+    call(|| {
+        let db = database(db)?;
+        if out_data.is_null() || out_len.is_null() {
+            return Err(FfiError::invalid("out_data"));
+        }
+        let key = key(password, keyfile_path)?;
+
+        let mut data = Vec::new();
+        db.save(&mut data, key)?;
+        let len = data.len();
+        write_out(out_len, len, "out_len")?;
+        write_out(
+            out_data,
+            Box::into_raw(data.into_boxed_slice()) as *mut u8,
+            "out_data",
+        )
+    })
+}
+
+/// Release a database handle. Passing `NULL` does nothing.
+///
+/// # Safety
+///
+/// `db` has to be a handle from `kp_database_open` that was not released yet.
+#[no_mangle]
+pub unsafe extern "C" fn kp_database_free(db: *mut KpDatabase) {
+    if !db.is_null() {
+        drop(Box::from_raw(db));
+    }
+}
+
+/// List all entries of the database, in depth-first order
+///
+/// # Safety
+///
+/// `db` has to be a handle from `kp_database_open`, and `out_list` has to point to writable memory.
+#[no_mangle]
+pub unsafe extern "C" fn kp_database_entries(db: *mut KpDatabase, out_list: *mut *mut KpEntryList) -> KpStatus {
+    call(|| {
+        let db = database(db)?;
+        let list = entry_list(db, &Filter::All);
+        write_out(out_list, list, "out_list")
+    })
+}
+
+/// List the entries with a field other than the password containing `text`, ignoring case
+///
+/// # Safety
+///
+/// `db` has to be a handle from `kp_database_open`, `text` has to be valid and zero-terminated,
+/// and `out_list` has to point to writable memory.
+#[no_mangle]
+pub unsafe extern "C" fn kp_database_search(
+    db: *mut KpDatabase,
+    text: *const c_char,
+    out_list: *mut *mut KpEntryList,
+) -> KpStatus {
+    call(|| {
+        let db = database(db)?;
+        let text = required_str(text, "text")?;
+        let list = entry_list(db, &Filter::any_field_contains(text));
+        write_out(out_list, list, "out_list")
+    })
+}
+
+fn entry_list(db: &Database, filter: &Filter) -> *mut KpEntryList {
+    let uuids = db.search(filter).into_iter().map(|e| *e.get_uuid()).collect();
+    Box::into_raw(Box::new(KpEntryList(uuids)))
+}
+
+/// The number of entries in a list, or 0 for `NULL`
+///
+/// # Safety
+///
+/// `list` has to be a list from `kp_database_entries` or `kp_database_search`.
+#[no_mangle]
+pub unsafe extern "C" fn kp_entry_list_len(list: *const KpEntryList) -> usize {
+    list.as_ref().map_or(0, |list| list.0.len())
+}
+
+/// Copy the 16 bytes of the UUID of the entry at `index` in the list to `out_uuid`
+///
+/// # Safety
+///
+/// `list` has to be a list from `kp_database_entries` or `kp_database_search`, and `out_uuid` has
+/// to point to 16 writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn kp_entry_list_uuid(
+    list: *const KpEntryList,
+    index: usize,
+    out_uuid: *mut u8,
+) -> KpStatus {
+    call(|| {
+        let list = list.as_ref().ok_or_else(|| FfiError::invalid("list"))?;
+        let uuid = list
+            .0
+            .get(index)
+            .ok_or_else(|| FfiError(KpStatus::NotFound, format!("No entry at index {}", index)))?;
+        if out_uuid.is_null() {
+            return Err(FfiError::invalid("out_uuid"));
+        }
+        ptr::copy_nonoverlapping(uuid.as_bytes().as_ptr(), out_uuid, 16);
+        Ok(())
+    })
+}
+
+/// Release an entry list. Passing `NULL` does nothing.
+///
+/// # Safety
+///
+/// `list` has to be a list from `kp_database_entries` or `kp_database_search` that was not
+/// released yet.
+#[no_mangle]
+pub unsafe extern "C" fn kp_entry_list_free(list: *mut KpEntryList) {
+    if !list.is_null() {
+        drop(Box::from_raw(list));
+    }
+}
+
+/// Copy the value of a field of an entry, e.g. `Title` or `Password`, into a new string stored in
+/// `out_value`, which has to be released with `kp_string_free`
+///
+/// # Safety
+///
+/// `db` has to be a handle from `kp_database_open`, `uuid` has to point to 16 bytes, `field` has
+/// to be valid and zero-terminated, and `out_value` has to point to writable memory.
+#[no_mangle]
+pub unsafe extern "C" fn kp_entry_get_field(
+    db: *mut KpDatabase,
+    uuid: *const u8,
+    field: *const c_char,
+    out_value: *mut *mut c_char,
+) -> KpStatus {
+    call(|| {
+        let db = database(db)?;
+        let uuid = self::uuid(uuid)?;
+        let field = required_str(field, "field")?;
+
+        let entry = match db.find_by_uuid(&uuid) {
+            Some(NodeRef::Entry(entry)) => entry,
+            _ => {
+                return Err(FfiError(
+                    KpStatus::NotFound,
+                    format!("No entry with UUID {}", uuid),
+                ))
+            }
+        };
+        let value = entry
+            .get_protected(field)
+            .ok_or_else(|| FfiError(KpStatus::NotFound, format!("The entry has no field {}", field)))?;
+        write_out(out_value, into_c_string(&value)?, "out_value")
+    })
+}
+
+/// Set a field of an entry, adding the previous state of the entry to its history. Protected
+/// fields like passwords are kept encrypted in memory and in the saved file.
+///
+/// # Safety
+///
+/// `db` has to be a handle from `kp_database_open`, `uuid` has to point to 16 bytes, and the
+/// strings have to be valid and zero-terminated.
+#[no_mangle]
+pub unsafe extern "C" fn kp_entry_set_field(
+    db: *mut KpDatabase,
+    uuid: *const u8,
+    field: *const c_char,
+    value: *const c_char,
+    protected: bool,
+) -> KpStatus {
+    call(|| {
+        let db = database(db)?;
+        let uuid = self::uuid(uuid)?;
+        let field = required_str(field, "field")?;
+        let value = required_str(value, "value")?;
+
+        let entry = match db.find_by_uuid_mut(&uuid) {
+            Some(NodeRefMut::Entry(entry)) => entry,
+            _ => {
+                return Err(FfiError(
+                    KpStatus::NotFound,
+                    format!("No entry with UUID {}", uuid),
+                ))
+            }
+        };
+        let value = match protected {
+            true => Value::Protected(value.into()),
+            false => Value::Unprotected(value.to_string()),
+        };
+        entry.set_field_with(field, value, &MutationOptions::default());
+        Ok(())
+    })
+}
+
+/// Wipe and release a string returned by the library. Passing `NULL` does nothing.
+///
+/// # Safety
+///
+/// `s` has to be a string from `kp_entry_get_field` that was not released yet.
+#[no_mangle]
+pub unsafe extern "C" fn kp_string_free(s: *mut c_char) {
+    if !s.is_null() {
+        CString::from_raw(s).into_bytes_with_nul().zeroize();
+    }
+}
+
+/// Wipe and release a buffer returned by the library. Passing `NULL` does nothing.
+///
+/// # Safety
+///
+/// `data` and `len` have to be a buffer and its length from `kp_database_save_buffer` that was not
+/// released yet.
+#[no_mangle]
+pub unsafe extern "C" fn kp_buffer_free(data: *mut u8, len: usize) {
+    if !data.is_null() {
+        Box::from_raw(ptr::slice_from_raw_parts_mut(data, len)).zeroize();
+    }
+}
+
+#[cfg(test)]
+mod ffi_tests {
+    use std::{ffi::CStr, ptr};
+
+    use super::*;
+
+    fn c(s: &str) -> CString {
+        CString::new(s).unwrap()
+    }
+
+    #[test]
+    fn test_open_search_edit_save() {
+        let password = c("demopass");
+        let path = c("tests/resources/test_db_with_password.kdbx");
+        let mut db = ptr::null_mut();
+        unsafe {
+            assert_eq!(
+                kp_database_open(path.as_ptr(), c("wrong").as_ptr(), ptr::null(), &mut db),
+                KpStatus::IncorrectKey
+            );
+            assert!(!kp_last_error().is_null());
+            assert_eq!(
+                kp_database_open(path.as_ptr(), password.as_ptr(), ptr::null(), &mut db),
+                KpStatus::Ok
+            );
+            assert!(kp_last_error().is_null());
+
+            let mut list = ptr::null_mut();
+            assert_eq!(
+                kp_database_search(db, c("sample entry #2").as_ptr(), &mut list),
+                KpStatus::Ok
+            );
+            assert_eq!(kp_entry_list_len(list), 1);
+            let mut uuid = [0u8; 16];
+            assert_eq!(kp_entry_list_uuid(list, 0, uuid.as_mut_ptr()), KpStatus::Ok);
+            assert_eq!(kp_entry_list_uuid(list, 1, uuid.as_mut_ptr()), KpStatus::NotFound);
+            kp_entry_list_free(list);
+
+            assert_eq!(
+                kp_entry_set_field(
+                    db,
+                    uuid.as_ptr(),
+                    c("Password").as_ptr(),
+                    c("hunter2").as_ptr(),
+                    true
+                ),
+                KpStatus::Ok
+            );
+            let mut data = ptr::null_mut();
+            let mut len = 0;
+            assert_eq!(
+                kp_database_save_buffer(db, password.as_ptr(), ptr::null(), &mut data, &mut len),
+                KpStatus::Ok
+            );
+            kp_database_free(db);
+
+            let mut db = ptr::null_mut();
+            assert_eq!(
+                kp_database_open_buffer(data, len, password.as_ptr(), ptr::null(), &mut db),
+                KpStatus::Ok
+            );
+            kp_buffer_free(data, len);
+
+            let mut value = ptr::null_mut();
+            assert_eq!(
+                kp_entry_get_field(db, uuid.as_ptr(), c("Password").as_ptr(), &mut value),
+                KpStatus::Ok
+            );
+            assert_eq!(CStr::from_ptr(value).to_str(), Ok("hunter2"));
+            kp_string_free(value);
+            assert_eq!(
+                kp_entry_get_field(db, uuid.as_ptr(), c("Missing").as_ptr(), &mut value),
+                KpStatus::NotFound
+            );
+            assert_eq!(
+                kp_entry_get_field(db, uuid.as_ptr(), ptr::null(), &mut value),
+                KpStatus::InvalidArgument
+            );
+            kp_database_free(db);
+        }
+    }
+}
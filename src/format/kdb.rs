@@ -1,16 +1,19 @@
 use crate::{
     config::{CompressionConfig, DatabaseConfig, InnerCipherConfig, KdfConfig, OuterCipherConfig},
     crypt::calculate_sha256,
-    db::{Database, Entry, Group, NodeRefMut, Value},
-    error::{DatabaseIntegrityError, DatabaseKeyError, DatabaseOpenError},
+    db::{AttachmentRef, Database, Entry, Group, HeaderAttachment, NodeRefMut, PublicCustomData, Times, Value},
+    error::{CryptographyError, DatabaseIntegrityError, DatabaseKeyError, DatabaseOpenError},
     format::DatabaseVersion,
     key::DatabaseKey,
 };
 
 use byteorder::{ByteOrder, LittleEndian};
+use chrono::NaiveDate;
 use cipher::generic_array::GenericArray;
+use uuid::Uuid;
+use zeroize::Zeroizing;
 
-use std::{collections::HashMap, convert::TryInto, str};
+use std::{collections::HashMap, str};
 
 #[derive(Debug)]
 struct KDBHeader {
@@ -67,19 +70,75 @@ fn ensure_length(
     }
 }
 
+/// Read the type, size and value of a field of a group or entry, returning `None` if the data ends
+/// inside of it
+fn read_field<'a>(data: &mut &'a [u8]) -> Option<(u16, u32, &'a [u8])> {
+    let field_type = LittleEndian::read_u16(data.get(0..2)?);
+    let field_size = LittleEndian::read_u32(data.get(2..6)?);
+    let field_value = data.get(6..6 + field_size as usize)?;
+
+    *data = &data[6 + field_size as usize..];
+    Some((field_type, field_size, field_value))
+}
+
 fn entry_name(field_type: u16) -> &'static str {
     match field_type {
         0x0004 => "Title",
         0x0005 => "URL",
         0x0006 => "UserName",
-        0x0008 => "Additional",
-        0x000d => "BinaryDesc",
+        0x0008 => "Notes",
         _ => {
             panic!("Unsupported field type!");
         }
     }
 }
 
+/// Expiry time KeePass 1.x uses for groups and entries that never expire
+const NEVER_EXPIRES: (i32, u32, u32) = (2999, 12, 28);
+
+/// Parse a packed 5 byte timestamp and store it in `times`. `index` selects the creation, last
+/// modification, last access or expiry time.
+fn parse_time(times: &mut Times, index: u16, field_value: &[u8]) {
+    let b = |i: usize| field_value[i] as u32;
+    let year = (b(0) << 6) | (b(1) >> 2);
+    let month = ((b(1) & 0x03) << 2) | (b(2) >> 6);
+    let day = (b(2) >> 1) & 0x1f;
+    let hour = ((b(2) & 0x01) << 4) | (b(3) >> 4);
+    let minute = ((b(3) & 0x0f) << 2) | (b(4) >> 6);
+    let second = b(4) & 0x3f;
+
+    // unset times are stored as zeros
+    let time = match NaiveDate::from_ymd_opt(year as i32, month, day)
+        .and_then(|date| date.and_hms_opt(hour, minute, second))
+    {
+        Some(time) => time,
+        None => return,
+    };
+
+    match index {
+        0 => {
+            times.set_creation(time);
+            times.set_location_changed(time);
+        }
+        1 => times.set_last_modification(time),
+        2 => times.set_last_access(time),
+        _ => {
+            times.expires = (year as i32, month, day) != NEVER_EXPIRES;
+            times.set_expiry(time);
+        }
+    }
+}
+
+/// KeePass 1.x keeps its own settings in entries with this title, which are not shown to users
+const META_STREAM_TITLE: &str = "Meta-Info";
+
+fn is_meta_stream(entry: &Entry, binary_desc: &str) -> bool {
+    binary_desc == "bin-stream"
+        && entry.get_title() == Some(META_STREAM_TITLE)
+        && entry.get_username() == Some("SYSTEM")
+        && entry.get_url() == Some("$")
+}
+
 // Collapse the tail of a deque of Groups up to the given level
 fn collapse_tail_groups(branch: &mut Vec<Group>, level: usize, root: &mut Group) {
     while level < branch.len() {
@@ -95,6 +154,14 @@ fn collapse_tail_groups(branch: &mut Vec<Group>, level: usize, root: &mut Group)
 // A map from a GroupId to a path identifying (by name) a group in the group tree.
 type GidMap = HashMap<u32, Vec<String>>;
 
+/// Groups of KeePass 1.x databases have no UUIDs, so they get new ones
+fn new_group() -> Group {
+    Group {
+        uuid: Uuid::new_v4(),
+        ..Default::default()
+    }
+}
+
 fn parse_groups(
     root: &mut Group,
     header_num_groups: u32,
@@ -103,16 +170,15 @@ fn parse_groups(
     // Loop over group TLVs
     let mut gid_map: HashMap<u32, Vec<String>> = HashMap::new(); // the gid to group path map
     let mut branch: Vec<Group> = Vec::new(); // the current branch in the group tree
-    let mut group: Group = Default::default(); // the current group (will be added as a leaf of the branch)
+    let mut group: Group = new_group(); // the current group (will be added as a leaf of the branch)
     let mut level: Option<u16> = None; // the current group's level
     let mut gid: Option<u32> = None; // the current group's id
     let mut group_path: Vec<String> = Vec::new(); // the current group path
     let mut num_groups = 0; // the total number of parsed groups
     while num_groups < header_num_groups as usize {
         // Read group TLV
-        let field_type = LittleEndian::read_u16(&data[0..]);
-        let field_size = LittleEndian::read_u32(&data[2..]);
-        let field_value = &data[6..6 + field_size as usize];
+        let (field_type, field_size, field_value) =
+            read_field(data).ok_or(DatabaseIntegrityError::IncompleteKDBGroup)?;
 
         match field_type {
             0x0000 => {} // KeePass ignores this field type
@@ -125,10 +191,12 @@ fn parse_groups(
             0x0003..=0x0006 => {
                 // Creation/LastMod/LastAccess/Expire
                 ensure_length(field_type, field_size, 5)?;
+                parse_time(&mut group.times, field_type - 0x0003, field_value);
             }
             0x0007 => {
                 //ImageId
                 ensure_length(field_type, field_size, 4)?;
+                group.icon_id = Some(LittleEndian::read_u32(field_value) as usize);
             }
             0x0008 => {
                 // Level
@@ -165,7 +233,7 @@ fn parse_groups(
                 // Update the GroupId map and reset state for the next group
                 let group_id = gid.ok_or_else(|| DatabaseIntegrityError::MissingKDBGroupId)?;
                 gid_map.insert(group_id, group_path.clone());
-                group = Default::default();
+                group = new_group();
                 gid = None;
                 num_groups += 1;
             }
@@ -173,8 +241,6 @@ fn parse_groups(
                 return Err(DatabaseIntegrityError::InvalidKDBGroupFieldType { field_type }.into());
             }
         }
-
-        *data = &data[6 + field_size as usize..];
     }
     if gid != None {
         return Err(DatabaseIntegrityError::IncompleteKDBGroup);
@@ -187,6 +253,7 @@ fn parse_groups(
 
 fn parse_entries(
     root: &mut Group,
+    header_attachments: &mut Vec<HeaderAttachment>,
     gid_map: GidMap,
     header_num_entries: u32,
     data: &mut &[u8],
@@ -194,18 +261,20 @@ fn parse_entries(
     // Loop over entry TLVs
     let mut entry: Entry = Default::default(); // the current entry
     let mut gid: Option<u32> = None; // the current entry's group id
+    let mut binary_desc = String::new(); // the current entry's attachment name
+    let mut binary_data: &[u8] = &[]; // the current entry's attachment content
     let mut num_entries = 0;
     while num_entries < header_num_entries {
         // Read entry TLV
-        let field_type = LittleEndian::read_u16(&data[0..]);
-        let field_size = LittleEndian::read_u32(&data[2..]);
-        let field_value = &data[6..6 + field_size as usize];
+        let (field_type, field_size, field_value) =
+            read_field(data).ok_or(DatabaseIntegrityError::IncompleteKDBEntry)?;
 
         match field_type {
             0x0000 => {} // KeePass ignores this field type
             0x0001 => {
                 // uuid
                 ensure_length(field_type, field_size, 16)?;
+                entry.uuid = Uuid::from_slice(field_value).unwrap_or_default();
             }
             0x0002 => {
                 // GroupId
@@ -215,14 +284,16 @@ fn parse_entries(
             0x0003 => {
                 // ImageId
                 ensure_length(field_type, field_size, 4)?;
+                entry.icon_id = Some(LittleEndian::read_u32(field_value) as usize);
             }
-            0x0004 | 0x0005 | 0x0006 | 0x0008 | 0x000d => {
-                // Title/URL/UserName/Additional/BinaryDesc
+            0x0004 | 0x0005 | 0x0006 | 0x0008 => {
+                // Title/URL/UserName/Additional
                 entry.fields.insert(
                     String::from(entry_name(field_type)),
                     Value::Unprotected(from_utf8(field_value)),
                 );
             }
+            0x000d => binary_desc = from_utf8(field_value), // BinaryDesc
             0x0007 => {
                 // Password
                 entry.fields.insert(
@@ -233,16 +304,32 @@ fn parse_entries(
             0x0009..=0x000c => {
                 // Creation/LastMod/LastAccess/Expire
                 ensure_length(field_type, field_size, 5)?;
+                parse_time(&mut entry.times, field_type - 0x0009, field_value);
             }
-            0x000e => {
-                // BinaryData
-                entry
-                    .fields
-                    .insert(String::from("BinaryData"), Value::Bytes(field_value.to_vec()));
-            }
+            0x000e => binary_data = field_value, // BinaryData
             0xffff => {
                 ensure_length(field_type, field_size, 0)?;
 
+                if is_meta_stream(&entry, &binary_desc) {
+                    entry = Default::default();
+                    binary_desc.clear();
+                    binary_data = &[];
+                    gid = None;
+                    num_entries += 1;
+                    continue;
+                }
+
+                if !binary_data.is_empty() {
+                    entry.attachments.push(AttachmentRef {
+                        name: std::mem::take(&mut binary_desc),
+                        identifier: header_attachments.len(),
+                    });
+                    header_attachments.push(HeaderAttachment {
+                        content: binary_data.to_vec(),
+                        ..Default::default()
+                    });
+                }
+
                 let group_id = gid.ok_or_else(|| DatabaseIntegrityError::MissingKDBGroupId)?;
                 let group_path: Vec<&str> = gid_map
                     .get(&group_id)
@@ -260,6 +347,8 @@ fn parse_entries(
 
                 group.add_child(entry);
                 entry = Default::default();
+                binary_desc.clear();
+                binary_data = &[];
                 gid = None;
                 num_entries += 1;
             }
@@ -267,8 +356,6 @@ fn parse_entries(
                 return Err(DatabaseIntegrityError::InvalidKDBEntryFieldType { field_type }.into());
             }
         }
-
-        *data = &data[6 + field_size as usize..];
     }
     if gid != None {
         return Err(DatabaseIntegrityError::IncompleteKDBEntry.into());
@@ -277,19 +364,26 @@ fn parse_entries(
     Ok(())
 }
 
-fn parse_db(header: &KDBHeader, data: &[u8]) -> Result<Group, DatabaseIntegrityError> {
+fn parse_db(header: &KDBHeader, data: &[u8]) -> Result<(Group, Vec<HeaderAttachment>), DatabaseIntegrityError> {
     let mut root = Group {
         name: "Root".to_owned(),
-        ..Default::default()
+        ..new_group()
     };
+    let mut header_attachments = Vec::new();
 
     let mut pos = &data[..];
 
     let gid_map = parse_groups(&mut root, header.num_groups, &mut pos)?;
 
-    parse_entries(&mut root, gid_map, header.num_entries, &mut pos)?;
+    parse_entries(
+        &mut root,
+        &mut header_attachments,
+        gid_map,
+        header.num_entries,
+        &mut pos,
+    )?;
 
-    Ok(root)
+    Ok((root, header_attachments))
 }
 
 pub(crate) fn parse_kdb(data: &[u8], db_key: &DatabaseKey) -> Result<Database, DatabaseOpenError> {
@@ -301,12 +395,11 @@ pub(crate) fn parse_kdb(data: &[u8], db_key: &DatabaseKey) -> Result<Database, D
 
     // derive master key from composite key, transform_seed, transform_rounds and master_seed
     let key_elements = db_key.get_key_elements()?;
-    let key_elements: Vec<&[u8]> = key_elements.iter().map(|v| &v[..]).collect();
     let composite_key = if key_elements.len() == 1 {
-        let key_element: [u8; 32] = key_elements[0].try_into().unwrap();
-        GenericArray::from(key_element) // single pass of SHA256, already done before the call to parse()
+        // single pass of SHA256, already done before the call to parse()
+        Zeroizing::new(GenericArray::clone_from_slice(&key_elements[0]))
     } else {
-        calculate_sha256(&key_elements)? // second pass of SHA256
+        db_key.composite_key()? // second pass of SHA256
     };
 
     // KDF is always AES
@@ -315,10 +408,10 @@ pub(crate) fn parse_kdb(data: &[u8], db_key: &DatabaseKey) -> Result<Database, D
     };
 
     let transformed_key = kdf_config
-        .get_kdf_seeded(&header.transform_seed)
-        .transform_key(&composite_key)?;
+        .get_kdf_seeded(&header.transform_seed, &Default::default())
+        .transform_key_cached(&composite_key)?;
 
-    let master_key = calculate_sha256(&[&header.master_seed, &transformed_key])?;
+    let master_key = Zeroizing::new(calculate_sha256(&[&header.master_seed, &transformed_key])?);
 
     let outer_cipher_config = if header.flags & 2 != 0 {
         OuterCipherConfig::AES256
@@ -328,12 +421,21 @@ pub(crate) fn parse_kdb(data: &[u8], db_key: &DatabaseKey) -> Result<Database, D
         return Err(DatabaseIntegrityError::InvalidFixedCipherID { cid: header.flags }.into());
     };
 
-    // Decrypt payload
-    let payload_padded = outer_cipher_config
+    // Decrypt payload. A wrong key usually shows in garbled padding already.
+    let payload_padded = match outer_cipher_config
         .get_cipher(&master_key, header.encryption_iv.as_ref())?
-        .decrypt(payload_encrypted)?;
-    let padlen = payload_padded[payload_padded.len() - 1] as usize;
-    let payload = &payload_padded[..payload_padded.len() - padlen];
+        .decrypt(payload_encrypted)
+    {
+        Ok(payload_padded) => payload_padded,
+        Err(CryptographyError::Unpadding(_)) => return Err(DatabaseKeyError::IncorrectKey.into()),
+        Err(e) => return Err(e.into()),
+    };
+    let padlen = payload_padded.last().copied().unwrap_or(0) as usize;
+    let payload = payload_padded
+        .len()
+        .checked_sub(padlen)
+        .map(|len| &payload_padded[..len])
+        .ok_or(DatabaseKeyError::IncorrectKey)?;
 
     // Check if we decrypted correctly
     let hash = calculate_sha256(&[&payload])?;
@@ -341,7 +443,7 @@ pub(crate) fn parse_kdb(data: &[u8], db_key: &DatabaseKey) -> Result<Database, D
         return Err(DatabaseKeyError::IncorrectKey.into());
     }
 
-    let root_group = parse_db(&header, &payload)?;
+    let (root_group, header_attachments) = parse_db(&header, &payload)?;
 
     let config = DatabaseConfig {
         version,
@@ -349,13 +451,19 @@ pub(crate) fn parse_kdb(data: &[u8], db_key: &DatabaseKey) -> Result<Database, D
         compression_config: CompressionConfig::None,
         inner_cipher_config: InnerCipherConfig::Plain,
         kdf_config,
+        argon2_secret_parameters: Default::default(),
     };
 
     Ok(Database {
         config,
-        header_attachments: Default::default(),
+        header_attachments,
         root: root_group,
         deleted_objects: Default::default(),
         meta: Default::default(),
+        trailing_data: Vec::new(),
+        public_custom_data: PublicCustomData::new(),
+        annotations: Default::default(),
+        file_fingerprint: Default::default(),
+        key_check: Default::default(),
     })
 }
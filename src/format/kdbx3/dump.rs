@@ -0,0 +1,245 @@
+use std::io::Write;
+
+use byteorder::{LittleEndian, WriteBytesExt};
+use zeroize::Zeroizing;
+
+use crate::{
+    config::{InnerCipherConfig, KdfConfig},
+    crypt,
+    db::{attachment_pool::compact, Database, SaveOptions},
+    error::DatabaseSaveError,
+    format::{
+        kdbx3::{
+            KDBX3Header, HEADER_COMMENT, HEADER_COMPRESSION_ID, HEADER_ENCRYPTION_IV, HEADER_END,
+            HEADER_INNER_RANDOM_STREAM_ID, HEADER_MASTER_SEED, HEADER_OUTER_ENCRYPTION_ID,
+            HEADER_PROTECTED_STREAM_KEY, HEADER_STREAM_START_BYTES, HEADER_TRANSFORM_ROUNDS,
+            HEADER_TRANSFORM_SEED,
+        },
+        kdbx4::padding_for_bucket,
+        DatabaseVersion,
+    },
+    io::ZeroizingBuffer,
+    key::DatabaseKey,
+    xml_db::dump::DumpContext,
+};
+
+/// Size of the master seed, the transform seed, the protected stream key and the stream start
+/// bytes
+const SEED_SIZE: usize = 32;
+
+/// Size of the blocks of the hashed block stream, as written by KeePass
+const BLOCK_SIZE: usize = 1024 * 1024;
+
+/// Dump a KeePass database in the KDBX 3.1 format using the key elements, for readers that do not
+/// support KDBX 4. If `SaveOptions::bucket_size` is given, the output is padded to a multiple of
+/// that many bytes with header comments, which readers ignore.
+///
+/// KDBX 3 only supports the AES key derivation and the Salsa20 inner cipher, and keeps attachments
+/// in the XML document instead of the inner header. It has no public custom data, which is left
+/// out.
+pub fn dump_kdbx3(
+    db: &Database,
+    db_key: &DatabaseKey,
+    writer: &mut dyn Write,
+    options: &SaveOptions,
+) -> Result<(), DatabaseSaveError> {
+    if !matches!(db.config.version, DatabaseVersion::KDB3(_)) {
+        return Err(DatabaseSaveError::UnsupportedVersion);
+    }
+
+    let rounds = match db.config.kdf_config {
+        KdfConfig::Aes { rounds } => rounds,
+        _ => {
+            return Err(DatabaseSaveError::UnsupportedSetting(
+                "Argon2 key derivation".to_string(),
+            ))
+        }
+    };
+    match db.config.inner_cipher_config {
+        InnerCipherConfig::Plain | InnerCipherConfig::Salsa20 => {}
+        InnerCipherConfig::ChaCha20 => {
+            return Err(DatabaseSaveError::UnsupportedSetting(
+                "ChaCha20 inner cipher".to_string(),
+            ))
+        }
+        InnerCipherConfig::ArcFourVariant => {
+            return Err(DatabaseSaveError::UnsupportedSetting(
+                "ArcFourVariant inner cipher".to_string(),
+            ))
+        }
+    }
+    if !db.header_attachments.is_empty() {
+        return Err(DatabaseSaveError::UnsupportedSetting(
+            "Attachments in the inner header".to_string(),
+        ));
+    }
+
+    write_kdbx3(db, db_key, writer, options, rounds)
+}
+
+/// Write a database that has been checked to be supported by KDBX 3
+pub(super) fn write_kdbx3(
+    db: &Database,
+    db_key: &DatabaseKey,
+    writer: &mut dyn Write,
+    options: &SaveOptions,
+    rounds: u64,
+) -> Result<(), DatabaseSaveError> {
+    // generate encryption keys and seeds on the fly when saving
+    let random = || -> Result<Vec<u8>, getrandom::Error> {
+        let mut seed = vec![0; SEED_SIZE];
+        getrandom::fill(&mut seed)?;
+        Ok(seed)
+    };
+
+    let mut outer_iv = vec![0; db.config.outer_cipher_config.get_iv_size()?];
+    getrandom::fill(&mut outer_iv)?;
+
+    let header = KDBX3Header {
+        outer_cipher: db.config.outer_cipher_config.clone(),
+        compression: db.config.compression_config.clone(),
+        master_seed: random()?,
+        transform_seed: random()?,
+        kdf_config: KdfConfig::Aes { rounds },
+        outer_iv,
+        protected_stream_key: random()?,
+        stream_start: random()?,
+        inner_cipher: db.config.inner_cipher_config.clone(),
+        body_start: 0,
+    };
+
+    // derive master key from composite key, transform_seed, transform_rounds and master_seed
+    let composite_key = db_key.composite_key()?;
+    let transformed_key = header
+        .kdf_config
+        .get_kdf_seeded(&header.transform_seed, &db.config.argon2_secret_parameters)
+        .transform_key_cached(&composite_key)?;
+    let master_key = Zeroizing::new(crypt::calculate_sha256(&[&header.master_seed, &transformed_key])?);
+
+    let stream_key = header.inner_stream_key()?;
+    let mut inner_cipher = header.inner_cipher.get_cipher(&stream_key)?;
+
+    // binaries that no entry refers to are left out while writing, keeping their identifiers
+    let context = DumpContext {
+        attachment_gzip_level: options.effective_gzip_level(),
+        attachment_indices: options
+            .drop_orphaned_attachments
+            .then(|| compact(&db.header_attachments, &db.root).1),
+        ..DumpContext::new(&mut *inner_cipher)
+    };
+
+    let mut payload_compressed = ZeroizingBuffer::default();
+    {
+        let mut payload = db
+            .config
+            .compression_config
+            .get_compression_at(options.effective_gzip_level())
+            .compress_stream(&mut payload_compressed);
+        crate::xml_db::dump::dump(db, context, &mut payload)?;
+        payload.finish()?;
+    }
+
+    // the stream start bytes are followed by the hashed block stream
+    let mut payload = ZeroizingBuffer::default();
+    payload.write_all(&header.stream_start)?;
+    write_hashed_block_stream(payload_compressed.as_slice(), &mut payload)?;
+
+    let payload_encrypted = db
+        .config
+        .outer_cipher_config
+        .get_cipher(&master_key, &header.outer_iv)?
+        .encrypt(payload.as_slice())?;
+
+    let mut header_data = Vec::new();
+    header.dump(&db.config.version, &mut header_data, 0)?;
+    if let Some(bucket_size) = options.bucket_size {
+        let padding = padding_for_bucket(header_data.len() + payload_encrypted.len(), bucket_size);
+        if padding > 0 {
+            header_data.clear();
+            header.dump(&db.config.version, &mut header_data, padding)?;
+        }
+    }
+
+    writer.write_all(&header_data)?;
+    writer.write_all(&payload_encrypted)?;
+
+    Ok(())
+}
+
+/// Write a buffer as blocks of an index, the SHA-256 hash of the block and the block size,
+/// followed by an empty block
+fn write_hashed_block_stream(data: &[u8], writer: &mut dyn Write) -> Result<(), DatabaseSaveError> {
+    let mut block_index = 0;
+    for block in data.chunks(BLOCK_SIZE) {
+        writer.write_u32::<LittleEndian>(block_index)?;
+        writer.write_all(&crypt::calculate_sha256(&[block])?)?;
+        writer.write_u32::<LittleEndian>(block.len() as u32)?;
+        writer.write_all(block)?;
+        block_index += 1;
+    }
+
+    writer.write_u32::<LittleEndian>(block_index)?;
+    writer.write_all(&[0; 32])?;
+    writer.write_u32::<LittleEndian>(0)?;
+    Ok(())
+}
+
+/// Write a header field, which has a 16-bit length in KDBX 3
+fn write_field(writer: &mut dyn Write, entry_type: u8, data: &[u8]) -> Result<(), std::io::Error> {
+    writer.write_u8(entry_type)?;
+    writer.write_u16::<LittleEndian>(data.len() as u16)?;
+    writer.write_all(data)
+}
+
+impl KDBX3Header {
+    /// Dump the header, including comment fields that take `padding` bytes in total if `padding`
+    /// is not 0. `padding` needs to be at least 3 bytes.
+    fn dump(
+        &self,
+        version: &DatabaseVersion,
+        writer: &mut dyn Write,
+        padding: usize,
+    ) -> Result<(), DatabaseSaveError> {
+        let rounds = match self.kdf_config {
+            KdfConfig::Aes { rounds } => rounds,
+            _ => {
+                return Err(DatabaseSaveError::UnsupportedSetting(
+                    "Argon2 key derivation".to_string(),
+                ))
+            }
+        };
+
+        version.dump(writer)?;
+
+        write_field(writer, HEADER_OUTER_ENCRYPTION_ID, &self.outer_cipher.dump())?;
+        write_field(writer, HEADER_COMPRESSION_ID, &self.compression.dump())?;
+        write_field(writer, HEADER_MASTER_SEED, &self.master_seed)?;
+        write_field(writer, HEADER_TRANSFORM_SEED, &self.transform_seed)?;
+        write_field(writer, HEADER_TRANSFORM_ROUNDS, &rounds.to_le_bytes())?;
+        write_field(writer, HEADER_ENCRYPTION_IV, &self.outer_iv)?;
+        write_field(writer, HEADER_PROTECTED_STREAM_KEY, &self.protected_stream_key)?;
+        write_field(writer, HEADER_STREAM_START_BYTES, &self.stream_start)?;
+        write_field(
+            writer,
+            HEADER_INNER_RANDOM_STREAM_ID,
+            &self.inner_cipher.dump().to_le_bytes(),
+        )?;
+
+        // a single comment holds at most u16::MAX bytes, larger padding is split up without
+        // leaving a remainder too small for another field
+        let mut padding = padding;
+        while padding > 0 {
+            let mut field_size = padding.min(3 + u16::MAX as usize);
+            if padding - field_size < 3 && padding != field_size {
+                field_size -= 3;
+            }
+            write_field(writer, HEADER_COMMENT, &vec![0; field_size - 3])?;
+            padding -= field_size;
+        }
+
+        // KeePass ends the header with an empty line
+        write_field(writer, HEADER_END, b"\r\n\r\n")?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,273 @@
+#[cfg(feature = "save_kdbx4")]
+mod dump;
+mod parse;
+
+use crate::{
+    config::{CompressionConfig, InnerCipherConfig, KdfConfig, OuterCipherConfig},
+    crypt::calculate_sha256,
+    error::CryptographyError,
+};
+
+#[cfg(feature = "save_kdbx4")]
+pub(crate) use crate::format::kdbx3::dump::dump_kdbx3;
+pub(crate) use crate::format::kdbx3::parse::{decrypt_kdbx3, parse_header_info, parse_kdbx3, verify_kdbx3_key};
+
+/// Header entry denoting the end of the header
+pub const HEADER_END: u8 = 0;
+/// Header entry denoting a comment
+pub const HEADER_COMMENT: u8 = 1;
+/// A UUID specifying which cipher suite should be used to encrypt the payload
+pub const HEADER_OUTER_ENCRYPTION_ID: u8 = 2;
+/// First byte determines compression of payload
+pub const HEADER_COMPRESSION_ID: u8 = 3;
+/// Master seed for deriving the master key
+pub const HEADER_MASTER_SEED: u8 = 4;
+/// Seed used in deriving the transformed key
+pub const HEADER_TRANSFORM_SEED: u8 = 5;
+/// Number of rounds used in derivation of the transformed key
+pub const HEADER_TRANSFORM_ROUNDS: u8 = 6;
+/// Initialization Vector for decrypting the payload
+pub const HEADER_ENCRYPTION_IV: u8 = 7;
+/// Key for decrypting the inner protected values
+pub const HEADER_PROTECTED_STREAM_KEY: u8 = 8;
+/// First bytes of the decrypted payload, to check for correct decryption
+pub const HEADER_STREAM_START_BYTES: u8 = 9;
+/// Specifies which cipher suite to use for decrypting the inner protected values
+pub const HEADER_INNER_RANDOM_STREAM_ID: u8 = 10;
+
+#[derive(Debug)]
+struct KDBX3Header {
+    // https://gist.github.com/msmuenchen/9318327
+    outer_cipher: OuterCipherConfig,
+    compression: CompressionConfig,
+    master_seed: Vec<u8>,
+
+    transform_seed: Vec<u8>,
+    kdf_config: KdfConfig,
+
+    outer_iv: Vec<u8>,
+    protected_stream_key: Vec<u8>,
+    stream_start: Vec<u8>,
+    inner_cipher: InnerCipherConfig,
+    body_start: usize,
+}
+
+impl KDBX3Header {
+    /// The key of the inner cipher. Salsa20 uses a hash of the protected stream key, while the
+    /// legacy ArcFourVariant cipher uses it as it is.
+    fn inner_stream_key(&self) -> Result<Vec<u8>, CryptographyError> {
+        match self.inner_cipher {
+            InnerCipherConfig::ArcFourVariant => Ok(self.protected_stream_key.clone()),
+            _ => Ok(calculate_sha256(&[&self.protected_stream_key])?.to_vec()),
+        }
+    }
+}
+
+#[cfg(feature = "save_kdbx4")]
+#[cfg(test)]
+mod kdbx3_tests {
+    use crate::{
+        config::{CompressionConfig, DatabaseConfig, InnerCipherConfig, KdfConfig, OuterCipherConfig},
+        db::{BinaryAttachment, Database, Entry, NodeRef, SaveOptions, Value},
+        error::DatabaseSaveError,
+        format::{
+            kdbx3::{decrypt_kdbx3, dump::write_kdbx3, dump_kdbx3, parse_kdbx3},
+            kdbx4::dump_kdbx4,
+            DatabaseVersion,
+        },
+        key::DatabaseKey,
+    };
+
+    #[test]
+    pub fn test_attachment_compression() {
+        let db_key = DatabaseKey::new().with_password("test");
+        let mut db = Database::new(kdbx3_config());
+        for (id, compressed) in [("0", true), ("1", false)] {
+            db.meta.binaries.binaries.push(BinaryAttachment {
+                identifier: Some(id.to_string()),
+                compressed,
+                content: b"compressible ".repeat(1000),
+                ..Default::default()
+            });
+        }
+
+        let mut data = Vec::new();
+        let options = SaveOptions::default().with_gzip_level(1);
+        dump_kdbx3(&db, &db_key, &mut data, &options).unwrap();
+
+        // each attachment is stored as chosen
+        let (_, _, xml) = decrypt_kdbx3(&data, &db_key).unwrap();
+        let xml = String::from_utf8_lossy(&xml);
+        assert_eq!(xml.matches(r#"<Binary ID="0" Compressed="True">"#).count(), 1);
+        assert_eq!(xml.matches(r#"<Binary ID="1">"#).count(), 1);
+
+        let reopened = parse_kdbx3(&data, &db_key).unwrap();
+        assert_eq!(reopened.meta.binaries, db.meta.binaries);
+    }
+
+    fn kdbx3_config() -> DatabaseConfig {
+        DatabaseConfig {
+            version: DatabaseVersion::KDB3(1),
+            kdf_config: KdfConfig::Aes { rounds: 10 },
+            inner_cipher_config: InnerCipherConfig::Salsa20,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    pub fn test_config_matrix() {
+        let db_key = DatabaseKey::new().with_password("test");
+
+        for outer_cipher_config in [OuterCipherConfig::AES256, OuterCipherConfig::Twofish].iter() {
+            for compression_config in [CompressionConfig::None, CompressionConfig::GZip].iter() {
+                for inner_cipher_config in [InnerCipherConfig::Plain, InnerCipherConfig::Salsa20].iter() {
+                    let mut db = Database::new(DatabaseConfig {
+                        outer_cipher_config: outer_cipher_config.clone(),
+                        compression_config: compression_config.clone(),
+                        inner_cipher_config: inner_cipher_config.clone(),
+                        ..kdbx3_config()
+                    });
+
+                    let mut entry = Entry::new();
+                    entry
+                        .fields
+                        .insert("Title".to_string(), Value::Unprotected("Demo Entry".into()));
+                    entry
+                        .fields
+                        .insert("Password".to_string(), Value::Protected("secret".into()));
+                    db.root.add_child(entry);
+
+                    let mut encrypted_db = Vec::new();
+                    dump_kdbx3(&db, &db_key, &mut encrypted_db, &Default::default()).unwrap();
+
+                    let decrypted_db = parse_kdbx3(&encrypted_db, &db_key).unwrap();
+                    assert_eq!(decrypted_db.config, db.config);
+                    match decrypted_db.root.get(&["Demo Entry"]) {
+                        Some(NodeRef::Entry(e)) => assert_eq!(e.get_password(), Some("secret")),
+                        _ => panic!("Could not get NodeRef"),
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    pub fn round_trip_existing_databases() {
+        for (path, password) in [
+            ("tests/resources/test_db_with_password.kdbx", "demopass"),
+            // attachments of more than one block
+            (
+                "tests/resources/test_db_kdb3_with_file_larger_1mb.kdbx",
+                "samplepassword",
+            ),
+        ]
+        .iter()
+        {
+            let db_key = DatabaseKey::new().with_password(password);
+            let db = parse_kdbx3(&std::fs::read(path).unwrap(), &db_key).unwrap();
+
+            let mut encrypted_db = Vec::new();
+            db.save(&mut encrypted_db, db_key.clone()).unwrap();
+            let reopened = Database::parse(&encrypted_db, db_key.clone()).unwrap();
+            assert_eq!(reopened.config, db.config);
+            assert_eq!(reopened.root, db.root);
+            assert_eq!(reopened.meta, db.meta);
+            assert_eq!(reopened.deleted_objects, db.deleted_objects);
+
+            // KDBX 3 readers expect timestamps as text
+            let xml = String::from_utf8(decrypt_kdbx3(&encrypted_db, &db_key).unwrap().2.to_vec()).unwrap();
+            let creation = db.root.times.get_creation().unwrap();
+            assert!(xml.contains(&creation.format("<CreationTime>%Y-%m-%dT%H:%M:%SZ").to_string()));
+        }
+    }
+
+    #[test]
+    pub fn pad_to_bucket() {
+        let mut db = Database::new(kdbx3_config());
+        db.root.add_child(Entry::new());
+        let db_key = DatabaseKey::new().with_password("test");
+
+        for bucket_size in [4096, 100_000].iter() {
+            let options = crate::db::SaveOptions::default().pad_to_bucket(*bucket_size);
+            let mut padded = Vec::new();
+            db.save_with_options(&mut padded, db_key.clone(), &options)
+                .unwrap();
+            assert_eq!(padded.len(), *bucket_size);
+            assert_eq!(parse_kdbx3(&padded, &db_key).unwrap().root.children.len(), 1);
+        }
+    }
+
+    #[test]
+    pub fn unsupported_settings() {
+        let db_key = DatabaseKey::new().with_password("test");
+
+        let db = Database::new(DatabaseConfig {
+            kdf_config: KdfConfig::Argon2id {
+                iterations: 10,
+                memory: 65536,
+                parallelism: 2,
+                version: argon2::Version::Version13,
+            },
+            ..kdbx3_config()
+        });
+        assert!(matches!(
+            dump_kdbx3(&db, &db_key, &mut Vec::new(), &Default::default()),
+            Err(DatabaseSaveError::UnsupportedSetting(_))
+        ));
+
+        let db = Database::new(DatabaseConfig {
+            inner_cipher_config: InnerCipherConfig::ChaCha20,
+            ..kdbx3_config()
+        });
+        assert!(matches!(
+            dump_kdbx3(&db, &db_key, &mut Vec::new(), &Default::default()),
+            Err(DatabaseSaveError::UnsupportedSetting(_))
+        ));
+    }
+
+    #[test]
+    pub fn arc_four_variant_is_read_only() {
+        let db_key = DatabaseKey::new().with_password("test");
+        let mut db = Database::new(DatabaseConfig {
+            inner_cipher_config: InnerCipherConfig::ArcFourVariant,
+            ..kdbx3_config()
+        });
+        let mut entry = Entry::new();
+        entry
+            .fields
+            .insert("Title".to_string(), Value::Unprotected("Demo Entry".into()));
+        entry
+            .fields
+            .insert("Password".to_string(), Value::Protected("secret".into()));
+        db.root.add_child(entry);
+
+        assert!(matches!(
+            dump_kdbx3(&db, &db_key, &mut Vec::new(), &Default::default()),
+            Err(DatabaseSaveError::UnsupportedSetting(_))
+        ));
+
+        // write it the way KeePass 2 pre-releases did, which can still be opened
+        let mut encrypted_db = Vec::new();
+        write_kdbx3(&db, &db_key, &mut encrypted_db, &Default::default(), 10).unwrap();
+        let decrypted_db = parse_kdbx3(&encrypted_db, &db_key).unwrap();
+        assert_eq!(
+            decrypted_db.config.inner_cipher_config,
+            InnerCipherConfig::ArcFourVariant
+        );
+        match decrypted_db.root.get(&["Demo Entry"]) {
+            Some(NodeRef::Entry(e)) => assert_eq!(e.get_password(), Some("secret")),
+            _ => panic!("Could not get NodeRef"),
+        }
+
+        let mut upgraded = decrypted_db.clone();
+        upgraded.convert_to(DatabaseVersion::KDB4(0)).unwrap();
+        assert_eq!(upgraded.config.inner_cipher_config, InnerCipherConfig::ChaCha20);
+
+        let mut kdbx4 = decrypted_db;
+        kdbx4.config.version = DatabaseVersion::KDB4(0);
+        assert!(matches!(
+            dump_kdbx4(&kdbx4, &db_key, &mut Vec::new(), &Default::default()),
+            Err(DatabaseSaveError::UnsupportedSetting(_))
+        ));
+    }
+}
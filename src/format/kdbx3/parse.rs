@@ -1,32 +1,27 @@
 use crate::{
-    config::{CompressionConfig, DatabaseConfig, InnerCipherConfig, KdfConfig, OuterCipherConfig},
-    crypt::{calculate_sha256, ciphers::Cipher},
-    db::Database,
-    error::{BlockStreamError, DatabaseIntegrityError, DatabaseKeyError, DatabaseOpenError},
-    format::DatabaseVersion,
+    config::{
+        Argon2SecretParameters, CompressionConfig, DatabaseConfig, InnerCipherConfig, KdfConfig,
+        OuterCipherConfig,
+    },
+    crypt::{calculate_sha256, ciphers::InnerStreamCipher},
+    db::{Database, HeaderInfo, PublicCustomData},
+    error::{BlockStreamError, CryptographyError, DatabaseIntegrityError, DatabaseKeyError, DatabaseOpenError},
+    format::{
+        kdbx3::{
+            KDBX3Header, HEADER_COMMENT, HEADER_COMPRESSION_ID, HEADER_ENCRYPTION_IV, HEADER_END,
+            HEADER_INNER_RANDOM_STREAM_ID, HEADER_MASTER_SEED, HEADER_OUTER_ENCRYPTION_ID,
+            HEADER_PROTECTED_STREAM_KEY, HEADER_STREAM_START_BYTES, HEADER_TRANSFORM_ROUNDS,
+            HEADER_TRANSFORM_SEED,
+        },
+        read_outer_header_data, DatabaseVersion,
+    },
     key::DatabaseKey,
 };
 
 use byteorder::{ByteOrder, LittleEndian};
+use zeroize::Zeroizing;
 
-use std::convert::TryFrom;
-
-#[derive(Debug)]
-struct KDBX3Header {
-    // https://gist.github.com/msmuenchen/9318327
-    outer_cipher: OuterCipherConfig,
-    compression: CompressionConfig,
-    master_seed: Vec<u8>,
-
-    transform_seed: Vec<u8>,
-    kdf_config: KdfConfig,
-
-    outer_iv: Vec<u8>,
-    protected_stream_key: Vec<u8>,
-    stream_start: Vec<u8>,
-    inner_cipher: InnerCipherConfig,
-    body_start: usize,
-}
+use std::{convert::TryFrom, io::Read};
 
 fn parse_outer_header(data: &[u8]) -> Result<KDBX3Header, DatabaseOpenError> {
     let mut outer_cipher: Option<OuterCipherConfig> = None;
@@ -62,23 +57,23 @@ fn parse_outer_header(data: &[u8]) -> Result<KDBX3Header, DatabaseOpenError> {
 
         match entry_type {
             // END - finished parsing header
-            0 => {
+            HEADER_END => {
                 break;
             }
 
             // COMMENT
-            1 => {}
+            HEADER_COMMENT => {}
 
             // CIPHERID - a UUID specifying which cipher suite
             //            should be used to encrypt the payload
-            2 => {
+            HEADER_OUTER_ENCRYPTION_ID => {
                 outer_cipher = Some(
                     OuterCipherConfig::try_from(entry_buffer).map_err(|e| DatabaseIntegrityError::from(e))?,
                 );
             }
 
             // COMPRESSIONFLAGS - first byte determines compression of payload
-            3 => {
+            HEADER_COMPRESSION_ID => {
                 compression = Some(
                     CompressionConfig::try_from(LittleEndian::read_u32(&entry_buffer))
                         .map_err(|e| DatabaseIntegrityError::from(e))?,
@@ -86,26 +81,26 @@ fn parse_outer_header(data: &[u8]) -> Result<KDBX3Header, DatabaseOpenError> {
             }
 
             // MASTERSEED - Master seed for deriving the master key
-            4 => master_seed = Some(entry_buffer.to_vec()),
+            HEADER_MASTER_SEED => master_seed = Some(entry_buffer.to_vec()),
 
             // TRANSFORMSEED - Seed used in deriving the transformed key
-            5 => transform_seed = Some(entry_buffer.to_vec()),
+            HEADER_TRANSFORM_SEED => transform_seed = Some(entry_buffer.to_vec()),
 
             // TRANSFORMROUNDS - Number of rounds used in derivation of transformed key
-            6 => transform_rounds = Some(LittleEndian::read_u64(entry_buffer)),
+            HEADER_TRANSFORM_ROUNDS => transform_rounds = Some(LittleEndian::read_u64(entry_buffer)),
 
             // ENCRYPTIONIV - Initialization Vector for decrypting the payload
-            7 => outer_iv = Some(entry_buffer.to_vec()),
+            HEADER_ENCRYPTION_IV => outer_iv = Some(entry_buffer.to_vec()),
 
             // PROTECTEDSTREAMKEY - Key for decrypting the inner protected values
-            8 => protected_stream_key = Some(entry_buffer.to_vec()),
+            HEADER_PROTECTED_STREAM_KEY => protected_stream_key = Some(entry_buffer.to_vec()),
 
             // STREAMSTARTBYTES - First bytes of decrypted payload (to check correct decryption)
-            9 => stream_start = Some(entry_buffer.to_vec()),
+            HEADER_STREAM_START_BYTES => stream_start = Some(entry_buffer.to_vec()),
 
             // INNERRANDOMSTREAMID - specifies which cipher suite
             //                       to use for decrypting the inner protected values
-            10 => {
+            HEADER_INNER_RANDOM_STREAM_ID => {
                 inner_cipher = Some(
                     InnerCipherConfig::try_from(LittleEndian::read_u32(entry_buffer))
                         .map_err(|e| DatabaseIntegrityError::from(e))?,
@@ -160,6 +155,17 @@ fn parse_outer_header(data: &[u8]) -> Result<KDBX3Header, DatabaseOpenError> {
 }
 
 /// Open, decrypt and parse a KeePass database from a source and a password
+/// Read the settings in the outer header, without decrypting the database
+pub(crate) fn parse_header_info(data: &[u8]) -> Result<HeaderInfo, DatabaseOpenError> {
+    let header = parse_outer_header(data)?;
+    Ok(HeaderInfo {
+        version: DatabaseVersion::parse(data)?,
+        outer_cipher_config: header.outer_cipher,
+        compression_config: header.compression,
+        kdf_config: header.kdf_config,
+    })
+}
+
 pub(crate) fn parse_kdbx3(data: &[u8], db_key: &DatabaseKey) -> Result<Database, DatabaseOpenError> {
     let (config, mut inner_decryptor, xml) = decrypt_kdbx3(data, db_key)?;
 
@@ -173,21 +179,63 @@ pub(crate) fn parse_kdbx3(data: &[u8], db_key: &DatabaseKey) -> Result<Database,
         root: database_content.root.group,
         deleted_objects: database_content.root.deleted_objects,
         meta: database_content.meta,
+        trailing_data: Vec::new(),
+        public_custom_data: PublicCustomData::new(),
+        annotations: Default::default(),
+        file_fingerprint: Default::default(),
+        key_check: Default::default(),
     };
 
     Ok(db)
 }
 
+/// Derive the master key from the composite key, transform_seed, transform_rounds and master_seed
+fn derive_master_key(
+    header: &KDBX3Header,
+    db_key: &DatabaseKey,
+) -> Result<Zeroizing<Vec<u8>>, DatabaseOpenError> {
+    let composite_key = db_key.composite_key()?;
+
+    // transform the key
+    let transformed_key = header
+        .kdf_config
+        .get_kdf_seeded(&header.transform_seed, &Argon2SecretParameters::default())
+        .transform_key_cached(&composite_key)?;
+
+    Ok(Zeroizing::new(
+        calculate_sha256(&[header.master_seed.as_ref(), &transformed_key])?.to_vec(),
+    ))
+}
+
+/// Check whether a key opens a KeePass KDBX3 database by decrypting nothing but the stream start
+/// bytes at the start of its payload
+pub(crate) fn verify_kdbx3_key(source: &mut dyn Read, db_key: &DatabaseKey) -> Result<bool, DatabaseOpenError> {
+    let header = parse_outer_header(&read_outer_header_data(source)?)?;
+
+    let mut cipher = header
+        .outer_cipher
+        .get_stream_cipher(&derive_master_key(&header, db_key)?, &header.outer_iv)?;
+
+    // only whole blocks can be decrypted
+    let block_size = cipher.block_size();
+    let length = header.stream_start.len().div_ceil(block_size) * block_size;
+    let mut payload_start = Zeroizing::new(vec![0; length]);
+    source.read_exact(&mut payload_start)?;
+    cipher.decrypt_blocks(&mut payload_start);
+    Ok(payload_start.starts_with(&header.stream_start))
+}
+
 /// Open and decrypt a KeePass KDBX3 database from a source and a password
 pub(crate) fn decrypt_kdbx3(
     data: &[u8],
     db_key: &DatabaseKey,
-) -> Result<(DatabaseConfig, Box<dyn Cipher>, Vec<u8>), DatabaseOpenError> {
+) -> Result<(DatabaseConfig, Box<dyn InnerStreamCipher>, Zeroizing<Vec<u8>>), DatabaseOpenError> {
     let version = DatabaseVersion::parse(data)?;
     let header = parse_outer_header(data)?;
 
     // Derive stream key for decrypting inner protected values and set up decryption context
-    let stream_key = calculate_sha256(&[header.protected_stream_key.as_ref()])
+    let stream_key = header
+        .inner_stream_key()
         .map_err(|e| DatabaseIntegrityError::from(e))?;
 
     let inner_decryptor = header
@@ -195,12 +243,15 @@ pub(crate) fn decrypt_kdbx3(
         .get_cipher(&stream_key)
         .map_err(|e| DatabaseIntegrityError::from(e))?;
 
+    let master_key = derive_master_key(&header, db_key)?;
+
     let config = DatabaseConfig {
         version,
         outer_cipher_config: header.outer_cipher,
         compression_config: header.compression,
         inner_cipher_config: header.inner_cipher,
         kdf_config: header.kdf_config,
+        argon2_secret_parameters: Default::default(),
     };
 
     let mut pos = header.body_start;
@@ -211,31 +262,24 @@ pub(crate) fn decrypt_kdbx3(
     // Rest of file after header is payload
     let payload_encrypted = &data[pos..];
 
-    // derive master key from composite key, transform_seed, transform_rounds and master_seed
-    let key_elements = db_key.get_key_elements()?;
-    let key_elements: Vec<&[u8]> = key_elements.iter().map(|v| &v[..]).collect();
-    let composite_key = calculate_sha256(&key_elements)?;
-
-    // transform the key
-    let transformed_key = config
-        .kdf_config
-        .get_kdf_seeded(&header.transform_seed)
-        .transform_key(&composite_key)?;
-
-    let master_key = calculate_sha256(&[header.master_seed.as_ref(), &transformed_key])?;
-
-    // Decrypt payload
-    let payload = config
+    // Decrypt payload. Without an HMAC, a wrong key only shows in garbled padding or stream start
+    // bytes.
+    let payload = match config
         .outer_cipher_config
         .get_cipher(&master_key, header.outer_iv.as_ref())?
-        .decrypt(payload_encrypted)?;
+        .decrypt(payload_encrypted)
+    {
+        Ok(payload) => Zeroizing::new(payload),
+        Err(CryptographyError::Unpadding(_)) => return Err(DatabaseKeyError::IncorrectKey.into()),
+        Err(e) => return Err(e.into()),
+    };
 
     // Check if we decrypted correctly
-    if &payload[0..header.stream_start.len()] != header.stream_start.as_slice() {
+    if payload.get(..header.stream_start.len()) != Some(header.stream_start.as_slice()) {
         return Err(DatabaseKeyError::IncorrectKey.into());
     }
 
-    let mut buf = Vec::new();
+    let mut buf = Zeroizing::new(Vec::new());
 
     pos = 32;
     let mut block_index = 0;
@@ -269,13 +313,13 @@ pub(crate) fn decrypt_kdbx3(
         }
 
         // Decompress block_buffer_compressed
-        buf.append(&mut block_buffer_compressed.to_vec());
+        buf.extend_from_slice(block_buffer_compressed);
 
         pos += 40 + block_size;
         block_index += 1;
     }
 
-    let xml = compression.decompress(&buf)?;
+    let xml = Zeroizing::new(compression.decompress(&buf)?);
 
     Ok((config, inner_decryptor, xml))
 }
@@ -1,54 +1,101 @@
-use std::io::Write;
+use std::{collections::HashMap, io::Write};
 
 use byteorder::{LittleEndian, WriteBytesExt};
+use cipher::generic_array::{
+    typenum::{U32, U64},
+    GenericArray,
+};
+use zeroize::Zeroizing;
 
 use crate::{
-    crypt,
-    db::{Database, HeaderAttachment},
+    crypt::{
+        self,
+        ciphers::{EncryptWriter, OuterCipherStream},
+    },
+    db::{attachment_pool::compact, Database, HeaderAttachment, SaveOptions},
     error::DatabaseSaveError,
     format::{
         kdbx4::{
-            KDBX4InnerHeader, KDBX4OuterHeader, HEADER_COMPRESSION_ID, HEADER_ENCRYPTION_IV, HEADER_END,
-            HEADER_KDF_PARAMS, HEADER_MASTER_SEED, HEADER_MASTER_SEED_SIZE, HEADER_OUTER_ENCRYPTION_ID,
-            INNER_HEADER_BINARY_ATTACHMENTS, INNER_HEADER_END, INNER_HEADER_RANDOM_STREAM_ID,
-            INNER_HEADER_RANDOM_STREAM_KEY,
+            KDBX4InnerHeader, KDBX4OuterHeader, HEADER_COMMENT, HEADER_COMPRESSION_ID, HEADER_ENCRYPTION_IV,
+            HEADER_END, HEADER_KDF_PARAMS, HEADER_MASTER_SEED, HEADER_MASTER_SEED_SIZE,
+            HEADER_OUTER_ENCRYPTION_ID, HEADER_PUBLIC_CUSTOM_DATA, INNER_HEADER_BINARY_ATTACHMENTS,
+            INNER_HEADER_END, INNER_HEADER_RANDOM_STREAM_ID, INNER_HEADER_RANDOM_STREAM_KEY,
         },
         DatabaseVersion,
     },
     hmac_block_stream,
     io::WriteLengthTaggedExt,
     key::DatabaseKey,
+    progress::{self, Phase},
     variant_dictionary::VariantDictionary,
+    xml_db::dump::DumpContext,
 };
 
-/// Dump a KeePass database using the key elements
+/// Dump a KeePass database using the key elements. If `SaveOptions::bucket_size` is given, the
+/// output is padded to a multiple of that many bytes with a header comment, which readers ignore.
 pub fn dump_kdbx4(
     db: &Database,
     db_key: &DatabaseKey,
     writer: &mut dyn Write,
+    options: &SaveOptions,
 ) -> Result<(), DatabaseSaveError> {
     if !matches!(db.config.version, DatabaseVersion::KDB4(_)) {
         return Err(DatabaseSaveError::UnsupportedVersion.into());
     }
+    if !db.config.inner_cipher_config.is_writable() {
+        return Err(DatabaseSaveError::UnsupportedSetting(
+            "ArcFourVariant inner cipher".to_string(),
+        ));
+    }
+
+    // contents that no entry refers to are left out, and the others renumbered, while writing
+    let (header_attachments, attachment_indices) = match options.drop_orphaned_attachments {
+        true => {
+            let (attachments, indices) = compact(&db.header_attachments, &db.root);
+            (attachments, Some(indices))
+        }
+        false => (db.header_attachments.iter().collect(), None),
+    };
+    let attachments = Attachments {
+        contents: &header_attachments,
+        indices: attachment_indices,
+    };
 
     // generate encryption keys and seeds on the fly when saving
     let mut master_seed = vec![0; HEADER_MASTER_SEED_SIZE];
     getrandom::fill(&mut master_seed)?;
 
-    let mut outer_iv = vec![0; db.config.outer_cipher_config.get_iv_size()];
+    let mut outer_iv = vec![0; db.config.outer_cipher_config.get_iv_size()?];
     getrandom::fill(&mut outer_iv)?;
 
-    let mut inner_random_stream_key = vec![0; db.config.inner_cipher_config.get_key_size()];
+    let mut inner_random_stream_key = Zeroizing::new(vec![0; db.config.inner_cipher_config.get_key_size()]);
     getrandom::fill(&mut inner_random_stream_key)?;
 
-    let (kdf, kdf_seed) = db.config.kdf_config.get_kdf_and_seed()?;
-
-    #[cfg(feature = "challenge_response")]
-    let db_key = db_key.clone().perform_challenge(&kdf_seed)?;
+    // a transformed key for the same KDF settings is reused along with its seed, so that saving
+    // does not run the KDF
+    let (kdf_seed, transformed_key) = match db_key.transformed_key_for_saving(&db.config) {
+        Some(transformed_key) => (
+            transformed_key.kdf_seed().to_vec(),
+            Zeroizing::new(GenericArray::<u8, U32>::clone_from_slice(
+                transformed_key.as_bytes(),
+            )),
+        ),
+        None => {
+            let (kdf, kdf_seed) = db
+                .config
+                .kdf_config
+                .get_kdf_and_seed(&db.config.argon2_secret_parameters)?;
+
+            #[cfg(feature = "challenge_response")]
+            let db_key = db_key.clone().perform_challenge(&kdf_seed)?;
+
+            let composite_key = db_key.composite_key()?;
+            let transformed_key = kdf.transform_key_cached(&composite_key)?;
+            (kdf_seed, transformed_key)
+        }
+    };
 
-    // dump the outer header - need to buffer so that SHA256 can be computed
-    let mut header_data = Vec::new();
-    KDBX4OuterHeader {
+    let outer_header = KDBX4OuterHeader {
         version: db.config.version.clone(),
         outer_cipher_config: db.config.outer_cipher_config.clone(),
         compression_config: db.config.compression_config.clone(),
@@ -56,75 +103,171 @@ pub fn dump_kdbx4(
         outer_iv: outer_iv.clone(),
         kdf_config: db.config.kdf_config.clone(),
         kdf_seed,
-    }
-    .dump(&mut header_data)?;
+        argon2_secret_parameters: db.config.argon2_secret_parameters.clone(),
+        public_custom_data: db.public_custom_data.clone(),
+    };
 
-    let header_sha256 = crypt::calculate_sha256(&[&header_data])?;
+    // derive master key from the transformed key and master_seed
+    let master_key = Zeroizing::new(crypt::calculate_sha256(&[&master_seed, &transformed_key])?);
 
-    // write out header and header hash
-    writer.write(&header_data)?;
-    writer.write(&header_sha256)?;
+    let hmac_key = Zeroizing::new(crypt::calculate_sha512(&[
+        &master_seed,
+        &transformed_key,
+        &hmac_block_stream::HMAC_KEY_END,
+    ])?);
 
-    // derive master key from composite key, transform_seed, transform_rounds and master_seed
-    let key_elements = db_key.get_key_elements()?;
-    let key_elements: Vec<&[u8]> = key_elements.iter().map(|v| &v[..]).collect();
-    let composite_key = crypt::calculate_sha256(&key_elements)?;
-    let transformed_key = kdf.transform_key(&composite_key)?;
-    let master_key = crypt::calculate_sha256(&[&master_seed, &transformed_key])?;
+    let inner_header = KDBX4InnerHeader {
+        inner_random_stream: db.config.inner_cipher_config.clone(),
+        inner_random_stream_key,
+    };
+    let outer_cipher = db
+        .config
+        .outer_cipher_config
+        .get_stream_cipher(&master_key, &outer_iv)?;
 
-    // verify credentials
-    let hmac_key =
-        crypt::calculate_sha512(&[&master_seed, &transformed_key, &hmac_block_stream::HMAC_KEY_END])?;
-    let header_hmac_key = hmac_block_stream::get_hmac_block_key(u64::max_value(), &hmac_key)?;
-    let header_hmac = crypt::calculate_hmac(&[&header_data], &header_hmac_key)?;
+    // dump the outer header - need to buffer so that SHA256 can be computed
+    let mut header_data = Vec::new();
+    outer_header.dump(&mut header_data, 0)?;
+
+    let bucket_size = match options.bucket_size {
+        Some(bucket_size) => bucket_size,
+        None => {
+            write_outer_header(&header_data, &hmac_key, writer)?;
+            return write_payload(
+                db,
+                attachments,
+                options,
+                &inner_header,
+                outer_cipher,
+                &hmac_key,
+                writer,
+            );
+        }
+    };
+
+    // the header is padded to the size of the whole file, so the encrypted payload needs to be
+    // buffered to know its size
+    let mut payload_hmac = Vec::new();
+    write_payload(
+        db,
+        attachments,
+        options,
+        &inner_header,
+        outer_cipher,
+        &hmac_key,
+        &mut payload_hmac,
+    )?;
+
+    // header, header hash, header HMAC and payload
+    let size = header_data.len() + 32 + 32 + payload_hmac.len();
+    let padding = padding_for_bucket(size, bucket_size);
+    if padding > 0 {
+        header_data.clear();
+        outer_header.dump(&mut header_data, padding)?;
+    }
 
-    writer.write(&header_hmac)?;
+    write_outer_header(&header_data, &hmac_key, writer)?;
+    writer.write_all(&payload_hmac)?;
 
-    // Initialize inner encryptor from inner header params
-    let mut inner_cipher = db
-        .config
-        .inner_cipher_config
-        .get_cipher(&inner_random_stream_key)?;
+    Ok(())
+}
 
-    // dump inner header into buffer
-    let mut payload = Vec::new();
-    KDBX4InnerHeader {
-        inner_random_stream: db.config.inner_cipher_config.clone(),
-        inner_random_stream_key,
-    }
-    .dump(&db.header_attachments, &mut payload)?;
+/// Write out the header, the header hash and the header HMAC
+fn write_outer_header(
+    header_data: &[u8],
+    hmac_key: &GenericArray<u8, U64>,
+    writer: &mut dyn Write,
+) -> Result<(), DatabaseSaveError> {
+    let header_sha256 = crypt::calculate_sha256(&[header_data])?;
+    let header_hmac_key = hmac_block_stream::get_hmac_block_key(u64::max_value(), hmac_key)?;
+    let header_hmac = crypt::calculate_hmac(&[header_data], &header_hmac_key)?;
 
-    // after inner header is one XML document
-    crate::xml_db::dump::dump(&db, &mut *inner_cipher, &mut payload)?;
+    writer.write_all(header_data)?;
+    writer.write_all(&header_sha256)?;
+    writer.write_all(&header_hmac)?;
+    Ok(())
+}
 
-    let payload_compressed = db
-        .config
-        .compression_config
-        .get_compression()
-        .compress(&payload)?;
+/// The attachment contents written to the inner header, and the identifier that each attachment
+/// identifier of the database is written as if they are renumbered
+struct Attachments<'a> {
+    contents: &'a [&'a HeaderAttachment],
+    indices: Option<HashMap<usize, usize>>,
+}
 
-    let payload_encrypted = db
-        .config
-        .outer_cipher_config
-        .get_cipher(&master_key, &outer_iv)?
-        .encrypt(&payload_compressed)?;
+/// Write the inner header and the XML document as a HMAC block stream. They are compressed,
+/// encrypted and split into blocks while they are written, so that neither the plaintext nor the
+/// encrypted payload exists in memory as a whole.
+fn write_payload(
+    db: &Database,
+    attachments: Attachments,
+    options: &SaveOptions,
+    inner_header: &KDBX4InnerHeader,
+    outer_cipher: Box<dyn OuterCipherStream>,
+    hmac_key: &GenericArray<u8, U64>,
+    writer: &mut dyn Write,
+) -> Result<(), DatabaseSaveError> {
+    // Initialize inner encryptor from inner header params
+    let mut inner_cipher = inner_header
+        .inner_random_stream
+        .get_cipher(&inner_header.inner_random_stream_key)?;
+
+    progress::start(Phase::Encryption, None)?;
+    let mut block_stream = hmac_block_stream::HmacBlockStreamWriter::new(writer, hmac_key);
+    {
+        let mut payload_encrypted = EncryptWriter::new(&mut block_stream, outer_cipher);
+        {
+            let mut payload = db
+                .config
+                .compression_config
+                .get_compression_at(options.effective_gzip_level())
+                .compress_stream(&mut payload_encrypted);
+
+            inner_header.dump(attachments.contents, &mut payload)?;
+
+            // after inner header is one XML document
+            let context = DumpContext {
+                attachment_indices: attachments.indices,
+                ..DumpContext::new(&mut *inner_cipher)
+            };
+            crate::xml_db::dump::dump(db, context, &mut payload)?;
+
+            payload.finish()?;
+        }
+        payload_encrypted.finish()?;
+    }
+    block_stream.finish()?;
 
-    let payload_hmac = hmac_block_stream::write_hmac_block_stream(&payload_encrypted, &hmac_key)?;
-    writer.write(&payload_hmac)?;
+    Ok(progress::finish()?)
+}
 
-    Ok(())
+/// The number of bytes a header comment needs to add to `size` to reach a multiple of
+/// `bucket_size`. The comment field itself takes 5 bytes, so small gaps skip to the next bucket.
+pub(crate) fn padding_for_bucket(size: usize, bucket_size: usize) -> usize {
+    let remainder = match size.checked_rem(bucket_size) {
+        None | Some(0) => return 0,
+        Some(remainder) => remainder,
+    };
+
+    let mut padding = bucket_size - remainder;
+    while padding < 5 {
+        padding += bucket_size;
+    }
+    padding
 }
 
 impl HeaderAttachment {
-    fn dump(&self, writer: &mut dyn Write) -> Result<(), std::io::Error> {
+    fn dump(&self, content: &[u8], writer: &mut dyn Write) -> Result<(), std::io::Error> {
         writer.write_u8(self.flags)?;
-        writer.write(&self.content)?;
+        writer.write_all(content)?;
         Ok(())
     }
 }
 
 impl KDBX4OuterHeader {
-    fn dump(&self, writer: &mut dyn Write) -> Result<(), DatabaseSaveError> {
+    /// Dump the header, including a comment field that takes `padding` bytes in total if
+    /// `padding` is not 0
+    fn dump(&self, writer: &mut dyn Write, padding: usize) -> Result<(), DatabaseSaveError> {
         self.version.dump(writer)?;
 
         writer.write_u8(HEADER_OUTER_ENCRYPTION_ID)?;
@@ -139,13 +282,30 @@ impl KDBX4OuterHeader {
         writer.write_u8(HEADER_MASTER_SEED)?;
         writer.write_with_len(&self.master_seed)?;
 
-        let vd: VariantDictionary = self.kdf_config.to_variant_dictionary(&self.kdf_seed);
+        let vd: VariantDictionary = self
+            .kdf_config
+            .to_variant_dictionary(&self.kdf_seed, &self.argon2_secret_parameters);
         let mut vd_buffer = Vec::new();
         vd.dump(&mut vd_buffer)?;
 
         writer.write_u8(HEADER_KDF_PARAMS)?;
         writer.write_with_len(&vd_buffer)?;
 
+        if !self.public_custom_data.is_empty() {
+            let mut public_buffer = Vec::new();
+            self.public_custom_data
+                .to_variant_dictionary()
+                .dump(&mut public_buffer)?;
+
+            writer.write_u8(HEADER_PUBLIC_CUSTOM_DATA)?;
+            writer.write_with_len(&public_buffer)?;
+        }
+
+        if padding > 0 {
+            writer.write_u8(HEADER_COMMENT)?;
+            writer.write_with_len(&vec![0; padding - 5])?;
+        }
+
         writer.write_u8(HEADER_END)?;
         writer.write_with_len(&[])?;
 
@@ -156,10 +316,10 @@ impl KDBX4OuterHeader {
 impl KDBX4InnerHeader {
     fn dump(
         &self,
-        header_attachments: &[HeaderAttachment],
+        header_attachments: &[&HeaderAttachment],
         writer: &mut dyn Write,
     ) -> Result<(), DatabaseSaveError> {
-        writer.write(&[INNER_HEADER_RANDOM_STREAM_ID])?;
+        writer.write_u8(INNER_HEADER_RANDOM_STREAM_ID)?;
         writer.write_u32::<LittleEndian>(4)?;
         writer.write_u32::<LittleEndian>(self.inner_random_stream.dump())?;
 
@@ -167,9 +327,10 @@ impl KDBX4InnerHeader {
         writer.write_with_len(&self.inner_random_stream_key)?;
 
         for attachment in header_attachments {
+            let content = attachment.data()?;
             writer.write_u8(INNER_HEADER_BINARY_ATTACHMENTS)?;
-            writer.write_u32::<LittleEndian>((attachment.content.len() + 1) as u32)?;
-            attachment.dump(writer)?;
+            writer.write_u32::<LittleEndian>((content.len() + 1) as u32)?;
+            attachment.dump(&content, writer)?;
         }
 
         writer.write_u8(INNER_HEADER_END)?;
@@ -0,0 +1,300 @@
+use std::{convert::TryFrom, io::Read};
+
+use byteorder::{ByteOrder, LittleEndian};
+use zeroize::Zeroizing;
+
+use crate::{
+    config::{CompressionConfig, InnerCipherConfig, KdfConfig, OuterCipherConfig},
+    crypt::{self, ciphers::DecryptReader},
+    db::{CheckResult, ForensicReport, HeaderField},
+    format::{
+        kdbx4::{
+            parse::{derive_keys, parse_inner_header, parse_outer_header},
+            HEADER_COMMENT, HEADER_COMPRESSION_ID, HEADER_ENCRYPTION_IV, HEADER_END, HEADER_KDF_PARAMS,
+            HEADER_MASTER_SEED, HEADER_OUTER_ENCRYPTION_ID, HEADER_PUBLIC_CUSTOM_DATA,
+            INNER_HEADER_BINARY_ATTACHMENTS, INNER_HEADER_END, INNER_HEADER_RANDOM_STREAM_ID,
+            INNER_HEADER_RANDOM_STREAM_KEY,
+        },
+        DatabaseVersion,
+    },
+    hmac_block_stream,
+    key::DatabaseKey,
+    variant_dictionary::VariantDictionary,
+};
+
+/// Retrace the steps of `decrypt_kdbx4` and `parse_kdbx4` without panicking on malformed data,
+/// recording how far they get. Keys, seeds and decrypted content are never recorded.
+pub(crate) fn analyze_kdbx4(data: &[u8], db_key: &DatabaseKey, report: &mut ForensicReport) {
+    let header_end = match walk_header(
+        data,
+        DatabaseVersion::get_version_header_size(),
+        describe_outer_field,
+        &mut report.header_fields,
+    ) {
+        Ok(end) => end,
+        Err(reason) => {
+            report.header = CheckResult::Failed(reason);
+            return;
+        }
+    };
+
+    let outer_header = match parse_outer_header(data) {
+        Ok((outer_header, _)) => outer_header,
+        Err(e) => {
+            report.header = CheckResult::Failed(e.to_string());
+            return;
+        }
+    };
+    report.header = CheckResult::Passed;
+
+    let (header_data, header_sha256, header_hmac) = match data.get(header_end..header_end + 64) {
+        Some(hashes) => (&data[..header_end], &hashes[..32], &hashes[32..]),
+        None => {
+            report.header_hash = CheckResult::Failed("The file ends before the header hashes".to_string());
+            return;
+        }
+    };
+
+    // a corrupted header hash is still worth checking the key for
+    report.header_hash = match crypt::calculate_sha256(&[header_data]) {
+        Ok(hash) if hash.as_slice() == header_sha256 => CheckResult::Passed,
+        Ok(_) => CheckResult::Failed("SHA-256 of the header does not match".to_string()),
+        Err(e) => CheckResult::Failed(e.to_string()),
+    };
+
+    let (master_key, hmac_key) = match derive_keys(&outer_header, db_key) {
+        Ok(keys) => keys,
+        Err(e) => {
+            report.header_hmac = CheckResult::Failed(format!("Could not derive the key: {}", e));
+            return;
+        }
+    };
+
+    let header_hmac_matches = hmac_block_stream::get_hmac_block_key(u64::MAX, &hmac_key)
+        .and_then(|key| crypt::calculate_hmac(&[header_data], &key))
+        .is_ok_and(|hmac| hmac.as_slice() == header_hmac);
+    if !header_hmac_matches {
+        report.header_hmac =
+            CheckResult::Failed("HMAC of the header does not match: wrong key or corrupted header".to_string());
+        return;
+    }
+    report.header_hmac = CheckResult::Passed;
+
+    let mut payload_encrypted = Vec::new();
+    let mut pos = header_end + 64;
+    loop {
+        let block_index = report.blocks_verified as u64;
+        let (hmac, size_bytes) = match data.get(pos..pos + 36) {
+            Some(block_header) => (&block_header[..32], &block_header[32..]),
+            None => {
+                report.block_stream = CheckResult::Failed(format!(
+                    "The file ends at offset {} before the header of block {}",
+                    data.len(),
+                    block_index
+                ));
+                return;
+            }
+        };
+
+        let size = LittleEndian::read_u32(size_bytes) as usize;
+        let block = match data.get(pos + 36..pos + 36 + size) {
+            Some(block) => block,
+            None => {
+                report.block_stream = CheckResult::Failed(format!(
+                    "Block {} at offset {} announces {} bytes, but only {} are left",
+                    block_index,
+                    pos,
+                    size,
+                    data.len() - pos - 36
+                ));
+                return;
+            }
+        };
+
+        let block_index_bytes = block_index.to_le_bytes();
+        let block_hmac_matches = hmac_block_stream::get_hmac_block_key(block_index, &hmac_key)
+            .and_then(|key| crypt::calculate_hmac(&[&block_index_bytes, size_bytes, block], &key))
+            .is_ok_and(|expected| expected.as_slice() == hmac);
+        if !block_hmac_matches {
+            report.block_stream = CheckResult::Failed(format!(
+                "HMAC of block {} at offset {} does not match",
+                block_index, pos
+            ));
+            return;
+        }
+
+        report.blocks_verified += 1;
+        pos += 36 + size;
+        if size == 0 {
+            break;
+        }
+        payload_encrypted.extend_from_slice(block);
+    }
+    report.block_stream = CheckResult::Passed;
+    report.trailing_bytes = data.len() - pos;
+
+    let mut payload_compressed = Zeroizing::new(Vec::new());
+    let decrypted = outer_header
+        .outer_cipher_config
+        .get_stream_cipher(&master_key, &outer_header.outer_iv)
+        .map_err(|e| e.to_string())
+        .and_then(|cipher| {
+            DecryptReader::new(&mut payload_encrypted.as_slice(), cipher)
+                .read_to_end(&mut payload_compressed)
+                .map_err(|e| e.to_string())
+        });
+    if let Err(e) = decrypted {
+        report.decryption = CheckResult::Failed(e);
+        return;
+    }
+    report.decryption = CheckResult::Passed;
+
+    let payload = match outer_header
+        .compression_config
+        .get_compression()
+        .decompress(&payload_compressed)
+    {
+        Ok(payload) => Zeroizing::new(payload),
+        Err(e) => {
+            report.decompression = CheckResult::Failed(e.to_string());
+            return;
+        }
+    };
+    report.decompression = CheckResult::Passed;
+    report.decompressed_size = Some(payload.len());
+
+    if let Err(reason) = walk_header(&payload, 0, describe_inner_field, &mut report.inner_header_fields) {
+        report.inner_header = CheckResult::Failed(reason);
+        return;
+    }
+    let (inner_header, body_start) = match parse_inner_header(&payload) {
+        Ok((_, inner_header, body_start)) => (inner_header, body_start),
+        Err(e) => {
+            report.inner_header = CheckResult::Failed(e.to_string());
+            return;
+        }
+    };
+    report.inner_header = CheckResult::Passed;
+
+    let mut inner_cipher = match inner_header
+        .inner_random_stream
+        .get_cipher(&inner_header.inner_random_stream_key)
+    {
+        Ok(cipher) => cipher,
+        Err(e) => {
+            report.xml = CheckResult::Failed(e.to_string());
+            return;
+        }
+    };
+
+    report.xml = match crate::xml_db::parse::parse(&payload[body_start..], &mut *inner_cipher) {
+        Ok(_) => CheckResult::Passed,
+        Err(e) => CheckResult::Failed(e.to_string()),
+    };
+}
+
+/// Record the fields of a header starting at `pos`, returning the position after the end field
+fn walk_header(
+    data: &[u8],
+    mut pos: usize,
+    describe: fn(u8, &[u8]) -> (&'static str, Option<String>),
+    fields: &mut Vec<HeaderField>,
+) -> Result<usize, String> {
+    loop {
+        let field_header = data
+            .get(pos..pos + 5)
+            .ok_or_else(|| format!("The header ends at offset {} inside a field", data.len()))?;
+        let id = field_header[0];
+        let length = LittleEndian::read_u32(&field_header[1..]) as usize;
+
+        let buffer = data.get(pos + 5..pos + 5 + length).ok_or_else(|| {
+            format!(
+                "Field {} at offset {} announces {} bytes, but only {} are left",
+                id,
+                pos,
+                length,
+                data.len() - pos - 5
+            )
+        })?;
+
+        let (name, value) = describe(id, buffer);
+        fields.push(HeaderField {
+            id,
+            name: name.to_string(),
+            offset: pos,
+            length,
+            value,
+        });
+
+        pos += 5 + length;
+        if id == HEADER_END {
+            return Ok(pos);
+        }
+    }
+}
+
+fn describe_outer_field(id: u8, buffer: &[u8]) -> (&'static str, Option<String>) {
+    let known = |value: Option<String>| Some(value.unwrap_or_else(|| "unknown".to_string()));
+
+    match id {
+        HEADER_END => ("End", None),
+        HEADER_COMMENT => ("Comment", None),
+        HEADER_OUTER_ENCRYPTION_ID => (
+            "OuterCipher",
+            known(
+                OuterCipherConfig::try_from_registered(buffer)
+                    .ok()
+                    .map(|c| format!("{:?}", c)),
+            ),
+        ),
+        HEADER_COMPRESSION_ID => (
+            "Compression",
+            known(
+                buffer
+                    .get(..4)
+                    .and_then(|b| CompressionConfig::try_from(LittleEndian::read_u32(b)).ok())
+                    .map(|c| format!("{:?}", c)),
+            ),
+        ),
+        HEADER_MASTER_SEED => ("MasterSeed", None),
+        HEADER_ENCRYPTION_IV => ("EncryptionIV", None),
+        HEADER_KDF_PARAMS => (
+            "KdfParameters",
+            // the seed and the secret parameters of Argon2 are left out
+            known(
+                VariantDictionary::parse(buffer)
+                    .ok()
+                    .and_then(|vd| <(KdfConfig, Vec<u8>)>::try_from(vd).ok())
+                    .map(|(kdf_config, _)| format!("{:?}", kdf_config)),
+            ),
+        ),
+        HEADER_PUBLIC_CUSTOM_DATA => (
+            "PublicCustomData",
+            known(
+                VariantDictionary::parse(buffer)
+                    .ok()
+                    .map(|vd| format!("{} items", vd.data.len())),
+            ),
+        ),
+        _ => ("Unknown", None),
+    }
+}
+
+fn describe_inner_field(id: u8, buffer: &[u8]) -> (&'static str, Option<String>) {
+    match id {
+        INNER_HEADER_END => ("End", None),
+        INNER_HEADER_RANDOM_STREAM_ID => (
+            "InnerCipher",
+            Some(
+                buffer
+                    .get(..4)
+                    .and_then(|b| InnerCipherConfig::try_from(LittleEndian::read_u32(b)).ok())
+                    .map(|c| format!("{:?}", c))
+                    .unwrap_or_else(|| "unknown".to_string()),
+            ),
+        ),
+        INNER_HEADER_RANDOM_STREAM_KEY => ("InnerCipherKey", None),
+        INNER_HEADER_BINARY_ATTACHMENTS => ("Attachment", None),
+        _ => ("Unknown", None),
+    }
+}
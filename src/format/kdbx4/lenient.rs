@@ -0,0 +1,170 @@
+use std::io::Read;
+
+use byteorder::{ByteOrder, LittleEndian};
+use zeroize::Zeroizing;
+
+use crate::{
+    config::DatabaseConfig,
+    crypt,
+    db::{Database, RecoveryReport},
+    error::DatabaseOpenError,
+    format::{
+        kdbx4::parse::{derive_keys, into_database, parse_outer_header, read_inner_header},
+        read_outer_header_data,
+    },
+    hmac_block_stream,
+    key::DatabaseKey,
+};
+
+/// Open as much of a damaged KDBX4 database as possible, recording in the report what had to be
+/// left out. Blocks whose HMAC does not match are used unverified, since leaving them out would
+/// throw the decryption and decompression of everything after them off. A payload that breaks off
+/// is used up to where it does, and the XML document is parsed up to where it breaks off or cannot
+/// be decompressed any further.
+pub(crate) fn parse_lenient(
+    data: &[u8],
+    db_key: &DatabaseKey,
+) -> Result<(Database, RecoveryReport), DatabaseOpenError> {
+    let mut report = RecoveryReport::default();
+
+    let source = &mut &data[..];
+    let header_data = read_outer_header_data(source)?;
+    let (outer_header, _) = parse_outer_header(&header_data)?;
+
+    let mut header_sha256 = [0u8; 32];
+    source.read_exact(&mut header_sha256)?;
+    let mut header_hmac = [0u8; 32];
+    source.read_exact(&mut header_hmac)?;
+
+    let (master_key, hmac_key) = derive_keys(&outer_header, db_key)?;
+
+    // a header HMAC that does not match most likely means a wrong key, and then nothing can be
+    // decrypted. If it does match, the header is intact, even if its hash was damaged.
+    let header_hmac_key = hmac_block_stream::get_hmac_block_key(u64::MAX, &hmac_key)?;
+    if header_hmac != crypt::calculate_hmac(&[&header_data], &header_hmac_key)?.as_slice() {
+        return Err(DatabaseOpenError::InvalidCredentials);
+    }
+    report.header_hash_mismatch = header_sha256 != crypt::calculate_sha256(&[&header_data])?.as_slice();
+
+    let mut payload = Vec::new();
+    let mut pos = header_data.len() + 64;
+    let mut block_index = 0u64;
+    let trailing_data = loop {
+        let (hmac, size_bytes) = match data.get(pos..pos + 36) {
+            Some(block_header) => block_header.split_at(32),
+            None => {
+                report.truncated = true;
+                break Vec::new();
+            }
+        };
+
+        let start = pos + 36;
+        let size = LittleEndian::read_u32(size_bytes) as usize;
+        let block = match data.get(start..start.saturating_add(size)) {
+            Some(block) => block,
+            None => {
+                // what is left of the last block cannot be verified, but is better than nothing
+                payload.extend_from_slice(&data[start.min(data.len())..]);
+                report.truncated = true;
+                break Vec::new();
+            }
+        };
+        pos = start + size;
+
+        let verified = hmac_block_stream::get_hmac_block_key(block_index, &hmac_key)
+            .and_then(|key| crypt::calculate_hmac(&[&block_index.to_le_bytes(), size_bytes, block], &key))
+            .is_ok_and(|expected| expected.as_slice() == hmac);
+        if !verified {
+            // keep the damaged data in place, so that the cipher and the compression stay aligned
+            report.skipped_blocks.push(block_index);
+            payload.extend_from_slice(block);
+        } else if block.is_empty() {
+            break data[pos..].to_vec();
+        } else {
+            payload.extend_from_slice(block);
+        }
+        block_index += 1;
+    };
+
+    // the payload is decrypted as a whole, only leaving out an incomplete block at its end. The
+    // padding is only there if the payload is complete.
+    let mut cipher = outer_header
+        .outer_cipher_config
+        .get_stream_cipher(&master_key, &outer_header.outer_iv)?;
+    let block_size = cipher.block_size();
+    let complete_blocks = payload.len() / block_size * block_size;
+    let mut payload = Zeroizing::new(payload);
+    payload.truncate(complete_blocks);
+    cipher.decrypt_blocks(&mut payload);
+    if block_size > 1 {
+        let padding = payload.last().copied().unwrap_or(0) as usize;
+        if (1..=block_size).contains(&padding)
+            && payload.len() >= padding
+            && payload[payload.len() - padding..]
+                .iter()
+                .all(|b| *b as usize == padding)
+        {
+            let len = payload.len() - padding;
+            payload.truncate(len);
+        }
+    }
+
+    let mut payload_error = None;
+    let mut payload_compressed = &payload[..];
+    let mut decompressed = UntilError {
+        inner: outer_header
+            .compression_config
+            .get_compression()
+            .decompress_stream(&mut payload_compressed),
+        error: &mut payload_error,
+    };
+
+    let (header_attachments, inner_header) = read_inner_header(&mut decompressed)?;
+    let mut inner_decryptor = inner_header
+        .inner_random_stream
+        .get_cipher(&inner_header.inner_random_stream_key)?;
+    let (database_content, xml_error) =
+        crate::xml_db::parse::parse_lenient(&mut decompressed, &mut *inner_decryptor);
+    drop(decompressed);
+    report.payload_error = payload_error.map(|e| e.to_string());
+    report.xml_error = xml_error;
+
+    let config = DatabaseConfig {
+        version: outer_header.version,
+        outer_cipher_config: outer_header.outer_cipher_config,
+        compression_config: outer_header.compression_config,
+        inner_cipher_config: inner_header.inner_random_stream,
+        kdf_config: outer_header.kdf_config,
+        argon2_secret_parameters: outer_header.argon2_secret_parameters,
+    };
+    let db = into_database(
+        config,
+        header_attachments,
+        database_content?,
+        trailing_data,
+        outer_header.public_custom_data,
+    );
+
+    Ok((db, report))
+}
+
+/// Ends the stream at the first error, keeping the error
+struct UntilError<'a, R> {
+    inner: R,
+    error: &'a mut Option<std::io::Error>,
+}
+
+impl<R: Read> Read for UntilError<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
+        if self.error.is_some() {
+            return Ok(0);
+        }
+        match self.inner.read(buf) {
+            Err(e) if e.kind() != std::io::ErrorKind::Interrupted => {
+                *self.error = Some(e);
+                Ok(0)
+            }
+            result => result,
+        }
+    }
+}
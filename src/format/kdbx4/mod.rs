@@ -1,15 +1,28 @@
 #[cfg(feature = "save_kdbx4")]
 mod dump;
+mod forensics;
+mod lenient;
 mod parse;
+mod pipeline;
+
+use zeroize::Zeroizing;
 
 use crate::{
-    config::{CompressionConfig, InnerCipherConfig, KdfConfig, OuterCipherConfig},
+    config::{Argon2SecretParameters, CompressionConfig, InnerCipherConfig, KdfConfig, OuterCipherConfig},
+    db::PublicCustomData,
     format::DatabaseVersion,
 };
 
 #[cfg(feature = "save_kdbx4")]
-pub(crate) use crate::format::kdbx4::dump::dump_kdbx4;
-pub(crate) use crate::format::kdbx4::parse::{decrypt_kdbx4, parse_kdbx4};
+pub(crate) use crate::format::kdbx4::dump::{dump_kdbx4, padding_for_bucket};
+pub(crate) use crate::format::kdbx4::{
+    forensics::analyze_kdbx4,
+    lenient::parse_lenient,
+    parse::{
+        decrypt_kdbx4, decrypt_kdbx4_stream, parse_header_info, parse_kdbx4, parse_kdbx4_transformed,
+        parse_public_custom_data, read_kdbx4, verify_kdbx4_key,
+    },
+};
 
 #[cfg(feature = "save_kdbx4")]
 /// Size for a master seed in bytes
@@ -29,6 +42,8 @@ pub const HEADER_MASTER_SEED: u8 = 4;
 pub const HEADER_ENCRYPTION_IV: u8 = 7;
 /// Parameters for the key derivation function
 pub const HEADER_KDF_PARAMS: u8 = 11;
+/// Custom data that can be read without the key
+pub const HEADER_PUBLIC_CUSTOM_DATA: u8 = 12;
 
 /// Inner header entry denoting the end of the inner header
 pub const INNER_HEADER_END: u8 = 0x00;
@@ -47,11 +62,13 @@ struct KDBX4OuterHeader {
     outer_iv: Vec<u8>,
     kdf_config: KdfConfig,
     kdf_seed: Vec<u8>,
+    argon2_secret_parameters: Argon2SecretParameters,
+    public_custom_data: PublicCustomData,
 }
 
 struct KDBX4InnerHeader {
     inner_random_stream: InnerCipherConfig,
-    inner_random_stream_key: Vec<u8>,
+    inner_random_stream_key: Zeroizing<Vec<u8>>,
 }
 
 #[cfg(feature = "save_kdbx4")]
@@ -62,7 +79,7 @@ mod kdbx4_tests {
     use crate::format::kdbx4::dump::dump_kdbx4;
     use crate::{
         config::{CompressionConfig, DatabaseConfig, InnerCipherConfig, KdfConfig, OuterCipherConfig},
-        db::{Database, Entry, Group, HeaderAttachment, NodeRef, Value},
+        db::{AttachmentRef, Database, Entry, Group, HeaderAttachment, Node, NodeRef, SaveOptions, Value},
         format::KDBX4_CURRENT_MINOR_VERSION,
         key::DatabaseKey,
     };
@@ -93,13 +110,52 @@ mod kdbx4_tests {
             ));
 
         let mut encrypted_db = Vec::new();
-        dump_kdbx4(&db, &db_key, &mut encrypted_db).unwrap();
+        dump_kdbx4(&db, &db_key, &mut encrypted_db, &Default::default()).unwrap();
 
         let decrypted_db = parse_kdbx4(&encrypted_db, &db_key).unwrap();
 
         assert_eq!(decrypted_db.root.children.len(), 3);
     }
 
+    #[cfg(feature = "challenge_response")]
+    #[test]
+    fn test_with_challenge_response_provider() {
+        use crate::{error::DatabaseKeyError, key::ChallengeResponseKey, ChallengeResponseProvider};
+
+        #[derive(Debug)]
+        struct Token;
+
+        impl ChallengeResponseProvider for Token {
+            fn challenge_response(&self, challenge: &[u8]) -> Result<Vec<u8>, DatabaseKeyError> {
+                Ok(crate::crypt::calculate_hmac_sha1(&[challenge], &[7; 20])?.to_vec())
+            }
+        }
+
+        let mut db = Database::new(DatabaseConfig::default());
+        db.root.add_child(Entry::new());
+
+        let db_key = DatabaseKey::new()
+            .with_password("test")
+            .with_challenge_response_key(ChallengeResponseKey::Provider(std::sync::Arc::new(Token)));
+
+        let mut encrypted_db = Vec::new();
+        dump_kdbx4(&db, &db_key, &mut encrypted_db, &Default::default()).unwrap();
+
+        // the provider is interchangeable with any other source of the same responses
+        let local_key = DatabaseKey::new()
+            .with_password("test")
+            .with_challenge_response_key(ChallengeResponseKey::LocalChallenge(hex::encode([7; 20])));
+        assert_eq!(
+            parse_kdbx4(&encrypted_db, &local_key)
+                .unwrap()
+                .root
+                .children
+                .len(),
+            1
+        );
+        assert!(parse_kdbx4(&encrypted_db, &DatabaseKey::new().with_password("test")).is_err());
+    }
+
     fn test_with_config(config: DatabaseConfig) {
         let mut db = Database::new(config);
 
@@ -130,10 +186,12 @@ mod kdbx4_tests {
         let db_key = DatabaseKey::new().with_password(&password);
 
         let mut encrypted_db = Vec::new();
-        dump_kdbx4(&db, &db_key, &mut encrypted_db).unwrap();
+        dump_kdbx4(&db, &db_key, &mut encrypted_db, &Default::default()).unwrap();
 
         let decrypted_db = parse_kdbx4(&encrypted_db, &db_key).unwrap();
 
+        // the KDF variant and all other settings survive the round trip
+        assert_eq!(decrypted_db.config, db.config);
         assert_eq!(decrypted_db.root.children.len(), 3);
 
         if let Some(NodeRef::Entry(e)) = decrypted_db.root.get(&["Demo Entry"]) {
@@ -185,6 +243,7 @@ mod kdbx4_tests {
                             compression_config: compression_config.clone(),
                             inner_cipher_config: inner_cipher_config.clone(),
                             kdf_config: kdf_config.clone(),
+                            argon2_secret_parameters: Default::default(),
                         };
 
                         println!("Testing with config: {config:?}");
@@ -196,6 +255,42 @@ mod kdbx4_tests {
         }
     }
 
+    #[test]
+    pub fn gzip_levels() {
+        let key = DatabaseKey::new().with_password("test");
+        let save = |level| {
+            let mut db = Database::new(DatabaseConfig {
+                compression_config: CompressionConfig::GZip,
+                kdf_config: KdfConfig::Aes { rounds: 10 },
+                ..Default::default()
+            });
+            db.header_attachments.push(HeaderAttachment {
+                flags: 0,
+                content: b"compressible ".repeat(10_000),
+                ..Default::default()
+            });
+            let mut entry = Entry::new();
+            entry.attachments.push(AttachmentRef {
+                name: "large.txt".to_string(),
+                identifier: 0,
+            });
+            db.root.add_child(entry);
+
+            let mut data = Vec::new();
+            let options = SaveOptions::default().with_gzip_level(level);
+            dump_kdbx4(&db, &key, &mut data, &options).unwrap();
+            let reopened = parse_kdbx4(&data, &key).unwrap();
+            assert!(reopened.header_attachments == db.header_attachments);
+            assert_eq!(reopened.config, db.config);
+            data.len()
+        };
+
+        // level 0 only wraps the payload into GZip, without compressing it
+        assert!(save(0) > 130_000);
+        assert!(save(1) < 10_000);
+        assert!(save(9) <= save(1));
+    }
+
     #[test]
     pub fn header_attachments() {
         let mut root_group = Group::new("Root");
@@ -207,10 +302,14 @@ mod kdbx4_tests {
             HeaderAttachment {
                 flags: 1,
                 content: vec![0x01, 0x02, 0x03, 0x04],
+                packed: false,
+                external: false,
             },
             HeaderAttachment {
                 flags: 2,
                 content: vec![0x04, 0x03, 0x02, 0x01],
+                packed: false,
+                external: false,
             },
         ];
 
@@ -218,13 +317,24 @@ mod kdbx4_tests {
         entry
             .fields
             .insert("Title".to_string(), Value::Unprotected("Demo entry".to_string()));
+        entry.attachments.push(AttachmentRef {
+            name: "first.txt".to_string(),
+            identifier: 0,
+        });
+        entry.update_history();
+
+        entry.attachments = vec![AttachmentRef {
+            name: "second.txt".to_string(),
+            identifier: 1,
+        }];
+        entry.update_history();
 
         db.root.add_child(entry);
 
         let db_key = DatabaseKey::new().with_password("test");
 
         let mut encrypted_db = Vec::new();
-        dump_kdbx4(&db, &db_key, &mut encrypted_db).unwrap();
+        dump_kdbx4(&db, &db_key, &mut encrypted_db, &Default::default()).unwrap();
 
         let decrypted_db = parse_kdbx4(&encrypted_db, &db_key).unwrap();
 
@@ -234,5 +344,306 @@ mod kdbx4_tests {
         assert_eq!(header_attachments.len(), 2);
         assert_eq!(header_attachments[0].flags, 1);
         assert_eq!(header_attachments[0].content, [0x01, 0x02, 0x03, 0x04]);
+
+        let entry = match &decrypted_db.root.children[0] {
+            Node::Entry(e) => e,
+            _ => panic!("Expected an entry"),
+        };
+        assert_eq!(entry.attachments()[0].name, "second.txt");
+        assert_eq!(entry.attachments()[0].identifier, 1);
+
+        let history = entry.history.as_ref().unwrap();
+        assert_eq!(history.attachments(1).unwrap()[0].name, "first.txt");
+        assert_eq!(history.attachments(1).unwrap()[0].identifier, 0);
+    }
+
+    #[cfg(feature = "argon2_secret")]
+    #[test]
+    pub fn argon2_secret_parameters() {
+        let mut config = DatabaseConfig::default();
+        config
+            .argon2_secret_parameters
+            .set_secret(Some(b"device bound secret".to_vec()));
+        config
+            .argon2_secret_parameters
+            .set_associated_data(Some(b"associated data".to_vec()));
+
+        // the secret parameters change the derived key
+        let composite_key = Default::default();
+        let seed = [0u8; 32];
+        assert_ne!(
+            config
+                .kdf_config
+                .get_kdf_seeded(&seed, &config.argon2_secret_parameters)
+                .transform_key(&composite_key)
+                .unwrap(),
+            config
+                .kdf_config
+                .get_kdf_seeded(&seed, &Default::default())
+                .transform_key(&composite_key)
+                .unwrap()
+        );
+
+        let mut db = Database::new(config);
+        db.root.add_child(Entry::new());
+
+        let db_key = DatabaseKey::new().with_password("test");
+        let mut encrypted_db = Vec::new();
+        dump_kdbx4(&db, &db_key, &mut encrypted_db, &Default::default()).unwrap();
+
+        let decrypted_db = parse_kdbx4(&encrypted_db, &db_key).unwrap();
+        assert_eq!(
+            decrypted_db.config.argon2_secret_parameters,
+            db.config.argon2_secret_parameters
+        );
+        assert_eq!(decrypted_db.root.children.len(), 1);
+
+        let debug = format!("{:?}", decrypted_db.config);
+        assert!(!debug.contains("115, 101, 99, 114, 101, 116"));
+        assert!(debug.contains("<redacted>"));
+
+        let warnings = Database::parse_with_warnings(&encrypted_db, db_key)
+            .unwrap()
+            .warnings;
+        assert!(warnings.contains(&crate::db::ParseWarning::Interoperability {
+            setting: "Argon2 secret key".to_string()
+        }));
+    }
+
+    #[test]
+    pub fn trailing_data() {
+        let mut db = Database::new(DatabaseConfig::default());
+        db.root.add_child(Entry::new());
+
+        let db_key = DatabaseKey::new().with_password("test");
+
+        let mut encrypted_db = Vec::new();
+        dump_kdbx4(&db, &db_key, &mut encrypted_db, &Default::default()).unwrap();
+        assert!(parse_kdbx4(&encrypted_db, &db_key)
+            .unwrap()
+            .trailing_data
+            .is_empty());
+
+        encrypted_db.extend_from_slice(b"appended signature");
+        let mut decrypted_db = parse_kdbx4(&encrypted_db, &db_key).unwrap();
+        assert_eq!(decrypted_db.trailing_data, b"appended signature");
+        assert_eq!(decrypted_db.root.children.len(), 1);
+
+        // trailing data is stripped by default
+        let mut stripped = Vec::new();
+        decrypted_db.save(&mut stripped, db_key.clone()).unwrap();
+        assert!(parse_kdbx4(&stripped, &db_key).unwrap().trailing_data.is_empty());
+
+        decrypted_db.trailing_data = b"new signature".to_vec();
+        let mut preserved = Vec::new();
+        let options = crate::db::SaveOptions {
+            preserve_trailing_data: true,
+            ..Default::default()
+        };
+        decrypted_db
+            .save_with_options(&mut preserved, db_key.clone(), &options)
+            .unwrap();
+        assert!(preserved.ends_with(b"new signature"));
+        assert_eq!(
+            parse_kdbx4(&preserved, &db_key).unwrap().trailing_data,
+            b"new signature"
+        );
+    }
+
+    /// A source that returns few bytes at a time, like a slow network stream
+    struct Trickle<'a>(&'a [u8]);
+
+    impl<'a> std::io::Read for Trickle<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let len = buf.len().min(self.0.len()).min(4099);
+            buf[..len].copy_from_slice(&self.0[..len]);
+            self.0 = &self.0[len..];
+            Ok(len)
+        }
+    }
+
+    #[test]
+    pub fn stream_large_payloads() {
+        // more than one block of the HMAC block stream, and not a multiple of the AES block size
+        let content: Vec<u8> = (0..1_100_001u32).map(|i| (i * 7 % 251) as u8).collect();
+        let db_key = DatabaseKey::new().with_password("test");
+
+        for outer_cipher_config in [
+            OuterCipherConfig::AES256,
+            OuterCipherConfig::Twofish,
+            OuterCipherConfig::ChaCha20,
+        ]
+        .iter()
+        {
+            for compression_config in [CompressionConfig::None, CompressionConfig::GZip].iter() {
+                let mut db = Database::new(DatabaseConfig {
+                    outer_cipher_config: outer_cipher_config.clone(),
+                    compression_config: compression_config.clone(),
+                    kdf_config: KdfConfig::Aes { rounds: 10 },
+                    ..Default::default()
+                });
+                db.header_attachments.push(HeaderAttachment {
+                    flags: 1,
+                    content: content.clone(),
+                    packed: false,
+                    external: false,
+                });
+                // attachments that no entry refers to are dropped when saving
+                let mut entry = Entry::new();
+                entry.attachments.push(AttachmentRef {
+                    name: "attachment.bin".to_string(),
+                    identifier: 0,
+                });
+                db.root.add_child(entry);
+                db.trailing_data = b"signature".to_vec();
+
+                let mut encrypted_db = Vec::new();
+                let options = crate::db::SaveOptions {
+                    preserve_trailing_data: true,
+                    ..Default::default()
+                };
+                db.save_with_options(&mut encrypted_db, db_key.clone(), &options)
+                    .unwrap();
+
+                let decrypted_db = Database::open(&mut Trickle(&encrypted_db), db_key.clone()).unwrap();
+                assert_eq!(decrypted_db.header_attachments, db.header_attachments);
+                assert_eq!(decrypted_db.trailing_data, b"signature");
+                assert_eq!(decrypted_db.config, db.config);
+            }
+        }
+
+        // corrupted blocks are still detected when streaming
+        let db = Database::new(DatabaseConfig::default());
+        let mut encrypted_db = Vec::new();
+        db.save(&mut encrypted_db, db_key.clone()).unwrap();
+        let last = encrypted_db.len() - 40;
+        encrypted_db[last] ^= 1;
+        assert!(matches!(
+            Database::open(&mut Trickle(&encrypted_db), db_key),
+            Err(crate::error::DatabaseOpenError::DatabaseIntegrity(
+                crate::error::DatabaseIntegrityError::BlockStream(
+                    crate::error::BlockStreamError::BlockHashMismatch { .. }
+                )
+            ))
+        ));
+    }
+
+    #[test]
+    pub fn open_errors_tell_credentials_from_corruption() {
+        use crate::error::DatabaseOpenError;
+        use byteorder::{ByteOrder, LittleEndian};
+
+        let mut db = Database::new(DatabaseConfig::default());
+        db.root.add_child(Entry::new());
+        let db_key = DatabaseKey::new().with_password("test");
+        let mut encrypted_db = Vec::new();
+        db.save(&mut encrypted_db, db_key.clone()).unwrap();
+
+        // the value of an outer header field
+        let field = |data: &[u8], wanted: u8| {
+            let mut pos = DatabaseVersion::get_version_header_size();
+            loop {
+                let length = LittleEndian::read_u32(&data[pos + 1..pos + 5]) as usize;
+                if data[pos] == wanted {
+                    return pos + 5..pos + 5 + length;
+                }
+                pos += 5 + length;
+            }
+        };
+        let open = |data: &[u8], key: DatabaseKey| Database::parse(data, key).unwrap_err();
+
+        assert!(matches!(
+            open(&encrypted_db, DatabaseKey::new().with_password("wrong")),
+            DatabaseOpenError::InvalidCredentials
+        ));
+
+        let mut tampered = encrypted_db.clone();
+        tampered[field(&encrypted_db, HEADER_MASTER_SEED).start] ^= 1;
+        assert!(matches!(
+            open(&tampered, db_key.clone()),
+            DatabaseOpenError::CorruptHeader { field } if field == "header hash"
+        ));
+
+        let mut unknown_cipher = encrypted_db.clone();
+        unknown_cipher[field(&encrypted_db, HEADER_OUTER_ENCRYPTION_ID)].fill(0x42);
+        assert!(matches!(
+            open(&unknown_cipher, db_key.clone()),
+            DatabaseOpenError::UnsupportedFeature(_)
+        ));
+
+        let mut newer_version = encrypted_db.clone();
+        newer_version[10] = 5;
+        assert!(matches!(
+            open(&newer_version, db_key),
+            DatabaseOpenError::IncompatibleVersion { found, .. } if found == "KDBX5.0"
+        ));
+    }
+
+    #[test]
+    pub fn pad_to_bucket() {
+        let mut db = Database::new(DatabaseConfig::default());
+        db.root.add_child(Entry::new());
+        let db_key = DatabaseKey::new().with_password("test");
+        let options = crate::db::SaveOptions::default().pad_to_bucket(4096);
+
+        let mut padded = Vec::new();
+        db.save_with_options(&mut padded, db_key.clone(), &options)
+            .unwrap();
+        assert_eq!(padded.len(), 4096);
+        assert_eq!(parse_kdbx4(&padded, &db_key).unwrap().root.children.len(), 1);
+
+        for _ in 0..50 {
+            db.root.add_child(Entry::new());
+        }
+        let mut grown = Vec::new();
+        db.save_with_options(&mut grown, db_key.clone(), &options)
+            .unwrap();
+        assert_eq!(grown.len() % 4096, 0);
+        assert_eq!(parse_kdbx4(&grown, &db_key).unwrap().root.children.len(), 51);
+
+        assert_eq!(super::dump::padding_for_bucket(4096, 4096), 0);
+        assert_eq!(super::dump::padding_for_bucket(4093, 4096), 4099);
+        assert_eq!(super::dump::padding_for_bucket(4000, 4096), 96);
+    }
+
+    #[test]
+    pub fn transformed_key_skips_the_kdf() {
+        let mut db = Database::new(DatabaseConfig::default());
+        db.root.add_child(Entry::new());
+        let db_key = DatabaseKey::new().with_password("test");
+        let mut encrypted_db = Vec::new();
+        dump_kdbx4(&db, &db_key, &mut encrypted_db, &Default::default()).unwrap();
+
+        let (_, transformed_key) = Database::parse_transformed(&encrypted_db, db_key.clone()).unwrap();
+        assert_eq!(transformed_key.kdf_config(), &db.config.kdf_config);
+        assert!(!format!("{:?}", transformed_key).contains(&format!("{:?}", transformed_key.as_bytes())));
+
+        // a key without any key elements can only work by reusing the transformed key
+        let reuse_key = DatabaseKey::new().with_transformed_key(transformed_key.clone());
+        let mut resaved = Vec::new();
+        dump_kdbx4(&db, &reuse_key, &mut resaved, &Default::default()).unwrap();
+        assert_ne!(resaved, encrypted_db);
+        assert_eq!(parse_kdbx4(&resaved, &reuse_key).unwrap().root, db.root);
+        assert_eq!(parse_kdbx4(&resaved, &db_key).unwrap().root, db.root);
+
+        // once the KDF settings change, the key elements are needed again
+        db.config.kdf_config = KdfConfig::Aes { rounds: 10 };
+        assert!(matches!(
+            dump_kdbx4(&db, &reuse_key, &mut Vec::new(), &Default::default()),
+            Err(crate::error::DatabaseSaveError::Key(_))
+        ));
+        let mut rekeyed = Vec::new();
+        dump_kdbx4(
+            &db,
+            &db_key.clone().with_transformed_key(transformed_key),
+            &mut rekeyed,
+            &Default::default(),
+        )
+        .unwrap();
+        assert!(matches!(
+            parse_kdbx4(&rekeyed, &reuse_key),
+            Err(crate::error::DatabaseOpenError::InvalidCredentials)
+        ));
+        assert_eq!(parse_kdbx4(&rekeyed, &db_key).unwrap().root, db.root);
     }
 }
@@ -1,137 +1,464 @@
 use std::convert::{TryFrom, TryInto};
+use std::io::Read;
 
-use byteorder::{ByteOrder, LittleEndian};
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt};
+use cipher::generic_array::{
+    typenum::{U32, U64},
+    GenericArray,
+};
+use zeroize::Zeroizing;
 
 use crate::{
-    config::{CompressionConfig, DatabaseConfig, InnerCipherConfig, KdfConfig, OuterCipherConfig},
-    crypt::{self, ciphers::Cipher},
-    db::{Database, HeaderAttachment},
-    error::{DatabaseIntegrityError, DatabaseKeyError, DatabaseOpenError},
+    config::{
+        Argon2SecretParameters, CompressionConfig, DatabaseConfig, InnerCipherConfig, KdfConfig,
+        OuterCipherConfig,
+    },
+    crypt::{
+        self,
+        ciphers::{DecryptReader, InnerStreamCipher},
+    },
+    db::{Database, HeaderAttachment, HeaderInfo, PublicCustomData},
+    error::{BlockStreamError, CryptographyError, DatabaseIntegrityError, DatabaseOpenError, KdfConfigError},
     format::{
         kdbx4::{
             KDBX4OuterHeader, HEADER_COMMENT, HEADER_COMPRESSION_ID, HEADER_ENCRYPTION_IV, HEADER_END,
-            HEADER_KDF_PARAMS, HEADER_MASTER_SEED, HEADER_OUTER_ENCRYPTION_ID, INNER_HEADER_BINARY_ATTACHMENTS,
-            INNER_HEADER_END, INNER_HEADER_RANDOM_STREAM_ID, INNER_HEADER_RANDOM_STREAM_KEY,
+            HEADER_KDF_PARAMS, HEADER_MASTER_SEED, HEADER_OUTER_ENCRYPTION_ID, HEADER_PUBLIC_CUSTOM_DATA,
+            INNER_HEADER_BINARY_ATTACHMENTS, INNER_HEADER_END, INNER_HEADER_RANDOM_STREAM_ID,
+            INNER_HEADER_RANDOM_STREAM_KEY,
         },
-        DatabaseVersion,
+        read_outer_header_data, DatabaseVersion,
     },
     hmac_block_stream,
-    key::DatabaseKey,
+    key::{DatabaseKey, TransformedKey},
+    progress::{self, Phase},
     variant_dictionary::VariantDictionary,
+    xml_db::parse::KeePassXml,
 };
 
-use super::KDBX4InnerHeader;
+use super::{pipeline, KDBX4InnerHeader};
 
-impl From<&[u8]> for HeaderAttachment {
-    fn from(data: &[u8]) -> Self {
-        let flags = data[0];
-        let content = data[1..].to_vec();
+/// Open, decrypt and parse a KeePass database from a source and key elements
+pub(crate) fn parse_kdbx4(data: &[u8], db_key: &DatabaseKey) -> Result<Database, DatabaseOpenError> {
+    read_kdbx4(&mut &data[..], Some(data.len() as u64), db_key)
+}
 
-        HeaderAttachment { flags, content }
-    }
+/// Parse a KeePass database, returning its transformed key along with it
+pub(crate) fn parse_kdbx4_transformed(
+    data: &[u8],
+    db_key: &DatabaseKey,
+) -> Result<(Database, TransformedKey), DatabaseOpenError> {
+    let header_data = read_outer_header_data(&mut &data[..])?;
+    let (outer_header, _) = parse_outer_header(&header_data)?;
+    let transformed_key = transform_key(&outer_header, db_key)?;
+
+    let db = parse_kdbx4(
+        data,
+        &db_key.clone().with_transformed_key(transformed_key.clone()),
+    )?;
+    Ok((db, transformed_key))
 }
 
-/// Open, decrypt and parse a KeePass database from a source and key elements
-pub(crate) fn parse_kdbx4(data: &[u8], db_key: &DatabaseKey) -> Result<Database, DatabaseOpenError> {
-    let (config, header_attachments, mut inner_decryptor, xml) = decrypt_kdbx4(data, db_key)?;
+/// Read, decrypt and parse a KeePass database from a stream. The payload is verified, decrypted,
+/// decompressed and parsed while it is read, so that neither the encrypted database nor its XML
+/// document are ever held in memory as a whole. `len` is the length of the source, if known, to
+/// report the progress of reading the payload against, see `crate::progress`.
+pub(crate) fn read_kdbx4(
+    source: &mut dyn Read,
+    len: Option<u64>,
+    db_key: &DatabaseKey,
+) -> Result<Database, DatabaseOpenError> {
+    let (config, header_attachments, database_content, trailing_data, public_custom_data) =
+        read_kdbx4_stream(source, len, db_key, |xml, inner_header| {
+            let mut inner_decryptor = inner_header
+                .inner_random_stream
+                .get_cipher(&inner_header.inner_random_stream_key)?;
+
+            // errors of the block stream surface as XML errors, so keep the original ones
+            let mut xml = KeepIoError {
+                inner: xml,
+                error: None,
+            };
+            match crate::xml_db::parse::parse_from_reader(&mut xml, &mut *inner_decryptor) {
+                Ok(database_content) => Ok(database_content),
+                Err(e) => Err(xml.error.map_or_else(|| e.into(), DatabaseOpenError::from)),
+            }
+        })?;
 
-    let database_content = crate::xml_db::parse::parse(&xml, &mut *inner_decryptor)?;
+    Ok(into_database(
+        config,
+        header_attachments,
+        database_content,
+        trailing_data,
+        public_custom_data,
+    ))
+}
 
-    let db = Database {
+/// Put together a database from what was read from a KDBX4 file
+pub(crate) fn into_database(
+    config: DatabaseConfig,
+    header_attachments: Vec<HeaderAttachment>,
+    database_content: KeePassXml,
+    trailing_data: Vec<u8>,
+    mut public_custom_data: PublicCustomData,
+) -> Database {
+    public_custom_data.ensure_database_uuid();
+
+    Database {
         config,
         header_attachments,
         root: database_content.root.group,
         deleted_objects: database_content.root.deleted_objects,
         meta: database_content.meta,
-    };
-
-    Ok(db)
+        trailing_data,
+        public_custom_data,
+        annotations: Default::default(),
+        file_fingerprint: Default::default(),
+        key_check: Default::default(),
+    }
 }
 
+/// The configuration, the attachments, the inner decryptor, the XML document, the trailing data
+/// and the public custom data of a decrypted KDBX4 database
+type DecryptedKdbx4 = (
+    DatabaseConfig,
+    Vec<HeaderAttachment>,
+    Box<dyn InnerStreamCipher>,
+    Zeroizing<Vec<u8>>,
+    Vec<u8>,
+    PublicCustomData,
+);
+
+/// The configuration, the attachments, what was read from the XML document, the trailing data and
+/// the public custom data of a KDBX4 database read from a stream
+type StreamedKdbx4<T> = (
+    DatabaseConfig,
+    Vec<HeaderAttachment>,
+    T,
+    Vec<u8>,
+    PublicCustomData,
+);
+
 /// Open and decrypt a KeePass KDBX4 database from a source and key elements
 pub(crate) fn decrypt_kdbx4(
     data: &[u8],
     db_key: &DatabaseKey,
-) -> Result<(DatabaseConfig, Vec<HeaderAttachment>, Box<dyn Cipher>, Vec<u8>), DatabaseOpenError> {
-    // parse header
-    let (outer_header, inner_header_start) = parse_outer_header(data)?;
+) -> Result<
+    (
+        DatabaseConfig,
+        Vec<HeaderAttachment>,
+        Box<dyn InnerStreamCipher>,
+        Zeroizing<Vec<u8>>,
+        Vec<u8>,
+    ),
+    DatabaseOpenError,
+> {
+    let (config, header_attachments, inner_decryptor, xml, trailing_data, _) =
+        decrypt_kdbx4_stream(&mut &data[..], Some(data.len() as u64), db_key)?;
+    Ok((config, header_attachments, inner_decryptor, xml, trailing_data))
+}
+
+/// Read and decrypt a KeePass KDBX4 database from a stream of length `len`, if known
+pub(crate) fn decrypt_kdbx4_stream(
+    source: &mut dyn Read,
+    len: Option<u64>,
+    db_key: &DatabaseKey,
+) -> Result<DecryptedKdbx4, DatabaseOpenError> {
+    let (config, header_attachments, (inner_decryptor, xml), trailing_data, public_custom_data) =
+        read_kdbx4_stream(source, len, db_key, |xml, inner_header| {
+            // initialize the inner decryptor
+            let inner_decryptor = inner_header
+                .inner_random_stream
+                .get_cipher(&inner_header.inner_random_stream_key)?;
+
+            let mut buffer = Zeroizing::new(Vec::new());
+            xml.read_to_end(&mut buffer)?;
+            Ok((inner_decryptor, buffer))
+        })?;
+
+    Ok((
+        config,
+        header_attachments,
+        inner_decryptor,
+        xml,
+        trailing_data,
+        public_custom_data,
+    ))
+}
+
+/// Check whether a key opens a KeePass KDBX4 database by the HMAC of its header, reading nothing
+/// past the header
+pub(crate) fn verify_kdbx4_key(source: &mut dyn Read, db_key: &DatabaseKey) -> Result<bool, DatabaseOpenError> {
+    let header_data = read_outer_header_data(source)?;
+    let (outer_header, _) = parse_outer_header(&header_data)?;
+
+    // the HMAC follows the SHA-256 hash of the header
+    let mut header_hashes = [0u8; 64];
+    source.read_exact(&mut header_hashes)?;
 
-    // split file into segments:
+    let (_, hmac_key) = derive_keys(&outer_header, db_key)?;
+    let header_hmac_key = hmac_block_stream::get_hmac_block_key(u64::MAX, &hmac_key)?;
+    Ok(header_hashes[32..] == *crypt::calculate_hmac(&[&header_data], &header_hmac_key)?.as_slice())
+}
+
+/// Read and decrypt a KeePass KDBX4 database from a stream, passing its XML document to
+/// `read_xml` as it is decompressed
+fn read_kdbx4_stream<T>(
+    source: &mut dyn Read,
+    len: Option<u64>,
+    db_key: &DatabaseKey,
+    read_xml: impl FnOnce(&mut dyn Read, &KDBX4InnerHeader) -> Result<T, DatabaseOpenError>,
+) -> Result<StreamedKdbx4<T>, DatabaseOpenError> {
+    // the file consists of these segments:
     //      header_data         - The outer header data
     //      header_sha256       - A Sha256 hash of header_data (for verification of header integrity)
     //      header_hmac         - A HMAC of the header_data (for verification of the key_elements)
     //      hmac_block_stream   - A HMAC-verified block stream of encrypted and compressed blocks
-    let header_data = &data[0..inner_header_start];
-    let header_sha256 = &data[inner_header_start..(inner_header_start + 32)];
-    let header_hmac = &data[(inner_header_start + 32)..(inner_header_start + 64)];
-    let hmac_block_stream = &data[(inner_header_start + 64)..];
+    let header_data = read_outer_header_data(source)?;
+    let (outer_header, _) = parse_outer_header(&header_data)?;
+
+    let mut header_sha256 = [0u8; 32];
+    source.read_exact(&mut header_sha256)?;
+    let mut header_hmac = [0u8; 32];
+    source.read_exact(&mut header_hmac)?;
 
     // verify header
-    if header_sha256 != crypt::calculate_sha256(&[header_data])?.as_slice() {
-        return Err(DatabaseIntegrityError::HeaderHashMismatch.into());
+    if header_sha256 != crypt::calculate_sha256(&[&header_data])?.as_slice() {
+        return Err(corrupt_header("header hash"));
     }
 
-    #[cfg(feature = "challenge_response")]
-    let db_key = db_key.clone().perform_challenge(&outer_header.kdf_seed)?;
-
-    // derive master key from composite key, transform_seed, transform_rounds and master_seed
-    let key_elements = db_key.get_key_elements()?;
-    let key_elements: Vec<&[u8]> = key_elements.iter().map(|v| &v[..]).collect();
-    let composite_key = crypt::calculate_sha256(&key_elements)?;
-    let transformed_key = outer_header
-        .kdf_config
-        .get_kdf_seeded(&outer_header.kdf_seed)
-        .transform_key(&composite_key)?;
-    let master_key = crypt::calculate_sha256(&[outer_header.master_seed.as_ref(), &transformed_key])?;
+    let (master_key, hmac_key) = derive_keys(&outer_header, db_key)?;
 
     // verify credentials
-    let hmac_key = crypt::calculate_sha512(&[
-        &outer_header.master_seed,
-        &transformed_key,
-        &hmac_block_stream::HMAC_KEY_END,
-    ])?;
     let header_hmac_key = hmac_block_stream::get_hmac_block_key(u64::max_value(), &hmac_key)?;
-    if header_hmac != crypt::calculate_hmac(&[header_data], &header_hmac_key)?.as_slice() {
-        return Err(DatabaseKeyError::IncorrectKey.into());
+    if header_hmac != crypt::calculate_hmac(&[&header_data], &header_hmac_key)?.as_slice() {
+        return Err(DatabaseOpenError::InvalidCredentials);
     }
 
-    // read encrypted payload from hmac-verified block stream
-    let payload_encrypted = hmac_block_stream::read_hmac_block_stream(&hmac_block_stream, &hmac_key)?;
+    // the payload follows the header, its hash and its HMAC
+    progress::start(
+        Phase::Decryption,
+        len.map(|len| len.saturating_sub(header_data.len() as u64 + 64)),
+    )?;
+
+    // read the payload from the hmac-verified block stream, decrypting and decompressing it on
+    // the fly, with the blocks verified and decrypted on other threads where possible
+    let payload = match pipeline::verifier_threads() {
+        Some(verifiers) => {
+            pipeline::read_payload_parallel(source, &outer_header, &master_key, &hmac_key, verifiers, read_xml)
+        }
+        None => read_payload(source, &outer_header, &master_key, &hmac_key, read_xml),
+    };
+    // a cancellation may surface as any error of the parser reading the payload
+    let (header_attachments, inner_header, content) = payload.map_err(|e| match progress::is_cancelled() {
+        true => DatabaseOpenError::Cancelled,
+        false => unwrap_stream_error(e),
+    })?;
+    progress::finish()?;
+
+    // Some tools append their own data (e.g. signatures) after the final block, which is kept
+    // as-is.
+    let mut trailing_data = Vec::new();
+    source.read_to_end(&mut trailing_data)?;
 
-    // Decrypt and decompress encrypted payload
-    let payload_compressed = outer_header
-        .outer_cipher_config
-        .get_cipher(&master_key, &outer_header.outer_iv)?
-        .decrypt(&payload_encrypted)?;
+    let config = DatabaseConfig {
+        version: outer_header.version,
+        outer_cipher_config: outer_header.outer_cipher_config,
+        compression_config: outer_header.compression_config,
+        inner_cipher_config: inner_header.inner_random_stream,
+        kdf_config: outer_header.kdf_config,
+        argon2_secret_parameters: outer_header.argon2_secret_parameters,
+    };
+
+    Ok((
+        config,
+        header_attachments,
+        content,
+        trailing_data,
+        outer_header.public_custom_data,
+    ))
+}
+
+/// The attachments, the inner header and what was read from the XML document making up the
+/// payload of a database
+pub(crate) type Payload<T> = (Vec<HeaderAttachment>, KDBX4InnerHeader, T);
+
+/// Read the payload from the HMAC block stream on the current thread
+pub(crate) fn read_payload<T>(
+    source: &mut dyn Read,
+    outer_header: &KDBX4OuterHeader,
+    master_key: &GenericArray<u8, U32>,
+    hmac_key: &GenericArray<u8, U64>,
+    read_xml: impl FnOnce(&mut dyn Read, &KDBX4InnerHeader) -> Result<T, DatabaseOpenError>,
+) -> Result<Payload<T>, DatabaseOpenError> {
+    let mut block_stream = hmac_block_stream::HmacBlockStreamReader::new(source, hmac_key);
+    let mut payload_compressed = DecryptReader::new(
+        &mut block_stream,
+        outer_header
+            .outer_cipher_config
+            .get_stream_cipher(master_key, &outer_header.outer_iv)?,
+    );
+    parse_payload(
+        &mut payload_compressed,
+        &outer_header.compression_config,
+        read_xml,
+    )
+}
 
-    let payload = outer_header
-        .compression_config
+/// Decompress and parse the decrypted payload, reading it to its end
+pub(crate) fn parse_payload<T>(
+    payload_compressed: &mut dyn Read,
+    compression_config: &CompressionConfig,
+    read_xml: impl FnOnce(&mut dyn Read, &KDBX4InnerHeader) -> Result<T, DatabaseOpenError>,
+) -> Result<Payload<T>, DatabaseOpenError> {
+    let mut payload = compression_config
         .get_compression()
-        .decompress(&payload_compressed)?;
+        .decompress_stream(payload_compressed);
 
-    // KDBX4 has inner header, too - parse it
-    let (header_attachments, inner_header, body_start) = parse_inner_header(&payload)?;
+    // KDBX4 has inner header, too - parse it. After the inner header is one XML document.
+    let (header_attachments, inner_header) = read_inner_header(&mut payload)?;
+    let content = read_xml(&mut payload, &inner_header)?;
 
-    // after inner header is one XML document
-    let xml = &payload[body_start..];
+    // the parser may stop before the end of the document, and the compressed data may end before
+    // the payload does, which both still need to be verified up to the final block
+    std::io::copy(&mut payload, &mut std::io::sink())?;
+    drop(payload);
+    std::io::copy(payload_compressed, &mut std::io::sink())?;
 
-    // initialize the inner decryptor
-    let inner_decryptor = inner_header
-        .inner_random_stream
-        .get_cipher(&inner_header.inner_random_stream_key)?;
+    Ok((header_attachments, inner_header, content))
+}
 
-    let config = DatabaseConfig {
+/// Passes reads through, keeping the first I/O error so that it can be told apart from the
+/// errors of a parser reading from it
+struct KeepIoError<'a> {
+    inner: &'a mut dyn Read,
+    error: Option<std::io::Error>,
+}
+
+impl Read for KeepIoError<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
+        match self.inner.read(buf) {
+            Err(e) if e.kind() != std::io::ErrorKind::Interrupted => {
+                let kind = e.kind();
+                self.error.get_or_insert(e);
+                Err(kind.into())
+            }
+            result => result,
+        }
+    }
+}
+
+/// Errors of the block stream and the decryption are passed through `Read` as I/O errors, turn
+/// them back into the errors they were
+pub(crate) fn unwrap_stream_error(e: DatabaseOpenError) -> DatabaseOpenError {
+    let e = match e {
+        DatabaseOpenError::Io(e) => e,
+        e => return e,
+    };
+
+    let is_stream_error = e.get_ref().map_or(false, |inner| {
+        inner.is::<BlockStreamError>() || inner.is::<CryptographyError>()
+    });
+    if !is_stream_error {
+        return e.into();
+    }
+
+    match e.into_inner().map(|inner| inner.downcast::<BlockStreamError>()) {
+        Some(Ok(e)) => (*e).into(),
+        Some(Err(inner)) => match inner.downcast::<CryptographyError>() {
+            Ok(e) => (*e).into(),
+            Err(inner) => std::io::Error::new(std::io::ErrorKind::InvalidData, inner).into(),
+        },
+        None => std::io::Error::from(std::io::ErrorKind::InvalidData).into(),
+    }
+}
+
+/// Read a header field of a type and a length-tagged value
+fn read_header_field(source: &mut dyn Read) -> Result<(u8, Vec<u8>), std::io::Error> {
+    let entry_type = source.read_u8()?;
+    let entry_length = source.read_u32::<LittleEndian>()? as u64;
+
+    let mut entry_buffer = Vec::new();
+    source.take(entry_length).read_to_end(&mut entry_buffer)?;
+    if entry_buffer.len() as u64 != entry_length {
+        return Err(std::io::ErrorKind::UnexpectedEof.into());
+    }
+
+    Ok((entry_type, entry_buffer))
+}
+
+/// The master key for the outer cipher and the key for the HMAC block stream, which are wiped from
+/// memory when dropped
+type DerivedKeys = (Zeroizing<GenericArray<u8, U32>>, Zeroizing<GenericArray<u8, U64>>);
+
+/// Transform the key with the KDF settings and seed of the outer header, unless `db_key` already
+/// holds the key transformed with them
+fn transform_key(
+    outer_header: &KDBX4OuterHeader,
+    db_key: &DatabaseKey,
+) -> Result<TransformedKey, DatabaseOpenError> {
+    if let Some(transformed_key) = db_key.transformed_key_for(
+        &outer_header.kdf_config,
+        &outer_header.kdf_seed,
+        &outer_header.argon2_secret_parameters,
+    ) {
+        return Ok(transformed_key.clone());
+    }
+
+    #[cfg(feature = "challenge_response")]
+    let db_key = db_key.clone().perform_challenge(&outer_header.kdf_seed)?;
+
+    let composite_key = db_key.composite_key()?;
+    let transformed_key = outer_header
+        .kdf_config
+        .get_kdf_seeded(&outer_header.kdf_seed, &outer_header.argon2_secret_parameters)
+        .transform_key_cached(&composite_key)?;
+
+    Ok(TransformedKey::new(
+        outer_header.kdf_config.clone(),
+        outer_header.kdf_seed.clone(),
+        outer_header.argon2_secret_parameters.clone(),
+        &transformed_key,
+    ))
+}
+
+/// Derive the master key and the HMAC key from the key elements and the outer header
+pub(crate) fn derive_keys(
+    outer_header: &KDBX4OuterHeader,
+    db_key: &DatabaseKey,
+) -> Result<DerivedKeys, DatabaseOpenError> {
+    // derive master key from composite key, transform_seed, transform_rounds and master_seed
+    let transformed_key = transform_key(outer_header, db_key)?;
+    let master_key = Zeroizing::new(crypt::calculate_sha256(&[
+        outer_header.master_seed.as_ref(),
+        transformed_key.as_bytes(),
+    ])?);
+
+    let hmac_key = Zeroizing::new(crypt::calculate_sha512(&[
+        &outer_header.master_seed,
+        transformed_key.as_bytes(),
+        &hmac_block_stream::HMAC_KEY_END,
+    ])?);
+
+    Ok((master_key, hmac_key))
+}
+
+/// Read the public custom data from the outer header, without decrypting the database
+pub(crate) fn parse_public_custom_data(data: &[u8]) -> Result<PublicCustomData, DatabaseOpenError> {
+    Ok(parse_outer_header(data)?.0.public_custom_data)
+}
+
+/// Read the settings in the outer header, without decrypting the database
+pub(crate) fn parse_header_info(data: &[u8]) -> Result<HeaderInfo, DatabaseOpenError> {
+    let (outer_header, _) = parse_outer_header(data)?;
+    Ok(HeaderInfo {
         version: outer_header.version,
         outer_cipher_config: outer_header.outer_cipher_config,
         compression_config: outer_header.compression_config,
-        inner_cipher_config: inner_header.inner_random_stream,
         kdf_config: outer_header.kdf_config,
-    };
-
-    Ok((config, header_attachments, inner_decryptor, xml.to_vec()))
+    })
 }
 
-fn parse_outer_header(data: &[u8]) -> Result<(KDBX4OuterHeader, usize), DatabaseOpenError> {
+pub(crate) fn parse_outer_header(data: &[u8]) -> Result<(KDBX4OuterHeader, usize), DatabaseOpenError> {
     let version = DatabaseVersion::parse(data)?;
 
     // skip over the version header
@@ -143,6 +470,8 @@ fn parse_outer_header(data: &[u8]) -> Result<(KDBX4OuterHeader, usize), Database
     let mut outer_iv: Option<Vec<u8>> = None;
     let mut kdf_config: Option<KdfConfig> = None;
     let mut kdf_seed: Option<Vec<u8>> = None;
+    let mut argon2_secret_parameters = Argon2SecretParameters::default();
+    let mut public_custom_data = PublicCustomData::default();
 
     // parse header
     loop {
@@ -170,13 +499,16 @@ fn parse_outer_header(data: &[u8]) -> Result<(KDBX4OuterHeader, usize), Database
             HEADER_COMMENT => {}
 
             HEADER_OUTER_ENCRYPTION_ID => {
-                outer_cipher = Some(OuterCipherConfig::try_from(entry_buffer)?);
+                outer_cipher = Some(OuterCipherConfig::try_from_registered(entry_buffer).map_err(unsupported)?);
             }
 
             HEADER_COMPRESSION_ID => {
-                compression_config = Some(CompressionConfig::try_from(LittleEndian::read_u32(
-                    &entry_buffer,
-                ))?);
+                if entry_buffer.len() != 4 {
+                    return Err(corrupt_header("compression flags"));
+                }
+                compression_config = Some(
+                    CompressionConfig::try_from(LittleEndian::read_u32(&entry_buffer)).map_err(unsupported)?,
+                );
             }
 
             HEADER_MASTER_SEED => master_seed = Some(entry_buffer.to_vec()),
@@ -184,14 +516,25 @@ fn parse_outer_header(data: &[u8]) -> Result<(KDBX4OuterHeader, usize), Database
             HEADER_ENCRYPTION_IV => outer_iv = Some(entry_buffer.to_vec()),
 
             HEADER_KDF_PARAMS => {
-                let vd = VariantDictionary::parse(entry_buffer)?;
-                let (kconf, kseed) = vd.try_into()?;
+                let vd =
+                    VariantDictionary::parse(entry_buffer).map_err(|_| corrupt_header("KDF parameters"))?;
+                argon2_secret_parameters = Argon2SecretParameters::from_variant_dictionary(&vd);
+                let (kconf, kseed) = vd.try_into().map_err(|e| match e {
+                    KdfConfigError::VariantDictionary(_) => corrupt_header("KDF parameters"),
+                    e => unsupported(e),
+                })?;
                 kdf_config = Some(kconf);
                 kdf_seed = Some(kseed)
             }
 
+            HEADER_PUBLIC_CUSTOM_DATA => {
+                let vd =
+                    VariantDictionary::parse(entry_buffer).map_err(|_| corrupt_header("public custom data"))?;
+                public_custom_data = PublicCustomData::from_variant_dictionary(vd);
+            }
+
             _ => {
-                return Err(DatabaseIntegrityError::InvalidOuterHeaderEntry { entry_type }.into());
+                return Err(corrupt_header(&format!("field type {}", entry_type)));
             }
         };
     }
@@ -199,21 +542,16 @@ fn parse_outer_header(data: &[u8]) -> Result<(KDBX4OuterHeader, usize), Database
     // at this point, the header needs to be fully defined - unwrap options and return errors if
     // something is missing
 
-    fn get_or_err<T>(v: Option<T>, err: &str) -> Result<T, DatabaseIntegrityError> {
-        v.ok_or_else(|| {
-            DatabaseIntegrityError::IncompleteOuterHeader {
-                missing_field: err.into(),
-            }
-            .into()
-        })
+    fn get_or_err<T>(v: Option<T>, field: &str) -> Result<T, DatabaseOpenError> {
+        v.ok_or_else(|| corrupt_header(field))
     }
 
-    let outer_cipher_config = get_or_err(outer_cipher, "Outer Cipher ID")?;
-    let compression_config = get_or_err(compression_config, "Compression ID")?;
-    let master_seed = get_or_err(master_seed, "Master seed")?;
-    let outer_iv = get_or_err(outer_iv, "Outer IV")?;
-    let kdf_config = get_or_err(kdf_config, "Key Derivation Function Parameters")?;
-    let kdf_seed = get_or_err(kdf_seed, "Key Derivation Function Seed")?;
+    let outer_cipher_config = get_or_err(outer_cipher, "cipher ID")?;
+    let compression_config = get_or_err(compression_config, "compression flags")?;
+    let master_seed = get_or_err(master_seed, "master seed")?;
+    let outer_iv = get_or_err(outer_iv, "encryption IV")?;
+    let kdf_config = get_or_err(kdf_config, "KDF parameters")?;
+    let kdf_seed = get_or_err(kdf_seed, "KDF seed")?;
 
     Ok((
         KDBX4OuterHeader {
@@ -224,41 +562,67 @@ fn parse_outer_header(data: &[u8]) -> Result<(KDBX4OuterHeader, usize), Database
             outer_iv,
             kdf_config,
             kdf_seed,
+            argon2_secret_parameters,
+            public_custom_data,
         },
         pos,
     ))
 }
 
-fn parse_inner_header(
+fn corrupt_header(field: &str) -> DatabaseOpenError {
+    DatabaseOpenError::CorruptHeader {
+        field: field.to_string(),
+    }
+}
+
+/// A cipher, key derivation or compression named in the header that is not known
+fn unsupported(e: impl std::error::Error) -> DatabaseOpenError {
+    DatabaseOpenError::UnsupportedFeature(e.to_string())
+}
+
+pub(crate) fn parse_inner_header(
     data: &[u8],
 ) -> Result<(Vec<HeaderAttachment>, KDBX4InnerHeader, usize), DatabaseOpenError> {
-    let mut pos = 0;
+    let mut rest = data;
+    let (header_attachments, inner_header) = read_inner_header(&mut rest)?;
+    Ok((header_attachments, inner_header, data.len() - rest.len()))
+}
 
+/// Read the inner header from the decrypted payload, leaving the XML document that follows it
+pub(crate) fn read_inner_header(
+    source: &mut dyn Read,
+) -> Result<(Vec<HeaderAttachment>, KDBX4InnerHeader), DatabaseOpenError> {
     let mut inner_random_stream = None;
     let mut inner_random_stream_key = None;
     let mut header_attachments = Vec::new();
 
     loop {
-        let entry_type = data[pos];
-        let entry_length: usize = LittleEndian::read_u32(&data[pos + 1..(pos + 5)]) as usize;
-        let entry_buffer = &data[(pos + 5)..(pos + 5 + entry_length)];
-
-        pos += 5 + entry_length;
+        let (entry_type, mut entry_buffer) = read_header_field(source)?;
 
         match entry_type {
             INNER_HEADER_END => break,
 
             INNER_HEADER_RANDOM_STREAM_ID => {
-                inner_random_stream = Some(InnerCipherConfig::try_from(LittleEndian::read_u32(
-                    &entry_buffer,
-                ))?);
+                inner_random_stream = Some(
+                    InnerCipherConfig::try_from(LittleEndian::read_u32(&entry_buffer)).map_err(unsupported)?,
+                );
             }
 
-            INNER_HEADER_RANDOM_STREAM_KEY => inner_random_stream_key = Some(entry_buffer.to_vec()),
+            INNER_HEADER_RANDOM_STREAM_KEY => inner_random_stream_key = Some(Zeroizing::new(entry_buffer)),
 
             INNER_HEADER_BINARY_ATTACHMENTS => {
-                let header_attachment = HeaderAttachment::from(entry_buffer);
-                header_attachments.push(header_attachment);
+                if entry_buffer.is_empty() {
+                    return Err(DatabaseIntegrityError::InvalidInnerHeaderEntry { entry_type }.into());
+                }
+
+                // the content is kept in the buffer it was read into, attachments can be large
+                let flags = entry_buffer.remove(0);
+                header_attachments.push(HeaderAttachment {
+                    flags,
+                    content: entry_buffer,
+                    packed: false,
+                    external: false,
+                });
             }
 
             _ => {
@@ -284,5 +648,5 @@ fn parse_inner_header(
         inner_random_stream_key,
     };
 
-    Ok((header_attachments, inner_header, pos))
+    Ok((header_attachments, inner_header))
 }
@@ -0,0 +1,429 @@
+//! Reading the payload of a KDBX4 database on several threads
+//!
+//! The payload is split into HMAC-verified blocks of 1 MiB, which are encrypted and compressed as
+//! a whole. The HMACs of the blocks are independent of each other and are verified on a pool of
+//! threads, while the verified blocks are decrypted on another thread, so that decryption
+//! overlaps with verification and with the decompression and parsing on the current thread:
+//!
+//! ```text
+//! source -> verifiers (pool) -> decryptor -> decompression and parsing (current thread)
+//! ```
+//!
+//! The current thread also reads the blocks from the source, whenever it waits for decrypted
+//! data, so it can stop right after the final block and leave the trailing data to the caller.
+//! Parsing stays on the current thread as it depends on thread-local settings like the clock.
+//! The channels to the decryptor are bounded to keep a few blocks in flight at most.
+
+use std::{
+    io::Read,
+    sync::{
+        mpsc::{channel, sync_channel, Receiver, RecvTimeoutError, Sender, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use cipher::generic_array::{
+    typenum::{U32, U64},
+    GenericArray,
+};
+use zeroize::Zeroizing;
+
+use crate::{
+    crypt::ciphers::DecryptReader,
+    error::DatabaseOpenError,
+    format::kdbx4::{
+        parse::{parse_payload, Payload},
+        KDBX4InnerHeader, KDBX4OuterHeader,
+    },
+    hmac_block_stream::{self, RawBlock},
+};
+
+/// The largest number of threads verifying blocks
+const MAX_VERIFIERS: usize = 4;
+
+/// The number of blocks and decrypted chunks waiting for the decryptor and the parser
+const QUEUE_LENGTH: usize = 4;
+
+/// The size of the decrypted chunks passed to the parser
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// How long to wait for decrypted data before checking whether the decryptor takes more blocks
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// A verified block, or the error verifying it
+type VerifiedBlock = std::io::Result<Vec<u8>>;
+
+/// A block to verify with its index, and where to send the result
+type Job = (u64, RawBlock, SyncSender<VerifiedBlock>);
+
+/// A piece of the decrypted payload
+type Chunk = std::io::Result<Zeroizing<Vec<u8>>>;
+
+/// The number of threads to verify blocks with, or `None` if the payload should be read on the
+/// current thread since there is only one core, or threads are not supported
+pub(crate) fn verifier_threads() -> Option<usize> {
+    let cores = thread::available_parallelism().ok()?.get();
+    if cores < 2 {
+        return None;
+    }
+    // the decryptor and the parser keep a core busy each
+    Some(cores.saturating_sub(2).clamp(1, MAX_VERIFIERS))
+}
+
+/// Read the payload from the HMAC block stream, verifying the blocks on `verifiers` threads and
+/// decrypting them on one more, while decompressing and parsing it on the current thread
+pub(crate) fn read_payload_parallel<T>(
+    source: &mut dyn Read,
+    outer_header: &KDBX4OuterHeader,
+    master_key: &GenericArray<u8, U32>,
+    hmac_key: &GenericArray<u8, U64>,
+    verifiers: usize,
+    read_xml: impl FnOnce(&mut dyn Read, &KDBX4InnerHeader) -> Result<T, DatabaseOpenError>,
+) -> Result<Payload<T>, DatabaseOpenError> {
+    let outer_cipher_config = &outer_header.outer_cipher_config;
+    let outer_iv = &outer_header.outer_iv;
+
+    thread::scope(|scope| {
+        // the number of jobs is bounded by the queue of verification results
+        let (job_sender, job_receiver) = channel::<Job>();
+        let job_receiver = Arc::new(Mutex::new(job_receiver));
+        // the results of the verifiers, in the order of the blocks
+        let (ordered_sender, ordered_receiver) = sync_channel::<Receiver<VerifiedBlock>>(QUEUE_LENGTH);
+        let (chunk_sender, chunk_receiver) = sync_channel::<Chunk>(QUEUE_LENGTH);
+
+        for _ in 0..verifiers {
+            let job_receiver = Arc::clone(&job_receiver);
+            scope.spawn(move || loop {
+                let job = job_receiver
+                    .lock()
+                    .map_err(|_| ())
+                    .and_then(|r| r.recv().map_err(|_| ()));
+                let (block_index, block, result) = match job {
+                    Ok(job) => job,
+                    Err(()) => break,
+                };
+                let verified = block.verify(block_index, hmac_key).map(|_| block.data);
+                // the decryptor is gone if it ran into an error before
+                let _ = result.send(verified);
+            });
+        }
+
+        let decryptor = scope.spawn(move || -> Result<(), DatabaseOpenError> {
+            let mut blocks = ChunkReader::new(|| {
+                let verified = ordered_receiver.recv().ok()?;
+                match verified.recv() {
+                    Ok(Ok(data)) if data.is_empty() => None,
+                    Ok(verified) => Some(verified),
+                    Err(_) => Some(Err(std::io::Error::other("block verification stopped"))),
+                }
+            });
+            let cipher = outer_cipher_config.get_stream_cipher(master_key, outer_iv)?;
+            let mut payload_compressed = DecryptReader::new(&mut blocks, cipher);
+
+            loop {
+                let mut chunk = Zeroizing::new(vec![0u8; CHUNK_SIZE]);
+                let chunk = match payload_compressed.read(&mut chunk) {
+                    Ok(0) => break,
+                    Ok(read) => {
+                        chunk.truncate(read);
+                        Ok(chunk)
+                    }
+                    Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                    Err(e) => Err(e),
+                };
+                let failed = chunk.is_err();
+                // the parser is gone if it ran into an error before
+                if chunk_sender.send(chunk).is_err() || failed {
+                    break;
+                }
+            }
+            Ok(())
+        });
+
+        let mut blocks = BlockDispatcher {
+            source,
+            job_sender: Some(job_sender),
+            ordered_sender: Some(ordered_sender),
+            pending: None,
+            block_index: 0,
+        };
+        let payload = parse_payload(
+            &mut ChunkReader::new(|| blocks.next_chunk(&chunk_receiver)),
+            &outer_header.compression_config,
+            read_xml,
+        );
+
+        // parsing may have stopped early, which the other threads notice once the channels close
+        drop(blocks);
+        drop(chunk_receiver);
+        decryptor
+            .join()
+            .unwrap_or_else(|panic| std::panic::resume_unwind(panic))?;
+        payload
+    })
+}
+
+/// Reads the blocks from the source and hands them out to the verifiers and the decryptor
+struct BlockDispatcher<'a> {
+    source: &'a mut dyn Read,
+    job_sender: Option<Sender<Job>>,
+    ordered_sender: Option<SyncSender<Receiver<VerifiedBlock>>>,
+    /// A block that was read but did not fit into the queue
+    pending: Option<(RawBlock, SyncSender<VerifiedBlock>, Receiver<VerifiedBlock>)>,
+    block_index: u64,
+}
+
+impl BlockDispatcher<'_> {
+    /// Hand out as many blocks as fit into the queue, then wait for the next decrypted chunk
+    fn next_chunk(&mut self, chunks: &Receiver<Chunk>) -> Option<Chunk> {
+        loop {
+            if let Err(e) = self.dispatch() {
+                return Some(Err(e));
+            }
+            if self.ordered_sender.is_none() {
+                return chunks.recv().ok();
+            }
+
+            // the decryptor may need more blocks before it has a chunk ready
+            match chunks.recv_timeout(POLL_INTERVAL) {
+                Ok(chunk) => return Some(chunk),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return None,
+            }
+        }
+    }
+
+    /// Read blocks and queue them until the queue is full or the final block was read
+    fn dispatch(&mut self) -> std::io::Result<()> {
+        while let Some(ordered_sender) = &self.ordered_sender {
+            let (block, result_sender, result_receiver) = match self.pending.take() {
+                Some(pending) => pending,
+                None => match hmac_block_stream::read_block(self.source, Vec::new()) {
+                    Ok(Some(block)) => {
+                        let (result_sender, result_receiver) = sync_channel(1);
+                        (block, result_sender, result_receiver)
+                    }
+                    Ok(None) => {
+                        self.finish();
+                        return Ok(());
+                    }
+                    Err(e) => {
+                        self.finish();
+                        return Err(e);
+                    }
+                },
+            };
+
+            match ordered_sender.try_send(result_receiver) {
+                Ok(()) => {
+                    let is_final = block.is_final();
+                    if let Some(job_sender) = &self.job_sender {
+                        // the verifiers only stop once the channel is closed
+                        let _ = job_sender.send((self.block_index, block, result_sender));
+                    }
+                    self.block_index += 1;
+                    if is_final {
+                        self.finish();
+                    }
+                }
+                Err(TrySendError::Full(result_receiver)) => {
+                    self.pending = Some((block, result_sender, result_receiver));
+                    return Ok(());
+                }
+                // the decryptor stopped after an error, which it passes on as a chunk
+                Err(TrySendError::Disconnected(_)) => self.finish(),
+            }
+        }
+        Ok(())
+    }
+
+    /// Stop reading blocks, closing the channels to the other threads
+    fn finish(&mut self) {
+        self.job_sender = None;
+        self.ordered_sender = None;
+        self.pending = None;
+    }
+}
+
+/// Reads the chunks returned by a function one after another, until it returns `None`
+struct ChunkReader<C, F> {
+    next_chunk: F,
+    chunk: Option<C>,
+    pos: usize,
+    finished: bool,
+}
+
+impl<C, F> ChunkReader<C, F>
+where
+    C: AsRef<[u8]>,
+    F: FnMut() -> Option<std::io::Result<C>>,
+{
+    fn new(next_chunk: F) -> Self {
+        ChunkReader {
+            next_chunk,
+            chunk: None,
+            pos: 0,
+            finished: false,
+        }
+    }
+}
+
+impl<C, F> Read for ChunkReader<C, F>
+where
+    C: AsRef<[u8]>,
+    F: FnMut() -> Option<std::io::Result<C>>,
+{
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            if let Some(chunk) = &self.chunk {
+                let chunk = chunk.as_ref();
+                if self.pos < chunk.len() {
+                    let len = buf.len().min(chunk.len() - self.pos);
+                    buf[..len].copy_from_slice(&chunk[self.pos..self.pos + len]);
+                    self.pos += len;
+                    return Ok(len);
+                }
+            }
+            if self.finished {
+                return Ok(0);
+            }
+
+            self.pos = 0;
+            self.chunk = match (self.next_chunk)() {
+                Some(chunk) => Some(chunk?),
+                None => {
+                    self.finished = true;
+                    None
+                }
+            };
+        }
+    }
+}
+
+#[cfg(all(test, feature = "save_kdbx4"))]
+mod pipeline_tests {
+    use std::io::Read;
+
+    use byteorder::{ByteOrder, LittleEndian};
+
+    use super::read_payload_parallel;
+    use crate::{
+        db::{Database, Entry},
+        error::{BlockStreamError, DatabaseOpenError},
+        format::{
+            kdbx4::parse::{derive_keys, parse_outer_header, read_payload, unwrap_stream_error},
+            read_outer_header_data,
+        },
+        key::DatabaseKey,
+    };
+
+    fn key() -> DatabaseKey {
+        DatabaseKey::new().with_password("demopass")
+    }
+
+    /// A database whose payload spans several blocks, and the offset of its block stream
+    fn multi_block_database() -> (Vec<u8>, usize) {
+        let mut content = vec![0u8; 3 * 1024 * 1024];
+        getrandom::fill(&mut content).unwrap();
+
+        let mut db = Database::new(Default::default());
+        let mut entry = Entry::new();
+        entry.add_attachment(&mut db.header_attachments, "noise.bin", content);
+        db.root.add_child(entry);
+
+        let mut data = Vec::new();
+        db.save(&mut data, key()).unwrap();
+        data.extend_from_slice(b"trailing");
+
+        let header_size = read_outer_header_data(&mut &data[..]).unwrap().len();
+        (data, header_size + 64)
+    }
+
+    /// Read the first `limit` bytes of the XML document
+    fn read_xml(xml: &mut dyn Read, limit: u64) -> Result<Vec<u8>, DatabaseOpenError> {
+        let mut buffer = Vec::new();
+        xml.take(limit).read_to_end(&mut buffer)?;
+        Ok(buffer)
+    }
+
+    /// Read the payload of a database, in parallel with the given number of verifiers, with the
+    /// first `limit` bytes of the XML document
+    fn open_payload(
+        data: &[u8],
+        verifiers: Option<usize>,
+        limit: u64,
+    ) -> (Result<super::Payload<Vec<u8>>, DatabaseOpenError>, Vec<u8>) {
+        let mut source = data;
+        let header_data = read_outer_header_data(&mut source).unwrap();
+        let (outer_header, _) = parse_outer_header(&header_data).unwrap();
+        let (master_key, hmac_key) = derive_keys(&outer_header, &key()).unwrap();
+        source = &source[64..];
+
+        let payload = match verifiers {
+            Some(verifiers) => read_payload_parallel(
+                &mut source,
+                &outer_header,
+                &master_key,
+                &hmac_key,
+                verifiers,
+                |xml, _| read_xml(xml, limit),
+            ),
+            None => read_payload(&mut source, &outer_header, &master_key, &hmac_key, |xml, _| {
+                read_xml(xml, limit)
+            }),
+        };
+
+        let mut rest = Vec::new();
+        source.read_to_end(&mut rest).unwrap();
+        (payload, rest)
+    }
+
+    #[test]
+    fn test_parallel_payload_matches_sequential() {
+        let (data, _) = multi_block_database();
+        let (expected, rest) = open_payload(&data, None, u64::MAX);
+        let (attachments, _, xml) = expected.unwrap();
+        assert_eq!(rest, b"trailing");
+
+        for verifiers in [1, 3] {
+            let (payload, rest) = open_payload(&data, Some(verifiers), u64::MAX);
+            let (parallel_attachments, _, parallel_xml) = payload.unwrap();
+            assert_eq!(parallel_attachments, attachments);
+            assert_eq!(parallel_xml, xml);
+            assert_eq!(rest, b"trailing");
+        }
+    }
+
+    #[test]
+    fn test_payload_is_read_to_the_end() {
+        // the rest of the document and the final block are read even if the parser stops early
+        let (data, _) = multi_block_database();
+        for verifiers in [None, Some(2)] {
+            let (payload, rest) = open_payload(&data, verifiers, 10);
+            assert_eq!(payload.unwrap().2.len(), 10);
+            assert_eq!(rest, b"trailing");
+        }
+    }
+
+    #[test]
+    fn test_parallel_payload_reports_corrupt_block() {
+        let (mut data, blocks_start) = multi_block_database();
+        let first_block_size = LittleEndian::read_u32(&data[blocks_start + 32..]) as usize;
+        // flip a bit in the data of the second block
+        data[blocks_start + 36 + first_block_size + 36 + 10] ^= 1;
+
+        let (payload, _) = open_payload(&data, Some(2), u64::MAX);
+        let error = match payload {
+            Err(e) => unwrap_stream_error(e),
+            Ok(_) => panic!("the corrupt block went unnoticed"),
+        };
+        assert!(matches!(
+            error,
+            DatabaseOpenError::DatabaseIntegrity(crate::error::DatabaseIntegrityError::BlockStream(
+                BlockStreamError::BlockHashMismatch { block_index: 1 }
+            ))
+        ));
+    }
+}
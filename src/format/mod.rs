@@ -2,6 +2,7 @@ pub(crate) mod kdb;
 pub(crate) mod kdbx3;
 pub(crate) mod kdbx4;
 
+use std::io::Read;
 #[cfg(feature = "save_kdbx4")]
 use std::io::Write;
 
@@ -9,7 +10,7 @@ use std::io::Write;
 use byteorder::WriteBytesExt;
 use byteorder::{ByteOrder, LittleEndian};
 
-use crate::error::DatabaseIntegrityError;
+use crate::error::{DatabaseIntegrityError, DatabaseOpenError};
 
 const KDBX_IDENTIFIER: [u8; 4] = [0x03, 0xd9, 0xa2, 0x9a];
 
@@ -25,10 +26,16 @@ pub const KDBX4_MAJOR_VERSION: u16 = 4;
 
 pub const KDBX4_CURRENT_MINOR_VERSION: u16 = 0;
 
+/// The versions that can be opened, for `DatabaseOpenError::IncompatibleVersion`
+pub(crate) const SUPPORTED_VERSIONS: &str = "KDB, KDBX3, KDBX4";
+
+/// The versions with an outer header that can be read without the key
+pub(crate) const SUPPORTED_KDBX_VERSIONS: &str = "KDBX3, KDBX4";
+
 /// Supported KDB database versions, with the associated
 /// minor version.
 #[derive(Debug, Clone, PartialEq, Eq)]
-#[cfg_attr(feature = "serialization", derive(serde::Serialize))]
+#[cfg_attr(feature = "serialization", derive(serde::Serialize, serde::Deserialize))]
 pub enum DatabaseVersion {
     KDB(u16),
     KDB2(u16),
@@ -75,16 +82,18 @@ impl DatabaseVersion {
 
     #[cfg(feature = "save_kdbx4")]
     fn dump(&self, writer: &mut dyn Write) -> Result<(), std::io::Error> {
-        if let DatabaseVersion::KDB4(minor_version) = self {
-            writer.write(&crate::format::KDBX_IDENTIFIER)?;
-            writer.write_u32::<LittleEndian>(KEEPASS_LATEST_ID)?;
-            writer.write_u16::<LittleEndian>(*minor_version)?;
-            writer.write_u16::<LittleEndian>(KDBX4_MAJOR_VERSION)?;
-
-            Ok(())
-        } else {
-            panic!("DatabaseVersion::dump only supports dumping KDBX4.");
-        }
+        let (minor_version, major_version) = match self {
+            DatabaseVersion::KDB3(minor_version) => (minor_version, KDBX3_MAJOR_VERSION),
+            DatabaseVersion::KDB4(minor_version) => (minor_version, KDBX4_MAJOR_VERSION),
+            _ => panic!("DatabaseVersion::dump only supports dumping KDBX3 and KDBX4."),
+        };
+
+        writer.write(&crate::format::KDBX_IDENTIFIER)?;
+        writer.write_u32::<LittleEndian>(KEEPASS_LATEST_ID)?;
+        writer.write_u16::<LittleEndian>(*minor_version)?;
+        writer.write_u16::<LittleEndian>(major_version)?;
+
+        Ok(())
     }
 
     pub(crate) fn get_version_header_size() -> usize {
@@ -92,6 +101,44 @@ impl DatabaseVersion {
     }
 }
 
+/// Read the raw outer header of a KDBX 3 or KDBX 4 database up to and including its end field,
+/// without reading any further. Header fields have a 16 bit length in KDBX 3 and a 32 bit length
+/// in KDBX 4.
+pub(crate) fn read_outer_header_data(source: &mut dyn Read) -> Result<Vec<u8>, DatabaseOpenError> {
+    let mut header_data = vec![0; DatabaseVersion::get_version_header_size()];
+    source.read_exact(&mut header_data)?;
+
+    let length_size = match DatabaseVersion::parse(&header_data)? {
+        DatabaseVersion::KDB3(_) => 2,
+        DatabaseVersion::KDB4(_) => 4,
+        version @ (DatabaseVersion::KDB(_) | DatabaseVersion::KDB2(_)) => {
+            return Err(DatabaseOpenError::IncompatibleVersion {
+                found: version.to_string(),
+                supported: SUPPORTED_KDBX_VERSIONS,
+            })
+        }
+    };
+
+    loop {
+        let start = header_data.len();
+        header_data.resize(start + 1 + length_size, 0);
+        source.read_exact(&mut header_data[start..])?;
+
+        let entry_type = header_data[start];
+        let entry_length = LittleEndian::read_uint(&header_data[start + 1..], length_size);
+
+        source.take(entry_length).read_to_end(&mut header_data)?;
+        if header_data.len() as u64 != (start + 1 + length_size) as u64 + entry_length {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+
+        // the end field is entry type 0 in both formats
+        if entry_type == 0 {
+            return Ok(header_data);
+        }
+    }
+}
+
 impl ToString for DatabaseVersion {
     fn to_string(&self) -> String {
         match self {
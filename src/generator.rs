@@ -0,0 +1,503 @@
+//! Generation of random passwords and passphrases
+//!
+//! `PasswordGenerator` creates passwords from configurable character classes and
+//! `PassphraseGenerator` creates passphrases from a word list, both with the random number
+//! generator of the operating system, so that new entries can be created without another
+//! dependency. Results are returned as `SecStr`, ready to be turned into a protected value:
+//!
+//! ```
+//! use keepass::{db::{Entry, Value}, generator::PasswordGenerator};
+//!
+//! let generator = PasswordGenerator::new().with_length(24).with_symbols(true).with_exclude_lookalikes(true);
+//! let password = generator.generate()?;
+//! assert_eq!(password.unsecure().len(), 24);
+//!
+//! let mut entry = Entry::new();
+//! entry.fields.insert("Password".to_string(), Value::Protected(password.into()));
+//! # Ok::<(), keepass::generator::GeneratorError>(())
+//! ```
+//!
+//! No word list is embedded. Diceware lists like the EFF large word list can be loaded as they
+//! are published, with or without the dice numbers in front of the words:
+//!
+//! ```
+//! use keepass::generator::{Capitalization, PassphraseGenerator};
+//!
+//! # let eff_large_wordlist = "11111\tabacus\n11112\tabdomen\n11113\tabdominal\n11114\tabide\n";
+//! let generator = PassphraseGenerator::from_wordlist(eff_large_wordlist)?
+//!     .with_words(6)
+//!     .with_separator("-")
+//!     .with_capitalization(Capitalization::FirstLetter);
+//! let passphrase = generator.generate()?;
+//! assert_eq!(std::str::from_utf8(passphrase.unsecure()).unwrap().split('-').count(), 6);
+//! # Ok::<(), keepass::generator::GeneratorError>(())
+//! ```
+
+use secstr::SecStr;
+use thiserror::Error;
+use zeroize::Zeroizing;
+
+const LOWERCASE: &str = "abcdefghijklmnopqrstuvwxyz";
+const UPPERCASE: &str = "ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+const DIGITS: &str = "0123456789";
+const SYMBOLS: &str = "!\"#$%&'()*+,-./:;<=>?@[\\]^_`{|}~";
+
+/// Characters that are easily confused with each other in many fonts
+pub const LOOKALIKES: &str = "0O1Il|B8G6S5Z2";
+
+/// Errors while generating a password
+#[derive(Debug, Error)]
+pub enum GeneratorError {
+    #[error("No characters are left to generate a password from")]
+    NoCharacters,
+
+    #[error("Invalid password length {0}, must be at least 1 and the number of required classes")]
+    InvalidLength(usize),
+
+    #[error("The word list needs at least two different words")]
+    InvalidWordlist,
+
+    #[error(transparent)]
+    Random(#[from] getrandom::Error),
+}
+
+/// Settings for generating random passwords
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serialization", derive(serde::Serialize))]
+pub struct PasswordGenerator {
+    pub length: usize,
+    pub lowercase: bool,
+    pub uppercase: bool,
+    pub digits: bool,
+    pub symbols: bool,
+
+    /// Characters that are used in addition to the enabled classes, forming a class of their own
+    pub extra_characters: String,
+
+    /// Leave out the characters in `LOOKALIKES`
+    pub exclude_lookalikes: bool,
+
+    /// Characters that are never used
+    pub exclude_characters: String,
+
+    /// Use at least one character of every enabled class
+    pub every_class: bool,
+}
+
+impl Default for PasswordGenerator {
+    fn default() -> Self {
+        PasswordGenerator {
+            length: 20,
+            lowercase: true,
+            uppercase: true,
+            digits: true,
+            symbols: false,
+            extra_characters: String::new(),
+            exclude_lookalikes: false,
+            exclude_characters: String::new(),
+            every_class: true,
+        }
+    }
+}
+
+impl PasswordGenerator {
+    /// Generate passwords of 20 letters and digits, using each of these classes at least once
+    pub fn new() -> Self {
+        PasswordGenerator::default()
+    }
+
+    pub fn with_length(mut self, length: usize) -> Self {
+        self.length = length;
+        self
+    }
+
+    pub fn with_lowercase(mut self, lowercase: bool) -> Self {
+        self.lowercase = lowercase;
+        self
+    }
+
+    pub fn with_uppercase(mut self, uppercase: bool) -> Self {
+        self.uppercase = uppercase;
+        self
+    }
+
+    pub fn with_digits(mut self, digits: bool) -> Self {
+        self.digits = digits;
+        self
+    }
+
+    pub fn with_symbols(mut self, symbols: bool) -> Self {
+        self.symbols = symbols;
+        self
+    }
+
+    pub fn with_extra_characters(mut self, extra_characters: &str) -> Self {
+        self.extra_characters = extra_characters.to_string();
+        self
+    }
+
+    pub fn with_exclude_lookalikes(mut self, exclude_lookalikes: bool) -> Self {
+        self.exclude_lookalikes = exclude_lookalikes;
+        self
+    }
+
+    pub fn with_exclude_characters(mut self, exclude_characters: &str) -> Self {
+        self.exclude_characters = exclude_characters.to_string();
+        self
+    }
+
+    pub fn with_every_class(mut self, every_class: bool) -> Self {
+        self.every_class = every_class;
+        self
+    }
+
+    /// The enabled character classes without the excluded characters. Classes that are left empty
+    /// are dropped.
+    fn classes(&self) -> Vec<Vec<char>> {
+        let mut classes: Vec<&str> = Vec::new();
+        if self.lowercase {
+            classes.push(LOWERCASE);
+        }
+        if self.uppercase {
+            classes.push(UPPERCASE);
+        }
+        if self.digits {
+            classes.push(DIGITS);
+        }
+        if self.symbols {
+            classes.push(SYMBOLS);
+        }
+        classes.push(&self.extra_characters);
+
+        let mut seen = Vec::new();
+        classes
+            .into_iter()
+            .map(|class| {
+                class
+                    .chars()
+                    .filter(|c| !(self.exclude_lookalikes && LOOKALIKES.contains(*c)))
+                    .filter(|c| !self.exclude_characters.contains(*c))
+                    .filter(|c| {
+                        // extra characters may repeat those of the other classes
+                        let new = !seen.contains(c);
+                        seen.push(*c);
+                        new
+                    })
+                    .collect::<Vec<char>>()
+            })
+            .filter(|class| !class.is_empty())
+            .collect()
+    }
+
+    /// Check the settings, returning the character classes to generate passwords from
+    pub(crate) fn validate(&self) -> Result<Vec<Vec<char>>, GeneratorError> {
+        let classes = self.classes();
+        if classes.is_empty() {
+            return Err(GeneratorError::NoCharacters);
+        }
+        if self.length == 0 || (self.every_class && self.length < classes.len()) {
+            return Err(GeneratorError::InvalidLength(self.length));
+        }
+        Ok(classes)
+    }
+
+    /// Generate a password
+    pub fn generate(&self) -> Result<SecStr, GeneratorError> {
+        self.generate_with(&mut |bound| Ok(random_index(bound)?))
+    }
+
+    /// Generate a password with the numbers below a bound returned by `random_index` in place of
+    /// the random number generator of the operating system
+    pub(crate) fn generate_with<E: From<GeneratorError>>(
+        &self,
+        random_index: &mut dyn FnMut(usize) -> Result<usize, E>,
+    ) -> Result<SecStr, E> {
+        let classes = self.validate()?;
+
+        let all: Vec<char> = classes.iter().flatten().copied().collect();
+        let mut password: Zeroizing<Vec<char>> = Zeroizing::new(Vec::with_capacity(self.length));
+        if self.every_class {
+            for class in &classes {
+                password.push(class[random_index(class.len())?]);
+            }
+        }
+        while password.len() < self.length {
+            password.push(all[random_index(all.len())?]);
+        }
+
+        // Fisher-Yates, so that the required characters are not always at the start
+        for i in (1..password.len()).rev() {
+            let j = random_index(i + 1)?;
+            password.swap(i, j);
+        }
+
+        let password: Zeroizing<String> = Zeroizing::new(password.iter().collect());
+        Ok(SecStr::from(password.as_str()))
+    }
+}
+
+/// How the words of a passphrase are capitalized
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serialization", derive(serde::Serialize))]
+pub enum Capitalization {
+    /// Keep the words as they are in the word list
+    #[default]
+    Unchanged,
+
+    Lowercase,
+    Uppercase,
+
+    /// Capitalize the first letter of every word
+    FirstLetter,
+
+    /// Capitalize the first letter of a random choice of words, which adds one bit of entropy per
+    /// word
+    RandomFirstLetter,
+}
+
+/// Settings for generating passphrases of random words, like Diceware
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PassphraseGenerator {
+    /// Number of words
+    pub words: usize,
+
+    /// Put between the words
+    pub separator: String,
+
+    pub capitalization: Capitalization,
+
+    wordlist: Vec<String>,
+}
+
+impl PassphraseGenerator {
+    /// Generate passphrases of seven words from `wordlist`, separated by spaces
+    pub fn new(wordlist: Vec<String>) -> Result<Self, GeneratorError> {
+        let mut unique = Vec::with_capacity(wordlist.len());
+        for word in wordlist {
+            if !word.is_empty() && !unique.contains(&word) {
+                unique.push(word);
+            }
+        }
+        if unique.len() < 2 {
+            return Err(GeneratorError::InvalidWordlist);
+        }
+
+        Ok(PassphraseGenerator {
+            words: 7,
+            separator: " ".to_string(),
+            capitalization: Capitalization::default(),
+            wordlist: unique,
+        })
+    }
+
+    /// Create a generator from the text of a word list with one word per line. Dice numbers in
+    /// front of the words, as in Diceware lists, are skipped.
+    pub fn from_wordlist(text: &str) -> Result<Self, GeneratorError> {
+        let words = text
+            .lines()
+            .filter_map(|line| {
+                let mut parts = line.split_whitespace();
+                let first = parts.next()?;
+                let is_dice_number = first.bytes().all(|b| (b'1'..=b'6').contains(&b));
+                match parts.next() {
+                    Some(word) if is_dice_number => Some(word.to_string()),
+                    _ => Some(line.trim().to_string()),
+                }
+            })
+            .collect();
+        PassphraseGenerator::new(words)
+    }
+
+    pub fn with_words(mut self, words: usize) -> Self {
+        self.words = words;
+        self
+    }
+
+    pub fn with_separator(mut self, separator: &str) -> Self {
+        self.separator = separator.to_string();
+        self
+    }
+
+    pub fn with_capitalization(mut self, capitalization: Capitalization) -> Self {
+        self.capitalization = capitalization;
+        self
+    }
+
+    /// The words that passphrases are made of
+    pub fn wordlist(&self) -> &[String] {
+        &self.wordlist
+    }
+
+    /// The entropy of the generated passphrases in bits
+    pub fn entropy_bits(&self) -> f64 {
+        let per_word = (self.wordlist.len() as f64).log2()
+            + match self.capitalization {
+                Capitalization::RandomFirstLetter => 1.0,
+                _ => 0.0,
+            };
+        per_word * self.words as f64
+    }
+
+    /// Generate a passphrase
+    pub fn generate(&self) -> Result<SecStr, GeneratorError> {
+        if self.words == 0 {
+            return Err(GeneratorError::InvalidLength(0));
+        }
+
+        let mut passphrase = Zeroizing::new(String::new());
+        for i in 0..self.words {
+            if i > 0 {
+                passphrase.push_str(&self.separator);
+            }
+
+            let word = &self.wordlist[random_index(self.wordlist.len())?];
+            let capitalize_first = match self.capitalization {
+                Capitalization::FirstLetter => true,
+                Capitalization::RandomFirstLetter => random_index(2)? == 1,
+                _ => false,
+            };
+            match self.capitalization {
+                Capitalization::Lowercase => passphrase.push_str(&word.to_lowercase()),
+                Capitalization::Uppercase => passphrase.push_str(&word.to_uppercase()),
+                _ if capitalize_first => {
+                    let mut chars = word.chars();
+                    if let Some(first) = chars.next() {
+                        passphrase.extend(first.to_uppercase());
+                        passphrase.push_str(chars.as_str());
+                    }
+                }
+                _ => passphrase.push_str(word),
+            }
+        }
+
+        Ok(SecStr::from(passphrase.as_str()))
+    }
+}
+
+/// A uniformly distributed random number below `bound`
+pub(crate) fn random_index(bound: usize) -> Result<usize, getrandom::Error> {
+    uniform_index(bound, || {
+        let mut bytes = [0u8; 8];
+        getrandom::fill(&mut bytes)?;
+        Ok(u64::from_le_bytes(bytes))
+    })
+}
+
+/// A uniformly distributed number below `bound`, taken from a source of uniformly distributed
+/// 64 bit numbers
+pub(crate) fn uniform_index<E>(bound: usize, mut next: impl FnMut() -> Result<u64, E>) -> Result<usize, E> {
+    let bound = bound as u64;
+    // reject the values of the last, incomplete range to avoid a modulo bias
+    let limit = u64::MAX - (u64::MAX % bound);
+    loop {
+        let value = next()?;
+        if value < limit {
+            return Ok((value % bound) as usize);
+        }
+    }
+}
+
+#[cfg(test)]
+mod generator_tests {
+    use super::{Capitalization, GeneratorError, PassphraseGenerator, PasswordGenerator, LOOKALIKES};
+
+    fn generate(generator: &PasswordGenerator) -> String {
+        String::from_utf8(generator.generate().unwrap().unsecure().to_vec()).unwrap()
+    }
+
+    #[test]
+    fn test_character_classes() {
+        let generator = PasswordGenerator::new().with_length(8).with_symbols(true);
+        for _ in 0..50 {
+            let password = generate(&generator);
+            assert_eq!(password.chars().count(), 8);
+            assert!(password.chars().any(|c| c.is_ascii_lowercase()));
+            assert!(password.chars().any(|c| c.is_ascii_uppercase()));
+            assert!(password.chars().any(|c| c.is_ascii_digit()));
+            assert!(password.chars().any(|c| c.is_ascii_punctuation()));
+        }
+
+        let generator = PasswordGenerator::new()
+            .with_length(64)
+            .with_exclude_lookalikes(true)
+            .with_exclude_characters("abc")
+            .with_extra_characters("äö");
+        let password = generate(&generator);
+        assert_eq!(password.chars().count(), 64);
+        assert!(!password
+            .chars()
+            .any(|c| LOOKALIKES.contains(c) || "abc".contains(c)));
+        assert!(password.chars().any(|c| "äö".contains(c)));
+
+        let digits_only = PasswordGenerator::new()
+            .with_lowercase(false)
+            .with_uppercase(false)
+            .with_length(6);
+        assert!(generate(&digits_only).chars().all(|c| c.is_ascii_digit()));
+    }
+
+    #[test]
+    fn test_invalid_settings() {
+        let nothing = PasswordGenerator::new()
+            .with_lowercase(false)
+            .with_uppercase(false)
+            .with_digits(false);
+        assert!(matches!(nothing.generate(), Err(GeneratorError::NoCharacters)));
+
+        let excluded = PasswordGenerator::new()
+            .with_lowercase(false)
+            .with_uppercase(false)
+            .with_exclude_characters("0123456789");
+        assert!(matches!(excluded.generate(), Err(GeneratorError::NoCharacters)));
+
+        let too_short = PasswordGenerator::new().with_length(2);
+        assert!(matches!(
+            too_short.generate(),
+            Err(GeneratorError::InvalidLength(2))
+        ));
+        assert!(too_short.with_every_class(false).generate().is_ok());
+
+        assert!(matches!(
+            PasswordGenerator::new().with_length(0).generate(),
+            Err(GeneratorError::InvalidLength(0))
+        ));
+    }
+
+    #[test]
+    fn test_passphrases() {
+        let wordlist = "11111\tabacus\n11112\tabdomen\n\n11113 abdominal\n11114\tabide\n";
+        let generator = PassphraseGenerator::from_wordlist(wordlist).unwrap();
+        assert_eq!(generator.wordlist(), ["abacus", "abdomen", "abdominal", "abide"]);
+        assert_eq!(generator.entropy_bits(), 14.0);
+
+        let passphrase = generator.generate().unwrap();
+        let passphrase = std::str::from_utf8(passphrase.unsecure()).unwrap();
+        let words: Vec<&str> = passphrase.split(' ').collect();
+        assert_eq!(words.len(), 7);
+        assert!(words.iter().all(|w| generator.wordlist().iter().any(|l| l == w)));
+
+        let generator = generator
+            .with_words(3)
+            .with_separator(".")
+            .with_capitalization(Capitalization::FirstLetter);
+        let passphrase = generator.generate().unwrap();
+        let passphrase = std::str::from_utf8(passphrase.unsecure()).unwrap();
+        assert_eq!(passphrase.split('.').count(), 3);
+        assert!(passphrase.split('.').all(|w| w.starts_with('A')));
+
+        let plain = PassphraseGenerator::from_wordlist("Correct\nhorse\nbattery\nstaple").unwrap();
+        let upper = plain.clone().with_capitalization(Capitalization::Uppercase);
+        let passphrase = upper.generate().unwrap();
+        let passphrase = std::str::from_utf8(passphrase.unsecure()).unwrap();
+        assert_eq!(passphrase, passphrase.to_uppercase());
+
+        assert!(matches!(
+            PassphraseGenerator::from_wordlist("11111\tsame\n11112\tsame"),
+            Err(GeneratorError::InvalidWordlist)
+        ));
+        assert!(matches!(
+            plain.with_words(0).generate(),
+            Err(GeneratorError::InvalidLength(0))
+        ));
+    }
+}
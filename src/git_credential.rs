@@ -0,0 +1,373 @@
+//! Building blocks for a git credential helper
+//!
+//! Git talks to credential helpers with a line-based protocol: the request is a list of
+//! `key=value` lines (`protocol`, `host`, `path`, `username` or `url`) ended by an empty line, and
+//! the helper answers with `username` and `password` lines. A `get` helper boils down to:
+//!
+//! ```no_run
+//! use std::io::Read;
+//!
+//! use keepass::{git_credential, Database, DatabaseKey};
+//!
+//! let mut input = String::new();
+//! std::io::stdin().read_to_string(&mut input)?;
+//! let request = git_credential::CredentialRequest::parse(&input)?;
+//!
+//! let mut file = std::fs::File::open("passwords.kdbx")?;
+//! let db = Database::open(&mut file, DatabaseKey::new().with_password("secret"))?;
+//! if let Some(credential) = git_credential::find_credential(&db, &request) {
+//!     print!("{}", credential.to_git_output()?);
+//! }
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+//!
+//! Entries are matched by their URL. The protocol and host have to match, and entries whose URL
+//! has a path are preferred when the request path starts with it (git only sends a path when
+//! `credential.useHttpPath` is set). Entries in the recycle bin are never used.
+
+use thiserror::Error;
+use url::Url;
+use zeroize::Zeroize;
+
+use crate::{
+    db::{with_audit_context, Database, Entry, Group},
+    url_match::UrlComponents,
+};
+
+/// Errors while reading a request or writing an answer
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum GitCredentialError {
+    #[error("Invalid line in credential request: {0:?}")]
+    InvalidLine(String),
+
+    #[error("Invalid URL in credential request: {0:?}")]
+    InvalidUrl(String),
+
+    #[error("Credential request is missing the {0} attribute")]
+    MissingAttribute(&'static str),
+
+    /// Values must not contain line breaks or NUL characters
+    #[error("The {0} cannot be passed to git")]
+    InvalidValue(&'static str),
+}
+
+/// What git asks for
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CredentialRequest {
+    /// E.g. `https`
+    pub protocol: String,
+
+    /// The host name, including a port if one was given
+    pub host: String,
+
+    /// The path of the repository, without a leading slash
+    pub path: Option<String>,
+
+    /// The user name, if git already knows it
+    pub username: Option<String>,
+}
+
+impl CredentialRequest {
+    /// Parse a request in the format of the git credential protocol. Parsing stops at the first
+    /// empty line, unknown attributes are ignored.
+    pub fn parse(input: &str) -> Result<Self, GitCredentialError> {
+        let mut request = CredentialRequest::default();
+
+        for line in input.lines() {
+            if line.is_empty() {
+                break;
+            }
+
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| GitCredentialError::InvalidLine(line.to_string()))?;
+
+            match key {
+                "protocol" => request.protocol = value.to_string(),
+                "host" => request.host = value.to_string(),
+                "path" => request.path = Some(value.to_string()),
+                "username" => request.username = Some(value.to_string()),
+                "url" => {
+                    let url =
+                        Url::parse(value).map_err(|_| GitCredentialError::InvalidUrl(value.to_string()))?;
+                    request.protocol = url.scheme().to_string();
+                    request.host = match (url.host_str(), url.port()) {
+                        (Some(host), Some(port)) => format!("{}:{}", host, port),
+                        (Some(host), None) => host.to_string(),
+                        (None, _) => String::new(),
+                    };
+                    let path = url.path().trim_start_matches('/');
+                    request.path = Some(path.to_string()).filter(|p| !p.is_empty());
+                    if !url.username().is_empty() {
+                        request.username = Some(url.username().to_string());
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        if request.protocol.is_empty() {
+            return Err(GitCredentialError::MissingAttribute("protocol"));
+        }
+        if request.host.is_empty() {
+            return Err(GitCredentialError::MissingAttribute("host"));
+        }
+
+        Ok(request)
+    }
+
+    fn url(&self) -> Option<Url> {
+        let path = self.path.as_deref().unwrap_or_default();
+        Url::parse(&format!("{}://{}/{}", self.protocol, self.host, path)).ok()
+    }
+}
+
+/// The answer to a request. The password is wiped from memory when the credential is dropped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GitCredential {
+    pub username: Option<String>,
+    pub password: String,
+}
+
+impl Drop for GitCredential {
+    fn drop(&mut self) {
+        self.password.zeroize();
+    }
+}
+
+impl GitCredential {
+    /// Format the credential as the answer to git
+    pub fn to_git_output(&self) -> Result<String, GitCredentialError> {
+        let invalid = |value: &str| value.contains(['\n', '\0']);
+
+        let mut out = String::new();
+        if let Some(ref username) = self.username {
+            if invalid(username) {
+                return Err(GitCredentialError::InvalidValue("username"));
+            }
+            out.push_str(&format!("username={}\n", username));
+        }
+        if invalid(&self.password) {
+            return Err(GitCredentialError::InvalidValue("password"));
+        }
+        out.push_str(&format!("password={}\n", self.password));
+
+        Ok(out)
+    }
+}
+
+/// Find the credential of the entry that matches the request best
+pub fn find_credential(db: &Database, request: &CredentialRequest) -> Option<GitCredential> {
+    let request_url = request.url()?;
+
+    let mut best: Option<(Score, &Entry)> = None;
+    visit_entries(&db.root, db.meta.recyclebin_uuid, &mut |entry| {
+        if let Some(score) = score(entry, request, &request_url) {
+            if best.as_ref().is_none_or(|(best_score, _)| score > *best_score) {
+                best = Some((score, entry));
+            }
+        }
+    });
+
+    let (_, entry) = best?;
+    with_audit_context("git credential helper", || {
+        Some(GitCredential {
+            username: username(entry),
+            password: entry.get_protected("Password")?.as_str()?.to_string(),
+        })
+    })
+}
+
+/// The user name of an entry, which may be protected
+fn username(entry: &Entry) -> Option<String> {
+    let username = entry.get_protected("UserName")?;
+    username.as_str().filter(|u| !u.is_empty()).map(str::to_string)
+}
+
+fn visit_entries<'a>(group: &'a Group, recycle_bin: Option<uuid::Uuid>, f: &mut dyn FnMut(&'a Entry)) {
+    if Some(group.uuid) == recycle_bin {
+        return;
+    }
+    for entry in group.entries() {
+        f(entry);
+    }
+    for child in group.groups() {
+        visit_entries(child, recycle_bin, f);
+    }
+}
+
+/// How well an entry matches, compared in field order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Score {
+    /// 0 if the entry URL has a path but the request has none, 1 if the entry URL has no path,
+    /// and 1 plus the length of the path if it matches the request
+    path: usize,
+
+    /// The user names match, rather than the request or the entry having none
+    username: bool,
+
+    /// The entry URL names the protocol, rather than just the host
+    protocol: bool,
+}
+
+fn score(entry: &Entry, request: &CredentialRequest, request_url: &Url) -> Option<Score> {
+    let entry_url = entry.get_url().map(str::trim).filter(|u| !u.is_empty())?;
+
+    // entries often only contain the host name
+    let (url, protocol) = match UrlComponents::split(entry_url).scheme {
+        Some(_) => (Url::parse(entry_url).ok()?, true),
+        None => (
+            Url::parse(&format!("{}://{}", request.protocol, entry_url)).ok()?,
+            false,
+        ),
+    };
+
+    if url.scheme() != request_url.scheme()
+        || url.host_str()? != request_url.host_str()?
+        || url.port_or_known_default() != request_url.port_or_known_default()
+    {
+        return None;
+    }
+
+    let username = match (request.username.as_deref(), username(entry)) {
+        (Some(wanted), Some(username)) => {
+            if wanted != username {
+                return None;
+            }
+            true
+        }
+        _ => false,
+    };
+
+    let entry_path = url.path().trim_matches('/');
+    let path = if entry_path.is_empty() {
+        1
+    } else {
+        let request_path = request_url.path().trim_matches('/');
+        if request_path == entry_path || request_path.starts_with(&format!("{}/", entry_path)) {
+            1 + entry_path.len()
+        } else if request.path.is_some() {
+            return None;
+        } else {
+            0
+        }
+    };
+
+    Some(Score {
+        path,
+        username,
+        protocol,
+    })
+}
+
+#[cfg(test)]
+mod git_credential_tests {
+    use crate::db::{Database, Entry, Group, Value};
+
+    use super::{find_credential, CredentialRequest, GitCredential, GitCredentialError};
+
+    fn entry(url: &str, username: &str, password: &str) -> Entry {
+        let mut entry = Entry::new();
+        entry
+            .fields
+            .insert("URL".to_string(), Value::Unprotected(url.to_string()));
+        entry
+            .fields
+            .insert("UserName".to_string(), Value::Unprotected(username.to_string()));
+        entry.fields.insert(
+            "Password".to_string(),
+            Value::Protected(password.as_bytes().into()),
+        );
+        entry
+    }
+
+    #[test]
+    fn test_parse_request() {
+        let request =
+            CredentialRequest::parse("protocol=https\nhost=example.com:8443\nwantlist=1\n\nhost=ignored\n")
+                .unwrap();
+        assert_eq!(request.protocol, "https");
+        assert_eq!(request.host, "example.com:8443");
+        assert_eq!(request.path, None);
+
+        let request = CredentialRequest::parse("url=https://alice@git.example.com/org/repo.git\n").unwrap();
+        assert_eq!(request.host, "git.example.com");
+        assert_eq!(request.path.as_deref(), Some("org/repo.git"));
+        assert_eq!(request.username.as_deref(), Some("alice"));
+
+        assert_eq!(
+            CredentialRequest::parse("protocol=https\n"),
+            Err(GitCredentialError::MissingAttribute("host"))
+        );
+        assert!(matches!(
+            CredentialRequest::parse("protocol https\n"),
+            Err(GitCredentialError::InvalidLine(_))
+        ));
+    }
+
+    #[test]
+    fn test_find_credential() {
+        let mut db = Database::new(Default::default());
+        db.root.add_child(entry("github.com", "bob", "host only"));
+        db.root.add_child(entry("https://github.com/org", "bob", "org"));
+        db.root.add_child(entry("https://github.com", "alice", "alice"));
+        db.root.add_child(entry("http://github.com", "bob", "plain http"));
+        db.root
+            .add_child(entry("https://example.com:8443", "", "custom port"));
+
+        let mut trash = Group::new("Recycle Bin");
+        trash.add_child(entry("https://gitlab.com", "bob", "deleted"));
+        db.meta.recyclebin_uuid = Some(trash.uuid);
+        db.root.add_child(trash);
+
+        let find = |input: &str| {
+            find_credential(&db, &CredentialRequest::parse(input).unwrap()).map(|c| c.password.clone())
+        };
+
+        // entries naming the protocol win over bare host names
+        assert_eq!(
+            find("protocol=https\nhost=github.com\n").as_deref(),
+            Some("alice")
+        );
+        assert_eq!(
+            find("protocol=https\nhost=github.com\nusername=bob\n").as_deref(),
+            Some("host only")
+        );
+        assert_eq!(
+            find("protocol=https\nhost=github.com\npath=org/repo.git\nusername=bob\n").as_deref(),
+            Some("org")
+        );
+        assert_eq!(
+            find("protocol=https\nhost=github.com\npath=other/repo.git\nusername=bob\n").as_deref(),
+            Some("host only")
+        );
+        assert_eq!(
+            find("protocol=http\nhost=github.com\n").as_deref(),
+            Some("plain http")
+        );
+        assert_eq!(find("protocol=https\nhost=github.com\nusername=carol\n"), None);
+        assert_eq!(
+            find("protocol=https\nhost=example.com:8443\n").as_deref(),
+            Some("custom port")
+        );
+        assert_eq!(find("protocol=https\nhost=example.com\n"), None);
+        assert_eq!(find("protocol=https\nhost=gitlab.com\n"), None);
+
+        let credential = find_credential(
+            &db,
+            &CredentialRequest::parse("url=https://example.com:8443\n").unwrap(),
+        )
+        .unwrap();
+        assert_eq!(credential.username, None);
+        assert_eq!(credential.to_git_output().unwrap(), "password=custom port\n");
+
+        let invalid = GitCredential {
+            username: Some("bob".to_string()),
+            password: "line\nbreak".to_string(),
+        };
+        assert_eq!(
+            invalid.to_git_output(),
+            Err(GitCredentialError::InvalidValue("password"))
+        );
+    }
+}
@@ -1,108 +1,232 @@
+use std::io::Read;
+#[cfg(feature = "save_kdbx4")]
+use std::io::Write;
+
 use byteorder::{ByteOrder, LittleEndian};
-use cipher::generic_array::{typenum::U64, GenericArray};
+use cipher::generic_array::{
+    typenum::{U32, U64},
+    GenericArray,
+};
 use hex_literal::hex;
+use zeroize::Zeroizing;
 
-use crate::error::{BlockStreamError, CryptographyError};
+use crate::{
+    error::{BlockStreamError, CryptographyError},
+    progress,
+};
 
 pub const HMAC_KEY_END: [u8; 1] = hex!("01");
 
-/// Read from a HMAC block stream into a raw buffer
-pub(crate) fn read_hmac_block_stream(
-    data: &[u8],
-    key: &GenericArray<u8, U64>,
-) -> Result<Vec<u8>, BlockStreamError> {
-    // keepassxc src/streams/HmacBlockStream.cpp
+/// The size of the blocks written to a HMAC block stream, as written by KeePass
+#[cfg(feature = "save_kdbx4")]
+const BLOCK_SIZE: usize = 1024 * 1024;
 
-    let mut out = Vec::new();
+fn stream_error(e: BlockStreamError) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+}
 
-    let mut pos = 0;
-    let mut block_index: u64 = 0;
+/// Compute the HMAC of a block from its index and its size
+fn block_hmac(
+    block_index: u64,
+    size_bytes: &[u8],
+    block: &[u8],
+    key: &GenericArray<u8, U64>,
+) -> Result<GenericArray<u8, U32>, CryptographyError> {
+    let hmac_block_key = get_hmac_block_key(block_index, key)?;
+    let mut block_index_buf = [0u8; 8];
+    LittleEndian::write_u64(&mut block_index_buf, block_index);
+    crate::crypt::calculate_hmac(&[&block_index_buf, size_bytes, block], &hmac_block_key)
+}
 
-    while pos < data.len() {
-        let hmac = &data[pos..(pos + 32)];
-        let size_bytes = &data[(pos + 32)..(pos + 36)];
-        let size = LittleEndian::read_u32(size_bytes) as usize;
-        let block = &data[(pos + 36)..(pos + 36 + size)];
+/// A block of a HMAC block stream as it was read, before its HMAC is verified
+pub(crate) struct RawBlock {
+    hmac: [u8; 32],
+    size_bytes: [u8; 4],
+    pub(crate) data: Vec<u8>,
+}
 
-        // verify block hmac
-        let hmac_block_key = get_hmac_block_key(block_index, key)?;
-        let mut block_index_buf = [0u8; 8];
-        LittleEndian::write_u64(&mut block_index_buf, block_index as u64);
+impl RawBlock {
+    /// Whether this is the empty block ending the stream
+    pub(crate) fn is_final(&self) -> bool {
+        self.data.is_empty()
+    }
 
-        if hmac
-            != crate::crypt::calculate_hmac(&[&block_index_buf, size_bytes, &block], &hmac_block_key)?
-                .as_slice()
-        {
-            return Err(BlockStreamError::BlockHashMismatch { block_index }.into());
+    /// Check the HMAC of the block, given its index in the stream
+    pub(crate) fn verify(&self, block_index: u64, key: &GenericArray<u8, U64>) -> Result<(), std::io::Error> {
+        let expected =
+            block_hmac(block_index, &self.size_bytes, &self.data, key).map_err(|e| stream_error(e.into()))?;
+        if self.hmac != expected.as_slice() {
+            return Err(stream_error(BlockStreamError::BlockHashMismatch { block_index }));
         }
+        Ok(())
+    }
+}
 
-        pos += 36 + size;
-        block_index += 1;
+/// Read the next block of a HMAC block stream into `data`, whose allocation is reused, or `None`
+/// if the source ends before it
+pub(crate) fn read_block(inner: &mut dyn Read, mut data: Vec<u8>) -> Result<Option<RawBlock>, std::io::Error> {
+    // keepassxc src/streams/HmacBlockStream.cpp
 
-        if size == 0 {
-            break;
+    // the source may end right after a block instead of with an empty final block
+    let mut block_header = [0u8; 36];
+    let mut header_len = 0;
+    while header_len < block_header.len() {
+        match inner.read(&mut block_header[header_len..]) {
+            Ok(0) if header_len == 0 => return Ok(None),
+            Ok(0) => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+            Ok(read) => header_len += read,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
         }
+    }
+
+    let mut hmac = [0u8; 32];
+    let mut size_bytes = [0u8; 4];
+    hmac.copy_from_slice(&block_header[..32]);
+    size_bytes.copy_from_slice(&block_header[32..]);
+    let size = LittleEndian::read_u32(&size_bytes) as u64;
 
-        out.extend_from_slice(block);
+    data.clear();
+    inner.take(size).read_to_end(&mut data)?;
+    if data.len() as u64 != size {
+        return Err(std::io::ErrorKind::UnexpectedEof.into());
     }
+    progress::advance_io(block_header.len() as u64 + size)?;
 
-    Ok(out)
+    Ok(Some(RawBlock {
+        hmac,
+        size_bytes,
+        data,
+    }))
 }
 
-#[cfg(feature = "save_kdbx4")]
-/// Write a raw buffer as a HMAC block stream
-pub(crate) fn write_hmac_block_stream(
-    data: &[u8],
-    key: &GenericArray<u8, U64>,
-) -> Result<Vec<u8>, CryptographyError> {
-    let mut out = Vec::new();
+/// Reads the verified content of a HMAC block stream, one block at a time. Reading stops after
+/// the final block of the stream, so that any data following it can be read from the source.
+pub(crate) struct HmacBlockStreamReader<'a> {
+    inner: &'a mut dyn Read,
+    key: Zeroizing<GenericArray<u8, U64>>,
+    block_index: u64,
+    block: Vec<u8>,
+    pos: usize,
+    finished: bool,
+}
 
-    let mut pos = 0;
-    let mut block_index = 0;
+impl<'a> HmacBlockStreamReader<'a> {
+    pub(crate) fn new(inner: &'a mut dyn Read, key: &GenericArray<u8, U64>) -> Self {
+        HmacBlockStreamReader {
+            inner,
+            key: Zeroizing::new(*key),
+            block_index: 0,
+            block: Vec::new(),
+            pos: 0,
+            finished: false,
+        }
+    }
 
-    while pos < data.len() {
-        let size = data.len() - pos;
+    /// Read and verify the next block, returning false at the end of the stream
+    fn next_block(&mut self) -> Result<bool, std::io::Error> {
+        self.pos = 0;
 
-        let block = &data[pos..(pos + size)];
+        let block = match read_block(self.inner, std::mem::take(&mut self.block))? {
+            Some(block) => block,
+            None => return Ok(false),
+        };
+        block.verify(self.block_index, &self.key)?;
+        self.block_index += 1;
 
-        let mut size_bytes: Vec<u8> = vec![];
-        size_bytes.resize(4, 0);
-        LittleEndian::write_u32(&mut size_bytes, size as u32);
+        self.block = block.data;
+        Ok(!self.block.is_empty())
+    }
+}
 
-        // Generate block hmac
-        let hmac_block_key = get_hmac_block_key(block_index, key)?;
-        let mut block_index_buf = [0u8; 8];
-        LittleEndian::write_u64(&mut block_index_buf, block_index as u64);
+impl<'a> Read for HmacBlockStreamReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
+        while self.pos == self.block.len() {
+            if self.finished {
+                return Ok(0);
+            }
+            if !self.next_block()? {
+                self.finished = true;
+            }
+        }
 
-        let hmac = crate::crypt::calculate_hmac(&[&block_index_buf, &size_bytes, &block], &hmac_block_key)?;
+        let len = buf.len().min(self.block.len() - self.pos);
+        buf[..len].copy_from_slice(&self.block[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
 
-        pos += 36 + size;
-        block_index += 1;
+/// Writes everything written to it as a HMAC block stream. `finish` needs to be called to write
+/// the last block and the empty block ending the stream.
+#[cfg(feature = "save_kdbx4")]
+pub(crate) struct HmacBlockStreamWriter<'a> {
+    inner: &'a mut dyn Write,
+    key: Zeroizing<GenericArray<u8, U64>>,
+    block_index: u64,
+    block: Vec<u8>,
+}
 
-        out.extend_from_slice(&hmac);
-        out.extend_from_slice(&size_bytes);
-        out.extend_from_slice(&block);
+#[cfg(feature = "save_kdbx4")]
+impl<'a> HmacBlockStreamWriter<'a> {
+    pub(crate) fn new(inner: &'a mut dyn Write, key: &GenericArray<u8, U64>) -> Self {
+        HmacBlockStreamWriter {
+            inner,
+            key: Zeroizing::new(*key),
+            block_index: 0,
+            block: Vec::new(),
+        }
     }
 
-    // the end of the HMAC block stream should be an empty block, but with a valid HMAC
-    let hmac_block_key = get_hmac_block_key(block_index, key)?;
-    let mut block_index_buf = [0u8; 8];
-    LittleEndian::write_u64(&mut block_index_buf, block_index as u64);
+    fn write_block(&mut self, len: usize) -> Result<(), std::io::Error> {
+        let mut size_bytes = [0u8; 4];
+        LittleEndian::write_u32(&mut size_bytes, len as u32);
 
-    let size_bytes = vec![0; 4];
-    let hmac = crate::crypt::calculate_hmac(&[&block_index_buf, &size_bytes, &[]], &hmac_block_key)?;
+        let block = &self.block[..len];
+        let hmac =
+            block_hmac(self.block_index, &size_bytes, block, &self.key).map_err(|e| stream_error(e.into()))?;
 
-    out.extend_from_slice(&hmac);
-    out.extend_from_slice(&size_bytes);
+        self.inner.write_all(&hmac)?;
+        self.inner.write_all(&size_bytes)?;
+        self.inner.write_all(block)?;
+        progress::advance_io((hmac.len() + size_bytes.len() + len) as u64)?;
 
-    Ok(out)
+        self.block.drain(..len);
+        self.block_index += 1;
+        Ok(())
+    }
+
+    /// Write the remaining data, followed by an empty block with a valid HMAC, which ends the
+    /// stream
+    pub(crate) fn finish(mut self) -> Result<(), std::io::Error> {
+        if !self.block.is_empty() {
+            self.write_block(self.block.len())?;
+        }
+        self.write_block(0)?;
+        self.inner.flush()
+    }
+}
+
+#[cfg(feature = "save_kdbx4")]
+impl<'a> Write for HmacBlockStreamWriter<'a> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, std::io::Error> {
+        self.block.extend_from_slice(buf);
+        while self.block.len() >= BLOCK_SIZE {
+            self.write_block(BLOCK_SIZE)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        self.inner.flush()
+    }
 }
 
 pub(crate) fn get_hmac_block_key(
     block_index: u64,
     key: &GenericArray<u8, U64>,
-) -> Result<GenericArray<u8, U64>, CryptographyError> {
+) -> Result<Zeroizing<GenericArray<u8, U64>>, CryptographyError> {
     let mut buf = [0u8; 8];
     LittleEndian::write_u64(&mut buf, block_index as u64);
-    crate::crypt::calculate_sha512(&[&buf, key])
+    Ok(Zeroizing::new(crate::crypt::calculate_sha512(&[&buf, key])?))
 }
@@ -0,0 +1,109 @@
+//! Import of entries from other password managers
+//!
+//! Every importer creates a new database holding the imported entries, which can be merged into
+//! an existing database or saved as it is.
+
+use crate::db::{Entry, Group, Node, Value};
+
+pub mod browser;
+pub mod csv;
+pub mod lastpass;
+
+#[cfg(feature = "bitwarden")]
+pub mod bitwarden;
+
+#[cfg(feature = "onepassword")]
+pub mod onepassword;
+
+#[cfg(any(feature = "onepassword", feature = "keeshare"))]
+pub(crate) mod zip;
+
+/// The group at `path` below `group`, creating missing groups
+pub(crate) fn group_at<'a>(group: &'a mut Group, path: &[&str]) -> &'a mut Group {
+    let (name, rest) = match path.split_first() {
+        Some(split) => split,
+        None => return group,
+    };
+
+    let index = match group
+        .children
+        .iter()
+        .position(|node| matches!(node, Node::Group(g) if g.name == *name))
+    {
+        Some(index) => index,
+        None => {
+            group.add_child(Group::new(name));
+            group.children.len() - 1
+        }
+    };
+
+    match &mut group.children[index] {
+        Node::Group(child) => group_at(child, rest),
+        Node::Entry(_) => unreachable!("the index points to a group"),
+    }
+}
+
+pub(crate) fn string_value(value: &str, protected: bool) -> Value {
+    if protected {
+        Value::Protected(value.as_bytes().into())
+    } else {
+        Value::Unprotected(value.to_string())
+    }
+}
+
+/// Insert a field, numbering its name if a field with that name exists already
+pub(crate) fn insert_unique(entry: &mut Entry, name: &str, value: Value) {
+    let mut unique = name.to_string();
+    let mut n = 1;
+    while entry.fields.contains_key(&unique) {
+        n += 1;
+        unique = format!("{} ({})", name, n);
+    }
+    entry.fields.insert(unique, value);
+}
+
+/// Set the URL of an entry to the first of `urls`, and store the others in `KP2A_URL`,
+/// `KP2A_URL_1`, ... fields, which KeePassXC and KeePass2Android match as well. Empty and
+/// repeated URLs are skipped.
+#[cfg(any(feature = "onepassword", feature = "bitwarden"))]
+pub(crate) fn set_urls<'a>(entry: &mut Entry, urls: impl IntoIterator<Item = &'a str>) {
+    let mut seen: Vec<&str> = Vec::new();
+    for url in urls {
+        if url.is_empty() || seen.contains(&url) {
+            continue;
+        }
+        let name = match seen.len() {
+            0 => "URL".to_string(),
+            1 => "KP2A_URL".to_string(),
+            n => format!("KP2A_URL_{}", n - 1),
+        };
+        seen.push(url);
+        insert_unique(entry, &name, Value::Unprotected(url.to_string()));
+    }
+}
+
+/// An `otpauth://` URL for a one-time password, which other password managers store either as
+/// such a URL or as the bare secret
+pub(crate) fn otp_url(title: &str, totp: &str) -> String {
+    if totp.starts_with("otpauth://") {
+        return totp.to_string();
+    }
+    format!(
+        "otpauth://totp/{}?secret={}",
+        percent_encode(title),
+        percent_encode(&totp.replace(' ', ""))
+    )
+}
+
+/// Percent-encode everything but unreserved characters, for use in an `otpauth://` URL
+fn percent_encode(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for b in value.bytes() {
+        if b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.' | b'_' | b'~') {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{:02X}", b));
+        }
+    }
+    out
+}
@@ -0,0 +1,488 @@
+//! Import of unencrypted Bitwarden exports in the JSON format
+//!
+//! Every folder of a personal export and every collection of an organization export becomes a
+//! group below the root group, and every item an entry in the group of its folder, or of its first
+//! collection if it is in no folder. Folders named like `Social/Forums` are nested as Bitwarden
+//! shows them:
+//!
+//! ```no_run
+//! use keepass::{import::bitwarden::import_bitwarden_json, DatabaseKey};
+//!
+//! let db = import_bitwarden_json(&mut std::fs::File::open("bitwarden_export.json")?)?;
+//! # #[cfg(feature = "save_kdbx4")]
+//! db.save(
+//!     &mut std::fs::File::create("imported.kdbx")?,
+//!     DatabaseKey::new().with_password("demopass"),
+//! )?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+//!
+//! The name and notes of an item are mapped to the entry, as are the user name and password of
+//! logins. The first URI of a login becomes the URL of the entry and further ones are stored in
+//! `KP2A_URL`, `KP2A_URL_1`, ... fields, which KeePassXC and KeePass2Android match as well. The
+//! one-time password is stored as an `otpauth://` URL in the `otp` field, and previous passwords
+//! become history entries. Custom fields become fields of the entry, protected if they are hidden
+//! in Bitwarden, and the details of cards, identities and SSH keys are stored in fields as well.
+//!
+//! Exports encrypted with the account key or a password are not supported.
+
+use std::io::Read;
+
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::{
+    db::{Database, Entry, History, Value},
+    import::{group_at, insert_unique, otp_url, set_urls, string_value},
+};
+
+/// Errors upon importing a Bitwarden export
+#[derive(Debug, Error)]
+pub enum BitwardenImportError {
+    /// An I/O error has occurred while reading the export
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    /// The export is not valid JSON in the format of Bitwarden
+    #[error("Invalid export data: {0}")]
+    Json(#[from] serde_json::Error),
+
+    /// The export is encrypted, which is not supported
+    #[error("Encrypted Bitwarden exports are not supported")]
+    EncryptedExport,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Export {
+    encrypted: bool,
+    folders: Vec<Folder>,
+    collections: Vec<Folder>,
+    items: Vec<Item>,
+}
+
+/// A folder or a collection
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Folder {
+    id: String,
+    name: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct Item {
+    folder_id: Option<String>,
+    collection_ids: Option<Vec<String>>,
+    name: Option<String>,
+    notes: Option<String>,
+    fields: Option<Vec<Field>>,
+    login: Option<Login>,
+    card: Option<Card>,
+    identity: Option<serde_json::Map<String, serde_json::Value>>,
+    ssh_key: Option<SshKey>,
+    password_history: Option<Vec<PasswordHistory>>,
+    creation_date: Option<String>,
+    revision_date: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct Field {
+    name: Option<String>,
+    value: Option<String>,
+    /// 0 for text, 1 for hidden, 2 for boolean and 3 for linked fields
+    #[serde(rename = "type")]
+    kind: u8,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Login {
+    uris: Option<Vec<LoginUri>>,
+    username: Option<String>,
+    password: Option<String>,
+    totp: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct LoginUri {
+    uri: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct Card {
+    cardholder_name: Option<String>,
+    brand: Option<String>,
+    number: Option<String>,
+    exp_month: Option<String>,
+    exp_year: Option<String>,
+    code: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct SshKey {
+    private_key: Option<String>,
+    public_key: Option<String>,
+    key_fingerprint: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct PasswordHistory {
+    last_used_date: Option<String>,
+    password: Option<String>,
+}
+
+/// The fields of an identity, in the order Bitwarden shows them, and their names in the entry
+const IDENTITY_FIELDS: [(&str, &str); 18] = [
+    ("title", "Title (Identity)"),
+    ("firstName", "First Name"),
+    ("middleName", "Middle Name"),
+    ("lastName", "Last Name"),
+    ("username", "Identity User Name"),
+    ("company", "Company"),
+    ("ssn", "Social Security Number"),
+    ("passportNumber", "Passport Number"),
+    ("licenseNumber", "License Number"),
+    ("email", "Email"),
+    ("phone", "Phone"),
+    ("address1", "Address 1"),
+    ("address2", "Address 2"),
+    ("address3", "Address 3"),
+    ("city", "City"),
+    ("state", "State"),
+    ("postalCode", "Postal Code"),
+    ("country", "Country"),
+];
+
+/// Import all folders, collections and items of an unencrypted Bitwarden JSON export into a new
+/// database
+pub fn import_bitwarden_json(source: &mut dyn Read) -> Result<Database, BitwardenImportError> {
+    let mut data = zeroize::Zeroizing::new(Vec::new());
+    source.read_to_end(&mut data)?;
+    let export: Export = serde_json::from_slice(&data)?;
+    if export.encrypted {
+        return Err(BitwardenImportError::EncryptedExport);
+    }
+
+    let mut db = Database::new(Default::default());
+
+    // create the groups up front, so that empty folders are kept as well
+    for folder in export.folders.iter().chain(&export.collections) {
+        group_at(&mut db.root, &group_path(&folder.name));
+    }
+
+    for item in &export.items {
+        let folder_id = item
+            .folder_id
+            .as_ref()
+            .or_else(|| item.collection_ids.as_ref().and_then(|ids| ids.first()));
+        let folder = folder_id.and_then(|id| {
+            export
+                .folders
+                .iter()
+                .chain(&export.collections)
+                .find(|f| &f.id == id)
+        });
+        let path = folder.map(|f| group_path(&f.name)).unwrap_or_default();
+
+        group_at(&mut db.root, &path).add_child(import_item(item));
+    }
+
+    Ok(db)
+}
+
+/// The path of the group of a folder, whose name is separated by `/` for nested folders
+fn group_path(name: &str) -> Vec<&str> {
+    name.split('/').filter(|n| !n.is_empty()).collect()
+}
+
+fn import_item(item: &Item) -> Entry {
+    let title = item.name.as_deref().unwrap_or_default();
+    let mut entry = Entry::new().with_title(title);
+
+    if let Some(notes) = item.notes.as_deref().filter(|n| !n.is_empty()) {
+        entry = entry.with_notes(notes);
+    }
+
+    if let Some(login) = &item.login {
+        if let Some(username) = login.username.as_deref().filter(|u| !u.is_empty()) {
+            entry = entry.with_username(username);
+        }
+        if let Some(password) = login.password.as_deref().filter(|p| !p.is_empty()) {
+            entry = entry.with_password(password);
+        }
+        set_urls(
+            &mut entry,
+            login.uris.iter().flatten().filter_map(|u| u.uri.as_deref()),
+        );
+        if let Some(totp) = login.totp.as_deref().filter(|t| !t.is_empty()) {
+            entry
+                .fields
+                .insert("otp".to_string(), string_value(&otp_url(title, totp), true));
+        }
+    }
+
+    if let Some(card) = &item.card {
+        import_card(&mut entry, card);
+    }
+
+    if let Some(identity) = &item.identity {
+        for (key, name) in IDENTITY_FIELDS.iter() {
+            if let Some(value) = identity
+                .get(*key)
+                .and_then(|v| v.as_str())
+                .filter(|v| !v.is_empty())
+            {
+                insert_unique(&mut entry, name, Value::Unprotected(value.to_string()));
+            }
+        }
+        if entry.get_username().is_none() {
+            if let Some(username) = identity
+                .get("username")
+                .and_then(|v| v.as_str())
+                .filter(|u| !u.is_empty())
+            {
+                entry = entry.with_username(username);
+            }
+        }
+    }
+
+    if let Some(key) = &item.ssh_key {
+        for (name, value, protected) in [
+            ("Private Key", &key.private_key, true),
+            ("Public Key", &key.public_key, false),
+            ("Key Fingerprint", &key.key_fingerprint, false),
+        ] {
+            if let Some(value) = value.as_deref().filter(|v| !v.is_empty()) {
+                insert_unique(&mut entry, name, string_value(value, protected));
+            }
+        }
+    }
+
+    for field in item.fields.iter().flatten() {
+        let value = field.value.as_deref().unwrap_or_default();
+        let value = match field.kind {
+            0 | 2 => Value::Unprotected(value.to_string()),
+            1 => string_value(value, true),
+            // linked fields only refer to another field of the item
+            _ => continue,
+        };
+        insert_unique(&mut entry, field.name.as_deref().unwrap_or_default(), value);
+    }
+
+    let created = item.creation_date.as_deref().and_then(timestamp);
+    let modified = item.revision_date.as_deref().and_then(timestamp);
+    if let Some(created) = created {
+        entry.times.set_creation(created);
+    }
+    if let Some(modified) = modified.or(created) {
+        entry.times.set_last_modification(modified);
+    }
+
+    import_password_history(&mut entry, item.password_history.iter().flatten());
+
+    entry
+}
+
+fn import_card(entry: &mut Entry, card: &Card) {
+    let expiration = match (card.exp_month.as_deref(), card.exp_year.as_deref()) {
+        (Some(month), Some(year)) if !month.is_empty() && !year.is_empty() => {
+            Some(format!("{:0>2}/{}", month, year))
+        }
+        (None, Some(year)) | (Some(""), Some(year)) if !year.is_empty() => Some(year.to_string()),
+        _ => None,
+    };
+
+    for (name, value, protected) in [
+        ("Cardholder Name", card.cardholder_name.as_deref(), false),
+        ("Brand", card.brand.as_deref(), false),
+        ("Number", card.number.as_deref(), true),
+        ("Expiration", expiration.as_deref(), false),
+        ("Security Code", card.code.as_deref(), true),
+    ] {
+        if let Some(value) = value.filter(|v| !v.is_empty()) {
+            insert_unique(entry, name, string_value(value, protected));
+        }
+    }
+}
+
+/// Add a history entry for every previous password, which Bitwarden lists newest first
+fn import_password_history<'a>(
+    entry: &mut Entry,
+    history: impl DoubleEndedIterator<Item = &'a PasswordHistory>,
+) {
+    let mut entries = History::default();
+    for previous in history.rev() {
+        let password = match previous.password.as_deref() {
+            Some(password) => password,
+            None => continue,
+        };
+        let mut old = entry.clone().with_password(password);
+        if let Some(time) = previous.last_used_date.as_deref().and_then(timestamp) {
+            old.times.set_last_modification(time);
+        }
+        entries.add_entry(old);
+    }
+
+    if !entries.entries.is_empty() {
+        entry.history = Some(entries);
+    }
+}
+
+/// Parse a timestamp like `2024-02-03T04:05:06.789Z` as UTC
+fn timestamp(value: &str) -> Option<chrono::NaiveDateTime> {
+    chrono::DateTime::parse_from_rfc3339(value)
+        .ok()
+        .map(|t| t.naive_utc())
+}
+
+#[cfg(test)]
+mod bitwarden_tests {
+    use chrono::NaiveDate;
+
+    use super::{import_bitwarden_json, BitwardenImportError};
+    use crate::db::{NodeRef, Value};
+
+    const EXPORT: &str = r#"{
+        "encrypted": false,
+        "folders": [
+            { "id": "f1", "name": "Social/Forums" },
+            { "id": "f2", "name": "Empty" }
+        ],
+        "items": [
+            {
+                "id": "i1",
+                "folderId": "f1",
+                "type": 1,
+                "name": "Forum",
+                "notes": "my forum account",
+                "fields": [
+                    { "name": "PIN", "value": "1234", "type": 1, "linkedId": null },
+                    { "name": "remember", "value": "true", "type": 2, "linkedId": null },
+                    { "name": "Username", "value": null, "type": 3, "linkedId": 100 },
+                    { "name": "Title", "value": "clash", "type": 0, "linkedId": null }
+                ],
+                "login": {
+                    "uris": [
+                        { "match": null, "uri": "https://forum.example.com" },
+                        { "match": null, "uri": "https://m.forum.example.com" }
+                    ],
+                    "username": "alice",
+                    "password": "hunter2",
+                    "totp": "JBSW Y3DP EHPK 3PXP"
+                },
+                "passwordHistory": [
+                    { "lastUsedDate": "2023-05-01T10:00:00.000Z", "password": "hunter1" },
+                    { "lastUsedDate": "2022-05-01T10:00:00.000Z", "password": "hunter0" }
+                ],
+                "creationDate": "2021-02-26T00:22:36.123Z",
+                "revisionDate": "2024-02-03T04:05:06.000Z"
+            },
+            {
+                "id": "i2",
+                "folderId": null,
+                "type": 3,
+                "name": "Visa",
+                "card": {
+                    "cardholderName": "Alice",
+                    "brand": "Visa",
+                    "number": "4111111111111111",
+                    "expMonth": "3",
+                    "expYear": "2027",
+                    "code": "123"
+                }
+            },
+            {
+                "id": "i3",
+                "type": 4,
+                "name": "Me",
+                "identity": { "firstName": "Alice", "lastName": "Doe", "username": "adoe", "ssn": null }
+            }
+        ]
+    }"#;
+
+    #[test]
+    fn test_import_bitwarden_json() {
+        let db = import_bitwarden_json(&mut EXPORT.as_bytes()).unwrap();
+
+        let groups: Vec<&str> = db.root.groups().iter().map(|g| g.name.as_str()).collect();
+        assert_eq!(groups, vec!["Social", "Empty"]);
+
+        let forum = match db.root.get(&["Social", "Forums", "Forum"]) {
+            Some(NodeRef::Entry(e)) => e,
+            _ => panic!("expected the Forum entry"),
+        };
+        assert_eq!(forum.get_username(), Some("alice"));
+        assert_eq!(forum.get_password(), Some("hunter2"));
+        assert_eq!(forum.get_url(), Some("https://forum.example.com"));
+        assert_eq!(forum.get("KP2A_URL"), Some("https://m.forum.example.com"));
+        assert_eq!(forum.get("Notes"), Some("my forum account"));
+        assert!(matches!(forum.fields["PIN"], Value::Protected(_)));
+        assert_eq!(forum.get("PIN"), Some("1234"));
+        assert_eq!(forum.get("remember"), Some("true"));
+        assert_eq!(forum.get("Username"), None);
+        assert_eq!(forum.get("Title (2)"), Some("clash"));
+        assert_eq!(
+            forum.get_raw_otp_value(),
+            Some("otpauth://totp/Forum?secret=JBSWY3DPEHPK3PXP")
+        );
+        assert_eq!(
+            forum.times.get_creation(),
+            Some(
+                &NaiveDate::from_ymd_opt(2021, 2, 26)
+                    .unwrap()
+                    .and_hms_milli_opt(0, 22, 36, 123)
+                    .unwrap()
+            )
+        );
+
+        let history = forum.history.as_ref().unwrap();
+        let passwords: Vec<_> = history
+            .get_entries()
+            .iter()
+            .map(|e| e.get_password().unwrap())
+            .collect();
+        assert_eq!(passwords, vec!["hunter1", "hunter0"]);
+
+        let card = match db.root.get(&["Visa"]) {
+            Some(NodeRef::Entry(e)) => e,
+            _ => panic!("expected the Visa entry"),
+        };
+        assert!(matches!(card.fields["Number"], Value::Protected(_)));
+        assert_eq!(card.get("Expiration"), Some("03/2027"));
+        assert_eq!(card.get("Security Code"), Some("123"));
+
+        let identity = match db.root.get(&["Me"]) {
+            Some(NodeRef::Entry(e)) => e,
+            _ => panic!("expected the Me entry"),
+        };
+        assert_eq!(identity.get("First Name"), Some("Alice"));
+        assert_eq!(identity.get_username(), Some("adoe"));
+        assert_eq!(identity.get("Social Security Number"), None);
+    }
+
+    #[test]
+    fn test_import_collections() {
+        let export = r#"{
+            "encrypted": false,
+            "collections": [{ "id": "c1", "organizationId": "o1", "name": "Team" }],
+            "items": [{ "type": 2, "name": "Note", "collectionIds": ["c1"], "secureNote": { "type": 0 } }]
+        }"#;
+        let db = import_bitwarden_json(&mut export.as_bytes()).unwrap();
+        assert!(matches!(db.root.get(&["Team", "Note"]), Some(NodeRef::Entry(_))));
+    }
+
+    #[test]
+    fn test_encrypted_export() {
+        let export = r#"{ "encrypted": true, "passwordProtected": true, "data": "2.abc" }"#;
+        assert!(matches!(
+            import_bitwarden_json(&mut export.as_bytes()),
+            Err(BitwardenImportError::EncryptedExport)
+        ));
+    }
+}
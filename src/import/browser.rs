@@ -0,0 +1,246 @@
+//! Import of passwords exported by web browsers
+//!
+//! Chrome, Edge and other Chromium based browsers export their saved passwords as CSV with the
+//! columns `name`, `url`, `username`, `password` and `note`. Firefox uses the columns `url`,
+//! `username`, `password`, `httpRealm`, `formActionOrigin`, `guid`, `timeCreated`, `timeLastUsed`
+//! and `timePasswordChanged`, and Safari `Title`, `URL`, `Username`, `Password`, `Notes` and
+//! `OTPAuth`. All of them are detected from the header line:
+//!
+//! ```
+//! use keepass::import::browser::import_browser_csv;
+//!
+//! let data = "name,url,username,password,note\n\
+//!             mail.example.com,https://www.mail.example.com/login,alice,hunter2,\n";
+//! let db = import_browser_csv(&mut data.as_bytes())?;
+//! assert_eq!(db.root.entries()[0].get_title(), Some("mail.example.com"));
+//! # Ok::<(), keepass::import::csv::CsvImportError>(())
+//! ```
+//!
+//! Every login becomes an entry in the root group, titled after the host name of its URL without
+//! a leading `www.`. Browsers often save the same login several times, e.g. for different pages of
+//! a site, so logins with the same URL and user name are imported only once, keeping the one whose
+//! password was changed last.
+
+use std::io::Read;
+
+use chrono::NaiveDateTime;
+
+use crate::{
+    db::{Database, Entry},
+    import::{
+        csv::{read_records, CsvImportError, Record},
+        insert_unique, otp_url, string_value,
+    },
+    url_match::UrlComponents,
+};
+
+/// The positions of the known columns in the header line
+struct Columns {
+    url: usize,
+    password: usize,
+    username: Option<usize>,
+    name: Option<usize>,
+    notes: Option<usize>,
+    otp: Option<usize>,
+    http_realm: Option<usize>,
+    created: Option<usize>,
+    last_used: Option<usize>,
+    password_changed: Option<usize>,
+}
+
+/// Import all logins of a CSV file exported by a web browser into a new database
+pub fn import_browser_csv(source: &mut dyn Read) -> Result<Database, CsvImportError> {
+    let records = read_records(source, ',')?;
+    let (header, records) = match records.split_first() {
+        Some(split) => split,
+        None => return Err(CsvImportError::MissingColumn("url".to_string())),
+    };
+
+    let position = |names: &[&str]| {
+        header
+            .fields
+            .iter()
+            .position(|h| names.iter().any(|n| h.trim().eq_ignore_ascii_case(n)))
+    };
+    let required =
+        |name: &str| position(&[name]).ok_or_else(|| CsvImportError::MissingColumn(name.to_string()));
+    let columns = Columns {
+        url: required("url")?,
+        password: required("password")?,
+        username: position(&["username"]),
+        name: position(&["name", "title"]),
+        notes: position(&["note", "notes"]),
+        otp: position(&["otpauth"]),
+        http_realm: position(&["httpRealm"]),
+        created: position(&["timeCreated"]),
+        last_used: position(&["timeLastUsed"]),
+        password_changed: position(&["timePasswordChanged"]),
+    };
+
+    // the imported logins with the key they are deduplicated by and the time of their password
+    let mut logins: Vec<((String, String), Option<NaiveDateTime>, Entry)> = Vec::new();
+    for record in records {
+        let (key, changed, entry) = import_record(record, &columns);
+        match logins.iter_mut().find(|(k, _, _)| k == &key) {
+            Some(login) if changed > login.1 => *login = (key, changed, entry),
+            Some(_) => {}
+            None => logins.push((key, changed, entry)),
+        }
+    }
+
+    let mut db = Database::new(Default::default());
+    for (_, _, entry) in logins {
+        db.root.add_child(entry);
+    }
+    Ok(db)
+}
+
+fn import_record(record: &Record, columns: &Columns) -> ((String, String), Option<NaiveDateTime>, Entry) {
+    let field = |index: Option<usize>| -> &str {
+        index
+            .and_then(|i| record.fields.get(i))
+            .map(|f| f.as_str())
+            .unwrap_or_default()
+    };
+
+    let url = field(Some(columns.url));
+    let username = field(columns.username);
+    let title = match host_name(url) {
+        Some(host) => host,
+        None if !field(columns.name).is_empty() => field(columns.name),
+        None => url,
+    };
+
+    let mut entry = Entry::new().with_title(title).with_url(url);
+    if !username.is_empty() {
+        entry = entry.with_username(username);
+    }
+    entry = entry.with_password(field(Some(columns.password)));
+    if !field(columns.notes).is_empty() {
+        entry = entry.with_notes(field(columns.notes));
+    }
+    if !field(columns.otp).is_empty() {
+        entry.fields.insert(
+            "otp".to_string(),
+            string_value(&otp_url(title, field(columns.otp)), true),
+        );
+    }
+    if !field(columns.http_realm).is_empty() {
+        insert_unique(
+            &mut entry,
+            "HTTP Realm",
+            string_value(field(columns.http_realm), false),
+        );
+    }
+
+    let created = timestamp(field(columns.created));
+    let changed = timestamp(field(columns.password_changed));
+    if let Some(created) = created {
+        entry.times.set_creation(created);
+    }
+    if let Some(changed) = changed.or(created) {
+        entry.times.set_last_modification(changed);
+    }
+    if let Some(last_used) = timestamp(field(columns.last_used)) {
+        entry.times.set_last_access(last_used);
+    }
+
+    let key = (url.trim_end_matches('/').to_string(), username.to_string());
+    (key, changed.or(created), entry)
+}
+
+/// The host name of a URL without a leading `www.`, e.g. `example.com` for
+/// `https://www.example.com:8080/login`, or the package name of an Android app for
+/// `android://hash@com.example.app/`
+fn host_name(url: &str) -> Option<&str> {
+    let url = UrlComponents::split(url);
+    url.scheme?;
+    let host = url
+        .host
+        .strip_prefix('[')
+        .and_then(|ipv6| ipv6.strip_suffix(']'))
+        .unwrap_or(url.host);
+    let host = host.strip_prefix("www.").unwrap_or(host);
+    Some(host).filter(|h| !h.is_empty())
+}
+
+/// Parse the milliseconds since the Unix epoch that Firefox writes
+fn timestamp(value: &str) -> Option<NaiveDateTime> {
+    let millis = value.trim().parse::<i64>().ok().filter(|&m| m > 0)?;
+    chrono::DateTime::from_timestamp_millis(millis).map(|t| t.naive_utc())
+}
+
+#[cfg(test)]
+mod browser_tests {
+    use chrono::NaiveDate;
+
+    use super::{host_name, import_browser_csv};
+    use crate::{db::Value, import::csv::CsvImportError};
+
+    #[test]
+    fn test_import_chrome() {
+        let data = "name,url,username,password,note\n\
+            mail.example.com,https://www.mail.example.com/login,alice,hunter2,work\n\
+            mail.example.com,https://www.mail.example.com/login/,alice,hunter2,\n\
+            mail.example.com,https://www.mail.example.com/login,bob,secret,\n\
+            com.example.app,android://abc123==@com.example.app/,carol,pw,\n";
+        let db = import_browser_csv(&mut data.as_bytes()).unwrap();
+
+        let entries = db.root.entries();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].get_title(), Some("mail.example.com"));
+        assert_eq!(entries[0].get_url(), Some("https://www.mail.example.com/login"));
+        assert_eq!(entries[0].get_username(), Some("alice"));
+        assert!(matches!(entries[0].fields["Password"], Value::Protected(_)));
+        assert_eq!(entries[0].get("Notes"), Some("work"));
+        assert_eq!(entries[1].get_username(), Some("bob"));
+        assert_eq!(entries[2].get_title(), Some("com.example.app"));
+    }
+
+    #[test]
+    fn test_import_firefox() {
+        let data = "\"url\",\"username\",\"password\",\"httpRealm\",\"formActionOrigin\",\"guid\",\
+            \"timeCreated\",\"timeLastUsed\",\"timePasswordChanged\"\n\
+            \"https://forum.example.com\",\"alice\",\"old\",,\"https://forum.example.com\",\"{1}\",\
+            \"1600000000000\",\"1600000000000\",\"1600000000000\"\n\
+            \"https://forum.example.com\",\"alice\",\"new\",,\"https://forum.example.com\",\"{2}\",\
+            \"1600000000000\",\"1700000000000\",\"1700000000000\"\n\
+            \"https://intranet.example.com:8443\",\"bob\",\"pw\",\"Intranet\",,\"{3}\",\
+            \"1600000000000\",\"1600000000000\",\"1600000000000\"\n";
+        let db = import_browser_csv(&mut data.as_bytes()).unwrap();
+
+        let entries = db.root.entries();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].get_password(), Some("new"));
+        assert_eq!(
+            entries[0].times.get_last_modification(),
+            Some(
+                &NaiveDate::from_ymd_opt(2023, 11, 14)
+                    .unwrap()
+                    .and_hms_opt(22, 13, 20)
+                    .unwrap()
+            )
+        );
+        assert_eq!(entries[1].get_title(), Some("intranet.example.com"));
+        assert_eq!(entries[1].get("HTTP Realm"), Some("Intranet"));
+    }
+
+    #[test]
+    fn test_host_name() {
+        assert_eq!(
+            host_name("https://user:pw@www.example.com:8080/a?b#c"),
+            Some("example.com")
+        );
+        assert_eq!(host_name("http://[::1]:8080/"), Some("::1"));
+        assert_eq!(host_name("example.com"), None);
+        assert_eq!(host_name("file:///etc/passwd"), None);
+    }
+
+    #[test]
+    fn test_missing_column() {
+        assert!(matches!(
+            import_browser_csv(&mut "url,username\nhttps://example.com,alice\n".as_bytes()),
+            Err(CsvImportError::MissingColumn(c)) if c == "password"
+        ));
+    }
+}
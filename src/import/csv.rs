@@ -0,0 +1,417 @@
+//! Import of entries from CSV files
+//!
+//! By default, the columns are detected from the header line. The columns of a KeePassXC export
+//! (see `export::csv::KEEPASSXC_COLUMNS`) are recognized, together with a few common aliases like
+//! `Login` or `Website`. All other columns become custom fields named after their header. Files
+//! from other applications can be imported by mapping the columns explicitly:
+//!
+//! ```
+//! use keepass::import::csv::{import_csv, CsvColumn, CsvImportOptions};
+//!
+//! let data = "Mail;alice;hunter2\n";
+//! let options = CsvImportOptions {
+//!     columns: Some(vec![CsvColumn::Title, CsvColumn::Username, CsvColumn::Password]),
+//!     has_header: false,
+//!     delimiter: ';',
+//! };
+//! let db = import_csv(&mut data.as_bytes(), &options)?;
+//! assert_eq!(db.root.entries()[0].get_protected("Password").unwrap().as_str(), Some("hunter2"));
+//! # Ok::<(), keepass::import::csv::CsvImportError>(())
+//! ```
+//!
+//! Group paths are separated by `/` and the groups are created as needed. If every entry has a
+//! group path starting with the same group, like the `Root/...` paths of KeePassXC, that group
+//! is taken to be the root group.
+
+use std::io::Read;
+
+use chrono::NaiveDateTime;
+use thiserror::Error;
+use zeroize::Zeroizing;
+
+use crate::{
+    db::{Database, Entry, Value},
+    export::csv::CSV_TIME_FORMAT,
+    import::group_at,
+};
+
+/// What a column of a CSV file holds
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CsvColumn {
+    /// The path of the group of the entry, separated by `/`
+    Group,
+    Title,
+    Username,
+    Password,
+    Url,
+    Notes,
+    /// An `otpauth://` URL, stored in the `otp` field
+    Totp,
+    /// The number of a built-in icon
+    Icon,
+    LastModified,
+    Created,
+    /// A custom field with the given name
+    Field(String),
+    /// A column that is not imported
+    Ignore,
+}
+
+impl CsvColumn {
+    /// The column for a header of a CSV file. Unknown headers become custom fields, and empty
+    /// headers are ignored.
+    pub fn from_header(header: &str) -> CsvColumn {
+        let header = header.trim();
+        match header.to_lowercase().as_str() {
+            "" => CsvColumn::Ignore,
+            "group" | "folder" | "path" => CsvColumn::Group,
+            "title" | "name" => CsvColumn::Title,
+            "username" | "user name" | "login" | "login_username" => CsvColumn::Username,
+            "password" | "login_password" => CsvColumn::Password,
+            "url" | "website" | "login_uri" => CsvColumn::Url,
+            "notes" | "comments" => CsvColumn::Notes,
+            "totp" | "otp" | "login_totp" => CsvColumn::Totp,
+            "icon" => CsvColumn::Icon,
+            "last modified" => CsvColumn::LastModified,
+            "created" => CsvColumn::Created,
+            _ => CsvColumn::Field(header.to_string()),
+        }
+    }
+}
+
+/// Settings for importing a CSV file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CsvImportOptions {
+    /// The columns of the file, in order. If not set, the columns are detected from the header
+    /// line, or are those of KeePassXC if there is no header line.
+    pub columns: Option<Vec<CsvColumn>>,
+
+    /// Whether the first line holds the names of the columns rather than an entry
+    pub has_header: bool,
+
+    /// The character separating the fields of a line
+    pub delimiter: char,
+}
+
+impl Default for CsvImportOptions {
+    fn default() -> Self {
+        CsvImportOptions {
+            columns: None,
+            has_header: true,
+            delimiter: ',',
+        }
+    }
+}
+
+/// Errors upon importing a CSV file
+#[derive(Debug, Error)]
+pub enum CsvImportError {
+    /// An I/O error has occurred while reading the file
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    /// The file is not UTF-8 encoded
+    #[error("The CSV file is not valid UTF-8")]
+    InvalidUtf8,
+
+    /// A quoted field is never closed
+    #[error("Unterminated quoted field starting on line {line}")]
+    UnterminatedQuote { line: usize },
+
+    /// A timestamp column holds something that is not a timestamp
+    #[error("Invalid timestamp '{value}' on line {line}")]
+    InvalidTimestamp { line: usize, value: String },
+
+    /// The icon column holds something that is not an icon number
+    #[error("Invalid icon '{value}' on line {line}")]
+    InvalidIcon { line: usize, value: String },
+
+    /// The header line lacks a column that the format of the file requires
+    #[error("The CSV file has no '{0}' column")]
+    MissingColumn(String),
+}
+
+/// A line of a CSV file, which may span several lines of text
+pub(crate) struct Record {
+    pub(crate) line: usize,
+    pub(crate) fields: Vec<Zeroizing<String>>,
+}
+
+/// Import all entries of a CSV file into a new database
+pub fn import_csv(source: &mut dyn Read, options: &CsvImportOptions) -> Result<Database, CsvImportError> {
+    let records = read_records(source, options.delimiter)?;
+    let (header, records) = match records.split_first() {
+        Some((header, rest)) if options.has_header => (Some(header), rest),
+        _ => (None, &records[..]),
+    };
+    let columns = match (&options.columns, header) {
+        (Some(columns), _) => columns.clone(),
+        (None, Some(header)) => header.fields.iter().map(|h| CsvColumn::from_header(h)).collect(),
+        (None, None) => crate::export::csv::KEEPASSXC_COLUMNS
+            .iter()
+            .map(|h| CsvColumn::from_header(h))
+            .collect(),
+    };
+
+    let mut imported = Vec::new();
+    for record in records {
+        imported.push(import_record(record, &columns)?);
+    }
+
+    // strip the root group from the paths if every entry is in it
+    let first_segments: Vec<Option<&str>> = imported.iter().map(|(path, _)| path.first().copied()).collect();
+    let root_name = match first_segments.first() {
+        Some(Some(first)) if first_segments.iter().all(|s| s == &Some(*first)) => Some(first.to_string()),
+        _ => None,
+    };
+
+    let mut db = Database::new(Default::default());
+    if let Some(root_name) = &root_name {
+        db.root.name = root_name.clone();
+    }
+    let skip = usize::from(root_name.is_some());
+    for (path, entry) in imported {
+        group_at(&mut db.root, &path[skip..]).add_child(entry);
+    }
+
+    Ok(db)
+}
+
+fn import_record<'a>(
+    record: &'a Record,
+    columns: &[CsvColumn],
+) -> Result<(Vec<&'a str>, Entry), CsvImportError> {
+    let mut entry = Entry::new();
+    let mut path = Vec::new();
+
+    for (column, value) in columns.iter().zip(&record.fields) {
+        let value: &str = value;
+        match column {
+            CsvColumn::Group => {
+                path = value
+                    .split('/')
+                    .map(str::trim)
+                    .filter(|s| !s.is_empty())
+                    .collect();
+            }
+            CsvColumn::Title => set_field(&mut entry, "Title", value, false),
+            CsvColumn::Username => set_field(&mut entry, "UserName", value, false),
+            CsvColumn::Password => set_field(&mut entry, "Password", value, true),
+            CsvColumn::Url => set_field(&mut entry, "URL", value, false),
+            CsvColumn::Notes => set_field(&mut entry, "Notes", value, false),
+            CsvColumn::Totp if !value.is_empty() => set_field(&mut entry, "otp", value, true),
+            CsvColumn::Field(name) if !value.is_empty() => set_field(&mut entry, name, value, false),
+            CsvColumn::Icon if !value.trim().is_empty() => {
+                let icon = value.trim().parse().map_err(|_| CsvImportError::InvalidIcon {
+                    line: record.line,
+                    value: value.to_string(),
+                })?;
+                entry.icon_id = Some(icon);
+            }
+            CsvColumn::LastModified if !value.trim().is_empty() => {
+                let time = parse_time(value, record.line)?;
+                entry.times.set_last_modification(time);
+            }
+            CsvColumn::Created if !value.trim().is_empty() => {
+                let time = parse_time(value, record.line)?;
+                entry.times.set_creation(time);
+            }
+            _ => {}
+        }
+    }
+
+    Ok((path, entry))
+}
+
+fn set_field(entry: &mut Entry, name: &str, value: &str, protected: bool) {
+    let value = if protected {
+        Value::Protected(value.as_bytes().into())
+    } else {
+        Value::Unprotected(value.to_string())
+    };
+    entry.fields.insert(name.to_string(), value);
+}
+
+/// Parse a timestamp as written by KeePassXC, or in RFC 3339 with any offset
+fn parse_time(value: &str, line: usize) -> Result<NaiveDateTime, CsvImportError> {
+    let value = value.trim();
+    NaiveDateTime::parse_from_str(value, CSV_TIME_FORMAT)
+        .or_else(|_| chrono::DateTime::parse_from_rfc3339(value).map(|t| t.naive_utc()))
+        .or_else(|_| NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S"))
+        .map_err(|_| CsvImportError::InvalidTimestamp {
+            line,
+            value: value.to_string(),
+        })
+}
+
+/// Read all records of a UTF-8 encoded CSV file, which may start with a byte order mark
+pub(crate) fn read_records(source: &mut dyn Read, delimiter: char) -> Result<Vec<Record>, CsvImportError> {
+    let mut data = Zeroizing::new(Vec::new());
+    source.read_to_end(&mut data)?;
+    let data = std::str::from_utf8(&data).map_err(|_| CsvImportError::InvalidUtf8)?;
+    let data = data.strip_prefix('\u{feff}').unwrap_or(data);
+
+    parse_records(data, delimiter)
+}
+
+/// Split CSV data into records as described in RFC 4180, skipping empty lines
+fn parse_records(data: &str, delimiter: char) -> Result<Vec<Record>, CsvImportError> {
+    let mut records = Vec::new();
+    let mut chars = data.chars().peekable();
+    let mut line = 1;
+
+    while chars.peek().is_some() {
+        let start = line;
+        let mut fields = Vec::new();
+        let mut field = Zeroizing::new(String::new());
+
+        loop {
+            // a field is quoted if it starts with a quote
+            if field.is_empty() && chars.peek() == Some(&'"') {
+                chars.next();
+                let quote_line = line;
+                loop {
+                    match chars.next() {
+                        Some('"') if chars.peek() == Some(&'"') => {
+                            chars.next();
+                            field.push('"');
+                        }
+                        Some('"') => break,
+                        Some(c) => {
+                            if c == '\n' {
+                                line += 1;
+                            }
+                            field.push(c);
+                        }
+                        None => return Err(CsvImportError::UnterminatedQuote { line: quote_line }),
+                    }
+                }
+            }
+
+            match chars.next() {
+                Some(c) if c == delimiter => {
+                    fields.push(std::mem::take(&mut field));
+                }
+                Some('\r') if chars.peek() == Some(&'\n') => {}
+                Some('\n') => {
+                    line += 1;
+                    break;
+                }
+                Some(c) => field.push(c),
+                None => break,
+            }
+        }
+
+        fields.push(field);
+        if fields.len() > 1 || !fields[0].is_empty() {
+            records.push(Record { line: start, fields });
+        }
+    }
+
+    Ok(records)
+}
+
+#[cfg(test)]
+mod csv_import_tests {
+    use super::{import_csv, CsvColumn, CsvImportError, CsvImportOptions};
+    use crate::{
+        db::{Entry, Group, Value},
+        export::csv::export_csv,
+        Database,
+    };
+
+    #[test]
+    fn test_roundtrip() {
+        let mut db = Database::new(Default::default());
+        let mut entry = Entry::new()
+            .with_title("GMail")
+            .with_username("alice")
+            .with_password("say \"hi\", bob")
+            .with_notes("first line\r\nsecond line")
+            .with_field(
+                "otp",
+                Value::Protected("otpauth://totp/GMail?secret=JBSWY3DPEHPK3PXP".as_bytes().into()),
+            );
+        entry.icon_id = Some(19);
+        db.root.add_child(Entry::new().with_title("top level"));
+        db.root
+            .add_child(Group::new("Internet").with_child(Group::new("Email").with_child(entry.clone())));
+
+        let mut data = Vec::new();
+        export_csv(&db, &mut data).unwrap();
+        let imported = import_csv(&mut data.as_slice(), &Default::default()).unwrap();
+
+        assert_eq!(imported.root.name, "Root");
+        assert_eq!(imported.root.entries()[0].get_title(), Some("top level"));
+        let copy = imported.root.get(&["Internet", "Email", "GMail"]).unwrap();
+        let copy = match copy {
+            crate::db::NodeRef::Entry(e) => e,
+            _ => panic!("expected an entry"),
+        };
+        assert_eq!(copy.get_password(), Some("say \"hi\", bob"));
+        assert_eq!(copy.get("Notes"), Some("first line\r\nsecond line"));
+        assert_eq!(copy.get_raw_otp_value(), entry.get_raw_otp_value());
+        assert!(matches!(copy.fields["Password"], Value::Protected(_)));
+        assert_eq!(copy.icon_id, Some(19));
+        assert_eq!(copy.times.get_creation(), entry.times.get_creation());
+        assert_eq!(
+            copy.times.get_last_modification(),
+            entry.times.get_last_modification()
+        );
+    }
+
+    #[test]
+    fn test_column_mapping() {
+        // a header with aliases and a custom field, and groups without a common root
+        let data = "\u{feff}Folder,Name,Login,Password,Website,PIN,\r\n\
+                    Bank,My Bank,alice,hunter2,https://bank.example.com,1234,x\r\n\
+                    \r\n\
+                    ,Unfiled,bob,,,,\r\n";
+        let db = import_csv(&mut data.as_bytes(), &Default::default()).unwrap();
+        assert_eq!(db.root.name, "Root");
+        let unfiled = db.root.entries()[0];
+        assert_eq!(unfiled.get_username(), Some("bob"));
+        assert_eq!(unfiled.get("PIN"), None);
+        let bank = db.root.groups()[0];
+        assert_eq!(bank.name, "Bank");
+        let entry = bank.entries()[0];
+        assert_eq!(entry.get_url(), Some("https://bank.example.com"));
+        assert_eq!(entry.get("PIN"), Some("1234"));
+        assert_eq!(entry.fields.len(), 5);
+
+        let options = CsvImportOptions {
+            columns: Some(vec![
+                CsvColumn::Ignore,
+                CsvColumn::Title,
+                CsvColumn::Field("Account".to_string()),
+            ]),
+            has_header: false,
+            delimiter: '\t',
+        };
+        let db = import_csv(&mut "1\tChecking\t\"DE00 1234\"".as_bytes(), &options).unwrap();
+        let entry = db.root.entries()[0];
+        assert_eq!(entry.get_title(), Some("Checking"));
+        assert_eq!(entry.get("Account"), Some("DE00 1234"));
+    }
+
+    #[test]
+    fn test_errors() {
+        let result = import_csv(&mut "Title\nok\n\"broken".as_bytes(), &Default::default());
+        assert!(matches!(
+            result,
+            Err(CsvImportError::UnterminatedQuote { line: 3 })
+        ));
+
+        let result = import_csv(
+            &mut "Title,Created\n\"a\nb\",yesterday".as_bytes(),
+            &Default::default(),
+        );
+        assert!(matches!(
+            result,
+            Err(CsvImportError::InvalidTimestamp { line: 2, .. })
+        ));
+
+        let result = import_csv(&mut "Icon\nkey".as_bytes(), &Default::default());
+        assert!(matches!(result, Err(CsvImportError::InvalidIcon { line: 2, .. })));
+    }
+}
@@ -0,0 +1,269 @@
+//! Import of LastPass CSV exports
+//!
+//! LastPass exports its vault as CSV with the columns `url`, `username`, `password`, `totp`,
+//! `extra`, `name`, `grouping` and `fav`. Folders in the `grouping` column are separated by `\`
+//! and become groups below the root group:
+//!
+//! ```
+//! use keepass::import::lastpass::import_lastpass_csv;
+//!
+//! let data = "url,username,password,totp,extra,name,grouping,fav\n\
+//!             https://mail.example.com,alice,hunter2,,,Mail,Personal\\Email,0\n";
+//! let db = import_lastpass_csv(&mut data.as_bytes())?;
+//! let entry = &db.root.groups()[0].groups()[0].entries()[0];
+//! assert_eq!(entry.get_protected("Password").unwrap().as_str(), Some("hunter2"));
+//! # Ok::<(), keepass::import::csv::CsvImportError>(())
+//! ```
+//!
+//! The `extra` column holds the notes of an entry. Secure notes have the URL `http://sn`, which
+//! is dropped. The notes of structured secure notes like credit cards start with a `NoteType:`
+//! line followed by `Key:Value` lines, which become fields of the entry, with the type of the
+//! note as a tag. The one-time password secret in the `totp` column is stored as an
+//! `otpauth://` URL in the `otp` field. Favorites are tagged as `Favorite`.
+
+use std::io::Read;
+
+use crate::{
+    db::{Database, Entry, Value},
+    import::{
+        csv::{read_records, CsvImportError},
+        group_at, insert_unique, otp_url, string_value,
+    },
+};
+
+/// The URL LastPass gives secure notes
+const SECURE_NOTE_URL: &str = "http://sn";
+
+/// Fields of structured secure notes that are protected in memory
+const PROTECTED_NOTE_FIELDS: [&str; 8] = [
+    "Account Number",
+    "Number",
+    "Passphrase",
+    "Pin",
+    "PIN",
+    "Private Key",
+    "Security Code",
+    "Routing Number",
+];
+
+/// The positions of the columns of a LastPass export in the header line
+struct Columns {
+    url: usize,
+    username: Option<usize>,
+    password: Option<usize>,
+    totp: Option<usize>,
+    extra: Option<usize>,
+    name: usize,
+    grouping: Option<usize>,
+    fav: Option<usize>,
+}
+
+/// Import all entries of a LastPass CSV export into a new database
+pub fn import_lastpass_csv(source: &mut dyn Read) -> Result<Database, CsvImportError> {
+    let records = read_records(source, ',')?;
+    let (header, records) = match records.split_first() {
+        Some(split) => split,
+        None => return Err(CsvImportError::MissingColumn("url".to_string())),
+    };
+
+    let position = |name: &str| header.fields.iter().position(|h| h.trim() == name);
+    let required = |name: &str| position(name).ok_or_else(|| CsvImportError::MissingColumn(name.to_string()));
+    let columns = Columns {
+        url: required("url")?,
+        username: position("username"),
+        password: position("password"),
+        totp: position("totp"),
+        extra: position("extra"),
+        name: required("name")?,
+        grouping: position("grouping"),
+        fav: position("fav"),
+    };
+
+    let mut db = Database::new(Default::default());
+    for record in records {
+        let field = |index: Option<usize>| -> &str {
+            index
+                .and_then(|i| record.fields.get(i))
+                .map(|f| f.as_str())
+                .unwrap_or_default()
+        };
+
+        let path: Vec<&str> = field(columns.grouping)
+            .split('\\')
+            .map(str::trim)
+            .filter(|s| !s.is_empty() && *s != "(none)")
+            .collect();
+
+        let entry = import_record(
+            field(Some(columns.name)),
+            field(Some(columns.url)),
+            field(columns.username),
+            field(columns.password),
+            field(columns.totp),
+            field(columns.extra),
+            field(columns.fav) == "1",
+        );
+        group_at(&mut db.root, &path).add_child(entry);
+    }
+
+    Ok(db)
+}
+
+fn import_record(
+    name: &str,
+    url: &str,
+    username: &str,
+    password: &str,
+    totp: &str,
+    extra: &str,
+    favorite: bool,
+) -> Entry {
+    let mut entry = Entry::new().with_title(name);
+
+    if !username.is_empty() {
+        entry = entry.with_username(username);
+    }
+    if !password.is_empty() {
+        entry = entry.with_password(password);
+    }
+    if !url.is_empty() && url != SECURE_NOTE_URL {
+        entry = entry.with_url(url);
+    }
+    if !totp.is_empty() {
+        entry
+            .fields
+            .insert("otp".to_string(), string_value(&otp_url(name, totp), true));
+    }
+
+    match extra.strip_prefix("NoteType:") {
+        Some(note) if url == SECURE_NOTE_URL => import_structured_note(&mut entry, note),
+        _ if !extra.is_empty() => entry = entry.with_notes(extra),
+        _ => {}
+    }
+
+    if favorite {
+        entry = entry.with_tag("Favorite");
+    }
+
+    entry
+}
+
+/// Store the `Key:Value` lines of a structured secure note in fields of the entry. `note` starts
+/// with the type of the note, and everything after a `Notes:` line are free-form notes.
+fn import_structured_note(entry: &mut Entry, note: &str) {
+    let (note_type, mut rest) = note.split_once('\n').unwrap_or((note, ""));
+    let note_type = note_type.trim_end_matches('\r').trim();
+    if !note_type.is_empty() {
+        entry.tags.push(note_type.to_string());
+    }
+
+    while !rest.is_empty() {
+        let (line, next) = rest.split_once('\n').unwrap_or((rest, ""));
+        if let Some(notes) = rest.strip_prefix("Notes:") {
+            if !notes.is_empty() {
+                entry
+                    .fields
+                    .insert("Notes".to_string(), Value::Unprotected(notes.to_string()));
+            }
+            break;
+        }
+        rest = next;
+
+        // empty dates are written as a lone comma between month and year
+        let (key, value) = match line.trim_end_matches('\r').split_once(':') {
+            Some((key, value)) if !value.trim_matches(',').is_empty() => (key, value),
+            _ => continue,
+        };
+        match key {
+            "Language" => {}
+            "Username" if entry.get_username().is_none() => {
+                entry
+                    .fields
+                    .insert("UserName".to_string(), string_value(value, false));
+            }
+            "Password" if !entry.fields.contains_key("Password") => {
+                entry
+                    .fields
+                    .insert("Password".to_string(), string_value(value, true));
+            }
+            _ => insert_unique(
+                entry,
+                key,
+                string_value(value, PROTECTED_NOTE_FIELDS.contains(&key)),
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod lastpass_tests {
+    use super::import_lastpass_csv;
+    use crate::{
+        db::{NodeRef, Value},
+        import::csv::CsvImportError,
+    };
+
+    #[test]
+    fn test_import_lastpass_csv() {
+        let data = "url,username,password,totp,extra,name,grouping,fav\r\n\
+            https://mail.example.com,alice,hunter2,JBSWY3DPEHPK3PXP,\"two\nlines\",Mail,Personal\\Email,1\r\n\
+            http://sn,,,,just a note,Note,,0\r\n\
+            http://sn,,,,\"NoteType:Credit Card\nLanguage:en-US\nName on Card:Alice\nType:Visa\n\
+            Number:4111111111111111\nSecurity Code:123\nStart Date:,\nExpiration Date:March,2027\n\
+            Notes:keep it\nsafe\",Visa,Personal,0\r\n\
+            http://sn,,,,\"NoteType:Server\nHostname:example.com\nUsername:root\nPassword:toor\nNotes:\",\
+            Server,(none),0\r\n";
+        let db = import_lastpass_csv(&mut data.as_bytes()).unwrap();
+
+        let mail = match db.root.get(&["Personal", "Email", "Mail"]) {
+            Some(NodeRef::Entry(e)) => e,
+            _ => panic!("expected the Mail entry"),
+        };
+        assert_eq!(mail.get_username(), Some("alice"));
+        assert_eq!(mail.get_password(), Some("hunter2"));
+        assert_eq!(mail.get_url(), Some("https://mail.example.com"));
+        assert_eq!(mail.get("Notes"), Some("two\nlines"));
+        assert_eq!(
+            mail.get_raw_otp_value(),
+            Some("otpauth://totp/Mail?secret=JBSWY3DPEHPK3PXP")
+        );
+        assert_eq!(mail.tags, vec!["Favorite"]);
+
+        let note = match db.root.get(&["Note"]) {
+            Some(NodeRef::Entry(e)) => e,
+            _ => panic!("expected the Note entry"),
+        };
+        assert_eq!(note.get_url(), None);
+        assert_eq!(note.get("Notes"), Some("just a note"));
+
+        let card = match db.root.get(&["Personal", "Visa"]) {
+            Some(NodeRef::Entry(e)) => e,
+            _ => panic!("expected the Visa entry"),
+        };
+        assert_eq!(card.tags, vec!["Credit Card"]);
+        assert_eq!(card.get("Name on Card"), Some("Alice"));
+        assert!(matches!(card.fields["Number"], Value::Protected(_)));
+        assert_eq!(card.get("Security Code"), Some("123"));
+        assert_eq!(card.get("Start Date"), None);
+        assert_eq!(card.get("Expiration Date"), Some("March,2027"));
+        assert_eq!(card.get("Notes"), Some("keep it\nsafe"));
+        assert_eq!(card.get("Language"), None);
+
+        let server = match db.root.get(&["Server"]) {
+            Some(NodeRef::Entry(e)) => e,
+            _ => panic!("expected the Server entry"),
+        };
+        assert_eq!(server.get_username(), Some("root"));
+        assert_eq!(server.get_password(), Some("toor"));
+        assert_eq!(server.get("Hostname"), Some("example.com"));
+        assert_eq!(server.get("Notes"), None);
+    }
+
+    #[test]
+    fn test_missing_column() {
+        assert!(matches!(
+            import_lastpass_csv(&mut "Title,Username\nMail,alice\n".as_bytes()),
+            Err(CsvImportError::MissingColumn(c)) if c == "url"
+        ));
+    }
+}
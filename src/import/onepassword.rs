@@ -0,0 +1,412 @@
+//! Import of 1Password exports in the 1PUX format
+//!
+//! A 1PUX file is a ZIP archive holding the exported items as JSON in `export.data` and the
+//! attached files in `files/`. Every vault becomes a group below the root group, and every item an
+//! entry in the group of its vault. Archived items are put in an `Archive` group inside their
+//! vault's group:
+//!
+//! ```no_run
+//! use keepass::{import::onepassword::import_1pux, DatabaseKey};
+//!
+//! let db = import_1pux(&mut std::fs::File::open("export.1pux")?)?;
+//! # #[cfg(feature = "save_kdbx4")]
+//! db.save(
+//!     &mut std::fs::File::create("imported.kdbx")?,
+//!     DatabaseKey::new().with_password("demopass"),
+//! )?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+//!
+//! The title, URL, notes and tags of an item are mapped to the entry, as are the user name and
+//! password of logins. Further URLs are stored in `KP2A_URL`, `KP2A_URL_1`, ... fields, which
+//! KeePassXC and KeePass2Android match as well. Other fields of an item become custom fields named
+//! after their title, protected if they are concealed in 1Password. The first one-time password of
+//! an item is stored as an `otpauth://` URL in the `otp` field. Attached files and documents
+//! become attachments of the entry.
+
+use std::io::Read;
+
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::{
+    db::{attach::add_header_attachment, AttachmentRef, Database, Entry, Group, HeaderAttachment},
+    import::{insert_unique, otp_url, set_urls, string_value, zip::ZipArchive},
+};
+
+/// Errors upon importing a 1PUX file
+#[derive(Debug, Error)]
+pub enum OnePasswordImportError {
+    /// An I/O error has occurred while reading the file, or it is not a valid ZIP archive
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    /// The archive does not contain the exported items
+    #[error("The archive does not contain export.data")]
+    MissingExportData,
+
+    /// The exported items could not be read
+    #[error("Invalid export data: {0}")]
+    Json(#[from] serde_json::Error),
+
+    /// A file attached to an item is not in the archive
+    #[error("The attached file {0} is missing from the archive")]
+    MissingFile(String),
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Export {
+    accounts: Vec<Account>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Account {
+    vaults: Vec<Vault>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Vault {
+    attrs: VaultAttributes,
+    items: Vec<Item>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct VaultAttributes {
+    name: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct Item {
+    created_at: i64,
+    updated_at: i64,
+    state: String,
+    details: ItemDetails,
+    overview: ItemOverview,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct ItemDetails {
+    login_fields: Vec<LoginField>,
+    notes_plain: Option<String>,
+    sections: Vec<Section>,
+    document_attributes: Option<FileAttributes>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct LoginField {
+    value: String,
+    name: String,
+    field_type: String,
+    designation: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Section {
+    fields: Vec<SectionField>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct SectionField {
+    title: String,
+    id: String,
+    /// An object with a single key naming the kind of the value, e.g. `{"concealed": "..."}`
+    value: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct FileAttributes {
+    file_name: String,
+    document_id: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct ItemOverview {
+    title: String,
+    url: String,
+    urls: Vec<ItemUrl>,
+    tags: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct ItemUrl {
+    url: String,
+}
+
+/// Import all vaults of a 1PUX file into a new database
+pub fn import_1pux(source: &mut dyn Read) -> Result<Database, OnePasswordImportError> {
+    let mut data = Vec::new();
+    source.read_to_end(&mut data)?;
+    let archive = ZipArchive::new(data)?;
+
+    let export_data = zeroize::Zeroizing::new(
+        archive
+            .read("export.data")?
+            .ok_or(OnePasswordImportError::MissingExportData)?,
+    );
+    let export: Export = serde_json::from_slice(&export_data)?;
+
+    let mut db = Database::new(Default::default());
+    for vault in export.accounts.iter().flat_map(|a| &a.vaults) {
+        let mut group = Group::new(&vault.attrs.name);
+        let mut archive_group = Group::new("Archive");
+
+        for item in &vault.items {
+            let entry = import_item(item, &archive, &mut db.header_attachments)?;
+            if item.state == "archived" {
+                archive_group.add_child(entry);
+            } else {
+                group.add_child(entry);
+            }
+        }
+
+        if !archive_group.children.is_empty() {
+            group.add_child(archive_group);
+        }
+        db.root.add_child(group);
+    }
+
+    Ok(db)
+}
+
+fn import_item(
+    item: &Item,
+    archive: &ZipArchive,
+    header_attachments: &mut Vec<HeaderAttachment>,
+) -> Result<Entry, OnePasswordImportError> {
+    let overview = &item.overview;
+    let mut entry = Entry::new().with_title(&overview.title);
+    entry.tags = overview.tags.clone();
+
+    set_urls(
+        &mut entry,
+        std::iter::once(overview.url.as_str()).chain(overview.urls.iter().map(|u| u.url.as_str())),
+    );
+
+    if let Some(notes) = item.details.notes_plain.as_deref().filter(|n| !n.is_empty()) {
+        entry = entry.with_notes(notes);
+    }
+
+    for field in &item.details.login_fields {
+        match field.designation.as_str() {
+            "username" => entry = entry.with_username(&field.value),
+            "password" => entry = entry.with_password(&field.value),
+            _ if field.value.is_empty() => {}
+            _ => {
+                let name = if field.name.is_empty() {
+                    "Field"
+                } else {
+                    &field.name
+                };
+                insert_unique(
+                    &mut entry,
+                    name,
+                    string_value(&field.value, field.field_type == "P"),
+                );
+            }
+        }
+    }
+
+    for field in item.details.sections.iter().flat_map(|s| &s.fields) {
+        import_section_field(&mut entry, field, archive, header_attachments)?;
+    }
+
+    if let Some(document) = &item.details.document_attributes {
+        attach_file(&mut entry, document, archive, header_attachments)?;
+    }
+
+    if let Some(created) = timestamp(item.created_at) {
+        entry.times.set_creation(created);
+    }
+    if let Some(updated) = timestamp(item.updated_at) {
+        entry.times.set_last_modification(updated);
+    }
+
+    Ok(entry)
+}
+
+fn import_section_field(
+    entry: &mut Entry,
+    field: &SectionField,
+    archive: &ZipArchive,
+    header_attachments: &mut Vec<HeaderAttachment>,
+) -> Result<(), OnePasswordImportError> {
+    let name = if field.title.is_empty() {
+        &field.id
+    } else {
+        &field.title
+    };
+    let name = if name.is_empty() { "Field" } else { name };
+
+    let (kind, value) = match field.value.iter().next() {
+        Some(kind_value) => kind_value,
+        None => return Ok(()),
+    };
+
+    let value = match (kind.as_str(), value) {
+        ("file", file) => {
+            let file = FileAttributes::deserialize(file)?;
+            return attach_file(entry, &file, archive, header_attachments);
+        }
+        ("totp", serde_json::Value::String(totp)) if !totp.is_empty() => {
+            let url = otp_url(entry.get_title().unwrap_or_default(), totp);
+            if !entry.fields.contains_key("otp") {
+                entry.fields.insert("otp".to_string(), string_value(&url, true));
+                return Ok(());
+            }
+            string_value(&url, true)
+        }
+        ("concealed" | "creditCardNumber", serde_json::Value::String(s)) => string_value(s, true),
+        ("date", serde_json::Value::Number(n)) => match n.as_i64().and_then(timestamp) {
+            Some(date) => string_value(&date.format("%Y-%m-%d").to_string(), false),
+            None => return Ok(()),
+        },
+        ("monthYear", serde_json::Value::Number(n)) => match n.as_u64() {
+            Some(n) => string_value(&format!("{:02}/{}", n % 100, n / 100), false),
+            None => return Ok(()),
+        },
+        (_, serde_json::Value::String(s)) => string_value(s, false),
+        (_, serde_json::Value::Number(n)) => string_value(&n.to_string(), false),
+        (_, serde_json::Value::Bool(b)) => string_value(&b.to_string(), false),
+        (_, serde_json::Value::Object(parts)) => {
+            // e.g. addresses or email addresses, joined line by line
+            let lines: Vec<&str> = parts
+                .values()
+                .filter_map(|v| v.as_str())
+                .filter(|s| !s.is_empty())
+                .collect();
+            string_value(&lines.join("\n"), false)
+        }
+        _ => return Ok(()),
+    };
+
+    if !value.is_empty() {
+        insert_unique(entry, name, value);
+    }
+    Ok(())
+}
+
+/// Attach a file of the archive to the entry. Files are stored as `files/<id>__<name>`.
+fn attach_file(
+    entry: &mut Entry,
+    file: &FileAttributes,
+    archive: &ZipArchive,
+    header_attachments: &mut Vec<HeaderAttachment>,
+) -> Result<(), OnePasswordImportError> {
+    let prefix = format!("files/{}", file.document_id);
+    let path = format!("{}__{}", prefix, file.file_name);
+    let path = if archive.names().any(|n| n == path) {
+        path
+    } else {
+        archive
+            .names()
+            .find(|n| n.starts_with(&prefix) && !n.ends_with('/'))
+            .map(str::to_string)
+            .ok_or_else(|| OnePasswordImportError::MissingFile(file.file_name.clone()))?
+    };
+
+    let content = archive
+        .read(&path)?
+        .ok_or_else(|| OnePasswordImportError::MissingFile(file.file_name.clone()))?;
+    let identifier = add_header_attachment(header_attachments, content, false);
+
+    let mut name = file.file_name.clone();
+    let mut n = 1;
+    while entry.attachments.iter().any(|a| a.name == name) {
+        n += 1;
+        name = format!("{} ({})", file.file_name, n);
+    }
+    entry.attachments.push(AttachmentRef { name, identifier });
+    Ok(())
+}
+
+fn timestamp(seconds: i64) -> Option<chrono::NaiveDateTime> {
+    if seconds <= 0 {
+        return None;
+    }
+    chrono::DateTime::from_timestamp(seconds, 0).map(|t| t.naive_utc())
+}
+
+#[cfg(test)]
+mod onepassword_tests {
+    use chrono::NaiveDate;
+
+    use super::{import_1pux, OnePasswordImportError};
+    use crate::db::{NodeRef, Value};
+
+    #[test]
+    fn test_import_1pux() {
+        let db = import_1pux(&mut std::fs::File::open("tests/resources/test_export.1pux").unwrap()).unwrap();
+
+        let vaults: Vec<&str> = db.root.groups().iter().map(|g| g.name.as_str()).collect();
+        assert_eq!(vaults, vec!["Private", "Shared"]);
+
+        let mail = match db.root.get(&["Private", "Mail"]) {
+            Some(NodeRef::Entry(e)) => e,
+            _ => panic!("expected the Mail entry"),
+        };
+        assert_eq!(mail.get_username(), Some("alice"));
+        assert_eq!(mail.get_password(), Some("hunter2"));
+        assert_eq!(mail.get_url(), Some("https://mail.example.com"));
+        assert_eq!(mail.get("KP2A_URL"), Some("https://webmail.example.com"));
+        assert_eq!(mail.get("Notes"), Some("Main mail account"));
+        assert_eq!(mail.tags, vec!["work", "email"]);
+        assert!(matches!(mail.fields["pin"], Value::Protected(_)));
+        assert_eq!(mail.get("pin"), Some("1234"));
+        assert_eq!(mail.get("pin (2)"), Some("5678"));
+        assert!(matches!(mail.fields["recovery code"], Value::Protected(_)));
+        assert_eq!(mail.get("expires"), Some("2024-01-01"));
+        assert_eq!(
+            mail.get_raw_otp_value(),
+            Some("otpauth://totp/Mail?secret=JBSWY3DPEHPK3PXP")
+        );
+        assert_eq!(
+            mail.times.get_creation(),
+            Some(
+                &NaiveDate::from_ymd_opt(2021, 2, 26)
+                    .unwrap()
+                    .and_hms_opt(0, 22, 36)
+                    .unwrap()
+            )
+        );
+
+        assert_eq!(mail.attachments.len(), 1);
+        assert_eq!(mail.attachments[0].name, "codes.txt");
+        let content = db.header_attachments[mail.attachments[0].identifier]
+            .data()
+            .unwrap();
+        assert_eq!(content.as_ref(), b"code1 code2");
+
+        let note = match db.root.get(&["Private", "Archive", "Old note"]) {
+            Some(NodeRef::Entry(e)) => e,
+            _ => panic!("expected the archived note"),
+        };
+        assert_eq!(note.get("Notes"), Some("old note"));
+
+        let contract = match db.root.get(&["Shared", "Contract"]) {
+            Some(NodeRef::Entry(e)) => e,
+            _ => panic!("expected the document"),
+        };
+        assert_eq!(contract.attachments[0].name, "contract.pdf");
+        let content = db.header_attachments[contract.attachments[0].identifier]
+            .data()
+            .unwrap();
+        assert_eq!(content.as_ref(), b"%PDF-1.4\n");
+
+        assert!(matches!(
+            import_1pux(&mut b"not a zip archive at all".as_slice()),
+            Err(OnePasswordImportError::Io(_))
+        ));
+    }
+}
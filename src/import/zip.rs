@@ -0,0 +1,186 @@
+//! Minimal reader and writer of ZIP archives, as used by the export formats of other password
+//! managers and by KeeShare containers
+//!
+//! Only what these need is supported: stored and deflated files listed in the central directory.
+//! ZIP64, encryption and multi-part archives are not. Archives are written with stored files only.
+
+use std::io::{Error, ErrorKind, Read};
+
+use byteorder::{ByteOrder, LittleEndian};
+use flate2::read::DeflateDecoder;
+
+const END_OF_CENTRAL_DIRECTORY: u32 = 0x0605_4b50;
+const CENTRAL_DIRECTORY_HEADER: u32 = 0x0201_4b50;
+const LOCAL_FILE_HEADER: u32 = 0x0403_4b50;
+
+const END_OF_CENTRAL_DIRECTORY_SIZE: usize = 22;
+const CENTRAL_DIRECTORY_HEADER_SIZE: usize = 46;
+const LOCAL_FILE_HEADER_SIZE: usize = 30;
+
+const METHOD_STORED: u16 = 0;
+const METHOD_DEFLATED: u16 = 8;
+
+/// A file in a ZIP archive
+struct ZipFile {
+    name: String,
+    method: u16,
+    compressed_size: usize,
+    uncompressed_size: usize,
+    local_header_offset: usize,
+}
+
+/// A ZIP archive held in memory
+pub(crate) struct ZipArchive {
+    data: Vec<u8>,
+    files: Vec<ZipFile>,
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("Invalid ZIP archive: {}", message),
+    )
+}
+
+/// `len` bytes of `data` at `offset`, or an error if the archive is too short
+fn slice(data: &[u8], offset: usize, len: usize) -> Result<&[u8], Error> {
+    offset
+        .checked_add(len)
+        .and_then(|end| data.get(offset..end))
+        .ok_or_else(|| invalid("unexpected end of data"))
+}
+
+impl ZipArchive {
+    /// Read the directory of an archive
+    pub(crate) fn new(data: Vec<u8>) -> Result<ZipArchive, Error> {
+        if data.len() < END_OF_CENTRAL_DIRECTORY_SIZE {
+            return Err(invalid("too short"));
+        }
+
+        // the end of central directory record is followed by a comment of up to 64 KiB
+        let last = data.len() - END_OF_CENTRAL_DIRECTORY_SIZE;
+        let end = (last.saturating_sub(u16::MAX as usize)..=last)
+            .rev()
+            .find(|&i| LittleEndian::read_u32(&data[i..]) == END_OF_CENTRAL_DIRECTORY)
+            .ok_or_else(|| invalid("no end of central directory"))?;
+
+        let record = slice(&data, end, END_OF_CENTRAL_DIRECTORY_SIZE)?;
+        let count = LittleEndian::read_u16(&record[10..]) as usize;
+        let mut offset = LittleEndian::read_u32(&record[16..]) as usize;
+
+        let mut files = Vec::with_capacity(count);
+        for _ in 0..count {
+            let header = slice(&data, offset, CENTRAL_DIRECTORY_HEADER_SIZE)?;
+            if LittleEndian::read_u32(header) != CENTRAL_DIRECTORY_HEADER {
+                return Err(invalid("bad central directory header"));
+            }
+            let name_len = LittleEndian::read_u16(&header[28..]) as usize;
+            let extra_len = LittleEndian::read_u16(&header[30..]) as usize;
+            let comment_len = LittleEndian::read_u16(&header[32..]) as usize;
+            let name = slice(&data, offset + CENTRAL_DIRECTORY_HEADER_SIZE, name_len)?;
+
+            files.push(ZipFile {
+                name: String::from_utf8_lossy(name).into_owned(),
+                method: LittleEndian::read_u16(&header[10..]),
+                compressed_size: LittleEndian::read_u32(&header[20..]) as usize,
+                uncompressed_size: LittleEndian::read_u32(&header[24..]) as usize,
+                local_header_offset: LittleEndian::read_u32(&header[42..]) as usize,
+            });
+
+            offset += CENTRAL_DIRECTORY_HEADER_SIZE + name_len + extra_len + comment_len;
+        }
+
+        Ok(ZipArchive { data, files })
+    }
+
+    /// The names of all files in the archive, including directories
+    #[cfg(feature = "onepassword")]
+    pub(crate) fn names(&self) -> impl Iterator<Item = &str> {
+        self.files.iter().map(|f| f.name.as_str())
+    }
+
+    /// The content of the file with the given name, if it is in the archive
+    pub(crate) fn read(&self, name: &str) -> Result<Option<Vec<u8>>, Error> {
+        let file = match self.files.iter().find(|f| f.name == name) {
+            Some(file) => file,
+            None => return Ok(None),
+        };
+
+        let header = slice(&self.data, file.local_header_offset, LOCAL_FILE_HEADER_SIZE)?;
+        if LittleEndian::read_u32(header) != LOCAL_FILE_HEADER {
+            return Err(invalid("bad local file header"));
+        }
+        let name_len = LittleEndian::read_u16(&header[26..]) as usize;
+        let extra_len = LittleEndian::read_u16(&header[28..]) as usize;
+        let start = file.local_header_offset + LOCAL_FILE_HEADER_SIZE + name_len + extra_len;
+        let compressed = slice(&self.data, start, file.compressed_size)?;
+
+        let content = match file.method {
+            METHOD_STORED => compressed.to_vec(),
+            METHOD_DEFLATED => {
+                let mut content = Vec::with_capacity(file.uncompressed_size);
+                DeflateDecoder::new(compressed).read_to_end(&mut content)?;
+                content
+            }
+            method => {
+                return Err(invalid(&format!(
+                    "unsupported compression method {} of {}",
+                    method, file.name
+                )))
+            }
+        };
+
+        if content.len() != file.uncompressed_size {
+            return Err(invalid(&format!("wrong size of {}", file.name)));
+        }
+        Ok(Some(content))
+    }
+}
+
+/// Write an archive holding `files`, given as names and contents, without compressing them
+#[cfg(feature = "keeshare")]
+pub(crate) fn write_stored(files: &[(&str, &[u8])]) -> Vec<u8> {
+    // 1980-01-01, the earliest date a ZIP archive can hold
+    const DOS_DATE: u16 = 0x21;
+    const VERSION: u16 = 20;
+
+    let mut out = Vec::new();
+    let mut directory = Vec::new();
+    for (name, content) in files {
+        let mut crc = flate2::Crc::new();
+        crc.update(content);
+
+        let mut header = [0u8; LOCAL_FILE_HEADER_SIZE];
+        LittleEndian::write_u32(&mut header[0..], LOCAL_FILE_HEADER);
+        LittleEndian::write_u16(&mut header[4..], VERSION);
+        LittleEndian::write_u16(&mut header[8..], METHOD_STORED);
+        LittleEndian::write_u16(&mut header[12..], DOS_DATE);
+        LittleEndian::write_u32(&mut header[14..], crc.sum());
+        LittleEndian::write_u32(&mut header[18..], content.len() as u32);
+        LittleEndian::write_u32(&mut header[22..], content.len() as u32);
+        LittleEndian::write_u16(&mut header[26..], name.len() as u16);
+
+        let mut entry = [0u8; CENTRAL_DIRECTORY_HEADER_SIZE];
+        LittleEndian::write_u32(&mut entry[0..], CENTRAL_DIRECTORY_HEADER);
+        LittleEndian::write_u16(&mut entry[4..], VERSION);
+        entry[6..32].copy_from_slice(&header[4..30]);
+        LittleEndian::write_u32(&mut entry[42..], out.len() as u32);
+        directory.extend_from_slice(&entry);
+        directory.extend_from_slice(name.as_bytes());
+
+        out.extend_from_slice(&header);
+        out.extend_from_slice(name.as_bytes());
+        out.extend_from_slice(content);
+    }
+
+    let mut end = [0u8; END_OF_CENTRAL_DIRECTORY_SIZE];
+    LittleEndian::write_u32(&mut end[0..], END_OF_CENTRAL_DIRECTORY);
+    LittleEndian::write_u16(&mut end[8..], files.len() as u16);
+    LittleEndian::write_u16(&mut end[10..], files.len() as u16);
+    LittleEndian::write_u32(&mut end[12..], directory.len() as u32);
+    LittleEndian::write_u32(&mut end[16..], out.len() as u32);
+
+    out.extend_from_slice(&directory);
+    out.extend_from_slice(&end);
+    out
+}
@@ -1,6 +1,7 @@
 use std::io::Write;
 
 use byteorder::{LittleEndian, WriteBytesExt};
+use zeroize::Zeroize;
 
 /// Extension trait to write a length-tagged field
 pub trait WriteLengthTaggedExt: Write {
@@ -12,3 +13,57 @@ pub trait WriteLengthTaggedExt: Write {
 }
 
 impl<W: Write + ?Sized> WriteLengthTaggedExt for W {}
+
+/// An in-memory buffer for plaintext, e.g. the XML of a database before it is encrypted. Unlike a
+/// `Vec`, the buffer wipes its old allocation when it grows, and its contents when it is dropped.
+#[derive(Default)]
+pub struct ZeroizingBuffer {
+    data: Vec<u8>,
+}
+
+impl ZeroizingBuffer {
+    pub fn as_slice(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl Write for ZeroizingBuffer {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, std::io::Error> {
+        let needed = self.data.len() + buf.len();
+        if needed > self.data.capacity() {
+            let mut grown = Vec::with_capacity(needed.max(self.data.capacity() * 2).max(4096));
+            grown.extend_from_slice(&self.data);
+            self.data.zeroize();
+            self.data = grown;
+        }
+        self.data.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+}
+
+impl Drop for ZeroizingBuffer {
+    fn drop(&mut self) {
+        self.data.zeroize();
+    }
+}
+
+#[cfg(test)]
+mod io_tests {
+    use std::io::Write;
+
+    use super::ZeroizingBuffer;
+
+    #[test]
+    fn test_zeroizing_buffer() {
+        let mut buffer = ZeroizingBuffer::default();
+        for i in 0..5000u32 {
+            buffer.write_all(&i.to_le_bytes()).unwrap();
+        }
+        assert_eq!(buffer.as_slice().len(), 20000);
+        assert_eq!(&buffer.as_slice()[4..8], &1u32.to_le_bytes());
+    }
+}
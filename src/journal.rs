@@ -0,0 +1,482 @@
+//! Write-ahead journal for crash-safe saves
+//!
+//! Writing a temporary file and renaming it over the database protects against torn database
+//! files, but changes made since the last save are still lost when the process crashes or the
+//! filesystem goes away before the next save. A `Journal` records every batch of commands in a
+//! file next to the database before applying it, and `Journal::open` replays the recorded commands
+//! that did not make it into the database file.
+//!
+//! The journal is encrypted and authenticated with keys derived from the database key with the KDF
+//! of the database and a random seed, which is replaced whenever the database is saved. It is bound
+//! to the exact database file it extends by its SHA-256 hash, which every record is authenticated
+//! with as well, so that a journal left behind by a crash after a successful save is discarded
+//! instead of replayed twice, and records cannot be moved from one journal to another.
+//!
+//! ```no_run
+//! use std::collections::BTreeMap;
+//!
+//! use keepass::{commands::Command, journal::Journal, DatabaseKey};
+//!
+//! let key = DatabaseKey::new().with_password("correct horse battery staple");
+//!
+//! // replays the changes recorded before a crash, if any
+//! let (mut db, mut journal) = Journal::open("my_database.kdbx", key)?;
+//!
+//! let root = db.root.uuid;
+//! journal.record(
+//!     &mut db,
+//!     &[Command::AddEntry {
+//!         parent: root,
+//!         uuid: uuid::Uuid::new_v4(),
+//!         fields: BTreeMap::new(),
+//!     }],
+//! )?;
+//!
+//! // writes the database and starts an empty journal
+//! journal.save(&db)?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::{
+    convert::TryInto,
+    fs::{File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use thiserror::Error;
+use zeroize::Zeroizing;
+
+use crate::{
+    commands::{dry_run_all, Command, CommandError},
+    crypt::{
+        calculate_hmac, calculate_sha256, calculate_sha512,
+        ciphers::{AES256Cipher, Cipher},
+        verify_hmac,
+    },
+    db::{reveal_protected_values, SaveOptions},
+    error::{CryptographyError, DatabaseKeyError, DatabaseOpenError, DatabaseSaveError},
+    Database, DatabaseKey,
+};
+
+const MAGIC: &[u8; 8] = b"KPRSJNL1";
+const HEADER_SIZE: usize = 8 + 32 + 32 + 32;
+const SEED_SIZE: usize = 32;
+const IV_SIZE: usize = 16;
+const MAC_SIZE: usize = 32;
+
+/// Errors while recording, replaying or checkpointing a journal
+#[derive(Debug, Error)]
+pub enum JournalError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error(transparent)]
+    Open(#[from] DatabaseOpenError),
+
+    #[error(transparent)]
+    Save(#[from] DatabaseSaveError),
+
+    #[error(transparent)]
+    Key(#[from] DatabaseKeyError),
+
+    #[error(transparent)]
+    Cryptography(#[from] CryptographyError),
+
+    #[error(transparent)]
+    Random(#[from] getrandom::Error),
+
+    #[error(transparent)]
+    Serialization(#[from] serde_json::Error),
+
+    /// A recorded command could not be applied, either when recording or when replaying it
+    #[error(transparent)]
+    Command(#[from] CommandError),
+
+    /// The journal was not written with this key, or was modified
+    #[error("The journal is corrupted or was written with another key")]
+    Corrupted,
+}
+
+/// The path of the journal of the database at `path`
+pub fn journal_path(path: impl AsRef<Path>) -> PathBuf {
+    let mut journal = path.as_ref().as_os_str().to_owned();
+    journal.push(".journal");
+    PathBuf::from(journal)
+}
+
+struct JournalKeys {
+    encryption: Zeroizing<Vec<u8>>,
+    authentication: Zeroizing<Vec<u8>>,
+
+    /// The SHA-256 hash of the database file the journal extends
+    base: [u8; 32],
+}
+
+impl JournalKeys {
+    fn derive(db: &Database, key: &DatabaseKey, seed: &[u8], base: [u8; 32]) -> Result<Self, JournalError> {
+        #[cfg(feature = "challenge_response")]
+        let key = key.clone().perform_challenge(seed)?;
+
+        let composite_key = key.composite_key()?;
+        let transformed_key = db
+            .config
+            .kdf_config
+            .get_kdf_seeded(seed, &db.config.argon2_secret_parameters)
+            .transform_key_cached(&composite_key)?;
+
+        Ok(JournalKeys {
+            encryption: Zeroizing::new(
+                calculate_sha256(&[seed, &transformed_key, b"journal encryption"])?.to_vec(),
+            ),
+            authentication: Zeroizing::new(
+                calculate_sha512(&[seed, &transformed_key, b"journal authentication"])?.to_vec(),
+            ),
+            base,
+        })
+    }
+
+    fn header(&self, seed: &[u8]) -> Result<Vec<u8>, JournalError> {
+        let mut header = Vec::with_capacity(HEADER_SIZE);
+        header.extend_from_slice(MAGIC);
+        header.extend_from_slice(&self.base);
+        header.extend_from_slice(seed);
+        let mac = calculate_hmac(&[&header], &self.authentication)?;
+        header.extend_from_slice(&mac);
+        Ok(header)
+    }
+
+    fn verify_header(&self, header: &[u8]) -> Result<bool, JournalError> {
+        let (header, mac) = header.split_at(HEADER_SIZE - MAC_SIZE);
+        Ok(verify_hmac(&[header], &self.authentication, mac)?)
+    }
+
+    fn record_mac(&self, index: u64, data: &[u8]) -> Result<Vec<u8>, JournalError> {
+        Ok(calculate_hmac(&[&self.base, &index.to_le_bytes(), data], &self.authentication)?.to_vec())
+    }
+
+    fn seal(&self, index: u64, commands: &[Command]) -> Result<Vec<u8>, JournalError> {
+        // the journal is encrypted, so it keeps the protected values
+        let plaintext = Zeroizing::new(reveal_protected_values(|| serde_json::to_vec(commands))?);
+        let mut iv = [0u8; IV_SIZE];
+        getrandom::fill(&mut iv)?;
+
+        let mut data = iv.to_vec();
+        data.extend(AES256Cipher::new(&self.encryption, &iv)?.encrypt(&plaintext)?);
+
+        let mut record = (data.len() as u32).to_le_bytes().to_vec();
+        record.extend_from_slice(&data);
+        record.extend(self.record_mac(index, &data)?);
+        Ok(record)
+    }
+
+    fn open(&self, index: u64, data: &[u8], mac: &[u8]) -> Result<Option<Vec<Command>>, JournalError> {
+        if !verify_hmac(
+            &[&self.base, &index.to_le_bytes(), data],
+            &self.authentication,
+            mac,
+        )? {
+            return Ok(None);
+        }
+        let (iv, ciphertext) = data.split_at(IV_SIZE);
+        let plaintext = Zeroizing::new(AES256Cipher::new(&self.encryption, iv)?.decrypt(ciphertext)?);
+        Ok(Some(serde_json::from_slice(&plaintext)?))
+    }
+}
+
+/// A write-ahead journal of the changes to a database file since it was last saved
+pub struct Journal {
+    path: PathBuf,
+    key: DatabaseKey,
+    keys: JournalKeys,
+    file: File,
+    pending: u64,
+}
+
+impl Journal {
+    /// Open the database at `path` and replay the changes in its journal that are missing from
+    /// the database file. A journal of another version of the database file is discarded.
+    pub fn open(path: impl AsRef<Path>, key: DatabaseKey) -> Result<(Database, Journal), JournalError> {
+        let path = path.as_ref().to_path_buf();
+        let data = std::fs::read(&path)?;
+        let mut db = Database::parse(&data, key.clone())?;
+        let base: [u8; 32] = calculate_sha256(&[&data])?.into();
+
+        let journal = match std::fs::read(journal_path(&path)) {
+            Ok(journal) => journal,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        if journal.len() >= HEADER_SIZE && journal.starts_with(MAGIC) && journal[8..40] == base {
+            let seed = &journal[40..40 + SEED_SIZE];
+            let keys = JournalKeys::derive(&db, &key, seed, base)?;
+            if !keys.verify_header(&journal[..HEADER_SIZE])? {
+                return Err(JournalError::Corrupted);
+            }
+
+            let (commands, valid_len) = read_records(&keys, &journal[HEADER_SIZE..])?;
+            for batch in &commands {
+                db = dry_run_all(&db, batch)?;
+            }
+
+            // drop a record that was only partially written when the process crashed
+            let file = OpenOptions::new().append(true).open(journal_path(&path))?;
+            file.set_len((HEADER_SIZE + valid_len) as u64)?;
+            file.sync_all()?;
+
+            let journal = Journal {
+                path,
+                key,
+                keys,
+                file,
+                pending: commands.len() as u64,
+            };
+            return Ok((db, journal));
+        }
+
+        let (keys, file) = start_journal(&path, &db, &key, base)?;
+        let journal = Journal {
+            path,
+            key,
+            keys,
+            file,
+            pending: 0,
+        };
+        Ok((db, journal))
+    }
+
+    /// Record a batch of commands in the journal and apply them to `db`. The database is only
+    /// changed after the commands are durably recorded, and not at all if any command fails.
+    pub fn record(&mut self, db: &mut Database, commands: &[Command]) -> Result<(), JournalError> {
+        let updated = dry_run_all(db, commands)?;
+
+        let record = self.keys.seal(self.pending, commands)?;
+        self.file.write_all(&record)?;
+        self.file.sync_all()?;
+        self.pending += 1;
+
+        *db = updated;
+        Ok(())
+    }
+
+    /// The number of recorded batches of commands that are not yet saved in the database file
+    pub fn pending(&self) -> usize {
+        self.pending as usize
+    }
+
+    /// Save `db` to the database file with `Database::save_to_path` and start an empty journal with
+    /// a new seed for the new file
+    pub fn save(&mut self, db: &Database) -> Result<(), JournalError> {
+        db.save_to_path(&self.path, self.key.clone(), &SaveOptions::default())?;
+
+        // a crash before the new journal is in place leaves the old journal, which no longer
+        // matches the database file and is discarded
+        let base: [u8; 32] = calculate_sha256(&[&std::fs::read(&self.path)?])?.into();
+        let (keys, file) = start_journal(&self.path, db, &self.key, base)?;
+        self.keys = keys;
+        self.file = file;
+        self.pending = 0;
+        Ok(())
+    }
+}
+
+/// Start an empty journal for the database file with the hash `base`, with a new random seed
+fn start_journal(
+    path: &Path,
+    db: &Database,
+    key: &DatabaseKey,
+    base: [u8; 32],
+) -> Result<(JournalKeys, File), JournalError> {
+    let mut seed = vec![0; SEED_SIZE];
+    getrandom::fill(&mut seed)?;
+    let keys = JournalKeys::derive(db, key, &seed, base)?;
+    let file = write_header(path, &keys.header(&seed)?)?;
+    Ok((keys, file))
+}
+
+/// Write a journal with only a header, replacing the current journal atomically
+fn write_header(path: &Path, header: &[u8]) -> Result<File, JournalError> {
+    let journal = journal_path(path);
+    let mut temp = journal.as_os_str().to_owned();
+    temp.push(".tmp");
+    let temp = PathBuf::from(temp);
+
+    let mut file = File::create(&temp)?;
+    file.write_all(header)?;
+    file.sync_all()?;
+    std::fs::rename(&temp, &journal)?;
+
+    Ok(OpenOptions::new().append(true).open(&journal)?)
+}
+
+/// Read the records of a journal, returning the recorded batches of commands and the length of
+/// the intact records. A record cut short by a crash ends the journal.
+fn read_records(keys: &JournalKeys, mut data: &[u8]) -> Result<(Vec<Vec<Command>>, usize), JournalError> {
+    let mut commands = Vec::new();
+    let mut valid_len = 0;
+
+    while data.len() >= 4 {
+        let len = u32::from_le_bytes(data[..4].try_into().unwrap()) as usize;
+        let end = 4 + len + MAC_SIZE;
+        if len < IV_SIZE || data.len() < end {
+            break;
+        }
+
+        match keys.open(commands.len() as u64, &data[4..4 + len], &data[4 + len..end])? {
+            Some(batch) => commands.push(batch),
+            // only the last record can be torn, anything else was tampered with
+            None if data.len() == end => break,
+            None => return Err(JournalError::Corrupted),
+        }
+
+        valid_len += end;
+        data = &data[end..];
+    }
+
+    Ok((commands, valid_len))
+}
+
+#[cfg(test)]
+mod journal_tests {
+    use std::{collections::BTreeMap, io::Write};
+
+    use uuid::Uuid;
+
+    use crate::{
+        commands::{Command, FieldValue},
+        config::{DatabaseConfig, KdfConfig},
+        Database, DatabaseKey,
+    };
+
+    use super::{journal_path, Journal, JournalError, HEADER_SIZE};
+
+    fn key() -> DatabaseKey {
+        DatabaseKey::new().with_password("journal")
+    }
+
+    fn create_database(path: &std::path::Path) -> Database {
+        let mut db = Database::new(DatabaseConfig {
+            kdf_config: KdfConfig::Aes { rounds: 10 },
+            ..Default::default()
+        });
+        db.meta.database_name = Some("Journaled".to_string());
+        db.save(&mut std::fs::File::create(path).unwrap(), key()).unwrap();
+        db
+    }
+
+    fn add_entry(parent: Uuid, title: &str) -> Command {
+        let mut fields = BTreeMap::new();
+        fields.insert("Title".to_string(), FieldValue::unprotected(title));
+        fields.insert("Password".to_string(), FieldValue::protected(title));
+        Command::AddEntry {
+            parent,
+            uuid: Uuid::new_v4(),
+            fields,
+        }
+    }
+
+    #[test]
+    fn test_replay_after_crash() {
+        let path = std::env::temp_dir().join(format!("keepass-journal-{}.kdbx", Uuid::new_v4()));
+        create_database(&path);
+
+        let (mut db, mut journal) = Journal::open(&path, key()).unwrap();
+        let root = db.root.uuid;
+        journal.record(&mut db, &[add_entry(root, "First")]).unwrap();
+        journal.record(&mut db, &[add_entry(root, "Second")]).unwrap();
+        assert_eq!(journal.pending(), 2);
+
+        // a failing batch is neither recorded nor applied
+        assert!(matches!(
+            journal.record(
+                &mut db,
+                &[add_entry(root, "Third"), add_entry(Uuid::new_v4(), "Lost")]
+            ),
+            Err(JournalError::Command(_))
+        ));
+        assert_eq!(db.root.entries().len(), 2);
+        drop(journal);
+
+        // crash mid-write of another record
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(journal_path(&path))
+            .unwrap();
+        file.write_all(&[200, 0, 0, 0, 1, 2, 3]).unwrap();
+        drop(file);
+
+        let (db, mut journal) = Journal::open(&path, key()).unwrap();
+        let titles: Vec<_> = db.root.entries().iter().map(|e| e.get_title().unwrap()).collect();
+        assert_eq!(titles, vec!["First", "Second"]);
+        assert_eq!(
+            db.root.entries()[1].get_protected("Password").unwrap().as_str(),
+            Some("Second")
+        );
+        assert_eq!(journal.pending(), 2);
+
+        assert!(matches!(
+            Journal::open(&path, DatabaseKey::new().with_password("wrong")),
+            Err(JournalError::Open(_))
+        ));
+
+        journal.save(&db).unwrap();
+        assert_eq!(journal.pending(), 0);
+        drop(journal);
+
+        let (reopened, journal) = Journal::open(&path, key()).unwrap();
+        assert_eq!(reopened.root.entries().len(), 2);
+        assert_eq!(journal.pending(), 0);
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(journal_path(&path)).unwrap();
+    }
+
+    #[test]
+    fn test_records_of_older_journals_are_not_replayed() {
+        let path = std::env::temp_dir().join(format!("keepass-journal-{}.kdbx", Uuid::new_v4()));
+        create_database(&path);
+
+        let (mut db, mut journal) = Journal::open(&path, key()).unwrap();
+        let root = db.root.uuid;
+        journal.record(&mut db, &[add_entry(root, "Once")]).unwrap();
+        let old = std::fs::read(journal_path(&path)).unwrap();
+        journal.save(&db).unwrap();
+        drop(journal);
+
+        // append the record of the old journal to the new one
+        let mut spliced = std::fs::read(journal_path(&path)).unwrap();
+        assert_ne!(spliced[40..HEADER_SIZE], old[40..HEADER_SIZE]);
+        spliced.extend_from_slice(&old[HEADER_SIZE..]);
+        std::fs::write(journal_path(&path), &spliced).unwrap();
+
+        let (db, journal) = Journal::open(&path, key()).unwrap();
+        assert_eq!(db.root.entries().len(), 1);
+        assert_eq!(journal.pending(), 0);
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(journal_path(&path)).unwrap();
+    }
+
+    #[test]
+    fn test_stale_journal_is_discarded() {
+        let path = std::env::temp_dir().join(format!("keepass-journal-{}.kdbx", Uuid::new_v4()));
+        create_database(&path);
+
+        let (mut db, mut journal) = Journal::open(&path, key()).unwrap();
+        let root = db.root.uuid;
+        journal.record(&mut db, &[add_entry(root, "Saved")]).unwrap();
+        drop(journal);
+
+        // the database file was saved, but the process crashed before the journal was reset
+        db.save(&mut std::fs::File::create(&path).unwrap(), key())
+            .unwrap();
+
+        let (db, journal) = Journal::open(&path, key()).unwrap();
+        assert_eq!(db.root.entries().len(), 1);
+        assert_eq!(journal.pending(), 0);
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(journal_path(&path)).unwrap();
+    }
+}
@@ -0,0 +1,758 @@
+//! Sharing groups between databases with KeeShare, as KeePassXC does
+//!
+//! A KeeShare container is a separate database holding one shared group, protected with a password
+//! of its own. The exporting database writes the group to the container, and every database that
+//! imports it merges the container into one of its groups. A signed container is a ZIP archive
+//! holding the database as `container.share.kdbx` and its RSA signature as
+//! `container.share.signature`, while an unsigned one is just the database.
+//!
+//! ```
+//! use keepass::{
+//!     config::{DatabaseConfig, KdfConfig},
+//!     db::{Entry, Group},
+//!     keeshare, Database,
+//! };
+//!
+//! let config = DatabaseConfig {
+//!     kdf_config: KdfConfig::Aes { rounds: 10 },
+//!     ..Default::default()
+//! };
+//!
+//! let mut source = Database::new(config.clone());
+//! let mut team = Group::new("Team");
+//! team.add_child(Entry::new().with_title("Deploy key"));
+//! let team_uuid = team.uuid;
+//! source.root.add_child(team);
+//! let container = keeshare::export(&source, &team_uuid, "share password")?;
+//!
+//! let mut target = Database::new(config);
+//! let shared = Group::new("Shared with me");
+//! let shared_uuid = shared.uuid;
+//! target.root.add_child(shared);
+//! let report = keeshare::import(&mut target, &shared_uuid, &container, "share password", None)?;
+//! assert_eq!(report.created_entries, 1);
+//! # Ok::<(), keepass::keeshare::KeeShareError>(())
+//! ```
+//!
+//! RSA signatures are checked and created by the caller through `ShareVerifier` and
+//! `ShareSigner`, e.g. with the `rsa` crate, so that this crate does not have to decide which
+//! signers to trust. The settings of a shared group, which KeePassXC keeps in its custom data, are
+//! read and written with `reference` and `set_reference`.
+
+use base64::{engine::general_purpose as base64_engine, Engine as _};
+use thiserror::Error;
+use uuid::Uuid;
+use xml::{
+    name::OwnedName,
+    reader::{EventReader, XmlEvent},
+};
+use zeroize::Zeroize;
+
+use crate::{
+    config::DatabaseVersion,
+    db::{
+        attach::add_header_attachment, convert::remap_attachments, merge::MergeEventType, ConversionError,
+        CustomDataItem, Database, Group, MergeOptions, MergeStrategy, Node, NodeRef, NodeRefMut, Times, Value,
+    },
+    error::{DatabaseOpenError, DatabaseSaveError},
+    format::KDBX4_CURRENT_MINOR_VERSION,
+    import::zip,
+    DatabaseKey,
+};
+
+/// Custom data key of a group holding its KeeShare settings
+pub const REFERENCE_KEY: &str = "KeeShare/Reference";
+
+/// Name of the database in a signed container
+pub const CONTAINER_DATABASE_NAME: &str = "container.share.kdbx";
+
+/// Name of the signature in a signed container
+pub const CONTAINER_SIGNATURE_NAME: &str = "container.share.signature";
+
+/// Errors while importing or exporting a KeeShare container
+#[derive(Debug, Error)]
+pub enum KeeShareError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error(transparent)]
+    Open(#[from] DatabaseOpenError),
+
+    #[error(transparent)]
+    Save(#[from] DatabaseSaveError),
+
+    #[error(transparent)]
+    Conversion(#[from] ConversionError),
+
+    #[error("No group with UUID {0}")]
+    GroupNotFound(Uuid),
+
+    #[error("Invalid KeeShare container: {0}")]
+    InvalidContainer(String),
+
+    #[error("Invalid KeeShare reference: {0}")]
+    InvalidReference(String),
+
+    /// The container is signed, but there was no verifier or it rejected the signature
+    #[error("The container is signed by {signer:?}, whose signature was not accepted")]
+    UntrustedSignature { signer: String },
+
+    #[error("Signing the container failed: {0}")]
+    Signing(String),
+
+    #[error("Merging the shared group failed: {0}")]
+    Merge(String),
+
+    /// Attachments in KDBX 3 databases are kept in a way that cannot take the shared ones
+    #[error("Attachments can only be imported into KDBX 4 databases")]
+    AttachmentsNeedKdbx4,
+}
+
+/// Which way a group is shared
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShareType {
+    /// The group is updated from the container
+    Import,
+
+    /// The group is written to the container
+    Export,
+
+    /// Both
+    Synchronize,
+}
+
+/// The KeeShare settings of a group
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShareReference {
+    pub share_type: ShareType,
+
+    /// Identifies the share
+    pub uuid: Uuid,
+
+    /// Path of the container, usually relative to the database
+    pub path: String,
+
+    /// Password of the container. It is wiped from memory when the reference is dropped.
+    pub password: String,
+
+    /// Whether the groups below the shared group are kept when importing, instead of putting all
+    /// entries directly into it
+    pub keep_groups: bool,
+}
+
+impl Drop for ShareReference {
+    fn drop(&mut self) {
+        self.password.zeroize();
+    }
+}
+
+impl ShareReference {
+    /// Parse the XML document KeePassXC keeps base64-encoded in the custom data of a group
+    fn parse(xml: &[u8]) -> Result<ShareReference, KeeShareError> {
+        let invalid = |message: &str| KeeShareError::InvalidReference(message.to_string());
+        let decode = |text: &str| {
+            base64_engine::STANDARD
+                .decode(text.trim())
+                .map_err(|_| invalid("bad base64"))
+        };
+
+        let mut import = false;
+        let mut export = false;
+        let mut uuid = None;
+        let mut path = String::new();
+        let mut password = String::new();
+        let mut keep_groups = true;
+
+        let mut tag_stack: Vec<String> = Vec::new();
+        for ev in EventReader::new(xml) {
+            match ev.map_err(|e| KeeShareError::InvalidReference(e.to_string()))? {
+                XmlEvent::StartElement {
+                    name: OwnedName { local_name, .. },
+                    ..
+                } => {
+                    tag_stack.push(local_name);
+                    match tag_stack.join("/").as_str() {
+                        "KeeShare/Type/Import" => import = true,
+                        "KeeShare/Type/Export" => export = true,
+                        _ => {}
+                    }
+                }
+                XmlEvent::EndElement { .. } => {
+                    tag_stack.pop();
+                }
+                XmlEvent::Characters(text) => match tag_stack.join("/").as_str() {
+                    "KeeShare/Group" => {
+                        uuid = Some(Uuid::from_slice(&decode(&text)?).map_err(|_| invalid("bad UUID"))?);
+                    }
+                    "KeeShare/Path" => {
+                        path = String::from_utf8(decode(&text)?).map_err(|_| invalid("bad path"))?;
+                    }
+                    "KeeShare/Password" => {
+                        password = String::from_utf8(decode(&text)?).map_err(|_| invalid("bad password"))?;
+                    }
+                    "KeeShare/KeepGroups" => keep_groups = text.trim().eq_ignore_ascii_case("true"),
+                    _ => {}
+                },
+                _ => {}
+            }
+        }
+
+        let share_type = match (import, export) {
+            (true, false) => ShareType::Import,
+            (false, true) => ShareType::Export,
+            (true, true) => ShareType::Synchronize,
+            (false, false) => return Err(invalid("the share is inactive")),
+        };
+
+        Ok(ShareReference {
+            share_type,
+            uuid: uuid.ok_or_else(|| invalid("missing UUID"))?,
+            path,
+            password,
+            keep_groups,
+        })
+    }
+
+    /// Write the XML document in the layout of KeePassXC
+    fn serialize(&self) -> String {
+        let mut share_type = String::new();
+        if self.share_type != ShareType::Export {
+            share_type.push_str("<Import/>");
+        }
+        if self.share_type != ShareType::Import {
+            share_type.push_str("<Export/>");
+        }
+
+        let encode = |value: &[u8]| base64_engine::STANDARD.encode(value);
+        format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<KeeShare><Type>{}</Type><Group>{}</Group>\
+             <Path>{}</Path><Password>{}</Password><KeepGroups>{}</KeepGroups></KeeShare>\n",
+            share_type,
+            encode(self.uuid.as_bytes()),
+            encode(self.path.as_bytes()),
+            encode(self.password.as_bytes()),
+            if self.keep_groups { "True" } else { "False" },
+        )
+    }
+}
+
+/// The KeeShare settings of a group, if it is shared
+pub fn reference(group: &Group) -> Result<Option<ShareReference>, KeeShareError> {
+    let encoded = match group
+        .custom_data
+        .items
+        .get(REFERENCE_KEY)
+        .and_then(|item| item.value.as_ref())
+    {
+        Some(Value::Unprotected(encoded)) => encoded.clone(),
+        Some(Value::Protected(encoded)) => String::from_utf8_lossy(&encoded.decrypt()).into_owned(),
+        Some(Value::Bytes(_)) | None => return Ok(None),
+    };
+
+    let xml = base64_engine::STANDARD
+        .decode(encoded.trim())
+        .map_err(|_| KeeShareError::InvalidReference("bad base64".to_string()))?;
+    ShareReference::parse(&xml).map(Some)
+}
+
+/// Share a group with the given settings, or stop sharing it with `None`
+pub fn set_reference(group: &mut Group, reference: Option<&ShareReference>) {
+    match reference {
+        Some(reference) => {
+            let encoded = base64_engine::STANDARD.encode(reference.serialize());
+            group.custom_data.items.insert(
+                REFERENCE_KEY.to_string(),
+                CustomDataItem {
+                    value: Some(Value::Unprotected(encoded)),
+                    last_modification_time: Some(Times::now()),
+                },
+            );
+        }
+        None => {
+            group.custom_data.items.remove(REFERENCE_KEY);
+        }
+    }
+}
+
+/// All shared groups of a database with their settings. Groups with invalid settings are left out.
+pub fn shared_groups(db: &Database) -> Vec<(Uuid, ShareReference)> {
+    db.root
+        .iter()
+        .filter_map(|node| match node {
+            NodeRef::Group(g) => reference(g).ok().flatten().map(|r| (g.uuid, r)),
+            NodeRef::Entry(_) => None,
+        })
+        .collect()
+}
+
+/// The signature of a signed container
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShareSignature {
+    /// Name of the signer, as entered in KeePassXC
+    pub signer: String,
+
+    /// Public key of the signer in the OpenSSH wire format
+    pub key: Vec<u8>,
+
+    /// Signature algorithm, `rsa` for RSA with SHA-256 and PKCS #1 v1.5 padding
+    pub algorithm: String,
+
+    /// The signature of the database in the container
+    pub signature: Vec<u8>,
+}
+
+impl ShareSignature {
+    fn parse(xml: &[u8]) -> Result<ShareSignature, KeeShareError> {
+        let invalid = |message: &str| KeeShareError::InvalidContainer(format!("signature: {}", message));
+
+        let mut signer = String::new();
+        let mut key = Vec::new();
+        let mut signature = None;
+
+        let mut tag_stack: Vec<String> = Vec::new();
+        for ev in EventReader::new(xml) {
+            match ev.map_err(|e| invalid(&e.to_string()))? {
+                XmlEvent::StartElement {
+                    name: OwnedName { local_name, .. },
+                    ..
+                } => tag_stack.push(local_name),
+                XmlEvent::EndElement { .. } => {
+                    tag_stack.pop();
+                }
+                XmlEvent::Characters(text) => match tag_stack.join("/").as_str() {
+                    "KeeShare/Signature" => signature = Some(text.trim().to_string()),
+                    "KeeShare/Certificate/Signer" => signer = text,
+                    "KeeShare/Certificate/Key" => {
+                        key = base64_engine::STANDARD
+                            .decode(text.trim())
+                            .map_err(|_| invalid("bad key"))?;
+                    }
+                    _ => {}
+                },
+                _ => {}
+            }
+        }
+
+        let signature = signature.ok_or_else(|| invalid("missing signature"))?;
+        let (algorithm, signature) = signature
+            .split_once('|')
+            .ok_or_else(|| invalid("missing algorithm"))?;
+        Ok(ShareSignature {
+            signer,
+            key,
+            algorithm: algorithm.to_string(),
+            signature: hex::decode(signature).map_err(|_| invalid("bad signature"))?,
+        })
+    }
+
+    fn serialize(&self) -> String {
+        format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<KeeShare><Signature>{}|{}</Signature>\
+             <Certificate><Signer>{}</Signer><Key>{}</Key></Certificate></KeeShare>\n",
+            self.algorithm,
+            hex::encode(&self.signature),
+            xml::escape::escape_str_pcdata(&self.signer),
+            base64_engine::STANDARD.encode(&self.key),
+        )
+    }
+}
+
+/// Checks the signature of a signed container before it is imported
+pub trait ShareVerifier {
+    /// Whether `signature` is a valid signature of `data` by a trusted signer
+    fn verify(&self, signature: &ShareSignature, data: &[u8]) -> bool;
+}
+
+/// Signs containers when exporting
+pub trait ShareSigner {
+    type Error: std::fmt::Display;
+
+    /// Sign the database `data` that goes into the container
+    fn sign(&self, data: &[u8]) -> Result<ShareSignature, Self::Error>;
+}
+
+/// What importing a container changed
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ShareImportReport {
+    /// Who signed the container, if it was signed
+    pub signer: Option<String>,
+
+    pub created_entries: usize,
+    pub updated_entries: usize,
+    pub deleted_entries: usize,
+    pub created_groups: usize,
+
+    /// Problems the merge worked around, e.g. missing timestamps
+    pub warnings: Vec<String>,
+}
+
+/// Import a container into the group with the UUID `group`, merging it like a synchronization
+/// does. A signed container is only imported if `verifier` accepts its signature.
+pub fn import(
+    db: &mut Database,
+    group: &Uuid,
+    container: &[u8],
+    password: &str,
+    verifier: Option<&dyn ShareVerifier>,
+) -> Result<ShareImportReport, KeeShareError> {
+    let mut report = ShareImportReport::default();
+
+    let (data, signature) = open_container(container)?;
+    if let Some(signature) = signature {
+        if !verifier.is_some_and(|v| v.verify(&signature, &data)) {
+            return Err(KeeShareError::UntrustedSignature {
+                signer: signature.signer,
+            });
+        }
+        report.signer = Some(signature.signer);
+    }
+
+    let mut share = Database::parse(&data, DatabaseKey::new().with_password(password))?;
+    if let DatabaseVersion::KDB3(_) = share.config.version {
+        share.convert_to(DatabaseVersion::KDB4(KDBX4_CURRENT_MINOR_VERSION))?;
+    }
+    share.remove_orphaned_attachments();
+    if !share.header_attachments.is_empty() && !matches!(db.config.version, DatabaseVersion::KDB4(_)) {
+        return Err(KeeShareError::AttachmentsNeedKdbx4);
+    }
+
+    let target = match db.root.find_by_uuid(group) {
+        Some(NodeRef::Group(g)) => g.clone(),
+        _ if db.root.uuid == *group => db.root.clone(),
+        _ => return Err(KeeShareError::GroupNotFound(*group)),
+    };
+
+    // the attachments and icons of the container move into the database
+    let mut indices = std::collections::HashMap::new();
+    for (old, attachment) in share.header_attachments.iter().enumerate() {
+        let new = add_header_attachment(
+            &mut db.header_attachments,
+            attachment.data()?.into_owned(),
+            attachment.flags & 1 != 0,
+        );
+        indices.insert(old, new);
+    }
+    remap_attachments(&mut share.root, &indices);
+    for icon in &share.meta.custom_icons.icons {
+        if !db.meta.custom_icons.icons.iter().any(|i| i.uuid == icon.uuid) {
+            db.meta.custom_icons.icons.push(icon.clone());
+        }
+    }
+
+    // merge into a database holding just the group, so that nothing outside of it is touched
+    let mut view = Database::new(db.config.clone());
+    view.root = target;
+    view.deleted_objects = db.deleted_objects.clone();
+    let options = MergeOptions {
+        strategy: MergeStrategy::Synchronize,
+        ..Default::default()
+    };
+    let log = view
+        .merge_with_options(&share, &options)
+        .map_err(|e| KeeShareError::Merge(e.to_string()))?;
+
+    match db.root.find_by_uuid_mut(group) {
+        Some(NodeRefMut::Group(g)) => g.children = view.root.children,
+        _ => db.root.children = view.root.children,
+    }
+    db.deleted_objects = view.deleted_objects;
+
+    for event in log.events {
+        match event.event_type {
+            MergeEventType::EntryCreated | MergeEventType::EntryConflictCopyCreated => {
+                report.created_entries += 1
+            }
+            MergeEventType::EntryUpdated | MergeEventType::EntryLocationUpdated => report.updated_entries += 1,
+            MergeEventType::EntryDeleted => report.deleted_entries += 1,
+            MergeEventType::GroupCreated => report.created_groups += 1,
+            MergeEventType::GroupDeleted
+            | MergeEventType::GroupLocationUpdated
+            | MergeEventType::GroupUpdated => {}
+        }
+    }
+    report.warnings = log.warnings;
+
+    Ok(report)
+}
+
+/// Export the group with the UUID `group` as an unsigned container, protected with `password`.
+/// Shared groups below it are left out, as they belong to other shares.
+pub fn export(db: &Database, group: &Uuid, password: &str) -> Result<Vec<u8>, KeeShareError> {
+    let share = share_database(db, group)?;
+    let mut data = Vec::new();
+    share.save(&mut data, DatabaseKey::new().with_password(password))?;
+    Ok(data)
+}
+
+/// Export the group with the UUID `group` as a container signed by `signer`, see `export`
+pub fn export_signed<S: ShareSigner>(
+    db: &Database,
+    group: &Uuid,
+    password: &str,
+    signer: &S,
+) -> Result<Vec<u8>, KeeShareError> {
+    let data = export(db, group, password)?;
+    let signature = signer
+        .sign(&data)
+        .map_err(|e| KeeShareError::Signing(e.to_string()))?;
+
+    Ok(zip::write_stored(&[
+        (CONTAINER_DATABASE_NAME, &data),
+        (CONTAINER_SIGNATURE_NAME, signature.serialize().as_bytes()),
+    ]))
+}
+
+/// The database in a container, and its signature if the container is signed
+fn open_container(container: &[u8]) -> Result<(Vec<u8>, Option<ShareSignature>), KeeShareError> {
+    if !container.starts_with(b"PK\x03\x04") {
+        return Ok((container.to_vec(), None));
+    }
+
+    let archive = zip::ZipArchive::new(container.to_vec())?;
+    let data = archive
+        .read(CONTAINER_DATABASE_NAME)?
+        .ok_or_else(|| KeeShareError::InvalidContainer(format!("{} is missing", CONTAINER_DATABASE_NAME)))?;
+    let signature = match archive.read(CONTAINER_SIGNATURE_NAME)? {
+        Some(xml) => Some(ShareSignature::parse(&xml)?),
+        None => None,
+    };
+    Ok((data, signature))
+}
+
+/// A database holding a copy of the group as its root, with the attachments and icons it uses
+fn share_database(db: &Database, group: &Uuid) -> Result<Database, KeeShareError> {
+    let mut root = match db.root.find_by_uuid(group) {
+        Some(NodeRef::Group(g)) => g.clone(),
+        _ if db.root.uuid == *group => db.root.clone(),
+        _ => return Err(KeeShareError::GroupNotFound(*group)),
+    };
+
+    // the settings hold the password of the container
+    root.custom_data.items.remove(REFERENCE_KEY);
+    remove_shared_groups(&mut root);
+
+    let mut share = Database::new(db.config.clone());
+    share.root = root;
+    share.header_attachments = db.header_attachments.clone();
+    share.meta.binaries = db.meta.binaries.clone();
+    if let DatabaseVersion::KDB3(_) = share.config.version {
+        share.convert_to(DatabaseVersion::KDB4(KDBX4_CURRENT_MINOR_VERSION))?;
+    }
+    share.remove_orphaned_attachments();
+    share.meta.custom_icons = db.meta.custom_icons.clone();
+    share.prune_unused_icons();
+
+    share.meta.database_name = Some(share.root.name.clone());
+    share.meta.recyclebin_enabled = Some(false);
+    Ok(share)
+}
+
+fn remove_shared_groups(group: &mut Group) {
+    group.children.retain(|node| match node {
+        Node::Group(g) => !g.custom_data.items.contains_key(REFERENCE_KEY),
+        Node::Entry(_) => true,
+    });
+    for node in &mut group.children {
+        if let Node::Group(g) = node {
+            remove_shared_groups(g);
+        }
+    }
+}
+
+#[cfg(test)]
+mod keeshare_tests {
+    use uuid::Uuid;
+
+    use crate::{
+        config::{DatabaseConfig, KdfConfig},
+        crypt::calculate_sha256,
+        db::{Entry, Group, NodeRef},
+        import::zip::{write_stored, ZipArchive},
+        Database,
+    };
+
+    use super::{
+        export, export_signed, import, reference, set_reference, shared_groups, KeeShareError, ShareReference,
+        ShareSignature, ShareSigner, ShareType, ShareVerifier, CONTAINER_DATABASE_NAME,
+        CONTAINER_SIGNATURE_NAME,
+    };
+
+    /// Signs with the SHA-256 hash of the data, which is enough to test the plumbing
+    struct HashSigner;
+
+    impl ShareSigner for HashSigner {
+        type Error = String;
+
+        fn sign(&self, data: &[u8]) -> Result<ShareSignature, String> {
+            Ok(ShareSignature {
+                signer: "Team <lead>".to_string(),
+                key: b"ssh-rsa".to_vec(),
+                algorithm: "rsa".to_string(),
+                signature: calculate_sha256(&[data]).unwrap().to_vec(),
+            })
+        }
+    }
+
+    impl ShareVerifier for HashSigner {
+        fn verify(&self, signature: &ShareSignature, data: &[u8]) -> bool {
+            signature.key == b"ssh-rsa" && signature.signature == calculate_sha256(&[data]).unwrap().as_slice()
+        }
+    }
+
+    fn database() -> Database {
+        Database::new(DatabaseConfig {
+            kdf_config: KdfConfig::Aes { rounds: 10 },
+            ..Default::default()
+        })
+    }
+
+    fn titles(group: &Group) -> Vec<String> {
+        let mut titles: Vec<String> = group
+            .iter()
+            .filter_map(|node| match node {
+                NodeRef::Entry(e) => e.get_title().map(str::to_string),
+                NodeRef::Group(_) => None,
+            })
+            .collect();
+        titles.sort();
+        titles
+    }
+
+    /// A database with a shared group holding two entries, one of them with an attachment, and a
+    /// nested share that is not exported
+    fn source() -> (Database, Uuid) {
+        let mut db = database();
+        let mut team = Group::new("Team");
+        set_reference(
+            &mut team,
+            Some(&ShareReference {
+                share_type: ShareType::Export,
+                uuid: Uuid::new_v4(),
+                path: "team.kdbx.share".to_string(),
+                password: "share".to_string(),
+                keep_groups: true,
+            }),
+        );
+
+        let mut entry = Entry::new().with_title("Deploy key");
+        entry.add_attachment(&mut db.header_attachments, "id_ed25519", b"private key".to_vec());
+        team.add_child(entry);
+        team.add_child(Group::new("Docs").with_child(Entry::new().with_title("Wiki")));
+
+        let mut nested = Group::new("Imported from elsewhere");
+        nested.add_child(Entry::new().with_title("Not ours"));
+        let mut nested_reference = reference(&team).unwrap().unwrap();
+        nested_reference.share_type = ShareType::Import;
+        set_reference(&mut nested, Some(&nested_reference));
+        team.add_child(nested);
+
+        let mut unrelated = Entry::new().with_title("Private");
+        unrelated.add_attachment(&mut db.header_attachments, "secret.txt", b"not shared".to_vec());
+        db.root.add_child(unrelated);
+
+        let uuid = team.uuid;
+        db.root.add_child(team);
+        (db, uuid)
+    }
+
+    fn target() -> (Database, Uuid) {
+        let mut db = database();
+        let group = Group::new("Shared");
+        let uuid = group.uuid;
+        db.root.add_child(group);
+        db.root.add_child(Entry::new().with_title("Mine"));
+        (db, uuid)
+    }
+
+    fn group<'a>(db: &'a Database, uuid: &Uuid) -> &'a Group {
+        match db.root.find_by_uuid(uuid) {
+            Some(NodeRef::Group(g)) => g,
+            _ => panic!("group not found"),
+        }
+    }
+
+    #[test]
+    fn test_reference_roundtrip() {
+        let (db, uuid) = source();
+        let shared = shared_groups(&db);
+        assert_eq!(shared.len(), 2);
+
+        let reference = reference(group(&db, &uuid)).unwrap().unwrap();
+        assert_eq!(reference.share_type, ShareType::Export);
+        assert_eq!(reference.path, "team.kdbx.share");
+        assert_eq!(reference.password, "share");
+        assert!(reference.keep_groups);
+        assert!(super::reference(&Group::new("Not shared")).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_export_and_import() {
+        let (source, team) = source();
+        let container = export(&source, &team, "share").unwrap();
+
+        let share = Database::parse(&container, crate::DatabaseKey::new().with_password("share")).unwrap();
+        assert_eq!(titles(&share.root), vec!["Deploy key", "Wiki"]);
+        assert_eq!(share.header_attachments.len(), 1);
+        assert!(reference(&share.root).unwrap().is_none());
+
+        let (mut target, shared) = target();
+        let report = import(&mut target, &shared, &container, "share", None).unwrap();
+        assert_eq!(report.created_entries, 2);
+        assert_eq!(report.signer, None);
+        assert_eq!(titles(group(&target, &shared)), vec!["Deploy key", "Wiki"]);
+        assert_eq!(titles(&target.root), vec!["Deploy key", "Mine", "Wiki"]);
+
+        let entry = group(&target, &shared).entries()[0];
+        let attachment = entry
+            .get_attachment(&target.header_attachments, "id_ed25519")
+            .unwrap();
+        assert_eq!(&attachment.data().unwrap()[..], b"private key");
+
+        // importing the same container again changes nothing
+        let report = import(&mut target, &shared, &container, "share", None).unwrap();
+        assert_eq!((report.created_entries, report.updated_entries), (0, 0));
+
+        assert!(matches!(
+            import(&mut target, &shared, &container, "wrong", None),
+            Err(KeeShareError::Open(_))
+        ));
+        assert!(matches!(
+            import(&mut target, &Uuid::new_v4(), &container, "share", None),
+            Err(KeeShareError::GroupNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_signed_container() {
+        let (source, team) = source();
+        let container = export_signed(&source, &team, "share", &HashSigner).unwrap();
+        assert!(container.starts_with(b"PK"));
+
+        let (mut target, shared) = target();
+        assert!(matches!(
+            import(&mut target, &shared, &container, "share", None),
+            Err(KeeShareError::UntrustedSignature { signer }) if signer == "Team <lead>"
+        ));
+
+        let report = import(&mut target, &shared, &container, "share", Some(&HashSigner)).unwrap();
+        assert_eq!(report.signer.as_deref(), Some("Team <lead>"));
+        assert_eq!(titles(group(&target, &shared)), vec!["Deploy key", "Wiki"]);
+
+        // a container whose database was replaced is rejected
+        let other = export_signed(&source, &team, "other", &HashSigner).unwrap();
+        let signature = ZipArchive::new(container)
+            .unwrap()
+            .read(CONTAINER_SIGNATURE_NAME)
+            .unwrap()
+            .unwrap();
+        let tampered = write_stored(&[
+            (CONTAINER_DATABASE_NAME, &export(&source, &team, "share").unwrap()),
+            (CONTAINER_SIGNATURE_NAME, &signature),
+        ]);
+        assert!(matches!(
+            import(&mut target, &shared, &tampered, "share", Some(&HashSigner)),
+            Err(KeeShareError::UntrustedSignature { .. })
+        ));
+        assert!(import(&mut target, &shared, &other, "other", Some(&HashSigner)).is_ok());
+    }
+}
@@ -1,9 +1,12 @@
 use std::io::Read;
+#[cfg(feature = "challenge_response")]
+use std::sync::Arc;
 
 use base64::{engine::general_purpose as base64_engine, Engine as _};
+use cipher::generic_array::{typenum::U32, GenericArray};
 use xml::name::OwnedName;
 use xml::reader::{EventReader, XmlEvent};
-use zeroize::{Zeroize, ZeroizeOnDrop};
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
 
 #[cfg(feature = "challenge_response")]
 use challenge_response::{
@@ -11,7 +14,11 @@ use challenge_response::{
     ChallengeResponse,
 };
 
-use crate::{crypt::calculate_sha256, error::DatabaseKeyError};
+use crate::{
+    config::{Argon2SecretParameters, KdfConfig},
+    crypt::calculate_sha256,
+    error::DatabaseKeyError,
+};
 
 pub type KeyElement = Vec<u8>;
 pub type KeyElements = Vec<KeyElement>;
@@ -26,21 +33,37 @@ fn parse_yubikey_slot(slot_number: &str) -> Result<Slot, DatabaseKeyError> {
     ));
 }
 
-fn parse_xml_keyfile(xml: &[u8]) -> Result<KeyElement, DatabaseKeyError> {
+/// Parse an XML keyfile of version 1.0 or 2.0. Returns `None` if `xml` is not a keyfile in XML,
+/// which is then used like a keyfile in any other format.
+fn parse_xml_keyfile(xml: &[u8]) -> Result<Option<KeyElement>, DatabaseKeyError> {
     let parser = EventReader::new(xml);
 
     let mut tag_stack = Vec::new();
 
     let mut key_version: Option<String> = None;
     let mut key_value: Option<String> = None;
+    let mut key_hash: Option<String> = None;
 
     for ev in parser {
-        match ev? {
+        let ev = match ev {
+            Ok(ev) => ev,
+            Err(_) => return Ok(None),
+        };
+
+        match ev {
             XmlEvent::StartElement {
                 name: OwnedName { ref local_name, .. },
+                ref attributes,
                 ..
             } => {
                 tag_stack.push(local_name.clone());
+
+                if tag_stack == ["KeyFile", "Key", "Data"] {
+                    key_hash = attributes
+                        .iter()
+                        .find(|a| a.name.local_name == "Hash")
+                        .map(|a| a.value.clone());
+                }
             }
             XmlEvent::EndElement { .. } => {
                 tag_stack.pop();
@@ -61,52 +84,153 @@ fn parse_xml_keyfile(xml: &[u8]) -> Result<KeyElement, DatabaseKeyError> {
     }
 
     let key_value = match key_value {
-        Some(k) => k,
-        None => return Err(DatabaseKeyError::InvalidKeyFile),
+        Some(k) => Zeroizing::new(k),
+        None => return Ok(None),
     };
-    let key_bytes = key_value.as_bytes().to_vec();
-
-    if key_version == Some("2.0".to_string()) {
-        // TODO we should also validate the integrity of a v2 keyfile using the hash value
 
-        let trimmed_key = key_value
-            .trim()
-            .replace(" ", "")
-            .replace("\n", "")
-            .replace("\r", "");
+    if key_version
+        .as_deref()
+        .map_or(false, |v| v.trim().starts_with("2."))
+    {
+        // KeePass and KeePassXC indent the key data with spaces and tabs
+        let trimmed_key: Zeroizing<String> =
+            Zeroizing::new(key_value.chars().filter(|c| !c.is_whitespace()).collect());
+        let key = hex::decode(trimmed_key.as_str()).map_err(|_| DatabaseKeyError::InvalidKeyFile)?;
+
+        // the hash is the start of the SHA-256 hash of the key, to detect typos in printed keyfiles
+        if let Some(key_hash) = key_hash {
+            let expected = hex::decode(key_hash.trim()).map_err(|_| DatabaseKeyError::InvalidKeyFile)?;
+            if !calculate_sha256(&[&key])?.starts_with(&expected) {
+                return Err(DatabaseKeyError::KeyFileHashMismatch);
+            }
+        }
 
-        return if let Ok(key) = hex::decode(&trimmed_key) {
-            Ok(key)
-        } else {
-            Ok(key_bytes)
-        };
+        return Ok(Some(key));
     }
 
     // Check if the key is base64-encoded. If yes, return decoded bytes
-    return if let Ok(key) = base64_engine::STANDARD.decode(&key_bytes) {
-        Ok(key)
+    let key_bytes = key_value.as_bytes();
+    return if let Ok(key) = base64_engine::STANDARD.decode(key_bytes) {
+        Ok(Some(key))
     } else {
-        Ok(key_bytes)
+        Ok(Some(key_bytes.to_vec()))
     };
 }
 
+/// Get the key from a keyfile, detecting its format like KeePass does: an XML keyfile of version
+/// 1.0 or 2.0, 32 bytes of binary key, 64 hexadecimal digits, or any other file, which is hashed
 fn parse_keyfile(buffer: &[u8]) -> Result<KeyElement, DatabaseKeyError> {
-    // try to parse the buffer as XML, if successful, use that data instead of full file
-    if let Ok(v) = parse_xml_keyfile(&buffer) {
-        Ok(v)
+    if let Some(key) = parse_xml_keyfile(buffer)? {
+        Ok(key)
     } else if buffer.len() == 32 {
         // legacy binary key format
         Ok(buffer.to_vec())
+    } else if buffer.len() == 64 && buffer.iter().all(u8::is_ascii_hexdigit) {
+        // legacy hexadecimal key format
+        Ok(hex::decode(buffer).map_err(|_| DatabaseKeyError::InvalidKeyFile)?)
     } else {
         Ok(calculate_sha256(&[&buffer])?.as_slice().to_vec())
     }
 }
 
+/// Name of the file in which KeePass keeps the key of the Windows user account
+#[cfg(all(feature = "windows", windows))]
+const USER_ACCOUNT_KEY_FILE_NAME: &str = "ProtectedUserKey.bin";
+
+/// The additional entropy with which KeePass encrypts the key of the Windows user account
+#[cfg(all(feature = "windows", windows))]
+const USER_ACCOUNT_KEY_ENTROPY: [u8; 16] = [
+    0xDE, 0x13, 0x5B, 0x5F, 0x18, 0xA3, 0x46, 0x70, 0xB2, 0x57, 0x24, 0x29, 0x69, 0x88, 0x98, 0xE6,
+];
+
+#[cfg(all(feature = "windows", windows))]
+mod dpapi {
+    use windows_sys::Win32::{
+        Foundation::LocalFree,
+        Security::Cryptography::{CryptUnprotectData, CRYPTPROTECT_UI_FORBIDDEN, CRYPT_INTEGER_BLOB},
+    };
+    use zeroize::Zeroizing;
+
+    use crate::error::DatabaseKeyError;
+
+    /// Decrypt `data` that was encrypted with DPAPI for the current user
+    pub(super) fn unprotect_data(data: &[u8], entropy: &[u8]) -> Result<Zeroizing<Vec<u8>>, DatabaseKeyError> {
+        let input = CRYPT_INTEGER_BLOB {
+            cbData: data.len() as u32,
+            pbData: data.as_ptr() as *mut u8,
+        };
+        let entropy = CRYPT_INTEGER_BLOB {
+            cbData: entropy.len() as u32,
+            pbData: entropy.as_ptr() as *mut u8,
+        };
+        let mut output = CRYPT_INTEGER_BLOB {
+            cbData: 0,
+            pbData: std::ptr::null_mut(),
+        };
+
+        // SAFETY: the input blobs point to live slices that DPAPI only reads, and the output blob
+        // is allocated by DPAPI and freed with LocalFree below
+        let success = unsafe {
+            CryptUnprotectData(
+                &input,
+                std::ptr::null_mut(),
+                &entropy,
+                std::ptr::null(),
+                std::ptr::null(),
+                CRYPTPROTECT_UI_FORBIDDEN,
+                &mut output,
+            )
+        };
+        if success == 0 {
+            return Err(DatabaseKeyError::UserAccountKeyError(
+                std::io::Error::last_os_error().to_string(),
+            ));
+        }
+
+        // SAFETY: on success, the output blob holds cbData bytes at pbData
+        unsafe {
+            let key =
+                Zeroizing::new(std::slice::from_raw_parts(output.pbData, output.cbData as usize).to_vec());
+            std::ptr::write_bytes(output.pbData, 0, output.cbData as usize);
+            LocalFree(output.pbData as _);
+            Ok(key)
+        }
+    }
+}
+
+/// A source of HMAC-SHA1 challenge-responses, e.g. a hardware token other than a YubiKey, that
+/// contributes to the database key through `ChallengeResponseKey::Provider`.
+///
+/// The challenge is the KDF seed of the database, which is replaced every time the database is
+/// saved. The SHA-256 hash of the response is added to the composite key after the password and
+/// the keyfile, like KeePassXC does.
 #[cfg(feature = "challenge_response")]
-#[derive(Debug, Clone, PartialEq, Zeroize, ZeroizeOnDrop)]
+pub trait ChallengeResponseProvider: std::fmt::Debug + Send + Sync {
+    /// Compute the response to `challenge`
+    fn challenge_response(&self, challenge: &[u8]) -> Result<Vec<u8>, DatabaseKeyError>;
+}
+
+#[cfg(feature = "challenge_response")]
+#[derive(Debug, Clone, Zeroize, ZeroizeOnDrop)]
 pub enum ChallengeResponseKey {
     LocalChallenge(String),
     YubikeyChallenge(Yubikey, String),
+    Provider(#[zeroize(skip)] Arc<dyn ChallengeResponseProvider>),
+}
+
+#[cfg(feature = "challenge_response")]
+impl PartialEq for ChallengeResponseKey {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (ChallengeResponseKey::LocalChallenge(a), ChallengeResponseKey::LocalChallenge(b)) => a == b,
+            (
+                ChallengeResponseKey::YubikeyChallenge(a, a_slot),
+                ChallengeResponseKey::YubikeyChallenge(b, b_slot),
+            ) => a == b && a_slot == b_slot,
+            (ChallengeResponseKey::Provider(a), ChallengeResponseKey::Provider(b)) => Arc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Zeroize, ZeroizeOnDrop)]
@@ -159,6 +283,7 @@ impl ChallengeResponseKey {
                     ))),
                 }
             }
+            ChallengeResponseKey::Provider(provider) => provider.challenge_response(challenge),
         }
     }
 
@@ -225,14 +350,22 @@ impl ChallengeResponseKey {
 }
 
 /// A KeePass key, which might consist of a password and/or a keyfile
+///
+/// The components are combined into the composite key in the order KeePass uses, no matter in
+/// which order they were added: password, keyfile or raw key, Windows user account,
+/// challenge-response.
 #[derive(Debug, Clone, Default, PartialEq, Zeroize, ZeroizeOnDrop)]
 pub struct DatabaseKey {
     password: Option<String>,
     keyfile: Option<Vec<u8>>,
+    raw_key: Option<Vec<u8>>,
+    #[cfg(feature = "windows")]
+    user_account_key: Option<Vec<u8>>,
     #[cfg(feature = "challenge_response")]
     challenge_response_key: Option<ChallengeResponseKey>,
     #[cfg(feature = "challenge_response")]
     challenge_response_result: Option<KeyElement>,
+    transformed_key: Option<TransformedKey>,
 }
 
 impl DatabaseKey {
@@ -264,6 +397,42 @@ impl DatabaseKey {
         Ok(self)
     }
 
+    /// Use `key` like the key stored in a keyfile, without parsing it as a keyfile, e.g. for keys
+    /// kept in a system keyring instead of a file. A 32 byte raw key opens the same databases as a
+    /// binary keyfile containing it.
+    pub fn with_raw_key(mut self, key: &[u8]) -> Self {
+        self.raw_key = Some(key.to_vec());
+        self
+    }
+
+    /// Add the Windows user account of the current user to the key, like the "Windows user
+    /// account" option of KeePass. KeePass keeps a random key for each user in
+    /// `%APPDATA%\KeePass\ProtectedUserKey.bin`, encrypted with DPAPI, which is decrypted here.
+    /// Only the same Windows user on the same machine, or with a restored user profile, can do
+    /// so.
+    #[cfg(all(feature = "windows", windows))]
+    pub fn with_windows_user_account(self) -> Result<Self, DatabaseKeyError> {
+        let appdata = std::env::var_os("APPDATA").ok_or_else(|| {
+            DatabaseKeyError::UserAccountKeyError("The APPDATA folder is not set".to_string())
+        })?;
+        let path = std::path::Path::new(&appdata)
+            .join("KeePass")
+            .join(USER_ACCOUNT_KEY_FILE_NAME);
+        let protected_key = std::fs::read(path)?;
+        let key = dpapi::unprotect_data(&protected_key, &USER_ACCOUNT_KEY_ENTROPY)?;
+
+        Ok(self.with_user_account_key(&key))
+    }
+
+    /// Use `key` like the key of a Windows user account, e.g. one that was decrypted from a
+    /// `ProtectedUserKey.bin` elsewhere, to open databases protected with the "Windows user
+    /// account" option of KeePass on any platform.
+    #[cfg(feature = "windows")]
+    pub fn with_user_account_key(mut self, key: &[u8]) -> Self {
+        self.user_account_key = Some(key.to_vec());
+        self
+    }
+
     #[cfg(feature = "challenge_response")]
     pub fn with_challenge_response_key(mut self, challenge_response_key: ChallengeResponseKey) -> Self {
         self.challenge_response_key = Some(challenge_response_key);
@@ -280,11 +449,51 @@ impl DatabaseKey {
         Ok(self)
     }
 
+    /// Use a key returned by `Database::parse_transformed` instead of running the KDF, for the
+    /// database it was derived for. See `TransformedKey`.
+    pub fn with_transformed_key(mut self, transformed_key: TransformedKey) -> Self {
+        self.transformed_key = Some(transformed_key);
+        self
+    }
+
     pub fn new() -> Self {
         Default::default()
     }
 
-    pub(crate) fn get_key_elements(&self) -> Result<KeyElements, DatabaseKeyError> {
+    /// The key without a transformed key, which has to be derived anew
+    pub(crate) fn without_transformed_key(mut self) -> Self {
+        self.transformed_key = None;
+        self
+    }
+
+    /// The transformed key, if it was derived with the given KDF settings and seed
+    pub(crate) fn transformed_key_for(
+        &self,
+        kdf_config: &KdfConfig,
+        kdf_seed: &[u8],
+        argon2_secret_parameters: &Argon2SecretParameters,
+    ) -> Option<&TransformedKey> {
+        self.transformed_key.as_ref().filter(|transformed| {
+            transformed.kdf_config == *kdf_config
+                && transformed.kdf_seed == kdf_seed
+                && transformed.argon2_secret_parameters == *argon2_secret_parameters
+        })
+    }
+
+    /// The transformed key, if it can be used to save a database with the given settings by
+    /// keeping its seed
+    #[cfg(feature = "save_kdbx4")]
+    pub(crate) fn transformed_key_for_saving(
+        &self,
+        config: &crate::config::DatabaseConfig,
+    ) -> Option<&TransformedKey> {
+        self.transformed_key.as_ref().filter(|transformed| {
+            transformed.kdf_config == config.kdf_config
+                && transformed.argon2_secret_parameters == config.argon2_secret_parameters
+        })
+    }
+
+    pub(crate) fn get_key_elements(&self) -> Result<Zeroizing<KeyElements>, DatabaseKeyError> {
         let mut out = Vec::new();
 
         if let Some(p) = &self.password {
@@ -295,6 +504,15 @@ impl DatabaseKey {
             out.push(parse_keyfile(f)?);
         }
 
+        if let Some(k) = &self.raw_key {
+            out.push(k.clone());
+        }
+
+        #[cfg(feature = "windows")]
+        if let Some(k) = &self.user_account_key {
+            out.push(k.clone());
+        }
+
         if out.is_empty() {
             return Err(DatabaseKeyError::IncorrectKey);
         }
@@ -308,12 +526,65 @@ impl DatabaseKey {
             ));
         }
 
-        Ok(out)
+        Ok(Zeroizing::new(out))
+    }
+
+    /// The composite key, which is the hash of all key elements and the input of the KDF
+    pub(crate) fn composite_key(&self) -> Result<Zeroizing<GenericArray<u8, U32>>, DatabaseKeyError> {
+        let key_elements = self.get_key_elements()?;
+        let key_elements: Vec<&[u8]> = key_elements.iter().map(|v| &v[..]).collect();
+        Ok(Zeroizing::new(calculate_sha256(&key_elements)?))
+    }
+
+    /// Estimate how well this key resists brute-force attacks when used with the given KDF
+    /// settings, e.g. to power a strength indicator while setting or changing the master key.
+    ///
+    /// Keyfiles are assumed to be random, so they contribute up to 256 bits depending on their
+    /// size. The total entropy is capped at 256 bits since all key material is hashed into a
+    /// 256 bit composite key.
+    pub fn strength_estimate(&self, kdf: &KdfConfig) -> KeyStrengthEstimate {
+        let mut key_entropy_bits = 0.0;
+
+        if let Some(p) = &self.password {
+            key_entropy_bits += estimate_password_entropy(p);
+        }
+
+        if let Some(f) = &self.keyfile {
+            key_entropy_bits += (8.0 * f.len() as f64).min(256.0);
+        }
+
+        if let Some(k) = &self.raw_key {
+            key_entropy_bits += (8.0 * k.len() as f64).min(256.0);
+        }
+
+        #[cfg(feature = "windows")]
+        if let Some(k) = &self.user_account_key {
+            key_entropy_bits += (8.0 * k.len() as f64).min(256.0);
+        }
+
+        // challenge-response keys are based on a 160 bit HMAC-SHA1 secret
+        #[cfg(feature = "challenge_response")]
+        if self.challenge_response_key.is_some() {
+            key_entropy_bits += 160.0;
+        }
+
+        KeyStrengthEstimate {
+            key_entropy_bits: key_entropy_bits.min(256.0),
+            kdf_work_bits: kdf_work_bits(kdf),
+        }
     }
 
     /// Returns true if the database key is not associated with any key component.
     pub fn is_empty(&self) -> bool {
-        if self.password.is_some() || self.keyfile.is_some() {
+        if self.password.is_some()
+            || self.keyfile.is_some()
+            || self.raw_key.is_some()
+            || self.transformed_key.is_some()
+        {
+            return false;
+        }
+        #[cfg(feature = "windows")]
+        if self.user_account_key.is_some() {
             return false;
         }
         #[cfg(feature = "challenge_response")]
@@ -324,12 +595,179 @@ impl DatabaseKey {
     }
 }
 
+/// The composite key of a KDBX 4 database after its key derivation function (KDF), together with
+/// the KDF settings and seed it was derived with
+///
+/// Deriving the key deliberately takes a long time. `Database::parse_transformed` returns the
+/// transformed key of the database it opened, and a `DatabaseKey` given it with
+/// `with_transformed_key` skips the KDF for as long as the database keeps these KDF settings and
+/// seed. Saving with such a key keeps the seed instead of generating a new one, so that e.g. a
+/// periodic autosave does not run the KDF either, while the master seed is still renewed on every
+/// save. For other databases, or once the KDF settings change, the key elements of the
+/// `DatabaseKey` are used as usual.
+///
+/// Anyone holding the transformed key can open the database, just like with the password, so it
+/// is wiped from memory when dropped and left out of `Debug` output.
+#[derive(Clone, PartialEq)]
+pub struct TransformedKey {
+    kdf_config: KdfConfig,
+    kdf_seed: Vec<u8>,
+    argon2_secret_parameters: Argon2SecretParameters,
+    key: Zeroizing<[u8; 32]>,
+}
+
+impl TransformedKey {
+    pub(crate) fn new(
+        kdf_config: KdfConfig,
+        kdf_seed: Vec<u8>,
+        argon2_secret_parameters: Argon2SecretParameters,
+        key: &[u8],
+    ) -> Self {
+        let mut bytes = Zeroizing::new([0u8; 32]);
+        bytes.copy_from_slice(key);
+        TransformedKey {
+            kdf_config,
+            kdf_seed,
+            argon2_secret_parameters,
+            key: bytes,
+        }
+    }
+
+    /// The KDF settings the key was derived with
+    pub fn kdf_config(&self) -> &KdfConfig {
+        &self.kdf_config
+    }
+
+    #[cfg(any(feature = "save_kdbx4", feature = "quick_unlock"))]
+    pub(crate) fn kdf_seed(&self) -> &[u8] {
+        &self.kdf_seed
+    }
+
+    #[cfg(feature = "quick_unlock")]
+    pub(crate) fn argon2_secret_parameters(&self) -> &Argon2SecretParameters {
+        &self.argon2_secret_parameters
+    }
+
+    pub(crate) fn as_bytes(&self) -> &[u8; 32] {
+        &self.key
+    }
+}
+
+impl Zeroize for TransformedKey {
+    fn zeroize(&mut self) {
+        self.key.zeroize();
+    }
+}
+
+impl std::fmt::Debug for TransformedKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TransformedKey")
+            .field("kdf_config", &self.kdf_config)
+            .field("key", &"<redacted>")
+            .finish()
+    }
+}
+
+/// Estimated resistance of a database key against brute-force attacks
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KeyStrengthEstimate {
+    /// Estimated entropy of the key material (password, keyfile, challenge-response secret) in bits
+    pub key_entropy_bits: f64,
+
+    /// Work that the key derivation function adds to every guess, expressed in bits
+    /// (i.e. the base 2 logarithm of the number of basic KDF operations per guess)
+    pub kdf_work_bits: f64,
+}
+
+impl KeyStrengthEstimate {
+    /// The total number of bits of work needed to exhaust the key space
+    pub fn total_bits(&self) -> f64 {
+        self.key_entropy_bits + self.kdf_work_bits
+    }
+
+    /// Expected time to find the key for an attacker that can perform `operations_per_second`
+    /// basic KDF operations per second, i.e. AES block encryptions for AES-KDF and 1 KiB memory
+    /// block compressions for Argon2. On average, half of the key space has to be searched.
+    ///
+    /// Returns `Duration::MAX` if the time does not fit into a `Duration`.
+    pub fn time_to_crack(&self, operations_per_second: f64) -> std::time::Duration {
+        let seconds = 2f64.powf(self.total_bits() - 1.0) / operations_per_second;
+        std::time::Duration::try_from_secs_f64(seconds).unwrap_or(std::time::Duration::MAX)
+    }
+}
+
+/// Estimate the entropy of a password in bits from its length and the character classes used.
+///
+/// Characters that repeat or continue a sequence (e.g. "aaa" or "123") add very little to the
+/// search space and only count as a single bit each.
+pub(crate) fn estimate_password_entropy(password: &str) -> f64 {
+    let mut pool_size = 0u32;
+    let has = |f: fn(&char) -> bool| password.chars().any(|c| f(&c));
+    if has(char::is_ascii_lowercase) {
+        pool_size += 26;
+    }
+    if has(char::is_ascii_uppercase) {
+        pool_size += 26;
+    }
+    if has(char::is_ascii_digit) {
+        pool_size += 10;
+    }
+    if has(|c| c.is_ascii() && !c.is_ascii_alphanumeric()) {
+        pool_size += 33;
+    }
+    if has(|c| !c.is_ascii()) {
+        pool_size += 100;
+    }
+
+    if pool_size == 0 {
+        return 0.0;
+    }
+
+    let bits_per_char = f64::from(pool_size).log2();
+    let mut entropy = 0.0;
+    let mut previous: Option<char> = None;
+    for c in password.chars() {
+        let predictable = match previous {
+            Some(p) => {
+                let distance = (c as i64) - (p as i64);
+                (-1..=1).contains(&distance)
+            }
+            None => false,
+        };
+
+        entropy += if predictable { 1.0 } else { bits_per_char };
+        previous = Some(c);
+    }
+
+    entropy
+}
+
+/// The base 2 logarithm of the number of basic operations that a KDF performs per guess
+fn kdf_work_bits(kdf: &KdfConfig) -> f64 {
+    let operations = match kdf {
+        // the KDF encrypts each half of the 32 byte key once per round
+        KdfConfig::Aes { rounds } => 2.0 * (*rounds as f64),
+        KdfConfig::Argon2 {
+            iterations, memory, ..
+        }
+        | KdfConfig::Argon2id {
+            iterations, memory, ..
+        } => (*iterations as f64) * ((*memory / 1024) as f64),
+        // the work of registered KDFs is unknown, so none is assumed
+        KdfConfig::Custom { .. } => 1.0,
+    };
+
+    operations.max(1.0).log2()
+}
+
 #[cfg(test)]
 mod key_tests {
 
-    use crate::error::DatabaseKeyError;
+    use base64::{engine::general_purpose as base64_engine, Engine as _};
+
+    use crate::{config::KdfConfig, crypt::calculate_sha256, error::DatabaseKeyError};
 
-    use super::DatabaseKey;
+    use super::{DatabaseKey, KeyStrengthEstimate};
 
     #[test]
     fn test_key() -> Result<(), DatabaseKeyError> {
@@ -386,6 +824,19 @@ mod key_tests {
             .get_key_elements()?;
         assert_eq!(ke.len(), 1);
 
+        let ke = DatabaseKey::new()
+            .with_password("asdf")
+            .with_raw_key(&[0x42; 32])
+            .get_key_elements()?;
+        assert_eq!(ke.len(), 2);
+        assert_eq!(
+            ke,
+            DatabaseKey::new()
+                .with_keyfile(&mut [0x42u8; 32].as_ref())?
+                .with_password("asdf")
+                .get_key_elements()?
+        );
+
         // other XML files will just be hashed as a "bare" keyfile
         let ke = DatabaseKey::new()
             .with_keyfile(&mut "<Not><A><KeyFile></KeyFile></A></Not>".as_bytes())?
@@ -396,14 +847,130 @@ mod key_tests {
         assert!(DatabaseKey {
             password: None,
             keyfile: None,
+            raw_key: None,
+            #[cfg(feature = "windows")]
+            user_account_key: None,
             #[cfg(feature = "challenge_response")]
             challenge_response_key: None,
             #[cfg(feature = "challenge_response")]
             challenge_response_result: None,
+            transformed_key: None,
         }
         .get_key_elements()
         .is_err());
 
         Ok(())
     }
+
+    #[cfg(feature = "windows")]
+    #[test]
+    fn test_user_account_key() -> Result<(), DatabaseKeyError> {
+        let user_key = [0x42u8; 64];
+        let ke = DatabaseKey::new()
+            .with_user_account_key(&user_key)
+            .with_password("asdf")
+            .with_raw_key(&[0x23; 32])
+            .get_key_elements()?;
+        assert_eq!(ke.len(), 3);
+        assert_eq!(ke[1], [0x23; 32]);
+        assert_eq!(ke[2], user_key);
+
+        // a user account alone is enough to open a database
+        let key = DatabaseKey::new().with_user_account_key(&user_key);
+        assert!(!key.is_empty());
+        assert_eq!(key.get_key_elements()?.len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_keyfile_formats() -> Result<(), DatabaseKeyError> {
+        let key: Vec<u8> = (0..32).collect();
+        let elements = |keyfile: &[u8]| {
+            DatabaseKey::new()
+                .with_keyfile(&mut &keyfile[..])
+                .unwrap()
+                .get_key_elements()
+        };
+
+        // 32 bytes of binary key
+        assert_eq!(elements(&key)?[0], key);
+
+        // 64 hexadecimal digits
+        assert_eq!(elements(hex::encode(&key).as_bytes())?[0], key);
+        assert_eq!(elements(hex::encode_upper(&key).as_bytes())?[0], key);
+
+        // XML version 1.0 with a base64-encoded key
+        let v1 = format!(
+            "<KeyFile><Meta><Version>1.00</Version></Meta><Key><Data>{}</Data></Key></KeyFile>",
+            base64_engine::STANDARD.encode(&key)
+        );
+        assert_eq!(elements(v1.as_bytes())?[0], key);
+
+        // XML version 2.0 with a hex-encoded key and the start of its hash
+        let hash = hex::encode_upper(&calculate_sha256(&[&key])?[..4]);
+        let v2 = |hash: &str| {
+            format!(
+                "<KeyFile><Meta><Version>2.0</Version></Meta><Key><Data Hash=\"{}\">\n{}\n</Data></Key></KeyFile>",
+                hash,
+                hex::encode_upper(&key)
+            )
+        };
+        assert_eq!(elements(v2(&hash).as_bytes())?[0], key);
+        assert!(matches!(
+            elements(v2("00000000").as_bytes()),
+            Err(DatabaseKeyError::KeyFileHashMismatch)
+        ));
+
+        // anything else is hashed
+        let other = hex::encode(&key[..31]);
+        assert_eq!(
+            elements(other.as_bytes())?[0],
+            calculate_sha256(&[other.as_bytes()])?.to_vec()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_strength_estimate() -> Result<(), std::io::Error> {
+        let kdf = KdfConfig::Aes { rounds: 1 << 19 };
+
+        let weak = DatabaseKey::new()
+            .with_password("aaaaaaaa")
+            .strength_estimate(&kdf);
+        let strong = DatabaseKey::new()
+            .with_password("c0rrect-H0rse-b4ttery-St4ple")
+            .strength_estimate(&kdf);
+        assert!(weak.key_entropy_bits < 15.0);
+        assert!(strong.key_entropy_bits > 100.0);
+        assert_eq!(weak.kdf_work_bits, 20.0);
+
+        let with_keyfile = DatabaseKey::new()
+            .with_password("aaaaaaaa")
+            .with_keyfile(&mut [0x42u8; 32].as_ref())?
+            .strength_estimate(&kdf);
+        assert_eq!(with_keyfile.key_entropy_bits, 256.0);
+
+        let argon2 = KdfConfig::Argon2 {
+            iterations: 2,
+            memory: 64 * 1024 * 1024,
+            parallelism: 2,
+            version: argon2::Version::Version13,
+        };
+        assert_eq!(DatabaseKey::new().strength_estimate(&argon2).kdf_work_bits, 17.0);
+
+        // 8 bits of entropy and 20 bits of KDF work need on average 2^27 operations
+        let estimate = KeyStrengthEstimate {
+            key_entropy_bits: 8.0,
+            kdf_work_bits: 20.0,
+        };
+        assert_eq!(
+            estimate.time_to_crack(f64::from(1 << 20)),
+            std::time::Duration::from_secs(128)
+        );
+        assert!(strong.time_to_crack(1e12) > std::time::Duration::from_secs(1000 * 365 * 24 * 3600));
+
+        Ok(())
+    }
 }
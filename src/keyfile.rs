@@ -0,0 +1,128 @@
+//! Creation of KeePass key files
+//!
+//! Key files in the XML format version 2.0 are what KeePass 2.47+ and KeePassXC create by default.
+//! They store the key as hex digits together with a hash of the key, so that typos made when
+//! copying a printed key file can be detected:
+//!
+//! ```
+//! use keepass::{keyfile, Database, DatabaseKey};
+//!
+//! let keyfile = keyfile::generate_xml_v2()?;
+//! let key = DatabaseKey::new().with_keyfile(&mut keyfile.as_slice())?;
+//!
+//! // keep the key file next to the database, e.g. with std::fs::write("db.keyx", &*keyfile)
+//! # #[cfg(feature = "save_kdbx4")]
+//! # {
+//! let db = Database::new(Default::default());
+//! let mut encrypted = Vec::new();
+//! db.save(&mut encrypted, key.clone())?;
+//! Database::open(&mut encrypted.as_slice(), key)?;
+//! # }
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::io::Write;
+
+use sha2::{Digest, Sha256};
+use zeroize::Zeroizing;
+
+/// Size of the keys in generated key files in bytes
+pub const KEY_SIZE: usize = 32;
+
+/// Generate a new key file in the XML format version 2.0 with a random key of `KEY_SIZE` bytes
+pub fn generate_xml_v2() -> Result<Zeroizing<Vec<u8>>, getrandom::Error> {
+    let mut key = Zeroizing::new(vec![0; KEY_SIZE]);
+    getrandom::fill(&mut key)?;
+
+    let mut out = Zeroizing::new(Vec::new());
+    write_xml_v2(&key, &mut *out).expect("writing to a Vec does not fail");
+    Ok(out)
+}
+
+/// Write `key` as a key file in the XML format version 2.0, laid out like the key files of
+/// KeePassXC with the key in groups of 4 bytes and the first 4 bytes of its SHA-256 hash
+pub fn write_xml_v2(key: &[u8], writer: &mut dyn Write) -> Result<(), std::io::Error> {
+    let hash = Sha256::digest(key);
+
+    let mut data = Zeroizing::new(String::new());
+    for (i, line) in key.chunks(16).enumerate() {
+        if i > 0 {
+            data.push('\n');
+        }
+        data.push_str("\t\t\t");
+        for (j, group) in line.chunks(4).enumerate() {
+            if j > 0 {
+                data.push(' ');
+            }
+            data.push_str(&Zeroizing::new(hex::encode_upper(group)));
+        }
+    }
+
+    write!(
+        writer,
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+         <KeyFile>\n\
+         \t<Meta>\n\
+         \t\t<Version>2.0</Version>\n\
+         \t</Meta>\n\
+         \t<Key>\n\
+         \t\t<Data Hash=\"{}\">\n\
+         {}\n\
+         \t\t</Data>\n\
+         \t</Key>\n\
+         </KeyFile>\n",
+        hex::encode_upper(&hash[..4]),
+        &*data
+    )
+}
+
+#[cfg(test)]
+mod keyfile_tests {
+    use super::{generate_xml_v2, write_xml_v2, KEY_SIZE};
+    use crate::DatabaseKey;
+
+    #[test]
+    fn test_write_xml_v2() {
+        let key = hex::decode("36057B1C35037FD962257893C0A22403EE3F8FBB504D998108B821CB00D28F89").unwrap();
+        let mut out = Vec::new();
+        write_xml_v2(&key, &mut out).unwrap();
+
+        assert_eq!(
+            String::from_utf8(out.clone()).unwrap(),
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+             <KeyFile>\n\
+             \t<Meta>\n\
+             \t\t<Version>2.0</Version>\n\
+             \t</Meta>\n\
+             \t<Key>\n\
+             \t\t<Data Hash=\"A65F0C2D\">\n\
+             \t\t\t36057B1C 35037FD9 62257893 C0A22403\n\
+             \t\t\tEE3F8FBB 504D9981 08B821CB 00D28F89\n\
+             \t\t</Data>\n\
+             \t</Key>\n\
+             </KeyFile>\n"
+        );
+
+        let elements = DatabaseKey::new()
+            .with_keyfile(&mut out.as_slice())
+            .unwrap()
+            .get_key_elements()
+            .unwrap();
+        assert_eq!(*elements, vec![key]);
+    }
+
+    #[test]
+    fn test_generate_xml_v2() {
+        let first = generate_xml_v2().unwrap();
+        let second = generate_xml_v2().unwrap();
+        assert_ne!(first, second);
+
+        let elements = DatabaseKey::new()
+            .with_keyfile(&mut first.as_slice())
+            .unwrap()
+            .get_key_elements()
+            .unwrap();
+        assert_eq!(elements.len(), 1);
+        assert_eq!(elements[0].len(), KEY_SIZE);
+    }
+}
@@ -1,20 +1,61 @@
 #![doc = include_str!("../README.md")]
 #![recursion_limit = "1024"]
 
+pub mod analysis;
+#[cfg(feature = "autosave")]
+pub mod autosave;
+pub mod commands;
+#[cfg(feature = "compat-0x")]
+pub mod compat;
 mod compression;
 pub mod config;
 pub(crate) mod crypt;
 pub mod db;
 pub mod error;
+pub mod export;
+#[cfg(feature = "ffi")]
+pub mod ffi;
 pub(crate) mod format;
+pub mod generator;
+#[cfg(feature = "git_credential")]
+pub mod git_credential;
 pub(crate) mod hmac_block_stream;
+pub mod import;
 #[cfg(feature = "save_kdbx4")]
 mod io;
+#[cfg(feature = "journal")]
+pub mod journal;
+#[cfg(feature = "keeshare")]
+pub mod keeshare;
 mod key;
+pub mod keyfile;
+pub mod lock;
+pub mod placeholders;
+pub mod prelude;
+pub mod progress;
+#[cfg(feature = "quick_unlock")]
+pub mod quick_unlock;
+pub mod redact;
+pub mod report;
+#[cfg(feature = "search_cache")]
+pub mod search_cache;
+#[cfg(feature = "service")]
+pub mod service;
+#[cfg(feature = "ssh")]
+pub mod ssh;
+pub mod table;
+pub mod url_match;
 pub(crate) mod variant_dictionary;
+#[cfg(feature = "notify")]
+pub mod watch;
 pub(crate) mod xml_db;
 
+pub use self::crypt::cipher_registry::{
+    register_outer_cipher, unregister_outer_cipher, CustomCipher, CustomCipherStream,
+};
+pub use self::crypt::kdf_registry::{register_kdf, unregister_kdf, Kdf, KdfParameters, KDF_SEED_PARAMETER};
+pub use self::crypt::key_cache::{clear_key_cache, set_key_cache_capacity, DEFAULT_KEY_CACHE_CAPACITY};
 pub use self::db::Database;
 #[cfg(feature = "challenge_response")]
-pub use self::key::ChallengeResponseKey;
-pub use self::key::DatabaseKey;
+pub use self::key::{ChallengeResponseKey, ChallengeResponseProvider};
+pub use self::key::{DatabaseKey, KeyStrengthEstimate, TransformedKey};
@@ -0,0 +1,487 @@
+//! Advisory lock files next to a database
+//!
+//! Desktop clients create a lock file next to an open database to signal other programs that
+//! the database is being edited. When a client crashes, the lock file is left behind. A
+//! `DatabaseLock` detects such stale locks and decides whether to take them over according to a
+//! `TakeoverPolicy`.
+//!
+//! Lock files follow the convention of KeePass 2, which creates `<database>.lock` with a header
+//! line, a random id, the time in UTC, the user, the machine and the domain, each on a line of its
+//! own. KeePass and this crate thus see each other's locks. The id of the process is appended on a
+//! line of its own, which KeePass ignores, so that locks left behind by crashed processes on this
+//! host are recognized as stale right away on Linux.
+//!
+//! ```no_run
+//! use keepass::lock::{DatabaseLock, LockOptions, TakeoverPolicy};
+//!
+//! let options = LockOptions {
+//!     policy: TakeoverPolicy::Confirm(Box::new(|info, stale| {
+//!         println!("Locked by {}@{} (pid {}, stale: {})", info.user, info.host, info.pid, stale);
+//!         stale
+//!     })),
+//!     ..Default::default()
+//! };
+//!
+//! let lock = DatabaseLock::acquire("my_database.kdbx", options)?;
+//! // ... edit and save the database ...
+//! drop(lock);
+//! # Ok::<(), keepass::lock::LockError>(())
+//! ```
+
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use base64::{engine::general_purpose as base64_engine, Engine as _};
+use chrono::NaiveDateTime;
+use thiserror::Error;
+
+use crate::db::{Database, Times};
+
+/// The first line of lock files written by KeePass 2
+const LOCK_FILE_HEADER: &str = "KeePass Lock File";
+
+/// Errors while acquiring a database lock
+#[derive(Debug, Error)]
+pub enum LockError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    /// The database is locked by another process, and the lock was not taken over
+    #[error("Database is locked by {} on {} (pid {})", _0.user, _0.host, _0.pid)]
+    Locked(LockInfo),
+}
+
+/// Information about the process holding a lock
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LockInfo {
+    /// Random id telling apart the locks of different processes
+    pub id: String,
+
+    /// Id of the process holding the lock, or 0 if it is not known, e.g. for locks of KeePass
+    pub pid: u32,
+
+    pub user: String,
+    pub host: String,
+
+    /// Windows domain of the user, if any
+    pub domain: String,
+
+    pub created: NaiveDateTime,
+}
+
+impl LockInfo {
+    fn current() -> Self {
+        LockInfo {
+            id: base64_engine::STANDARD.encode(uuid::Uuid::new_v4().as_bytes()),
+            pid: current_pid(),
+            user: current_user(),
+            host: current_host(),
+            domain: std::env::var("USERDOMAIN").unwrap_or_default(),
+            created: Times::now(),
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        let lines: Vec<&str> = s.lines().map(str::trim).collect();
+        match lines.first() {
+            Some(header) if header.starts_with(LOCK_FILE_HEADER) => Self::parse_keepass(&lines[1..]),
+            _ => None,
+        }
+    }
+
+    /// Parse a lock file in the format of KeePass 2, optionally followed by the process id
+    fn parse_keepass(lines: &[&str]) -> Option<Self> {
+        if lines.len() < 5 {
+            return None;
+        }
+        let created = lines[1].trim_end_matches('Z');
+
+        Some(LockInfo {
+            id: lines[0].to_string(),
+            pid: lines
+                .get(5)
+                .and_then(|line| line.strip_prefix("pid="))
+                .and_then(|pid| pid.parse().ok())
+                .unwrap_or(0),
+            user: lines[2].to_string(),
+            host: lines[3].to_string(),
+            domain: lines[4].to_string(),
+            created: NaiveDateTime::parse_from_str(created, "%Y-%m-%dT%H:%M:%S%.f").ok()?,
+        })
+    }
+
+    fn serialize(&self) -> String {
+        format!(
+            "{}\n{}\n{}\n{}\n{}\n{}\npid={}\n",
+            LOCK_FILE_HEADER,
+            self.id,
+            self.created.format("%Y-%m-%dT%H:%M:%SZ"),
+            self.user,
+            self.host,
+            self.domain,
+            self.pid,
+        )
+    }
+
+    /// Whether the lock was most likely left behind by a process that no longer runs.
+    ///
+    /// A lock is stale if it is older than `max_age`, or if it was created by the current user on
+    /// this host by a process that is no longer alive. Whether a process is alive is only known on
+    /// Linux. Elsewhere, and for locks held by other users or hosts or not recording their process,
+    /// locks only become stale by age.
+    pub fn is_stale(&self, max_age: chrono::Duration) -> bool {
+        if Times::now() - self.created > max_age {
+            return true;
+        }
+
+        self.pid != 0
+            && self.user == current_user()
+            && self.host == current_host()
+            && process_is_alive(self.pid) == Some(false)
+    }
+}
+
+/// A callback deciding whether to take over a lock, given the current lock holder and whether
+/// the lock is stale
+pub type TakeoverConfirmation = Box<dyn Fn(&LockInfo, bool) -> bool>;
+
+/// What to do when a database is already locked
+pub enum TakeoverPolicy {
+    /// Never take over an existing lock
+    Never,
+
+    /// Take over locks that are stale
+    IfStale,
+
+    /// Ask a callback whether to take over the lock
+    Confirm(TakeoverConfirmation),
+
+    /// Always take over existing locks
+    Always,
+}
+
+/// Options for acquiring a database lock
+pub struct LockOptions {
+    pub policy: TakeoverPolicy,
+
+    /// Locks older than this are considered stale
+    pub max_age: chrono::Duration,
+}
+
+impl Default for LockOptions {
+    fn default() -> Self {
+        LockOptions {
+            policy: TakeoverPolicy::IfStale,
+            max_age: chrono::Duration::hours(24),
+        }
+    }
+}
+
+/// An advisory lock on a database file, released when dropped
+#[derive(Debug)]
+pub struct DatabaseLock {
+    path: PathBuf,
+    info: LockInfo,
+}
+
+impl DatabaseLock {
+    /// Acquire the lock for the database at `database_path`, taking over an existing lock if the
+    /// policy allows it.
+    pub fn acquire(database_path: impl AsRef<Path>, options: LockOptions) -> Result<Self, LockError> {
+        let path = lock_path(database_path.as_ref());
+
+        loop {
+            let info = LockInfo::current();
+            match std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&path)
+            {
+                Ok(mut file) => {
+                    file.write_all(info.serialize().as_bytes())?;
+                    return Ok(DatabaseLock { path, info });
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {}
+                Err(e) => return Err(e.into()),
+            }
+
+            let existing = match std::fs::read_to_string(&path) {
+                Ok(content) => content,
+                // the lock was released in the meantime
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+
+            // unreadable lock files are treated as if they were created by an unknown, dead
+            // process a long time ago
+            let holder = LockInfo::parse(&existing).unwrap_or(LockInfo {
+                id: String::new(),
+                pid: 0,
+                user: String::new(),
+                host: String::new(),
+                domain: String::new(),
+                created: Times::epoch(),
+            });
+            let stale = holder.is_stale(options.max_age);
+
+            let take_over = match &options.policy {
+                TakeoverPolicy::Never => false,
+                TakeoverPolicy::IfStale => stale,
+                TakeoverPolicy::Confirm(confirm) => confirm(&holder, stale),
+                TakeoverPolicy::Always => true,
+            };
+
+            if !take_over {
+                return Err(LockError::Locked(holder));
+            }
+
+            match std::fs::remove_file(&path) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Information about this lock as written to the lock file
+    pub fn info(&self) -> &LockInfo {
+        &self.info
+    }
+
+    /// The path of the lock file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Whether the lock file still belongs to this lock, i.e. it was neither removed nor taken
+    /// over by another process in the meantime
+    pub fn is_held(&self) -> bool {
+        std::fs::read_to_string(&self.path)
+            .ok()
+            .and_then(|content| LockInfo::parse(&content))
+            .as_ref()
+            == Some(&self.info)
+    }
+
+    /// Release the lock, removing the lock file unless it was taken over in the meantime. Unlike
+    /// dropping the lock, this reports when the lock file cannot be removed.
+    pub fn release(self) -> Result<(), LockError> {
+        if self.is_held() {
+            match std::fs::remove_file(&self.path) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for DatabaseLock {
+    fn drop(&mut self) {
+        // only remove the lock file if it was not taken over by someone else in the meantime
+        if self.is_held() {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+impl Database {
+    /// Lock the database at `path` for editing like KeePass 2 does, taking over stale locks. When
+    /// another client has the database open, this fails with `LockError::Locked` telling who.
+    pub fn acquire_lock(path: impl AsRef<Path>) -> Result<DatabaseLock, LockError> {
+        DatabaseLock::acquire(path, LockOptions::default())
+    }
+
+    /// Release a lock acquired with `acquire_lock`, see `DatabaseLock::release`
+    pub fn release_lock(lock: DatabaseLock) -> Result<(), LockError> {
+        lock.release()
+    }
+}
+
+/// The lock file for a database, e.g. `passwords.kdbx.lock` for `passwords.kdbx`
+pub fn lock_path(database_path: &Path) -> PathBuf {
+    let mut path = database_path.as_os_str().to_owned();
+    path.push(".lock");
+    PathBuf::from(path)
+}
+
+/// The id of this process, or 0 in the browser, which has no processes
+fn current_pid() -> u32 {
+    #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+    {
+        std::process::id()
+    }
+
+    #[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+    {
+        0
+    }
+}
+
+fn current_user() -> String {
+    std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .unwrap_or_default()
+}
+
+fn current_host() -> String {
+    std::env::var("HOSTNAME")
+        .or_else(|_| std::env::var("COMPUTERNAME"))
+        .ok()
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .map(|h| h.trim().to_string())
+        .unwrap_or_default()
+}
+
+/// Check whether a process is running, which is only known on Linux
+#[cfg(target_os = "linux")]
+fn process_is_alive(pid: u32) -> Option<bool> {
+    Some(Path::new(&format!("/proc/{}", pid)).exists())
+}
+
+/// Check whether a process is running, which is only known on Linux
+#[cfg(not(target_os = "linux"))]
+fn process_is_alive(_pid: u32) -> Option<bool> {
+    None
+}
+
+#[cfg(test)]
+mod lock_tests {
+    use std::path::PathBuf;
+
+    use chrono::Timelike;
+
+    use crate::db::{Database, Times};
+
+    use super::{lock_path, DatabaseLock, LockError, LockInfo, LockOptions, TakeoverPolicy};
+
+    fn temp_database_path() -> PathBuf {
+        std::env::temp_dir().join(format!("keepass-lock-{}.kdbx", uuid::Uuid::new_v4()))
+    }
+
+    fn write_foreign_lock(database_path: &PathBuf, info: &LockInfo) {
+        std::fs::write(lock_path(database_path), info.serialize()).unwrap();
+    }
+
+    #[test]
+    fn test_acquire_and_release() {
+        let path = temp_database_path();
+
+        let lock = DatabaseLock::acquire(&path, LockOptions::default()).unwrap();
+        assert!(lock.path().exists());
+        assert_eq!(lock.info().pid, std::process::id());
+
+        // our own process is alive, so the lock is not stale
+        assert!(matches!(
+            DatabaseLock::acquire(&path, LockOptions::default()),
+            Err(LockError::Locked(_))
+        ));
+
+        let lock_file = lock.path().to_path_buf();
+        drop(lock);
+        assert!(!lock_file.exists());
+    }
+
+    #[test]
+    fn test_stale_lock_takeover() {
+        let path = temp_database_path();
+
+        // a lock left behind long ago by another user on another host
+        let old_lock = LockInfo {
+            id: "b2xkLWxvY2s=".to_string(),
+            pid: 1,
+            user: "someone-else".to_string(),
+            host: "elsewhere".to_string(),
+            domain: String::new(),
+            created: Times::now().with_nanosecond(0).unwrap() - chrono::Duration::days(2),
+        };
+        write_foreign_lock(&path, &old_lock);
+        assert!(old_lock.is_stale(chrono::Duration::hours(24)));
+
+        let never = LockOptions {
+            policy: TakeoverPolicy::Never,
+            ..Default::default()
+        };
+        match DatabaseLock::acquire(&path, never) {
+            Err(LockError::Locked(info)) => assert_eq!(info, old_lock),
+            _ => panic!("Expected the database to be locked"),
+        }
+
+        let lock = DatabaseLock::acquire(&path, LockOptions::default()).unwrap();
+        drop(lock);
+        assert!(!lock_path(&path).exists());
+    }
+
+    #[test]
+    fn test_confirm_takeover() {
+        let path = temp_database_path();
+
+        let recent_lock = LockInfo {
+            id: "cmVjZW50LWxvY2s=".to_string(),
+            pid: 1,
+            user: "someone-else".to_string(),
+            host: "elsewhere".to_string(),
+            domain: String::new(),
+            created: Times::now(),
+        };
+        write_foreign_lock(&path, &recent_lock);
+        assert!(!recent_lock.is_stale(chrono::Duration::hours(24)));
+
+        let decline = LockOptions {
+            policy: TakeoverPolicy::Confirm(Box::new(|info, stale| {
+                assert_eq!(info.user, "someone-else");
+                assert!(!stale);
+                false
+            })),
+            ..Default::default()
+        };
+        assert!(DatabaseLock::acquire(&path, decline).is_err());
+
+        let accept = LockOptions {
+            policy: TakeoverPolicy::Confirm(Box::new(|_, _| true)),
+            ..Default::default()
+        };
+        let lock = DatabaseLock::acquire(&path, accept).unwrap();
+        assert_eq!(lock.info().pid, std::process::id());
+    }
+
+    #[test]
+    fn test_keepass_lock_files() {
+        let path = temp_database_path();
+
+        // a lock file as written by KeePass 2, which does not record the process
+        std::fs::write(
+            lock_path(&path),
+            "KeePass Lock File\r\nAAECAwQFBgcICQoLDA0ODw==\r\n2024-01-01T12:00:00Z\r\nalice\r\nDESKTOP\r\nWORKGROUP\r\n",
+        )
+        .unwrap();
+        let held_by_keepass = LockOptions {
+            policy: TakeoverPolicy::Never,
+            ..Default::default()
+        };
+        match DatabaseLock::acquire(&path, held_by_keepass) {
+            Err(LockError::Locked(info)) => {
+                assert_eq!(info.id, "AAECAwQFBgcICQoLDA0ODw==");
+                assert_eq!((info.user.as_str(), info.host.as_str()), ("alice", "DESKTOP"));
+                assert_eq!(info.domain, "WORKGROUP");
+                assert_eq!(info.pid, 0);
+            }
+            _ => panic!("Expected the database to be locked"),
+        }
+
+        // the lock is a day old by now, so it is taken over
+        let lock = Database::acquire_lock(&path).unwrap();
+        let content = std::fs::read_to_string(lock.path()).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines[0], "KeePass Lock File");
+        assert_eq!(lines[1], lock.info().id);
+        assert!(lines[2].ends_with('Z'));
+        assert_eq!(LockInfo::parse(&content).as_ref(), Some(lock.info()));
+
+        Database::release_lock(lock).unwrap();
+        assert!(!lock_path(&path).exists());
+    }
+}
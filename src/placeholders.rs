@@ -0,0 +1,302 @@
+//! Expansion of KeePass placeholders
+//!
+//! Auto-Type sequences, URL overrides and custom strings may contain placeholders like `{TITLE}`,
+//! `{URL:HOST}`, `{S:Field}`, `{DT_SIMPLE}` or `{REF:P@I:<uuid>}`, which KeePass and KeePassXC
+//! replace with values from the entry, the database and the current time. `expand` does the same,
+//! so that these strings can be evaluated outside of KeePass:
+//!
+//! ```
+//! use keepass::{db::{Entry, Value}, placeholders::expand, Database};
+//!
+//! let db = Database::new(Default::default());
+//! let mut entry = Entry::new();
+//! entry.fields.insert("UserName".to_string(), Value::Unprotected("jdoe".to_string()));
+//! entry.fields.insert("URL".to_string(), Value::Unprotected("https://mail.example.com:8443/login".to_string()));
+//!
+//! assert_eq!(expand(&entry, "{USERNAME}@{URL:HOST}{TAB}{PASSWORD}{ENTER}", &db), "jdoe@mail.example.com{TAB}{ENTER}");
+//! assert_eq!(expand(&entry, "{URL:SCM}, port {URL:PORT}", &db), "https, port 8443");
+//! ```
+//!
+//! Placeholders are matched ignoring case. Unknown placeholders, like the special keys of
+//! Auto-Type sequences, are kept as they are. Fields that contain placeholders themselves are
+//! expanded as well, up to a depth of `MAX_DEPTH`.
+
+use chrono::{Datelike, NaiveDateTime, TimeZone, Timelike};
+
+use crate::{
+    db::{Database, Entry, Group, Node, Times},
+    url_match::{default_port, UrlComponents},
+};
+
+/// How deep placeholders in the values of placeholders are expanded, which also stops fields from
+/// expanding themselves endlessly
+pub const MAX_DEPTH: usize = 10;
+
+/// Expand the placeholders in `template` for `entry`, which is looked up in `db` for references,
+/// its group and the name of the database
+pub fn expand(entry: &Entry, template: &str, db: &Database) -> String {
+    expand_with_depth(entry, template, db, 0)
+}
+
+fn expand_with_depth(entry: &Entry, template: &str, db: &Database, depth: usize) -> String {
+    if depth >= MAX_DEPTH {
+        return template.to_string();
+    }
+
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        let placeholder = &rest[start..];
+
+        let end = match placeholder[1..].find('}') {
+            Some(end) => end + 1,
+            None => {
+                rest = placeholder;
+                break;
+            }
+        };
+
+        match resolve(entry, &placeholder[1..end], db, depth) {
+            Some(value) => out.push_str(&value),
+            None => out.push_str(&placeholder[..=end]),
+        }
+        rest = &placeholder[end + 1..];
+    }
+
+    out.push_str(rest);
+    out
+}
+
+/// The value of a single placeholder, given without braces, or `None` if it is unknown
+fn resolve(entry: &Entry, name: &str, db: &Database, depth: usize) -> Option<String> {
+    let field = |field: &str| -> Option<String> {
+        let value = entry.get_protected(field);
+        Some(expand_with_depth(
+            entry,
+            value.as_ref().and_then(|v| v.as_str()).unwrap_or_default(),
+            db,
+            depth + 1,
+        ))
+    };
+    let upper = name.to_ascii_uppercase();
+
+    if let Some(custom) = strip_prefix_ignore_case(name, "S:") {
+        return field(custom);
+    }
+    if upper.starts_with("REF:") {
+        return crate::db::references::resolve_text(&format!("{{{}}}", name), db, &mut Vec::new()).ok();
+    }
+    if let Some(part) = upper.strip_prefix("URL:") {
+        let url = field("URL")?;
+        return url_part(&url, part);
+    }
+    if let Some(part) = upper.strip_prefix("DT_UTC_") {
+        return date_part(Times::now(), part);
+    }
+    if let Some(part) = upper.strip_prefix("DT_") {
+        let local = chrono::Local.from_utc_datetime(&Times::now()).naive_local();
+        return date_part(local, part);
+    }
+
+    match upper.as_str() {
+        "TITLE" => field("Title"),
+        "USERNAME" => field("UserName"),
+        "PASSWORD" => field("Password"),
+        "URL" => field("URL"),
+        "NOTES" => field("Notes"),
+        "UUID" => Some(entry.uuid.simple().to_string().to_uppercase()),
+        "GROUP" => parent_groups(&db.root, entry)
+            .map(|path| path.last().map_or(db.root.name.clone(), |g| g.name.clone())),
+        "GROUP_PATH" => parent_groups(&db.root, entry)
+            .map(|path| path.iter().map(|g| g.name.as_str()).collect::<Vec<_>>().join("/")),
+        "DB_NAME" => Some(db.meta.database_name.clone().unwrap_or_default()),
+        _ => None,
+    }
+}
+
+fn strip_prefix_ignore_case<'a>(text: &'a str, prefix: &str) -> Option<&'a str> {
+    match text.get(..prefix.len()) {
+        Some(start) if start.eq_ignore_ascii_case(prefix) => Some(&text[prefix.len()..]),
+        _ => None,
+    }
+}
+
+/// The groups containing `entry` below the root group, outermost first
+pub(crate) fn parent_groups<'a>(group: &'a Group, entry: &Entry) -> Option<Vec<&'a Group>> {
+    for child in &group.children {
+        match child {
+            Node::Entry(e) if e.uuid == entry.uuid => return Some(Vec::new()),
+            Node::Group(g) => {
+                if let Some(mut path) = parent_groups(g, entry) {
+                    path.insert(0, g);
+                    return Some(path);
+                }
+            }
+            Node::Entry(_) => {}
+        }
+    }
+    None
+}
+
+/// A part of a URL, for `{URL:<part>}`
+fn url_part(url: &str, part: &str) -> Option<String> {
+    let components = UrlComponents::split(url);
+    let scheme = components.scheme.unwrap_or_default();
+    let rest = match components.scheme {
+        Some(scheme) => &url[scheme.len() + 3..],
+        None => url,
+    };
+    let userinfo = components.userinfo.unwrap_or_default();
+    let (host, port, path, query) = (
+        components.host,
+        components.port,
+        components.path,
+        components.query,
+    );
+
+    let value = match part {
+        "RMVSCM" => rest.to_string(),
+        "SCM" => scheme.to_string(),
+        "HOST" => host.to_string(),
+        "PORT" => match port {
+            Some(port) => port.to_string(),
+            None => default_port(scheme).map(|p| p.to_string()).unwrap_or_default(),
+        },
+        "PATH" => path.to_string(),
+        "QUERY" => query.to_string(),
+        "USERINFO" => userinfo.to_string(),
+        "USERNAME" => userinfo.split(':').next().unwrap_or_default().to_string(),
+        "PASSWORD" => userinfo
+            .split_once(':')
+            .map(|(_, p)| p)
+            .unwrap_or_default()
+            .to_string(),
+        _ => return None,
+    };
+    Some(value)
+}
+
+/// A part of a date, for `{DT_<part>}` and `{DT_UTC_<part>}`
+fn date_part(time: NaiveDateTime, part: &str) -> Option<String> {
+    let value = match part {
+        "SIMPLE" => time.format("%Y%m%d%H%M%S").to_string(),
+        "YEAR" => format!("{:04}", time.year()),
+        "MONTH" => format!("{:02}", time.month()),
+        "DAY" => format!("{:02}", time.day()),
+        "HOUR" => format!("{:02}", time.hour()),
+        "MINUTE" => format!("{:02}", time.minute()),
+        "SECOND" => format!("{:02}", time.second()),
+        _ => return None,
+    };
+    Some(value)
+}
+
+#[cfg(test)]
+mod placeholders_tests {
+    use chrono::NaiveDate;
+
+    use super::expand;
+    use crate::db::{with_clock, Database, Entry, Group, Value};
+
+    fn set(entry: &mut Entry, field: &str, value: &str) {
+        entry
+            .fields
+            .insert(field.to_string(), Value::Unprotected(value.to_string()));
+    }
+
+    #[test]
+    fn test_entry_fields() {
+        let mut db = Database::new(Default::default());
+        db.meta.database_name = Some("Personal".to_string());
+
+        let mut target = Entry::new();
+        set(&mut target, "Title", "Mail");
+        set(&mut target, "Password", "secret");
+        let target_uuid = target.uuid;
+
+        let mut entry = Entry::new();
+        set(&mut entry, "Title", "Alias of {REF:T@I:placeholder}");
+        set(&mut entry, "UserName", "jdoe");
+        set(
+            &mut entry,
+            "Password",
+            &format!("{{REF:P@I:{}}}", target_uuid.simple()),
+        );
+        set(&mut entry, "PIN", "{S:PIN}");
+        set(&mut entry, "Extra", "{USERNAME}!");
+        let entry_uuid = entry.uuid;
+
+        let mut work = Group::new("Work");
+        let mut mail = Group::new("Mail");
+        mail.add_child(entry.clone());
+        work.add_child(mail);
+        db.root.add_child(work);
+        db.root.add_child(target);
+
+        assert_eq!(
+            expand(
+                &entry,
+                "{title} {UserName}:{PASSWORD} {S:Extra} {S:Missing}.",
+                &db
+            ),
+            "Alias of {REF:T@I:placeholder} jdoe:secret jdoe! ."
+        );
+        assert_eq!(
+            expand(&entry, "{GROUP} in {GROUP_PATH} of {DB_NAME}", &db),
+            "Mail in Work/Mail of Personal"
+        );
+        assert_eq!(
+            expand(&entry, "{UUID}", &db),
+            entry_uuid.simple().to_string().to_uppercase()
+        );
+
+        // fields expanding themselves stop at the maximum depth, unknown placeholders are kept
+        assert_eq!(expand(&entry, "{s:PIN}", &db), "{S:PIN}");
+        assert_eq!(
+            expand(&entry, "{UNKNOWN} {DELAY 100}{}} {", &db),
+            "{UNKNOWN} {DELAY 100}{}} {"
+        );
+    }
+
+    #[test]
+    fn test_url_and_dates() {
+        let db = Database::new(Default::default());
+        let mut entry = Entry::new();
+        set(
+            &mut entry,
+            "URL",
+            "https://user:pw@mail.example.com/inbox?folder=1#top",
+        );
+
+        assert_eq!(
+            expand(
+                &entry,
+                "{URL:SCM}|{URL:HOST}|{URL:PORT}|{URL:PATH}|{URL:QUERY}|{URL:USERINFO}|{URL:USERNAME}|{URL:PASSWORD}",
+                &db
+            ),
+            "https|mail.example.com|443|/inbox|?folder=1|user:pw|user|pw"
+        );
+        assert_eq!(
+            expand(&entry, "{URL:RMVSCM}", &db),
+            "user:pw@mail.example.com/inbox?folder=1#top"
+        );
+
+        let time = NaiveDate::from_ymd_opt(2024, 2, 3)
+            .unwrap()
+            .and_hms_opt(4, 5, 6)
+            .unwrap();
+        let expanded = with_clock(
+            move || time,
+            || {
+                expand(
+                    &entry,
+                    "{DT_UTC_SIMPLE} {DT_UTC_YEAR}-{DT_UTC_MONTH}-{DT_UTC_DAY} {DT_UTC_HOUR}:{DT_UTC_MINUTE}:{DT_UTC_SECOND}",
+                    &db,
+                )
+            },
+        );
+        assert_eq!(expanded, "20240203040506 2024-02-03 04:05:06");
+    }
+}
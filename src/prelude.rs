@@ -0,0 +1,25 @@
+//! Commonly used types in one import
+//!
+//! ```
+//! use keepass::prelude::*;
+//!
+//! let mut db = Database::new(DatabaseConfig::default());
+//! db.root.add_child(Entry::new());
+//! # let _ = DatabaseKey::new();
+//! ```
+//!
+//! The prelude is versioned: `prelude::v1` only ever gains names, so a glob import of it keeps
+//! compiling across releases. Names that are renamed or removed go into the next version of the
+//! prelude instead, and `prelude` itself re-exports the latest one.
+
+pub use self::v1::*;
+
+/// The first version of the prelude
+pub mod v1 {
+    pub use crate::{
+        config::DatabaseConfig,
+        db::{Entry, Group, Meta, Node, NodeRef, NodeRefMut, Times, Value},
+        error::{DatabaseKeyError, DatabaseOpenError, DatabaseSaveError},
+        Database, DatabaseKey,
+    };
+}
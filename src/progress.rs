@@ -0,0 +1,404 @@
+//! Progress reports and cancellation for opening and saving databases
+//!
+//! Deriving the key of a database can take seconds by design, and reading or writing a large one
+//! takes a while, too. Code run with `with_progress` reports its progress to a handler and stops
+//! with `DatabaseOpenError::Cancelled` or `DatabaseSaveError::Cancelled` once its
+//! `CancellationToken` is cancelled, e.g. from a UI thread:
+//!
+//! ```
+//! use keepass::{
+//!     error::DatabaseOpenError,
+//!     progress::{with_progress, CancellationToken, Phase, Progress},
+//!     Database, DatabaseKey,
+//! };
+//!
+//! let token = CancellationToken::new();
+//! let handler = |progress: &Progress| {
+//!     if let Some(percent) = progress.percent() {
+//!         println!("{:?}: {}%", progress.phase, percent);
+//!     }
+//! };
+//!
+//! let mut file = std::fs::File::open("tests/resources/test_db_kdbx4_with_password_aes.kdbx")?;
+//! let key = DatabaseKey::new().with_password("demopass");
+//! match with_progress(handler, token.clone(), || Database::open(&mut file, key)) {
+//!     Ok(db) => println!("Opened {:?}", db.meta.database_name),
+//!     Err(DatabaseOpenError::Cancelled) => println!("Cancelled"),
+//!     Err(e) => return Err(e.into()),
+//! }
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+//!
+//! AES-KDF reports each percent of its rounds. Argon2 cannot report how far it got, so it only
+//! reports its start and its end, but can still be cancelled: it then runs on a thread of its
+//! own, which is left to finish in the background when cancelled. The payload of KDBX 4 files is
+//! reported as the HMAC blocks are read or written; its size is only known when parsing from a
+//! slice.
+
+use std::{
+    cell::{Cell, RefCell},
+    rc::Rc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use crate::error::CryptographyError;
+
+/// What a database is busy with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// Transforming the key with the key derivation function of the database
+    KeyDerivation,
+
+    /// Reading, verifying and decrypting the payload of the database
+    Decryption,
+
+    /// Encrypting and writing the payload of the database
+    Encryption,
+}
+
+/// How far a phase got, in rounds for key derivations and in bytes for the payload
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    pub phase: Phase,
+    pub done: u64,
+
+    /// The amount of work in the phase, if known
+    pub total: Option<u64>,
+}
+
+impl Progress {
+    /// The percentage of the phase that is done, if the total is known
+    pub fn percent(&self) -> Option<u8> {
+        match self.total {
+            Some(0) => Some(100),
+            Some(total) => Some((self.done.min(total) as u128 * 100 / total as u128) as u8),
+            None => None,
+        }
+    }
+}
+
+/// Receives the progress of the code run with `with_progress`, on the thread that runs it
+pub trait ProgressHandler {
+    fn progress(&self, progress: &Progress);
+}
+
+impl<F> ProgressHandler for F
+where
+    F: Fn(&Progress),
+{
+    fn progress(&self, progress: &Progress) {
+        self(progress)
+    }
+}
+
+/// Cancels the code run with `with_progress` from any thread. Clones share the same state.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+struct Context {
+    handler: Box<dyn ProgressHandler>,
+    token: CancellationToken,
+    current: Cell<Progress>,
+}
+
+thread_local! {
+    static CONTEXT: RefCell<Vec<Rc<Context>>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Run `f`, reporting the progress of opening and saving databases on this thread to `handler`
+/// and stopping once `token` is cancelled. Calls can be nested, the innermost handler and token
+/// win.
+pub fn with_progress<H: ProgressHandler + 'static, R>(
+    handler: H,
+    token: CancellationToken,
+    f: impl FnOnce() -> R,
+) -> R {
+    struct PopOnDrop;
+
+    impl Drop for PopOnDrop {
+        fn drop(&mut self) {
+            CONTEXT.with(|c| c.borrow_mut().pop());
+        }
+    }
+
+    CONTEXT.with(|c| {
+        c.borrow_mut().push(Rc::new(Context {
+            handler: Box::new(handler),
+            token,
+            current: Cell::new(Progress {
+                phase: Phase::KeyDerivation,
+                done: 0,
+                total: None,
+            }),
+        }))
+    });
+    let _pop = PopOnDrop;
+    f()
+}
+
+fn context() -> Option<Rc<Context>> {
+    // clone the context so that the handler can itself call `with_progress`
+    CONTEXT.with(|c| c.borrow().last().cloned())
+}
+
+/// The token of the innermost `with_progress` on this thread
+pub(crate) fn token() -> Option<CancellationToken> {
+    context().map(|context| context.token.clone())
+}
+
+/// Whether the token of the innermost `with_progress` on this thread is cancelled
+pub(crate) fn is_cancelled() -> bool {
+    token().is_some_and(|token| token.is_cancelled())
+}
+
+/// Pass on progress reported on another thread to the innermost handler of this thread
+#[cfg(feature = "async")]
+pub(crate) fn forward(progress: Progress) {
+    if let Some(context) = context() {
+        context.current.set(progress);
+        context.handler.progress(&progress);
+    }
+}
+
+fn report(context: &Context, progress: Progress) -> Result<(), CryptographyError> {
+    context.current.set(progress);
+    if context.token.is_cancelled() {
+        return Err(CryptographyError::Cancelled);
+    }
+    context.handler.progress(&progress);
+    Ok(())
+}
+
+/// Start a phase, failing if the operation is cancelled
+pub(crate) fn start(phase: Phase, total: Option<u64>) -> Result<(), CryptographyError> {
+    match context() {
+        Some(context) => report(
+            &context,
+            Progress {
+                phase,
+                done: 0,
+                total,
+            },
+        ),
+        None => Ok(()),
+    }
+}
+
+/// Report more work done in the current phase, failing if the operation is cancelled
+pub(crate) fn advance(done: u64) -> Result<(), CryptographyError> {
+    match context() {
+        Some(context) => {
+            let current = context.current.get();
+            report(
+                &context,
+                Progress {
+                    done: current.done.saturating_add(done),
+                    ..current
+                },
+            )
+        }
+        None => Ok(()),
+    }
+}
+
+/// Report the end of the current phase, whose total becomes what was done if it was not known
+pub(crate) fn finish() -> Result<(), CryptographyError> {
+    match context() {
+        Some(context) => {
+            let current = context.current.get();
+            let total = current.total.unwrap_or(current.done);
+            report(
+                &context,
+                Progress {
+                    done: total,
+                    total: Some(total),
+                    ..current
+                },
+            )
+        }
+        None => Ok(()),
+    }
+}
+
+/// Fail reading or writing the payload if the operation is cancelled, after `len` more bytes
+pub(crate) fn advance_io(len: u64) -> Result<(), std::io::Error> {
+    advance(len).map_err(std::io::Error::other)
+}
+
+#[cfg(feature = "save_kdbx4")]
+#[cfg(test)]
+mod progress_tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::{with_progress, CancellationToken, Phase, Progress};
+    use crate::{
+        config::{DatabaseConfig, KdfConfig},
+        db::Entry,
+        error::{DatabaseOpenError, DatabaseSaveError},
+        Database, DatabaseKey,
+    };
+
+    fn record() -> (Rc<RefCell<Vec<Progress>>>, impl Fn(&Progress)) {
+        let reports = Rc::new(RefCell::new(Vec::new()));
+        let recorder = Rc::clone(&reports);
+        (reports, move |progress: &Progress| {
+            recorder.borrow_mut().push(*progress)
+        })
+    }
+
+    #[test]
+    fn test_progress() {
+        let mut db = Database::new(DatabaseConfig {
+            kdf_config: KdfConfig::Aes { rounds: 1000 },
+            ..Default::default()
+        });
+        for _ in 0..100 {
+            db.root.add_child(Entry::new().with_title("Entry"));
+        }
+        let key = DatabaseKey::new().with_password("test");
+
+        let (reports, handler) = record();
+        let mut data = Vec::new();
+        with_progress(handler, CancellationToken::new(), || {
+            db.save(&mut data, key.clone())
+        })
+        .unwrap();
+
+        let reports = reports.borrow();
+        let kdf: Vec<_> = reports
+            .iter()
+            .filter(|p| p.phase == Phase::KeyDerivation)
+            .collect();
+        assert_eq!(kdf.first().unwrap().done, 0);
+        assert_eq!(kdf.last().unwrap().done, 1000);
+        assert!(kdf.len() > 10);
+        let last = reports.last().unwrap();
+        assert_eq!(last.phase, Phase::Encryption);
+        assert_eq!(last.percent(), Some(100));
+
+        let (reports, handler) = record();
+        let parsed = with_progress(handler, CancellationToken::new(), || {
+            Database::parse(&data, key.clone())
+        })
+        .unwrap();
+        assert_eq!(parsed.root, db.root);
+
+        let reports = reports.borrow();
+        let decryption: Vec<_> = reports.iter().filter(|p| p.phase == Phase::Decryption).collect();
+        assert_eq!(decryption.first().unwrap().done, 0);
+        assert!(decryption.windows(2).all(|w| w[0].done <= w[1].done));
+        assert_eq!(
+            decryption.last().unwrap().total,
+            Some(decryption.last().unwrap().done)
+        );
+        assert_eq!(decryption.last().unwrap().percent(), Some(100));
+
+        // reading from a stream does not know the size of the payload up front
+        let (reports, handler) = record();
+        with_progress(handler, CancellationToken::new(), || {
+            Database::open(&mut data.as_slice(), key.clone())
+        })
+        .unwrap();
+        assert!(reports
+            .borrow()
+            .iter()
+            .any(|p| p.phase == Phase::Decryption && p.total.is_none()));
+    }
+
+    #[test]
+    fn test_cancellation() {
+        let key = DatabaseKey::new().with_password("test");
+        let token = CancellationToken::new();
+        token.cancel();
+
+        for kdf_config in [
+            KdfConfig::Aes { rounds: 1000 },
+            DatabaseConfig::default().kdf_config,
+        ]
+        .iter()
+        {
+            let db = Database::new(DatabaseConfig {
+                kdf_config: kdf_config.clone(),
+                ..Default::default()
+            });
+            let mut data = Vec::new();
+            let saved = with_progress(
+                |_: &Progress| {},
+                token.clone(),
+                || db.save(&mut data, key.clone()),
+            );
+            assert!(matches!(saved, Err(DatabaseSaveError::Cancelled)));
+
+            data.clear();
+            db.save(&mut data, key.clone()).unwrap();
+            // the key is cached by now, so this is cancelled while reading the payload
+            let parsed = with_progress(
+                |_: &Progress| {},
+                token.clone(),
+                || Database::parse(&data, key.clone()),
+            );
+            assert!(matches!(parsed, Err(DatabaseOpenError::Cancelled)));
+            assert!(Database::parse(&data, key.clone()).is_ok());
+        }
+
+        // cancelling from within the handler stops at the next report
+        let token = CancellationToken::new();
+        let canceller = token.clone();
+        let mut data = Vec::new();
+        Database::new(Default::default())
+            .save(&mut data, key.clone())
+            .unwrap();
+        let parsed = with_progress(
+            move |p: &Progress| {
+                if p.phase == Phase::Decryption {
+                    canceller.cancel()
+                }
+            },
+            token,
+            || Database::open(&mut data.as_slice(), key.clone()),
+        );
+        assert!(matches!(parsed, Err(DatabaseOpenError::Cancelled)));
+
+        // a running Argon2 hash is given up on
+        let db = Database::new(DatabaseConfig {
+            kdf_config: KdfConfig::Argon2id {
+                iterations: 10,
+                memory: 64 * 1024 * 1024,
+                parallelism: 1,
+                version: argon2::Version::Version13,
+            },
+            ..Default::default()
+        });
+        let token = CancellationToken::new();
+        let canceller = token.clone();
+        let started = std::time::Instant::now();
+        let saved = with_progress(
+            move |p: &Progress| {
+                if p.phase == Phase::KeyDerivation {
+                    canceller.cancel()
+                }
+            },
+            token,
+            || db.save(&mut Vec::new(), key.clone()),
+        );
+        assert!(matches!(saved, Err(DatabaseSaveError::Cancelled)));
+        assert!(started.elapsed() < std::time::Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,331 @@
+//! Unlocking a database again with a short PIN
+//!
+//! After the first full unlock with the master key, apps often offer to unlock again with a PIN.
+//! `QuickUnlock::seal` encrypts the `TransformedKey` of a database under a key derived from the
+//! PIN, and `QuickUnlock::unlock` returns it for the right PIN, to be used with
+//! `DatabaseKey::with_transformed_key`. As a PIN is easily guessed, only a few wrong PINs are
+//! allowed and the sealed key expires after a while. Either way it is wiped, and the master key is
+//! needed again.
+//!
+//! ```
+//! use keepass::{
+//!     config::KdfConfig,
+//!     quick_unlock::{QuickUnlock, QuickUnlockError, QuickUnlockOptions},
+//!     Database, DatabaseKey,
+//! };
+//!
+//! let data = std::fs::read("tests/resources/test_db_kdbx4_with_password_aes.kdbx")?;
+//! let (_, transformed_key) = Database::parse_transformed(&data, DatabaseKey::new().with_password("demopass"))?;
+//!
+//! let options = QuickUnlockOptions {
+//!     pin_kdf: KdfConfig::Aes { rounds: 1000 },
+//!     ..Default::default()
+//! };
+//! let mut quick_unlock = QuickUnlock::seal(&transformed_key, "1234", &options)?;
+//!
+//! assert!(matches!(quick_unlock.unlock("0000"), Err(QuickUnlockError::WrongPin { attempts_left: 2 })));
+//! let key = DatabaseKey::new().with_transformed_key(quick_unlock.unlock("1234")?);
+//! let db = Database::parse(&data, key)?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+//!
+//! The sealed key is only kept in memory, and the limits are enforced by this type alone: whoever
+//! can read the memory of the process can try PINs without any limit. Quick unlock saves typing
+//! the master key, it does not replace it.
+
+use chrono::NaiveDateTime;
+use thiserror::Error;
+use zeroize::Zeroizing;
+
+use crate::{
+    config::{Argon2SecretParameters, KdfConfig},
+    crypt::{
+        calculate_hmac, calculate_sha256,
+        ciphers::{ChaCha20Cipher, Cipher},
+        verify_hmac,
+    },
+    db::Times,
+    error::CryptographyError,
+    key::TransformedKey,
+};
+
+const SALT_SIZE: usize = 32;
+const NONCE_SIZE: usize = 12;
+
+/// The keys for encrypting and authenticating the sealed key
+type PinKeys = (Zeroizing<Vec<u8>>, Zeroizing<Vec<u8>>);
+
+/// Errors while sealing a key or unlocking it with a PIN
+#[derive(Debug, Error)]
+pub enum QuickUnlockError {
+    /// The PIN was wrong. Once no attempts are left, the sealed key is wiped.
+    #[error("Wrong PIN, {attempts_left} attempts left")]
+    WrongPin { attempts_left: u32 },
+
+    /// The sealed key was wiped after too many wrong PINs, after it expired or by `invalidate`
+    #[error("Quick unlock is not available anymore")]
+    Invalidated,
+
+    #[error(transparent)]
+    Cryptography(#[from] CryptographyError),
+
+    #[error(transparent)]
+    Random(#[from] getrandom::Error),
+}
+
+/// Options for `QuickUnlock::seal`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuickUnlockOptions {
+    /// Number of wrong PINs after which the sealed key is wiped
+    pub max_attempts: u32,
+
+    /// How long the sealed key can be unlocked after it was sealed, or `None` to keep it until it
+    /// is invalidated
+    pub timeout: Option<chrono::Duration>,
+
+    /// The KDF deriving the key from the PIN
+    pub pin_kdf: KdfConfig,
+}
+
+impl Default for QuickUnlockOptions {
+    fn default() -> Self {
+        QuickUnlockOptions {
+            max_attempts: 3,
+            timeout: Some(chrono::Duration::hours(8)),
+            pin_kdf: KdfConfig::Argon2id {
+                iterations: 2,
+                memory: 19 * 1024 * 1024,
+                parallelism: 1,
+                version: argon2::Version::Version13,
+            },
+        }
+    }
+}
+
+/// A transformed key sealed under a PIN, see the module documentation
+///
+/// It is deliberately not `Clone`, as every clone would allow its own wrong PINs.
+pub struct QuickUnlock {
+    sealed: Option<SealedKey>,
+    max_attempts: u32,
+    attempts_left: u32,
+    expires: Option<NaiveDateTime>,
+    pin_kdf: KdfConfig,
+}
+
+/// The encrypted transformed key, along with the KDF settings it was derived with
+struct SealedKey {
+    salt: Vec<u8>,
+    nonce: [u8; NONCE_SIZE],
+    ciphertext: Vec<u8>,
+    mac: Vec<u8>,
+
+    kdf_config: KdfConfig,
+    kdf_seed: Vec<u8>,
+    argon2_secret_parameters: Argon2SecretParameters,
+}
+
+impl QuickUnlock {
+    /// Seal a transformed key under a PIN
+    pub fn seal(
+        transformed_key: &TransformedKey,
+        pin: &str,
+        options: &QuickUnlockOptions,
+    ) -> Result<QuickUnlock, QuickUnlockError> {
+        let mut salt = vec![0u8; SALT_SIZE];
+        getrandom::fill(&mut salt)?;
+        let mut nonce = [0u8; NONCE_SIZE];
+        getrandom::fill(&mut nonce)?;
+
+        let (encryption_key, mac_key) = derive_pin_keys(&options.pin_kdf, pin, &salt)?;
+        // ChaCha20 is a stream cipher, so applying the key stream encrypts as well as decrypts
+        let ciphertext =
+            ChaCha20Cipher::new_key_iv(&encryption_key, &nonce)?.decrypt(transformed_key.as_bytes())?;
+        let mac = calculate_hmac(&[&nonce, &ciphertext], &mac_key)?.to_vec();
+
+        Ok(QuickUnlock {
+            sealed: Some(SealedKey {
+                salt,
+                nonce,
+                ciphertext,
+                mac,
+                kdf_config: transformed_key.kdf_config().clone(),
+                kdf_seed: transformed_key.kdf_seed().to_vec(),
+                argon2_secret_parameters: transformed_key.argon2_secret_parameters().clone(),
+            }),
+            max_attempts: options.max_attempts,
+            attempts_left: options.max_attempts,
+            expires: options.timeout.map(|timeout| Times::now() + timeout),
+            pin_kdf: options.pin_kdf.clone(),
+        })
+    }
+
+    /// Unlock the transformed key with the PIN. A wrong PIN uses up an attempt, and the right one
+    /// restores all of them.
+    pub fn unlock(&mut self, pin: &str) -> Result<TransformedKey, QuickUnlockError> {
+        if !self.is_available() {
+            self.invalidate();
+            return Err(QuickUnlockError::Invalidated);
+        }
+        let sealed = self.sealed.as_ref().ok_or(QuickUnlockError::Invalidated)?;
+
+        let (encryption_key, mac_key) = derive_pin_keys(&self.pin_kdf, pin, &sealed.salt)?;
+        if !verify_hmac(&[&sealed.nonce, &sealed.ciphertext], &mac_key, &sealed.mac)? {
+            self.attempts_left = self.attempts_left.saturating_sub(1);
+            if self.attempts_left == 0 {
+                self.invalidate();
+            }
+            return Err(QuickUnlockError::WrongPin {
+                attempts_left: self.attempts_left,
+            });
+        }
+
+        let key = Zeroizing::new(
+            ChaCha20Cipher::new_key_iv(&encryption_key, &sealed.nonce)?.decrypt(&sealed.ciphertext)?,
+        );
+        self.attempts_left = self.max_attempts;
+
+        Ok(TransformedKey::new(
+            sealed.kdf_config.clone(),
+            sealed.kdf_seed.clone(),
+            sealed.argon2_secret_parameters.clone(),
+            &key,
+        ))
+    }
+
+    /// Whether the sealed key can still be unlocked, i.e. it has neither been wiped nor expired
+    pub fn is_available(&self) -> bool {
+        self.sealed.is_some() && !matches!(self.expires, Some(expires) if Times::now() >= expires)
+    }
+
+    /// The number of wrong PINs left before the sealed key is wiped
+    pub fn attempts_left(&self) -> u32 {
+        if self.sealed.is_some() {
+            self.attempts_left
+        } else {
+            0
+        }
+    }
+
+    /// When the sealed key expires, if it does
+    pub fn expires(&self) -> Option<NaiveDateTime> {
+        self.expires
+    }
+
+    /// Wipe the sealed key, e.g. when the database is closed or its key is changed
+    pub fn invalidate(&mut self) {
+        self.sealed = None;
+        self.attempts_left = 0;
+    }
+}
+
+impl std::fmt::Debug for QuickUnlock {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("QuickUnlock")
+            .field("available", &self.is_available())
+            .field("attempts_left", &self.attempts_left())
+            .field("expires", &self.expires)
+            .finish()
+    }
+}
+
+/// Derive separate keys for encryption and authentication from the PIN. The transformed PIN is not
+/// kept in the key cache, as it would allow to check PINs without using up attempts.
+fn derive_pin_keys(pin_kdf: &KdfConfig, pin: &str, salt: &[u8]) -> Result<PinKeys, CryptographyError> {
+    let composite_key = Zeroizing::new(calculate_sha256(&[pin.as_bytes()])?);
+    let pin_key = pin_kdf
+        .get_kdf_seeded(salt, &Argon2SecretParameters::default())
+        .transform_key(&composite_key)?;
+
+    let encryption_key = calculate_hmac(&[b"keepass-rs quick unlock encryption"], &pin_key)?;
+    let mac_key = calculate_hmac(&[b"keepass-rs quick unlock authentication"], &pin_key)?;
+    Ok((
+        Zeroizing::new(encryption_key.to_vec()),
+        Zeroizing::new(mac_key.to_vec()),
+    ))
+}
+
+#[cfg(test)]
+mod quick_unlock_tests {
+    use chrono::NaiveDate;
+
+    use super::{QuickUnlock, QuickUnlockError, QuickUnlockOptions};
+    use crate::{
+        config::KdfConfig,
+        db::{with_clock, ManualClock},
+        Database, DatabaseKey,
+    };
+
+    fn options() -> QuickUnlockOptions {
+        QuickUnlockOptions {
+            pin_kdf: KdfConfig::Aes { rounds: 10 },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_quick_unlock() {
+        let data = std::fs::read("tests/resources/test_db_kdbx4_with_password_aes.kdbx").unwrap();
+        let (db, transformed_key) =
+            Database::parse_transformed(&data, DatabaseKey::new().with_password("demopass")).unwrap();
+
+        let mut quick_unlock = QuickUnlock::seal(&transformed_key, "1234", &options()).unwrap();
+        assert!(!format!("{:?}", quick_unlock).contains("1234"));
+        assert!(matches!(
+            quick_unlock.unlock("0000"),
+            Err(QuickUnlockError::WrongPin { attempts_left: 2 })
+        ));
+
+        // the right PIN restores all attempts
+        let unlocked = quick_unlock.unlock("1234").unwrap();
+        assert!(unlocked == transformed_key);
+        assert_eq!(quick_unlock.attempts_left(), 3);
+        let reopened = Database::parse(&data, DatabaseKey::new().with_transformed_key(unlocked)).unwrap();
+        assert_eq!(reopened.root, db.root);
+
+        for attempts_left in [2, 1, 0].iter() {
+            assert!(matches!(
+                quick_unlock.unlock("4321"),
+                Err(QuickUnlockError::WrongPin { attempts_left: a }) if a == *attempts_left
+            ));
+        }
+        assert!(!quick_unlock.is_available());
+        assert!(matches!(
+            quick_unlock.unlock("1234"),
+            Err(QuickUnlockError::Invalidated)
+        ));
+    }
+
+    #[test]
+    fn test_quick_unlock_expires() {
+        let data = std::fs::read("tests/resources/test_db_kdbx4_with_password_aes.kdbx").unwrap();
+        let (_, transformed_key) =
+            Database::parse_transformed(&data, DatabaseKey::new().with_password("demopass")).unwrap();
+
+        let start = NaiveDate::from_ymd_opt(2024, 1, 1)
+            .unwrap()
+            .and_hms_opt(12, 0, 0)
+            .unwrap();
+        let clock = ManualClock::new(start);
+        with_clock(clock.clone(), || {
+            let mut quick_unlock = QuickUnlock::seal(&transformed_key, "1234", &options()).unwrap();
+            assert_eq!(quick_unlock.expires(), Some(start + chrono::Duration::hours(8)));
+            assert!(quick_unlock.unlock("1234").is_ok());
+
+            clock.advance(chrono::Duration::hours(8));
+            assert!(!quick_unlock.is_available());
+            assert!(matches!(
+                quick_unlock.unlock("1234"),
+                Err(QuickUnlockError::Invalidated)
+            ));
+
+            let mut quick_unlock = QuickUnlock::seal(&transformed_key, "1234", &options()).unwrap();
+            quick_unlock.invalidate();
+            assert_eq!(quick_unlock.attempts_left(), 0);
+            assert!(matches!(
+                quick_unlock.unlock("1234"),
+                Err(QuickUnlockError::Invalidated)
+            ));
+        });
+    }
+}
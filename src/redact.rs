@@ -0,0 +1,173 @@
+//! Redaction of identifying values in exports and reports
+//!
+//! Protected values are masked by every renderer of this crate, but user names and URLs are not
+//! protected and still tell a lot about a vault. A `RedactionProfile` hides them as well, so that
+//! diagnostics from real databases can be shared or shown on screen without scrubbing them by
+//! hand. The profile is chosen when rendering, e.g. with `SecretsExportOptions::redaction`,
+//! `TableView::with_redaction` or `DiffStyle::with_redaction`.
+//!
+//! ```
+//! use keepass::redact::RedactionProfile;
+//!
+//! let profile = RedactionProfile::screenshot_safe();
+//! assert_eq!(profile.redact_url("https://mail.example.com/login?user=jdoe"), "https://***.***.com");
+//! assert_eq!(profile.redact_username("jdoe@example.com"), "jd…@***.com");
+//! ```
+
+use std::borrow::Cow;
+
+use crate::url_match::UrlComponents;
+
+/// Shown in place of the removed part of a truncated value
+const ELLIPSIS: &str = "…";
+
+/// Shown in place of a masked domain label
+const MASKED_LABEL: &str = "***";
+
+/// Which values are redacted when rendering entries. The default redacts nothing.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RedactionProfile {
+    /// Mask all labels of domains except the top level domain, in URLs and in email addresses
+    /// used as user names. The path, query and fragment of URLs are removed.
+    pub mask_domains: bool,
+
+    /// Keep only this many characters of user names. For email addresses, only the part before
+    /// the `@` is truncated.
+    pub truncate_usernames: Option<usize>,
+}
+
+impl RedactionProfile {
+    /// Redact nothing
+    pub fn none() -> Self {
+        RedactionProfile::default()
+    }
+
+    /// Mask domains and keep the first two characters of user names
+    pub fn screenshot_safe() -> Self {
+        RedactionProfile {
+            mask_domains: true,
+            truncate_usernames: Some(2),
+        }
+    }
+
+    pub fn is_none(&self) -> bool {
+        *self == RedactionProfile::none()
+    }
+
+    /// Redact the value of a field according to its name. Only `UserName` and `URL` are
+    /// redacted.
+    pub fn redact_field<'a>(&self, name: &str, value: &'a str) -> Cow<'a, str> {
+        match name {
+            "UserName" => self.redact_username(value),
+            "URL" => self.redact_url(value),
+            _ => Cow::Borrowed(value),
+        }
+    }
+
+    /// Redact a user name
+    pub fn redact_username<'a>(&self, username: &'a str) -> Cow<'a, str> {
+        if self.is_none() || username.is_empty() {
+            return Cow::Borrowed(username);
+        }
+
+        let (local, domain) = match username.rsplit_once('@') {
+            Some((local, domain)) if !domain.is_empty() => (local, Some(domain)),
+            _ => (username, None),
+        };
+
+        let mut out = match self.truncate_usernames {
+            Some(keep) if local.chars().count() > keep => {
+                let mut kept: String = local.chars().take(keep).collect();
+                kept.push_str(ELLIPSIS);
+                kept
+            }
+            _ => local.to_string(),
+        };
+
+        if let Some(domain) = domain {
+            out.push('@');
+            if self.mask_domains {
+                out.push_str(&mask_host(domain));
+            } else {
+                out.push_str(domain);
+            }
+        }
+
+        Cow::Owned(out)
+    }
+
+    /// Redact a URL, keeping its scheme and the top level domain of its host
+    pub fn redact_url<'a>(&self, url: &'a str) -> Cow<'a, str> {
+        if !self.mask_domains || url.is_empty() {
+            return Cow::Borrowed(url);
+        }
+
+        // credentials in the URL are dropped along with the path
+        let components = UrlComponents::split(url);
+        let mut out = match components.scheme {
+            Some(scheme) => format!("{}://", scheme),
+            None => String::new(),
+        };
+        out.push_str(&mask_host(components.host));
+        if let Some(port) = components.port {
+            out.push(':');
+            out.push_str(port);
+        }
+        Cow::Owned(out)
+    }
+}
+
+/// Mask every label of a host name except the last one. IP addresses are masked completely.
+fn mask_host(host: &str) -> String {
+    let labels: Vec<&str> = host.split('.').collect();
+    let is_ip = host.starts_with('[') || labels.iter().all(|l| l.bytes().all(|b| b.is_ascii_digit()));
+    if host.is_empty() || labels.len() == 1 || is_ip {
+        return MASKED_LABEL.to_string();
+    }
+
+    let mut masked = vec![MASKED_LABEL; labels.len() - 1];
+    masked.push(labels[labels.len() - 1]);
+    masked.join(".")
+}
+
+#[cfg(test)]
+mod redact_tests {
+    use super::RedactionProfile;
+
+    #[test]
+    fn test_redact_url() {
+        let profile = RedactionProfile::screenshot_safe();
+        assert_eq!(profile.redact_url("https://example.com"), "https://***.com");
+        assert_eq!(
+            profile.redact_url("ssh://admin:pw@db.internal.example.org:2222/data#x"),
+            "ssh://***.***.***.org:2222"
+        );
+        assert_eq!(profile.redact_url("intranet/login"), "***");
+        assert_eq!(profile.redact_url("http://10.0.0.1:8080/"), "http://***:8080");
+        assert_eq!(profile.redact_url(""), "");
+
+        assert_eq!(
+            RedactionProfile::none().redact_url("https://example.com/a"),
+            "https://example.com/a"
+        );
+    }
+
+    #[test]
+    fn test_redact_username() {
+        let profile = RedactionProfile::screenshot_safe();
+        assert_eq!(profile.redact_username("jdoe"), "jd…");
+        assert_eq!(profile.redact_username("jo"), "jo");
+        assert_eq!(
+            profile.redact_username("jdoe@mail.example.com"),
+            "jd…@***.***.com"
+        );
+
+        let truncate_only = RedactionProfile {
+            truncate_usernames: Some(0),
+            ..Default::default()
+        };
+        assert_eq!(truncate_only.redact_username("jdoe@example.com"), "…@example.com");
+        assert_eq!(truncate_only.redact_field("Title", "jdoe"), "jdoe");
+        assert_eq!(truncate_only.redact_field("UserName", "jdoe"), "…");
+    }
+}
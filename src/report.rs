@@ -0,0 +1,242 @@
+//! Reports on the contents of a database
+//!
+//! `compliance_csv` summarizes, per group, how many entries violate a password policy. The output
+//! is meant to be generated on a schedule and fed into spreadsheets or monitoring tools.
+
+use std::collections::HashSet;
+
+use chrono::NaiveDateTime;
+use uuid::Uuid;
+
+use crate::{
+    db::{with_audit_context, Database, Entry, Filter, Group, Times},
+    key::estimate_password_entropy,
+};
+
+/// Thresholds that passwords are checked against
+#[derive(Debug, Clone)]
+pub struct CompliancePolicy {
+    /// Passwords with a lower estimated entropy in bits count as weak
+    pub min_password_bits: f64,
+
+    /// Passwords that were not changed for longer than this count as old
+    pub max_password_age: chrono::Duration,
+
+    /// Only check the entries matching this filter
+    pub filter: Filter,
+}
+
+impl Default for CompliancePolicy {
+    fn default() -> Self {
+        CompliancePolicy {
+            min_password_bits: 60.0,
+            max_password_age: chrono::Duration::days(365),
+            filter: Filter::All,
+        }
+    }
+}
+
+/// Policy violations of the entries directly contained in a group
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GroupCompliance {
+    /// Names of the groups from the root down to this group, separated by `/`
+    pub path: String,
+    pub entries: usize,
+    pub weak: usize,
+    pub expired: usize,
+    pub old: usize,
+}
+
+/// Check the entries of every group in the database against a policy, in depth-first order
+pub fn compliance(db: &Database, policy: &CompliancePolicy) -> Vec<GroupCompliance> {
+    let mut out = Vec::new();
+    with_audit_context("compliance report", || {
+        let selected = policy.filter.select_uuids(&db.root).into_iter().collect();
+        collect_group(
+            &db.root,
+            db.root.name.clone(),
+            policy,
+            &selected,
+            Times::now(),
+            &mut out,
+        )
+    });
+    out
+}
+
+/// Check the entries of every group in the database against a policy and format the results as
+/// CSV, with a header line followed by one line per group.
+pub fn compliance_csv(db: &Database, policy: &CompliancePolicy) -> String {
+    let mut csv = String::from("group,entries,weak,expired,old\n");
+    for group in compliance(db, policy) {
+        csv.push_str(&format!(
+            "{},{},{},{},{}\n",
+            csv_field(&group.path),
+            group.entries,
+            group.weak,
+            group.expired,
+            group.old
+        ));
+    }
+    csv
+}
+
+fn collect_group(
+    group: &Group,
+    path: String,
+    policy: &CompliancePolicy,
+    selected: &HashSet<Uuid>,
+    now: NaiveDateTime,
+    out: &mut Vec<GroupCompliance>,
+) {
+    let mut stats = GroupCompliance {
+        path: path.clone(),
+        ..Default::default()
+    };
+
+    for entry in group.entries().into_iter().filter(|e| selected.contains(&e.uuid)) {
+        stats.entries += 1;
+
+        if let Some(password) = entry.get_protected("Password").as_ref().and_then(|p| p.as_str()) {
+            if estimate_password_entropy(password) < policy.min_password_bits {
+                stats.weak += 1;
+            }
+        }
+
+        if entry.times.expires && entry.get_expiry_time().is_some_and(|expiry| *expiry <= now) {
+            stats.expired += 1;
+        }
+
+        if password_changed_at(entry).is_some_and(|changed| now - changed > policy.max_password_age) {
+            stats.old += 1;
+        }
+    }
+
+    out.push(stats);
+
+    for child in group.groups() {
+        collect_group(
+            child,
+            format!("{}/{}", path, child.name),
+            policy,
+            selected,
+            now,
+            out,
+        );
+    }
+}
+
+/// When the current password of an entry was set, judging from its history
+fn password_changed_at(entry: &Entry) -> Option<NaiveDateTime> {
+    let password = entry.get_protected("Password");
+    let mut changed = entry.times.get_last_modification().copied();
+
+    // history entries are ordered from newest to oldest
+    if let Some(history) = &entry.history {
+        for old in history.get_entries() {
+            if old.get_protected("Password").as_deref() != password.as_deref() {
+                break;
+            }
+            changed = old.times.get_last_modification().copied().or(changed);
+        }
+    }
+
+    changed
+}
+
+fn csv_field(value: &str) -> String {
+    // keep spreadsheet applications from interpreting group names as formulas
+    let value = if value.starts_with(['=', '+', '-', '@']) {
+        format!("'{}", value)
+    } else {
+        value.to_string()
+    };
+
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value
+    }
+}
+
+#[cfg(test)]
+mod report_tests {
+    use crate::{
+        config::DatabaseConfig,
+        db::{Database, Entry, Filter, Group, History, Times, Value},
+    };
+
+    use super::{compliance_csv, CompliancePolicy};
+
+    fn entry_with_password(password: &str) -> Entry {
+        let mut entry = Entry::new();
+        entry.fields.insert(
+            "Password".to_string(),
+            Value::Protected(password.as_bytes().into()),
+        );
+        entry
+    }
+
+    #[test]
+    fn test_compliance_csv() {
+        let mut db = Database::new(DatabaseConfig::default());
+        db.root.name = "Root".to_string();
+
+        db.root.add_child(entry_with_password("hunter2"));
+        db.root.add_child(entry_with_password("Xk9#mQ2$vL7@pR4!wT6&"));
+
+        let mut group = Group::new("Web, Mail");
+
+        let mut expired = entry_with_password("Xk9#mQ2$vL7@pR4!wT6&");
+        expired.times.expires = true;
+        expired.times.set_expiry(Times::epoch());
+        group.add_child(expired);
+
+        let mut old = entry_with_password("password");
+        old.times
+            .set_last_modification(Times::now() - chrono::Duration::days(400));
+        group.add_child(old);
+
+        db.root.add_child(group);
+
+        assert_eq!(
+            compliance_csv(&db, &CompliancePolicy::default()),
+            "group,entries,weak,expired,old\nRoot,2,1,0,0\n\"Root/Web, Mail\",2,1,1,1\n"
+        );
+
+        let policy = CompliancePolicy {
+            filter: Filter::expired().not(),
+            ..Default::default()
+        };
+        assert_eq!(
+            compliance_csv(&db, &policy),
+            "group,entries,weak,expired,old\nRoot,2,1,0,0\n\"Root/Web, Mail\",1,1,0,1\n"
+        );
+    }
+
+    #[test]
+    fn test_password_age_uses_history() {
+        let mut entry = entry_with_password("Xk9#mQ2$vL7@pR4!wT6&");
+        entry
+            .times
+            .set_last_modification(Times::now() - chrono::Duration::days(400));
+
+        let mut history = History::default();
+        history.add_entry(entry.clone());
+        entry.history = Some(history);
+
+        // a change to another field does not reset the password age
+        entry
+            .fields
+            .insert("Title".to_string(), Value::Unprotected("renamed".to_string()));
+        entry.times.set_last_modification(Times::now());
+
+        assert!(super::password_changed_at(&entry).unwrap() < Times::now() - chrono::Duration::days(365));
+
+        entry.fields.insert(
+            "Password".to_string(),
+            Value::Protected("new password".as_bytes().into()),
+        );
+        assert!(super::password_changed_at(&entry).unwrap() > Times::now() - chrono::Duration::days(1));
+    }
+}
@@ -0,0 +1,435 @@
+//! Encrypted on-disk search index for instant startup search
+//!
+//! Opening a large database means running the KDF and decrypting and parsing the whole file.
+//! A `SearchIndex` keeps the titles, URLs and tags of all entries in a small, separately
+//! encrypted cache file that can be loaded right away, e.g. to show search results while the
+//! database itself is still being opened. The database always remains the source of truth: the
+//! index is brought up to date with `SearchIndex::update` whenever the database has been opened.
+//!
+//! ```
+//! use keepass::{search_cache::SearchIndex, Database, DatabaseKey};
+//!
+//! let mut file = std::fs::File::open("tests/resources/test_db_with_password.kdbx")?;
+//! let db = Database::open(&mut file, DatabaseKey::new().with_password("demopass"))?;
+//!
+//! let cache_key = b"a secret that is kept e.g. in the OS keyring";
+//! let index = SearchIndex::build(&db);
+//! let encrypted = index.to_encrypted_bytes(cache_key)?;
+//!
+//! let index = SearchIndex::from_encrypted_bytes(&encrypted, cache_key)?;
+//! for entry in index.search("sample") {
+//!     println!("{} ({})", entry.title, entry.uuid);
+//! }
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+//!
+//! The cache file is encrypted with ChaCha20 and authenticated with HMAC-SHA256, using keys that
+//! are derived from a caller-supplied secret.
+
+use std::{
+    collections::BTreeMap,
+    io::{Cursor, Read, Write},
+    path::Path,
+};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use chrono::NaiveDateTime;
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::{
+    crypt::{
+        calculate_hmac,
+        ciphers::{ChaCha20Cipher, Cipher},
+        verify_hmac,
+    },
+    db::{Database, Entry, NodeRef},
+    error::CryptographyError,
+};
+
+const MAGIC: &[u8; 4] = b"KPSI";
+const FORMAT_VERSION: u32 = 1;
+const NONCE_SIZE: usize = 12;
+const MAC_SIZE: usize = 32;
+
+/// Errors while reading or writing a search index
+#[derive(Debug, Error)]
+pub enum SearchCacheError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error(transparent)]
+    Cryptography(#[from] CryptographyError),
+
+    #[error(transparent)]
+    Random(#[from] getrandom::Error),
+
+    /// The cache was not written by this library or uses an unsupported format version
+    #[error("Not a supported search index file")]
+    InvalidFormat,
+
+    /// The cache was tampered with, or a different key was used to write it
+    #[error("Search index failed the integrity check")]
+    IntegrityCheckFailed,
+}
+
+/// The searchable data of a single entry
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexedEntry {
+    pub uuid: Uuid,
+    pub title: String,
+    pub url: String,
+    pub tags: Vec<String>,
+    pub last_modification: Option<NaiveDateTime>,
+}
+
+impl IndexedEntry {
+    fn from_entry(entry: &Entry) -> Self {
+        IndexedEntry {
+            uuid: entry.uuid,
+            title: entry.get_title().unwrap_or_default().to_string(),
+            url: entry.get_url().unwrap_or_default().to_string(),
+            tags: entry.tags.clone(),
+            last_modification: entry.times.get_last_modification().copied(),
+        }
+    }
+
+    fn matches(&self, query: &str) -> bool {
+        self.title.to_lowercase().contains(query)
+            || self.url.to_lowercase().contains(query)
+            || self.tags.iter().any(|t| t.to_lowercase().contains(query))
+    }
+}
+
+/// The changes applied to an index while bringing it up to date with a database
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct IndexUpdate {
+    pub added: Vec<Uuid>,
+    pub updated: Vec<Uuid>,
+    pub removed: Vec<Uuid>,
+}
+
+impl IndexUpdate {
+    /// Whether the index was already up to date
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.updated.is_empty() && self.removed.is_empty()
+    }
+}
+
+/// A search index over the titles, URLs and tags of all entries in a database
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SearchIndex {
+    entries: BTreeMap<Uuid, IndexedEntry>,
+}
+
+impl SearchIndex {
+    /// Build a new index from all entries in a database
+    pub fn build(db: &Database) -> Self {
+        let mut index = SearchIndex::default();
+        index.update(db);
+        index
+    }
+
+    /// Bring the index up to date with the database, returning what changed
+    pub fn update(&mut self, db: &Database) -> IndexUpdate {
+        let mut update = IndexUpdate::default();
+        let mut current: BTreeMap<Uuid, IndexedEntry> = BTreeMap::new();
+
+        for node in &db.root {
+            if let NodeRef::Entry(e) = node {
+                current.insert(e.uuid, IndexedEntry::from_entry(e));
+            }
+        }
+
+        for (uuid, entry) in &current {
+            match self.entries.get(uuid) {
+                None => update.added.push(*uuid),
+                Some(existing) if existing != entry => update.updated.push(*uuid),
+                Some(_) => {}
+            }
+        }
+
+        for uuid in self.entries.keys() {
+            if !current.contains_key(uuid) {
+                update.removed.push(*uuid);
+            }
+        }
+
+        self.entries = current;
+        update
+    }
+
+    /// All indexed entries, ordered by UUID
+    pub fn entries(&self) -> impl Iterator<Item = &IndexedEntry> {
+        self.entries.values()
+    }
+
+    /// Find all entries whose title, URL or tags contain the query, ignoring case
+    pub fn search(&self, query: &str) -> Vec<&IndexedEntry> {
+        let query = query.to_lowercase();
+        self.entries.values().filter(|e| e.matches(&query)).collect()
+    }
+
+    /// Read an index from an encrypted cache file
+    pub fn load(path: impl AsRef<Path>, key: &[u8]) -> Result<Self, SearchCacheError> {
+        let data = std::fs::read(path)?;
+        Self::from_encrypted_bytes(&data, key)
+    }
+
+    /// Write the index to an encrypted cache file, replacing it atomically
+    pub fn save(&self, path: impl AsRef<Path>, key: &[u8]) -> Result<(), SearchCacheError> {
+        let path = path.as_ref();
+        let data = self.to_encrypted_bytes(key)?;
+
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+
+        std::fs::write(&tmp_path, data)?;
+        std::fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    /// Serialize and encrypt the index
+    pub fn to_encrypted_bytes(&self, key: &[u8]) -> Result<Vec<u8>, SearchCacheError> {
+        let (encryption_key, mac_key) = derive_keys(key)?;
+
+        let mut nonce = [0u8; NONCE_SIZE];
+        getrandom::fill(&mut nonce)?;
+
+        // ChaCha20 is a stream cipher, so applying the key stream encrypts as well as decrypts
+        let plaintext = self.serialize()?;
+        let ciphertext = ChaCha20Cipher::new_key_iv(&encryption_key, &nonce)?.decrypt(&plaintext)?;
+
+        let mut out = Vec::with_capacity(MAGIC.len() + 4 + NONCE_SIZE + ciphertext.len() + MAC_SIZE);
+        out.extend_from_slice(MAGIC);
+        out.write_u32::<LittleEndian>(FORMAT_VERSION)?;
+        out.extend_from_slice(&nonce);
+        out.extend_from_slice(&ciphertext);
+
+        let mac = calculate_hmac(&[&out], &mac_key)?;
+        out.extend_from_slice(&mac);
+
+        Ok(out)
+    }
+
+    /// Verify, decrypt and deserialize an index
+    pub fn from_encrypted_bytes(data: &[u8], key: &[u8]) -> Result<Self, SearchCacheError> {
+        let header_size = MAGIC.len() + 4 + NONCE_SIZE;
+        if data.len() < header_size + MAC_SIZE || &data[..MAGIC.len()] != MAGIC {
+            return Err(SearchCacheError::InvalidFormat);
+        }
+
+        let mut version = &data[MAGIC.len()..MAGIC.len() + 4];
+        if version.read_u32::<LittleEndian>()? != FORMAT_VERSION {
+            return Err(SearchCacheError::InvalidFormat);
+        }
+
+        let (encryption_key, mac_key) = derive_keys(key)?;
+
+        let (authenticated, mac) = data.split_at(data.len() - MAC_SIZE);
+        if !verify_hmac(&[authenticated], &mac_key, mac)? {
+            return Err(SearchCacheError::IntegrityCheckFailed);
+        }
+
+        let nonce = &authenticated[MAGIC.len() + 4..header_size];
+        let plaintext =
+            ChaCha20Cipher::new_key_iv(&encryption_key, nonce)?.decrypt(&authenticated[header_size..])?;
+
+        Self::deserialize(&plaintext)
+    }
+
+    fn serialize(&self) -> Result<Vec<u8>, std::io::Error> {
+        let mut out = Vec::new();
+
+        out.write_u32::<LittleEndian>(self.entries.len() as u32)?;
+        for entry in self.entries.values() {
+            out.write_all(entry.uuid.as_bytes())?;
+            match entry.last_modification {
+                Some(t) => {
+                    out.write_u8(1)?;
+                    out.write_i64::<LittleEndian>(t.and_utc().timestamp())?;
+                }
+                None => out.write_u8(0)?,
+            }
+            write_string(&mut out, &entry.title)?;
+            write_string(&mut out, &entry.url)?;
+            out.write_u32::<LittleEndian>(entry.tags.len() as u32)?;
+            for tag in &entry.tags {
+                write_string(&mut out, tag)?;
+            }
+        }
+
+        Ok(out)
+    }
+
+    fn deserialize(data: &[u8]) -> Result<Self, SearchCacheError> {
+        let mut cursor = Cursor::new(data);
+        let mut entries = BTreeMap::new();
+
+        let count = cursor.read_u32::<LittleEndian>()?;
+        for _ in 0..count {
+            let mut uuid = [0u8; 16];
+            cursor.read_exact(&mut uuid)?;
+            let uuid = Uuid::from_bytes(uuid);
+
+            let last_modification = match cursor.read_u8()? {
+                0 => None,
+                _ => Some(
+                    chrono::DateTime::from_timestamp(cursor.read_i64::<LittleEndian>()?, 0)
+                        .ok_or(SearchCacheError::InvalidFormat)?
+                        .naive_utc(),
+                ),
+            };
+
+            let title = read_string(&mut cursor)?;
+            let url = read_string(&mut cursor)?;
+
+            let tag_count = cursor.read_u32::<LittleEndian>()?;
+            let mut tags = Vec::new();
+            for _ in 0..tag_count {
+                tags.push(read_string(&mut cursor)?);
+            }
+
+            entries.insert(
+                uuid,
+                IndexedEntry {
+                    uuid,
+                    title,
+                    url,
+                    tags,
+                    last_modification,
+                },
+            );
+        }
+
+        Ok(SearchIndex { entries })
+    }
+}
+
+/// Derive separate keys for encryption and authentication from the caller-supplied secret
+fn derive_keys(key: &[u8]) -> Result<(Vec<u8>, Vec<u8>), CryptographyError> {
+    let encryption_key = calculate_hmac(&[b"keepass-rs search index encryption"], key)?;
+    let mac_key = calculate_hmac(&[b"keepass-rs search index authentication"], key)?;
+    Ok((encryption_key.to_vec(), mac_key.to_vec()))
+}
+
+fn write_string(out: &mut Vec<u8>, s: &str) -> Result<(), std::io::Error> {
+    out.write_u32::<LittleEndian>(s.len() as u32)?;
+    out.write_all(s.as_bytes())
+}
+
+fn read_string(cursor: &mut Cursor<&[u8]>) -> Result<String, SearchCacheError> {
+    let len = cursor.read_u32::<LittleEndian>()? as usize;
+    let remaining = cursor.get_ref().len() - cursor.position() as usize;
+    if len > remaining {
+        return Err(SearchCacheError::InvalidFormat);
+    }
+
+    let mut buf = vec![0u8; len];
+    cursor.read_exact(&mut buf)?;
+    String::from_utf8(buf).map_err(|_| SearchCacheError::InvalidFormat)
+}
+
+#[cfg(test)]
+mod search_cache_tests {
+    use crate::{
+        db::{Entry, Group, Node, Value},
+        Database,
+    };
+
+    use super::{SearchCacheError, SearchIndex};
+
+    fn entry(title: &str, url: &str, tags: &[&str]) -> Entry {
+        let mut entry = Entry::new();
+        entry
+            .fields
+            .insert("Title".to_string(), Value::Unprotected(title.to_string()));
+        entry
+            .fields
+            .insert("URL".to_string(), Value::Unprotected(url.to_string()));
+        entry.tags = tags.iter().map(|t| t.to_string()).collect();
+        entry
+    }
+
+    fn test_database() -> Database {
+        let mut db = Database::new(Default::default());
+        db.root
+            .add_child(entry("Email", "https://mail.example.com", &["work"]));
+
+        let mut group = Group::new("Shopping");
+        group.add_child(entry("Bookshop", "https://books.example.com", &["Private"]));
+        db.root.add_child(group);
+
+        db
+    }
+
+    #[test]
+    fn test_search() {
+        let index = SearchIndex::build(&test_database());
+
+        assert_eq!(index.entries().count(), 2);
+        assert_eq!(index.search("EMAIL").len(), 1);
+        assert_eq!(index.search("example.com").len(), 2);
+        assert_eq!(index.search("private")[0].title, "Bookshop");
+        assert!(index.search("missing").is_empty());
+    }
+
+    #[test]
+    fn test_update() {
+        let mut db = test_database();
+        let mut index = SearchIndex::build(&db);
+        assert!(index.update(&db).is_empty());
+
+        let new_entry = entry("Bank", "https://bank.example.com", &[]);
+        let new_uuid = new_entry.uuid;
+        db.root.add_child(new_entry);
+
+        let removed = db.root.children.remove(0);
+
+        let shopping = match &mut db.root.children[0] {
+            Node::Group(g) => g,
+            _ => panic!("Expected the shopping group"),
+        };
+        let updated_uuid = shopping.entries_mut()[0].uuid;
+        shopping.entries_mut()[0]
+            .fields
+            .insert("Title".to_string(), Value::Unprotected("Books".to_string()));
+
+        let update = index.update(&db);
+        assert_eq!(update.added, vec![new_uuid]);
+        assert_eq!(update.updated, vec![updated_uuid]);
+        assert_eq!(update.removed, vec![removed.uuid()]);
+        assert_eq!(index.search("books")[0].title, "Books");
+    }
+
+    #[test]
+    fn test_encryption_roundtrip() -> Result<(), SearchCacheError> {
+        let index = SearchIndex::build(&test_database());
+
+        let encrypted = index.to_encrypted_bytes(b"cache key")?;
+        assert!(!encrypted.windows(5).any(|w| w == b"Email"));
+
+        let decrypted = SearchIndex::from_encrypted_bytes(&encrypted, b"cache key")?;
+        assert_eq!(decrypted, index);
+
+        assert!(matches!(
+            SearchIndex::from_encrypted_bytes(&encrypted, b"wrong key"),
+            Err(SearchCacheError::IntegrityCheckFailed)
+        ));
+
+        let mut tampered = encrypted.clone();
+        let last = tampered.len() - 40;
+        tampered[last] ^= 1;
+        assert!(matches!(
+            SearchIndex::from_encrypted_bytes(&tampered, b"cache key"),
+            Err(SearchCacheError::IntegrityCheckFailed)
+        ));
+
+        assert!(matches!(
+            SearchIndex::from_encrypted_bytes(b"garbage", b"cache key"),
+            Err(SearchCacheError::InvalidFormat)
+        ));
+
+        Ok(())
+    }
+}
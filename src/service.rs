@@ -0,0 +1,332 @@
+//! Credential lookup for browser extensions
+//!
+//! Browser extensions like keepassxc-browser ask a native messaging host for the logins of the
+//! page that is open. `Database::find_logins_for_url` answers such requests the way KeePassXC
+//! does, so that a host built on this library behaves the same for existing databases:
+//!
+//! ```
+//! use keepass::{db::Entry, Database};
+//!
+//! let mut db = Database::new(Default::default());
+//! db.root.add_child(
+//!     Entry::new()
+//!         .with_title("Example")
+//!         .with_url("https://example.com")
+//!         .with_username("alice"),
+//! );
+//!
+//! let logins = db.find_logins_for_url("https://accounts.example.com/login");
+//! assert_eq!(logins[0].entry.get_username(), Some("alice"));
+//! ```
+//!
+//! An entry matches if its URL or one of its `KP2A_URL*` fields matches the page as described in
+//! `url_match`, by default if the page is on its host or a subdomain of it. The browser
+//! integration settings of KeePassXC are honored: entries and groups can be hidden from browsers
+//! or restricted to HTTP authentication dialogs with the custom data items `BrowserHideEntry`,
+//! `BrowserOnlyHttpAuth` and `BrowserNotHttpAuth` set to `true`. Entries inherit these settings
+//! from their groups unless they set them to `false` themselves. Entries in the recycle bin and,
+//! unless requested otherwise, expired entries are never returned.
+
+use crate::{
+    db::{CustomData, Database, Entry, Group, Times, Value},
+    url_match::{entry_urls, match_level, MatchMode},
+};
+
+/// Custom data key that hides an entry, or all entries of a group, from browsers
+pub const HIDE_ENTRY_KEY: &str = "BrowserHideEntry";
+
+/// Custom data key that offers an entry only for HTTP authentication dialogs
+pub const ONLY_HTTP_AUTH_KEY: &str = "BrowserOnlyHttpAuth";
+
+/// Custom data key that never offers an entry for HTTP authentication dialogs
+pub const NOT_HTTP_AUTH_KEY: &str = "BrowserNotHttpAuth";
+
+/// Custom data key that keeps browsers from submitting a form after filling in an entry
+pub const SKIP_AUTO_SUBMIT_KEY: &str = "BrowserSkipAutoSubmit";
+
+/// A request for the logins of a web page
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LoginRequest {
+    /// The URL of the page
+    pub url: String,
+
+    /// The URL a login form is submitted to, if it differs from the page. Entries matching it
+    /// are ranked higher.
+    pub submit_url: Option<String>,
+
+    /// Whether the logins are for an HTTP authentication dialog rather than a form
+    pub http_auth: bool,
+
+    /// Whether expired entries are returned as well
+    pub allow_expired: bool,
+
+    /// How closely the URL of an entry has to match the page
+    pub match_mode: MatchMode,
+}
+
+/// An entry matching a login request
+#[derive(Debug, Clone, Copy)]
+pub struct Login<'a> {
+    pub entry: &'a Entry,
+
+    /// The group holding the entry
+    pub group: &'a Group,
+
+    /// Whether the entry has expired
+    pub expired: bool,
+
+    /// Whether the browser should not submit the form after filling in the entry
+    pub skip_auto_submit: bool,
+}
+
+/// Browser integration settings, inherited from the groups of an entry
+#[derive(Debug, Clone, Copy, Default)]
+struct Settings {
+    hide: bool,
+    only_http_auth: bool,
+    not_http_auth: bool,
+    skip_auto_submit: bool,
+}
+
+impl Settings {
+    /// The settings of a group or entry, falling back to the inherited ones where it has none
+    fn apply(self, custom_data: &CustomData) -> Settings {
+        let get = |key: &str, inherited: bool| match custom_data
+            .items
+            .get(key)
+            .and_then(|item| item.value.as_ref())
+        {
+            Some(value) => matches!(value, Value::Unprotected(v) if v.eq_ignore_ascii_case("true")),
+            None => inherited,
+        };
+
+        Settings {
+            hide: get(HIDE_ENTRY_KEY, self.hide),
+            only_http_auth: get(ONLY_HTTP_AUTH_KEY, self.only_http_auth),
+            not_http_auth: get(NOT_HTTP_AUTH_KEY, self.not_http_auth),
+            skip_auto_submit: get(SKIP_AUTO_SUBMIT_KEY, self.skip_auto_submit),
+        }
+    }
+}
+
+impl Database {
+    /// The logins for a web page, best matches first
+    pub fn find_logins_for_url(&self, url: &str) -> Vec<Login<'_>> {
+        self.find_logins(&LoginRequest {
+            url: url.to_string(),
+            ..Default::default()
+        })
+    }
+
+    /// The logins matching a request from a browser, best matches first
+    pub fn find_logins(&self, request: &LoginRequest) -> Vec<Login<'_>> {
+        let recycle_bin = match self.meta.recyclebin_enabled {
+            Some(false) => None,
+            _ => self.meta.recyclebin_uuid,
+        };
+
+        let mut found = Vec::new();
+        let search = Search {
+            request,
+            recycle_bin,
+            now: Times::now(),
+        };
+        search.visit(&self.root, Settings::default(), &mut found);
+
+        // stable, so equally good matches stay in database order
+        found.sort_by(|(a, _), (b, _)| b.cmp(a));
+        found.into_iter().map(|(_, login)| login).collect()
+    }
+}
+
+struct Search<'r> {
+    request: &'r LoginRequest,
+    recycle_bin: Option<uuid::Uuid>,
+    now: chrono::NaiveDateTime,
+}
+
+impl Search<'_> {
+    fn visit<'a>(&self, group: &'a Group, inherited: Settings, found: &mut Vec<((usize, usize), Login<'a>)>) {
+        if Some(group.uuid) == self.recycle_bin {
+            return;
+        }
+        let settings = inherited.apply(&group.custom_data);
+
+        for entry in group.entries() {
+            let settings = settings.apply(&entry.custom_data);
+            if settings.hide
+                || (settings.only_http_auth && !self.request.http_auth)
+                || (settings.not_http_auth && self.request.http_auth)
+            {
+                continue;
+            }
+
+            let expired = entry.times.expires && entry.get_expiry_time().is_some_and(|t| *t <= self.now);
+            if expired && !self.request.allow_expired {
+                continue;
+            }
+
+            let score = match self.score(entry) {
+                Some(score) => score,
+                None => continue,
+            };
+            found.push((
+                score,
+                Login {
+                    entry,
+                    group,
+                    expired,
+                    skip_auto_submit: settings.skip_auto_submit,
+                },
+            ));
+        }
+
+        for child in group.groups() {
+            self.visit(child, settings, found);
+        }
+    }
+
+    /// How well the best URL of an entry matches the request, if any matches. Stricter matches
+    /// rank higher, and among URLs the page starts with the longer, more specific one.
+    fn score(&self, entry: &Entry) -> Option<(usize, usize)> {
+        // a form submitted to the entry's URL is a strong hint
+        let score = |level: MatchMode| level as usize + 1;
+        entry_urls(entry)
+            .filter_map(|entry_url| {
+                let level =
+                    match_level(entry_url, &self.request.url).filter(|l| *l >= self.request.match_mode)?;
+                let submit = self
+                    .request
+                    .submit_url
+                    .as_deref()
+                    .and_then(|u| match_level(entry_url, u));
+                let prefix = if level == MatchMode::StartsWith {
+                    entry_url.len()
+                } else {
+                    0
+                };
+                Some((score(level) + submit.map_or(0, |s| 2 * score(s)), prefix))
+            })
+            .max()
+    }
+}
+
+#[cfg(test)]
+mod service_tests {
+    use chrono::Duration;
+
+    use super::{LoginRequest, HIDE_ENTRY_KEY, NOT_HTTP_AUTH_KEY, ONLY_HTTP_AUTH_KEY, SKIP_AUTO_SUBMIT_KEY};
+    use crate::db::{CustomData, CustomDataItem, Database, Entry, Group, Times, Value};
+
+    fn set(custom_data: &mut CustomData, key: &str, value: &str) {
+        custom_data.items.insert(
+            key.to_string(),
+            CustomDataItem {
+                value: Some(Value::Unprotected(value.to_string())),
+                last_modification_time: None,
+            },
+        );
+    }
+
+    fn titles(db: &Database, request: &LoginRequest) -> Vec<String> {
+        db.find_logins(request)
+            .iter()
+            .map(|l| l.entry.get_title().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_find_logins_for_url() {
+        let mut db = Database::new(Default::default());
+        db.root
+            .add_child(Entry::new().with_title("domain").with_url("example.com"));
+        db.root.add_child(
+            Entry::new()
+                .with_title("host")
+                .with_url("https://accounts.example.com"),
+        );
+        db.root.add_child(
+            Entry::new()
+                .with_title("path")
+                .with_url("https://accounts.example.com/login"),
+        );
+        db.root.add_child(
+            Entry::new()
+                .with_title("additional")
+                .with_url("https://other.org")
+                .with_field(
+                    "KP2A_URL_1",
+                    Value::Unprotected("https://example.com".to_string()),
+                ),
+        );
+        db.root
+            .add_child(Entry::new().with_title("http").with_url("http://example.com"));
+        db.root.add_child(
+            Entry::new()
+                .with_title("port")
+                .with_url("https://example.com:8443"),
+        );
+        db.root.add_child(
+            Entry::new()
+                .with_title("lookalike")
+                .with_url("https://notexample.com"),
+        );
+
+        let found: Vec<_> = db
+            .find_logins_for_url("https://accounts.example.com/login?next=/")
+            .iter()
+            .map(|l| l.entry.get_title().unwrap().to_string())
+            .collect();
+        assert_eq!(found, vec!["path", "host", "domain", "additional"]);
+
+        assert!(db.find_logins_for_url("not a url").is_empty());
+    }
+
+    #[test]
+    fn test_browser_settings() {
+        let mut db = Database::new(Default::default());
+
+        let mut hidden_group = Group::new("hidden");
+        set(&mut hidden_group.custom_data, HIDE_ENTRY_KEY, "true");
+        hidden_group.add_child(Entry::new().with_title("hidden by group").with_url("example.com"));
+        let mut shown = Entry::new()
+            .with_title("shown in hidden group")
+            .with_url("example.com");
+        set(&mut shown.custom_data, HIDE_ENTRY_KEY, "false");
+        set(&mut shown.custom_data, SKIP_AUTO_SUBMIT_KEY, "true");
+        hidden_group.add_child(shown);
+        db.root.add_child(hidden_group);
+
+        let mut http_auth = Entry::new().with_title("http auth").with_url("example.com");
+        set(&mut http_auth.custom_data, ONLY_HTTP_AUTH_KEY, "true");
+        db.root.add_child(http_auth);
+        let mut form = Entry::new().with_title("form").with_url("example.com");
+        set(&mut form.custom_data, NOT_HTTP_AUTH_KEY, "true");
+        db.root.add_child(form);
+
+        let mut expired = Entry::new().with_title("expired").with_url("example.com");
+        expired.times.expires = true;
+        expired.times.set_expiry(Times::now() - Duration::days(1));
+        db.root.add_child(expired);
+
+        let mut trash = Group::new("Recycle Bin");
+        trash.add_child(Entry::new().with_title("deleted").with_url("example.com"));
+        db.meta.recyclebin_uuid = Some(trash.uuid);
+        db.root.add_child(trash);
+
+        let mut request = LoginRequest {
+            url: "https://example.com".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(titles(&db, &request), vec!["form", "shown in hidden group"]);
+        assert!(db.find_logins(&request)[1].skip_auto_submit);
+
+        request.http_auth = true;
+        request.allow_expired = true;
+        assert_eq!(
+            titles(&db, &request),
+            vec!["http auth", "expired", "shown in hidden group"]
+        );
+        assert!(db.find_logins(&request)[1].expired);
+    }
+}
@@ -0,0 +1,385 @@
+//! Entries as rows of a table
+//!
+//! `TableView` adapts a list of entries, e.g. the entries of a group or the result of a search, to
+//! the rows and columns a table widget expects. Rows are identified by the UUID of their entry, so
+//! a user interface can rebuild the view after the database was edited and find the selected row
+//! again with `TableView::position`.
+//!
+//! Protected fields are never copied into cells, they show as `********`. A widget that wants to
+//! show a password reads it from the entry of the row, which is also available through
+//! `TableView::row`. User names and URLs can be redacted as well with `TableView::with_redaction`,
+//! e.g. for screenshots.
+
+use std::{borrow::Cow, cmp::Ordering};
+
+use uuid::Uuid;
+
+use crate::{
+    db::{Entry, Filter, Group, NodeRef, Value},
+    redact::RedactionProfile,
+};
+
+/// Shown in place of protected values
+pub const MASKED_VALUE: &str = "********";
+
+/// What a column of the table shows
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Column {
+    /// A string field of the entry, e.g. `Title` or `UserName`
+    Field(String),
+
+    /// The tags of the entry, separated by commas
+    Tags,
+
+    /// The creation time
+    Created,
+
+    /// The time of the last modification
+    Modified,
+
+    /// The expiry time, if the entry expires
+    Expires,
+}
+
+impl Column {
+    /// Column for a string field of the entry
+    pub fn field(name: &str) -> Self {
+        Column::Field(name.to_string())
+    }
+
+    /// The default columns: title, user name and URL
+    pub fn defaults() -> Vec<Column> {
+        vec![
+            Column::field("Title"),
+            Column::field("UserName"),
+            Column::field("URL"),
+        ]
+    }
+
+    /// A header for the column
+    pub fn title(&self) -> &str {
+        match self {
+            Column::Field(name) => name,
+            Column::Tags => "Tags",
+            Column::Created => "Created",
+            Column::Modified => "Modified",
+            Column::Expires => "Expires",
+        }
+    }
+
+    fn cell<'a>(&self, entry: &'a Entry) -> Option<Cow<'a, str>> {
+        let time = match self {
+            Column::Field(name) => {
+                return match entry.fields.get(name)? {
+                    Value::Unprotected(value) => Some(Cow::Borrowed(value.as_str())),
+                    Value::Protected(_) => Some(Cow::Borrowed(MASKED_VALUE)),
+                    Value::Bytes(_) => None,
+                }
+            }
+            Column::Tags => {
+                if entry.tags.is_empty() {
+                    return None;
+                }
+                return Some(Cow::Owned(entry.tags.join(", ")));
+            }
+            Column::Created => entry.times.get_creation(),
+            Column::Modified => entry.times.get_last_modification(),
+            Column::Expires => entry.get_expiry_time(),
+        };
+
+        // sorting the formatted time sorts by time
+        time.map(|t| Cow::Owned(t.format("%Y-%m-%d %H:%M:%S").to_string()))
+    }
+}
+
+/// Direction of sorting
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortOrder {
+    Ascending,
+    Descending,
+}
+
+/// A sortable, filterable table over a list of entries
+#[derive(Debug, Clone)]
+pub struct TableView<'a> {
+    columns: Vec<Column>,
+    entries: Vec<&'a Entry>,
+    rows: Vec<&'a Entry>,
+    sort: Option<(usize, SortOrder)>,
+    filter: String,
+    redaction: RedactionProfile,
+}
+
+impl<'a> TableView<'a> {
+    /// Create a table over the given entries, in their original order
+    pub fn new(entries: impl IntoIterator<Item = &'a Entry>, columns: Vec<Column>) -> Self {
+        let entries: Vec<&'a Entry> = entries.into_iter().collect();
+
+        TableView {
+            columns,
+            rows: entries.clone(),
+            entries,
+            sort: None,
+            filter: String::new(),
+            redaction: RedactionProfile::none(),
+        }
+    }
+
+    /// Create a table over the entries of a group. With `recursive`, the entries of all subgroups
+    /// are included as well.
+    pub fn for_group(group: &'a Group, columns: Vec<Column>, recursive: bool) -> Self {
+        if recursive {
+            let entries = group.iter().filter_map(|node| match node {
+                NodeRef::Entry(e) => Some(e),
+                NodeRef::Group(_) => None,
+            });
+            Self::new(entries, columns)
+        } else {
+            Self::new(group.entries(), columns)
+        }
+    }
+
+    /// Create a table over the entries under `root` that match a filter
+    pub fn for_filter(root: &'a Group, filter: &Filter, columns: Vec<Column>) -> Self {
+        Self::new(filter.select(root), columns)
+    }
+
+    /// Carry the columns, sorting and filter of this table over to another list of entries, e.g.
+    /// after the database was edited
+    pub fn rebind<'b>(&self, entries: impl IntoIterator<Item = &'b Entry>) -> TableView<'b> {
+        let mut view = TableView::new(entries, self.columns.clone());
+        view.sort = self.sort;
+        view.filter = self.filter.clone();
+        view.redaction = self.redaction.clone();
+        view.update();
+        view
+    }
+
+    /// Redact user names and URLs in cells. Sorting and filtering still use the original values.
+    pub fn with_redaction(mut self, redaction: RedactionProfile) -> Self {
+        self.redaction = redaction;
+        self
+    }
+
+    pub fn redaction(&self) -> &RedactionProfile {
+        &self.redaction
+    }
+
+    pub fn columns(&self) -> &[Column] {
+        &self.columns
+    }
+
+    /// Number of rows left after filtering
+    pub fn len(&self) -> usize {
+        self.rows.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+
+    /// The entry shown in a row
+    pub fn row(&self, row: usize) -> Option<&'a Entry> {
+        self.rows.get(row).copied()
+    }
+
+    /// The stable identity of a row, i.e. the UUID of its entry
+    pub fn row_id(&self, row: usize) -> Option<Uuid> {
+        self.row(row).map(|e| e.uuid)
+    }
+
+    /// The row showing the entry with the given UUID, if it is not filtered out
+    pub fn position(&self, uuid: Uuid) -> Option<usize> {
+        self.rows.iter().position(|e| e.uuid == uuid)
+    }
+
+    /// The content of a cell, or `None` if the entry has no value for the column
+    pub fn cell(&self, row: usize, column: usize) -> Option<Cow<'a, str>> {
+        let column = self.columns.get(column)?;
+        column
+            .cell(self.row(row)?)
+            .map(|value| self.redact(column, value))
+    }
+
+    /// All cells of a row, with empty strings for missing values
+    pub fn cells(&self, row: usize) -> Option<Vec<Cow<'a, str>>> {
+        let entry = self.row(row)?;
+        Some(
+            self.columns
+                .iter()
+                .map(|c| c.cell(entry).map(|v| self.redact(c, v)).unwrap_or_default())
+                .collect(),
+        )
+    }
+
+    fn redact(&self, column: &Column, value: Cow<'a, str>) -> Cow<'a, str> {
+        match (column, &value) {
+            // field cells borrow from the entry, only tags and times are formatted
+            (Column::Field(name), Cow::Borrowed(v)) => self.redaction.redact_field(name, v),
+            _ => value,
+        }
+    }
+
+    /// Sort the rows by a column, ignoring case. Rows with equal values keep their original
+    /// order.
+    pub fn sort_by(&mut self, column: usize, order: SortOrder) {
+        self.sort = Some((column, order));
+        self.update();
+    }
+
+    /// Go back to the original order of the entries
+    pub fn clear_sort(&mut self) {
+        self.sort = None;
+        self.update();
+    }
+
+    pub fn sort(&self) -> Option<(usize, SortOrder)> {
+        self.sort
+    }
+
+    /// Only show rows where any cell contains `text`, ignoring case. An empty text shows all
+    /// rows. Protected values are not searched.
+    pub fn set_filter(&mut self, text: &str) {
+        self.filter = text.to_lowercase();
+        self.update();
+    }
+
+    pub fn filter(&self) -> &str {
+        &self.filter
+    }
+
+    fn update(&mut self) {
+        let columns = &self.columns;
+        let filter = &self.filter;
+
+        self.rows = self
+            .entries
+            .iter()
+            .copied()
+            .filter(|entry| {
+                filter.is_empty()
+                    || columns.iter().any(|c| match c {
+                        Column::Field(name) if matches!(entry.fields.get(name), Some(Value::Protected(_))) => {
+                            false
+                        }
+                        c => c
+                            .cell(entry)
+                            .is_some_and(|value| value.to_lowercase().contains(filter.as_str())),
+                    })
+            })
+            .collect();
+
+        if let Some((column, order)) = self.sort {
+            let column = match columns.get(column) {
+                Some(c) => c,
+                None => return,
+            };
+
+            let key = |entry: &Entry| column.cell(entry).map(|v| v.to_lowercase());
+            self.rows.sort_by(|a, b| {
+                // missing values go last in both directions
+                let ordering = match (key(a), key(b)) {
+                    (Some(a), Some(b)) => a.cmp(&b),
+                    (Some(_), None) => return Ordering::Less,
+                    (None, Some(_)) => return Ordering::Greater,
+                    (None, None) => Ordering::Equal,
+                };
+                match order {
+                    SortOrder::Ascending => ordering,
+                    SortOrder::Descending => ordering.reverse(),
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod table_tests {
+    use crate::{
+        db::{Database, Entry, Group, Value},
+        redact::RedactionProfile,
+    };
+
+    use super::{Column, SortOrder, TableView, MASKED_VALUE};
+
+    fn entry(title: &str, user: Option<&str>) -> Entry {
+        let mut entry = Entry::new();
+        entry
+            .fields
+            .insert("Title".to_string(), Value::Unprotected(title.to_string()));
+        if let Some(user) = user {
+            entry
+                .fields
+                .insert("UserName".to_string(), Value::Unprotected(user.to_string()));
+        }
+        entry.fields.insert(
+            "Password".to_string(),
+            Value::Protected("secret".as_bytes().into()),
+        );
+        entry
+    }
+
+    #[test]
+    fn test_table_view() {
+        let mut db = Database::new(Default::default());
+        db.root.add_child(entry("mail", Some("bob")));
+        db.root.add_child(entry("Bank", None));
+        let mut group = Group::new("Work");
+        group.add_child(entry("ci", Some("Alice")));
+        db.root.add_child(group);
+
+        let columns = vec![
+            Column::field("Title"),
+            Column::field("UserName"),
+            Column::field("Password"),
+        ];
+        assert_eq!(TableView::for_group(&db.root, columns.clone(), false).len(), 2);
+
+        let mut view = TableView::for_group(&db.root, columns, true);
+        assert_eq!(view.len(), 3);
+        assert_eq!(view.cell(0, 0).unwrap(), "mail");
+        assert_eq!(view.cell(0, 2).unwrap(), MASKED_VALUE);
+        assert_eq!(view.cell(1, 1), None);
+        assert_eq!(view.cells(1).unwrap(), vec!["Bank", "", MASKED_VALUE]);
+
+        view.sort_by(0, SortOrder::Ascending);
+        let titles: Vec<_> = (0..view.len()).map(|r| view.cell(r, 0).unwrap()).collect();
+        assert_eq!(titles, vec!["Bank", "ci", "mail"]);
+
+        // missing values go last
+        view.sort_by(1, SortOrder::Descending);
+        let titles: Vec<_> = (0..view.len()).map(|r| view.cell(r, 0).unwrap()).collect();
+        assert_eq!(titles, vec!["mail", "ci", "Bank"]);
+
+        view.set_filter("AL");
+        assert_eq!(view.len(), 1);
+        assert_eq!(view.cell(0, 0).unwrap(), "ci");
+
+        // protected values are not searched
+        view.set_filter("secret");
+        assert!(view.is_empty());
+        view.set_filter("");
+        assert_eq!(view.len(), 3);
+
+        // rows keep their identity across edits
+        let uuid = view.row_id(0).unwrap();
+        let mut edited = db.clone();
+        edited.root.entries_mut()[0]
+            .fields
+            .insert("UserName".to_string(), Value::Unprotected("aaron".to_string()));
+        let view = view.rebind(edited.root.iter().filter_map(|n| match n {
+            crate::db::NodeRef::Entry(e) => Some(e),
+            _ => None,
+        }));
+        assert_eq!(view.sort(), Some((1, SortOrder::Descending)));
+        assert_eq!(view.position(uuid), Some(1));
+        assert_eq!(view.cell(1, 1).unwrap(), "aaron");
+
+        // redaction only changes what is shown
+        let mut view = view.with_redaction(RedactionProfile::screenshot_safe());
+        assert_eq!(view.cell(1, 1).unwrap(), "aa…");
+        view.set_filter("aaron");
+        assert_eq!(view.cells(0).unwrap(), vec!["mail", "aa…", MASKED_VALUE]);
+        assert_eq!(view.rebind(view.row(0)).redaction(), view.redaction());
+    }
+}
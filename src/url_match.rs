@@ -0,0 +1,518 @@
+//! Matching entry URLs against the URL of a web page
+//!
+//! Browser integrations, autofill services and search boxes all need to decide whether an entry
+//! belongs to a page. The rules here follow KeePassXC, from the strictest to the loosest:
+//!
+//! - `MatchMode::Exact`: the same URL, ignoring the fragment and a trailing slash
+//! - `MatchMode::StartsWith`: the page URL starts with the entry URL, e.g. an entry for
+//!   `https://example.com/mail` matches `https://example.com/mail/inbox`
+//! - `MatchMode::Host`: the same host name
+//! - `MatchMode::Domain`: the page is on the host of the entry or a subdomain of it, within the
+//!   same registrable domain
+//!
+//! ```
+//! use keepass::url_match::{match_level, matches, MatchMode};
+//!
+//! assert!(matches("https://google.com", "https://accounts.google.com/login", MatchMode::Domain));
+//! assert!(!matches("https://google.com", "https://accounts.google.com/login", MatchMode::Host));
+//! assert_eq!(
+//!     match_level("example.com/mail", "https://example.com/mail/inbox"),
+//!     Some(MatchMode::StartsWith)
+//! );
+//! ```
+//!
+//! `entry_match_level` looks at the URL of an entry and its additional URLs in `KP2A_URL*` fields,
+//! and `db::Filter::url_matches` searches a database with it.
+//!
+//! If the entry URL names a scheme or a port, the page has to use the same. Entry URLs without a
+//! scheme, like `example.com`, match pages with any scheme.
+//!
+//! Registrable domains are found with a public suffix list, so that an entry for `alice.github.io`
+//! does not match `mallory.github.io`. A built-in list covers common country code second-level
+//! domains and hosting platforms. Applications that ship the full list from
+//! <https://publicsuffix.org> can load it with `PublicSuffixList::parse` and pass it to
+//! `match_level_with`.
+//!
+//! The rest of the crate splits URLs with `UrlParts`, which normalizes them for comparisons, and
+//! `UrlComponents`, which keeps the text as written.
+
+use std::{collections::HashSet, sync::OnceLock};
+
+use crate::db::Entry;
+
+/// Prefix of the fields holding additional URLs of an entry, as used by KeePassXC and
+/// KeePass2Android
+pub const ADDITIONAL_URL_PREFIX: &str = "KP2A_URL";
+
+/// How closely an entry URL has to match a page, from the loosest to the strictest
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serialization", derive(serde::Serialize, serde::Deserialize))]
+pub enum MatchMode {
+    /// The host of the entry or a subdomain of it, within the same registrable domain
+    #[default]
+    Domain,
+
+    /// The same host name
+    Host,
+
+    /// The page URL starts with the entry URL
+    StartsWith,
+
+    /// The same URL
+    Exact,
+}
+
+/// The parts of a URL that matching looks at
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UrlParts {
+    /// The scheme in lowercase, if the URL has one
+    pub scheme: Option<String>,
+
+    /// The host name in lowercase, without brackets around IPv6 addresses and without a
+    /// trailing dot
+    pub host: String,
+
+    /// The port, if the URL names one
+    pub port: Option<u16>,
+
+    /// The path without a trailing slash, with the query if there is one
+    pub path: String,
+}
+
+impl UrlParts {
+    /// Split a URL into its parts. URLs without a scheme are taken to start with the host name.
+    /// Returns `None` if there is no host name or the port is not a number.
+    pub fn parse(url: &str) -> Option<UrlParts> {
+        let components = UrlComponents::split(url.trim());
+
+        let port = match components.port {
+            Some(port) => Some(port.parse().ok()?),
+            None => None,
+        };
+        let host = match components.host.strip_prefix('[') {
+            Some(ipv6) => ipv6.strip_suffix(']')?,
+            // anything after a colon that is not a port
+            None if components.host.contains(':') => return None,
+            None => components.host,
+        };
+        let host = host.trim_end_matches('.').to_lowercase();
+        if host.is_empty() {
+            return None;
+        }
+
+        Some(UrlParts {
+            scheme: components.scheme.map(str::to_ascii_lowercase),
+            host,
+            port,
+            path: format!("{}{}", components.path.trim_end_matches('/'), components.query),
+        })
+    }
+
+    /// The origin of the URL, e.g. `https://example.com:8443`, or `None` if it has no scheme
+    pub fn origin(&self) -> Option<String> {
+        let scheme = self.scheme.as_ref()?;
+        let host = match self.host.contains(':') {
+            true => format!("[{}]", self.host),
+            false => self.host.clone(),
+        };
+        Some(match self.port {
+            Some(port) => format!("{}://{}:{}", scheme, host, port),
+            None => format!("{}://{}", scheme, host),
+        })
+    }
+
+    /// The port, or the default port of the scheme. URLs without a scheme are taken to have the
+    /// scheme of the URL they are compared with.
+    fn effective_port(&self, other: &UrlParts) -> Option<u16> {
+        self.port
+            .or_else(|| default_port(self.scheme.as_ref().or(other.scheme.as_ref())?))
+    }
+}
+
+/// A URL split into its components as written, for callers that need the original text rather
+/// than the normalized `UrlParts`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UrlComponents<'a> {
+    /// The scheme, if the URL has one
+    pub scheme: Option<&'a str>,
+
+    /// The user name and password in front of the host, if the URL has them
+    pub userinfo: Option<&'a str>,
+
+    /// The host name, with the brackets around IPv6 addresses
+    pub host: &'a str,
+
+    /// The port, if the URL names one
+    pub port: Option<&'a str>,
+
+    /// The path, which is empty or starts with a slash
+    pub path: &'a str,
+
+    /// The query including the leading `?`, or empty if there is none
+    pub query: &'a str,
+}
+
+impl<'a> UrlComponents<'a> {
+    /// Split a URL into its components. URLs without a scheme are taken to start with the host
+    /// name, and the fragment is dropped.
+    pub fn split(url: &'a str) -> UrlComponents<'a> {
+        let (scheme, rest) = match url.split_once("://") {
+            Some((scheme, rest)) => (Some(scheme), rest),
+            None => (None, url),
+        };
+
+        let rest = rest.split('#').next().unwrap_or_default();
+        let authority_end = rest.find(['/', '?']).unwrap_or(rest.len());
+        let (authority, path_query) = rest.split_at(authority_end);
+        let (userinfo, host_port) = match authority.rsplit_once('@') {
+            Some((userinfo, host_port)) => (Some(userinfo), host_port),
+            None => (None, authority),
+        };
+
+        // IPv6 addresses are enclosed in brackets to separate them from the port
+        let host_end = match host_port.starts_with('[') {
+            true => host_port.find(']').map_or(host_port.len(), |end| end + 1),
+            false => host_port.rfind(':').unwrap_or(host_port.len()),
+        };
+        let (host, port) = match host_port[host_end..].strip_prefix(':') {
+            Some("") => (&host_port[..host_end], None),
+            Some(port) if port.bytes().all(|b| b.is_ascii_digit()) => (&host_port[..host_end], Some(port)),
+            _ => (host_port, None),
+        };
+
+        let (path, query) = path_query.split_at(path_query.find('?').unwrap_or(path_query.len()));
+
+        UrlComponents {
+            scheme,
+            userinfo,
+            host,
+            port,
+            path,
+            query,
+        }
+    }
+}
+
+/// The port a scheme uses if a URL does not name one
+pub fn default_port(scheme: &str) -> Option<u16> {
+    match scheme.to_ascii_lowercase().as_str() {
+        "http" | "ws" => Some(80),
+        "https" | "wss" => Some(443),
+        "ftp" => Some(21),
+        "ssh" | "sftp" => Some(22),
+        _ => None,
+    }
+}
+
+/// Whether `entry_url` matches the page at `url` at least as closely as `mode` requires
+pub fn matches(entry_url: &str, url: &str, mode: MatchMode) -> bool {
+    match_level(entry_url, url).is_some_and(|level| level >= mode)
+}
+
+/// The strictest mode in which `entry_url` matches the page at `url`, if any, using the built-in
+/// public suffix list
+pub fn match_level(entry_url: &str, url: &str) -> Option<MatchMode> {
+    match_level_with(entry_url, url, PublicSuffixList::builtin())
+}
+
+/// The strictest mode in which `entry_url` matches the page at `url`, if any
+pub fn match_level_with(entry_url: &str, url: &str, suffixes: &PublicSuffixList) -> Option<MatchMode> {
+    let entry = UrlParts::parse(entry_url)?;
+    let page = UrlParts::parse(url)?;
+
+    if let (Some(entry_scheme), Some(page_scheme)) = (&entry.scheme, &page.scheme) {
+        if entry_scheme != page_scheme {
+            return None;
+        }
+    }
+    let same_port = entry.effective_port(&page) == page.effective_port(&entry);
+    if entry.port.is_some() && !same_port {
+        return None;
+    }
+
+    if entry.host == page.host {
+        // an entry without a port matches any port of its host, but not the exact URL
+        if !same_port {
+            return Some(MatchMode::Host);
+        }
+        if entry.path == page.path {
+            return Some(MatchMode::Exact);
+        }
+        if page.path.starts_with(&entry.path) && page.path[entry.path.len()..].starts_with(['/', '?']) {
+            return Some(MatchMode::StartsWith);
+        }
+        return Some(MatchMode::Host);
+    }
+
+    let is_subdomain = page
+        .host
+        .strip_suffix(&entry.host)
+        .is_some_and(|prefix| prefix.ends_with('.'));
+    if is_subdomain && suffixes.base_domain(&page.host) == suffixes.base_domain(&entry.host) {
+        return Some(MatchMode::Domain);
+    }
+
+    None
+}
+
+/// The URL and the additional URLs of an entry
+pub fn entry_urls(entry: &Entry) -> impl Iterator<Item = &str> {
+    let additional = entry
+        .fields
+        .keys()
+        .filter(|name| name.starts_with(ADDITIONAL_URL_PREFIX))
+        .filter_map(move |name| entry.get_unprotected(name));
+
+    entry
+        .get_url()
+        .into_iter()
+        .chain(additional)
+        .map(str::trim)
+        .filter(|url| !url.is_empty())
+}
+
+/// The strictest mode in which any URL of an entry matches the page at `url`, if any
+pub fn entry_match_level(entry: &Entry, url: &str) -> Option<MatchMode> {
+    entry_urls(entry)
+        .filter_map(|entry_url| match_level(entry_url, url))
+        .max()
+}
+
+/// Rules for finding the registrable domain of a host, in the format of
+/// <https://publicsuffix.org/list/>
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PublicSuffixList {
+    rules: HashSet<String>,
+    wildcards: HashSet<String>,
+    exceptions: HashSet<String>,
+}
+
+/// The rules of the built-in public suffix list
+const BUILTIN_RULES: &str = "
+// country code second-level domains
+ac.uk co.uk gov.uk ltd.uk me.uk net.uk org.uk plc.uk sch.uk
+com.au edu.au gov.au net.au org.au id.au
+co.nz net.nz org.nz govt.nz ac.nz
+co.jp ne.jp or.jp ac.jp go.jp
+com.br net.br org.br gov.br
+com.cn net.cn org.cn gov.cn edu.cn
+com.hk com.tw com.sg com.my com.ph com.vn com.pk com.bd
+co.in net.in org.in gov.in ac.in
+co.kr or.kr go.kr
+co.id or.id go.id
+co.il org.il ac.il
+co.za org.za gov.za
+com.mx com.ar com.co com.pe com.ve com.uy
+com.tr gov.tr com.ua com.pl com.es com.gr com.cy
+co.at or.at
+*.ck !www.ck
+// hosting platforms where every subdomain belongs to someone else
+github.io gitlab.io pages.dev workers.dev netlify.app vercel.app web.app firebaseapp.com
+herokuapp.com appspot.com blogspot.com azurewebsites.net cloudfront.net s3.amazonaws.com
+duckdns.org ngrok.io glitch.me fly.dev onrender.com myshopify.com
+";
+
+impl PublicSuffixList {
+    /// Parse a list with one rule per line, like `co.uk`, `*.ck` or `!www.ck`. Lines starting with
+    /// `//` are comments, and only the first word of each line is read.
+    pub fn parse(list: &str) -> PublicSuffixList {
+        let mut suffixes = PublicSuffixList::default();
+        for line in list.lines().map(str::trim).filter(|l| !l.starts_with("//")) {
+            suffixes.add_rules(line.split_whitespace().take(1));
+        }
+        suffixes
+    }
+
+    /// The built-in list of common public suffixes
+    pub fn builtin() -> &'static PublicSuffixList {
+        static BUILTIN: OnceLock<PublicSuffixList> = OnceLock::new();
+        BUILTIN.get_or_init(|| {
+            let mut suffixes = PublicSuffixList::default();
+            for line in BUILTIN_RULES.lines().filter(|l| !l.starts_with("//")) {
+                suffixes.add_rules(line.split_whitespace());
+            }
+            suffixes
+        })
+    }
+
+    fn add_rules<'a>(&mut self, rules: impl Iterator<Item = &'a str>) {
+        for rule in rules.map(str::to_lowercase) {
+            if let Some(exception) = rule.strip_prefix('!') {
+                self.exceptions.insert(exception.to_string());
+            } else if let Some(parent) = rule.strip_prefix("*.") {
+                self.wildcards.insert(parent.to_string());
+            } else {
+                self.rules.insert(rule);
+            }
+        }
+    }
+
+    /// Whether `domain` is a public suffix, under which anyone can register names. Top-level
+    /// domains always are.
+    pub fn is_public_suffix(&self, domain: &str) -> bool {
+        if self.exceptions.contains(domain) {
+            return false;
+        }
+        match domain.split_once('.') {
+            None => true,
+            Some((_, parent)) => self.rules.contains(domain) || self.wildcards.contains(parent),
+        }
+    }
+
+    /// The registrable domain of a host, e.g. `example.co.uk` for `mail.example.co.uk`. IP
+    /// addresses and hosts that are public suffixes themselves are their own base domain.
+    pub fn base_domain<'a>(&self, host: &'a str) -> &'a str {
+        if host.parse::<std::net::IpAddr>().is_ok() {
+            return host;
+        }
+
+        // the longest public suffix wins, so look for the first one from the left
+        let mut base = host;
+        let mut rest = host;
+        while let Some((_, parent)) = rest.split_once('.') {
+            if self.is_public_suffix(parent) {
+                return rest;
+            }
+            base = parent;
+            rest = parent;
+        }
+        base
+    }
+}
+
+#[cfg(test)]
+mod url_match_tests {
+    use super::{match_level, match_level_with, MatchMode, PublicSuffixList, UrlComponents, UrlParts};
+
+    #[test]
+    fn test_match_level() {
+        let cases = [
+            (
+                "https://example.com/",
+                "https://example.com#top",
+                Some(MatchMode::Exact),
+            ),
+            (
+                "https://example.com/a?b=1",
+                "https://example.com/a?b=1",
+                Some(MatchMode::Exact),
+            ),
+            (
+                "example.com/mail",
+                "https://example.com/mail/inbox",
+                Some(MatchMode::StartsWith),
+            ),
+            (
+                "https://example.com/mail",
+                "https://example.com/mailbox",
+                Some(MatchMode::Host),
+            ),
+            (
+                "https://EXAMPLE.com",
+                "https://example.com/login",
+                Some(MatchMode::StartsWith),
+            ),
+            (
+                "https://google.com",
+                "https://accounts.google.com",
+                Some(MatchMode::Domain),
+            ),
+            ("https://mail.google.com", "https://accounts.google.com", None),
+            ("https://notgoogle.com", "https://google.com", None),
+            ("http://example.com", "https://example.com", None),
+            (
+                "https://example.com:443",
+                "https://example.com",
+                Some(MatchMode::Exact),
+            ),
+            ("https://example.com:8443", "https://example.com", None),
+            (
+                "https://example.com",
+                "https://example.com:8443",
+                Some(MatchMode::Host),
+            ),
+            ("https://github.io", "https://alice.github.io", None),
+            (
+                "https://example.co.uk",
+                "https://www.example.co.uk",
+                Some(MatchMode::Domain),
+            ),
+            ("https://co.uk", "https://example.co.uk", None),
+            ("https://[::1]:8080/", "http://[::1]:8080", None),
+            ("[::1]:8080", "http://[::1]:8080/x", Some(MatchMode::StartsWith)),
+            ("", "https://example.com", None),
+        ];
+        for (entry_url, url, expected) in cases.iter() {
+            assert_eq!(
+                match_level(entry_url, url),
+                *expected,
+                "{} for {}",
+                entry_url,
+                url
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_url() {
+        assert_eq!(
+            UrlParts::parse("HTTPS://user:pw@Example.COM.:8080/a/?q#f"),
+            Some(UrlParts {
+                scheme: Some("https".to_string()),
+                host: "example.com".to_string(),
+                port: Some(8080),
+                path: "/a?q".to_string(),
+            })
+        );
+        assert_eq!(UrlParts::parse("https://example.com:http"), None);
+        assert_eq!(
+            UrlParts::parse("HTTP://[::1]:8080/x")
+                .and_then(|u| u.origin())
+                .as_deref(),
+            Some("http://[::1]:8080")
+        );
+        assert_eq!(UrlParts::parse("example.com").unwrap().origin(), None);
+    }
+
+    #[test]
+    fn test_split_url() {
+        assert_eq!(
+            UrlComponents::split("https://user:pw@Mail.Example.com:8443/inbox?a=1#top"),
+            UrlComponents {
+                scheme: Some("https"),
+                userinfo: Some("user:pw"),
+                host: "Mail.Example.com",
+                port: Some("8443"),
+                path: "/inbox",
+                query: "?a=1",
+            }
+        );
+        let ipv6 = UrlComponents::split("[::1]:22");
+        assert_eq!((ipv6.scheme, ipv6.host, ipv6.port), (None, "[::1]", Some("22")));
+        assert_eq!(UrlComponents::split("file:///etc/passwd").host, "");
+    }
+
+    #[test]
+    fn test_public_suffix_list() {
+        let builtin = PublicSuffixList::builtin();
+        assert_eq!(builtin.base_domain("a.b.example.com"), "example.com");
+        assert_eq!(builtin.base_domain("www.example.co.uk"), "example.co.uk");
+        assert_eq!(builtin.base_domain("alice.github.io"), "alice.github.io");
+        assert_eq!(builtin.base_domain("a.b.example.ck"), "b.example.ck");
+        assert_eq!(builtin.base_domain("www.ck"), "www.ck");
+        assert_eq!(builtin.base_domain("com"), "com");
+        assert_eq!(builtin.base_domain("192.168.0.1"), "192.168.0.1");
+
+        let custom = PublicSuffixList::parse("// comment\nexample.com\n\n*.wild.test\n");
+        assert_eq!(custom.base_domain("a.b.example.com"), "b.example.com");
+        assert_eq!(custom.base_domain("a.b.wild.test"), "a.b.wild.test");
+        assert_eq!(
+            match_level_with("https://a.example.com", "https://x.a.example.com", &custom),
+            Some(MatchMode::Domain)
+        );
+        assert_eq!(
+            match_level_with("https://example.com", "https://a.example.com", &custom),
+            None
+        );
+    }
+}
@@ -0,0 +1,402 @@
+//! Watch a database file for changes made by other programs
+//!
+//! ```no_run
+//! use keepass::{watch::watch, Database, DatabaseKey};
+//!
+//! let path = "tests/resources/test_db_with_password.kdbx";
+//! let key = DatabaseKey::new().with_password("demopass");
+//!
+//! let mut db = Database::open(&mut std::fs::File::open(path)?, key.clone())?;
+//! let mut watcher = watch(path)?;
+//!
+//! while let Some(event) = watcher.next() {
+//!     event?;
+//!     if watcher.reload_if_changed(&mut db, key.clone())? {
+//!         println!("Database was reloaded");
+//!     }
+//! }
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+//!
+//! With the `async` feature, `DatabaseWatcher::into_stream` turns the watcher into a `Stream` for
+//! async applications.
+
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+    sync::mpsc::{channel, Receiver, RecvTimeoutError, TryRecvError},
+    time::Duration,
+};
+
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use thiserror::Error;
+
+use crate::{
+    crypt::calculate_sha256,
+    error::{CryptographyError, DatabaseOpenError},
+    Database, DatabaseKey,
+};
+
+/// Default time to wait for further events before reporting a change
+pub const DEFAULT_DEBOUNCE: Duration = Duration::from_millis(250);
+
+/// How often the thread behind a `WatchStream` checks whether the stream was dropped
+#[cfg(feature = "async")]
+const STREAM_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Errors while watching a database file
+#[derive(Debug, Error)]
+pub enum WatchError {
+    #[error(transparent)]
+    Notify(#[from] notify::Error),
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error(transparent)]
+    Cryptography(#[from] CryptographyError),
+
+    #[error(transparent)]
+    Open(#[from] DatabaseOpenError),
+
+    /// The path to watch does not name a file
+    #[error("Not a file path: {}", _0.display())]
+    NotAFile(PathBuf),
+
+    /// The underlying file system watcher stopped sending events
+    #[error("The file system watcher disconnected")]
+    Disconnected,
+}
+
+/// A debounced change to the watched database file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchEvent {
+    /// The file was created, written to or replaced
+    Changed,
+
+    /// The file was removed
+    Removed,
+}
+
+/// Watches a single database file and reports debounced change events.
+///
+/// The parent directory is watched rather than the file itself, so that saves which atomically
+/// replace the file (as done by most desktop clients) are picked up as well.
+///
+/// The watcher is an iterator that blocks until the next event arrives.
+/// With the `async` feature, it can be turned into a stream with `into_stream`.
+pub struct DatabaseWatcher {
+    path: PathBuf,
+    file_name: OsString,
+    debounce: Duration,
+    events: Receiver<notify::Result<notify::Event>>,
+    last_digest: Option<Vec<u8>>,
+
+    // dropping the watcher stops the notifications
+    _watcher: RecommendedWatcher,
+}
+
+/// Start watching the database file at `path`
+pub fn watch(path: impl AsRef<Path>) -> Result<DatabaseWatcher, WatchError> {
+    let path = path.as_ref().to_path_buf();
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| WatchError::NotAFile(path.clone()))?
+        .to_os_string();
+
+    let directory = match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+
+    let (tx, rx) = channel();
+    let mut watcher = notify::recommended_watcher(tx)?;
+    watcher.watch(&directory, RecursiveMode::NonRecursive)?;
+
+    let last_digest = file_digest(&path)?;
+
+    Ok(DatabaseWatcher {
+        path,
+        file_name,
+        debounce: DEFAULT_DEBOUNCE,
+        events: rx,
+        last_digest,
+        _watcher: watcher,
+    })
+}
+
+impl DatabaseWatcher {
+    /// Set how long to wait for further events before reporting a change
+    pub fn with_debounce(mut self, debounce: Duration) -> Self {
+        self.debounce = debounce;
+        self
+    }
+
+    /// The path of the watched database file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Block until the watched file changes
+    pub fn next_event(&mut self) -> Result<WatchEvent, WatchError> {
+        loop {
+            let event = self.events.recv().map_err(|_| WatchError::Disconnected)?;
+            if let Some(event) = self.classify(event?) {
+                return self.debounced(event);
+            }
+        }
+    }
+
+    /// Return the next change of the watched file, if one has happened
+    pub fn try_next_event(&mut self) -> Result<Option<WatchEvent>, WatchError> {
+        loop {
+            let event = match self.events.try_recv() {
+                Ok(event) => event?,
+                Err(TryRecvError::Empty) => return Ok(None),
+                Err(TryRecvError::Disconnected) => return Err(WatchError::Disconnected),
+            };
+
+            if let Some(event) = self.classify(event) {
+                return self.debounced(event).map(Some);
+            }
+        }
+    }
+
+    /// Re-open the database if the contents of the file differ from the version that was last
+    /// seen by this watcher. Returns whether the database was reloaded.
+    ///
+    /// If the file is missing, e.g. because it is in the middle of being replaced, the database
+    /// is left untouched.
+    pub fn reload_if_changed(&mut self, db: &mut Database, key: DatabaseKey) -> Result<bool, WatchError> {
+        reload_if_changed(&self.path, &mut self.last_digest, db, key)
+    }
+
+    /// Turn the watcher into a stream of its events, for async applications. The watcher waits
+    /// for events on a thread of its own, which stops the file system watcher and ends shortly
+    /// after the stream is dropped.
+    #[cfg(feature = "async")]
+    pub fn into_stream(mut self) -> WatchStream {
+        let path = self.path.clone();
+        let last_digest = self.last_digest.take();
+
+        let (sender, events) = tokio::sync::mpsc::unbounded_channel();
+        let thread = std::thread::spawn(move || {
+            while !sender.is_closed() {
+                let event = match self.events.recv_timeout(STREAM_POLL_INTERVAL) {
+                    Ok(Ok(event)) => match self.classify(event) {
+                        Some(event) => self.debounced(event),
+                        None => continue,
+                    },
+                    Ok(Err(e)) => Err(e.into()),
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    Err(RecvTimeoutError::Disconnected) => break,
+                };
+
+                if matches!(event, Err(WatchError::Disconnected)) || sender.send(event).is_err() {
+                    break;
+                }
+            }
+        });
+
+        WatchStream {
+            path,
+            last_digest,
+            events,
+            _thread: thread,
+        }
+    }
+
+    /// Coalesce all events that arrive within the debounce window into a single one. The last
+    /// event wins, so that a remove followed by a re-create is reported as a change.
+    fn debounced(&mut self, mut current: WatchEvent) -> Result<WatchEvent, WatchError> {
+        loop {
+            match self.events.recv_timeout(self.debounce) {
+                Ok(event) => {
+                    if let Some(event) = self.classify(event?) {
+                        current = event;
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => return Ok(current),
+                Err(RecvTimeoutError::Disconnected) => return Err(WatchError::Disconnected),
+            }
+        }
+    }
+
+    fn classify(&self, event: notify::Event) -> Option<WatchEvent> {
+        if !event
+            .paths
+            .iter()
+            .any(|p| p.file_name() == Some(self.file_name.as_os_str()))
+        {
+            return None;
+        }
+
+        match event.kind {
+            EventKind::Create(_) | EventKind::Modify(_) => Some(WatchEvent::Changed),
+            EventKind::Remove(_) => Some(WatchEvent::Removed),
+            _ => None,
+        }
+    }
+}
+
+impl Iterator for DatabaseWatcher {
+    type Item = Result<WatchEvent, WatchError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_event() {
+            Err(WatchError::Disconnected) => None,
+            result => Some(result),
+        }
+    }
+}
+
+/// The events of a `DatabaseWatcher` as a `Stream`, see `DatabaseWatcher::into_stream`
+#[cfg(feature = "async")]
+pub struct WatchStream {
+    path: PathBuf,
+    last_digest: Option<Vec<u8>>,
+    events: tokio::sync::mpsc::UnboundedReceiver<Result<WatchEvent, WatchError>>,
+    _thread: std::thread::JoinHandle<()>,
+}
+
+#[cfg(feature = "async")]
+impl WatchStream {
+    /// The path of the watched database file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Re-open the database if the file changed, see `DatabaseWatcher::reload_if_changed`
+    pub fn reload_if_changed(&mut self, db: &mut Database, key: DatabaseKey) -> Result<bool, WatchError> {
+        reload_if_changed(&self.path, &mut self.last_digest, db, key)
+    }
+}
+
+#[cfg(feature = "async")]
+impl futures_core::Stream for WatchStream {
+    type Item = Result<WatchEvent, WatchError>;
+
+    fn poll_next(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Self::Item>> {
+        self.events.poll_recv(cx)
+    }
+}
+
+fn reload_if_changed(
+    path: &Path,
+    last_digest: &mut Option<Vec<u8>>,
+    db: &mut Database,
+    key: DatabaseKey,
+) -> Result<bool, WatchError> {
+    let data = match std::fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e.into()),
+    };
+
+    let digest = calculate_sha256(&[&data])?.to_vec();
+    if last_digest.as_ref() == Some(&digest) {
+        return Ok(false);
+    }
+
+    *db = Database::parse(&data, key)?;
+    *last_digest = Some(digest);
+
+    Ok(true)
+}
+
+fn file_digest(path: &Path) -> Result<Option<Vec<u8>>, WatchError> {
+    match std::fs::read(path) {
+        Ok(data) => Ok(Some(calculate_sha256(&[&data])?.to_vec())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod watch_tests {
+    use std::time::Duration;
+
+    use crate::{Database, DatabaseKey};
+
+    use super::{watch, WatchEvent};
+
+    const DB_PATH: &str = "tests/resources/test_db_with_password.kdbx";
+
+    #[test]
+    fn test_watch_and_reload() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = std::env::temp_dir().join(format!("keepass-watch-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join("watched.kdbx");
+        std::fs::copy(DB_PATH, &path)?;
+
+        let key = DatabaseKey::new().with_password("demopass");
+        let mut db = Database::open(&mut std::fs::File::open(&path)?, key.clone())?;
+        let mut watcher = watch(&path)?.with_debounce(Duration::from_millis(50));
+
+        // nothing changed yet
+        assert!(!watcher.reload_if_changed(&mut db, key.clone())?);
+
+        std::fs::copy("tests/resources/test_db_kdbx4_with_password_aes.kdbx", &path)?;
+        assert_eq!(watcher.next_event()?, WatchEvent::Changed);
+
+        assert!(watcher.reload_if_changed(&mut db, key.clone())?);
+        assert!(!watcher.reload_if_changed(&mut db, key)?);
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_watch_stream() -> Result<(), Box<dyn std::error::Error>> {
+        use futures_core::Stream;
+
+        use crate::db::background::background_tests::block_on;
+
+        let dir = std::env::temp_dir().join(format!("keepass-watch-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join("watched.kdbx");
+        std::fs::copy(DB_PATH, &path)?;
+
+        let key = DatabaseKey::new().with_password("demopass");
+        let mut db = Database::open(&mut std::fs::File::open(&path)?, key.clone())?;
+        let mut stream = watch(&path)?
+            .with_debounce(Duration::from_millis(50))
+            .into_stream();
+        assert!(!stream.reload_if_changed(&mut db, key.clone())?);
+
+        std::fs::copy("tests/resources/test_db_kdbx4_with_password_aes.kdbx", &path)?;
+        let event = block_on(std::future::poll_fn(|cx| {
+            std::pin::Pin::new(&mut stream).poll_next(cx)
+        }));
+        assert_eq!(event.unwrap()?, WatchEvent::Changed);
+        assert!(stream.reload_if_changed(&mut db, key)?);
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_watch_stream_drop_stops_thread() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = std::env::temp_dir().join(format!("keepass-watch-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join("watched.kdbx");
+        std::fs::copy(DB_PATH, &path)?;
+
+        // dropping everything but the thread handle closes the stream
+        let super::WatchStream { _thread: thread, .. } = watch(&path)?.into_stream();
+
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while !thread.is_finished() {
+            assert!(std::time::Instant::now() < deadline, "watch thread did not end");
+            std::thread::sleep(Duration::from_millis(20));
+        }
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -2,66 +2,92 @@ use base64::{engine::general_purpose as base64_engine, Engine as _};
 use xml::writer::{EventWriter, XmlEvent as WriterEvent};
 
 use crate::{
-    crypt::ciphers::Cipher,
     db::{AutoType, AutoTypeAssociation, Entry, History, Value},
-    xml_db::dump::{DumpXml, SimpleTag},
+    xml_db::dump::{DumpContext, DumpXml, SimpleTag},
 };
 
 impl DumpXml for Entry {
     fn dump_xml<E: std::io::Write>(
         &self,
         writer: &mut EventWriter<E>,
-        inner_cipher: &mut dyn Cipher,
+        context: &mut DumpContext,
     ) -> Result<(), xml::writer::Error> {
         writer.write(WriterEvent::start_element("Entry"))?;
 
-        SimpleTag("UUID", &self.uuid).dump_xml(writer, inner_cipher)?;
+        SimpleTag("UUID", &self.uuid).dump_xml(writer, context)?;
 
-        SimpleTag("Tags", &self.tags.join(";")).dump_xml(writer, inner_cipher)?;
+        SimpleTag("Tags", &self.tags.join(";")).dump_xml(writer, context)?;
 
         for (field_name, field_value) in &self.fields {
             writer.write(WriterEvent::start_element("String"))?;
 
-            SimpleTag("Key", field_name).dump_xml(writer, inner_cipher)?;
-            field_value.dump_xml(writer, inner_cipher)?;
+            SimpleTag("Key", field_name).dump_xml(writer, context)?;
+            match field_value {
+                Value::Unprotected(s) if context.is_protected_by_settings(field_name) => {
+                    Value::Protected(s.as_str().into()).dump_xml(writer, context)?
+                }
+                _ => field_value.dump_xml(writer, context)?,
+            }
 
             writer.write(WriterEvent::end_element())?; // String
         }
 
-        self.custom_data.dump_xml(writer, inner_cipher)?;
+        for attachment in &self.attachments {
+            let identifier = context
+                .attachment_identifier(attachment.identifier)
+                .unwrap_or(attachment.identifier);
+
+            writer.write(WriterEvent::start_element("Binary"))?;
+
+            SimpleTag("Key", attachment.name.as_str()).dump_xml(writer, context)?;
+            writer.write(WriterEvent::start_element("Value").attr("Ref", &identifier.to_string()))?;
+            writer.write(WriterEvent::end_element())?; // Value
+
+            writer.write(WriterEvent::end_element())?; // Binary
+        }
+
+        self.custom_data.dump_xml(writer, context)?;
 
         if let Some(ref value) = self.autotype {
-            value.dump_xml(writer, inner_cipher)?;
+            value.dump_xml(writer, context)?;
         }
 
-        self.times.dump_xml(writer, inner_cipher)?;
+        self.times.dump_xml(writer, context)?;
 
         if let Some(value) = self.icon_id {
-            SimpleTag("IconID", value).dump_xml(writer, inner_cipher)?;
+            SimpleTag("IconID", value).dump_xml(writer, context)?;
         }
 
         if let Some(ref value) = self.custom_icon_uuid {
-            SimpleTag("CustomIconUUID", value).dump_xml(writer, inner_cipher)?;
+            SimpleTag("CustomIconUUID", value).dump_xml(writer, context)?;
         }
 
         if let Some(ref value) = self.foreground_color {
-            SimpleTag("ForegroundColor", value).dump_xml(writer, inner_cipher)?;
+            SimpleTag("ForegroundColor", value).dump_xml(writer, context)?;
         }
 
         if let Some(ref value) = self.background_color {
-            SimpleTag("BackgroundColor", value).dump_xml(writer, inner_cipher)?;
+            SimpleTag("BackgroundColor", value).dump_xml(writer, context)?;
         }
 
         if let Some(ref value) = self.override_url {
-            SimpleTag("OverrideURL", value).dump_xml(writer, inner_cipher)?;
+            SimpleTag("OverrideURL", value).dump_xml(writer, context)?;
         }
 
         if let Some(value) = self.quality_check {
-            SimpleTag("QualityCheck", value).dump_xml(writer, inner_cipher)?;
+            SimpleTag("QualityCheck", value).dump_xml(writer, context)?;
+        }
+
+        if let Some(ref value) = self.previous_parent_group {
+            SimpleTag("PreviousParentGroup", value).dump_xml(writer, context)?;
+        }
+
+        for element in &self.unknown_elements {
+            element.dump_xml(writer, context)?;
         }
 
         if let Some(ref value) = self.history {
-            value.dump_xml(writer, inner_cipher)?;
+            value.dump_xml(writer, context)?;
         }
 
         writer.write(WriterEvent::end_element())?; // Entry
@@ -74,18 +100,25 @@ impl DumpXml for Value {
     fn dump_xml<E: std::io::Write>(
         &self,
         writer: &mut EventWriter<E>,
-        inner_cipher: &mut dyn Cipher,
+        context: &mut DumpContext,
     ) -> Result<(), xml::writer::Error> {
         match self {
             Value::Bytes(b) => {
-                SimpleTag("Value", std::str::from_utf8(b).expect("utf-8")).dump_xml(writer, inner_cipher)
+                SimpleTag("Value", std::str::from_utf8(b).expect("utf-8")).dump_xml(writer, context)
+            }
+            Value::Unprotected(s) => SimpleTag("Value", s).dump_xml(writer, context),
+            Value::Protected(p) if context.plain_export => {
+                writer.write(WriterEvent::start_element("Value").attr("ProtectInMemory", "True"))?;
+                writer.write(WriterEvent::characters(&String::from_utf8_lossy(&p.decrypt())))?;
+                writer.write(WriterEvent::end_element())?;
+                Ok(())
             }
-            Value::Unprotected(s) => SimpleTag("Value", s).dump_xml(writer, inner_cipher),
             Value::Protected(p) => {
                 writer.write(WriterEvent::start_element("Value").attr("Protected", "True"))?;
 
-                let encrypted_value = inner_cipher
-                    .encrypt(p.unsecure())
+                let encrypted_value = context
+                    .inner_cipher
+                    .encrypt(&p.decrypt())
                     .expect("Encrypt with inner cipher");
 
                 let protected_value = base64_engine::STANDARD.encode(&encrypted_value);
@@ -103,18 +136,19 @@ impl DumpXml for AutoType {
     fn dump_xml<E: std::io::Write>(
         &self,
         writer: &mut EventWriter<E>,
-        inner_cipher: &mut dyn Cipher,
+        context: &mut DumpContext,
     ) -> Result<(), xml::writer::Error> {
         writer.write(WriterEvent::start_element("AutoType"))?;
 
-        SimpleTag("Enabled", self.enabled).dump_xml(writer, inner_cipher)?;
+        SimpleTag("Enabled", self.enabled).dump_xml(writer, context)?;
+        SimpleTag("DataTransferObfuscation", self.data_transfer_obfuscation).dump_xml(writer, context)?;
 
         if let Some(ref value) = self.sequence {
-            SimpleTag("DefaultSequence", value).dump_xml(writer, inner_cipher)?;
+            SimpleTag("DefaultSequence", value).dump_xml(writer, context)?;
         }
 
         for assoc in &self.associations {
-            assoc.dump_xml(writer, inner_cipher)?;
+            assoc.dump_xml(writer, context)?;
         }
 
         writer.write(WriterEvent::end_element())?;
@@ -126,16 +160,16 @@ impl DumpXml for AutoTypeAssociation {
     fn dump_xml<E: std::io::Write>(
         &self,
         writer: &mut EventWriter<E>,
-        inner_cipher: &mut dyn Cipher,
+        context: &mut DumpContext,
     ) -> Result<(), xml::writer::Error> {
         writer.write(WriterEvent::start_element("Association"))?;
 
         if let Some(ref value) = self.window {
-            SimpleTag("Window", value).dump_xml(writer, inner_cipher)?;
+            SimpleTag("Window", value).dump_xml(writer, context)?;
         }
 
         if let Some(ref value) = self.sequence {
-            SimpleTag("KeystrokeSequence", value).dump_xml(writer, inner_cipher)?;
+            SimpleTag("KeystrokeSequence", value).dump_xml(writer, context)?;
         }
 
         writer.write(WriterEvent::end_element())?;
@@ -147,12 +181,12 @@ impl DumpXml for History {
     fn dump_xml<E: std::io::Write>(
         &self,
         writer: &mut EventWriter<E>,
-        inner_cipher: &mut dyn Cipher,
+        context: &mut DumpContext,
     ) -> Result<(), xml::writer::Error> {
         writer.write(WriterEvent::start_element("History"))?;
 
         for entry in &self.entries {
-            entry.dump_xml(writer, inner_cipher)?;
+            entry.dump_xml(writer, context)?;
         }
 
         writer.write(WriterEvent::end_element())?;
@@ -1,57 +1,72 @@
 use xml::writer::{EventWriter, XmlEvent as WriterEvent};
 
 use crate::{
-    crypt::ciphers::Cipher,
     db::{Group, Node},
-    xml_db::dump::{DumpXml, SimpleTag},
+    xml_db::dump::{DumpContext, DumpXml, SimpleTag},
 };
 
 impl DumpXml for Group {
     fn dump_xml<E: std::io::Write>(
         &self,
         writer: &mut EventWriter<E>,
-        inner_cipher: &mut dyn Cipher,
+        context: &mut DumpContext,
     ) -> Result<(), xml::writer::Error> {
         writer.write(WriterEvent::start_element("Group"))?;
 
-        SimpleTag("Name", &self.name).dump_xml(writer, inner_cipher)?;
-        SimpleTag("UUID", &self.uuid).dump_xml(writer, inner_cipher)?;
+        SimpleTag("Name", &self.name).dump_xml(writer, context)?;
+        SimpleTag("UUID", &self.uuid).dump_xml(writer, context)?;
 
         if let Some(ref value) = self.notes {
-            SimpleTag("Notes", value).dump_xml(writer, inner_cipher)?;
+            SimpleTag("Notes", value).dump_xml(writer, context)?;
+        }
+
+        if !self.tags.is_empty() {
+            SimpleTag("Tags", &self.tags.join(";")).dump_xml(writer, context)?;
         }
 
         if let Some(value) = self.icon_id {
-            SimpleTag("IconID", value).dump_xml(writer, inner_cipher)?;
+            SimpleTag("IconID", value).dump_xml(writer, context)?;
         }
 
         if let Some(ref value) = self.custom_icon_uuid {
-            SimpleTag("CustomIconUUID", value).dump_xml(writer, inner_cipher)?;
+            SimpleTag("CustomIconUUID", value).dump_xml(writer, context)?;
         }
 
-        self.times.dump_xml(writer, inner_cipher)?;
-        self.custom_data.dump_xml(writer, inner_cipher)?;
+        self.times.dump_xml(writer, context)?;
+        self.custom_data.dump_xml(writer, context)?;
 
-        SimpleTag("IsExpanded", self.is_expanded).dump_xml(writer, inner_cipher)?;
+        SimpleTag("IsExpanded", self.is_expanded).dump_xml(writer, context)?;
 
         if let Some(ref value) = self.default_autotype_sequence {
-            SimpleTag("DefaultAutoTypeSequence", value).dump_xml(writer, inner_cipher)?;
+            SimpleTag("DefaultAutoTypeSequence", value).dump_xml(writer, context)?;
         }
 
         if let Some(ref value) = self.enable_autotype {
-            SimpleTag("EnableAutoType", value).dump_xml(writer, inner_cipher)?;
+            SimpleTag("EnableAutoType", value).dump_xml(writer, context)?;
         }
 
         if let Some(ref value) = self.enable_searching {
-            SimpleTag("EnableSearching", value).dump_xml(writer, inner_cipher)?;
+            SimpleTag("EnableSearching", value).dump_xml(writer, context)?;
         }
 
         if let Some(ref value) = self.last_top_visible_entry {
-            SimpleTag("LastTopVisibleEntry", value).dump_xml(writer, inner_cipher)?;
+            SimpleTag("LastTopVisibleEntry", value).dump_xml(writer, context)?;
+        }
+
+        if let Some(ref value) = self.previous_parent_group {
+            SimpleTag("PreviousParentGroup", value).dump_xml(writer, context)?;
+        }
+
+        for element in &self.unknown_elements {
+            element.dump_xml(writer, context)?;
         }
 
         for child in &self.children {
-            child.dump_xml(writer, inner_cipher)?;
+            child.dump_xml(writer, context)?;
+        }
+
+        for quarantined in &self.quarantined {
+            quarantined.dump_xml(writer, context)?;
         }
 
         writer.write(WriterEvent::end_element())?; // Group
@@ -64,11 +79,11 @@ impl DumpXml for Node {
     fn dump_xml<E: std::io::Write>(
         &self,
         writer: &mut EventWriter<E>,
-        inner_cipher: &mut dyn Cipher,
+        context: &mut DumpContext,
     ) -> Result<(), xml::writer::Error> {
         match self {
-            Node::Group(g) => g.dump_xml(writer, inner_cipher),
-            Node::Entry(e) => e.dump_xml(writer, inner_cipher),
+            Node::Group(g) => g.dump_xml(writer, context),
+            Node::Entry(e) => e.dump_xml(writer, context),
         }
     }
 }
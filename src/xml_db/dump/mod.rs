@@ -2,7 +2,7 @@ mod entry;
 mod group;
 mod meta;
 
-use std::io::Write;
+use std::{collections::HashMap, io::Write};
 
 use base64::{engine::general_purpose as base64_engine, Engine as _};
 use uuid::Uuid;
@@ -12,28 +12,115 @@ use xml::{
 };
 
 use crate::{
-    crypt::ciphers::Cipher,
-    db::{Color, CustomData, CustomDataItem, Database, DeletedObject, DeletedObjects, Times},
-    xml_db::get_epoch_baseline,
+    compression::GZipCompression,
+    config::DEFAULT_GZIP_LEVEL,
+    crypt::ciphers::{InnerStreamCipher, PlainCipher},
+    db::{
+        Color, CustomData, CustomDataItem, Database, DeletedObject, DeletedObjects, MemoryProtection,
+        ProtectedValue, QuarantinedNode, Times, UnknownElement,
+    },
+    format::DatabaseVersion,
+    xml_db::{get_epoch_baseline, parse::simple_events, parse::SimpleXmlEvent},
 };
 
-/// Format a timestamp suitable for an XML database
-pub fn format_xml_timestamp(timestamp: &chrono::NaiveDateTime) -> String {
+/// How the XML document of a database is written: the inner cipher that protected values are
+/// encrypted with and the settings of the file being written
+pub(crate) struct DumpContext<'a> {
+    pub(crate) inner_cipher: &'a mut dyn InnerStreamCipher,
+
+    /// Whether timestamps are written as ISO 8601 strings, which is what readers of KDBX 3 expect
+    pub(crate) iso_timestamps: bool,
+
+    /// Whether protected values are written in plain text, as in unencrypted XML exports
+    pub(crate) plain_export: bool,
+
+    /// The memory protection settings of the database being written
+    pub(crate) memory_protection: Option<MemoryProtection>,
+
+    /// The GZip level for attachments in the metadata that are stored compressed
+    pub(crate) attachment_gzip_level: u32,
+
+    /// The identifier that each attachment identifier of the database is written as, if the
+    /// attachment contents are renumbered while writing. Binaries of the metadata without one are
+    /// left out.
+    pub(crate) attachment_indices: Option<HashMap<usize, usize>>,
+}
+
+impl<'a> DumpContext<'a> {
+    /// A context writing KDBX 4 XML with the given inner cipher and no memory protection settings
+    pub(crate) fn new(inner_cipher: &'a mut dyn InnerStreamCipher) -> DumpContext<'a> {
+        DumpContext {
+            inner_cipher,
+            iso_timestamps: false,
+            plain_export: false,
+            memory_protection: None,
+            attachment_gzip_level: DEFAULT_GZIP_LEVEL,
+            attachment_indices: None,
+        }
+    }
+
+    /// Whether the memory protection settings of the database being written protect a field
+    pub(crate) fn is_protected_by_settings(&self, field_name: &str) -> bool {
+        self.memory_protection
+            .is_some_and(|settings| settings.protects(field_name))
+    }
+
+    /// The compression of attachments in the metadata that are stored compressed
+    pub(crate) fn attachment_compression(&self) -> GZipCompression {
+        GZipCompression::with_level(self.attachment_gzip_level)
+    }
+
+    /// The identifier that an attachment identifier of the database is written as
+    pub(crate) fn attachment_identifier(&self, identifier: usize) -> Option<usize> {
+        match &self.attachment_indices {
+            Some(indices) => indices.get(&identifier).copied(),
+            None => Some(identifier),
+        }
+    }
+}
+
+/// Format a timestamp suitable for an XML database, as an ISO 8601 string or as base64-encoded
+/// seconds
+pub fn format_xml_timestamp(timestamp: &chrono::NaiveDateTime, iso_timestamps: bool) -> String {
+    if iso_timestamps {
+        return timestamp.format("%Y-%m-%dT%H:%M:%SZ").to_string();
+    }
+
     let timestamp = timestamp.and_utc().timestamp() - get_epoch_baseline().and_utc().timestamp();
     let timestamp_bytes = i64::to_le_bytes(timestamp);
     base64_engine::STANDARD.encode(timestamp_bytes)
 }
 
+/// Write the XML document of a database to `writer` as it is generated, without buffering it.
+/// Protected values are encrypted with the inner cipher of `context` as they are written, and the
+/// timestamps and memory protection follow the database.
 pub(crate) fn dump(
     db: &Database,
-    inner_cipher: &mut dyn Cipher,
+    mut context: DumpContext,
     writer: &mut dyn Write,
 ) -> Result<(), xml::writer::Error> {
     let mut xml_writer = EmitterConfig::new().perform_indent(false).create_writer(writer);
 
-    db.dump_xml(&mut xml_writer, inner_cipher)?;
+    context.iso_timestamps = matches!(db.config.version, DatabaseVersion::KDB3(_));
+    context.memory_protection = Some(db.meta.effective_memory_protection());
+    db.dump_xml(&mut xml_writer, &mut context)
+}
 
-    Ok(())
+/// Write the XML document of a database as an unencrypted export, like the "KeePass XML (2.x)"
+/// export of KeePass: protected values are written in plain text and only marked to be
+/// protected, and timestamps are ISO 8601 strings.
+pub(crate) fn dump_export(db: &Database, writer: &mut dyn Write) -> Result<(), xml::writer::Error> {
+    let mut xml_writer = EmitterConfig::new().perform_indent(true).create_writer(writer);
+
+    let mut context = DumpContext {
+        inner_cipher: &mut PlainCipher,
+        iso_timestamps: true,
+        plain_export: true,
+        memory_protection: Some(db.meta.effective_memory_protection()),
+        attachment_gzip_level: DEFAULT_GZIP_LEVEL,
+        attachment_indices: None,
+    };
+    db.dump_xml(&mut xml_writer, &mut context)
 }
 
 /// A trait that denotes an inner KeePass database object can be stored into an XML database.
@@ -44,7 +131,7 @@ pub(crate) trait DumpXml {
     fn dump_xml<E: std::io::Write>(
         &self,
         writer: &mut EventWriter<E>,
-        inner_cipher: &mut dyn Cipher,
+        context: &mut DumpContext,
     ) -> Result<(), xml::writer::Error>;
 
     fn normalize_empty_elements(&self) -> bool {
@@ -56,9 +143,12 @@ impl DumpXml for &chrono::NaiveDateTime {
     fn dump_xml<E: std::io::Write>(
         &self,
         writer: &mut EventWriter<E>,
-        _inner_cipher: &mut dyn Cipher,
+        context: &mut DumpContext,
     ) -> Result<(), xml::writer::Error> {
-        writer.write(WriterEvent::characters(&format_xml_timestamp(self)))
+        writer.write(WriterEvent::characters(&format_xml_timestamp(
+            self,
+            context.iso_timestamps,
+        )))
     }
 }
 
@@ -66,7 +156,7 @@ impl DumpXml for bool {
     fn dump_xml<E: std::io::Write>(
         &self,
         writer: &mut EventWriter<E>,
-        _inner_cipher: &mut dyn Cipher,
+        _context: &mut DumpContext,
     ) -> Result<(), xml::writer::Error> {
         writer.write(WriterEvent::characters(if *self { "True" } else { "False" }))
     }
@@ -76,7 +166,7 @@ impl DumpXml for usize {
     fn dump_xml<E: std::io::Write>(
         &self,
         writer: &mut EventWriter<E>,
-        _inner_cipher: &mut dyn Cipher,
+        _context: &mut DumpContext,
     ) -> Result<(), xml::writer::Error> {
         writer.write(WriterEvent::characters(&format!("{}", self)))
     }
@@ -86,7 +176,7 @@ impl DumpXml for isize {
     fn dump_xml<E: std::io::Write>(
         &self,
         writer: &mut EventWriter<E>,
-        _inner_cipher: &mut dyn Cipher,
+        _context: &mut DumpContext,
     ) -> Result<(), xml::writer::Error> {
         writer.write(WriterEvent::characters(&format!("{}", self)))
     }
@@ -96,7 +186,7 @@ impl DumpXml for &str {
     fn dump_xml<E: std::io::Write>(
         &self,
         writer: &mut EventWriter<E>,
-        _inner_cipher: &mut dyn Cipher,
+        _context: &mut DumpContext,
     ) -> Result<(), xml::writer::Error> {
         writer.write(WriterEvent::characters(self))
     }
@@ -110,7 +200,7 @@ impl DumpXml for &String {
     fn dump_xml<E: std::io::Write>(
         &self,
         writer: &mut EventWriter<E>,
-        _inner_cipher: &mut dyn Cipher,
+        _context: &mut DumpContext,
     ) -> Result<(), xml::writer::Error> {
         writer.write(WriterEvent::characters(self))
     }
@@ -124,7 +214,7 @@ impl DumpXml for &Uuid {
     fn dump_xml<E: std::io::Write>(
         &self,
         writer: &mut EventWriter<E>,
-        _inner_cipher: &mut dyn Cipher,
+        _context: &mut DumpContext,
     ) -> Result<(), xml::writer::Error> {
         let b64 = base64_engine::STANDARD.encode(self.as_bytes());
         writer.write(WriterEvent::Characters(&b64))
@@ -135,7 +225,7 @@ impl DumpXml for &Color {
     fn dump_xml<E: std::io::Write>(
         &self,
         writer: &mut EventWriter<E>,
-        _inner_cipher: &mut dyn Cipher,
+        _context: &mut DumpContext,
     ) -> Result<(), xml::writer::Error> {
         writer.write(WriterEvent::Characters(&self.to_string()))
     }
@@ -148,11 +238,11 @@ impl<S: AsRef<str>, D: DumpXml> DumpXml for SimpleTag<S, D> {
     fn dump_xml<E: std::io::Write>(
         &self,
         writer: &mut EventWriter<E>,
-        inner_cipher: &mut dyn Cipher,
+        context: &mut DumpContext,
     ) -> Result<(), xml::writer::Error> {
         writer.write(WriterEvent::start_element(self.0.as_ref()))?;
         if !self.1.normalize_empty_elements() {
-            self.1.dump_xml(writer, inner_cipher)?;
+            self.1.dump_xml(writer, context)?;
         }
         writer.write(WriterEvent::end_element())?;
         Ok(())
@@ -163,17 +253,17 @@ impl DumpXml for Database {
     fn dump_xml<E: std::io::Write>(
         &self,
         writer: &mut EventWriter<E>,
-        inner_cipher: &mut dyn Cipher,
+        context: &mut DumpContext,
     ) -> Result<(), xml::writer::Error> {
         writer.write(WriterEvent::start_element("KeePassFile"))?;
 
-        self.meta.dump_xml(writer, inner_cipher)?;
+        self.meta.dump_xml(writer, context)?;
 
         writer.write(WriterEvent::start_element("Root"))?;
 
-        self.root.dump_xml(writer, inner_cipher)?;
+        self.root.dump_xml(writer, context)?;
 
-        self.deleted_objects.dump_xml(writer, inner_cipher)?;
+        self.deleted_objects.dump_xml(writer, context)?;
 
         writer.write(WriterEvent::end_element())?; // Root
 
@@ -187,15 +277,15 @@ impl DumpXml for Times {
     fn dump_xml<E: std::io::Write>(
         &self,
         writer: &mut EventWriter<E>,
-        inner_cipher: &mut dyn Cipher,
+        context: &mut DumpContext,
     ) -> Result<(), xml::writer::Error> {
         writer.write(WriterEvent::start_element("Times"))?;
         for (time_name, time) in &self.times {
-            SimpleTag(time_name, time).dump_xml(writer, inner_cipher)?;
+            SimpleTag(time_name, time).dump_xml(writer, context)?;
         }
 
-        SimpleTag("Expires", self.expires).dump_xml(writer, inner_cipher)?;
-        SimpleTag("UsageCount", self.usage_count).dump_xml(writer, inner_cipher)?;
+        SimpleTag("Expires", self.expires).dump_xml(writer, context)?;
+        SimpleTag("UsageCount", self.usage_count).dump_xml(writer, context)?;
 
         writer.write(WriterEvent::end_element())?;
 
@@ -207,15 +297,15 @@ impl DumpXml for CustomData {
     fn dump_xml<E: std::io::Write>(
         &self,
         writer: &mut EventWriter<E>,
-        inner_cipher: &mut dyn Cipher,
+        context: &mut DumpContext,
     ) -> Result<(), xml::writer::Error> {
         writer.write(WriterEvent::start_element("CustomData"))?;
 
         for (key, item) in &self.items {
             writer.write(WriterEvent::start_element("Item"))?;
 
-            SimpleTag("Key", key).dump_xml(writer, inner_cipher)?;
-            item.dump_xml(writer, inner_cipher)?;
+            SimpleTag("Key", key).dump_xml(writer, context)?;
+            item.dump_xml(writer, context)?;
 
             writer.write(WriterEvent::end_element())?;
         }
@@ -230,14 +320,14 @@ impl DumpXml for CustomDataItem {
     fn dump_xml<E: std::io::Write>(
         &self,
         writer: &mut EventWriter<E>,
-        inner_cipher: &mut dyn Cipher,
+        context: &mut DumpContext,
     ) -> Result<(), xml::writer::Error> {
         if let Some(ref value) = self.value {
-            value.dump_xml(writer, inner_cipher)?;
+            value.dump_xml(writer, context)?;
         }
 
         if let Some(ref value) = self.last_modification_time {
-            SimpleTag("LastModificationTime", value).dump_xml(writer, inner_cipher)?;
+            SimpleTag("LastModificationTime", value).dump_xml(writer, context)?;
         }
 
         Ok(())
@@ -248,12 +338,12 @@ impl DumpXml for DeletedObjects {
     fn dump_xml<E: std::io::Write>(
         &self,
         writer: &mut EventWriter<E>,
-        inner_cipher: &mut dyn Cipher,
+        context: &mut DumpContext,
     ) -> Result<(), xml::writer::Error> {
         writer.write(WriterEvent::start_element("DeletedObjects"))?;
 
         for object in &self.objects {
-            object.dump_xml(writer, inner_cipher)?;
+            object.dump_xml(writer, context)?;
         }
 
         writer.write(WriterEvent::end_element())?;
@@ -265,14 +355,97 @@ impl DumpXml for DeletedObject {
     fn dump_xml<E: std::io::Write>(
         &self,
         writer: &mut EventWriter<E>,
-        inner_cipher: &mut dyn Cipher,
+        context: &mut DumpContext,
     ) -> Result<(), xml::writer::Error> {
         writer.write(WriterEvent::start_element("DeletedObject"))?;
 
-        SimpleTag("UUID", &self.uuid).dump_xml(writer, inner_cipher)?;
-        SimpleTag("DeletionTime", &self.deletion_time).dump_xml(writer, inner_cipher)?;
+        SimpleTag("UUID", &self.uuid).dump_xml(writer, context)?;
+        SimpleTag("DeletionTime", &self.deletion_time).dump_xml(writer, context)?;
 
         writer.write(WriterEvent::end_element())?;
         Ok(())
     }
 }
+
+impl DumpXml for UnknownElement {
+    fn dump_xml<E: std::io::Write>(
+        &self,
+        writer: &mut EventWriter<E>,
+        context: &mut DumpContext,
+    ) -> Result<(), xml::writer::Error> {
+        dump_fragment(&self.xml, &self.protected_values, writer, context)
+    }
+}
+
+impl DumpXml for QuarantinedNode {
+    fn dump_xml<E: std::io::Write>(
+        &self,
+        writer: &mut EventWriter<E>,
+        context: &mut DumpContext,
+    ) -> Result<(), xml::writer::Error> {
+        dump_fragment(&self.raw_xml, &self.protected_values, writer, context)
+    }
+}
+
+/// Write an XML fragment kept verbatim, filling its protected elements with the protected values
+fn dump_fragment<E: std::io::Write>(
+    xml: &str,
+    protected_values: &[ProtectedValue],
+    writer: &mut EventWriter<E>,
+    context: &mut DumpContext,
+) -> Result<(), xml::writer::Error> {
+    let invalid = |e: String| std::io::Error::new(std::io::ErrorKind::InvalidData, e);
+
+    let mut protected_values = protected_values.iter();
+    let mut protected = false;
+    for event in simple_events(xml.as_bytes()) {
+        match event {
+            SimpleXmlEvent::Start(name, attributes) => {
+                protected = attributes.get("Protected").map(|v| v.to_lowercase()).as_deref() == Some("true");
+
+                let mut attributes: Vec<_> = attributes.iter().collect();
+                attributes.sort();
+
+                let mut start = WriterEvent::start_element(name.as_str());
+                for (key, value) in attributes {
+                    start = match key.as_str() {
+                        "Protected" if protected && context.plain_export => {
+                            start.attr("ProtectInMemory", value)
+                        }
+                        key => start.attr(key, value),
+                    };
+                }
+                writer.write(start)?;
+
+                if !protected {
+                    continue;
+                }
+                let value = match protected_values.next() {
+                    Some(value) if !value.is_empty() => value.decrypt(),
+                    _ => continue,
+                };
+                if context.plain_export {
+                    writer.write(WriterEvent::characters(&String::from_utf8_lossy(&value)))?;
+                } else {
+                    let encrypted_value = context
+                        .inner_cipher
+                        .encrypt(&value)
+                        .expect("Encrypt with inner cipher");
+                    writer.write(WriterEvent::characters(
+                        &base64_engine::STANDARD.encode(encrypted_value),
+                    ))?;
+                }
+            }
+            SimpleXmlEvent::End(_) => {
+                protected = false;
+                writer.write(WriterEvent::end_element())?;
+            }
+            // the content of protected elements is in the protected values
+            SimpleXmlEvent::Characters(_) if protected => {}
+            SimpleXmlEvent::Characters(text) => writer.write(WriterEvent::characters(&text))?,
+            SimpleXmlEvent::Err(e) => return Err(invalid(e.to_string()).into()),
+        }
+    }
+
+    Ok(())
+}
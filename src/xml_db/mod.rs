@@ -12,7 +12,6 @@ pub fn get_epoch_baseline() -> chrono::NaiveDateTime {
 #[cfg(test)]
 mod tests {
     use chrono::NaiveDateTime;
-    use secstr::SecStr;
     use std::collections::HashMap;
     use uuid::uuid;
 
@@ -22,11 +21,12 @@ mod tests {
             entry::History,
             meta::{BinaryAttachments, CustomIcons, Icon, MemoryProtection},
             AutoType, AutoTypeAssociation, BinaryAttachment, CustomData, CustomDataItem, Database,
-            DeletedObject, Entry, Group, Meta, Node, Times, Value,
+            DeletedObject, Entry, Group, Meta, Node, ProtectedValue, QuarantinedNode, Times, UnknownElement,
+            Value,
         },
         format::kdbx4,
         key::DatabaseKey,
-        xml_db::dump::DumpXml,
+        xml_db::dump::{DumpContext, DumpXml},
     };
 
     fn make_key() -> DatabaseKey {
@@ -69,6 +69,7 @@ mod tests {
         entry.autotype = Some(AutoType {
             enabled: true,
             sequence: Some("Autotype-sequence".to_string()),
+            data_transfer_obfuscation: 1,
             associations: vec![
                 AutoTypeAssociation {
                     window: Some("window-1".to_string()),
@@ -97,9 +98,15 @@ mod tests {
 
         entry.override_url = Some("https://docs.rs/keepass-rs/".to_string());
         entry.quality_check = Some(true);
+        entry.previous_parent_group = Some(uuid!("0123456789abcdef0123456789abcdef"));
 
         let mut history = History::default();
         history.entries.push(entry.clone());
+        let mut older_version = entry.clone();
+        older_version
+            .fields
+            .insert("Title".to_string(), Value::Unprotected("Older Title".to_string()));
+        history.entries.push(older_version);
 
         entry.history = Some(history);
 
@@ -111,7 +118,7 @@ mod tests {
         let db_key = make_key();
 
         let mut encrypted_db = Vec::new();
-        kdbx4::dump_kdbx4(&db, &db_key, &mut encrypted_db).unwrap();
+        kdbx4::dump_kdbx4(&db, &db_key, &mut encrypted_db, &Default::default()).unwrap();
         let decrypted_db = kdbx4::parse_kdbx4(&encrypted_db, &db_key).unwrap();
 
         assert_eq!(decrypted_db.root.children.len(), 1);
@@ -129,7 +136,9 @@ mod tests {
         let group = Group::new("");
         let mut inner_cipher = InnerCipherConfig::Plain.get_cipher(&[]).unwrap();
         let mut writer = xml::EventWriter::new(Vec::new());
-        let _v = group.dump_xml(&mut writer, &mut *inner_cipher).unwrap();
+        let _v = group
+            .dump_xml(&mut writer, &mut DumpContext::new(&mut *inner_cipher))
+            .unwrap();
         let xml = writer.into_inner();
         assert!(String::from_utf8(xml).unwrap().contains("<Name />"));
 
@@ -144,6 +153,7 @@ mod tests {
 
         let mut subgroup = Group::new("Child group");
         subgroup.notes = Some("I am a subgroup".to_string());
+        subgroup.tags = vec!["tag-1".to_string(), "tag-2".to_string()];
         subgroup.icon_id = Some(42);
         subgroup.custom_icon_uuid = Some(uuid!("11111111111111111111111111111111"));
         subgroup.times.expires = true;
@@ -160,6 +170,7 @@ mod tests {
         subgroup.enable_searching = Some("sure".to_string());
 
         subgroup.last_top_visible_entry = Some(uuid!("43210000000000000000000000000000"));
+        subgroup.previous_parent_group = Some(uuid!("fedcba9876543210fedcba9876543210"));
 
         subgroup.custom_data.items.insert(
             "CustomOption".to_string(),
@@ -177,7 +188,7 @@ mod tests {
         let db_key = make_key();
 
         let mut encrypted_db = Vec::new();
-        kdbx4::dump_kdbx4(&db, &db_key, &mut encrypted_db).unwrap();
+        kdbx4::dump_kdbx4(&db, &db_key, &mut encrypted_db, &Default::default()).unwrap();
         let decrypted_db = kdbx4::parse_kdbx4(&encrypted_db, &db_key).unwrap();
 
         assert_eq!(decrypted_db.root.children.len(), 2);
@@ -221,6 +232,8 @@ mod tests {
                 icons: vec![Icon {
                     uuid: uuid!("a1a2a3a4b1bffffffffffff4d5d6d7d8"),
                     data: b"fake-data".to_vec(),
+                    name: Some("Fake icon".to_string()),
+                    last_modification_time: Some("2000-12-31T12:34:56".parse().unwrap()),
                 }],
             },
             recyclebin_enabled: Some(true),
@@ -239,16 +252,22 @@ mod tests {
                         identifier: Some("1".to_string()),
                         compressed: false,
                         content: b"i am binary data".to_vec(),
+                        packed: false,
+                        external: false,
                     },
                     BinaryAttachment {
                         identifier: Some("2".to_string()),
                         compressed: true,
                         content: b"i am compressed binary data".to_vec(),
+                        packed: false,
+                        external: false,
                     },
                     BinaryAttachment {
                         identifier: None,
                         compressed: true,
                         content: b"i am compressed binary data without an identifier".to_vec(),
+                        packed: false,
+                        external: false,
                     },
                 ],
             },
@@ -271,12 +290,15 @@ mod tests {
                     (
                         "custom-data-protected-key".to_string(),
                         CustomDataItem {
-                            value: Some(Value::Protected(SecStr::new(b"custom-data-value".to_vec()))),
+                            value: Some(Value::Protected(ProtectedValue::new(
+                                b"custom-data-value".to_vec(),
+                            ))),
                             last_modification_time: Some("2000-12-31T12:35:03".parse().unwrap()),
                         },
                     ),
                 ]),
             },
+            unknown_elements: vec![],
         };
 
         db.meta = meta.clone();
@@ -284,7 +306,7 @@ mod tests {
         let db_key = make_key();
 
         let mut encrypted_db = Vec::new();
-        kdbx4::dump_kdbx4(&db, &db_key, &mut encrypted_db).unwrap();
+        kdbx4::dump_kdbx4(&db, &db_key, &mut encrypted_db, &Default::default()).unwrap();
         let decrypted_db = kdbx4::parse_kdbx4(&encrypted_db, &db_key).unwrap();
 
         assert_eq!(decrypted_db.meta, meta);
@@ -307,9 +329,139 @@ mod tests {
         let db_key = make_key();
 
         let mut encrypted_db = Vec::new();
-        kdbx4::dump_kdbx4(&db, &db_key, &mut encrypted_db).unwrap();
+        kdbx4::dump_kdbx4(&db, &db_key, &mut encrypted_db, &Default::default()).unwrap();
         let decrypted_db = kdbx4::parse_kdbx4(&encrypted_db, &db_key).unwrap();
 
         assert_eq!(decrypted_db, db);
     }
+
+    #[test]
+    fn test_quarantined_entries_are_saved() {
+        let mut db = Database::new(DatabaseConfig::default());
+        db.root.quarantined.push(QuarantinedNode {
+            uuid: Some(uuid!("a1a2a3a4b1b2c1c2d1d2d3d4d5d6d7d8")),
+            raw_xml: "<Entry><UUID>oaKjpLGywcLR0tPU1dbX2A==</UUID>\
+                <String><Key>Password</Key><Value Protected=\"True\"></Value></String>\
+                <Times><ExpiryTime>garbage</ExpiryTime></Times></Entry>"
+                .to_string(),
+            protected_values: vec!["first secret".into()],
+            error: String::new(),
+        });
+
+        // protected values after the quarantined entry are only readable if the inner cipher
+        // stream stays in sync
+        let mut entry = Entry::new();
+        entry
+            .fields
+            .insert("Password".to_string(), Value::Protected("hunter2".into()));
+        db.root.add_child(entry);
+
+        let db_key = make_key();
+        let mut encrypted_db = Vec::new();
+        kdbx4::dump_kdbx4(&db, &db_key, &mut encrypted_db, &Default::default()).unwrap();
+        let decrypted_db = kdbx4::parse_kdbx4(&encrypted_db, &db_key).unwrap();
+
+        assert_eq!(decrypted_db.root.children, db.root.children);
+        let quarantined = &decrypted_db.root.quarantined[0];
+        assert_eq!(quarantined.uuid, db.root.quarantined[0].uuid);
+        assert_eq!(quarantined.raw_xml, db.root.quarantined[0].raw_xml);
+        assert_eq!(
+            quarantined.protected_values,
+            db.root.quarantined[0].protected_values
+        );
+    }
+
+    #[test]
+    fn test_unknown_elements() {
+        let unknown = |name: &str, xml: &str| UnknownElement {
+            name: name.to_string(),
+            xml: xml.to_string(),
+            protected_values: vec![],
+        };
+
+        let mut db = Database::new(DatabaseConfig::default());
+        db.meta.unknown_elements = vec![unknown("FutureSetting", "<FutureSetting>42</FutureSetting>")];
+        db.root.unknown_elements = vec![unknown(
+            "PluginData",
+            "<PluginData Version=\"2\"><Item>a &amp; b</Item><Empty></Empty></PluginData>",
+        )];
+
+        let mut entry = Entry::new();
+        entry.unknown_elements = vec![UnknownElement {
+            name: "PluginSecret".to_string(),
+            xml:
+                "<PluginSecret><Key Protected=\"True\"></Key><Empty Protected=\"True\"></Empty></PluginSecret>"
+                    .to_string(),
+            protected_values: vec!["secret".into(), "".into()],
+        }];
+        assert!(!format!("{:?}", entry.unknown_elements).contains("secret"));
+        db.root.add_child(entry);
+
+        // protected values after the unknown elements are only readable if the inner cipher stream
+        // stays in sync
+        let mut entry = Entry::new();
+        entry
+            .fields
+            .insert("Password".to_string(), Value::Protected("hunter2".into()));
+        db.root.add_child(entry);
+
+        let db_key = make_key();
+
+        let mut encrypted_db = Vec::new();
+        kdbx4::dump_kdbx4(&db, &db_key, &mut encrypted_db, &Default::default()).unwrap();
+        let decrypted_db = kdbx4::parse_kdbx4(&encrypted_db, &db_key).unwrap();
+
+        assert_eq!(decrypted_db, db);
+
+        let mut xml = Vec::new();
+        crate::xml_db::dump::dump_export(&db, &mut xml).unwrap();
+        let xml = String::from_utf8(xml).unwrap();
+        assert!(xml.contains("<Key ProtectInMemory=\"True\">secret</Key>"));
+        assert!(xml.contains("<Empty ProtectInMemory=\"True\" />"));
+    }
+
+    #[test]
+    fn test_memory_protection() {
+        let mut db = Database::new(DatabaseConfig::default());
+        db.meta.memory_protection = Some(MemoryProtection {
+            protect_title: true,
+            ..Default::default()
+        });
+
+        let mut entry = Entry::new();
+        for (field, value) in [("Title", "Mail"), ("UserName", "jdoe"), ("Password", "hunter2")].iter() {
+            entry
+                .fields
+                .insert(field.to_string(), Value::Unprotected(value.to_string()));
+        }
+        entry
+            .fields
+            .insert("Notes".to_string(), db.meta.field_value("Notes", "secret notes"));
+        entry
+            .fields
+            .insert("PIN".to_string(), Value::Protected("1234".into()));
+        assert_eq!(
+            db.meta.field_value("Title", "Mail"),
+            Value::Protected("Mail".into())
+        );
+        db.root.add_child(entry);
+
+        let db_key = make_key();
+        let mut encrypted_db = Vec::new();
+        kdbx4::dump_kdbx4(&db, &db_key, &mut encrypted_db, &Default::default()).unwrap();
+        let decrypted_db = kdbx4::parse_kdbx4(&encrypted_db, &db_key).unwrap();
+
+        // the settings protect the title and the password, the PIN was protected by the caller
+        let fields = &decrypted_db.root.entries()[0].fields;
+        assert_eq!(fields["Title"], Value::Protected("Mail".into()));
+        assert_eq!(fields["Password"], Value::Protected("hunter2".into()));
+        assert_eq!(fields["PIN"], Value::Protected("1234".into()));
+        assert_eq!(fields["UserName"], Value::Unprotected("jdoe".to_string()));
+        assert_eq!(fields["Notes"], Value::Unprotected("secret notes".to_string()));
+
+        let mut xml = Vec::new();
+        crate::xml_db::dump::dump_export(&db, &mut xml).unwrap();
+        let xml = String::from_utf8(xml).unwrap();
+        assert!(xml.contains("<Value ProtectInMemory=\"True\">Mail</Value>"));
+    }
 }
@@ -1,12 +1,11 @@
 use std::iter::Peekable;
 
 use base64::{engine::general_purpose as base64_engine, Engine as _};
-use secstr::SecStr;
 use uuid::Uuid;
 
 use crate::{
-    crypt::ciphers::Cipher,
-    db::{AutoType, AutoTypeAssociation, Color, Entry, History, Times, Value},
+    crypt::ciphers::InnerStreamCipher,
+    db::{AttachmentRef, AutoType, AutoTypeAssociation, Color, Entry, History, Times, UnknownElement, Value},
     xml_db::parse::{bad_event, CustomData, FromXml, IgnoreSubfield, SimpleTag, SimpleXmlEvent, XmlParseError},
 };
 
@@ -15,7 +14,7 @@ impl FromXml for Entry {
 
     fn from_xml<I: Iterator<Item = SimpleXmlEvent>>(
         iterator: &mut Peekable<I>,
-        inner_cipher: &mut dyn Cipher,
+        inner_cipher: &mut dyn InnerStreamCipher,
     ) -> Result<Self::Parses, XmlParseError> {
         let open_tag = iterator.next().ok_or(XmlParseError::Eof)?;
         if !matches!(open_tag, SimpleXmlEvent::Start(ref tag, _) if tag == "Entry") {
@@ -49,9 +48,11 @@ impl FromXml for Entry {
                         out.custom_data = CustomData::from_xml(iterator, inner_cipher)?;
                     }
                     "Binary" => {
-                        let _field = BinaryField::from_xml(iterator, inner_cipher)?;
-                        // TODO reference into a binary field from the Meta. Might only appear in
-                        // kdbx3
+                        let field = BinaryField::from_xml(iterator, inner_cipher)?;
+                        out.attachments.push(AttachmentRef {
+                            name: field.key,
+                            identifier: field.identifier.parse()?,
+                        });
                     }
                     "AutoType" => {
                         out.autotype = Some(AutoType::from_xml(iterator, inner_cipher)?);
@@ -80,10 +81,16 @@ impl FromXml for Entry {
                     "QualityCheck" => {
                         out.quality_check = SimpleTag::<Option<bool>>::from_xml(iterator, inner_cipher)?.value;
                     }
+                    "PreviousParentGroup" => {
+                        out.previous_parent_group =
+                            SimpleTag::<Option<Uuid>>::from_xml(iterator, inner_cipher)?.value;
+                    }
                     "History" => {
                         out.history = Some(History::from_xml(iterator, inner_cipher)?);
                     }
-                    _ => IgnoreSubfield::from_xml(iterator, inner_cipher)?,
+                    _ => out
+                        .unknown_elements
+                        .push(UnknownElement::from_xml(iterator, inner_cipher)?),
                 },
                 SimpleXmlEvent::End(name) if name == "Entry" => break,
                 _ => return Err(bad_event("start tag or close entry", event.clone())),
@@ -108,7 +115,7 @@ impl FromXml for StringField {
 
     fn from_xml<I: Iterator<Item = SimpleXmlEvent>>(
         iterator: &mut Peekable<I>,
-        inner_cipher: &mut dyn Cipher,
+        inner_cipher: &mut dyn InnerStreamCipher,
     ) -> Result<Self::Parses, XmlParseError> {
         let open_tag = iterator.next().ok_or(XmlParseError::Eof)?;
         if !matches!(open_tag, SimpleXmlEvent::Start(ref tag, _) if tag == "String") {
@@ -144,7 +151,6 @@ impl FromXml for StringField {
 }
 
 #[derive(Debug)]
-#[allow(dead_code)]
 pub(crate) struct BinaryField {
     pub key: String,
     pub identifier: String,
@@ -155,7 +161,7 @@ impl FromXml for BinaryField {
 
     fn from_xml<I: Iterator<Item = SimpleXmlEvent>>(
         iterator: &mut Peekable<I>,
-        inner_cipher: &mut dyn Cipher,
+        inner_cipher: &mut dyn InnerStreamCipher,
     ) -> Result<Self::Parses, XmlParseError> {
         let open_tag = iterator.next().ok_or(XmlParseError::Eof)?;
         if !matches!(open_tag, SimpleXmlEvent::Start(ref tag, _) if tag == "Binary") {
@@ -190,7 +196,7 @@ impl FromXml for Value {
 
     fn from_xml<I: Iterator<Item = SimpleXmlEvent>>(
         iterator: &mut Peekable<I>,
-        inner_cipher: &mut dyn Cipher,
+        inner_cipher: &mut dyn InnerStreamCipher,
     ) -> Result<Self::Parses, XmlParseError> {
         let open_tag = iterator.next().ok_or(XmlParseError::Eof)?;
 
@@ -201,13 +207,23 @@ impl FromXml for Value {
                     .map(|v| v.to_lowercase().parse::<bool>())
                     .unwrap_or(Ok(false))?;
 
+                // unencrypted XML exports mark values to protect without encrypting them
+                let protect_in_memory: bool = attributes
+                    .get("ProtectInMemory")
+                    .map(|v| v.to_lowercase().parse::<bool>())
+                    .unwrap_or(Ok(false))?;
+
                 let content = Option::<String>::from_xml(iterator, inner_cipher)?.unwrap_or(String::new());
 
                 let value = if protected {
                     let buf = base64_engine::STANDARD.decode(&content)?;
-                    let buf_decrypted = inner_cipher.decrypt(&buf)?;
-                    let value = String::from_utf8_lossy(&buf_decrypted).to_string();
-                    Value::Protected(SecStr::from(value))
+                    let value = match String::from_utf8(inner_cipher.decrypt(&buf)?) {
+                        Ok(value) => value,
+                        Err(e) => String::from_utf8_lossy(e.as_bytes()).into_owned(),
+                    };
+                    Value::Protected(value.into())
+                } else if protect_in_memory {
+                    Value::Protected(content.into())
                 } else {
                     Value::Unprotected(content)
                 };
@@ -229,7 +245,7 @@ impl FromXml for AutoType {
 
     fn from_xml<I: Iterator<Item = SimpleXmlEvent>>(
         iterator: &mut Peekable<I>,
-        inner_cipher: &mut dyn Cipher,
+        inner_cipher: &mut dyn InnerStreamCipher,
     ) -> Result<Self::Parses, XmlParseError> {
         let open_tag = iterator.next().ok_or(XmlParseError::Eof)?;
         if !matches!(open_tag, SimpleXmlEvent::Start(ref tag, _) if tag == "AutoType") {
@@ -248,8 +264,10 @@ impl FromXml for AutoType {
                         out.sequence = SimpleTag::<Option<String>>::from_xml(iterator, inner_cipher)?.value;
                     }
                     "DataTransferObfuscation" => {
-                        let _value = SimpleTag::<Option<usize>>::from_xml(iterator, inner_cipher)?.value;
-                        // TODO probably not needed?
+                        out.data_transfer_obfuscation =
+                            SimpleTag::<Option<usize>>::from_xml(iterator, inner_cipher)?
+                                .value
+                                .unwrap_or_default();
                     }
                     "Association" => {
                         let ata = AutoTypeAssociation::from_xml(iterator, inner_cipher)?;
@@ -274,7 +292,7 @@ impl FromXml for AutoTypeAssociation {
 
     fn from_xml<I: Iterator<Item = SimpleXmlEvent>>(
         iterator: &mut Peekable<I>,
-        inner_cipher: &mut dyn Cipher,
+        inner_cipher: &mut dyn InnerStreamCipher,
     ) -> Result<Self::Parses, XmlParseError> {
         let open_tag = iterator.next().ok_or(XmlParseError::Eof)?;
         if !matches!(open_tag, SimpleXmlEvent::Start(ref tag, _) if tag == "Association") {
@@ -311,7 +329,7 @@ impl FromXml for History {
 
     fn from_xml<I: Iterator<Item = SimpleXmlEvent>>(
         iterator: &mut Peekable<I>,
-        inner_cipher: &mut dyn Cipher,
+        inner_cipher: &mut dyn InnerStreamCipher,
     ) -> Result<Self::Parses, XmlParseError> {
         let open_tag = iterator.next().ok_or(XmlParseError::Eof)?;
         if !matches!(open_tag, SimpleXmlEvent::Start(ref tag, _) if tag == "History") {
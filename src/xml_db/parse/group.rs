@@ -1,8 +1,13 @@
+use std::iter::Peekable;
+
+use base64::{engine::general_purpose as base64_engine, Engine as _};
 use uuid::Uuid;
+use xml::escape::{escape_str_attribute, escape_str_pcdata};
 
 use crate::{
-    db::{CustomData, Entry, Group, Times},
-    xml_db::parse::{bad_event, FromXml, IgnoreSubfield, SimpleTag, SimpleXmlEvent, XmlParseError},
+    crypt::ciphers::{InnerStreamCipher, PlainCipher},
+    db::{CustomData, Entry, Group, ProtectedValue, QuarantinedNode, Times, UnknownElement},
+    xml_db::parse::{bad_event, FromXml, FromXmlCharacters, SimpleTag, SimpleXmlEvent, XmlParseError},
 };
 
 impl FromXml for Group {
@@ -10,7 +15,7 @@ impl FromXml for Group {
 
     fn from_xml<I: Iterator<Item = super::SimpleXmlEvent>>(
         iterator: &mut std::iter::Peekable<I>,
-        inner_cipher: &mut dyn crate::crypt::ciphers::Cipher,
+        inner_cipher: &mut dyn crate::crypt::ciphers::InnerStreamCipher,
     ) -> Result<Self::Parses, super::XmlParseError> {
         let open_tag = iterator.next().ok_or(XmlParseError::Eof)?;
         if !matches!(open_tag, SimpleXmlEvent::Start(ref tag, _) if tag == "Group") {
@@ -33,6 +38,12 @@ impl FromXml for Group {
                     "Notes" => {
                         out.notes = SimpleTag::<Option<String>>::from_xml(iterator, inner_cipher)?.value;
                     }
+                    "Tags" => {
+                        if let Some(tags) = SimpleTag::<Option<String>>::from_xml(iterator, inner_cipher)?.value
+                        {
+                            out.tags = tags.split([';', ',']).map(|x| x.to_owned()).collect();
+                        }
+                    }
                     "IconID" => {
                         out.icon_id = SimpleTag::<Option<usize>>::from_xml(iterator, inner_cipher)?.value;
                     }
@@ -62,10 +73,14 @@ impl FromXml for Group {
                         out.last_top_visible_entry =
                             SimpleTag::<Option<Uuid>>::from_xml(iterator, inner_cipher)?.value;
                     }
-                    "Entry" => {
-                        let entry = Entry::from_xml(iterator, inner_cipher)?;
-                        out.add_child(entry);
+                    "PreviousParentGroup" => {
+                        out.previous_parent_group =
+                            SimpleTag::<Option<Uuid>>::from_xml(iterator, inner_cipher)?.value;
                     }
+                    "Entry" => match parse_entry_or_quarantine(iterator, inner_cipher)? {
+                        Ok(entry) => out.add_child(entry),
+                        Err(quarantined) => out.quarantined.push(quarantined),
+                    },
                     "Group" => {
                         let group = Group::from_xml(iterator, inner_cipher)?;
                         out.add_child(group);
@@ -73,7 +88,9 @@ impl FromXml for Group {
                     "CustomData" => {
                         out.custom_data = CustomData::from_xml(iterator, inner_cipher)?;
                     }
-                    _ => IgnoreSubfield::from_xml(iterator, inner_cipher)?,
+                    _ => out
+                        .unknown_elements
+                        .push(UnknownElement::from_xml(iterator, inner_cipher)?),
                 },
                 SimpleXmlEvent::End(name) if name == "Group" => break,
                 _ => return Err(bad_event("start tag or close Group", event.clone())),
@@ -87,12 +104,203 @@ impl FromXml for Group {
     }
 }
 
+/// Parse an entry, setting it aside as a `QuarantinedNode` if it turns out to be malformed.
+///
+/// The events of the entry are buffered first so that parsing can resume after the entry if it
+/// fails. Protected values are decrypted up front in document order, which keeps the inner cipher
+/// stream in sync no matter where the entry parser gives up.
+fn parse_entry_or_quarantine<I: Iterator<Item = SimpleXmlEvent>>(
+    iterator: &mut Peekable<I>,
+    inner_cipher: &mut dyn InnerStreamCipher,
+) -> Result<Result<Entry, QuarantinedNode>, XmlParseError> {
+    let mut events = take_subtree(iterator)?;
+    decrypt_protected_values(&mut events, inner_cipher)?;
+
+    let error = match Entry::from_xml(&mut events.clone().into_iter().peekable(), &mut PlainCipher) {
+        Ok(entry) => return Ok(Ok(entry)),
+        Err(e) => e.to_string(),
+    };
+
+    let uuid = find_uuid(&events);
+    let (events, protected_values) = take_protected_values(events, &mut PlainCipher)?;
+    Ok(Err(QuarantinedNode {
+        uuid,
+        raw_xml: events_to_xml(&events),
+        protected_values,
+        error,
+    }))
+}
+
+/// Skip through the events up to the first entry matching `matches`, see `parse::probe_entry`.
+/// Protected values of all entries before it are still decrypted to keep the inner cipher stream
+/// in sync. Entries that fail to parse are skipped.
+pub(super) fn probe_entry<I: Iterator<Item = SimpleXmlEvent>>(
+    iterator: &mut Peekable<I>,
+    inner_cipher: &mut dyn InnerStreamCipher,
+    uuid: Option<Uuid>,
+    matches: &dyn Fn(&Entry) -> bool,
+) -> Result<Option<Entry>, XmlParseError> {
+    while let Some(event) = iterator.peek() {
+        match event {
+            SimpleXmlEvent::Start(name, _) if name == "Entry" => {
+                let mut events = take_subtree(iterator)?;
+                decrypt_protected_values(&mut events, inner_cipher)?;
+
+                if uuid.is_some() && find_uuid(&events) != uuid {
+                    continue;
+                }
+                match Entry::from_xml(&mut events.into_iter().peekable(), &mut PlainCipher) {
+                    Ok(entry) if matches(&entry) => return Ok(Some(entry)),
+                    _ => {}
+                }
+            }
+            SimpleXmlEvent::Err(_) => {
+                if let Some(SimpleXmlEvent::Err(e)) = iterator.next() {
+                    return Err(XmlParseError::Xml(e));
+                }
+            }
+            _ => {
+                iterator.next();
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+/// Consume all events from the next start tag up to and including its matching end tag
+pub(super) fn take_subtree<I: Iterator<Item = SimpleXmlEvent>>(
+    iterator: &mut Peekable<I>,
+) -> Result<Vec<SimpleXmlEvent>, XmlParseError> {
+    let mut events = Vec::new();
+    let mut depth = 0usize;
+
+    loop {
+        let event = iterator.next().ok_or(XmlParseError::Eof)?;
+        match event {
+            SimpleXmlEvent::Start(..) => depth += 1,
+            SimpleXmlEvent::End(..) => depth = depth.saturating_sub(1),
+            SimpleXmlEvent::Err(e) => return Err(XmlParseError::Xml(e)),
+            SimpleXmlEvent::Characters(..) => {}
+        }
+        events.push(event);
+
+        if depth == 0 {
+            return Ok(events);
+        }
+    }
+}
+
+/// Replace the content of all protected elements with their decrypted (but still base64-encoded)
+/// form, so that the events can afterwards be parsed with a `PlainCipher`.
+pub(super) fn decrypt_protected_values(
+    events: &mut [SimpleXmlEvent],
+    inner_cipher: &mut dyn InnerStreamCipher,
+) -> Result<(), XmlParseError> {
+    for i in 1..events.len() {
+        let protected = matches!(
+            &events[i - 1],
+            SimpleXmlEvent::Start(_, attributes)
+                if attributes.get("Protected").map(|v| v.to_lowercase()).as_deref() == Some("true")
+        );
+
+        if let (true, SimpleXmlEvent::Characters(content)) = (protected, &mut events[i]) {
+            let buf = base64_engine::STANDARD.decode(&content)?;
+            let buf_decrypted = inner_cipher.decrypt(&buf)?;
+            *content = base64_engine::STANDARD.encode(buf_decrypted);
+        }
+    }
+
+    Ok(())
+}
+
+/// Take the content of all protected elements out of the events, decrypting it into protected
+/// values in document order
+pub(super) fn take_protected_values(
+    events: Vec<SimpleXmlEvent>,
+    inner_cipher: &mut dyn InnerStreamCipher,
+) -> Result<(Vec<SimpleXmlEvent>, Vec<ProtectedValue>), XmlParseError> {
+    let mut events = events.into_iter().peekable();
+    let mut kept = Vec::new();
+    let mut protected_values = Vec::new();
+
+    while let Some(event) = events.next() {
+        let protected = matches!(
+            &event,
+            SimpleXmlEvent::Start(_, attributes)
+                if attributes.get("Protected").map(|v| v.to_lowercase()).as_deref() == Some("true")
+        );
+        kept.push(event);
+
+        if protected {
+            let value = match events.next_if(|e| matches!(e, SimpleXmlEvent::Characters(_))) {
+                Some(SimpleXmlEvent::Characters(content)) => {
+                    inner_cipher.decrypt(&base64_engine::STANDARD.decode(&content)?)?
+                }
+                _ => Vec::new(),
+            };
+            protected_values.push(ProtectedValue::new(value));
+        }
+    }
+
+    Ok((kept, protected_values))
+}
+
+/// Try to recover the UUID of an entry from its events
+fn find_uuid(events: &[SimpleXmlEvent]) -> Option<Uuid> {
+    let mut depth = 0usize;
+    for window in events.windows(2) {
+        match &window[0] {
+            SimpleXmlEvent::Start(name, _) => {
+                depth += 1;
+                if depth == 2 && name == "UUID" {
+                    if let SimpleXmlEvent::Characters(text) = &window[1] {
+                        return Uuid::from_xml_characters(text).ok();
+                    }
+                }
+            }
+            SimpleXmlEvent::End(_) => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Turn a list of events back into an XML fragment
+pub(super) fn events_to_xml(events: &[SimpleXmlEvent]) -> String {
+    let mut out = String::new();
+
+    for event in events {
+        match event {
+            SimpleXmlEvent::Start(name, attributes) => {
+                let mut attributes: Vec<_> = attributes.iter().collect();
+                attributes.sort();
+
+                out.push('<');
+                out.push_str(name);
+                for (key, value) in attributes {
+                    out.push_str(&format!(" {}=\"{}\"", key, escape_str_attribute(value)));
+                }
+                out.push('>');
+            }
+            SimpleXmlEvent::End(name) => out.push_str(&format!("</{}>", name)),
+            SimpleXmlEvent::Characters(text) => out.push_str(&escape_str_pcdata(text)),
+            SimpleXmlEvent::Err(_) => {}
+        }
+    }
+
+    out
+}
+
 #[cfg(test)]
 mod parse_group_test {
 
+    use base64::{engine::general_purpose::STANDARD, Engine as _};
+
     use crate::{
-        db::Group,
-        xml_db::parse::{parse_test::parse_test_xml, XmlParseError},
+        crypt::ciphers::{InnerStreamCipher, Salsa20Cipher},
+        db::{Group, Node},
+        xml_db::parse::{parse_from_bytes, parse_test::parse_test_xml, XmlParseError},
     };
 
     use uuid::uuid;
@@ -131,4 +339,67 @@ mod parse_group_test {
 
         Ok(())
     }
+
+    #[test]
+    fn test_quarantined_entry() -> Result<(), XmlParseError> {
+        let value = parse_test_xml::<Group>(
+            "<Group>\
+                <Entry><UUID>oaKjpLGywcLR0tPU1dbX2A==</UUID><Times><ExpiryTime>garbage</ExpiryTime></Times></Entry>\
+                <Entry><UUID>sbKztLGywcLR0tPU1dbX2A==</UUID></Entry>\
+            </Group>",
+        )?;
+
+        assert_eq!(value.children.len(), 1);
+        assert_eq!(value.quarantined.len(), 1);
+
+        let quarantined = &value.quarantined[0];
+        assert_eq!(quarantined.uuid, Some(uuid!("a1a2a3a4b1b2c1c2d1d2d3d4d5d6d7d8")));
+        assert!(quarantined.raw_xml.starts_with("<Entry><UUID>"));
+        assert!(quarantined.raw_xml.contains("<ExpiryTime>garbage</ExpiryTime>"));
+        assert!(!quarantined.error.is_empty());
+
+        // structural XML errors still fail the whole document
+        let value = parse_test_xml::<Group>("<Group><Entry><UUID></Group>");
+        assert!(value.is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_quarantined_entry_keeps_inner_stream_in_sync() -> Result<(), XmlParseError> {
+        let key = [0x42u8; 32];
+        let mut encryptor = Salsa20Cipher::new(&key).unwrap();
+
+        // a stream cipher decrypts and encrypts the same way
+        let secret_broken = STANDARD.encode(encryptor.decrypt(b"first secret").unwrap());
+        let secret_ok = STANDARD.encode(encryptor.decrypt(b"second secret").unwrap());
+
+        let xml = format!(
+            "<Group>\
+                <Entry>\
+                    <String><Key>Password</Key><Value Protected=\"True\">{}</Value></String>\
+                    <Times><ExpiryTime>garbage</ExpiryTime></Times>\
+                </Entry>\
+                <Entry>\
+                    <String><Key>Password</Key><Value Protected=\"True\">{}</Value></String>\
+                </Entry>\
+            </Group>",
+            secret_broken, secret_ok
+        );
+
+        let mut decryptor = Salsa20Cipher::new(&key).unwrap();
+        let value = parse_from_bytes::<Group>(xml.as_bytes(), &mut decryptor)?;
+
+        assert_eq!(value.quarantined.len(), 1);
+        let quarantined = &value.quarantined[0];
+        assert!(quarantined.raw_xml.contains("<Value Protected=\"True\"></Value>"));
+        assert_eq!(quarantined.protected_values, vec!["first secret".into()]);
+
+        match &value.children[..] {
+            [Node::Entry(e)] => assert_eq!(e.get_password(), Some("second secret")),
+            _ => panic!("Expected a single entry"),
+        }
+
+        Ok(())
+    }
 }
@@ -6,7 +6,7 @@ use crate::{
     compression::{Compression, GZipCompression},
     db::{
         meta::{BinaryAttachment, BinaryAttachments, CustomIcons, Icon, MemoryProtection, Meta},
-        Color,
+        Color, UnknownElement,
     },
     xml_db::parse::{bad_event, CustomData, FromXml, IgnoreSubfield, SimpleTag, SimpleXmlEvent, XmlParseError},
 };
@@ -16,7 +16,7 @@ impl FromXml for Meta {
 
     fn from_xml<I: Iterator<Item = crate::xml_db::parse::SimpleXmlEvent>>(
         iterator: &mut std::iter::Peekable<I>,
-        inner_cipher: &mut dyn crate::crypt::ciphers::Cipher,
+        inner_cipher: &mut dyn crate::crypt::ciphers::InnerStreamCipher,
     ) -> Result<Self::Parses, crate::xml_db::parse::XmlParseError> {
         let open_tag = iterator.next().ok_or(XmlParseError::Eof)?;
         if !matches!(open_tag, SimpleXmlEvent::Start(ref tag, _) if tag == "Meta") {
@@ -128,7 +128,13 @@ impl FromXml for Meta {
                     "CustomData" => {
                         out.custom_data = CustomData::from_xml(iterator, inner_cipher)?;
                     }
-                    _ => IgnoreSubfield::from_xml(iterator, inner_cipher)?,
+                    "HeaderHash" => {
+                        // the hash of the KDBX 3 header would be outdated when writing it back
+                        IgnoreSubfield::from_xml(iterator, inner_cipher)?
+                    }
+                    _ => out
+                        .unknown_elements
+                        .push(UnknownElement::from_xml(iterator, inner_cipher)?),
                 },
                 SimpleXmlEvent::End(name) if name == "Meta" => break,
                 _ => return Err(bad_event("start tag or close Meta", event.clone())),
@@ -147,7 +153,7 @@ impl FromXml for MemoryProtection {
 
     fn from_xml<I: Iterator<Item = SimpleXmlEvent>>(
         iterator: &mut std::iter::Peekable<I>,
-        inner_cipher: &mut dyn crate::crypt::ciphers::Cipher,
+        inner_cipher: &mut dyn crate::crypt::ciphers::InnerStreamCipher,
     ) -> Result<Self::Parses, XmlParseError> {
         let open_tag = iterator.next().ok_or(XmlParseError::Eof)?;
         if !matches!(open_tag, SimpleXmlEvent::Start(ref tag, _) if tag == "MemoryProtection") {
@@ -193,7 +199,7 @@ impl FromXml for BinaryAttachments {
 
     fn from_xml<I: Iterator<Item = SimpleXmlEvent>>(
         iterator: &mut std::iter::Peekable<I>,
-        inner_cipher: &mut dyn crate::crypt::ciphers::Cipher,
+        inner_cipher: &mut dyn crate::crypt::ciphers::InnerStreamCipher,
     ) -> Result<Self::Parses, XmlParseError> {
         let open_tag = iterator.next().ok_or(XmlParseError::Eof)?;
         if !matches!(open_tag, SimpleXmlEvent::Start(ref tag, _) if tag == "Binaries") {
@@ -228,7 +234,7 @@ impl FromXml for BinaryAttachment {
 
     fn from_xml<I: Iterator<Item = SimpleXmlEvent>>(
         iterator: &mut std::iter::Peekable<I>,
-        inner_cipher: &mut dyn crate::crypt::ciphers::Cipher,
+        inner_cipher: &mut dyn crate::crypt::ciphers::InnerStreamCipher,
     ) -> Result<Self::Parses, XmlParseError> {
         let open_tag = iterator.next().ok_or(XmlParseError::Eof)?;
 
@@ -256,7 +262,7 @@ impl FromXml for BinaryAttachment {
         out.identifier = identifier;
         out.compressed = compressed;
         out.content = if compressed {
-            Compression::decompress(&GZipCompression, &buf).map_err(XmlParseError::Compression)?
+            Compression::decompress(&GZipCompression::default(), &buf).map_err(XmlParseError::Compression)?
         } else {
             buf
         };
@@ -273,7 +279,7 @@ impl FromXml for CustomIcons {
 
     fn from_xml<I: Iterator<Item = SimpleXmlEvent>>(
         iterator: &mut std::iter::Peekable<I>,
-        inner_cipher: &mut dyn crate::crypt::ciphers::Cipher,
+        inner_cipher: &mut dyn crate::crypt::ciphers::InnerStreamCipher,
     ) -> Result<Self::Parses, XmlParseError> {
         let open_tag = iterator.next().ok_or(XmlParseError::Eof)?;
         if !matches!(open_tag, SimpleXmlEvent::Start(ref tag, _) if tag == "CustomIcons") {
@@ -308,7 +314,7 @@ impl FromXml for Icon {
 
     fn from_xml<I: Iterator<Item = SimpleXmlEvent>>(
         iterator: &mut std::iter::Peekable<I>,
-        inner_cipher: &mut dyn crate::crypt::ciphers::Cipher,
+        inner_cipher: &mut dyn crate::crypt::ciphers::InnerStreamCipher,
     ) -> Result<Self::Parses, XmlParseError> {
         let open_tag = iterator.next().ok_or(XmlParseError::Eof)?;
         if !matches!(open_tag, SimpleXmlEvent::Start(ref tag, _) if tag == "Icon") {
@@ -328,6 +334,13 @@ impl FromXml for Icon {
                         let buf = base64_engine::STANDARD.decode(&data)?;
                         out.data = buf;
                     }
+                    "Name" => {
+                        out.name = SimpleTag::<Option<String>>::from_xml(iterator, inner_cipher)?.value;
+                    }
+                    "LastModificationTime" => {
+                        out.last_modification_time =
+                            SimpleTag::<Option<NaiveDateTime>>::from_xml(iterator, inner_cipher)?.value;
+                    }
                     _ => IgnoreSubfield::from_xml(iterator, inner_cipher)?,
                 },
                 SimpleXmlEvent::End(name) if name == "Icon" => break,
@@ -2,7 +2,12 @@ mod entry;
 mod group;
 mod meta;
 
-use std::{collections::HashMap, iter::Peekable};
+use std::{
+    cell::RefCell,
+    collections::{HashMap, VecDeque},
+    io::{BufReader, Read},
+    iter::Peekable,
+};
 
 use base64::{engine::general_purpose as base64_engine, Engine as _};
 use chrono::NaiveDateTime;
@@ -10,15 +15,43 @@ use uuid::Uuid;
 use xml::{name::OwnedName, reader::XmlEvent, EventReader};
 
 use crate::{
-    crypt::ciphers::Cipher,
+    crypt::ciphers::InnerStreamCipher,
     db::{
-        Color, CustomData, CustomDataItem, CustomDataItemDenormalized, DeletedObject, DeletedObjects, Group,
-        Meta, Times, Value,
+        Color, CustomData, CustomDataItem, CustomDataItemDenormalized, DeletedObject, DeletedObjects, Entry,
+        Group, Meta, Times, UnknownElement, Value,
     },
     error::XmlParseError,
     xml_db::get_epoch_baseline,
 };
 
+thread_local! {
+    // names of elements skipped by `IgnoreSubfield`, only recorded while `collect_ignored_elements`
+    // is running
+    static IGNORED_ELEMENTS: RefCell<Option<Vec<String>>> = const { RefCell::new(None) };
+}
+
+/// Run `f`, returning the names of all XML elements that were skipped during parsing because they
+/// are not known to this library
+pub(crate) fn collect_ignored_elements<R>(f: impl FnOnce() -> R) -> (R, Vec<String>) {
+    struct ResetOnDrop(Option<Vec<String>>);
+    impl Drop for ResetOnDrop {
+        fn drop(&mut self) {
+            let previous = self.0.take();
+            IGNORED_ELEMENTS.with(|i| *i.borrow_mut() = previous);
+        }
+    }
+
+    let previous = IGNORED_ELEMENTS.with(|i| i.borrow_mut().replace(Vec::new()));
+    let reset = ResetOnDrop(previous);
+    let result = f();
+    let ignored = IGNORED_ELEMENTS
+        .with(|i| i.borrow_mut().take())
+        .unwrap_or_default();
+    drop(reset);
+
+    (result, ignored)
+}
+
 /// Parse a KeePass timestamp string
 pub fn parse_xml_timestamp(t: &str) -> Result<chrono::NaiveDateTime, XmlParseError> {
     match chrono::NaiveDateTime::parse_from_str(t, "%Y-%m-%dT%H:%M:%SZ") {
@@ -47,7 +80,7 @@ pub(crate) trait FromXml {
 
     fn from_xml<I: Iterator<Item = SimpleXmlEvent>>(
         iterator: &mut Peekable<I>,
-        inner_cipher: &mut dyn Cipher,
+        inner_cipher: &mut dyn InnerStreamCipher,
     ) -> Result<Self::Parses, XmlParseError>;
 }
 
@@ -66,43 +99,153 @@ pub(crate) fn bad_event(expected: &'static str, event: SimpleXmlEvent) -> XmlPar
     XmlParseError::BadEvent { expected, event }
 }
 
-pub(crate) fn parse(xml: &[u8], inner_cipher: &mut dyn Cipher) -> Result<KeePassXml, XmlParseError> {
+pub(crate) fn parse(xml: &[u8], inner_cipher: &mut dyn InnerStreamCipher) -> Result<KeePassXml, XmlParseError> {
     parse_from_bytes::<KeePassXml>(xml, inner_cipher)
 }
 
+/// Parse a KeePass XML document while it is read, without holding all of it in memory
+pub(crate) fn parse_from_reader(
+    xml: &mut dyn Read,
+    inner_cipher: &mut dyn InnerStreamCipher,
+) -> Result<KeePassXml, XmlParseError> {
+    KeePassXml::from_xml(&mut simple_events(BufReader::new(xml)).peekable(), inner_cipher)
+}
+
+/// Parse a KeePass XML document that may break off or be malformed, keeping everything up to the
+/// point where it does. Also returns where and why it broke off, if it did.
+pub(crate) fn parse_lenient(
+    xml: &mut dyn Read,
+    inner_cipher: &mut dyn InnerStreamCipher,
+) -> (Result<KeePassXml, XmlParseError>, Option<String>) {
+    let mut cut_off = None;
+    let parsed = KeePassXml::from_xml(
+        &mut CloseOnError::new(simple_events(BufReader::new(xml)), &mut cut_off).peekable(),
+        inner_cipher,
+    );
+    (parsed, cut_off)
+}
+
 pub(crate) fn parse_from_bytes<P: FromXml>(
     xml: &[u8],
-    inner_cipher: &mut dyn Cipher,
+    inner_cipher: &mut dyn InnerStreamCipher,
 ) -> Result<<P as FromXml>::Parses, XmlParseError> {
-    let mut reader = EventReader::new(xml)
-        .into_iter()
-        .filter_map(|e| {
-            // simplify iterator by ignoring unneeded events and flattening the structure
-            match e {
-                Ok(XmlEvent::StartElement {
-                    name: OwnedName { local_name, .. },
-                    attributes,
-                    ..
-                }) => Some(SimpleXmlEvent::Start(
-                    local_name,
-                    attributes
-                        .into_iter()
-                        .map(|a| (a.name.local_name, a.value))
-                        .collect(),
-                )),
-                Ok(XmlEvent::EndElement {
-                    name: OwnedName { local_name, .. },
-                }) => Some(SimpleXmlEvent::End(local_name)),
-                Ok(XmlEvent::Characters(c)) => Some(SimpleXmlEvent::Characters(c)),
-                Err(e) => Some(SimpleXmlEvent::Err(e.into())),
-
-                // ignore whitespace, comments, ...
-                _ => None,
+    P::from_xml(&mut simple_events(xml).peekable(), inner_cipher)
+}
+
+/// Find the first entry matching `matches` without building the rest of the tree. If `uuid` is
+/// given, only the entry with that UUID is parsed at all.
+pub(crate) fn probe_entry(
+    xml: &[u8],
+    inner_cipher: &mut dyn InnerStreamCipher,
+    uuid: Option<Uuid>,
+    matches: &dyn Fn(&Entry) -> bool,
+) -> Result<Option<Entry>, XmlParseError> {
+    group::probe_entry(&mut simple_events(xml).peekable(), inner_cipher, uuid, matches)
+}
+
+pub(crate) fn simple_events<R: Read>(xml: R) -> impl Iterator<Item = SimpleXmlEvent> {
+    EventReader::new(xml).into_iter().filter_map(|e| {
+        // simplify iterator by ignoring unneeded events and flattening the structure
+        match e {
+            Ok(XmlEvent::StartElement {
+                name: OwnedName { local_name, .. },
+                attributes,
+                ..
+            }) => Some(SimpleXmlEvent::Start(
+                local_name,
+                attributes
+                    .into_iter()
+                    .map(|a| (a.name.local_name, a.value))
+                    .collect(),
+            )),
+            Ok(XmlEvent::EndElement {
+                name: OwnedName { local_name, .. },
+            }) => Some(SimpleXmlEvent::End(local_name)),
+            Ok(XmlEvent::Characters(c)) => Some(SimpleXmlEvent::Characters(c)),
+            Err(e) => Some(SimpleXmlEvent::Err(e.into())),
+
+            // ignore whitespace, comments, ...
+            _ => None,
+        }
+    })
+}
+
+/// Passes XML events through, closing all open elements where the document breaks off with an
+/// error or ends too early, so that the parsers get a complete document. The start of an element is
+/// held back until it is known whether the element holds text, and its text until the element is
+/// closed, so that no value that was cut off is parsed.
+struct CloseOnError<'a, I> {
+    inner: I,
+    held: Vec<SimpleXmlEvent>,
+    ready: VecDeque<SimpleXmlEvent>,
+
+    /// Names of the elements that were passed on but are not closed yet
+    open: Vec<String>,
+    closing: bool,
+    cut_off: &'a mut Option<String>,
+}
+
+impl<'a, I: Iterator<Item = SimpleXmlEvent>> CloseOnError<'a, I> {
+    fn new(inner: I, cut_off: &'a mut Option<String>) -> Self {
+        CloseOnError {
+            inner,
+            held: Vec::new(),
+            ready: VecDeque::new(),
+            open: Vec::new(),
+            closing: false,
+            cut_off,
+        }
+    }
+
+    /// Pass on the held start of an element that turned out to contain other elements
+    fn release(&mut self) {
+        if let Some(SimpleXmlEvent::Start(name, _)) = self.held.first() {
+            self.open.push(name.clone());
+        }
+        self.ready.extend(self.held.drain(..));
+    }
+
+    fn cut_off(&mut self, error: XmlParseError) {
+        // anything after the end of the document, like padding, does not matter
+        if !self.open.is_empty() || !self.held.is_empty() {
+            *self.cut_off = Some(format!("{} in {}", error, self.open.join("/")));
+        }
+        self.held.clear();
+        self.closing = true;
+    }
+}
+
+impl<I: Iterator<Item = SimpleXmlEvent>> Iterator for CloseOnError<'_, I> {
+    type Item = SimpleXmlEvent;
+
+    fn next(&mut self) -> Option<SimpleXmlEvent> {
+        loop {
+            if let Some(event) = self.ready.pop_front() {
+                return Some(event);
+            }
+            if self.closing {
+                return self.open.pop().map(SimpleXmlEvent::End);
             }
-        })
-        .peekable();
 
-    P::from_xml(&mut reader, inner_cipher)
+            match self.inner.next() {
+                Some(event @ SimpleXmlEvent::Start(..)) => {
+                    self.release();
+                    self.held.push(event);
+                }
+                Some(event @ SimpleXmlEvent::Characters(_)) if !self.held.is_empty() => self.held.push(event),
+                Some(event @ SimpleXmlEvent::Characters(_)) => self.ready.push_back(event),
+                Some(event @ SimpleXmlEvent::End(_)) => {
+                    if self.held.is_empty() {
+                        self.open.pop();
+                    }
+                    self.ready.extend(self.held.drain(..));
+                    self.ready.push_back(event);
+                }
+                Some(SimpleXmlEvent::Err(e)) => self.cut_off(XmlParseError::Xml(e)),
+                None => self.cut_off(XmlParseError::Eof),
+            }
+        }
+    }
 }
 
 /// Helper trait for converting `SimpleXmlEvent::Characters` into types that can be parsed from
@@ -119,7 +262,7 @@ impl<T: FromXmlCharacters> FromXml for T {
 
     fn from_xml<I: Iterator<Item = SimpleXmlEvent>>(
         iterator: &mut Peekable<I>,
-        _inner_cipher: &mut dyn Cipher,
+        _inner_cipher: &mut dyn InnerStreamCipher,
     ) -> Result<Self::Parses, XmlParseError> {
         let event = iterator.next().ok_or(XmlParseError::Eof)?;
         if let SimpleXmlEvent::Characters(text) = event {
@@ -135,7 +278,7 @@ impl<T: FromXmlCharacters> FromXml for Option<T> {
 
     fn from_xml<I: Iterator<Item = SimpleXmlEvent>>(
         iterator: &mut Peekable<I>,
-        _inner_cipher: &mut dyn Cipher,
+        _inner_cipher: &mut dyn InnerStreamCipher,
     ) -> Result<Self::Parses, XmlParseError> {
         let event = iterator.peek().ok_or(XmlParseError::Eof)?;
         if let SimpleXmlEvent::Characters(_) = event {
@@ -204,7 +347,7 @@ impl<V: FromXml> FromXml for SimpleTag<V> {
 
     fn from_xml<I: Iterator<Item = SimpleXmlEvent>>(
         iterator: &mut Peekable<I>,
-        inner_cipher: &mut dyn Cipher,
+        inner_cipher: &mut dyn InnerStreamCipher,
     ) -> Result<Self::Parses, XmlParseError> {
         let open_tag = iterator.next().ok_or(XmlParseError::Eof)?;
         if let SimpleXmlEvent::Start(name, _) = open_tag {
@@ -233,7 +376,7 @@ impl FromXml for KeePassXml {
 
     fn from_xml<I: Iterator<Item = SimpleXmlEvent>>(
         iterator: &mut Peekable<I>,
-        inner_cipher: &mut dyn Cipher,
+        inner_cipher: &mut dyn InnerStreamCipher,
     ) -> Result<Self::Parses, XmlParseError> {
         let open_tag = iterator.next().ok_or(XmlParseError::Eof)?;
         if !matches!(open_tag, SimpleXmlEvent::Start(ref tag, _) if tag == "KeePassFile") {
@@ -270,7 +413,7 @@ impl FromXml for Times {
 
     fn from_xml<I: Iterator<Item = SimpleXmlEvent>>(
         iterator: &mut Peekable<I>,
-        inner_cipher: &mut dyn Cipher,
+        inner_cipher: &mut dyn InnerStreamCipher,
     ) -> Result<Self::Parses, XmlParseError> {
         let open_tag = iterator.next().ok_or(XmlParseError::Eof)?;
         if !matches!(open_tag, SimpleXmlEvent::Start(ref tag, _) if tag == "Times") {
@@ -317,7 +460,7 @@ impl FromXml for Root {
 
     fn from_xml<I: Iterator<Item = SimpleXmlEvent>>(
         iterator: &mut Peekable<I>,
-        inner_cipher: &mut dyn Cipher,
+        inner_cipher: &mut dyn InnerStreamCipher,
     ) -> Result<Self::Parses, XmlParseError> {
         let open_tag = iterator.next().ok_or(XmlParseError::Eof)?;
         if !matches!(open_tag, SimpleXmlEvent::Start(ref tag, _) if tag == "Root") {
@@ -354,7 +497,7 @@ impl FromXml for DeletedObjects {
 
     fn from_xml<I: Iterator<Item = SimpleXmlEvent>>(
         iterator: &mut Peekable<I>,
-        inner_cipher: &mut dyn Cipher,
+        inner_cipher: &mut dyn InnerStreamCipher,
     ) -> Result<Self::Parses, XmlParseError> {
         let open_tag = iterator.next().ok_or(XmlParseError::Eof)?;
         if !matches!(open_tag, SimpleXmlEvent::Start(ref tag, _) if tag == "DeletedObjects") {
@@ -386,7 +529,7 @@ impl FromXml for DeletedObject {
 
     fn from_xml<I: Iterator<Item = SimpleXmlEvent>>(
         iterator: &mut Peekable<I>,
-        inner_cipher: &mut dyn Cipher,
+        inner_cipher: &mut dyn InnerStreamCipher,
     ) -> Result<Self::Parses, XmlParseError> {
         let open_tag = iterator.next().ok_or(XmlParseError::Eof)?;
         if !matches!(open_tag, SimpleXmlEvent::Start(ref tag, _) if tag == "DeletedObject") {
@@ -423,7 +566,7 @@ impl FromXml for CustomData {
 
     fn from_xml<I: Iterator<Item = SimpleXmlEvent>>(
         iterator: &mut std::iter::Peekable<I>,
-        inner_cipher: &mut dyn crate::crypt::ciphers::Cipher,
+        inner_cipher: &mut dyn crate::crypt::ciphers::InnerStreamCipher,
     ) -> Result<Self::Parses, XmlParseError> {
         let open_tag = iterator.next().ok_or(XmlParseError::Eof)?;
         if !matches!(open_tag, SimpleXmlEvent::Start(ref tag, _) if tag == "CustomData") {
@@ -464,7 +607,7 @@ impl FromXml for CustomDataItemDenormalized {
 
     fn from_xml<I: Iterator<Item = SimpleXmlEvent>>(
         iterator: &mut std::iter::Peekable<I>,
-        inner_cipher: &mut dyn crate::crypt::ciphers::Cipher,
+        inner_cipher: &mut dyn crate::crypt::ciphers::InnerStreamCipher,
     ) -> Result<Self::Parses, XmlParseError> {
         let open_tag = iterator.next().ok_or(XmlParseError::Eof)?;
         if !matches!(open_tag, SimpleXmlEvent::Start(ref tag, _) if tag == "Item") {
@@ -500,6 +643,34 @@ impl FromXml for CustomDataItemDenormalized {
     }
 }
 
+/// Keeps an element unknown to the parsers as an XML fragment. Protected content is decrypted so
+/// that the inner cipher stream stays in sync, and taken out of the fragment into protected values.
+impl FromXml for UnknownElement {
+    type Parses = Self;
+
+    fn from_xml<I: Iterator<Item = SimpleXmlEvent>>(
+        iterator: &mut Peekable<I>,
+        inner_cipher: &mut dyn InnerStreamCipher,
+    ) -> Result<Self::Parses, XmlParseError> {
+        let name = match iterator.peek() {
+            Some(SimpleXmlEvent::Start(name, _)) => name.clone(),
+            _ => {
+                let event = iterator.next().ok_or(XmlParseError::Eof)?;
+                return Err(bad_event("Open tag (to be kept)", event));
+            }
+        };
+
+        let events = group::take_subtree(iterator)?;
+        let (events, protected_values) = group::take_protected_values(events, inner_cipher)?;
+
+        Ok(UnknownElement {
+            name,
+            xml: group::events_to_xml(&events),
+            protected_values,
+        })
+    }
+}
+
 /// A helper parser that will ignore everything in its tag.
 pub(crate) struct IgnoreSubfield;
 
@@ -508,10 +679,16 @@ impl FromXml for IgnoreSubfield {
 
     fn from_xml<I: Iterator<Item = SimpleXmlEvent>>(
         iterator: &mut Peekable<I>,
-        _inner_cipher: &mut dyn Cipher,
+        _inner_cipher: &mut dyn InnerStreamCipher,
     ) -> Result<Self::Parses, XmlParseError> {
         let open_tag = iterator.next().ok_or(XmlParseError::Eof)?;
-        if let SimpleXmlEvent::Start(_, _) = open_tag {
+        if let SimpleXmlEvent::Start(ref name, _) = open_tag {
+            IGNORED_ELEMENTS.with(|i| {
+                if let Some(ignored) = i.borrow_mut().as_mut() {
+                    ignored.push(name.clone());
+                }
+            });
+
             let mut stack = Vec::new();
 
             while let Some(event) = iterator.next() {
@@ -548,7 +725,10 @@ mod parse_test {
         xml_db::parse::{entry::StringField, DeletedObject, DeletedObjects, IgnoreSubfield, Root},
     };
 
-    use super::{entry::BinaryField, parse, parse_from_bytes, FromXml, KeePassXml, SimpleTag, XmlParseError};
+    use super::{
+        entry::BinaryField, parse, parse_from_bytes, parse_lenient, FromXml, KeePassXml, SimpleTag,
+        XmlParseError,
+    };
 
     pub(crate) fn parse_test_xml<P: FromXml>(xml: &str) -> Result<<P as FromXml>::Parses, XmlParseError> {
         parse_from_bytes::<P>(xml.as_bytes(), &mut PlainCipher)
@@ -565,6 +745,26 @@ mod parse_test {
         Ok(())
     }
 
+    #[test]
+    fn test_parse_lenient_closes_cut_off_document() {
+        let xml = "<KeePassFile><Root><Group><Name>Root</Name>\
+            <Entry><String><Key>Title</Key><Value>One</Value></String></Entry>\
+            <Group><Name>Cut off</Name><Entry><String><Key>Title</Key><Value>Tw";
+
+        let (parsed, cut_off) = parse_lenient(&mut xml.as_bytes(), &mut PlainCipher);
+        let root = parsed.unwrap().root.group;
+        assert_eq!(root.name, "Root");
+        assert_eq!(root.entries()[0].get_title(), Some("One"));
+        assert_eq!(root.groups()[0].name, "Cut off");
+        assert!(cut_off
+            .unwrap()
+            .ends_with("in KeePassFile/Root/Group/Group/Entry/String"));
+
+        let (parsed, cut_off) = parse_lenient(&mut "<KeePassFile></KeePassFile>".as_bytes(), &mut PlainCipher);
+        assert!(parsed.is_ok());
+        assert_eq!(cut_off, None);
+    }
+
     #[test]
     fn test_simple_tag() -> Result<(), XmlParseError> {
         // String tag
@@ -875,6 +1075,7 @@ mod parse_test {
         let value = parse_test_xml::<AutoType>("<AutoType><Enabled>True</Enabled><DefaultSequence>ASDF</DefaultSequence><DataTransferObfuscation>42</DataTransferObfuscation></AutoType>")?;
         assert_eq!(value.enabled, true);
         assert_eq!(value.sequence, Some("ASDF".to_string()));
+        assert_eq!(value.data_transfer_obfuscation, 42);
         assert_eq!(value.associations.len(), 0);
 
         let value = parse_test_xml::<AutoType>("<WrongTag></WrongTag>");
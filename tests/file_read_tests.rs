@@ -1,5 +1,6 @@
 mod file_read_tests {
     use keepass::{
+        config::KdfConfig,
         db::{Database, NodeRef},
         error::{DatabaseIntegrityError, DatabaseOpenError},
         DatabaseKey,
@@ -152,6 +153,7 @@ mod file_read_tests {
 
         println!("{:?} DB Opened", db);
 
+        assert!(matches!(db.config.kdf_config, KdfConfig::Argon2id { .. }));
         assert_eq!(db.root.name, "Root");
         assert_eq!(db.root.children.len(), 2);
 
@@ -292,7 +294,7 @@ mod file_read_tests {
     }
 
     #[test]
-    #[should_panic(expected = r#"InvalidKDBXVersion"#)]
+    #[should_panic(expected = r#"IncompatibleVersion"#)]
     fn open_broken_kdbx_version() {
         let path = Path::new("tests/resources/broken_kdbx_version.kdbx");
         Database::open(
@@ -330,7 +332,20 @@ mod file_read_tests {
         }
 
         assert_eq!(total_groups, 12);
-        assert_eq!(total_entries, 5);
+        // the two Meta-Info entries holding KeePass 1.x settings are left out
+        assert_eq!(total_entries, 3);
+
+        let entry = db
+            .root
+            .iter()
+            .find_map(|node| match node {
+                NodeRef::Entry(e) if e.get_title() == Some("title") => Some(e),
+                _ => None,
+            })
+            .unwrap();
+        assert!(!entry.uuid.is_nil());
+        assert!(entry.times.get_creation().is_some());
+        assert!(!entry.times.expires);
 
         println!("{:?}", db);
 